@@ -0,0 +1,1338 @@
+/// Example WebSocket server for testing the notification agent
+///
+/// Run with: cargo run --example test_server
+///
+/// With `--port <port>`, listen somewhere other than 8080, e.g. to act as the
+/// agent's standby server alongside another instance.
+///
+/// The port above the WebSocket one serves a small REST API for scheduling
+/// suppression windows on every connected agent, and for cancelling alerts;
+/// agents learn of cancellations when they next reconnect:
+///
+/// ```text
+/// curl -X POST localhost:8081/suppressions -H 'Content-Type: application/json' \
+///     -d '{"id": "…", "starts_at": "…", "ends_at": "…", "reason": "Fire alarm testing"}'
+/// curl -X DELETE localhost:8081/suppressions/<id>
+/// curl -X DELETE localhost:8081/alerts/<id>
+/// curl localhost:8081/alerts/<id>
+/// curl localhost:8081/api/clients/<client id>
+/// curl -X POST localhost:8081/api/alerts/preview -H 'X-Api-Key: <key>' \
+///     -H 'Content-Type: application/json' \
+///     -d '{"client_id": "…", "title": "…", "message": "…", "level": "warning"}'
+/// ```
+///
+/// `GET /api/clients/{id}` shows a connected agent's last heartbeat and how its
+/// previous run ended, as it reported on registering. Every heartbeat is
+/// answered with a `heartbeat_ack`, as agents expect of current servers. An
+/// agent that stops says so with `unregister` before it disconnects.
+///
+/// `POST /api/alerts/preview` sends an alert marked as a preview to a single
+/// agent, one the caller's API key owns as given by `--api-key
+/// <key>=<client>[,<client>…]` (repeatable), and answers with the agent's
+/// delivery status once it arrives, or after 10 seconds without one.
+///
+/// Bundles of confirmations and delivery records carried from isolated sites
+/// are imported with `cargo run --example test_server -- import-offline
+/// <bundle.json> [--port <port>]`, which hands the bundle to the running
+/// server; it checks the bundle against `OFFLINE_EXPORT_KEY`, refuses one
+/// already imported, and skips records it already has. `GET /alerts/{id}`
+/// lists what the server has heard of an alert, marking what came from a bundle.
+///
+/// Agents that offer `confirmation_timeouts` are asked to report alerts
+/// nobody confirmed in time as `confirmation_timeout`; these are listed
+/// under `timeouts`, apart from the alert's confirmations. A preview may set
+/// `confirm_timeout_secs`.
+///
+/// The Critical test alert asks for a quorum of two: once two people have
+/// confirmed it, the other agents are told so and stop escalating it.
+///
+/// The Emergency test alert asks who is safe; the delivery report, and
+/// `responses` under `GET /alerts/{id}`, count how many chose each option,
+/// with those who timed out or chose none under "timed out / no option". A
+/// preview may set `response_options` too.
+///
+/// With `--token <token>`, registrations are refused with `register_rejected`
+/// unless the upgrade request carried `Authorization: Bearer <token>`, as an
+/// agent with `AUTH_TOKEN` set sends.
+///
+/// With `--ws-compression`, the server agrees to permessage-deflate with
+/// agents that offer it (`WS_COMPRESSION=true`); `GET /api/clients/{id}` shows
+/// whether a connection is compressed. Without it, offers are declined and
+/// every connection is plain.
+///
+/// With `--msgpack`, agents that offer MessagePack are asked to send it in
+/// binary frames; the server reads either encoding and always sends JSON.
+///
+/// On Ctrl+C the server sends every agent `server_shutdown` before closing its
+/// connection, so agents wait quietly for it to return instead of backing off;
+/// `--resume-after <secs>` sets when they expect it back (default 60).
+///
+/// With `--multicast`, each test alert is also broadcast as a signed envelope
+/// to `MULTICAST_GROUP` (default 239.255.40.1), signed with `MULTICAST_KEY`.
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::{delete, get, post};
+use axum::{Extension, Json, Router};
+use emns_agent::compression::{self, ReceiveLimits};
+use emns_agent::multicast::{MulticastConfig, MulticastSender, SigningKey};
+use emns_agent::offline;
+use emns_agent::transport::{
+    Connection, DEFAULT_MAX_RECEIVE_FRAME_BYTES, DEFAULT_MAX_RECEIVE_MESSAGE_BYTES,
+};
+use emns_agent::EmnsError;
+use emns_protocol::{
+    Alert, AlertLevel, Confirmation, ConfirmationResponse, DeliveryStatus, Encoding,
+    HeartbeatStats, LatencySummary, Message as AgentMessage, OfflineBundle, OfflineRecord,
+    QuorumTally, ResponseOption, ShutdownReason, ShutdownRecord, SuppressionWindow, ToastOptions,
+    PROTOCOL_VERSION,
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{oneshot, Mutex};
+use tokio_tungstenite::tungstenite::handshake::server::{
+    Callback, ErrorResponse, Request, Response,
+};
+use tokio_tungstenite::{accept_hdr_async, tungstenite::Message};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use uuid::Uuid;
+
+/// A registered agent and what its latest heartbeat said
+struct ConnectedClient {
+    tx: tokio::sync::mpsc::Sender<String>,
+    addr: SocketAddr,
+    heartbeat: HeartbeatStats,
+    /// Registered as the agent's standby link; alerts go to it but it is not a second client
+    standby: bool,
+    /// How the agent's previous run ended, as reported on registering
+    previous_shutdown: Option<ShutdownRecord>,
+    /// What the machine is used for; older agents do not say
+    machine_role: Option<String>,
+    /// Whether the connection is compressed with permessage-deflate
+    compressed: bool,
+}
+
+type Clients = Arc<Mutex<HashMap<String, ConnectedClient>>>;
+
+/// An alert nobody at an agent confirmed in time, as it reported with `confirmation_timeout`
+#[derive(Debug, Clone, Serialize)]
+struct TimedOut {
+    client_id: String,
+    timed_out_at: chrono::DateTime<chrono::Utc>,
+    /// The timeout passed while nobody was using the machine
+    idle: bool,
+}
+
+/// Confirmations and delivery statuses received for one alert, and its quorum if it has one
+#[derive(Default)]
+struct Delivery {
+    confirmations: Vec<Confirmation>,
+    /// Kept apart from `confirmations`, as nobody confirmed these
+    timeouts: Vec<TimedOut>,
+    statuses: Vec<DeliveryStatus>,
+    quorum: Option<QuorumTally>,
+    /// What the alert offered to answer with, counted in the report
+    response_options: Vec<ResponseOption>,
+    /// Clients whose records here came in an offline bundle rather than over a connection
+    offline_imports: HashSet<String>,
+}
+
+/// How many answered an alert with one of its response options
+#[derive(Debug, Clone, Serialize)]
+struct ResponseCount {
+    /// `None` for those who timed out or confirmed without choosing an option
+    response_id: Option<String>,
+    label: String,
+    count: usize,
+}
+
+/// Confirmations of an alert counted per response option, in the order the
+/// alert offered them, then those who timed out or chose no option. Empty
+/// for an alert that offered none.
+fn response_counts(
+    options: &[ResponseOption],
+    confirmations: &[Confirmation],
+    timeouts: usize,
+) -> Vec<ResponseCount> {
+    if options.is_empty() {
+        return Vec::new();
+    }
+    let chose = |id: &str| {
+        confirmations
+            .iter()
+            .filter(|c| c.response_id.as_deref() == Some(id))
+            .count()
+    };
+    let mut counts: Vec<ResponseCount> = options
+        .iter()
+        .map(|option| ResponseCount {
+            response_id: Some(option.id.clone()),
+            label: option.label.clone(),
+            count: chose(&option.id),
+        })
+        .collect();
+    counts.push(ResponseCount {
+        response_id: None,
+        label: "timed out / no option".to_string(),
+        count: confirmations
+            .iter()
+            .filter(|c| c.response_id.is_none())
+            .count()
+            + timeouts,
+    });
+    counts
+}
+
+impl Delivery {
+    fn response_counts(&self) -> Vec<ResponseCount> {
+        response_counts(
+            &self.response_options,
+            &self.confirmations,
+            self.timeouts.len(),
+        )
+    }
+}
+
+/// Deliveries so far, per alert, for the delivery report
+type Confirmations = Arc<Mutex<HashMap<Uuid, Delivery>>>;
+
+/// How long a sent alert stays active before it expires
+const ALERT_LIFETIME: chrono::Duration = chrono::Duration::minutes(30);
+
+/// Where an alert the server knows of stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AlertState {
+    Sent(chrono::DateTime<chrono::Utc>),
+    Cancelled,
+    /// Reported pending by an agent without this server having sent it,
+    /// e.g. raised locally or received from another server while unreachable
+    UnreachablePeriodPending,
+}
+
+/// Every alert sent or reported, for answering agents' pending syncs
+type Alerts = Arc<Mutex<HashMap<Uuid, AlertState>>>;
+
+/// Previews awaiting their agent's delivery status, by alert
+type Previews = Arc<Mutex<HashMap<Uuid, oneshot::Sender<DeliveryStatus>>>>;
+
+/// How long a preview waits for the agent's delivery status
+const PREVIEW_REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Highest bundle sequence imported from each agent, so none is imported twice
+type OfflineImports = Arc<Mutex<HashMap<String, u64>>>;
+
+/// What the server and its REST API work on
+#[derive(Clone, Default)]
+pub struct ServerState {
+    clients: Clients,
+    alerts: Alerts,
+    confirmations: Confirmations,
+    previews: Previews,
+    offline_imports: OfflineImports,
+}
+
+/// How long after stopping the server tells agents to expect it back, unless
+/// `--resume-after` says otherwise
+const DEFAULT_RESUME_AFTER: Duration = Duration::from_secs(60);
+
+/// How the server treats agents, from the command line
+pub struct Options {
+    /// Bearer token registrations must carry (`--token`)
+    pub token: Option<String>,
+    /// Ask agents that offer MessagePack to send it (`--msgpack`)
+    pub msgpack: bool,
+    /// How soon agents are told to expect the server back once it stops (`--resume-after`)
+    pub resume_after: Duration,
+    /// The clients each API key may preview alerts on (`--api-key`)
+    pub api_keys: HashMap<String, Vec<String>>,
+    /// How long a preview waits for the agent's delivery status
+    pub preview_timeout: Duration,
+    /// Key offline bundles are signed with (`OFFLINE_EXPORT_KEY`); without it none are imported
+    pub offline_key: Option<SigningKey>,
+    /// Agree to permessage-deflate with agents that offer it (`--ws-compression`)
+    pub ws_compression: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            token: None,
+            msgpack: false,
+            resume_after: DEFAULT_RESUME_AFTER,
+            api_keys: HashMap::new(),
+            preview_timeout: PREVIEW_REPORT_TIMEOUT,
+            offline_key: None,
+            ws_compression: false,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    let port: u16 = std::env::args()
+        .skip_while(|arg| arg != "--port")
+        .nth(1)
+        .map(|port| port.parse().expect("--port needs a port number"))
+        .unwrap_or(8080);
+
+    // Hand a bundle from an isolated site to the server running on `--port`
+    if std::env::args().nth(1).as_deref() == Some("import-offline") {
+        let bundle: String = std::env::args()
+            .nth(2)
+            .expect("usage: test_server import-offline <bundle.json> [--port <port>]");
+        post_offline_bundle(&bundle, port + 1).await;
+        return;
+    }
+
+    let addr: String = format!("127.0.0.1:{}", port);
+    let listener = TcpListener::bind(&addr).await.expect("Failed to bind");
+    println!("WebSocket server listening on: {}", addr);
+
+    let multicast: Option<MulticastSender> = std::env::args()
+        .any(|arg| arg == "--multicast")
+        .then(multicast_sender);
+
+    let options: Options = Options {
+        token: std::env::args().skip_while(|arg| arg != "--token").nth(1),
+        msgpack: std::env::args().any(|arg| arg == "--msgpack"),
+        ws_compression: std::env::args().any(|arg| arg == "--ws-compression"),
+        resume_after: std::env::args()
+            .skip_while(|arg| arg != "--resume-after")
+            .nth(1)
+            .map(|secs| {
+                Duration::from_secs(
+                    secs.parse()
+                        .expect("--resume-after needs a number of seconds"),
+                )
+            })
+            .unwrap_or(DEFAULT_RESUME_AFTER),
+        api_keys: std::env::args()
+            .collect::<Vec<String>>()
+            .windows(2)
+            .filter(|pair| pair[0] == "--api-key")
+            .map(|pair| api_key(&pair[1]))
+            .collect(),
+        offline_key: std::env::var("OFFLINE_EXPORT_KEY")
+            .ok()
+            .filter(|key| !key.is_empty())
+            .map(SigningKey::new),
+        ..Options::default()
+    };
+
+    let api_addr: String = format!("127.0.0.1:{}", port + 1);
+    let api_listener = TcpListener::bind(&api_addr)
+        .await
+        .expect("Failed to bind REST API");
+    println!("REST API listening on: http://{}", api_addr);
+
+    let state: ServerState = ServerState::default();
+
+    // Spawn a task to send periodic test alerts
+    tokio::spawn(send_test_alerts(state.clone(), multicast));
+
+    let shutdown: CancellationToken = CancellationToken::new();
+    let ctrl_c: CancellationToken = shutdown.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("\nStopping; telling agents when to expect the server back");
+            ctrl_c.cancel();
+        }
+    });
+
+    serve(listener, api_listener, state, Arc::new(options), shutdown).await;
+}
+
+/// An `--api-key <key>=<client>[,<client>…]` argument: the key and the clients it owns
+fn api_key(arg: &str) -> (String, Vec<String>) {
+    let (key, clients) = arg
+        .split_once('=')
+        .expect("--api-key needs <key>=<client>[,<client>...]");
+    (
+        key.to_string(),
+        clients.split(',').map(str::to_string).collect(),
+    )
+}
+
+/// Accept agents and serve the REST API until `shutdown`, then tell every
+/// agent when to expect the server back before closing its connection
+pub async fn serve(
+    listener: TcpListener,
+    api_listener: TcpListener,
+    state: ServerState,
+    options: Arc<Options>,
+    shutdown: CancellationToken,
+) {
+    let api: Router = Router::new()
+        .route("/suppressions", post(create_suppression))
+        .route("/suppressions/:id", delete(cancel_suppression))
+        .route("/alerts/:id", get(alert_deliveries).delete(cancel_alert))
+        .route("/api/clients/:id", get(client_details))
+        .route("/api/alerts/preview", post(preview_alert))
+        .route("/offline-imports", post(import_offline_bundle))
+        .layer(Extension(options.clone()))
+        .with_state(state.clone());
+    let api_shutdown: CancellationToken = shutdown.clone();
+    let api_task = tokio::spawn(async move {
+        if let Err(e) = axum::serve(api_listener, api)
+            .with_graceful_shutdown(api_shutdown.cancelled_owned())
+            .await
+        {
+            eprintln!("REST API stopped: {}", e);
+        }
+    });
+
+    // Cancelled once every agent has been told of the shutdown
+    let closing: CancellationToken = CancellationToken::new();
+    let sessions: TaskTracker = TaskTracker::new();
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                    sessions.spawn(handle_connection(
+                        stream,
+                        addr,
+                        state.clone(),
+                        options.clone(),
+                        closing.clone(),
+                    ));
+                }
+                Err(e) => {
+                    eprintln!("Failed to accept a connection: {}", e);
+                    break;
+                }
+            },
+        }
+    }
+
+    // Agents told when the server will be back wait for it instead of backing off
+    let resume_expected_at: chrono::DateTime<chrono::Utc> = chrono::Utc::now()
+        + chrono::Duration::from_std(options.resume_after).expect("--resume-after is too long");
+    broadcast(
+        &state.clients,
+        &AgentMessage::ServerShutdown {
+            resume_expected_at,
+            reason: Some("test server stopping".to_string()),
+        },
+    )
+    .await;
+    drop(listener);
+    closing.cancel();
+    sessions.close();
+    sessions.wait().await;
+    let _ = api_task.await;
+}
+
+/// A connected agent, with how its previous run ended
+async fn client_details(
+    State(ServerState { clients, .. }): State<ServerState>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let clients = clients.lock().await;
+    let client: &ConnectedClient = clients.get(&id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serde_json::json!({
+        "client_id": id,
+        "addr": client.addr.to_string(),
+        "standby": client.standby,
+        "heartbeat": client.heartbeat,
+        "previous_shutdown": client.previous_shutdown,
+        "compressed": client.compressed,
+    })))
+}
+
+/// Schedule a suppression window; agents outside its location ignore it
+async fn create_suppression(
+    State(ServerState { clients, .. }): State<ServerState>,
+    Json(window): Json<SuppressionWindow>,
+) -> StatusCode {
+    if window.ends_at <= window.starts_at {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    println!(
+        "\nScheduling suppression window {} from {} to {}: {}",
+        window.id, window.starts_at, window.ends_at, window.reason
+    );
+    broadcast(&clients, &AgentMessage::Suppression { window }).await;
+    StatusCode::ACCEPTED
+}
+
+/// End a suppression window early
+async fn cancel_suppression(
+    State(ServerState { clients, .. }): State<ServerState>,
+    Path(id): Path<Uuid>,
+) -> StatusCode {
+    println!("\nCancelling suppression window {}", id);
+    broadcast(&clients, &AgentMessage::CancelSuppression { id }).await;
+    StatusCode::ACCEPTED
+}
+
+/// Cancel an alert; agents still showing it withdraw it at their next pending sync
+async fn cancel_alert(
+    State(ServerState { alerts, .. }): State<ServerState>,
+    Path(id): Path<Uuid>,
+) -> StatusCode {
+    match alerts.lock().await.get_mut(&id) {
+        Some(state) => {
+            println!("\nCancelling alert {}", id);
+            *state = AlertState::Cancelled;
+            StatusCode::ACCEPTED
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+/// What the server has heard of an alert's delivery, with whether each
+/// record came in an offline bundle
+async fn alert_deliveries(
+    State(ServerState { confirmations, .. }): State<ServerState>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let confirmations = confirmations.lock().await;
+    let delivery: &Delivery = confirmations.get(&id).ok_or(StatusCode::NOT_FOUND)?;
+    let offline_import = |client_id: &str| delivery.offline_imports.contains(client_id);
+    Ok(Json(serde_json::json!({
+        "alert_id": id,
+        "confirmations": delivery.confirmations.iter().map(|confirmation| serde_json::json!({
+            "confirmation": confirmation,
+            "offline_import": offline_import(&confirmation.client_id),
+        })).collect::<Vec<_>>(),
+        "timeouts": delivery.timeouts,
+        "responses": delivery.response_counts(),
+        "statuses": delivery.statuses.iter().map(|status| serde_json::json!({
+            "status": status,
+            "offline_import": offline_import(&status.client_id),
+        })).collect::<Vec<_>>(),
+    })))
+}
+
+/// What importing an offline bundle added to the store
+#[derive(Debug, Default, Serialize)]
+pub struct ImportSummary {
+    pub client_id: String,
+    pub sequence: u64,
+    pub confirmations: usize,
+    pub statuses: usize,
+    /// Records the server already had
+    pub duplicates: usize,
+}
+
+/// Merge a signed offline bundle into the store, marking what it adds as
+/// imported. A bundle that fails its signature check, or is no newer than
+/// the last imported from its agent, is refused.
+///
+/// A confirmation is skipped if the server already has one for the alert
+/// from that client; a delivery status if it has the same one.
+pub async fn import_offline(
+    state: &ServerState,
+    key: &SigningKey,
+    signed: &[u8],
+) -> emns_agent::Result<ImportSummary> {
+    let mut imported = state.offline_imports.lock().await;
+    // Which sequence the bundle must follow is known only once it says whose it is
+    let client_id: String = offline::open_bundle(signed, key, None)?.client_id;
+    let bundle: OfflineBundle =
+        offline::open_bundle(signed, key, imported.get(&client_id).copied())?;
+
+    let mut summary: ImportSummary = ImportSummary {
+        client_id: bundle.client_id.clone(),
+        sequence: bundle.sequence,
+        ..ImportSummary::default()
+    };
+    let mut confirmations = state.confirmations.lock().await;
+    for record in bundle.records {
+        match record {
+            OfflineRecord::Confirmation { confirmation } => {
+                let delivery: &mut Delivery =
+                    confirmations.entry(confirmation.alert_id).or_default();
+                if delivery
+                    .confirmations
+                    .iter()
+                    .any(|known| known.client_id == confirmation.client_id)
+                {
+                    summary.duplicates += 1;
+                    continue;
+                }
+                delivery
+                    .offline_imports
+                    .insert(confirmation.client_id.clone());
+                delivery.confirmations.push(confirmation);
+                summary.confirmations += 1;
+            }
+            OfflineRecord::DeliveryStatus { status } => {
+                let delivery: &mut Delivery = confirmations.entry(status.alert_id).or_default();
+                if delivery.statuses.contains(&status) {
+                    summary.duplicates += 1;
+                    continue;
+                }
+                delivery.offline_imports.insert(status.client_id.clone());
+                delivery.statuses.push(status);
+                summary.statuses += 1;
+            }
+        }
+    }
+    imported.insert(bundle.client_id, bundle.sequence);
+    Ok(summary)
+}
+
+/// Import an offline bundle posted by `import-offline`
+async fn import_offline_bundle(
+    State(state): State<ServerState>,
+    Extension(options): Extension<Arc<Options>>,
+    bundle: Bytes,
+) -> Result<Json<ImportSummary>, (StatusCode, String)> {
+    let key: &SigningKey = options.offline_key.as_ref().ok_or((
+        StatusCode::NOT_IMPLEMENTED,
+        "OFFLINE_EXPORT_KEY is not set".to_string(),
+    ))?;
+    match import_offline(&state, key, &bundle).await {
+        Ok(summary) => {
+            println!(
+                "\nImported bundle {} from {}: {} confirmation(s), {} status(es), {} already known",
+                summary.sequence,
+                summary.client_id,
+                summary.confirmations,
+                summary.statuses,
+                summary.duplicates
+            );
+            Ok(Json(summary))
+        }
+        Err(e) => {
+            println!("\nRefused offline bundle: {}", e);
+            Err((StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))
+        }
+    }
+}
+
+/// `import-offline`: send the bundle at `path` to the server whose REST API is on `api_port`
+async fn post_offline_bundle(path: &str, api_port: u16) {
+    let bundle: Vec<u8> = std::fs::read(path).expect("Failed to read the bundle");
+    let response = reqwest::Client::new()
+        .post(format!("http://127.0.0.1:{}/offline-imports", api_port))
+        .body(bundle)
+        .send()
+        .await
+        .expect("Failed to reach the server's REST API");
+    let status = response.status();
+    let body: String = response.text().await.unwrap_or_default();
+    if status.is_success() {
+        println!("Imported {}: {}", path, body);
+    } else {
+        eprintln!("Import of {} refused ({}): {}", path, status, body);
+        std::process::exit(1);
+    }
+}
+
+/// An alert an operator is composing, to preview on their own machine
+#[derive(Deserialize)]
+struct PreviewRequest {
+    client_id: String,
+    title: String,
+    message: String,
+    level: AlertLevel,
+    #[serde(default)]
+    requires_confirmation: bool,
+    #[serde(default)]
+    sound_file: Option<String>,
+    #[serde(default)]
+    toast: Option<ToastOptions>,
+    #[serde(default)]
+    confirm_timeout_secs: Option<u32>,
+    #[serde(default)]
+    response_options: Option<Vec<ResponseOption>>,
+}
+
+/// How a preview was delivered: the agent's delivery status, unless none came in time
+#[derive(Serialize)]
+struct PreviewReport {
+    alert_id: Uuid,
+    client_id: String,
+    status: Option<DeliveryStatus>,
+    timed_out: bool,
+}
+
+/// Send a preview to one agent the caller's API key owns, and answer with
+/// its delivery status once it arrives or the wait runs out
+async fn preview_alert(
+    State(ServerState {
+        clients,
+        previews,
+        confirmations,
+        ..
+    }): State<ServerState>,
+    Extension(options): Extension<Arc<Options>>,
+    headers: HeaderMap,
+    Json(request): Json<PreviewRequest>,
+) -> Result<Json<PreviewReport>, StatusCode> {
+    let owned: &Vec<String> = headers
+        .get("x-api-key")
+        .and_then(|key| key.to_str().ok())
+        .and_then(|key| options.api_keys.get(key))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if !owned.contains(&request.client_id) {
+        println!(
+            "\nRefused preview on {}: not owned by the API key",
+            request.client_id
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let alert = Alert {
+        id: Uuid::new_v4(),
+        title: request.title,
+        message: request.message,
+        level: request.level,
+        requires_confirmation: request.requires_confirmation,
+        sound_file: request.sound_file,
+        timestamp: chrono::Utc::now(),
+        toast: request.toast,
+        confirm_timeout_secs: request.confirm_timeout_secs,
+        response_options: request.response_options,
+        is_preview: true,
+        ..Default::default()
+    };
+    let alert_id: Uuid = alert.id;
+    if let Some(response_options) = &alert.response_options {
+        confirmations.lock().await.insert(
+            alert_id,
+            Delivery {
+                response_options: response_options.clone(),
+                ..Delivery::default()
+            },
+        );
+    }
+    let (report_tx, report_rx) = oneshot::channel::<DeliveryStatus>();
+    previews.lock().await.insert(alert_id, report_tx);
+    let text: String = serde_json::to_string(&AgentMessage::Alert { alert }).unwrap();
+    let sent: bool = match clients.lock().await.get(&request.client_id) {
+        Some(client) => client.tx.send(text).await.is_ok(),
+        None => false,
+    };
+    if !sent {
+        previews.lock().await.remove(&alert_id);
+        return Err(StatusCode::NOT_FOUND);
+    }
+    println!("\nPreviewing alert {} on {}", alert_id, request.client_id);
+
+    let status: Option<DeliveryStatus> = tokio::time::timeout(options.preview_timeout, report_rx)
+        .await
+        .ok()
+        .and_then(Result::ok);
+    if status.is_none() {
+        previews.lock().await.remove(&alert_id);
+        println!(
+            "  No delivery status for preview {} within {}s",
+            alert_id,
+            options.preview_timeout.as_secs_f64()
+        );
+    }
+    Ok(Json(PreviewReport {
+        alert_id,
+        client_id: request.client_id,
+        timed_out: status.is_none(),
+        status,
+    }))
+}
+
+/// Sort the alerts an agent reports pending by what this server knows of them
+async fn pending_sync_result(alerts: &Alerts, pending_alert_ids: Vec<Uuid>) -> AgentMessage {
+    let now: chrono::DateTime<chrono::Utc> = chrono::Utc::now();
+    let mut alerts = alerts.lock().await;
+    let mut still_active: Vec<Uuid> = Vec::new();
+    let mut cancelled: Vec<Uuid> = Vec::new();
+    let mut expired: Vec<Uuid> = Vec::new();
+    for id in pending_alert_ids {
+        match alerts.get(&id) {
+            Some(AlertState::Sent(sent_at)) if now - *sent_at > ALERT_LIFETIME => expired.push(id),
+            Some(AlertState::Cancelled) => cancelled.push(id),
+            Some(_) => still_active.push(id),
+            None => {
+                println!(
+                    "Alert {} was pending while unreachable; not sent by this server",
+                    id
+                );
+                alerts.insert(id, AlertState::UnreachablePeriodPending);
+                still_active.push(id);
+            }
+        }
+    }
+    AgentMessage::PendingSyncResult {
+        still_active,
+        cancelled,
+        expired,
+    }
+}
+
+/// Send `message` to every connected agent, standby links included
+async fn broadcast(clients: &Clients, message: &AgentMessage) {
+    let text: String = serde_json::to_string(message).unwrap();
+    for (client_id, client) in clients.lock().await.iter() {
+        if let Err(e) = client.tx.send(text.clone()).await {
+            eprintln!("Failed to send to {}: {}", client_id, e);
+        }
+    }
+}
+
+/// Serve one agent until it disconnects or the server is `closing`
+async fn handle_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    state: ServerState,
+    options: Arc<Options>,
+    closing: CancellationToken,
+) {
+    println!("New connection from: {}", addr);
+    let ServerState {
+        clients,
+        alerts,
+        confirmations,
+        previews,
+        ..
+    } = state;
+
+    // Checked when the agent registers, so it is told why it was refused
+    let mut authorized: bool = options.token.is_none();
+    let (connection, compressed) = match upgrade(stream, addr, &options, &mut authorized).await {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            eprintln!("WebSocket handshake failed: {}", e);
+            return;
+        }
+    };
+    if compressed {
+        println!(
+            "Connection from {} is compressed with permessage-deflate",
+            addr
+        );
+    }
+
+    let Connection {
+        sink: mut write,
+        stream: mut read,
+    } = connection;
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<String>(100);
+
+    let mut client_id: Option<String> = None;
+
+    // Spawn task to handle outgoing messages
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if let Err(e) = write.send(Message::Text(msg)).await {
+                eprintln!("Failed to send message: {}", e);
+                return;
+            }
+        }
+        // Everything queued, a shutdown notice included, has gone out
+        let _ = write.send(Message::Close(None)).await;
+    });
+
+    // Handle incoming messages
+    loop {
+        let msg = tokio::select! {
+            _ = closing.cancelled() => break,
+            msg = read.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+        };
+        let parsed: Result<AgentMessage, String> = match msg {
+            Ok(Message::Text(text)) => {
+                println!("Received: {}", text);
+                serde_json::from_str(&text).map_err(|e| e.to_string())
+            }
+            Ok(Message::Binary(bytes)) => {
+                let parsed = AgentMessage::from_msgpack(&bytes).map_err(|e| e.to_string());
+                println!("Received ({} bytes of msgpack): {:?}", bytes.len(), parsed);
+                parsed
+            }
+            Ok(Message::Close(_)) => {
+                println!("Client {} disconnected", addr);
+                break;
+            }
+            Err(e) => {
+                eprintln!("WebSocket error: {}", e);
+                break;
+            }
+            _ => continue,
+        };
+
+        match parsed {
+            Ok(AgentMessage::Register {
+                client_id: id,
+                standby,
+                previous_shutdown,
+                machine_role,
+                supported_encodings,
+                confirmation_timeouts: offers_timeouts,
+                ..
+            }) => {
+                if !authorized {
+                    println!("Rejected client {} ({}): bad or missing token", id, addr);
+                    let rejected: String = serde_json::to_string(&AgentMessage::RegisterRejected {
+                        reason: "invalid or missing token".to_string(),
+                    })
+                    .unwrap();
+                    let _ = tx.send(rejected).await;
+                    break;
+                }
+                if let Some(record) = &previous_shutdown {
+                    if matches!(record.reason, ShutdownReason::Crash | ShutdownReason::Panic) {
+                        println!("Client {} restarted after a {:?}", id, record.reason);
+                    }
+                }
+                // A promoted standby link registers again and replaces its entry
+                clients.lock().await.insert(
+                    id.clone(),
+                    ConnectedClient {
+                        tx: tx.clone(),
+                        addr,
+                        heartbeat: HeartbeatStats::default(),
+                        standby,
+                        previous_shutdown,
+                        machine_role,
+                        compressed,
+                    },
+                );
+                let mode: &str = if standby { " on standby" } else { "" };
+                println!("Registered client: {} ({}){}", id, addr, mode);
+                client_id = Some(id);
+                let ack: String = serde_json::to_string(&AgentMessage::RegisterAck {
+                    server_name: Some("EMNS".to_string()),
+                    environment: Some("test".to_string()),
+                    encoding: (options.msgpack && supported_encodings.contains(&Encoding::Msgpack))
+                        .then_some(Encoding::Msgpack),
+                    server_version: Some(format!("test_server {}", env!("CARGO_PKG_VERSION"))),
+                    protocol_version: Some(PROTOCOL_VERSION),
+                    server_time: Some(chrono::Utc::now()),
+                    // Alerts go out bare; nothing here waits on acks
+                    envelopes: false,
+                    // Told apart from confirmations, by agents that can say which is which
+                    confirmation_timeouts: offers_timeouts,
+                })
+                .unwrap();
+                let _ = tx.send(ack).await;
+            }
+            Ok(AgentMessage::Confirmation { confirmation }) => {
+                println!("Received confirmation for alert: {}", confirmation.alert_id);
+                let alert_id: Uuid = confirmation.alert_id;
+                let mut confirmations = confirmations.lock().await;
+                let delivery: &mut Delivery = confirmations.entry(alert_id).or_default();
+                let quorum_met: Option<AgentMessage> = delivery
+                    .quorum
+                    .as_mut()
+                    .and_then(|tally| tally.record(&confirmation));
+                delivery.confirmations.push(confirmation);
+                print_delivery_report(alert_id, delivery);
+                if let Some(message) = quorum_met {
+                    // Only those still waiting on the alert need telling
+                    let text: String = serde_json::to_string(&message).unwrap();
+                    for (id, client) in clients.lock().await.iter() {
+                        if delivery.confirmations.iter().any(|c| c.client_id == *id) {
+                            continue;
+                        }
+                        if let Err(e) = client.tx.send(text.clone()).await {
+                            eprintln!("Failed to send to {}: {}", id, e);
+                        }
+                    }
+                }
+            }
+            Ok(AgentMessage::ConfirmationTimeout {
+                alert_id,
+                client_id: id,
+                timed_out_at,
+                idle,
+            }) => {
+                println!(
+                    "Alert {} timed out unconfirmed on {}{}",
+                    alert_id,
+                    id,
+                    if idle { " while idle" } else { "" }
+                );
+                let mut confirmations = confirmations.lock().await;
+                let delivery: &mut Delivery = confirmations.entry(alert_id).or_default();
+                delivery.timeouts.push(TimedOut {
+                    client_id: id,
+                    timed_out_at,
+                    idle,
+                });
+                print_delivery_report(alert_id, delivery);
+            }
+            Ok(AgentMessage::DeliveryStatus { status }) => {
+                println!(
+                    "Delivery status for alert {} from {}",
+                    status.alert_id, status.client_id
+                );
+                confirmations
+                    .lock()
+                    .await
+                    .entry(status.alert_id)
+                    .or_default()
+                    .statuses
+                    .push(status.clone());
+                // The first status for a preview is its delivery report
+                if let Some(report) = previews.lock().await.remove(&status.alert_id) {
+                    let _ = report.send(status);
+                }
+            }
+            Ok(AgentMessage::PendingSync { pending_alert_ids }) => {
+                println!("{} alerts pending on {}", pending_alert_ids.len(), addr);
+                let result: AgentMessage = pending_sync_result(&alerts, pending_alert_ids).await;
+                let _ = tx.send(serde_json::to_string(&result).unwrap()).await;
+            }
+            Ok(AgentMessage::Heartbeat { stats }) => {
+                println!("Heartbeat from {}", addr);
+                let ack: String =
+                    serde_json::to_string(&AgentMessage::HeartbeatAck { seq: stats.seq }).unwrap();
+                let _ = tx.send(ack).await;
+                if let Some(id) = &client_id {
+                    if let Some(client) = clients.lock().await.get_mut(id) {
+                        client.heartbeat = stats;
+                    }
+                }
+            }
+            Ok(AgentMessage::Unregister {
+                client_id: id,
+                reason,
+            }) => {
+                // Removed below when the connection closes
+                println!("Client {} is stopping ({:?})", id, reason);
+            }
+            Ok(AgentMessage::Error { context, detail }) => {
+                println!(
+                    "{} could not read a message ({}): {}",
+                    addr, detail, context
+                );
+            }
+            Ok(_) => {
+                println!("Unexpected message type");
+            }
+            Err(e) => {
+                println!("Unknown message: {}", e);
+            }
+        }
+    }
+
+    // Remove client on disconnect, unless it has since registered over another connection
+    if let Some(id) = client_id {
+        let mut clients = clients.lock().await;
+        if clients.get(&id).is_some_and(|client| client.addr == addr) {
+            clients.remove(&id);
+            println!("Removed client: {}", id);
+        }
+    }
+    drop(tx);
+    let _ = writer.await;
+}
+
+/// Take `stream`'s WebSocket upgrade, noting in `authorized` whether it
+/// carried the `--token` bearer token; also returns whether the connection is
+/// compressed, as it may be with `--ws-compression`
+async fn upgrade(
+    stream: TcpStream,
+    addr: SocketAddr,
+    options: &Options,
+    authorized: &mut bool,
+) -> Result<(Connection, bool), EmnsError> {
+    let peer: String = addr.to_string();
+    if options.ws_compression {
+        if let Some(token) = &options.token {
+            *authorized = peek_authorization(&stream).await == Some(format!("Bearer {}", token));
+        }
+        let limits: ReceiveLimits = ReceiveLimits {
+            max_message: DEFAULT_MAX_RECEIVE_MESSAGE_BYTES,
+            max_frame: DEFAULT_MAX_RECEIVE_FRAME_BYTES,
+        };
+        return compression::accept(&peer, stream, limits).await;
+    }
+
+    let check_token: TokenCheck = TokenCheck {
+        token: options.token.as_deref(),
+        authorized,
+    };
+    let (sink, stream) = accept_hdr_async(stream, check_token)
+        .await
+        .map_err(|e| EmnsError::connection(&peer, e))?
+        .split();
+    let stream_peer: String = peer.clone();
+    let connection: Connection = Connection {
+        sink: Box::pin(sink.sink_map_err(move |e| EmnsError::connection(&peer, e))),
+        stream: Box::pin(
+            stream.map(move |r| r.map_err(|e| EmnsError::connection(&stream_peer, e))),
+        ),
+    };
+    Ok((connection, false))
+}
+
+/// The `Authorization` header of the upgrade request waiting on `stream`,
+/// read without taking it off the stream, as the compressed handshake does
+/// not hand request headers on
+async fn peek_authorization(stream: &TcpStream) -> Option<String> {
+    let mut head: Vec<u8> = vec![0; 8192];
+    loop {
+        let len: usize = stream.peek(&mut head).await.ok()?;
+        if len == 0 {
+            return None;
+        }
+        if let Some(end) = head[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            return std::str::from_utf8(&head[..end])
+                .ok()?
+                .lines()
+                .skip(1)
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+                .map(|(_, value)| value.trim().to_string());
+        }
+        if len == head.len() {
+            return None;
+        }
+        // Peeking again returns at once with what is there; wait for the rest
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Notes whether an upgrade request carried the `--token` bearer token
+struct TokenCheck<'a> {
+    token: Option<&'a str>,
+    authorized: &'a mut bool,
+}
+
+impl Callback for TokenCheck<'_> {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        if let Some(token) = self.token {
+            *self.authorized = request
+                .headers()
+                .get("authorization")
+                .and_then(|value| value.to_str().ok())
+                == Some(format!("Bearer {}", token).as_str());
+        }
+        Ok(response)
+    }
+}
+
+/// Sender for `--multicast`, configured like the agent's listener
+fn multicast_sender() -> MulticastSender {
+    let group: Ipv4Addr = std::env::var("MULTICAST_GROUP")
+        .ok()
+        .and_then(|g| g.parse().ok())
+        .unwrap_or(Ipv4Addr::new(239, 255, 40, 1));
+    let key: String = std::env::var("MULTICAST_KEY").expect("--multicast needs MULTICAST_KEY");
+    let mut config: MulticastConfig = MulticastConfig::new(group, SigningKey::new(key));
+    if let Some(port) = std::env::var("MULTICAST_PORT")
+        .ok()
+        .and_then(|p| p.parse().ok())
+    {
+        config.port = port;
+    }
+    if let Some(interface) = std::env::var("MULTICAST_INTERFACE")
+        .ok()
+        .and_then(|i| i.parse().ok())
+    {
+        config.interface = interface;
+    }
+    println!("Broadcasting alerts to {}:{}", config.group, config.port);
+    MulticastSender::new(&config).expect("Failed to open multicast socket")
+}
+
+/// List connected agents with the details from their last heartbeat
+fn print_clients(clients: &HashMap<String, ConnectedClient>) {
+    let standby: usize = clients.values().filter(|client| client.standby).count();
+    println!(
+        "Connected clients: {} ({} on standby)",
+        clients.len(),
+        standby
+    );
+    let show = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    for (client_id, client) in clients {
+        let heartbeat: &HeartbeatStats = &client.heartbeat;
+        println!(
+            "  {} ({}{}): uptime {}s, last alert {}s ago, {} pending, connected since {}",
+            client_id,
+            client.addr,
+            if client.standby { ", standby" } else { "" },
+            show(heartbeat.uptime_secs.map(|s| s.to_string())),
+            show(heartbeat.last_alert_secs.map(|s| s.to_string())),
+            show(heartbeat.pending_confirmations.map(|n| n.to_string())),
+            show(heartbeat.connected_at.map(|t| t.to_rfc3339())),
+        );
+    }
+}
+
+/// Response latency across everyone who has confirmed `alert_id` so far,
+/// how many agents reported it timed out, how many chose each of its response
+/// options, and whether its quorum has been met
+fn print_delivery_report(alert_id: Uuid, delivery: &Delivery) {
+    let confirmations: &[Confirmation] = &delivery.confirmations;
+    match LatencySummary::from_confirmations(confirmations) {
+        Some(summary) => println!(
+            "  Alert {}: {} responded (p50 {:.1}s, p95 {:.1}s), {} timed out",
+            alert_id,
+            summary.responses,
+            summary.p50_ms as f64 / 1000.0,
+            summary.p95_ms as f64 / 1000.0,
+            summary.timed_out,
+        ),
+        None => println!(
+            "  Alert {}: no responses yet, {} confirmations without latency or timed out",
+            alert_id,
+            confirmations.len()
+        ),
+    }
+    if !delivery.timeouts.is_empty() {
+        let idle: usize = delivery.timeouts.iter().filter(|t| t.idle).count();
+        println!(
+            "  {} agent(s) reported it timed out unconfirmed, {} of them idle",
+            delivery.timeouts.len(),
+            idle
+        );
+    }
+    for count in delivery.response_counts() {
+        println!("  {}: {}", count.label, count.count);
+    }
+    if let Some(tally) = &delivery.quorum {
+        match tally.met_at() {
+            Some(met_at) => println!(
+                "  Quorum met at {} by {}",
+                met_at.to_rfc3339(),
+                tally.confirmed_by().join(", ")
+            ),
+            None => println!(
+                "  Quorum not met yet: {} acknowledged",
+                tally.confirmed_by().len()
+            ),
+        }
+    }
+}
+
+async fn send_test_alerts(state: ServerState, multicast: Option<MulticastSender>) {
+    let ServerState {
+        clients,
+        alerts,
+        confirmations,
+        ..
+    } = state;
+    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+
+    let test_alerts = vec![
+        (
+            "Info Alert",
+            "This is an informational message",
+            AlertLevel::Info,
+            false,
+        ),
+        (
+            "Warning Alert",
+            "This requires your attention",
+            AlertLevel::Warning,
+            true,
+        ),
+        (
+            "Critical Alert",
+            "Critical system event detected!",
+            AlertLevel::Critical,
+            true,
+        ),
+        (
+            "Emergency Alert",
+            "IMMEDIATE ACTION REQUIRED",
+            AlertLevel::Emergency,
+            true,
+        ),
+    ];
+
+    for (i, (title, message, level, requires_confirmation)) in test_alerts.into_iter().enumerate() {
+        tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+
+        // A two-person response: any two people confirming is enough
+        let quorum: Option<u32> = (level == AlertLevel::Critical).then_some(2);
+        // Kept off signage and kiosks, as a security incident would be
+        let visibility: Option<Vec<String>> =
+            (level == AlertLevel::Warning).then(|| vec!["workstation".to_string()]);
+        // A headcount: everyone says whether they are safe
+        let response_options: Option<Vec<ResponseOption>> =
+            (level == AlertLevel::Emergency).then(|| {
+                vec![
+                    ResponseOption {
+                        id: "safe".to_string(),
+                        label: "I am safe".to_string(),
+                        response: ConfirmationResponse::Acknowledged,
+                    },
+                    ResponseOption {
+                        id: "need_help".to_string(),
+                        label: "Need assistance".to_string(),
+                        response: ConfirmationResponse::CannotComply { note: None },
+                    },
+                ]
+            });
+        let alert = Alert {
+            id: Uuid::new_v4(),
+            title: title.to_string(),
+            message: message.to_string(),
+            level,
+            requires_confirmation,
+            sound_file: None,
+            timestamp: chrono::Utc::now(),
+            quorum,
+            visibility,
+            expires_at: Some(chrono::Utc::now() + ALERT_LIFETIME),
+            response_options,
+            ..Default::default()
+        };
+
+        if alert.quorum.is_some() || alert.response_options.is_some() {
+            confirmations.lock().await.insert(
+                alert.id,
+                Delivery {
+                    quorum: alert
+                        .quorum
+                        .map(|quorum| QuorumTally::new(alert.id, quorum)),
+                    response_options: alert.response_options.clone().unwrap_or_default(),
+                    ..Delivery::default()
+                },
+            );
+        }
+        if let Some(sender) = &multicast {
+            if let Err(e) = sender.send(&alert) {
+                eprintln!("Failed to broadcast alert: {}", e);
+            }
+        }
+
+        alerts
+            .lock()
+            .await
+            .insert(alert.id, AlertState::Sent(alert.timestamp));
+        // Agents that report no role decide for themselves
+        let skips = |client: &ConnectedClient| {
+            client
+                .machine_role
+                .as_deref()
+                .is_some_and(|role| !alert.visible_to(role))
+        };
+        let alert_str = serde_json::to_string(&AgentMessage::Alert {
+            alert: alert.clone(),
+        })
+        .unwrap();
+        println!("\nSending test alert {}: {}", i + 1, title);
+
+        let clients_lock = clients.lock().await;
+        print_clients(&clients_lock);
+        for (client_id, client) in clients_lock.iter() {
+            if skips(client) {
+                println!("  Not sending to {}: not visible to its role", client_id);
+                continue;
+            }
+            if let Err(e) = client.tx.send(alert_str.clone()).await {
+                eprintln!("Failed to send alert to {}: {}", client_id, e);
+            }
+        }
+    }
+
+    println!("\nAll test alerts sent. Server will continue running...");
+    println!("Press Ctrl+C to stop the server");
+}
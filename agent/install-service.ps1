@@ -10,7 +10,7 @@ param(
 )
 
 $ServiceName = "NotificationAgent"
-$ExePath = Join-Path $InstallPath "notification-agent.exe"
+$ExePath = Join-Path $InstallPath "emns-agent.exe"
 
 function Test-Administrator {
     $user = [Security.Principal.WindowsIdentity]::GetCurrent()
@@ -66,7 +66,7 @@ if (-not (Test-Path $SoundsDir)) {
 }
 
 # Copy executable
-$sourcePath = ".\target\release\notification-agent.exe"
+$sourcePath = ".\target\release\emns-agent.exe"
 if (-not (Test-Path $sourcePath)) {
     Write-Error "Executable not found at: $sourcePath"
     Write-Host "Please build the project first: cargo build --release"
@@ -102,6 +102,8 @@ nssm set $ServiceName AppEnvironmentExtra "SERVER_URL=$ServerUrl" "CLIENT_ID=$Cl
 nssm set $ServiceName DisplayName "Notification Agent"
 nssm set $ServiceName Description "Receives and displays alerts from notification server"
 nssm set $ServiceName Start SERVICE_AUTO_START
+# Start after the audio and network services, so alerts can be heard and the server reached
+nssm set $ServiceName DependOnService Audiosrv Tcpip Dnscache
 nssm set $ServiceName AppStdout "$InstallPath\logs\stdout.log"
 nssm set $ServiceName AppStderr "$InstallPath\logs\stderr.log"
 nssm set $ServiceName AppRotateFiles 1
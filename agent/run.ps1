@@ -28,13 +28,13 @@ if (-not (Test-Path $SoundsDir)) {
 }
 
 # Check if executable exists
-$exePath = ".\target\release\notification-agent.exe"
+$exePath = ".\target\release\emns-agent.exe"
 if (-not (Test-Path $exePath)) {
-    $exePath = ".\target\debug\notification-agent.exe"
+    $exePath = ".\target\debug\emns-agent.exe"
     if (-not (Test-Path $exePath)) {
         Write-Host "Building project..." -ForegroundColor Yellow
         cargo build
-        $exePath = ".\target\debug\notification-agent.exe"
+        $exePath = ".\target\debug\emns-agent.exe"
     }
 }
 
@@ -0,0 +1,1125 @@
+//! Top-level owner of the agent's components and background tasks
+
+use crate::annunciator::AnnunciatorSink;
+use crate::attachments::{self, AttachmentStore};
+use crate::attention::AttentionBackend;
+use crate::audio::AudioBackend;
+use crate::board::BoardChanges;
+use crate::broker::{self, SessionBroker, SessionMode};
+use crate::callback::CallbackSender;
+use crate::capabilities::{self, SelfCheck};
+use crate::capture::WireCapture;
+use crate::client::{self, WebSocketClient};
+use crate::clock::{ServerClock, SystemClock};
+use crate::config::Config;
+use crate::discovery::{DnsDiscovery, DnsResolver, ServerDiscovery, SystemResolver};
+use crate::error::{EmnsError, Result};
+use crate::handler::AlertHandler;
+use crate::health::{self, HostProbe, SystemProbe};
+use crate::history::AlertHistory;
+use crate::http_api::{HttpApi, HttpApiState};
+use crate::images::{self, ImageCache};
+use crate::maintenance::MaintenanceWindow;
+use crate::messages::{AgentStatus, Alert, Capabilities, ReceivedVia, ShutdownReason};
+use crate::multicast::MulticastListener;
+use crate::notification::{self, ActivationArgs, NotificationBackend};
+use crate::offline::{self, OfflineSpool};
+use crate::operator::OperatorIdentity;
+use crate::outage;
+use crate::outbound::OutboundQueue;
+use crate::power::PowerBackend;
+use crate::queue::AlertQueue;
+use crate::rate_limit::{self, AlertRateLimiter, RateDecision};
+use crate::retention;
+use crate::settings::SharedSettings;
+use crate::shutdown::ShutdownLog;
+use crate::sink::AlertSink;
+use crate::sound_pack::{self, SoundPacks};
+use crate::sounds::SoundLibrary;
+use crate::status::StatusCollector;
+use crate::storage::LineProtector;
+use crate::suppression::SuppressionWindows;
+use crate::timing::DeliveryTrace;
+use crate::transport::{Transport, TungsteniteTransport};
+use crate::update::{self, Updater};
+use crate::watchdog::{self, PipelineWatchdog, WatchdogConfig};
+use crate::watermark::Watermark;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Builder for [`Agent`]
+pub struct AgentBuilder {
+    config: Config,
+    notifier: Option<Arc<dyn NotificationBackend>>,
+    audio: Option<Arc<dyn AudioBackend>>,
+    transport: Option<Arc<dyn Transport>>,
+    power: Option<Arc<dyn PowerBackend>>,
+    attention: Option<Arc<dyn AttentionBackend>>,
+    system_probe: Option<Arc<dyn SystemProbe>>,
+    resolver: Option<Arc<dyn DnsResolver>>,
+    sinks: Vec<Arc<dyn AlertSink>>,
+    degraded_start: bool,
+}
+
+impl AgentBuilder {
+    /// Replace the toast backend
+    pub fn notification_backend(mut self, notifier: Arc<dyn NotificationBackend>) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Replace the sound backend
+    pub fn audio_backend(mut self, audio: Arc<dyn AudioBackend>) -> Self {
+        self.audio = Some(audio);
+        self
+    }
+
+    /// Replace the display power backend
+    pub fn power_backend(mut self, power: Arc<dyn PowerBackend>) -> Self {
+        self.power = Some(power);
+        self
+    }
+
+    /// Replace the fullscreen probe and taskbar flasher
+    pub fn attention_backend(mut self, attention: Arc<dyn AttentionBackend>) -> Self {
+        self.attention = Some(attention);
+        self
+    }
+
+    /// Replace the host health probe used in status reports
+    pub fn system_probe(mut self, probe: Arc<dyn SystemProbe>) -> Self {
+        self.system_probe = Some(probe);
+        self
+    }
+
+    /// Replace the DNS resolver used for server discovery
+    pub fn dns_resolver(mut self, resolver: Arc<dyn DnsResolver>) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// Also report delivered and resolved alerts to `sink`; may be called more than once
+    pub fn sink(mut self, sink: Arc<dyn AlertSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Replace the server transport
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Whether startup gave up waiting for the network or audio; if so the
+    /// self-check is rerun soon after start instead of at the usual interval
+    pub fn degraded_start(mut self, degraded: bool) -> Self {
+        self.degraded_start = degraded;
+        self
+    }
+
+    pub fn build(self) -> Agent {
+        let cancel: CancellationToken = CancellationToken::new();
+        let tracker: TaskTracker = TaskTracker::new();
+        let settings: SharedSettings = SharedSettings::new(self.config.settings.clone());
+        let outbound: Arc<OutboundQueue> =
+            Arc::new(OutboundQueue::new(self.config.outbound_queue_capacity));
+        let alert_queue: Arc<AlertQueue> = Arc::new(
+            AlertQueue::new(self.config.alert_queue_capacity)
+                .reporting_to(outbound.clone(), self.config.client_id.clone()),
+        );
+        let (activation_tx, activation_rx) = mpsc::unbounded_channel::<ActivationArgs>();
+        let (capabilities_tx, capabilities_rx) = watch::channel(Capabilities::all());
+        let watchdog: Arc<PipelineWatchdog> = Arc::new(PipelineWatchdog::new());
+        let broker: Option<Arc<SessionBroker>> = (self.config.session_mode == SessionMode::Broker)
+            .then(|| {
+                Arc::new(
+                    SessionBroker::new(
+                        self.config.client_id.clone(),
+                        client::get_hostname(),
+                        outbound.clone(),
+                    )
+                    .with_settings(settings.clone()),
+                )
+            });
+
+        let suppressions: SuppressionWindows = match &self.config.suppression_file {
+            Some(path) => SuppressionWindows::open(path).unwrap_or_else(|e| {
+                log::warn!("Keeping suppression windows in memory only: {}", e);
+                SuppressionWindows::new()
+            }),
+            None => SuppressionWindows::new(),
+        };
+        let board_changes: BoardChanges = BoardChanges::new();
+        let suppressions: Arc<SuppressionWindows> = Arc::new(
+            suppressions
+                .allow_emergency(self.config.allow_emergency_suppression)
+                .with_changes(board_changes.clone()),
+        );
+
+        let history: AlertHistory = match &self.config.history_file {
+            Some(path) => {
+                AlertHistory::open_protected(path, LineProtector::platform(self.config.dpapi_scope))
+                    .unwrap_or_else(|e| {
+                        log::warn!("Keeping alert history in memory only: {}", e);
+                        AlertHistory::new()
+                    })
+            }
+            None => AlertHistory::new(),
+        };
+
+        let sounds: SoundLibrary = SoundLibrary::open(&self.config.sounds_dir);
+
+        let attachments: Arc<AttachmentStore> = Arc::new(AttachmentStore::new(
+            &self.config.data_dir,
+            &self.config.attachments,
+        ));
+        let images: Arc<ImageCache> =
+            Arc::new(ImageCache::new(&self.config.data_dir, &self.config.images));
+
+        // Confirmations are stamped by the server's time once registration has measured the skew
+        let server_clock: Arc<ServerClock> = Arc::new(ServerClock::new(Arc::new(SystemClock)));
+
+        let mut handler = AlertHandler::builder(outbound.clone(), self.config.client_id.clone())
+            .clock(server_clock.clone())
+            .sound_library(sounds.clone())
+            .settings(settings.clone())
+            .text_limits(self.config.text_limits)
+            .history(history)
+            .suppressions(suppressions.clone())
+            .display_wake_cap(self.config.display_wake_cap)
+            .idle_extension(self.config.idle_auto_confirm_extension)
+            .pause_while_locked(self.config.pause_auto_confirm_while_locked)
+            .superseded_pending(self.config.superseded_pending)
+            .escalation(self.config.escalation.clone())
+            .burst_coalescing(self.config.burst)
+            .deduplicate(self.config.dedup)
+            .toast_styles(self.config.toast_styles)
+            .toast_activations(activation_tx.clone())
+            .attachment_store(attachments.clone())
+            .image_cache(images.clone())
+            .callback_sender(Arc::new(CallbackSender::new(&self.config.callbacks)))
+            .reminders(self.config.reminder.clone())
+            .machine_role(self.config.machine_role.clone())
+            .groups(self.config.groups.clone())
+            .categories(self.config.categories.clone())
+            .locale(self.config.locale.clone())
+            .hidden_alert_placeholder(self.config.hidden_alert_placeholder)
+            .capabilities(capabilities_rx.clone())
+            .board_changes(board_changes)
+            .watchdog(watchdog.clone())
+            .cancellation(cancel.child_token())
+            .task_tracker(tracker.clone());
+        if let Some(notifier) = self.notifier {
+            handler = handler.notification_backend(notifier);
+        }
+        if let Some(audio) = self.audio {
+            handler = handler.audio_backend(audio);
+        }
+        if let Some(power) = self.power {
+            handler = handler.power_backend(power);
+        }
+        if let Some(attention) = self.attention {
+            handler = handler.attention_backend(attention);
+        }
+        if let Some(operator) = &self.config.operator_identity {
+            handler = handler.operator_identity(Arc::new(OperatorIdentity::new(operator.clone())));
+        }
+        if let Some(annunciator) = &self.config.annunciator {
+            handler = handler.sink(Arc::new(
+                AnnunciatorSink::new(annunciator, outbound.clone(), &self.config.client_id)
+                    .with_cancellation(cancel.child_token())
+                    .with_task_tracker(tracker.clone()),
+            ));
+        }
+        for sink in self.sinks {
+            handler = handler.sink(sink);
+        }
+        let handler: Arc<AlertHandler> = Arc::new(handler.build());
+        let self_check: Arc<SelfCheck> = Arc::new(SelfCheck::new(
+            handler.notification_backend().clone(),
+            handler.audio_backend().clone(),
+            &self.config.data_dir,
+        ));
+
+        let rate_limiter: Arc<AlertRateLimiter> =
+            Arc::new(AlertRateLimiter::new(self.config.alert_rate));
+        let maintenance: Arc<MaintenanceWindow> = Arc::new(MaintenanceWindow::new());
+        let updater: Option<Arc<Updater>> =
+            self.config
+                .update
+                .as_ref()
+                .and_then(|update| match std::env::current_exe() {
+                    Ok(exe) => Some(Arc::new(Updater::new(
+                        update.clone(),
+                        &self.config.data_dir,
+                        exe,
+                    ))),
+                    Err(e) => {
+                        log::warn!(
+                            "Self-update disabled: cannot locate the agent binary: {}",
+                            e
+                        );
+                        None
+                    }
+                });
+        let mut status: StatusCollector = StatusCollector::new(
+            self.config.client_id.clone(),
+            alert_queue.clone(),
+            outbound.clone(),
+        )
+        .with_rate_limiter(rate_limiter.clone())
+        .with_maintenance(maintenance.clone());
+        if self.config.watchdog.is_some() {
+            status = status.with_watchdog(watchdog.clone());
+        }
+        // In broker mode the helpers handle alerts, so the local handler has nothing to report
+        if broker.is_none() {
+            status = status.with_handler_stats(handler.stats().clone());
+        }
+        if let Some(updater) = &updater {
+            status = status.with_updater(updater.clone());
+        }
+        status = status.with_sounds(sounds.clone());
+        if broker.is_none() {
+            status = status.with_delivery_timings(handler.delivery_timings().clone());
+        }
+        let status: Arc<StatusCollector> = Arc::new(status);
+
+        let mut client: WebSocketClient = WebSocketClient::new(
+            self.config.server_url.clone(),
+            self.config.client_id.clone(),
+            client::get_hostname(),
+        )
+        .with_outbound_queue(outbound.clone())
+        .with_status(status.clone())
+        .with_settings(settings.clone())
+        .with_fallbacks(self.config.fallback_server_urls.clone())
+        .with_primary_retry(self.config.primary_retry)
+        .with_reconnect_backoff(self.config.reconnect_backoff.clone())
+        .with_server_timeout(self.config.server_timeout)
+        .with_heartbeat_missed_acks(self.config.heartbeat_missed_acks)
+        .with_max_unreadable_messages(self.config.max_unreadable_messages)
+        .with_send_timeout(self.config.send_timeout)
+        .with_register_timeout(self.config.register_timeout)
+        .with_location(self.config.location.clone())
+        .with_machine_role(self.config.machine_role.clone())
+        .with_groups(self.config.groups.clone())
+        .with_categories(self.config.categories.names())
+        .with_standby(self.config.standby_server_url.clone())
+        .with_suppressions(suppressions)
+        .with_maintenance(maintenance)
+        .with_alert_key(self.config.alert_key.clone())
+        .with_signing_key(self.config.alert_hmac_key.clone())
+        .with_capabilities(capabilities_rx)
+        .with_delivery_timings(handler.delivery_timings().clone())
+        .with_server_clock(server_clock);
+        let shutdown_log: ShutdownLog = ShutdownLog::new(self.config.shutdown_file.clone());
+        client = client.with_previous_shutdown(shutdown_log.take());
+        if let Some(file) = &self.config.watermark_file {
+            client = client.with_watermark(Arc::new(Watermark::load(file)));
+        }
+        // In broker mode the helpers hold the pending alerts, not this handler
+        if broker.is_none() {
+            client = client.with_pending_sync(handler.clone());
+        }
+        if let Some(transport) = self.transport {
+            client = client.with_transport(transport);
+        } else {
+            let transport: TungsteniteTransport =
+                match TungsteniteTransport::with_tls(&self.config.tls) {
+                    Ok(transport) => transport,
+                    Err(e) => {
+                        log::error!("Trusting the system store only for wss:// servers: {}", e);
+                        TungsteniteTransport::default()
+                    }
+                };
+            client = client.with_transport(Arc::new(
+                transport
+                    .with_auth_token(self.config.auth_token.as_ref())
+                    .with_connect_timeout(self.config.connect_timeout)
+                    .with_receive_limits(
+                        self.config.max_receive_message_bytes,
+                        self.config.max_receive_frame_bytes,
+                    )
+                    .with_compression(self.config.ws_compression),
+            ));
+        }
+        if let Some(updater) = &updater {
+            client = client.with_updater(updater.clone());
+        }
+        let sound_packs: Arc<SoundPacks> = Arc::new(SoundPacks::new(sounds.clone()));
+        client = client.with_sound_packs(sound_packs.clone());
+        if let Some(capture) = &self.config.wire_capture {
+            client = client.with_wire_capture(Arc::new(WireCapture::new(capture.clone())));
+        }
+        if let ServerDiscovery::Dns {
+            domain,
+            fallback_url,
+        } = &self.config.server_discovery
+        {
+            let resolver: Arc<dyn DnsResolver> = self
+                .resolver
+                .unwrap_or_else(|| Arc::new(SystemResolver::new()));
+            client = client.with_discovery(
+                DnsDiscovery::new(domain.clone(), resolver).with_fallback(fallback_url.clone()),
+            );
+        }
+
+        let system_probe: Arc<dyn SystemProbe> = self
+            .system_probe
+            .unwrap_or_else(|| Arc::new(HostProbe::new(self.config.data_dir.clone())));
+
+        Agent {
+            config: self.config,
+            cancel,
+            tracker,
+            handler,
+            client: Arc::new(client),
+            alert_queue,
+            rate_limiter,
+            watchdog,
+            outbound,
+            status,
+            system_probe,
+            self_check,
+            first_recheck: if self.degraded_start {
+                capabilities::DEGRADED_RECHECK_AFTER
+            } else {
+                capabilities::RECHECK_INTERVAL
+            },
+            capabilities: capabilities_tx,
+            attachments,
+            images,
+            sounds,
+            settings,
+            http_addr: None,
+            multicast_addr: None,
+            broker,
+            updater,
+            sound_packs,
+            shutdown_log,
+            activation_tx,
+            pending_start: Some(activation_rx),
+        }
+    }
+}
+
+/// Owns the handler and client and the tasks that drive them.
+///
+/// Every task is spawned on one [`TaskTracker`] and stops when the agent's
+/// [`CancellationToken`] (or one of its children) is cancelled.
+pub struct Agent {
+    config: Config,
+    cancel: CancellationToken,
+    tracker: TaskTracker,
+    handler: Arc<AlertHandler>,
+    client: Arc<WebSocketClient>,
+    alert_queue: Arc<AlertQueue>,
+    /// Sheds alerts arriving faster than the configured rate before they are handled
+    rate_limiter: Arc<AlertRateLimiter>,
+    /// Progress of the alert processing loop
+    watchdog: Arc<PipelineWatchdog>,
+    outbound: Arc<OutboundQueue>,
+    status: Arc<StatusCollector>,
+    system_probe: Arc<dyn SystemProbe>,
+    self_check: Arc<SelfCheck>,
+    /// Wait before the self-check first runs again
+    first_recheck: Duration,
+    /// Latest self-check, read by the handler and the client
+    capabilities: watch::Sender<Capabilities>,
+    attachments: Arc<AttachmentStore>,
+    /// Images shown in toasts, trimmed by age and size
+    images: Arc<ImageCache>,
+    /// Sound files alerts play; a new set can be installed while the agent runs
+    sounds: SoundLibrary,
+    settings: SharedSettings,
+    http_addr: Option<SocketAddr>,
+    multicast_addr: Option<SocketAddr>,
+    /// Session helpers alerts are forwarded to in broker mode
+    broker: Option<Arc<SessionBroker>>,
+    /// Stages signed agent releases, when self-update is enabled
+    updater: Option<Arc<Updater>>,
+    /// Installs the sound packs the server offers
+    sound_packs: Arc<SoundPacks>,
+    /// Where how this run ends is recorded for the next one to report
+    shutdown_log: ShutdownLog,
+    activation_tx: mpsc::UnboundedSender<ActivationArgs>,
+    /// Taken by the first call to [`Agent::start`]
+    pending_start: Option<mpsc::UnboundedReceiver<ActivationArgs>>,
+}
+
+impl Agent {
+    /// Start building an agent with the default toast and sound backends
+    pub fn builder(config: Config) -> AgentBuilder {
+        AgentBuilder {
+            config,
+            notifier: None,
+            audio: None,
+            transport: None,
+            power: None,
+            attention: None,
+            system_probe: None,
+            resolver: None,
+            sinks: Vec::new(),
+            degraded_start: false,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Runtime settings; changes apply to every component
+    pub fn settings(&self) -> &SharedSettings {
+        &self.settings
+    }
+
+    pub fn handler(&self) -> &Arc<AlertHandler> {
+        &self.handler
+    }
+
+    /// Sound files alerts play from
+    pub fn sounds(&self) -> &SoundLibrary {
+        &self.sounds
+    }
+
+    /// Queue that feeds alerts into the handler, bypassing the server connection
+    pub fn alert_queue(&self) -> &Arc<AlertQueue> {
+        &self.alert_queue
+    }
+
+    /// Current queue depths, as reported to the server
+    pub fn status(&self) -> AgentStatus {
+        self.status.collect()
+    }
+
+    /// What the latest self-check found this machine lets the agent do
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities.borrow().clone()
+    }
+
+    /// Root token; cancelling it stops every task
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancel
+    }
+
+    pub fn task_tracker(&self) -> &TaskTracker {
+        &self.tracker
+    }
+
+    /// Address the local HTTP API is listening on, once started
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http_addr
+    }
+
+    /// Address the multicast fallback listener is bound to, once started
+    pub fn multicast_addr(&self) -> Option<SocketAddr> {
+        self.multicast_addr
+    }
+
+    /// Session broker, when running in broker mode
+    pub fn broker(&self) -> Option<&Arc<SessionBroker>> {
+        self.broker.as_ref()
+    }
+
+    /// Self-update, when enabled; the agent should exit with
+    /// [`RESTART_EXIT_CODE`](crate::update::RESTART_EXIT_CODE) once it asks for a restart
+    pub fn updater(&self) -> Option<&Arc<Updater>> {
+        self.updater.as_ref()
+    }
+
+    /// Where how this run ends is recorded; [`shutdown`](Self::shutdown) records
+    /// clean stops and restarts for updates
+    pub fn shutdown_log(&self) -> &ShutdownLog {
+        &self.shutdown_log
+    }
+
+    /// Sender for toast clicks; the default toast backend reports through it
+    pub fn toast_activations(&self) -> mpsc::UnboundedSender<ActivationArgs> {
+        self.activation_tx.clone()
+    }
+
+    /// Spawn the alert processing loop, the server connection, and the local HTTP API.
+    ///
+    /// Calling this more than once has no effect.
+    pub fn start(&mut self) -> Result<()> {
+        let Some(activation_rx) = self.pending_start.take() else {
+            log::warn!("Agent already started");
+            return Ok(());
+        };
+
+        // Before connecting, so the first registration reports it
+        let found: Capabilities = self.self_check.run();
+        capabilities::log_findings(&found);
+        self.capabilities.send_replace(found);
+
+        // Bind first so a bad listen address fails startup before anything runs
+        if let Some(http_config) = &self.config.http_api {
+            let api: HttpApi = HttpApi::bind(
+                http_config,
+                HttpApiState {
+                    client_id: self.config.client_id.clone(),
+                    alert_queue: self.alert_queue.clone(),
+                    outbound: self.outbound.clone(),
+                    status: self.status.clone(),
+                    local_alert_token: http_config.local_alert_token.as_deref().map(Arc::from),
+                    forward_local_alerts: http_config.forward_local_alerts,
+                    // In broker mode the helpers hold the alerts, not this handler
+                    handler: self.broker.is_none().then(|| self.handler.clone()),
+                    board_recent: http_config.board_recent,
+                    board_origins: Arc::from(http_config.board_origins.clone()),
+                },
+            )?;
+            self.http_addr = Some(api.local_addr()?);
+            let cancel: CancellationToken = self.cancel.child_token();
+            self.tracker.spawn(async move {
+                if let Err(e) = api.serve(cancel).await {
+                    log::error!("Local HTTP API failed: {}", e);
+                }
+            });
+        }
+
+        // Multicast fallback for when the server is unreachable
+        match (&self.config.multicast, &self.broker) {
+            (Some(_), Some(_)) => {
+                log::warn!("Multicast fallback is not available in broker mode; not listening")
+            }
+            (Some(multicast_config), None) => {
+                let listener: MulticastListener = MulticastListener::bind(multicast_config)?
+                    .with_location(self.config.location.clone());
+                self.multicast_addr = Some(listener.local_addr()?);
+                let handler: Arc<AlertHandler> = self.handler.clone();
+                let cancel: CancellationToken = self.cancel.child_token();
+                self.tracker.spawn(async move {
+                    if let Err(e) = listener.run(handler, cancel).await {
+                        log::error!("Multicast listener failed: {}", e);
+                    }
+                });
+            }
+            (None, _) => {}
+        }
+
+        // Broker mode: helpers in each session connect over a named pipe
+        if let Some(session_broker) = &self.broker {
+            let listener = broker::listen(
+                session_broker.clone(),
+                self.config.session_pipe_name.clone(),
+                self.cancel.child_token(),
+                self.tracker.clone(),
+            );
+            self.tracker.spawn(async move {
+                if let Err(e) = listener.await {
+                    log::error!("Session broker failed: {}", e);
+                }
+            });
+            self.tracker.spawn(broker::launch_helpers(
+                session_broker.clone(),
+                self.cancel.child_token(),
+            ));
+        }
+
+        // Alert processing loop, restarted by the watchdog if it stalls and that is enabled
+        let watchdog_config: Option<WatchdogConfig> = self.config.watchdog;
+        let alert_loop: AlertLoop = AlertLoop {
+            handler: self.handler.clone(),
+            session_broker: self.broker.clone(),
+            alert_queue: self.alert_queue.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            outbound: self.outbound.clone(),
+            client_id: self.config.client_id.clone(),
+            watchdog: self.watchdog.clone(),
+            report_every: watchdog_config.unwrap_or_default().check_interval(),
+        };
+        let cancel: CancellationToken = self.cancel.child_token();
+        let mut pipeline: JoinHandle<()> =
+            self.tracker.spawn(alert_loop.clone().run(cancel.clone()));
+        if let Some(config) = watchdog_config {
+            let alert_queue: Arc<AlertQueue> = self.alert_queue.clone();
+            let tracker: TaskTracker = self.tracker.clone();
+            self.tracker.spawn(watchdog::run(
+                self.watchdog.clone(),
+                config,
+                move || alert_queue.depth(),
+                move || {
+                    if config.restart {
+                        // A loop wedged in blocking code keeps its thread until it returns
+                        pipeline.abort();
+                        pipeline = tracker.spawn(alert_loop.clone().run(cancel.clone()));
+                    }
+                },
+                self.cancel.child_token(),
+            ));
+        }
+
+        // Toast clicks: open details windows and confirm from buttons
+        self.tracker.spawn(run_activation_loop(
+            self.handler.clone(),
+            activation_rx,
+            self.cancel.child_token(),
+            self.tracker.clone(),
+        ));
+
+        // Host health for status reports, sampled off the report path
+        self.tracker.spawn(health::run_sampler(
+            self.system_probe.clone(),
+            self.status.clone(),
+            self.settings.clone(),
+            self.cancel.child_token(),
+        ));
+
+        // Capabilities that come and go, e.g. a headset being plugged in
+        self.tracker.spawn(capabilities::run_rechecks(
+            self.self_check.clone(),
+            self.capabilities.clone(),
+            self.first_recheck,
+            self.cancel.child_token(),
+        ));
+
+        // Downloaded attachments past their retention
+        self.tracker.spawn(attachments::run_sweeper(
+            self.attachments.clone(),
+            self.config.attachments.retention,
+            self.cancel.child_token(),
+        ));
+
+        // Cached toast images past their retention or over the cache size
+        self.tracker.spawn(images::run_sweeper(
+            self.images.clone(),
+            self.cancel.child_token(),
+        ));
+
+        // Aged history and the attachments only it referenced
+        self.tracker.spawn(retention::run_pruner(
+            self.handler.clone(),
+            self.attachments.clone(),
+            self.config.retention,
+            self.cancel.child_token(),
+        ));
+
+        // Confirmations and reports held for export while the server stays unreachable
+        if let Some(offline_config) = &self.config.offline {
+            let spool: OfflineSpool = OfflineSpool::open_protected(
+                &self.config.data_dir,
+                LineProtector::platform(self.config.dpapi_scope),
+            )?;
+            self.tracker.spawn(offline::run_spooler(
+                Arc::new(spool),
+                self.outbound.clone(),
+                self.client.connection_state(),
+                offline_config.clone(),
+                self.cancel.child_token(),
+            ));
+        }
+
+        // Toasts when the server has been unreachable a while, and when it is back;
+        // in broker mode the service has no desktop to show them on
+        if let (Some(grace), None) = (self.config.connection_notice_after, &self.broker) {
+            self.tracker.spawn(outage::run_notices(
+                self.client.connection_state(),
+                self.handler.notification_backend().clone(),
+                grace,
+                self.cancel.child_token(),
+            ));
+        }
+
+        // Signed agent releases, and restarts into them in the restart window
+        if let Some(updater) = &self.updater {
+            self.tracker.spawn(update::run_updates(
+                updater.clone(),
+                // In broker mode the helpers hold the alerts, so it cannot tell when restarting is safe
+                self.broker.is_none().then(|| self.handler.clone()),
+                self.cancel.child_token(),
+            ));
+        }
+
+        // Sound packs the server offers
+        self.tracker.spawn(sound_pack::run_sound_packs(
+            self.sound_packs.clone(),
+            self.cancel.child_token(),
+        ));
+
+        // Server connection (reconnects on failures)
+        let client: Arc<WebSocketClient> = self.client.clone();
+        let alert_queue: Arc<AlertQueue> = self.alert_queue.clone();
+        let cancel: CancellationToken = self.cancel.child_token();
+        self.tracker.spawn(async move {
+            if let Err(e) = client.run(alert_queue, cancel).await {
+                log::error!("WebSocket client failed: {}", e);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Cancel every task and wait for them to finish, and record the stop for
+    /// the next run to report: as an update if the updater asked for a restart,
+    /// otherwise as clean.
+    ///
+    /// Returns `false` if tasks were still running when `timeout` elapsed.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        log::info!("Shutting down agent");
+        self.cancel.cancel();
+        self.tracker.close();
+        let restarting: bool = self
+            .updater
+            .as_ref()
+            .is_some_and(|updater| updater.restart_requested().is_cancelled());
+        self.shutdown_log.record(if restarting {
+            ShutdownReason::Update
+        } else {
+            ShutdownReason::Clean
+        });
+
+        match tokio::time::timeout(timeout, self.tracker.wait()).await {
+            Ok(()) => true,
+            Err(_) => {
+                log::warn!(
+                    "{} task(s) still running after {:?}",
+                    self.tracker.len(),
+                    timeout
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Takes alerts off the queue, sheds those over the rate limit, and hands the rest on
+#[derive(Clone)]
+struct AlertLoop {
+    handler: Arc<AlertHandler>,
+    /// Helpers to forward alerts to in broker mode
+    session_broker: Option<Arc<SessionBroker>>,
+    alert_queue: Arc<AlertQueue>,
+    rate_limiter: Arc<AlertRateLimiter>,
+    outbound: Arc<OutboundQueue>,
+    client_id: String,
+    watchdog: Arc<PipelineWatchdog>,
+    /// How often an idle loop tells the watchdog it is still alive
+    report_every: Duration,
+}
+
+impl AlertLoop {
+    async fn run(self, cancel: CancellationToken) {
+        let mut idle: tokio::time::Interval = tokio::time::interval(self.report_every);
+        loop {
+            self.watchdog.progress();
+            let (alert, mut trace): (Alert, DeliveryTrace) = tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = idle.tick() => continue,
+                queued = self.alert_queue.recv_traced() => queued,
+            };
+            trace.dequeued = Some(self.handler.delivery_timings().now());
+            let alert: Alert = match self.rate_limiter.admit(&alert, Instant::now()) {
+                RateDecision::Admit => alert,
+                RateDecision::Shed => {
+                    self.handler.record_rate_limited(alert);
+                    continue;
+                }
+                RateDecision::ShedOverloaded => {
+                    self.handler.record_rate_limited(alert);
+                    log::warn!("Alerts are arriving faster than the rate limit allows");
+                    self.outbound.push(rate_limit::overload_error(
+                        &self.client_id,
+                        &self.rate_limiter.config(),
+                    ));
+                    rate_limit::overload_warning(&self.rate_limiter.config())
+                }
+            };
+            if let Some(session_broker) = &self.session_broker {
+                // Helpers have no suppression windows of their own
+                if !self.handler.suppress(&alert) {
+                    session_broker.dispatch(&alert);
+                }
+            } else if let Err(e) = self
+                .handler
+                .handle_alert_traced(alert, ReceivedVia::WebSocket, trace)
+                .await
+            {
+                log::error!("Failed to handle alert: {}", e);
+            }
+        }
+        log::debug!("Alert processing loop stopped");
+    }
+}
+
+/// Handle toast clicks until `cancel` fires or every sender is gone
+pub(crate) async fn run_activation_loop(
+    handler: Arc<AlertHandler>,
+    mut activation_rx: mpsc::UnboundedReceiver<ActivationArgs>,
+    cancel: CancellationToken,
+    tracker: TaskTracker,
+) {
+    loop {
+        let activation: ActivationArgs = tokio::select! {
+            _ = cancel.cancelled() => break,
+            activation = activation_rx.recv() => match activation {
+                Some(activation) => activation,
+                None => break,
+            },
+        };
+        let alert_id: uuid::Uuid = activation.alert_id;
+        match notification::dispatch_activation(&handler, activation, &cancel, &tracker).await {
+            Ok(()) => {}
+            Err(e @ EmnsError::StaleAlert { .. }) => log::warn!("Ignoring toast click: {}", e),
+            Err(e) => log::error!("Toast action for alert {} failed: {}", alert_id, e),
+        }
+    }
+    log::debug!("Toast activation loop stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{
+        AlertErrorReason, AlertLevel, Confirmation, ConfirmationResponse, DeliveryOutcome,
+        HeartbeatStats, Message, ResponseOption,
+    };
+    use crate::notification::{NotificationManager, ToastAction};
+    use crate::outbound::OutboundMessage;
+    use crate::test_support::{Confirmations, MockAudio, MockNotifier};
+    use crate::transport::memory::{MemoryPeer, MemoryTransport};
+
+    #[tokio::test]
+    async fn test_shutdown_leaves_no_tasks() {
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let mut agent: Agent = Agent::builder(Config::new("ws://127.0.0.1:9/ws", "test-client"))
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .build();
+        agent.start().unwrap();
+
+        // A confirmation-required alert leaves an auto-confirm timer running
+        let alert: Alert = crate::test_support::alert(AlertLevel::Warning, true);
+        agent.alert_queue().try_push(alert).unwrap();
+        while notifier.shown().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(agent.task_tracker().len(), 11);
+        assert_eq!(audio.played().len(), 1);
+        assert_eq!(agent.status().alert_queue_depth, 0);
+
+        assert!(agent.shutdown(Duration::from_secs(5)).await);
+        assert!(agent.task_tracker().is_closed());
+        assert!(agent.task_tracker().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_confirm_button_confirms_pending_alert() {
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let mut agent: Agent = Agent::builder(Config::new("ws://127.0.0.1:9/ws", "test-client"))
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .build();
+        agent.start().unwrap();
+
+        let alert: Alert = crate::test_support::alert(AlertLevel::Critical, true);
+        agent.alert_queue().try_push(alert.clone()).unwrap();
+        while agent.handler().pending_count().await == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        agent
+            .toast_activations()
+            .send(ActivationArgs::new(ToastAction::Confirm, alert.id))
+            .unwrap();
+        while agent.handler().pending_count().await > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(agent.status().confirmation_queue_depth, 1);
+
+        assert!(agent.shutdown(Duration::from_secs(5)).await);
+    }
+
+    #[tokio::test]
+    async fn test_response_option_flows_from_toast_to_confirmation() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let handler: Arc<AlertHandler> = Arc::new(
+            AlertHandler::builder(outbound, "test-client")
+                .notification_backend(Arc::new(MockNotifier::default()))
+                .audio_backend(Arc::new(MockAudio::default()))
+                .build(),
+        );
+        let (activation_tx, activation_rx) = mpsc::unbounded_channel::<ActivationArgs>();
+        let cancel: CancellationToken = CancellationToken::new();
+        let tracker: TaskTracker = TaskTracker::new();
+        tracker.spawn(run_activation_loop(
+            handler.clone(),
+            activation_rx,
+            cancel.clone(),
+            tracker.clone(),
+        ));
+
+        let mut alert: Alert = crate::test_support::alert(AlertLevel::Critical, true);
+        alert.response_options = Some(vec![
+            ResponseOption {
+                id: "safe".to_string(),
+                label: "Safe".to_string(),
+                response: ConfirmationResponse::Acknowledged,
+            },
+            ResponseOption {
+                id: "need-assistance".to_string(),
+                label: "Need assistance".to_string(),
+                response: ConfirmationResponse::Acknowledged,
+            },
+        ]);
+        handler.handle_alert(alert.clone()).await.unwrap();
+
+        // Click the second button, with the arguments Windows hands back
+        let arguments: String = format!("action=respond&alert={}&option=need-assistance", alert.id);
+        let xml: String = NotificationManager::new("test").create_toast_xml(&alert);
+        assert!(xml.contains(&format!(
+            r#"arguments="{}""#,
+            arguments.replace('&', "&amp;")
+        )));
+        activation_tx
+            .send(ActivationArgs::parse(&arguments).unwrap())
+            .unwrap();
+
+        let confirmation: Confirmation = confirmations.recv().await;
+        let sent: serde_json::Value =
+            serde_json::to_value(Message::Confirmation { confirmation }).unwrap();
+        assert_eq!(sent["confirmation"]["alert_id"], alert.id.to_string());
+        assert_eq!(sent["confirmation"]["response_id"], "need-assistance");
+        assert_eq!(handler.pending_count().await, 0);
+
+        cancel.cancel();
+        tracker.close();
+        tracker.wait().await;
+    }
+
+    async fn next_heartbeat(peer: &mut MemoryPeer) -> HeartbeatStats {
+        loop {
+            match peer.recv().await {
+                Some(Message::Heartbeat { stats }) => return stats,
+                Some(_) => {}
+                None => panic!("agent disconnected"),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat_reports_handler_activity() {
+        let (transport, mut listener) = MemoryTransport::new();
+        let mut agent: Agent = Agent::builder(Config::new("ws://server.test/ws", "test-client"))
+            .notification_backend(Arc::new(MockNotifier::default()))
+            .audio_backend(Arc::new(MockAudio::default()))
+            .transport(Arc::new(transport))
+            .build();
+        agent.start().unwrap();
+        let mut peer: MemoryPeer = listener.accept().await.unwrap();
+        peer.ack_registration();
+
+        let idle: HeartbeatStats = next_heartbeat(&mut peer).await;
+        assert_eq!(idle.last_alert_secs, None);
+        assert_eq!(idle.pending_confirmations, Some(0));
+        let connected_at = idle.connected_at.expect("connection time reported");
+
+        peer.send(&Message::Alert {
+            alert: crate::test_support::alert(AlertLevel::Critical, true),
+        });
+        while agent.handler().pending_count().await == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The next heartbeat is one interval later
+        let busy: HeartbeatStats = next_heartbeat(&mut peer).await;
+        assert_eq!(busy.pending_confirmations, Some(1));
+        assert!(busy
+            .last_alert_secs
+            .is_some_and(|secs| (29..=30).contains(&secs)));
+        assert!(busy.uptime_secs.is_some_and(|secs| secs >= 30));
+        assert_eq!(busy.connected_at, Some(connected_at));
+
+        assert!(agent.shutdown(Duration::from_secs(5)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_alert_flood_is_rate_limited() {
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let mut config: Config = Config::new("ws://127.0.0.1:9/ws", "test-client");
+        config.alert_queue_capacity = 500;
+        let mut agent: Agent = Agent::builder(config)
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .build();
+        agent.start().unwrap();
+
+        // 100 urgent alerts, within their allowance, among 400 routine ones;
+        // with time paused no tokens refill while the flood is handled. Each
+        // says something different, or they would be taken for retries
+        let flood: Vec<Alert> = (0..500)
+            .map(|i| {
+                let alert: Alert = match i % 10 {
+                    0 => crate::test_support::alert(AlertLevel::Emergency, true),
+                    1 => crate::test_support::alert(AlertLevel::Critical, false),
+                    2 | 3 => crate::test_support::alert(AlertLevel::Warning, true),
+                    _ => crate::test_support::alert(AlertLevel::Info, false),
+                };
+                Alert {
+                    message: format!("Flood alert {}", i),
+                    ..alert
+                }
+            })
+            .collect();
+        for alert in &flood {
+            agent.alert_queue().try_push(alert.clone()).unwrap();
+        }
+        // Once the queue is empty, the last routine alert has been shed
+        while agent.status().alert_queue_depth > 0 || agent.status().alerts_rate_limited < 370 {
+            tokio::task::yield_now().await;
+        }
+
+        let status: AgentStatus = agent.status();
+        assert_eq!(status.alerts_rate_limited, 370);
+        assert_eq!(status.urgent_alerts_rate_limited, 0);
+
+        // Every Emergency alert was shown and awaits confirmation
+        let shown: Vec<Alert> = notifier.shown();
+        for emergency in flood.iter().filter(|a| a.level == AlertLevel::Emergency) {
+            assert!(shown.iter().any(|a| a.id == emergency.id));
+            assert!(agent
+                .handler()
+                .get_pending_alerts()
+                .await
+                .contains(&emergency.id));
+        }
+        // Only admitted alerts can be pending
+        assert!(agent.handler().pending_count().await <= 30 + 100);
+        assert_eq!(
+            shown
+                .iter()
+                .filter(|a| a.title == "Too many alerts; some are not being shown")
+                .count(),
+            1
+        );
+
+        let mut rate_limited: usize = 0;
+        let mut errors: usize = 0;
+        while !agent.outbound.is_empty() {
+            match agent.outbound.next().await {
+                OutboundMessage::DeliveryStatus(status)
+                    if status.outcome == Some(DeliveryOutcome::RateLimited) =>
+                {
+                    rate_limited += 1
+                }
+                OutboundMessage::AlertError { reason, .. } => {
+                    assert_eq!(reason, AlertErrorReason::Overloaded);
+                    errors += 1;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!((rate_limited, errors), (370, 1));
+
+        assert!(agent.shutdown(Duration::from_secs(5)).await);
+    }
+}
@@ -0,0 +1,530 @@
+//! Lights a local alarm panel over a serial port while urgent alerts await confirmation.
+//!
+//! [`Annunciator`] decides what the panel should show; [`AnnunciatorSink`]
+//! feeds it handler events and hands the bytes to a background task, which
+//! reopens the port and retries with backoff when a write fails.
+
+use crate::messages::{Alert, AlertLevel, AnnunciatorState, DeliveryStatus};
+use crate::outbound::{OutboundMessage, OutboundQueue};
+use crate::queue::priority;
+use crate::sink::{AlertSink, Resolution};
+use serialport::{DataBits, Parity, SerialPort, StopBits};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+pub const DEFAULT_ANNUNCIATOR_BAUD: u32 = 9600;
+pub const DEFAULT_LIGHT_SEQUENCE: &str = r"ALARM {level}\r\n";
+pub const DEFAULT_CLEAR_SEQUENCE: &str = r"CLEAR\r\n";
+
+/// Writes tried before a failure is reported to the server
+const WRITE_ATTEMPTS: u32 = 5;
+/// Wait before the first retry; doubles after each failed attempt
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Longest one write to the port may block before it counts as failed
+const PORT_WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Opens the port the panel is attached to
+pub type PortOpener = Box<dyn FnMut() -> io::Result<Box<dyn Write + Send>> + Send>;
+
+/// Serial port and byte sequences of the alarm panel
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnunciatorConfig {
+    /// Port name, e.g. `COM3`
+    pub port: String,
+    pub baud: u32,
+    /// Sent when the panel lights or the most severe outstanding level changes
+    pub light: SequenceTemplate,
+    /// Sent when the last outstanding alert is resolved
+    pub clear: SequenceTemplate,
+}
+
+impl AnnunciatorConfig {
+    /// Default baud rate and sequences on `port`
+    pub fn new(port: impl Into<String>) -> Self {
+        Self {
+            port: port.into(),
+            baud: DEFAULT_ANNUNCIATOR_BAUD,
+            light: SequenceTemplate::parse(DEFAULT_LIGHT_SEQUENCE).unwrap(),
+            clear: SequenceTemplate::parse(DEFAULT_CLEAR_SEQUENCE).unwrap(),
+        }
+    }
+}
+
+/// Bytes to send, written as text with `{level}` standing for the alert level.
+///
+/// `\r`, `\n`, `\t`, `\\` and `\xNN` escapes allow control bytes, since
+/// the templates come from environment variables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceTemplate {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Bytes(Vec<u8>),
+    Level,
+}
+
+impl SequenceTemplate {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut pieces: Vec<Piece> = Vec::new();
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut rest: &str = template;
+        while let Some(c) = rest.chars().next() {
+            if let Some(after) = rest.strip_prefix("{level}") {
+                if !bytes.is_empty() {
+                    pieces.push(Piece::Bytes(std::mem::take(&mut bytes)));
+                }
+                pieces.push(Piece::Level);
+                rest = after;
+                continue;
+            }
+            rest = &rest[c.len_utf8()..];
+            if c != '\\' {
+                let mut buf: [u8; 4] = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                continue;
+            }
+            let escape: char = rest
+                .chars()
+                .next()
+                .ok_or_else(|| format!("{:?} ends in a lone backslash", template))?;
+            rest = &rest[escape.len_utf8()..];
+            match escape {
+                'r' => bytes.push(b'\r'),
+                'n' => bytes.push(b'\n'),
+                't' => bytes.push(b'\t'),
+                '\\' => bytes.push(b'\\'),
+                'x' => {
+                    let byte: u8 = rest
+                        .get(..2)
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                        .ok_or_else(|| format!("{:?}: \\x needs two hex digits", template))?;
+                    bytes.push(byte);
+                    rest = &rest[2..];
+                }
+                other => return Err(format!("{:?}: unknown escape \\{}", template, other)),
+            }
+        }
+        if !bytes.is_empty() {
+            pieces.push(Piece::Bytes(bytes));
+        }
+        Ok(Self { pieces })
+    }
+
+    pub fn render(&self, level: &AlertLevel) -> Vec<u8> {
+        self.pieces
+            .iter()
+            .flat_map(|piece| match piece {
+                Piece::Bytes(bytes) => bytes.as_slice(),
+                Piece::Level => level.as_str().as_bytes(),
+            })
+            .copied()
+            .collect()
+    }
+}
+
+/// Which urgent alerts are outstanding and what the panel was last told.
+///
+/// The panel is lit at the most severe outstanding level while any Critical
+/// or Emergency alert awaits confirmation, and cleared when the last is
+/// resolved. Alerts without a confirmation prompt are never resolved, so they
+/// do not light it.
+#[derive(Debug)]
+pub struct Annunciator {
+    light: SequenceTemplate,
+    clear: SequenceTemplate,
+    outstanding: HashMap<uuid::Uuid, AlertLevel>,
+    lit: Option<AlertLevel>,
+}
+
+impl Annunciator {
+    pub fn new(config: &AnnunciatorConfig) -> Self {
+        Self {
+            light: config.light.clone(),
+            clear: config.clear.clone(),
+            outstanding: HashMap::new(),
+            lit: None,
+        }
+    }
+
+    /// Bytes to send for a delivered alert, if the panel changes
+    pub fn delivered(&mut self, alert: &Alert) -> Option<Vec<u8>> {
+        let urgent: bool = matches!(alert.level, AlertLevel::Critical | AlertLevel::Emergency);
+        if !urgent || !alert.requires_confirmation {
+            return None;
+        }
+        self.outstanding.insert(alert.id, alert.level.clone());
+        self.update()
+    }
+
+    /// Bytes to send for a resolved alert, if the panel changes
+    pub fn resolved(&mut self, alert_id: uuid::Uuid) -> Option<Vec<u8>> {
+        self.outstanding.remove(&alert_id)?;
+        self.update()
+    }
+
+    /// Level the panel is lit at
+    pub fn lit(&self) -> Option<&AlertLevel> {
+        self.lit.as_ref()
+    }
+
+    fn update(&mut self) -> Option<Vec<u8>> {
+        let highest: Option<AlertLevel> = self
+            .outstanding
+            .values()
+            .max_by_key(|level| priority(level))
+            .cloned();
+        if highest == self.lit {
+            return None;
+        }
+        let bytes: Vec<u8> = match (&highest, &self.lit) {
+            (Some(level), _) => self.light.render(level),
+            (None, Some(was)) => self.clear.render(was),
+            (None, None) => return None,
+        };
+        self.lit = highest;
+        Some(bytes)
+    }
+}
+
+/// Drives the alarm panel from handler events.
+///
+/// The port is written on a background task started by the first write; a
+/// write that still fails after retrying is reported to the server as a
+/// delivery status for the alert that caused it.
+pub struct AnnunciatorSink {
+    panel: Mutex<Annunciator>,
+    writes: mpsc::UnboundedSender<PanelWrite>,
+    /// The task's state until the first write starts it
+    writer: Mutex<Option<PortWriter>>,
+    tracker: TaskTracker,
+}
+
+#[derive(Debug)]
+struct PanelWrite {
+    alert_id: uuid::Uuid,
+    bytes: Vec<u8>,
+}
+
+struct PortWriter {
+    port_name: String,
+    opener: PortOpener,
+    writes: mpsc::UnboundedReceiver<PanelWrite>,
+    outbound: Arc<OutboundQueue>,
+    client_id: String,
+    cancel: CancellationToken,
+}
+
+impl AnnunciatorSink {
+    /// Sink writing to the serial port in `config`, reporting failures on `outbound`
+    pub fn new(
+        config: &AnnunciatorConfig,
+        outbound: Arc<OutboundQueue>,
+        client_id: impl Into<String>,
+    ) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel::<PanelWrite>();
+        let port: String = config.port.clone();
+        let baud: u32 = config.baud;
+        Self {
+            panel: Mutex::new(Annunciator::new(config)),
+            writes: tx,
+            writer: Mutex::new(Some(PortWriter {
+                port_name: config.port.clone(),
+                opener: Box::new(move || open_port(&port, baud)),
+                writes: rx,
+                outbound,
+                client_id: client_id.into(),
+                cancel: CancellationToken::new(),
+            })),
+            tracker: TaskTracker::new(),
+        }
+    }
+
+    /// Replace how the port is opened (default: the serial port in the config)
+    pub fn with_opener(self, opener: PortOpener) -> Self {
+        if let Some(writer) = self.writer.lock().unwrap().as_mut() {
+            writer.opener = opener;
+        }
+        self
+    }
+
+    /// Token that stops the writer task when cancelled
+    pub fn with_cancellation(self, cancel: CancellationToken) -> Self {
+        if let Some(writer) = self.writer.lock().unwrap().as_mut() {
+            writer.cancel = cancel;
+        }
+        self
+    }
+
+    /// Tracker the writer task is spawned on
+    pub fn with_task_tracker(mut self, tracker: TaskTracker) -> Self {
+        self.tracker = tracker;
+        self
+    }
+
+    /// Queue `bytes` for the port, starting the writer on first use
+    fn send(&self, alert_id: uuid::Uuid, bytes: Vec<u8>) {
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            self.tracker.spawn(writer.run());
+        }
+        if self.writes.send(PanelWrite { alert_id, bytes }).is_err() {
+            log::warn!(
+                "Annunciator writer has stopped; panel not updated for {}",
+                alert_id
+            );
+        }
+    }
+}
+
+impl AlertSink for AnnunciatorSink {
+    fn delivered(&self, alert: &Alert) {
+        // Held across the send so writes reach the port in the order the panel changed
+        let mut panel = self.panel.lock().unwrap();
+        if let Some(bytes) = panel.delivered(alert) {
+            log::info!("Lighting annunciator for alert {}", alert.id);
+            self.send(alert.id, bytes);
+        }
+    }
+
+    fn resolved(&self, alert_id: uuid::Uuid, _resolution: Resolution) {
+        let mut panel = self.panel.lock().unwrap();
+        if let Some(bytes) = panel.resolved(alert_id) {
+            match panel.lit() {
+                Some(level) => log::info!("Annunciator back to {}", level.as_str()),
+                None => log::info!("Clearing annunciator"),
+            }
+            self.send(alert_id, bytes);
+        }
+    }
+}
+
+impl PortWriter {
+    /// Write queued sequences until the sink is dropped or the token is cancelled
+    async fn run(mut self) {
+        let mut port: Option<Box<dyn Write + Send>> = None;
+        loop {
+            let write: PanelWrite = tokio::select! {
+                _ = self.cancel.cancelled() => return,
+                write = self.writes.recv() => match write {
+                    Some(write) => write,
+                    None => return,
+                },
+            };
+
+            let mut delay: Duration = FIRST_RETRY_DELAY;
+            for attempt in 1..=WRITE_ATTEMPTS {
+                let Err(e) = self.write(&mut port, &write.bytes) else {
+                    break;
+                };
+                // Reopen next time in case the adapter was unplugged
+                port = None;
+                if attempt == WRITE_ATTEMPTS {
+                    log::error!(
+                        "Annunciator on {} failed after {} attempts: {}",
+                        self.port_name,
+                        attempt,
+                        e
+                    );
+                    self.report_failure(write.alert_id, &e);
+                    break;
+                }
+                log::warn!(
+                    "Annunciator write to {} failed, retrying in {:?}: {}",
+                    self.port_name,
+                    delay,
+                    e
+                );
+                tokio::select! {
+                    _ = self.cancel.cancelled() => return,
+                    _ = tokio::time::sleep(delay) => {}
+                }
+                delay *= 2;
+            }
+        }
+    }
+
+    fn write(&mut self, port: &mut Option<Box<dyn Write + Send>>, bytes: &[u8]) -> io::Result<()> {
+        let port: &mut Box<dyn Write + Send> = match port {
+            Some(port) => port,
+            None => port.insert((self.opener)()?),
+        };
+        port.write_all(bytes)?;
+        port.flush()
+    }
+
+    fn report_failure(&self, alert_id: uuid::Uuid, error: &io::Error) {
+        self.outbound
+            .push(OutboundMessage::DeliveryStatus(DeliveryStatus {
+                alert_id,
+                client_id: self.client_id.clone(),
+                reported_at: chrono::Utc::now(),
+                attachment: None,
+                sound: None,
+                outcome: None,
+                annunciator: Some(AnnunciatorState::Failed),
+                callback: None,
+                detail: Some(format!("{}: {}", self.port_name, error)),
+                decision: None,
+                locale: None,
+            }));
+    }
+}
+
+/// Open `port` for writing at `baud`, 8 data bits, no parity, one stop bit
+pub fn open_port(port: &str, baud: u32) -> io::Result<Box<dyn Write + Send>> {
+    let port: Box<dyn SerialPort> = serialport::new(port, baud)
+        .data_bits(DataBits::Eight)
+        .parity(Parity::None)
+        .stop_bits(StopBits::One)
+        .timeout(PORT_WRITE_TIMEOUT)
+        .open()?;
+    Ok(Box::new(port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::ConfirmationReason;
+    use crate::sink::Withdrawal;
+    use crate::test_support::alert;
+
+    /// Port that records everything written to it
+    #[derive(Clone, Default)]
+    struct MockPort(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for MockPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl MockPort {
+        fn written(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    /// Opener that fails `failures` times, then opens `port`; counts every attempt
+    fn opener(port: &MockPort, failures: usize, opens: Arc<Mutex<usize>>) -> PortOpener {
+        let port: MockPort = port.clone();
+        Box::new(move || {
+            let mut opens = opens.lock().unwrap();
+            *opens += 1;
+            if *opens <= failures {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "port missing"));
+            }
+            Ok(Box::new(port.clone()))
+        })
+    }
+
+    /// Drop the sink and wait for its writer to finish what was queued
+    async fn finish(sink: AnnunciatorSink, tracker: TaskTracker) {
+        drop(sink);
+        tracker.close();
+        tracker.wait().await;
+    }
+
+    #[test]
+    fn test_sequence_templates() {
+        let template: SequenceTemplate = SequenceTemplate::parse(r"\x02{level}\\\r\n").unwrap();
+        assert_eq!(
+            template.render(&AlertLevel::Emergency),
+            b"\x02Emergency\\\r\n"
+        );
+        assert_eq!(
+            SequenceTemplate::parse("ALARM")
+                .unwrap()
+                .render(&AlertLevel::Critical),
+            b"ALARM"
+        );
+        assert!(SequenceTemplate::parse(r"\x2").is_err());
+        assert!(SequenceTemplate::parse(r"\q").is_err());
+        assert!(SequenceTemplate::parse("ends\\").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_panel_follows_urgent_alerts_through_their_lifecycle() {
+        let port: MockPort = MockPort::default();
+        let tracker: TaskTracker = TaskTracker::new();
+        let sink: AnnunciatorSink = AnnunciatorSink::new(
+            &AnnunciatorConfig::new("COM3"),
+            Arc::default(),
+            "test-client",
+        )
+        .with_opener(opener(&port, 0, Arc::default()))
+        .with_task_tracker(tracker.clone());
+
+        let critical: Alert = alert(AlertLevel::Critical, true);
+        let emergency: Alert = alert(AlertLevel::Emergency, true);
+        sink.delivered(&critical);
+        sink.delivered(&alert(AlertLevel::Info, true));
+        sink.delivered(&alert(AlertLevel::Emergency, false));
+        sink.delivered(&emergency);
+        let confirmed: Resolution = Resolution::Confirmed(ConfirmationReason::User);
+        sink.resolved(emergency.id, confirmed);
+        // Unknown and repeated resolutions change nothing
+        sink.resolved(emergency.id, confirmed);
+        sink.resolved(uuid::Uuid::new_v4(), confirmed);
+        sink.resolved(critical.id, Resolution::Withdrawn(Withdrawal::Cancelled));
+        finish(sink, tracker).await;
+
+        assert_eq!(
+            port.written(),
+            "ALARM Critical\r\nALARM Emergency\r\nALARM Critical\r\nCLEAR\r\n"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_port_errors_are_retried_then_reported() {
+        let port: MockPort = MockPort::default();
+        let opens: Arc<Mutex<usize>> = Arc::default();
+        let outbound: Arc<OutboundQueue> = Arc::default();
+        let tracker: TaskTracker = TaskTracker::new();
+        let sink: AnnunciatorSink = AnnunciatorSink::new(
+            &AnnunciatorConfig::new("COM3"),
+            outbound.clone(),
+            "test-client",
+        )
+        .with_opener(opener(&port, 2, opens.clone()))
+        .with_task_tracker(tracker.clone());
+        let critical: Alert = alert(AlertLevel::Critical, true);
+        sink.delivered(&critical);
+        finish(sink, tracker).await;
+
+        // Two failed opens, then the write lands and nothing is reported
+        assert_eq!(*opens.lock().unwrap(), 3);
+        assert_eq!(port.written(), "ALARM Critical\r\n");
+        assert!(outbound.is_empty());
+
+        let opens: Arc<Mutex<usize>> = Arc::default();
+        let tracker: TaskTracker = TaskTracker::new();
+        let sink: AnnunciatorSink = AnnunciatorSink::new(
+            &AnnunciatorConfig::new("COM3"),
+            outbound.clone(),
+            "test-client",
+        )
+        .with_opener(opener(&port, usize::MAX, opens.clone()))
+        .with_task_tracker(tracker.clone());
+        sink.delivered(&critical);
+        finish(sink, tracker).await;
+
+        assert_eq!(*opens.lock().unwrap(), WRITE_ATTEMPTS as usize);
+        let OutboundMessage::DeliveryStatus(status) = outbound.next().await else {
+            panic!("expected a delivery status");
+        };
+        assert_eq!(status.alert_id, critical.id);
+        assert_eq!(status.annunciator, Some(AnnunciatorState::Failed));
+        assert_eq!(status.detail.as_deref(), Some("COM3: port missing"));
+    }
+}
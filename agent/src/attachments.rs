@@ -0,0 +1,503 @@
+//! Documents linked from alerts: verified download, opening, and cleanup
+
+use crate::error::{EmnsError, Result};
+use crate::messages::Attachment;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// Directory under the data dir that holds one folder per alert
+pub const ATTACHMENTS_DIR: &str = "attachments";
+
+/// Largest attachment downloaded by default
+pub const DEFAULT_MAX_ATTACHMENT_BYTES: u64 = 25 * 1024 * 1024;
+
+/// Default limit on one whole download
+pub const DEFAULT_ATTACHMENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Default age after which downloaded attachments are removed
+pub const DEFAULT_ATTACHMENT_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How often old attachments are swept
+pub const ATTACHMENT_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Download limits and retention for alert attachments
+#[derive(Debug, Clone)]
+pub struct AttachmentConfig {
+    pub max_bytes: u64,
+    pub timeout: Duration,
+    pub retention: Duration,
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: DEFAULT_MAX_ATTACHMENT_BYTES,
+            timeout: DEFAULT_ATTACHMENT_TIMEOUT,
+            retention: DEFAULT_ATTACHMENT_RETENTION,
+        }
+    }
+}
+
+/// A file that downloaded and matched its checksum
+#[derive(Debug, Clone)]
+struct VerifiedFile {
+    path: PathBuf,
+    sha256: String,
+}
+
+/// Downloads attachments into `<data dir>/attachments/<alert id>/` and
+/// remembers which ones verified
+pub struct AttachmentStore {
+    root: PathBuf,
+    max_bytes: u64,
+    timeout: Duration,
+    client: reqwest::Client,
+    verified: Mutex<HashMap<Uuid, VerifiedFile>>,
+}
+
+impl AttachmentStore {
+    pub fn new(data_dir: impl AsRef<Path>, config: &AttachmentConfig) -> Self {
+        Self {
+            root: data_dir.as_ref().join(ATTACHMENTS_DIR),
+            max_bytes: config.max_bytes,
+            timeout: config.timeout,
+            client: reqwest::Client::new(),
+            verified: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Download an alert's attachment and check it against its declared size and checksum.
+    ///
+    /// The file only gets its real name once it has verified, so a failed
+    /// download never leaves something openable behind.
+    pub async fn fetch(&self, alert_id: Uuid, attachment: &Attachment) -> Result<PathBuf> {
+        if attachment.size > self.max_bytes {
+            return Err(EmnsError::protocol(format!(
+                "attachment {} is {} bytes, over the {} byte limit",
+                attachment.filename, attachment.size, self.max_bytes
+            )));
+        }
+        if !(attachment.url.starts_with("https://") || attachment.url.starts_with("http://")) {
+            return Err(EmnsError::protocol(format!(
+                "attachment URL {} is not http(s)",
+                attachment.url
+            )));
+        }
+
+        let body: Vec<u8> = tokio::time::timeout(self.timeout, self.download(attachment))
+            .await
+            .map_err(|_| {
+                EmnsError::connection(
+                    &attachment.url,
+                    format!("download timed out after {:?}", self.timeout),
+                )
+            })??;
+
+        let actual: String = sha256_hex(&body);
+        if !actual.eq_ignore_ascii_case(attachment.sha256.trim()) {
+            return Err(EmnsError::protocol(format!(
+                "attachment {} checksum mismatch: expected {}, got {}",
+                attachment.filename, attachment.sha256, actual
+            )));
+        }
+
+        let dir: PathBuf = self.root.join(alert_id.to_string());
+        let path: PathBuf = dir.join(safe_filename(&attachment.filename));
+        let partial: PathBuf = path.with_extension("part");
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| EmnsError::storage(Some(&dir), e))?;
+        tokio::fs::write(&partial, &body)
+            .await
+            .map_err(|e| EmnsError::storage(Some(&partial), e))?;
+        tokio::fs::rename(&partial, &path)
+            .await
+            .map_err(|e| EmnsError::storage(Some(&path), e))?;
+
+        self.verified.lock().unwrap().insert(
+            alert_id,
+            VerifiedFile {
+                path: path.clone(),
+                sha256: actual,
+            },
+        );
+        log::info!(
+            "Attachment for alert {} saved to {}",
+            alert_id,
+            path.display()
+        );
+        Ok(path)
+    }
+
+    /// Read the response body, stopping as soon as it passes the declared size
+    async fn download(&self, attachment: &Attachment) -> Result<Vec<u8>> {
+        let url: &str = &attachment.url;
+        let mut response: reqwest::Response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| EmnsError::connection(url, e))?;
+
+        let mut body: Vec<u8> = Vec::with_capacity(attachment.size as usize);
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| EmnsError::connection(url, e))?
+        {
+            if (body.len() + chunk.len()) as u64 > attachment.size {
+                return Err(EmnsError::protocol(format!(
+                    "attachment {} is larger than its declared {} bytes",
+                    attachment.filename, attachment.size
+                )));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    /// The alert's attachment, if it verified and is unchanged on disk
+    pub fn verified_path(&self, alert_id: Uuid) -> Option<PathBuf> {
+        let file: VerifiedFile = self.verified.lock().unwrap().get(&alert_id)?.clone();
+        match std::fs::read(&file.path) {
+            Ok(contents) if sha256_hex(&contents) == file.sha256 => Some(file.path),
+            Ok(_) => {
+                log::warn!("Attachment {} changed since download", file.path.display());
+                None
+            }
+            Err(e) => {
+                log::warn!("Attachment {} unreadable: {}", file.path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Remove alert folders last modified more than `retention` before `now`.
+    ///
+    /// Returns how many folders were removed.
+    pub fn sweep(&self, retention: Duration, now: SystemTime) -> Result<usize> {
+        let removed: usize = self.remove_where(|_, entry| {
+            entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| {
+                    now.duration_since(modified)
+                        .is_ok_and(|age| age > retention)
+                })
+        })?;
+        if removed > 0 {
+            log::info!("Removed {} expired attachment folder(s)", removed);
+        }
+        Ok(removed)
+    }
+
+    /// Remove the folders of alerts not in `referenced`, whatever their age.
+    ///
+    /// Returns how many folders were removed.
+    pub fn remove_unreferenced(&self, referenced: &HashSet<Uuid>) -> Result<usize> {
+        let removed: usize = self.remove_where(|alert_id, _| !referenced.contains(&alert_id))?;
+        if removed > 0 {
+            log::info!("Removed {} orphaned attachment folder(s)", removed);
+        }
+        Ok(removed)
+    }
+
+    /// Remove the alert folders `doomed` picks; anything not named for an alert is left alone
+    fn remove_where(&self, doomed: impl Fn(Uuid, &std::fs::DirEntry) -> bool) -> Result<usize> {
+        let entries = match std::fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(EmnsError::storage(Some(&self.root), e)),
+        };
+
+        let mut removed: usize = 0;
+        for entry in entries.flatten() {
+            let path: PathBuf = entry.path();
+            let Some(alert_id) = path
+                .file_name()
+                .and_then(|name| Uuid::parse_str(&name.to_string_lossy()).ok())
+            else {
+                continue;
+            };
+            if !doomed(alert_id, &entry) {
+                continue;
+            }
+            match std::fs::remove_dir_all(&path) {
+                Ok(()) => {
+                    self.verified.lock().unwrap().remove(&alert_id);
+                    removed += 1;
+                }
+                Err(e) => log::warn!("Failed to remove {}: {}", path.display(), e),
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Sweep old attachments now and every [`ATTACHMENT_SWEEP_INTERVAL`] until `cancel` fires
+pub async fn run_sweeper(
+    store: Arc<AttachmentStore>,
+    retention: Duration,
+    cancel: CancellationToken,
+) {
+    loop {
+        let sweeping = tokio::task::spawn_blocking({
+            let store: Arc<AttachmentStore> = store.clone();
+            move || store.sweep(retention, SystemTime::now())
+        });
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            swept = sweeping => match swept {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => log::warn!("Attachment sweep failed: {}", e),
+                Err(e) => log::warn!("Attachment sweep panicked: {}", e),
+            },
+        }
+
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = tokio::time::sleep(ATTACHMENT_SWEEP_INTERVAL) => {}
+        }
+    }
+    log::debug!("Attachment sweeper stopped");
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The last path component of `filename` with characters Windows forbids replaced
+fn safe_filename(filename: &str) -> String {
+    let name: &str = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let cleaned: &str = cleaned.trim_matches(|c: char| c == '.' || c.is_whitespace());
+    if cleaned.is_empty() {
+        "attachment".to_string()
+    } else {
+        cleaned.to_string()
+    }
+}
+
+/// Opens a downloaded document with whatever the user has set up for its type
+pub trait DocumentLauncher: Send + Sync {
+    fn open(&self, path: &Path) -> Result<()>;
+}
+
+/// Launches documents through the Windows shell
+#[derive(Default)]
+pub struct ShellLauncher;
+
+impl ShellLauncher {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl DocumentLauncher for ShellLauncher {
+    fn open(&self, path: &Path) -> Result<()> {
+        use windows::core::{w, HSTRING, PCWSTR};
+        use windows::Win32::UI::Shell::ShellExecuteW;
+        use windows::Win32::UI::WindowsAndMessaging::SW_SHOWNORMAL;
+
+        let instance = unsafe {
+            ShellExecuteW(
+                None,
+                w!("open"),
+                &HSTRING::from(path.as_os_str()),
+                PCWSTR::null(),
+                PCWSTR::null(),
+                SW_SHOWNORMAL,
+            )
+        };
+        // Values of 32 and below are error codes
+        if instance.0 <= 32 {
+            return Err(EmnsError::storage(
+                Some(path),
+                format!("ShellExecuteW failed with code {}", instance.0),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Opening documents is only available on Windows; elsewhere the path is logged
+#[cfg(not(target_os = "windows"))]
+impl DocumentLauncher for ShellLauncher {
+    fn open(&self, path: &Path) -> Result<()> {
+        log::info!("Would open {}", path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use std::net::SocketAddr;
+
+    const DOCUMENT: &[u8] = b"%PDF-1.4 evacuation procedure";
+
+    /// Serve `DOCUMENT` at `/doc.pdf` and a 2 KiB body at `/large.pdf`
+    async fn fixture() -> SocketAddr {
+        let app = axum::Router::new()
+            .route("/doc.pdf", get(|| async { DOCUMENT }))
+            .route("/large.pdf", get(|| async { vec![b'x'; 2048] }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    fn attachment(addr: SocketAddr, path: &str, size: u64) -> Attachment {
+        Attachment {
+            url: format!("http://{}{}", addr, path),
+            filename: "Evacuation Procedure.pdf".to_string(),
+            sha256: sha256_hex(DOCUMENT).to_ascii_uppercase(),
+            size,
+        }
+    }
+
+    fn store(dir: &Path, max_bytes: u64) -> AttachmentStore {
+        AttachmentStore::new(
+            dir,
+            &AttachmentConfig {
+                max_bytes,
+                ..AttachmentConfig::default()
+            },
+        )
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir: PathBuf = std::env::temp_dir().join(format!("emns-{}-{}", name, Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_verified_download_is_openable() {
+        let addr: SocketAddr = fixture().await;
+        let dir: PathBuf = temp_dir("attachment-ok");
+        let store: AttachmentStore = store(&dir, 1024);
+        let alert_id: Uuid = Uuid::new_v4();
+
+        let path: PathBuf = store
+            .fetch(
+                alert_id,
+                &attachment(addr, "/doc.pdf", DOCUMENT.len() as u64),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            path,
+            dir.join(ATTACHMENTS_DIR)
+                .join(alert_id.to_string())
+                .join("Evacuation Procedure.pdf")
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), DOCUMENT);
+        assert_eq!(store.verified_path(alert_id), Some(path.clone()));
+
+        // Edited after download: no longer trusted
+        std::fs::write(&path, b"tampered").unwrap();
+        assert_eq!(store.verified_path(alert_id), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checksum_mismatch_is_rejected() {
+        let addr: SocketAddr = fixture().await;
+        let dir: PathBuf = temp_dir("attachment-mismatch");
+        let store: AttachmentStore = store(&dir, 1024);
+        let alert_id: Uuid = Uuid::new_v4();
+
+        let mut wrong: Attachment = attachment(addr, "/doc.pdf", DOCUMENT.len() as u64);
+        wrong.sha256 = sha256_hex(b"something else");
+        let err: EmnsError = store.fetch(alert_id, &wrong).await.unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+        assert_eq!(store.verified_path(alert_id), None);
+        assert!(!dir
+            .join(ATTACHMENTS_DIR)
+            .join(alert_id.to_string())
+            .exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oversize_attachments_are_rejected() {
+        let addr: SocketAddr = fixture().await;
+        let dir: PathBuf = temp_dir("attachment-oversize");
+        let store: AttachmentStore = store(&dir, 1024);
+
+        // Declared over the cap: never requested
+        let declared: Attachment = attachment(addr, "/large.pdf", 2048);
+        let err: EmnsError = store.fetch(Uuid::new_v4(), &declared).await.unwrap_err();
+        assert!(
+            err.to_string().contains("over the 1024 byte limit"),
+            "{}",
+            err
+        );
+
+        // Declared small, but the server sends more
+        let understated: Attachment = attachment(addr, "/large.pdf", DOCUMENT.len() as u64);
+        let err: EmnsError = store.fetch(Uuid::new_v4(), &understated).await.unwrap_err();
+        assert!(
+            err.to_string().contains("larger than its declared"),
+            "{}",
+            err
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sweep_removes_expired_attachments() {
+        let addr: SocketAddr = fixture().await;
+        let dir: PathBuf = temp_dir("attachment-sweep");
+        let store: AttachmentStore = store(&dir, 1024);
+        let alert_id: Uuid = Uuid::new_v4();
+        let path: PathBuf = store
+            .fetch(
+                alert_id,
+                &attachment(addr, "/doc.pdf", DOCUMENT.len() as u64),
+            )
+            .await
+            .unwrap();
+        let unrelated: PathBuf = dir.join(ATTACHMENTS_DIR).join("keep-me");
+        std::fs::create_dir_all(&unrelated).unwrap();
+
+        let retention: Duration = Duration::from_secs(24 * 60 * 60);
+        let now: SystemTime = SystemTime::now();
+        assert_eq!(store.sweep(retention, now).unwrap(), 0);
+        assert!(path.exists());
+
+        let later: SystemTime = now + retention * 2;
+        assert_eq!(store.sweep(retention, later).unwrap(), 1);
+        assert!(!path.exists());
+        assert!(unrelated.exists());
+        assert_eq!(store.verified_path(alert_id), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_filenames_stay_inside_the_alert_folder() {
+        assert_eq!(safe_filename("..\\..\\evil.exe"), "evil.exe");
+        assert_eq!(safe_filename("/etc/passwd"), "passwd");
+        assert_eq!(safe_filename("a:b?.pdf"), "a_b_.pdf");
+        assert_eq!(safe_filename(".."), "attachment");
+    }
+}
@@ -0,0 +1,247 @@
+//! Getting Critical alerts noticed while a fullscreen app suppresses toasts
+
+use crate::messages::{Alert, AlertLevel};
+
+/// How often deferred alerts check whether toasts are accepted again
+pub const DEFERRED_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Whether Windows will currently show toasts to the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserNotificationState {
+    AcceptsNotifications,
+    /// A fullscreen app, presentation, or game is in the foreground
+    Fullscreen,
+}
+
+/// What to do with an alert given the current notification state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Show,
+    /// Flash the taskbar now and show the toast once toasts are accepted again
+    Defer,
+}
+
+/// Decide how to deliver an alert of `level`
+pub fn delivery_for(level: &AlertLevel, state: UserNotificationState) -> Delivery {
+    match (level, state) {
+        (AlertLevel::Critical, UserNotificationState::Fullscreen) => Delivery::Defer,
+        _ => Delivery::Show,
+    }
+}
+
+/// Notification-state probe and taskbar attention request
+pub trait AttentionBackend: Send + Sync {
+    fn notification_state(&self) -> UserNotificationState;
+    /// Flash the agent's taskbar button for a deferred alert
+    fn request_attention(&self, alert: &Alert);
+    /// Stop flashing once deferred alerts have been shown
+    fn clear_attention(&self);
+}
+
+/// Uses `SHQueryUserNotificationState` and `FlashWindowEx` on a small agent window
+#[derive(Default)]
+pub struct SystemAttention {
+    #[cfg(target_os = "windows")]
+    window: std::sync::OnceLock<Option<isize>>,
+}
+
+impl SystemAttention {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl SystemAttention {
+    /// Window whose taskbar button is flashed, created on first use
+    fn window(&self) -> Option<windows::Win32::Foundation::HWND> {
+        self.window
+            .get_or_init(|| match win32::spawn_attention_window() {
+                Ok(hwnd) => Some(hwnd.0),
+                Err(e) => {
+                    log::error!("Failed to create attention window: {}", e);
+                    None
+                }
+            })
+            .map(windows::Win32::Foundation::HWND)
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl AttentionBackend for SystemAttention {
+    fn notification_state(&self) -> UserNotificationState {
+        use windows::Win32::UI::Shell::{
+            SHQueryUserNotificationState, QUNS_BUSY, QUNS_PRESENTATION_MODE,
+            QUNS_RUNNING_D3D_FULL_SCREEN,
+        };
+
+        match unsafe { SHQueryUserNotificationState() } {
+            Ok(QUNS_BUSY | QUNS_RUNNING_D3D_FULL_SCREEN | QUNS_PRESENTATION_MODE) => {
+                UserNotificationState::Fullscreen
+            }
+            Ok(_) => UserNotificationState::AcceptsNotifications,
+            Err(e) => {
+                log::warn!("Failed to query notification state: {}", e);
+                UserNotificationState::AcceptsNotifications
+            }
+        }
+    }
+
+    fn request_attention(&self, alert: &Alert) {
+        use windows::Win32::UI::WindowsAndMessaging::{
+            FlashWindowEx, ShowWindow, FLASHWINFO, FLASHW_ALL, FLASHW_TIMER, SW_SHOWMINNOACTIVE,
+        };
+
+        let Some(hwnd) = self.window() else { return };
+        log::info!("Flashing taskbar for deferred alert {}", alert.id);
+        unsafe {
+            ShowWindow(hwnd, SW_SHOWMINNOACTIVE);
+            FlashWindowEx(&FLASHWINFO {
+                cbSize: std::mem::size_of::<FLASHWINFO>() as u32,
+                hwnd,
+                dwFlags: FLASHW_ALL | FLASHW_TIMER,
+                uCount: 0,
+                dwTimeout: 0,
+            });
+        }
+    }
+
+    fn clear_attention(&self) {
+        use windows::Win32::UI::WindowsAndMessaging::{
+            FlashWindowEx, ShowWindow, FLASHWINFO, FLASHW_STOP, SW_HIDE,
+        };
+
+        let Some(hwnd) = self.window() else { return };
+        unsafe {
+            FlashWindowEx(&FLASHWINFO {
+                cbSize: std::mem::size_of::<FLASHWINFO>() as u32,
+                hwnd,
+                dwFlags: FLASHW_STOP,
+                uCount: 0,
+                dwTimeout: 0,
+            });
+            ShowWindow(hwnd, SW_HIDE);
+        }
+    }
+}
+
+/// Fullscreen detection is only available on Windows; elsewhere toasts are always accepted
+#[cfg(not(target_os = "windows"))]
+impl AttentionBackend for SystemAttention {
+    fn notification_state(&self) -> UserNotificationState {
+        UserNotificationState::AcceptsNotifications
+    }
+
+    fn request_attention(&self, alert: &Alert) {
+        log::info!("Attention requested for deferred alert {}", alert.id);
+    }
+
+    fn clear_attention(&self) {}
+}
+
+#[cfg(target_os = "windows")]
+mod win32 {
+    use windows::core::{w, PCWSTR};
+    use windows::Win32::Foundation::{HINSTANCE, HWND, LPARAM, LRESULT, WPARAM};
+    use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+    use windows::Win32::UI::WindowsAndMessaging::*;
+
+    const CLASS_NAME: PCWSTR = w!("EmnsAttention");
+
+    /// Create the hidden attention window on its own message-loop thread
+    pub(super) fn spawn_attention_window() -> windows::core::Result<HWND> {
+        let (tx, rx) = std::sync::mpsc::channel::<windows::core::Result<isize>>();
+        std::thread::spawn(move || unsafe {
+            let created = create_window();
+            let ok: bool = created.is_ok();
+            let _ = tx.send(created.map(|hwnd| hwnd.0));
+            if !ok {
+                return;
+            }
+            let mut message: MSG = MSG::default();
+            while GetMessageW(&mut message, None, 0, 0).as_bool() {
+                TranslateMessage(&message);
+                DispatchMessageW(&message);
+            }
+        });
+        rx.recv()
+            .unwrap_or_else(|_| Err(windows::core::Error::from_win32()))
+            .map(HWND)
+    }
+
+    unsafe fn create_window() -> windows::core::Result<HWND> {
+        let instance: HINSTANCE = GetModuleHandleW(None)?.into();
+        let class: WNDCLASSW = WNDCLASSW {
+            lpfnWndProc: Some(window_proc),
+            hInstance: instance,
+            lpszClassName: CLASS_NAME,
+            ..Default::default()
+        };
+        RegisterClassW(&class);
+
+        let hwnd: HWND = CreateWindowExW(
+            WS_EX_APPWINDOW,
+            CLASS_NAME,
+            w!("Critical alert waiting"),
+            WS_OVERLAPPED | WS_CAPTION | WS_SYSMENU | WS_MINIMIZEBOX,
+            CW_USEDEFAULT,
+            CW_USEDEFAULT,
+            240,
+            80,
+            None,
+            None,
+            instance,
+            None,
+        );
+        if hwnd.0 == 0 {
+            return Err(windows::core::Error::from_win32());
+        }
+        Ok(hwnd)
+    }
+
+    extern "system" fn window_proc(
+        window: HWND,
+        message: u32,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        unsafe {
+            match message {
+                // The window is reused; closing it only hides it
+                WM_CLOSE => {
+                    ShowWindow(window, SW_HIDE);
+                    LRESULT(0)
+                }
+                _ => DefWindowProcW(window, message, wparam, lparam),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_critical_is_deferred_while_fullscreen() {
+        use UserNotificationState::*;
+
+        assert_eq!(
+            delivery_for(&AlertLevel::Critical, Fullscreen),
+            Delivery::Defer
+        );
+        assert_eq!(
+            delivery_for(&AlertLevel::Emergency, Fullscreen),
+            Delivery::Show
+        );
+        assert_eq!(
+            delivery_for(&AlertLevel::Warning, Fullscreen),
+            Delivery::Show
+        );
+        assert_eq!(delivery_for(&AlertLevel::Info, Fullscreen), Delivery::Show);
+        assert_eq!(
+            delivery_for(&AlertLevel::Critical, AcceptsNotifications),
+            Delivery::Show
+        );
+    }
+}
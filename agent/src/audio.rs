@@ -1,21 +1,108 @@
-use anyhow::{Context, Result};
+use crate::error::{EmnsError, Result};
+use crate::settings::SharedSettings;
+use crate::sounds::SoundLibrary;
 use rodio::{Decoder, OutputStream, Sink};
 use std::fs::File;
 use std::io::BufReader;
 use std::path::PathBuf;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
+/// How often a playing sound checks whether it should stop
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Something that can play alert sounds without blocking the caller
+pub trait AudioBackend: Send + Sync {
+    /// Start playing a sound by file name
+    fn play(&self, sound_file: &str);
+
+    /// Start playing a sound at `volume`, ignoring the volume setting.
+    ///
+    /// The sound stops early when the returned handle is stopped or dropped.
+    /// Backends that cannot stop playback play it as [`play`](Self::play) does.
+    fn play_at(&self, sound_file: &str, volume: f32) -> PlaybackHandle {
+        let _ = volume;
+        self.play(sound_file);
+        PlaybackHandle::new(CancellationToken::new())
+    }
+
+    /// Whether sounds played now would be heard; asked by the self-check.
+    ///
+    /// Backends that cannot tell say they would.
+    fn available(&self) -> bool {
+        true
+    }
+}
+
+/// Stops a sound started with [`AudioBackend::play_at`]; dropping it stops the sound too
+#[derive(Debug)]
+pub struct PlaybackHandle {
+    stop: CancellationToken,
+}
+
+impl PlaybackHandle {
+    /// A handle that cancels `stop` to end playback
+    pub fn new(stop: CancellationToken) -> Self {
+        Self { stop }
+    }
+
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+}
+
+impl Drop for PlaybackHandle {
+    fn drop(&mut self) {
+        self.stop.cancel();
+    }
+}
+
+/// Plays alert sounds from the sounds directory
 pub struct AudioPlayer {
-    sounds_dir: PathBuf,
+    sounds: SoundLibrary,
+    cancel: CancellationToken,
+    settings: SharedSettings,
 }
 
 impl AudioPlayer {
+    /// Create a player that resolves sound names against `sounds_dir`
     pub fn new(sounds_dir: PathBuf) -> Self {
-        Self { sounds_dir }
+        Self::from_library(SoundLibrary::new(sounds_dir))
+    }
+
+    /// Create a player that follows the active set of `sounds` as it is replaced
+    pub fn from_library(sounds: SoundLibrary) -> Self {
+        Self {
+            sounds,
+            cancel: CancellationToken::new(),
+            settings: SharedSettings::default(),
+        }
+    }
+
+    /// Stop any playback when `cancel` fires
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Read the playback volume from `settings`
+    pub fn with_settings(mut self, settings: SharedSettings) -> Self {
+        self.settings = settings;
+        self
     }
 
     /// Play a sound file by name
     pub fn play_sound(&self, filename: &str) -> Result<()> {
-        let sound_path: PathBuf = self.sounds_dir.join(filename);
+        self.play_sound_with(
+            filename,
+            self.settings.snapshot().playback_volume(),
+            &self.cancel,
+        )
+    }
+
+    /// Play a sound file at `volume` until it ends or `stop` fires
+    fn play_sound_with(&self, filename: &str, volume: f32, stop: &CancellationToken) -> Result<()> {
+        let sound_path: PathBuf = self.sounds.path(filename);
 
         if !sound_path.exists() {
             log::warn!(
@@ -28,22 +115,42 @@ impl AudioPlayer {
 
         log::info!("Playing sound: {}", sound_path.display());
 
+        // Load the audio file before touching the device so bad files are reported as such
+        let file: File = File::open(&sound_path).map_err(|e| {
+            EmnsError::audio(
+                Some(&sound_path),
+                format!("Failed to open sound file: {}", e),
+            )
+        })?;
+        let source: Decoder<BufReader<File>> = Decoder::new(BufReader::new(file)).map_err(|e| {
+            EmnsError::audio(
+                Some(&sound_path),
+                format!("Failed to decode audio file: {}", e),
+            )
+        })?;
+
         // Create an output stream (this needs to stay alive during playback)
-        let (_stream, stream_handle) =
-            OutputStream::try_default().context("Failed to get default audio output stream")?;
+        let (_stream, stream_handle) = OutputStream::try_default().map_err(|e| {
+            EmnsError::audio(
+                None,
+                format!("Failed to get default audio output stream: {}", e),
+            )
+        })?;
 
         // Create a sink to play audio
-        let sink = Sink::try_new(&stream_handle).context("Failed to create audio sink")?;
-
-        // Load the audio file
-        let file: File = File::open(&sound_path)
-            .with_context(|| format!("Failed to open sound file: {}", sound_path.display()))?;
-        let source: Decoder<BufReader<File>> = Decoder::new(BufReader::new(file))
-            .with_context(|| format!("Failed to decode audio file: {}", sound_path.display()))?;
+        let sink = Sink::try_new(&stream_handle)
+            .map_err(|e| EmnsError::audio(None, format!("Failed to create audio sink: {}", e)))?;
 
-        // Play the sound
+        // Play the sound, stopping early on shutdown
+        sink.set_volume(volume);
         sink.append(source);
-        sink.sleep_until_end();
+        while !sink.empty() {
+            if stop.is_cancelled() {
+                sink.stop();
+                break;
+            }
+            std::thread::sleep(CANCEL_POLL_INTERVAL);
+        }
 
         Ok(())
     }
@@ -52,16 +159,24 @@ impl AudioPlayer {
     fn play_system_beep(&self) {
         #[cfg(target_os = "windows")]
         unsafe {
-            use windows::Win32::UI::WindowsAndMessaging::{MessageBeep, MB_ICONEXCLAMATION};
+            use windows::Win32::System::Diagnostics::Debug::MessageBeep;
+            use windows::Win32::UI::WindowsAndMessaging::MB_ICONEXCLAMATION;
             let _ = MessageBeep(MB_ICONEXCLAMATION);
         }
     }
 
     /// Play sound in a separate thread (non-blocking)
     pub fn play_sound_async(&self, filename: String) {
-        let sounds_dir: PathBuf = self.sounds_dir.clone();
+        if self.cancel.is_cancelled() {
+            return;
+        }
+        let sounds: SoundLibrary = self.sounds.clone();
+        let cancel: CancellationToken = self.cancel.clone();
+        let settings: SharedSettings = self.settings.clone();
         std::thread::spawn(move || {
-            let player: AudioPlayer = AudioPlayer::new(sounds_dir);
+            let player: AudioPlayer = AudioPlayer::from_library(sounds)
+                .with_cancellation(cancel)
+                .with_settings(settings);
             if let Err(e) = player.play_sound(&filename) {
                 log::error!("Failed to play sound {}: {}", filename, e);
             }
@@ -69,6 +184,34 @@ impl AudioPlayer {
     }
 }
 
+impl AudioBackend for AudioPlayer {
+    fn play(&self, sound_file: &str) {
+        self.play_sound_async(sound_file.to_string());
+    }
+
+    fn play_at(&self, sound_file: &str, volume: f32) -> PlaybackHandle {
+        // A child token, so shutdown still stops the sound
+        let stop: CancellationToken = self.cancel.child_token();
+        if !stop.is_cancelled() {
+            let player: AudioPlayer = AudioPlayer::from_library(self.sounds.clone())
+                .with_cancellation(self.cancel.clone());
+            let filename: String = sound_file.to_string();
+            let playing: CancellationToken = stop.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = player.play_sound_with(&filename, volume, &playing) {
+                    log::error!("Failed to play sound {}: {}", filename, e);
+                }
+            });
+        }
+        PlaybackHandle::new(stop)
+    }
+
+    /// Whether a default output device can be opened
+    fn available(&self) -> bool {
+        OutputStream::try_default().is_ok()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -78,4 +221,19 @@ mod tests {
         let player: AudioPlayer = AudioPlayer::new(PathBuf::from("./sounds"));
         player.play_system_beep();
     }
+
+    #[test]
+    fn test_undecodable_file_is_audio_error() {
+        let dir: PathBuf = std::env::temp_dir();
+        let name: String = format!("emns-bad-{}.wav", uuid::Uuid::new_v4());
+        std::fs::write(dir.join(&name), b"not a wav file").unwrap();
+
+        let player: AudioPlayer = AudioPlayer::new(dir.clone());
+        let err: EmnsError = player.play_sound(&name).unwrap_err();
+        match err {
+            EmnsError::Audio { path, .. } => assert_eq!(path, Some(dir.join(&name))),
+            other => panic!("expected audio error, got {:?}", other),
+        }
+        std::fs::remove_file(dir.join(&name)).unwrap();
+    }
 }
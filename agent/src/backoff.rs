@@ -0,0 +1,236 @@
+//! Growing waits between reconnect attempts, so agents do not all hammer a
+//! server the moment it comes back
+
+use rand::Rng;
+use std::time::Duration;
+
+/// Default longest wait between reconnect attempts, before jitter
+pub const DEFAULT_BACKOFF_MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Default time a connection must stay up before the wait starts over
+pub const DEFAULT_BACKOFF_RESET_AFTER: Duration = Duration::from_secs(60);
+
+/// How the wait between reconnect attempts grows.
+///
+/// The first wait is the settings' reconnect delay. The default multiplier
+/// and jitter keep every wait at that delay.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackoffConfig {
+    /// Each wait is this many times the one before; 1.0 keeps it fixed
+    pub multiplier: f64,
+    /// Longest wait, before jitter; never below the first wait
+    pub max_delay: Duration,
+    /// Each wait is moved up or down at random by up to this percentage
+    pub jitter_percent: u32,
+    /// A connection up at least this long starts the next wait over from the first
+    pub reset_after: Duration,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            multiplier: 1.0,
+            max_delay: DEFAULT_BACKOFF_MAX_DELAY,
+            jitter_percent: 0,
+            reset_after: DEFAULT_BACKOFF_RESET_AFTER,
+        }
+    }
+}
+
+/// Where one reconnect loop is in its backoff
+#[derive(Debug, Clone)]
+pub struct ReconnectBackoff {
+    config: BackoffConfig,
+    /// Last wait before jitter; `None` until the first, and after a reset
+    last: Option<Duration>,
+    /// Whether the server has refused the agent since the last reset
+    rejected: bool,
+}
+
+impl ReconnectBackoff {
+    pub fn new(config: BackoffConfig) -> Self {
+        Self {
+            config,
+            last: None,
+            rejected: false,
+        }
+    }
+
+    /// Wait before the next attempt: `first` at the start, then growing by the
+    /// multiplier up to the maximum, with jitter drawn from `rng`
+    pub fn next_delay(&mut self, first: Duration, rng: &mut impl Rng) -> Duration {
+        let base: Duration = match self.last {
+            None => first,
+            Some(last) => {
+                let cap: f64 = self.config.max_delay.max(first).as_secs_f64();
+                Duration::from_secs_f64((last.as_secs_f64() * self.config.multiplier).min(cap))
+            }
+        };
+        self.last = Some(base);
+        self.jitter(base, rng)
+    }
+
+    /// Note a connection that stayed up for `up`; one up long enough resets the backoff
+    pub fn connection_ended(&mut self, up: Duration) {
+        if up >= self.config.reset_after {
+            self.last = None;
+            self.rejected = false;
+        }
+    }
+
+    /// The server refused the agent, which retrying soon will not change:
+    /// the next wait is the longest
+    pub fn rejected(&mut self) {
+        self.last = Some(self.config.max_delay);
+        self.rejected = true;
+    }
+
+    /// A server accepted the registration, so a refusal no longer holds the
+    /// wait at the longest, however soon the connection drops
+    pub fn registered(&mut self) {
+        if self.rejected {
+            self.last = None;
+            self.rejected = false;
+        }
+    }
+
+    fn jitter(&self, base: Duration, rng: &mut impl Rng) -> Duration {
+        if self.config.jitter_percent == 0 {
+            return base;
+        }
+        let spread: f64 = f64::from(self.config.jitter_percent.min(100)) / 100.0;
+        base.mul_f64(1.0 + rng.gen_range(-spread..=spread))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const FIRST: Duration = Duration::from_secs(5);
+
+    fn delays(backoff: &mut ReconnectBackoff, count: usize) -> Vec<Duration> {
+        let mut rng: StdRng = StdRng::seed_from_u64(7);
+        (0..count)
+            .map(|_| backoff.next_delay(FIRST, &mut rng))
+            .collect()
+    }
+
+    #[test]
+    fn test_default_keeps_the_fixed_delay() {
+        let mut backoff: ReconnectBackoff = ReconnectBackoff::new(BackoffConfig::default());
+        assert_eq!(delays(&mut backoff, 4), [FIRST; 4]);
+    }
+
+    #[test]
+    fn test_delay_grows_to_the_cap() {
+        let mut backoff: ReconnectBackoff = ReconnectBackoff::new(BackoffConfig {
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(30),
+            ..BackoffConfig::default()
+        });
+        assert_eq!(
+            delays(&mut backoff, 5),
+            [5, 10, 20, 30, 30].map(Duration::from_secs)
+        );
+    }
+
+    #[test]
+    fn test_cap_below_first_delay_keeps_first() {
+        let mut backoff: ReconnectBackoff = ReconnectBackoff::new(BackoffConfig {
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(1),
+            ..BackoffConfig::default()
+        });
+        assert_eq!(delays(&mut backoff, 3), [FIRST; 3]);
+    }
+
+    #[test]
+    fn test_jitter_stays_within_the_percentage() {
+        let mut backoff: ReconnectBackoff = ReconnectBackoff::new(BackoffConfig {
+            jitter_percent: 20,
+            ..BackoffConfig::default()
+        });
+        let delays: Vec<Duration> = delays(&mut backoff, 50);
+        assert!(delays
+            .iter()
+            .all(|d| (Duration::from_secs(4)..=Duration::from_secs(6)).contains(d)));
+        // Spread out, not all the same
+        assert!(delays.iter().any(|d| *d != delays[0]));
+    }
+
+    #[test]
+    fn test_jitter_does_not_compound() {
+        let mut backoff: ReconnectBackoff = ReconnectBackoff::new(BackoffConfig {
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(40),
+            jitter_percent: 10,
+            ..BackoffConfig::default()
+        });
+        let delays: Vec<Duration> = delays(&mut backoff, 6);
+        for (delay, base) in delays.iter().zip([5, 10, 20, 40, 40, 40]) {
+            let base: Duration = Duration::from_secs(base);
+            assert!(*delay >= base.mul_f64(0.9) && *delay <= base.mul_f64(1.1));
+        }
+    }
+
+    #[test]
+    fn test_rejection_waits_the_longest() {
+        let mut backoff: ReconnectBackoff = ReconnectBackoff::new(BackoffConfig::default());
+        assert_eq!(delays(&mut backoff, 1), [FIRST]);
+
+        backoff.rejected();
+        assert_eq!(delays(&mut backoff, 2), [DEFAULT_BACKOFF_MAX_DELAY; 2]);
+
+        // Accepted and kept up, so back to the first wait
+        backoff.connection_ended(DEFAULT_BACKOFF_RESET_AFTER);
+        assert_eq!(delays(&mut backoff, 1), [FIRST]);
+    }
+
+    #[test]
+    fn test_registration_clears_a_rejection() {
+        // A fixed multiplier never brings the longest wait back down by itself
+        let mut backoff: ReconnectBackoff = ReconnectBackoff::new(BackoffConfig::default());
+        backoff.rejected();
+        assert_eq!(delays(&mut backoff, 2), [DEFAULT_BACKOFF_MAX_DELAY; 2]);
+
+        // Accepted, then dropped well before the reset time
+        backoff.registered();
+        backoff.connection_ended(Duration::from_secs(1));
+        assert_eq!(delays(&mut backoff, 2), [FIRST; 2]);
+    }
+
+    #[test]
+    fn test_registration_keeps_growth_from_failed_connects() {
+        let mut backoff: ReconnectBackoff = ReconnectBackoff::new(BackoffConfig {
+            multiplier: 2.0,
+            ..BackoffConfig::default()
+        });
+        assert_eq!(delays(&mut backoff, 2), [5, 10].map(Duration::from_secs));
+
+        // Only a connection that stays up resets growth that was not from a refusal
+        backoff.registered();
+        assert_eq!(delays(&mut backoff, 1), [Duration::from_secs(20)]);
+    }
+
+    #[test]
+    fn test_long_connection_resets_the_delay() {
+        let mut backoff: ReconnectBackoff = ReconnectBackoff::new(BackoffConfig {
+            multiplier: 3.0,
+            ..BackoffConfig::default()
+        });
+        assert_eq!(
+            delays(&mut backoff, 3),
+            [5, 15, 45].map(Duration::from_secs)
+        );
+
+        // Dropped again too soon: keeps growing
+        backoff.connection_ended(DEFAULT_BACKOFF_RESET_AFTER - Duration::from_secs(1));
+        assert_eq!(delays(&mut backoff, 1), [Duration::from_secs(135)]);
+
+        backoff.connection_ended(DEFAULT_BACKOFF_RESET_AFTER);
+        assert_eq!(delays(&mut backoff, 2), [5, 15].map(Duration::from_secs));
+    }
+}
@@ -0,0 +1,195 @@
+//! Pending and recent alerts as one document, for kiosks that keep them on screen
+
+use crate::decision::DeliveryDecision;
+use crate::history::HistoryEntry;
+use crate::messages::{AlertLevel, SuppressionWindow};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Version of the [`AlertBoard`] shape; bumped when a field changes meaning or goes away
+pub const BOARD_VERSION: u32 = 1;
+
+/// Default age past which alerts that need no confirmation leave the board
+pub const DEFAULT_RECENT_WINDOW: Duration = Duration::from_secs(4 * 3600);
+
+/// How often a board stream is rebuilt even without a change, so recent alerts age out
+pub const BOARD_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Alerts a kiosk should show, and the suppression windows in effect
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AlertBoard {
+    pub version: u32,
+    pub generated_at: DateTime<Utc>,
+    /// Alerts waiting to be confirmed, oldest first
+    pub pending: Vec<PendingItem>,
+    /// Alerts that needed no confirmation, received within the recent window, newest first
+    pub recent: Vec<RecentItem>,
+    /// Suppression windows that have not ended, including ones yet to start
+    pub suppressions: Vec<SuppressionWindow>,
+}
+
+impl AlertBoard {
+    /// Whether `other` lists the same alerts and windows, whenever it was generated
+    pub fn same_contents(&self, other: &AlertBoard) -> bool {
+        self.pending == other.pending
+            && self.recent == other.recent
+            && self.suppressions == other.suppressions
+    }
+}
+
+/// An alert waiting for the user to confirm it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PendingItem {
+    pub alert_id: uuid::Uuid,
+    pub level: AlertLevel,
+    pub title: String,
+    pub message: String,
+    pub sent_at: DateTime<Utc>,
+    pub received_at: DateTime<Utc>,
+    /// When it will be confirmed on the user's behalf, as things stand;
+    /// absent if it waits until someone confirms it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_confirm_at: Option<DateTime<Utc>>,
+    /// When it will switch to its escalation sound; absent if it never will
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalates_at: Option<DateTime<Utc>>,
+    /// When it switched to its escalation sound
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalated_at: Option<DateTime<Utc>>,
+    /// Why it did or did not sound and show
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decision: Option<DeliveryDecision>,
+}
+
+/// A recent alert that needed no confirmation
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RecentItem {
+    pub alert_id: uuid::Uuid,
+    pub level: AlertLevel,
+    pub title: String,
+    pub message: String,
+    pub sent_at: DateTime<Utc>,
+    pub received_at: DateTime<Utc>,
+    /// Why it did or did not sound and show
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decision: Option<DeliveryDecision>,
+}
+
+impl From<&HistoryEntry> for RecentItem {
+    fn from(entry: &HistoryEntry) -> Self {
+        Self {
+            alert_id: entry.alert_id,
+            level: entry.level.clone(),
+            title: entry.title.clone(),
+            message: entry.message.clone(),
+            sent_at: entry.sent_at,
+            received_at: entry.received_at,
+            decision: entry.decision.clone(),
+        }
+    }
+}
+
+/// Revision counter bumped whenever something on the board may have changed
+#[derive(Debug, Clone)]
+pub struct BoardChanges(watch::Sender<u64>);
+
+impl BoardChanges {
+    pub fn new() -> Self {
+        Self(watch::Sender::new(0))
+    }
+
+    pub fn bump(&self) {
+        self.0.send_modify(|revision| *revision += 1);
+    }
+
+    /// Receiver that is marked changed at every later bump
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.0.subscribe()
+    }
+}
+
+impl Default for BoardChanges {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decision::{Rule, RuleOutcome, Verdict};
+
+    /// The v1 document, as kiosk pages written against it expect
+    const SNAPSHOT_V1: &str = include_str!("../tests/snapshots/alert_board_v1.json");
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().into()
+    }
+
+    fn sample() -> AlertBoard {
+        AlertBoard {
+            version: BOARD_VERSION,
+            generated_at: at("2024-01-15T10:35:00Z"),
+            pending: vec![PendingItem {
+                alert_id: uuid::Uuid::parse_str("123e4567-e89b-12d3-a456-426614174000").unwrap(),
+                level: AlertLevel::Critical,
+                title: "Shelter in place".to_string(),
+                message: "Move away from windows".to_string(),
+                sent_at: at("2024-01-15T10:30:00Z"),
+                received_at: at("2024-01-15T10:30:01Z"),
+                auto_confirm_at: Some(at("2024-01-15T10:35:01Z")),
+                escalates_at: None,
+                escalated_at: Some(at("2024-01-15T10:32:01Z")),
+                decision: None,
+            }],
+            recent: vec![RecentItem {
+                alert_id: uuid::Uuid::parse_str("123e4567-e89b-12d3-a456-426614174001").unwrap(),
+                level: AlertLevel::Info,
+                title: "Fire drill at 14:00".to_string(),
+                message: "Assemble in car park B".to_string(),
+                sent_at: at("2024-01-15T09:00:00Z"),
+                received_at: at("2024-01-15T09:00:02Z"),
+                decision: Some(DeliveryDecision {
+                    steps: vec![RuleOutcome {
+                        rule: Rule::QuietHours,
+                        verdict: Verdict::Silence,
+                        detail: None,
+                    }],
+                }),
+            }],
+            suppressions: vec![SuppressionWindow {
+                id: uuid::Uuid::parse_str("123e4567-e89b-12d3-a456-426614174002").unwrap(),
+                starts_at: at("2024-01-15T14:00:00Z"),
+                ends_at: at("2024-01-15T15:00:00Z"),
+                levels: vec![AlertLevel::Info],
+                categories: Vec::new(),
+                reason: "Fire drill".to_string(),
+                location: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_board_matches_v1_snapshot() {
+        let snapshot: serde_json::Value = serde_json::from_str(SNAPSHOT_V1).unwrap();
+        assert_eq!(serde_json::to_value(sample()).unwrap(), snapshot);
+        let parsed: AlertBoard = serde_json::from_value(snapshot).unwrap();
+        assert_eq!(parsed, sample());
+    }
+
+    #[test]
+    fn test_same_contents_ignores_generation_time() {
+        let later: AlertBoard = AlertBoard {
+            generated_at: at("2024-01-15T10:36:00Z"),
+            ..sample()
+        };
+        assert!(later.same_contents(&sample()));
+        let emptied: AlertBoard = AlertBoard {
+            recent: Vec::new(),
+            ..sample()
+        };
+        assert!(!emptied.same_contents(&sample()));
+    }
+}
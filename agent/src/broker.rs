@@ -0,0 +1,761 @@
+//! Fan-out of alerts to per-session helper processes on multi-user hosts.
+//!
+//! In broker mode the service keeps the single server connection and each
+//! interactive session runs `emns-agent --session-helper`, which connects over
+//! a named pipe, shows alerts in its session, and relays confirmations back.
+//! Messages on the pipe are newline-delimited JSON [`PipeMessage`]s.
+
+use crate::error::{EmnsError, Result};
+use crate::handler::sound_suppressed_status;
+use crate::messages::{
+    Alert, Confirmation, ConfirmationMethod, ConfirmationReason, ConfirmationResponse, ReceivedVia,
+    SoundPolicy,
+};
+use crate::outbound::{OutboundMessage, OutboundQueue};
+use crate::settings::{AgentSettings, SharedSettings};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// Pipe the broker listens on unless `SESSION_PIPE_NAME` says otherwise
+pub const DEFAULT_PIPE_NAME: &str = r"\\.\pipe\emns-agent";
+
+/// How the agent delivers alerts to users
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SessionMode {
+    /// Show alerts in the agent's own session
+    #[default]
+    Standalone,
+    /// Forward alerts to a helper in every interactive session
+    Broker,
+}
+
+impl SessionMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "standalone" => Some(Self::Standalone),
+            "broker" => Some(Self::Broker),
+            _ => None,
+        }
+    }
+}
+
+/// One line on the broker pipe
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PipeMessage {
+    /// First message from a helper, identifying its session
+    Hello { session_id: u32, username: String },
+    /// Broker to helper: show this alert under the service's current sound policy and server identity
+    Alert {
+        alert: Box<Alert>,
+        #[serde(default)]
+        sound_policy: Option<SoundPolicy>,
+        #[serde(default)]
+        server_name: Option<String>,
+        #[serde(default)]
+        server_environment: Option<String>,
+    },
+    /// Helper to broker: a user in the session confirmed an alert
+    Confirm {
+        alert_id: Uuid,
+        username: String,
+        #[serde(default)]
+        operator_id: Option<String>,
+        confirmed_at: DateTime<Utc>,
+        #[serde(default)]
+        reason: ConfirmationReason,
+        #[serde(default)]
+        method: Option<ConfirmationMethod>,
+        #[serde(default)]
+        user_idle_secs: Option<u64>,
+        #[serde(default)]
+        response_id: Option<String>,
+        #[serde(default)]
+        response: ConfirmationResponse,
+        #[serde(default)]
+        shown_at: Option<DateTime<Utc>>,
+        #[serde(default)]
+        response_latency_ms: Option<u64>,
+        #[serde(default)]
+        is_preview: bool,
+    },
+}
+
+/// Read the next message, or `None` when the peer closed the pipe
+pub(crate) async fn read_message<R: AsyncRead + Unpin>(
+    lines: &mut Lines<BufReader<R>>,
+) -> Result<Option<PipeMessage>> {
+    loop {
+        let Some(line) = lines.next_line().await.map_err(EmnsError::protocol)? else {
+            return Ok(None);
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        return serde_json::from_str(&line)
+            .map(Some)
+            .map_err(EmnsError::protocol);
+    }
+}
+
+pub(crate) async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &PipeMessage,
+) -> Result<()> {
+    let mut line: String = serde_json::to_string(message).map_err(EmnsError::protocol)?;
+    line.push('\n');
+    writer
+        .write_all(line.as_bytes())
+        .await
+        .map_err(EmnsError::protocol)
+}
+
+/// A confirmation received from one session
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionConfirmation {
+    pub session_id: u32,
+    pub username: String,
+    pub confirmed_at: DateTime<Utc>,
+    /// Whether this was the confirmation sent to the server
+    pub first: bool,
+}
+
+/// A connected helper
+struct SessionHandle {
+    session_id: u32,
+    username: String,
+    alerts: mpsc::UnboundedSender<Alert>,
+}
+
+/// Tracks connected session helpers and aggregates their confirmations
+pub struct SessionBroker {
+    client_id: String,
+    hostname: String,
+    outbound: Arc<OutboundQueue>,
+    settings: SharedSettings,
+    sessions: Mutex<HashMap<u64, SessionHandle>>,
+    next_connection: AtomicU64,
+    confirmations: Mutex<HashMap<Uuid, Vec<SessionConfirmation>>>,
+}
+
+impl SessionBroker {
+    pub fn new(
+        client_id: impl Into<String>,
+        hostname: impl Into<String>,
+        outbound: Arc<OutboundQueue>,
+    ) -> Self {
+        Self {
+            client_id: client_id.into(),
+            hostname: hostname.into(),
+            outbound,
+            settings: SharedSettings::default(),
+            sessions: Mutex::new(HashMap::new()),
+            next_connection: AtomicU64::new(0),
+            confirmations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Pass the sound policy and server identity in `settings` on to helpers with each alert
+    pub fn with_settings(mut self, settings: SharedSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Send an alert to every connected session, returning how many received it
+    pub fn dispatch(&self, alert: &Alert) -> usize {
+        // Helpers enforce the policy; the service, holding the connection, reports it
+        if !self
+            .settings
+            .snapshot()
+            .sound_policy()
+            .permits(&alert.get_sound_file())
+        {
+            self.outbound
+                .push(sound_suppressed_status(alert.id, &self.client_id));
+        }
+        let sessions = self.sessions.lock().unwrap();
+        let delivered: usize = sessions
+            .values()
+            .filter(|s| s.alerts.send(alert.clone()).is_ok())
+            .count();
+        if delivered == 0 {
+            log::warn!("No session helpers connected for alert {}", alert.id);
+        }
+        delivered
+    }
+
+    /// Sessions with a connected helper, as `(session id, username)`
+    pub fn sessions(&self) -> Vec<(u32, String)> {
+        let mut sessions: Vec<(u32, String)> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|s| (s.session_id, s.username.clone()))
+            .collect();
+        sessions.sort();
+        sessions
+    }
+
+    /// Every confirmation received for an alert, in arrival order
+    pub fn confirmations(&self, alert_id: Uuid) -> Vec<SessionConfirmation> {
+        self.confirmations
+            .lock()
+            .unwrap()
+            .get(&alert_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Serve one helper connection until it disconnects or `cancel` fires
+    pub async fn serve_connection<S>(&self, stream: S, cancel: CancellationToken) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut lines = BufReader::new(reader).lines();
+
+        let (session_id, username) = match read_message(&mut lines).await? {
+            Some(PipeMessage::Hello {
+                session_id,
+                username,
+            }) => (session_id, username),
+            Some(other) => {
+                return Err(EmnsError::protocol(format!(
+                    "expected hello from session helper, got {:?}",
+                    other
+                )))
+            }
+            None => return Ok(()),
+        };
+
+        let connection: u64 = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        let (alerts_tx, mut alerts_rx) = mpsc::unbounded_channel::<Alert>();
+        self.sessions.lock().unwrap().insert(
+            connection,
+            SessionHandle {
+                session_id,
+                username: username.clone(),
+                alerts: alerts_tx,
+            },
+        );
+        log::info!(
+            "Session helper connected for {} (session {})",
+            username,
+            session_id
+        );
+
+        let result: Result<()> = async {
+            loop {
+                tokio::select! {
+                    _ = cancel.cancelled() => return Ok(()),
+                    Some(alert) = alerts_rx.recv() => {
+                        let settings: AgentSettings = self.settings.snapshot();
+                        let message: PipeMessage = PipeMessage::Alert {
+                            alert: Box::new(alert),
+                            sound_policy: Some(settings.sound_policy().clone()),
+                            server_name: settings.server_name().map(String::from),
+                            server_environment: settings.server_environment().map(String::from),
+                        };
+                        write_message(&mut writer, &message).await?;
+                    }
+                    message = read_message(&mut lines) => match message? {
+                        Some(PipeMessage::Confirm {
+                            alert_id,
+                            username,
+                            operator_id,
+                            confirmed_at,
+                            reason,
+                            method,
+                            user_idle_secs,
+                            response_id,
+                            response,
+                            shown_at,
+                            response_latency_ms,
+                            is_preview,
+                        }) => {
+                            self.record_confirmation(session_id, Confirmation {
+                                alert_id,
+                                client_id: self.client_id.clone(),
+                                confirmed_at,
+                                hostname: self.hostname.clone(),
+                                username,
+                                operator_id,
+                                reason,
+                                method,
+                                user_idle_secs,
+                                response_id,
+                                response,
+                                // Broker mode does not listen for multicast
+                                received_via: ReceivedVia::WebSocket,
+                                shown_at,
+                                response_latency_ms,
+                                is_preview,
+                            })?;
+                        }
+                        Some(other) => log::warn!("Unexpected message from session helper: {:?}", other),
+                        None => return Ok(()),
+                    },
+                }
+            }
+        }
+        .await;
+
+        self.sessions.lock().unwrap().remove(&connection);
+        log::info!("Session helper for session {} disconnected", session_id);
+        result
+    }
+
+    /// Record a session's confirmation; the first one per alert goes to the server
+    fn record_confirmation(&self, session_id: u32, confirmation: Confirmation) -> Result<()> {
+        let alert_id: Uuid = confirmation.alert_id;
+        let first: bool = {
+            let mut confirmations = self.confirmations.lock().unwrap();
+            let records: &mut Vec<SessionConfirmation> = confirmations.entry(alert_id).or_default();
+            let first: bool = records.is_empty();
+            records.push(SessionConfirmation {
+                session_id,
+                username: confirmation.username.clone(),
+                confirmed_at: confirmation.confirmed_at,
+                first,
+            });
+            first
+        };
+
+        if !first {
+            log::info!(
+                "Alert {} also confirmed by {} (session {})",
+                alert_id,
+                confirmation.username,
+                session_id
+            );
+            return Ok(());
+        }
+        log::info!(
+            "Alert {} confirmed by {} (session {})",
+            alert_id,
+            confirmation.username,
+            session_id
+        );
+        self.outbound
+            .push(OutboundMessage::Confirmation(confirmation));
+        Ok(())
+    }
+}
+
+/// Accept helper connections on the named pipe until `cancel` fires
+#[cfg(target_os = "windows")]
+pub async fn listen(
+    broker: Arc<SessionBroker>,
+    pipe_name: String,
+    cancel: CancellationToken,
+    tracker: tokio_util::task::TaskTracker,
+) -> Result<()> {
+    use tokio::net::windows::named_pipe::NamedPipeServer;
+
+    let mut server: NamedPipeServer = wts::create_pipe(&pipe_name, true)?;
+    log::info!("Session broker listening on {}", pipe_name);
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            connected = server.connect() => {
+                connected.map_err(|e| EmnsError::connection(pipe_name.clone(), e))?;
+            }
+        }
+        let client: NamedPipeServer =
+            std::mem::replace(&mut server, wts::create_pipe(&pipe_name, false)?);
+        let broker: Arc<SessionBroker> = broker.clone();
+        let cancel: CancellationToken = cancel.clone();
+        tracker.spawn(async move {
+            if let Err(e) = broker.serve_connection(client, cancel).await {
+                log::warn!("Session helper connection failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Named pipes are only available on Windows
+#[cfg(not(target_os = "windows"))]
+pub async fn listen(
+    _broker: Arc<SessionBroker>,
+    pipe_name: String,
+    _cancel: CancellationToken,
+    _tracker: tokio_util::task::TaskTracker,
+) -> Result<()> {
+    Err(EmnsError::config(
+        "SESSION_MODE",
+        format!("broker mode needs Windows named pipes ({})", pipe_name),
+    ))
+}
+
+/// How often the broker looks for sessions without a helper
+pub const SESSION_SCAN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Start a helper in every active session that lacks one, until `cancel` fires
+#[cfg(target_os = "windows")]
+pub async fn launch_helpers(broker: Arc<SessionBroker>, cancel: CancellationToken) {
+    let exe: std::path::PathBuf = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            log::error!(
+                "Cannot find agent executable to launch session helpers: {}",
+                e
+            );
+            return;
+        }
+    };
+    // Sessions we launched into, so a helper that is still starting is not launched twice
+    let mut launched: HashMap<u32, std::time::Instant> = HashMap::new();
+    let mut scan = tokio::time::interval(SESSION_SCAN_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = scan.tick() => {}
+        }
+        let sessions = match wts::active_sessions() {
+            Ok(sessions) => sessions,
+            Err(e) => {
+                log::warn!("Failed to enumerate sessions: {}", e);
+                continue;
+            }
+        };
+        let connected: Vec<u32> = broker.sessions().into_iter().map(|(id, _)| id).collect();
+        for (session_id, username) in sessions {
+            let recently_launched: bool = launched
+                .get(&session_id)
+                .is_some_and(|at| at.elapsed() < SESSION_SCAN_INTERVAL * 2);
+            if connected.contains(&session_id) || recently_launched {
+                continue;
+            }
+            match wts::launch_in_session(session_id, &exe) {
+                Ok(()) => {
+                    log::info!(
+                        "Started session helper for {} (session {})",
+                        username,
+                        session_id
+                    );
+                    launched.insert(session_id, std::time::Instant::now());
+                }
+                Err(e) => log::warn!("Failed to start helper in session {}: {}", session_id, e),
+            }
+        }
+    }
+}
+
+/// Session enumeration is only available on Windows
+#[cfg(not(target_os = "windows"))]
+pub async fn launch_helpers(_broker: Arc<SessionBroker>, _cancel: CancellationToken) {}
+
+/// Id of the session this process runs in
+#[cfg(target_os = "windows")]
+pub fn current_session_id() -> u32 {
+    use windows::Win32::System::RemoteDesktop::ProcessIdToSessionId;
+    let mut session_id: u32 = 0;
+    unsafe {
+        let _ = ProcessIdToSessionId(std::process::id(), &mut session_id);
+    }
+    session_id
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn current_session_id() -> u32 {
+    0
+}
+
+#[cfg(target_os = "windows")]
+mod wts {
+    use crate::error::{EmnsError, Result};
+    use std::path::Path;
+    use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+    use windows::core::{w, HSTRING, PWSTR};
+    use windows::Win32::Foundation::{CloseHandle, LocalFree, HANDLE, HLOCAL};
+    use windows::Win32::Security::Authorization::{
+        ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+    };
+    use windows::Win32::Security::{PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES};
+    use windows::Win32::System::RemoteDesktop::{
+        WTSActive, WTSEnumerateSessionsW, WTSFreeMemory, WTSQuerySessionInformationW,
+        WTSQueryUserToken, WTSUserName, WTS_CURRENT_SERVER_HANDLE, WTS_SESSION_INFOW,
+    };
+    use windows::Win32::System::Threading::{
+        CreateProcessAsUserW, CREATE_NO_WINDOW, PROCESS_INFORMATION, STARTUPINFOW,
+    };
+
+    /// SYSTEM gets full control; interactive users may connect and read/write
+    const PIPE_SDDL: &str = "D:(A;;GA;;;SY)(A;;GRGW;;;IU)";
+
+    pub(super) fn create_pipe(pipe_name: &str, first: bool) -> Result<NamedPipeServer> {
+        let fail = |e: &dyn std::fmt::Display| EmnsError::connection(pipe_name, e);
+        unsafe {
+            let mut descriptor: PSECURITY_DESCRIPTOR = PSECURITY_DESCRIPTOR::default();
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                &HSTRING::from(PIPE_SDDL),
+                SDDL_REVISION_1,
+                &mut descriptor,
+                None,
+            )
+            .map_err(|e| fail(&e))?;
+            let mut attributes: SECURITY_ATTRIBUTES = SECURITY_ATTRIBUTES {
+                nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+                lpSecurityDescriptor: descriptor.0,
+                bInheritHandle: false.into(),
+            };
+            let server = ServerOptions::new()
+                .first_pipe_instance(first)
+                .reject_remote_clients(true)
+                .create_with_security_attributes_raw(
+                    pipe_name,
+                    &mut attributes as *mut SECURITY_ATTRIBUTES as *mut std::ffi::c_void,
+                );
+            let _ = LocalFree(HLOCAL(descriptor.0));
+            server.map_err(|e| fail(&e))
+        }
+    }
+
+    /// Active sessions as `(session id, username)`, skipping sessions with nobody logged on
+    pub(super) fn active_sessions() -> Result<Vec<(u32, String)>> {
+        let mut sessions: Vec<(u32, String)> = Vec::new();
+        unsafe {
+            let mut info: *mut WTS_SESSION_INFOW = std::ptr::null_mut();
+            let mut count: u32 = 0;
+            WTSEnumerateSessionsW(WTS_CURRENT_SERVER_HANDLE, 0, 1, &mut info, &mut count)
+                .map_err(|e| EmnsError::connection("WTS", e))?;
+            for session in std::slice::from_raw_parts(info, count as usize) {
+                if session.State != WTSActive {
+                    continue;
+                }
+                let mut buffer: PWSTR = PWSTR::null();
+                let mut bytes: u32 = 0;
+                if WTSQuerySessionInformationW(
+                    WTS_CURRENT_SERVER_HANDLE,
+                    session.SessionId,
+                    WTSUserName,
+                    &mut buffer,
+                    &mut bytes,
+                )
+                .is_ok()
+                {
+                    let username: String = buffer.to_string().unwrap_or_default();
+                    WTSFreeMemory(buffer.0 as *mut std::ffi::c_void);
+                    if !username.is_empty() {
+                        sessions.push((session.SessionId, username));
+                    }
+                }
+            }
+            WTSFreeMemory(info as *mut std::ffi::c_void);
+        }
+        Ok(sessions)
+    }
+
+    /// Start `exe --session-helper` as the user logged on to `session_id`
+    pub(super) fn launch_in_session(session_id: u32, exe: &Path) -> Result<()> {
+        let fail =
+            |e: windows::core::Error| EmnsError::connection(format!("session {}", session_id), e);
+        unsafe {
+            let mut token: HANDLE = HANDLE::default();
+            WTSQueryUserToken(session_id, &mut token).map_err(fail)?;
+
+            let mut command_line: Vec<u16> = format!("\"{}\" --session-helper", exe.display())
+                .encode_utf16()
+                .chain(std::iter::once(0))
+                .collect();
+            let startup: STARTUPINFOW = STARTUPINFOW {
+                cb: std::mem::size_of::<STARTUPINFOW>() as u32,
+                lpDesktop: PWSTR(w!("winsta0\\default").as_ptr() as *mut u16),
+                ..Default::default()
+            };
+            let mut process: PROCESS_INFORMATION = PROCESS_INFORMATION::default();
+            let created = CreateProcessAsUserW(
+                token,
+                None,
+                PWSTR(command_line.as_mut_ptr()),
+                None,
+                None,
+                false,
+                CREATE_NO_WINDOW,
+                None,
+                None,
+                &startup,
+                &mut process,
+            );
+            let _ = CloseHandle(token);
+            created.map_err(fail)?;
+            let _ = CloseHandle(process.hThread);
+            let _ = CloseHandle(process.hProcess);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::AlertHandler;
+    use crate::messages::AlertLevel;
+    use crate::session_helper;
+    use crate::test_support::{alert, Confirmations, MockAudio, MockNotifier};
+    use std::time::Duration;
+
+    #[test]
+    fn test_pipe_messages_round_trip() {
+        let message: PipeMessage = PipeMessage::Confirm {
+            alert_id: Uuid::new_v4(),
+            username: "ops1".to_string(),
+            operator_id: None,
+            confirmed_at: Utc::now(),
+            reason: ConfirmationReason::User,
+            method: Some(ConfirmationMethod::ToastButton),
+            user_idle_secs: Some(2),
+            response_id: Some("off-site".to_string()),
+            response: ConfirmationResponse::CannotComply {
+                note: Some("Working from home".to_string()),
+            },
+            shown_at: Some(Utc::now()),
+            response_latency_ms: Some(4_200),
+            is_preview: false,
+        };
+        let json: String = serde_json::to_string(&message).unwrap();
+        assert!(json.contains(r#""type":"confirm""#));
+        let decoded: PipeMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+    }
+
+    #[tokio::test]
+    async fn test_helper_must_say_hello_first() {
+        let broker: SessionBroker =
+            SessionBroker::new("test-client", "host", Arc::new(OutboundQueue::default()));
+        let (broker_side, mut helper_side) = tokio::io::duplex(4096);
+        write_message(
+            &mut helper_side,
+            &PipeMessage::Alert {
+                alert: Box::new(alert(AlertLevel::Info, false)),
+                sound_policy: None,
+                server_name: None,
+                server_environment: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        let result: Result<()> = broker
+            .serve_connection(broker_side, CancellationToken::new())
+            .await;
+        assert!(matches!(result, Err(EmnsError::Protocol { .. })));
+        assert!(broker.sessions().is_empty());
+    }
+
+    /// A helper running over an in-memory pipe, with the handler it feeds
+    struct SimulatedHelper {
+        handler: Arc<AlertHandler>,
+        notifier: Arc<MockNotifier>,
+    }
+
+    fn connect_helper(
+        broker: &Arc<SessionBroker>,
+        session_id: u32,
+        username: &'static str,
+        cancel: &CancellationToken,
+    ) -> SimulatedHelper {
+        let (broker_side, helper_side) = tokio::io::duplex(64 * 1024);
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let handler: Arc<AlertHandler> = Arc::new(
+            AlertHandler::builder(outbound.clone(), "session-helper")
+                .notification_backend(notifier.clone())
+                .audio_backend(Arc::new(MockAudio::default()))
+                .build(),
+        );
+
+        let serving: Arc<SessionBroker> = broker.clone();
+        let cancel_broker: CancellationToken = cancel.clone();
+        tokio::spawn(async move {
+            serving
+                .serve_connection(broker_side, cancel_broker)
+                .await
+                .unwrap();
+        });
+        let helper_handler: Arc<AlertHandler> = handler.clone();
+        let cancel_helper: CancellationToken = cancel.clone();
+        tokio::spawn(async move {
+            session_helper::serve(
+                helper_side,
+                session_id,
+                username,
+                &helper_handler,
+                &outbound,
+                &cancel_helper,
+            )
+            .await
+            .unwrap();
+        });
+
+        SimulatedHelper { handler, notifier }
+    }
+
+    async fn eventually(mut check: impl FnMut() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !check() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("condition not reached");
+    }
+
+    #[tokio::test]
+    async fn test_first_session_confirmation_wins() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let broker: Arc<SessionBroker> = Arc::new(SessionBroker::new(
+            "rds-host-01",
+            "rds-host-01",
+            outbound.clone(),
+        ));
+        let cancel: CancellationToken = CancellationToken::new();
+        let alice: SimulatedHelper = connect_helper(&broker, 2, "alice", &cancel);
+        let bob: SimulatedHelper = connect_helper(&broker, 3, "bob", &cancel);
+        eventually(|| broker.sessions().len() == 2).await;
+        assert_eq!(
+            broker.sessions(),
+            vec![(2, "alice".to_string()), (3, "bob".to_string())]
+        );
+
+        // Both sessions show the alert
+        let alert: Alert = alert(AlertLevel::Critical, true);
+        assert_eq!(broker.dispatch(&alert), 2);
+        eventually(|| alice.notifier.shown().len() == 1 && bob.notifier.shown().len() == 1).await;
+
+        bob.handler
+            .confirm_alert(alert.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        let confirmation: Confirmation = confirmations.recv().await;
+        assert_eq!(confirmation.alert_id, alert.id);
+        assert_eq!(confirmation.client_id, "rds-host-01");
+        assert_eq!(confirmation.method, Some(ConfirmationMethod::ToastButton));
+
+        alice
+            .handler
+            .confirm_alert(alert.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        eventually(|| broker.confirmations(alert.id).len() == 2).await;
+        let records: Vec<SessionConfirmation> = broker.confirmations(alert.id);
+        assert_eq!(records[0].session_id, 3);
+        assert!(records[0].first);
+        assert_eq!(records[1].session_id, 2);
+        assert!(!records[1].first);
+        // Only the first confirmation reaches the server
+        assert!(confirmations.try_recv().is_none());
+
+        cancel.cancel();
+        eventually(|| broker.sessions().is_empty()).await;
+    }
+}
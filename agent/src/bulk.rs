@@ -0,0 +1,151 @@
+//! Confirming or dismissing many pending alerts at once
+
+use crate::error::{EmnsError, Result};
+use crate::messages::AlertLevel;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Which pending alerts a bulk confirm or dismiss applies to.
+///
+/// Emergency alerts are never included, whatever the filter says.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BulkFilter {
+    /// Levels to include; every level but Emergency when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub levels: Vec<AlertLevel>,
+    /// Only alerts received at most this many seconds ago
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_secs: Option<u64>,
+}
+
+impl BulkFilter {
+    /// Every pending alert but Emergency ones
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// Only alerts at `level`; may be called more than once
+    pub fn level(mut self, level: AlertLevel) -> Self {
+        self.levels.push(level);
+        self
+    }
+
+    /// Only alerts received at most `max_age` ago
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age_secs = Some(max_age.as_secs());
+        self
+    }
+
+    /// Parse `--level <level>`, which may repeat, and `--max-age-mins <minutes>`
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut filter: BulkFilter = BulkFilter::all();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| EmnsError::config(name, "needs a value"))
+            };
+            match arg.as_str() {
+                "--level" => {
+                    let value: String = value("--level")?;
+                    let level: AlertLevel =
+                        [AlertLevel::Info, AlertLevel::Warning, AlertLevel::Critical]
+                            .into_iter()
+                            .find(|level| level.as_str().eq_ignore_ascii_case(&value))
+                            .ok_or_else(|| {
+                                EmnsError::config(
+                                    "--level",
+                                    format!("expected info, warning or critical, got {}", value),
+                                )
+                            })?;
+                    filter = filter.level(level);
+                }
+                "--max-age-mins" => {
+                    let minutes: u64 = value("--max-age-mins")?.parse().map_err(|e| {
+                        EmnsError::config("--max-age-mins", format!("not a number: {}", e))
+                    })?;
+                    filter = filter.max_age(Duration::from_secs(minutes * 60));
+                }
+                other => return Err(EmnsError::config(other, "unknown argument")),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Whether a pending alert at `level`, received `age` ago, is included
+    pub fn matches(&self, level: &AlertLevel, age: Duration) -> bool {
+        *level != AlertLevel::Emergency
+            && (self.levels.is_empty() || self.levels.contains(level))
+            && self
+                .max_age_secs
+                .is_none_or(|max| age <= Duration::from_secs(max))
+    }
+}
+
+/// What a bulk confirm or dismiss did
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BulkSummary {
+    /// Alerts confirmed or dismissed, oldest first, in the order their messages were queued
+    pub resolved: Vec<uuid::Uuid>,
+    /// Pending alerts left alone: Emergency, outside the filter, or the operator id prompt was cancelled
+    pub skipped: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_never_matches_emergency() {
+        let minute: Duration = Duration::from_secs(60);
+        let all: BulkFilter = BulkFilter::all();
+        assert!(all.matches(&AlertLevel::Info, minute));
+        assert!(all.matches(&AlertLevel::Critical, minute));
+        assert!(!all.matches(&AlertLevel::Emergency, minute));
+        assert!(!BulkFilter::all()
+            .level(AlertLevel::Emergency)
+            .matches(&AlertLevel::Emergency, minute));
+
+        let recent_info: BulkFilter = BulkFilter::all()
+            .level(AlertLevel::Info)
+            .max_age(Duration::from_secs(3600));
+        assert!(recent_info.matches(&AlertLevel::Info, Duration::from_secs(3600)));
+        assert!(!recent_info.matches(&AlertLevel::Info, Duration::from_secs(3601)));
+        assert!(!recent_info.matches(&AlertLevel::Warning, minute));
+    }
+
+    #[test]
+    fn test_filter_json_omits_defaults() {
+        assert_eq!(serde_json::to_string(&BulkFilter::all()).unwrap(), "{}");
+        let parsed: BulkFilter =
+            serde_json::from_str(r#"{"levels": ["info", "warning"], "max_age_secs": 900}"#)
+                .unwrap();
+        assert_eq!(
+            parsed,
+            BulkFilter::all()
+                .level(AlertLevel::Info)
+                .level(AlertLevel::Warning)
+                .max_age(Duration::from_secs(900))
+        );
+    }
+
+    #[test]
+    fn test_filter_from_args() {
+        let args = |line: &str| {
+            line.split_whitespace()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(BulkFilter::from_args(args("")).unwrap(), BulkFilter::all());
+        assert_eq!(
+            BulkFilter::from_args(args("--level info --level Warning --max-age-mins 30")).unwrap(),
+            BulkFilter::all()
+                .level(AlertLevel::Info)
+                .level(AlertLevel::Warning)
+                .max_age(Duration::from_secs(1800))
+        );
+        assert!(BulkFilter::from_args(args("--level emergency")).is_err());
+        assert!(BulkFilter::from_args(args("--max-age-mins")).is_err());
+        assert!(BulkFilter::from_args(args("--everything")).is_err());
+    }
+}
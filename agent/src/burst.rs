@@ -0,0 +1,269 @@
+//! Folding bursts of low-severity alerts into a single summary toast
+
+use crate::messages::{Alert, AlertLevel, AlertOrigin};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Alerts of one level within the window before later ones are coalesced
+pub const DEFAULT_BURST_THRESHOLD: usize = 5;
+
+/// Sliding window the threshold applies to; a burst ends after this long without another alert
+pub const DEFAULT_BURST_WINDOW: Duration = Duration::from_secs(10);
+
+/// Summaries kept so clicking an older summary toast still opens its details
+const SUMMARIES_KEPT: usize = 16;
+
+/// When bursts of Info (and optionally Warning) alerts are coalesced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BurstConfig {
+    /// Individual toasts shown per window before the rest are held for a summary
+    pub threshold: usize,
+    pub window: Duration,
+    /// Coalesce Warning alerts as well as Info ones
+    pub include_warning: bool,
+}
+
+impl Default for BurstConfig {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_BURST_THRESHOLD,
+            window: DEFAULT_BURST_WINDOW,
+            include_warning: false,
+        }
+    }
+}
+
+/// Whether an alert gets its own toast
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BurstDecision {
+    Show,
+    /// Held for the summary; `started` is set for the first alert held in this burst
+    Coalesce {
+        started: bool,
+    },
+}
+
+#[derive(Debug, Default)]
+struct LevelBurst {
+    arrivals: VecDeque<Instant>,
+    held: Vec<Alert>,
+}
+
+impl LevelBurst {
+    fn active(&self) -> bool {
+        !self.held.is_empty()
+    }
+}
+
+/// Arrival times and held alerts for each coalesced level
+#[derive(Debug)]
+pub(crate) struct BurstTracker {
+    config: BurstConfig,
+    info: LevelBurst,
+    warning: LevelBurst,
+    summaries: VecDeque<Alert>,
+}
+
+impl BurstTracker {
+    pub fn new(config: BurstConfig) -> Self {
+        Self {
+            config,
+            info: LevelBurst::default(),
+            warning: LevelBurst::default(),
+            summaries: VecDeque::new(),
+        }
+    }
+
+    fn level(&mut self, level: &AlertLevel) -> Option<&mut LevelBurst> {
+        match level {
+            AlertLevel::Info => Some(&mut self.info),
+            AlertLevel::Warning if self.config.include_warning => Some(&mut self.warning),
+            _ => None,
+        }
+    }
+
+    /// Note an alert's arrival and decide whether it gets its own toast.
+    ///
+    /// Critical and Emergency alerts and those needing confirmation are never held.
+    pub fn arrive(&mut self, alert: &Alert, now: Instant) -> BurstDecision {
+        if alert.requires_confirmation {
+            return BurstDecision::Show;
+        }
+        let threshold: usize = self.config.threshold;
+        let window: Duration = self.config.window;
+        let Some(burst) = self.level(&alert.level) else {
+            return BurstDecision::Show;
+        };
+
+        burst.arrivals.push_back(now);
+        while burst
+            .arrivals
+            .front()
+            .is_some_and(|&at| now.duration_since(at) > window)
+        {
+            burst.arrivals.pop_front();
+        }
+        if !burst.active() && burst.arrivals.len() <= threshold {
+            return BurstDecision::Show;
+        }
+        let started: bool = !burst.active();
+        burst.held.push(alert.clone());
+        BurstDecision::Coalesce { started }
+    }
+
+    /// When the burst at `level` ends if nothing else arrives, or `None` if there is none
+    pub fn quiet_at(&mut self, level: &AlertLevel) -> Option<Instant> {
+        let window: Duration = self.config.window;
+        let burst = self.level(level)?;
+        if !burst.active() {
+            return None;
+        }
+        burst.arrivals.back().map(|&at| at + window)
+    }
+
+    /// End the burst at `level` if it has been quiet for a full window, returning its summary toast
+    pub fn finish(&mut self, level: &AlertLevel, now: Instant) -> Option<Alert> {
+        if self.quiet_at(level).is_some_and(|at| now < at) {
+            return None;
+        }
+        let burst = self.level(level)?;
+        if !burst.active() {
+            return None;
+        }
+        let held: Vec<Alert> = std::mem::take(&mut burst.held);
+        burst.arrivals.clear();
+        let summary: Alert = summarize(level, &held);
+        let toast: Alert = Alert {
+            message: "Click for details".to_string(),
+            ..summary.clone()
+        };
+
+        if self.summaries.len() == SUMMARIES_KEPT {
+            self.summaries.pop_front();
+        }
+        self.summaries.push_back(summary);
+        Some(toast)
+    }
+
+    /// A summary shown earlier, with the held alerts listed in its message
+    pub fn summary(&self, id: uuid::Uuid) -> Option<&Alert> {
+        self.summaries.iter().find(|s| s.id == id)
+    }
+}
+
+/// `intro` followed by a line per alert with its local time, in `time_format`, and title
+pub(crate) fn list_alerts(intro: &str, alerts: &[Alert], time_format: &str) -> String {
+    let mut message: String = format!("{}\n", intro);
+    for alert in alerts {
+        message.push_str(&format!(
+            "\n{}  {}",
+            alert
+                .timestamp
+                .with_timezone(&chrono::Local)
+                .format(time_format),
+            alert.title
+        ));
+    }
+    message
+}
+
+/// The summary toast for `held`, listing each alert in its message for the details window
+fn summarize(level: &AlertLevel, held: &[Alert]) -> Alert {
+    let noun: &str = match level {
+        AlertLevel::Info => "informational",
+        _ => "warning",
+    };
+    Alert {
+        id: uuid::Uuid::new_v4(),
+        title: format!("{} {} alerts received", held.len(), noun),
+        message: list_alerts(
+            "Shown together because they arrived in a burst:",
+            held,
+            "%H:%M:%S",
+        ),
+        level: level.clone(),
+        requires_confirmation: false,
+        sound_file: None,
+        timestamp: chrono::Utc::now(),
+        origin: AlertOrigin::Local,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::alert;
+
+    fn tracker() -> BurstTracker {
+        BurstTracker::new(BurstConfig {
+            threshold: 2,
+            window: Duration::from_secs(10),
+            include_warning: false,
+        })
+    }
+
+    #[test]
+    fn test_alerts_past_the_threshold_are_held_until_quiet() {
+        let mut bursts: BurstTracker = tracker();
+        let start: Instant = Instant::now();
+        let decisions: Vec<BurstDecision> = (0..4)
+            .map(|i| {
+                bursts.arrive(
+                    &alert(AlertLevel::Info, false),
+                    start + Duration::from_secs(i),
+                )
+            })
+            .collect();
+        assert_eq!(
+            decisions,
+            [
+                BurstDecision::Show,
+                BurstDecision::Show,
+                BurstDecision::Coalesce { started: true },
+                BurstDecision::Coalesce { started: false },
+            ]
+        );
+
+        let quiet: Instant = bursts.quiet_at(&AlertLevel::Info).unwrap();
+        assert_eq!(quiet, start + Duration::from_secs(13));
+        assert!(bursts
+            .finish(&AlertLevel::Info, quiet - Duration::from_secs(1))
+            .is_none());
+        let toast: Alert = bursts.finish(&AlertLevel::Info, quiet).unwrap();
+        assert_eq!(toast.title, "2 informational alerts received");
+        assert_eq!(toast.message, "Click for details");
+        let summary: &Alert = bursts.summary(toast.id).unwrap();
+        assert_eq!(summary.message.lines().count(), 4);
+
+        // Once the burst is over alerts show again
+        let later: Alert = alert(AlertLevel::Info, false);
+        assert_eq!(bursts.arrive(&later, quiet), BurstDecision::Show);
+    }
+
+    #[test]
+    fn test_exempt_alerts_are_never_held() {
+        let mut bursts: BurstTracker = tracker();
+        let now: Instant = Instant::now();
+        for level in [
+            AlertLevel::Warning,
+            AlertLevel::Critical,
+            AlertLevel::Emergency,
+        ] {
+            for _ in 0..5 {
+                assert_eq!(
+                    bursts.arrive(&alert(level.clone(), false), now),
+                    BurstDecision::Show
+                );
+            }
+        }
+        for _ in 0..5 {
+            assert_eq!(
+                bursts.arrive(&alert(AlertLevel::Info, true), now),
+                BurstDecision::Show
+            );
+        }
+        assert!(bursts.quiet_at(&AlertLevel::Info).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+//! HTTP callbacks alerts ask for when their user confirms them
+
+use crate::error::{EmnsError, Result};
+use crate::messages::{Confirmation, ConfirmationResponse};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Default limit on one callback request
+pub const DEFAULT_CALLBACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait before the one retry of a failed callback
+pub const CALLBACK_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Where callbacks may go
+#[derive(Debug, Clone)]
+pub struct CallbackConfig {
+    /// Hosts callbacks may be sent to, each also admitting its subdomains;
+    /// every callback is refused while this is empty
+    pub allowed_domains: Vec<String>,
+    pub timeout: Duration,
+}
+
+impl Default for CallbackConfig {
+    fn default() -> Self {
+        Self {
+            allowed_domains: Vec::new(),
+            timeout: DEFAULT_CALLBACK_TIMEOUT,
+        }
+    }
+}
+
+/// JSON body POSTed to an alert's `confirm_callback_url`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CallbackBody {
+    pub alert_id: uuid::Uuid,
+    pub client_id: String,
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator_id: Option<String>,
+    pub confirmed_at: chrono::DateTime<chrono::Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_id: Option<String>,
+    #[serde(default, skip_serializing_if = "ConfirmationResponse::is_acknowledged")]
+    pub response: ConfirmationResponse,
+}
+
+impl From<&Confirmation> for CallbackBody {
+    fn from(confirmation: &Confirmation) -> Self {
+        Self {
+            alert_id: confirmation.alert_id,
+            client_id: confirmation.client_id.clone(),
+            username: confirmation.username.clone(),
+            operator_id: confirmation.operator_id.clone(),
+            confirmed_at: confirmation.confirmed_at,
+            response_id: confirmation.response_id.clone(),
+            response: confirmation.response.clone(),
+        }
+    }
+}
+
+/// Sends confirm callbacks to allow-listed HTTPS hosts
+pub struct CallbackSender {
+    allowed_domains: Vec<String>,
+    timeout: Duration,
+    /// Plain HTTP to loopback addresses, for tests only; see [`Self::with_plain_http_loopback`]
+    plain_http_loopback: bool,
+    client: reqwest::Client,
+}
+
+impl CallbackSender {
+    pub fn new(config: &CallbackConfig) -> Self {
+        Self {
+            allowed_domains: config
+                .allowed_domains
+                .iter()
+                .map(|d| d.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|d| !d.is_empty())
+                .collect(),
+            timeout: config.timeout,
+            plain_http_loopback: false,
+            // A redirect could bounce the callback off the allow-list
+            client: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Also allow plain HTTP to allow-listed loopback addresses, so tests can
+    /// call back to a local server without TLS. Never set from configuration.
+    pub fn with_plain_http_loopback(mut self) -> Self {
+        self.plain_http_loopback = true;
+        self
+    }
+
+    /// Parse `url` and check it may be called: HTTPS to an allow-listed host
+    pub fn check(&self, url: &str) -> Result<reqwest::Url> {
+        let parsed: reqwest::Url = reqwest::Url::parse(url)
+            .map_err(|e| EmnsError::protocol(format!("callback URL {} is invalid: {}", url, e)))?;
+        let host: String = parsed.host_str().unwrap_or_default().to_ascii_lowercase();
+        let loopback: bool = host == "localhost"
+            || host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .is_ok_and(|ip| ip.is_loopback());
+        match parsed.scheme() {
+            "https" => {}
+            "http" if loopback && self.plain_http_loopback => {}
+            _ => {
+                return Err(EmnsError::protocol(format!(
+                    "callback URL {} is not https",
+                    url
+                )))
+            }
+        }
+        let allowed: bool = self
+            .allowed_domains
+            .iter()
+            .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain)));
+        if !allowed {
+            return Err(EmnsError::protocol(format!(
+                "callback host {} is not in CONFIRM_CALLBACK_DOMAINS",
+                host
+            )));
+        }
+        Ok(parsed)
+    }
+
+    /// POST `body` to `url`, retrying once after [`CALLBACK_RETRY_DELAY`]
+    pub async fn send(&self, url: &str, body: &CallbackBody) -> Result<()> {
+        let url: reqwest::Url = self.check(url)?;
+        if let Err(e) = self.post(&url, body).await {
+            log::warn!(
+                "Confirm callback for alert {} failed, retrying: {}",
+                body.alert_id,
+                e
+            );
+            tokio::time::sleep(CALLBACK_RETRY_DELAY).await;
+            self.post(&url, body).await?;
+        }
+        Ok(())
+    }
+
+    async fn post(&self, url: &reqwest::Url, body: &CallbackBody) -> Result<()> {
+        let response: reqwest::Response = self
+            .client
+            .post(url.clone())
+            .json(body)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .map_err(|e| EmnsError::connection(url.as_str(), e))?;
+        if !response.status().is_success() {
+            return Err(EmnsError::connection(
+                url.as_str(),
+                format!("answered {}", response.status()),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sender(domains: &[&str]) -> CallbackSender {
+        CallbackSender::new(&CallbackConfig {
+            allowed_domains: domains.iter().map(|d| d.to_string()).collect(),
+            ..CallbackConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_check_enforces_allow_list_and_https() {
+        let sender: CallbackSender = sender(&["Hooks.Example.com", "127.0.0.1"]);
+        assert!(sender.check("https://hooks.example.com/ack").is_ok());
+        assert!(sender.check("https://eu.hooks.example.com/ack").is_ok());
+
+        for refused in [
+            "http://127.0.0.1:8080/ack",
+            "http://hooks.example.com/ack",
+            "https://evilhooks.example.com.attacker.net/ack",
+            "https://example.com/ack",
+            "https://hooks.example.com.attacker.net/ack",
+            "https://127.0.0.2/ack",
+            "ftp://hooks.example.com/ack",
+            "not a url",
+        ] {
+            assert!(sender.check(refused).is_err(), "{} accepted", refused);
+        }
+    }
+
+    #[test]
+    fn test_plain_http_only_to_loopback_when_allowed_for_tests() {
+        let sender: CallbackSender =
+            sender(&["hooks.example.com", "127.0.0.1"]).with_plain_http_loopback();
+        assert!(sender.check("http://127.0.0.1:8080/ack").is_ok());
+        assert!(sender.check("http://hooks.example.com/ack").is_err());
+        assert!(sender.check("http://127.0.0.2/ack").is_err());
+    }
+
+    #[test]
+    fn test_empty_allow_list_refuses_everything() {
+        let err: EmnsError = sender(&[])
+            .check("https://hooks.example.com/ack")
+            .unwrap_err();
+        assert!(err.to_string().contains("CONFIRM_CALLBACK_DOMAINS"));
+    }
+}
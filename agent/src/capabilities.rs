@@ -0,0 +1,308 @@
+//! Self-check of what this machine lets the agent do, and how alerts are
+//! delivered with whatever is missing
+
+use crate::attachments::ATTACHMENTS_DIR;
+use crate::audio::AudioBackend;
+use crate::eventlog;
+use crate::messages::{AlertLevel, Capabilities};
+use crate::notification::NotificationBackend;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+/// How often the self-check runs again after startup
+pub const RECHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// First recheck after a start that went ahead without the network or audio
+pub const DEGRADED_RECHECK_AFTER: Duration = Duration::from_secs(15);
+
+/// Written and removed again to prove a directory can be written
+const PROBE_FILE: &str = ".emns-write-probe";
+
+/// How an alert is put in front of the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presentation {
+    Toast,
+    /// The details window, with its Confirm and response buttons
+    Window,
+    /// Recorded in the history only
+    HistoryOnly,
+}
+
+/// How one alert is delivered with the capabilities at hand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryPlan {
+    pub presentation: Presentation,
+    /// Whether its sound is played; the settings and sound policy still have the last word
+    pub sound: bool,
+}
+
+/// Decide how to deliver an alert given what the last self-check found.
+///
+/// Without toasts, an alert that must be confirmed or is above Info opens the
+/// details window instead; an Info alert that needs no confirmation is only
+/// recorded. Without an audio device, alerts are visual only.
+pub fn plan(
+    capabilities: &Capabilities,
+    level: &AlertLevel,
+    requires_confirmation: bool,
+) -> DeliveryPlan {
+    let presentation: Presentation = if capabilities.toasts {
+        Presentation::Toast
+    } else if requires_confirmation || *level != AlertLevel::Info {
+        Presentation::Window
+    } else {
+        Presentation::HistoryOnly
+    };
+    DeliveryPlan {
+        presentation,
+        sound: capabilities.audio,
+    }
+}
+
+/// One line of the self-check report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub name: &'static str,
+    pub available: bool,
+    /// What the agent does without it
+    pub fallback: &'static str,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.available {
+            write!(f, "{:<16} ok", self.name)
+        } else {
+            write!(f, "{:<16} unavailable: {}", self.name, self.fallback)
+        }
+    }
+}
+
+/// `capabilities` as report lines, one per capability
+pub fn findings(capabilities: &Capabilities) -> [Finding; 5] {
+    [
+        Finding {
+            name: "toasts",
+            available: capabilities.toasts,
+            fallback: "alerts open the details window; Info alerts are only recorded",
+        },
+        Finding {
+            name: "audio",
+            available: capabilities.audio,
+            fallback: "alerts are silent",
+        },
+        Finding {
+            name: "data directory",
+            available: capabilities.data_dir_writable,
+            fallback: "history and state changes are lost at restart",
+        },
+        Finding {
+            name: "event log",
+            available: capabilities.event_log,
+            fallback: "problems are only written to the agent's log",
+        },
+        Finding {
+            name: "attachments",
+            available: capabilities.attachment_cache,
+            fallback: "attachments cannot be downloaded",
+        },
+    ]
+}
+
+/// Probes what the agent depends on; every [`run`](Self::run) asks afresh
+pub struct SelfCheck {
+    notifier: Arc<dyn NotificationBackend>,
+    audio: Arc<dyn AudioBackend>,
+    data_dir: PathBuf,
+}
+
+impl SelfCheck {
+    pub fn new(
+        notifier: Arc<dyn NotificationBackend>,
+        audio: Arc<dyn AudioBackend>,
+        data_dir: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            notifier,
+            audio,
+            data_dir: data_dir.into(),
+        }
+    }
+
+    /// Probe every capability. May block, e.g. opening the audio device.
+    pub fn run(&self) -> Capabilities {
+        Capabilities {
+            toasts: self.notifier.available(),
+            audio: self.audio.available(),
+            data_dir_writable: writable(&self.data_dir),
+            event_log: eventlog::available(),
+            attachment_cache: writable(&self.data_dir.join(ATTACHMENTS_DIR)),
+        }
+    }
+}
+
+/// Whether a file can be created in `dir`, which is created if missing
+fn writable(dir: &Path) -> bool {
+    let probe: PathBuf = dir.join(PROBE_FILE);
+    let written = std::fs::create_dir_all(dir)
+        .and_then(|()| std::fs::write(&probe, b""))
+        .and_then(|()| std::fs::remove_file(&probe));
+    if let Err(e) = &written {
+        log::debug!("{} is not writable: {}", dir.display(), e);
+    }
+    written.is_ok()
+}
+
+/// Log a warning for everything `capabilities` lacks
+pub fn log_findings(capabilities: &Capabilities) {
+    for finding in findings(capabilities) {
+        if !finding.available {
+            log::warn!("Self-check: {}", finding);
+        }
+    }
+}
+
+/// Run `check` every [`RECHECK_INTERVAL`], publishing what changed on `capabilities`.
+///
+/// The first check runs after `first`, and the wait doubles from there up to
+/// the interval, so a start that went ahead without a device notices it soon.
+/// Checks run on the blocking pool; one that panics keeps the previous result.
+pub async fn run_rechecks(
+    check: Arc<SelfCheck>,
+    capabilities: watch::Sender<Capabilities>,
+    first: Duration,
+    cancel: CancellationToken,
+) {
+    let mut wait: Duration = first.min(RECHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = tokio::time::sleep(wait) => {}
+        }
+        wait = (wait * 2).min(RECHECK_INTERVAL);
+        let checking = tokio::task::spawn_blocking({
+            let check: Arc<SelfCheck> = check.clone();
+            move || check.run()
+        });
+        let found: Capabilities = tokio::select! {
+            _ = cancel.cancelled() => break,
+            checked = checking => match checked {
+                Ok(found) => found,
+                Err(e) => {
+                    log::warn!("Self-check failed: {}", e);
+                    continue;
+                }
+            },
+        };
+        capabilities.send_if_modified(|current| {
+            let before: [Finding; 5] = findings(current);
+            for (was, now) in before.iter().zip(findings(&found)) {
+                match (was.available, now.available) {
+                    (false, true) => log::info!("Self-check: {} available again", now.name),
+                    (true, false) => log::warn!("Self-check: {}", now),
+                    _ => {}
+                }
+            }
+            let changed: bool = *current != found;
+            *current = found;
+            changed
+        });
+    }
+    log::debug!("Self-check stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockAudio, MockNotifier};
+
+    fn capabilities(toasts: bool, audio: bool) -> Capabilities {
+        Capabilities {
+            toasts,
+            audio,
+            ..Capabilities::all()
+        }
+    }
+
+    #[test]
+    fn test_plan_matrix() {
+        use Presentation::{HistoryOnly, Toast, Window};
+        let levels: [AlertLevel; 4] = [
+            AlertLevel::Info,
+            AlertLevel::Warning,
+            AlertLevel::Critical,
+            AlertLevel::Emergency,
+        ];
+        // Without toasts, by level, for alerts that need no confirmation and those that do
+        let expected: [(Presentation, Presentation); 4] = [
+            (HistoryOnly, Window),
+            (Window, Window),
+            (Window, Window),
+            (Window, Window),
+        ];
+        for (level, (unconfirmed, confirmed)) in levels.iter().zip(expected) {
+            for audio in [true, false] {
+                for (requires_confirmation, without_toasts) in
+                    [(false, unconfirmed), (true, confirmed)]
+                {
+                    let with: DeliveryPlan =
+                        plan(&capabilities(true, audio), level, requires_confirmation);
+                    assert_eq!(with.presentation, Toast, "{:?}", level);
+                    assert_eq!(with.sound, audio);
+
+                    let without: DeliveryPlan =
+                        plan(&capabilities(false, audio), level, requires_confirmation);
+                    assert_eq!(
+                        without.presentation, without_toasts,
+                        "{:?}, confirmation {}",
+                        level, requires_confirmation
+                    );
+                    assert_eq!(without.sound, audio);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_self_check_probes_data_dir() {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("emns-check-{}", uuid::Uuid::new_v4()));
+        let check: SelfCheck = SelfCheck::new(
+            Arc::new(MockNotifier::default()),
+            Arc::new(MockAudio::default()),
+            &dir,
+        );
+        let found: Capabilities = check.run();
+        assert!(found.toasts && found.audio);
+        assert!(found.data_dir_writable && found.attachment_cache);
+        // The probe file is cleaned up; the attachments directory stays for downloads
+        let left: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(left, vec![dir.join(ATTACHMENTS_DIR)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // A file where the data directory should be
+        std::fs::write(&dir, b"").unwrap();
+        let found: Capabilities = check.run();
+        assert!(!found.data_dir_writable && !found.attachment_cache);
+        std::fs::remove_file(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_findings_report_fallbacks() {
+        let report: Vec<String> = findings(&capabilities(false, true))
+            .iter()
+            .map(Finding::to_string)
+            .collect();
+        assert_eq!(
+            report[0],
+            "toasts           unavailable: alerts open the details window; Info alerts are only recorded"
+        );
+        assert_eq!(report[1], "audio            ok");
+    }
+}
@@ -0,0 +1,526 @@
+//! Verbatim record of the frames exchanged with the server, for protocol debugging.
+//!
+//! [`WireCapture`] taps a [`Connection`] and appends every text and binary
+//! frame to a JSON Lines file as it crosses the transport, before the client
+//! parses it. The file rotates by size and the rotated files are capped, so a
+//! capture left running cannot fill the disk. [`inspect`] prints a capture
+//! back for the `inspect-capture` subcommand.
+
+use crate::error::{EmnsError, Result};
+use crate::transport::{Connection, Frame};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Size a capture file reaches before it is rotated
+pub const DEFAULT_CAPTURE_FILE_BYTES: u64 = 10 * 1024 * 1024;
+/// Most disk space the capture and its rotated files may take together
+pub const DEFAULT_CAPTURE_TOTAL_BYTES: u64 = 100 * 1024 * 1024;
+
+/// Replaces redacted alert text
+const REDACTED: &str = "[redacted]";
+
+/// Where frames are captured and how much is kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WireCaptureConfig {
+    pub path: PathBuf,
+    pub max_file_bytes: u64,
+    pub max_total_bytes: u64,
+    /// Blank alert titles and messages, and drop payloads that are not JSON
+    pub redact: bool,
+}
+
+impl WireCaptureConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_file_bytes: DEFAULT_CAPTURE_FILE_BYTES,
+            max_total_bytes: DEFAULT_CAPTURE_TOTAL_BYTES,
+            redact: false,
+        }
+    }
+
+    /// Rotated files kept besides the live one, so all of them fit in `max_total_bytes`
+    fn rotated_files(&self) -> u64 {
+        (self.max_total_bytes / self.max_file_bytes.max(1)).saturating_sub(1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameKind {
+    Text,
+    /// Payload is base64
+    Binary,
+}
+
+/// One line of a capture file
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CapturedFrame {
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub direction: Direction,
+    pub url: String,
+    pub kind: FrameKind,
+    /// Bytes on the wire, before any redaction
+    pub size: usize,
+    /// Left out when redacted or too large for one capture file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub redacted: bool,
+}
+
+impl CapturedFrame {
+    /// Record of `frame`, or `None` for control frames
+    fn new(direction: Direction, url: &str, frame: &Frame, redact: bool) -> Option<Self> {
+        let (kind, size, payload) = match frame {
+            Frame::Text(text) => (FrameKind::Text, text.len(), text.clone()),
+            Frame::Binary(bytes) => (FrameKind::Binary, bytes.len(), BASE64.encode(bytes)),
+            _ => return None,
+        };
+        let payload: Option<String> = match (redact, kind) {
+            (false, _) => Some(payload),
+            (true, FrameKind::Text) => redact_json(&payload),
+            (true, FrameKind::Binary) => None,
+        };
+        Some(Self {
+            at: chrono::Utc::now(),
+            direction,
+            url: url.to_string(),
+            kind,
+            size,
+            payload,
+            redacted: redact,
+        })
+    }
+
+    /// The payload's message `type`, when it is a protocol message
+    pub fn message_type(&self) -> Option<String> {
+        let payload: &str = self.payload.as_deref()?;
+        let value: serde_json::Value = serde_json::from_str(payload).ok()?;
+        value.get("type")?.as_str().map(String::from)
+    }
+}
+
+/// `text` with every alert title and message blanked, or `None` if it is not JSON
+fn redact_json(text: &str) -> Option<String> {
+    fn blank(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(fields) => {
+                for (key, field) in fields.iter_mut() {
+                    if (key == "title" || key == "message") && field.is_string() {
+                        *field = serde_json::Value::String(REDACTED.to_string());
+                    } else {
+                        blank(field);
+                    }
+                }
+            }
+            serde_json::Value::Array(items) => items.iter_mut().for_each(blank),
+            _ => {}
+        }
+    }
+    let mut value: serde_json::Value = serde_json::from_str(text).ok()?;
+    blank(&mut value);
+    Some(value.to_string())
+}
+
+/// Appends captured frames to a size-capped set of files
+#[derive(Debug)]
+pub struct WireCapture {
+    config: WireCaptureConfig,
+    file: Mutex<CaptureFile>,
+}
+
+#[derive(Debug, Default)]
+struct CaptureFile {
+    file: Option<File>,
+    len: u64,
+    /// Set after a write fails, so the failure is logged once
+    failed: bool,
+}
+
+impl WireCapture {
+    /// Capture to `config.path`; nothing is written until the first frame
+    pub fn new(config: WireCaptureConfig) -> Self {
+        log::warn!(
+            "Capturing server frames to {}{}",
+            config.path.display(),
+            if config.redact { " (redacted)" } else { "" }
+        );
+        Self {
+            config,
+            file: Mutex::new(CaptureFile::default()),
+        }
+    }
+
+    /// Record `frame`; control frames are skipped and write errors only logged
+    pub fn record(&self, direction: Direction, url: &str, frame: &Frame) {
+        let Some(mut captured) = CapturedFrame::new(direction, url, frame, self.config.redact)
+        else {
+            return;
+        };
+        let mut line: String = serde_json::to_string(&captured).unwrap_or_default();
+        if line.len() as u64 + 1 > self.config.max_file_bytes {
+            captured.payload = None;
+            line = serde_json::to_string(&captured).unwrap_or_default();
+        }
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        match self.append(&mut file, line.as_bytes()) {
+            Ok(()) => file.failed = false,
+            Err(e) if !file.failed => {
+                log::error!("Failed to write wire capture: {}", e);
+                file.failed = true;
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Wrap `connection` so every frame it carries is recorded
+    pub fn tap(self: &Arc<Self>, url: &str, connection: Connection) -> Connection {
+        let Connection { sink, stream } = connection;
+        let (sent, received) = (self.clone(), self.clone());
+        let (sent_url, received_url) = (url.to_string(), url.to_string());
+        Connection {
+            sink: Box::pin(sink.with(move |frame: Frame| {
+                sent.record(Direction::Sent, &sent_url, &frame);
+                futures_util::future::ready(Ok::<Frame, EmnsError>(frame))
+            })),
+            stream: Box::pin(stream.inspect(move |frame| {
+                if let Ok(frame) = frame {
+                    received.record(Direction::Received, &received_url, frame);
+                }
+            })),
+        }
+    }
+
+    fn append(&self, file: &mut CaptureFile, line: &[u8]) -> std::io::Result<()> {
+        if file.file.is_some() && file.len + line.len() as u64 > self.config.max_file_bytes {
+            file.file = None;
+            self.rotate()?;
+        }
+        if file.file.is_none() {
+            if let Some(dir) = self.config.path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let opened: File = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.config.path)?;
+            file.len = opened.metadata()?.len();
+            file.file = Some(opened);
+            // A file left over from an earlier run may already be full
+            if file.len + line.len() as u64 > self.config.max_file_bytes && file.len > 0 {
+                file.file = None;
+                self.rotate()?;
+                return self.append(file, line);
+            }
+        }
+        if let Some(open) = file.file.as_mut() {
+            open.write_all(line)?;
+            file.len += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Shift `capture.jsonl` to `capture.jsonl.1` and so on, dropping the oldest
+    fn rotate(&self) -> std::io::Result<()> {
+        let keep: u64 = self.config.rotated_files();
+        let path: &Path = &self.config.path;
+        if keep == 0 {
+            return remove_if_exists(path);
+        }
+        remove_if_exists(&rotated_path(path, keep))?;
+        for n in (1..keep).rev() {
+            let from: PathBuf = rotated_path(path, n);
+            if from.exists() {
+                std::fs::rename(&from, rotated_path(path, n + 1))?;
+            }
+        }
+        std::fs::rename(path, rotated_path(path, 1))
+    }
+}
+
+fn rotated_path(path: &Path, n: u64) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Which captured frames [`inspect`] prints
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CaptureFilter {
+    pub direction: Option<Direction>,
+    /// Protocol message type, e.g. `alert`
+    pub message_type: Option<String>,
+    /// Text the payload must contain
+    pub contains: Option<String>,
+}
+
+impl CaptureFilter {
+    /// Parse `inspect-capture` options, returning the filter and the files to read
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<(Self, Vec<PathBuf>)> {
+        let mut filter: CaptureFilter = CaptureFilter::default();
+        let mut files: Vec<PathBuf> = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| EmnsError::config(name, "needs a value"))
+            };
+            match arg.as_str() {
+                "--direction" => {
+                    filter.direction = Some(match value("--direction")?.as_str() {
+                        "sent" => Direction::Sent,
+                        "received" => Direction::Received,
+                        other => {
+                            return Err(EmnsError::config(
+                                "--direction",
+                                format!("expected sent or received, got {}", other),
+                            ))
+                        }
+                    })
+                }
+                "--type" => filter.message_type = Some(value("--type")?),
+                "--contains" => filter.contains = Some(value("--contains")?),
+                _ => files.push(PathBuf::from(arg)),
+            }
+        }
+        if files.is_empty() {
+            return Err(EmnsError::config(
+                "inspect-capture",
+                "no capture file given",
+            ));
+        }
+        Ok((filter, files))
+    }
+
+    pub fn matches(&self, frame: &CapturedFrame) -> bool {
+        self.direction.is_none_or(|d| d == frame.direction)
+            && self
+                .message_type
+                .as_ref()
+                .is_none_or(|t| frame.message_type().as_ref() == Some(t))
+            && self
+                .contains
+                .as_ref()
+                .is_none_or(|text| frame.payload.as_deref().is_some_and(|p| p.contains(text)))
+    }
+}
+
+/// Pretty-print the frames in `capture` that match `filter`; returns how many were printed.
+///
+/// Lines that are not captured frames are reported and skipped.
+pub fn inspect(
+    capture: impl BufRead,
+    filter: &CaptureFilter,
+    out: &mut impl Write,
+) -> std::io::Result<usize> {
+    let mut printed: usize = 0;
+    for (number, line) in capture.lines().enumerate() {
+        let line: String = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let frame: CapturedFrame = match serde_json::from_str(&line) {
+            Ok(frame) => frame,
+            Err(e) => {
+                writeln!(out, "line {}: not a captured frame: {}", number + 1, e)?;
+                continue;
+            }
+        };
+        if !filter.matches(&frame) {
+            continue;
+        }
+        let arrow: &str = match frame.direction {
+            Direction::Sent => "->",
+            Direction::Received => "<-",
+        };
+        writeln!(
+            out,
+            "{} {} {} {} bytes {}",
+            frame
+                .at
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            arrow,
+            frame.url,
+            frame.size,
+            frame
+                .message_type()
+                .unwrap_or_else(|| format!("({:?})", frame.kind).to_lowercase())
+        )?;
+        let body: String = match frame.payload.as_deref() {
+            None => "  (payload not captured)".to_string(),
+            Some(payload) => match serde_json::from_str::<serde_json::Value>(payload) {
+                Ok(json) => serde_json::to_string_pretty(&json)?,
+                Err(_) => payload.to_string(),
+            },
+        };
+        for body_line in body.lines() {
+            writeln!(out, "  {}", body_line)?;
+        }
+        printed += 1;
+    }
+    Ok(printed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture_dir(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("emns-capture-{}-{}", test, uuid::Uuid::new_v4()))
+    }
+
+    fn read_frames(path: &Path) -> Vec<CapturedFrame> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_rotation_keeps_total_under_cap() {
+        let dir: PathBuf = capture_dir("rotation");
+        let path: PathBuf = dir.join("wire.jsonl");
+        let config: WireCaptureConfig = WireCaptureConfig {
+            max_file_bytes: 1000,
+            max_total_bytes: 3000,
+            ..WireCaptureConfig::new(&path)
+        };
+        let capture: WireCapture = WireCapture::new(config);
+        for n in 0..100 {
+            let text: String = format!(r#"{{"type":"heartbeat","n":{}}}"#, n);
+            capture.record(Direction::Sent, "ws://test/ws", &Frame::Text(text));
+        }
+        // Control frames are not captured, and an oversized frame keeps only its size
+        capture.record(Direction::Received, "ws://test/ws", &Frame::Ping(vec![]));
+        capture.record(
+            Direction::Received,
+            "ws://test/ws",
+            &Frame::Text("x".repeat(5000)),
+        );
+
+        let mut files: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["wire.jsonl", "wire.jsonl.1", "wire.jsonl.2"]);
+        let total: u64 = files
+            .iter()
+            .map(|name| std::fs::metadata(dir.join(name)).unwrap().len())
+            .sum();
+        assert!(total <= 3000, "capture took {} bytes", total);
+
+        // The newest frames are kept, the oldest dropped
+        let mut live: Vec<CapturedFrame> = read_frames(&path);
+        let last: CapturedFrame = live.pop().unwrap();
+        assert_eq!(last.size, 5000);
+        assert!(last.payload.is_none());
+        assert_eq!(
+            live.pop().unwrap().payload.as_deref(),
+            Some(r#"{"type":"heartbeat","n":99}"#)
+        );
+        let oldest_kept: CapturedFrame = read_frames(&rotated_path(&path, 2)).remove(0);
+        assert!(!oldest_kept.payload.unwrap().contains(r#""n":0}"#));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_redaction_blanks_alert_text_only() {
+        let text: &str = r#"{"type":"alert","alert":{"id":"a1","title":"Fire","message":"Leave now","level":"critical"}}"#;
+        let frame: CapturedFrame = CapturedFrame::new(
+            Direction::Received,
+            "ws://test/ws",
+            &Frame::Text(text.into()),
+            true,
+        )
+        .unwrap();
+        let payload: serde_json::Value =
+            serde_json::from_str(frame.payload.as_deref().unwrap()).unwrap();
+        assert_eq!(payload["alert"]["title"], REDACTED);
+        assert_eq!(payload["alert"]["message"], REDACTED);
+        assert_eq!(payload["alert"]["id"], "a1");
+        assert_eq!(frame.size, text.len());
+        assert!(frame.redacted);
+
+        // Anything that is not JSON could be anything, so it is dropped
+        let garbage: CapturedFrame = CapturedFrame::new(
+            Direction::Received,
+            "ws://test/ws",
+            &Frame::Text("not json".into()),
+            true,
+        )
+        .unwrap();
+        assert!(garbage.payload.is_none());
+    }
+
+    #[test]
+    fn test_inspect_filters_and_pretty_prints() {
+        let lines: Vec<String> = [
+            (
+                Direction::Sent,
+                r#"{"type":"register","client_id":"ws-01"}"#,
+            ),
+            (
+                Direction::Received,
+                r#"{"type":"alert","alert":{"id":"a1"}}"#,
+            ),
+            (Direction::Received, "not json"),
+        ]
+        .into_iter()
+        .map(|(direction, text)| {
+            let frame: CapturedFrame =
+                CapturedFrame::new(direction, "ws://test/ws", &Frame::Text(text.into()), false)
+                    .unwrap();
+            serde_json::to_string(&frame).unwrap()
+        })
+        .chain(["garbage".to_string()])
+        .collect();
+        let capture: String = lines.join("\n");
+
+        let (filter, files) = CaptureFilter::from_args(
+            ["--direction", "received", "wire.jsonl", "--type", "alert"].map(String::from),
+        )
+        .unwrap();
+        assert_eq!(files, [PathBuf::from("wire.jsonl")]);
+        let mut out: Vec<u8> = Vec::new();
+        assert_eq!(inspect(capture.as_bytes(), &filter, &mut out).unwrap(), 1);
+        let out: String = String::from_utf8(out).unwrap();
+        assert!(out.contains("<- ws://test/ws 36 bytes alert"), "{}", out);
+        assert!(out.contains("    \"id\": \"a1\""), "{}", out);
+        assert!(out.contains("line 4: not a captured frame"), "{}", out);
+
+        let mut out: Vec<u8> = Vec::new();
+        let filter: CaptureFilter = CaptureFilter {
+            contains: Some("not".to_string()),
+            ..CaptureFilter::default()
+        };
+        assert_eq!(inspect(capture.as_bytes(), &filter, &mut out).unwrap(), 1);
+        assert!(String::from_utf8(out).unwrap().contains("(text)"));
+        assert!(CaptureFilter::from_args(["--direction".to_string()]).is_err());
+        assert!(CaptureFilter::from_args(Vec::<String>::new()).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+//! Per-category handling set up on this machine, e.g. a sound of its own for
+//! weather alerts, or EXERCISE on the title of every exercise alert
+
+use crate::messages::Alert;
+
+/// Category whose alerts are marked as an exercise unless configured otherwise
+pub const EXERCISE_CATEGORY: &str = "exercise";
+
+/// Put at the front of an exercise alert's title
+pub const EXERCISE_PREFIX: &str = "EXERCISE";
+
+/// How alerts of one category are handled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CategoryConfig {
+    /// The category as servers send it, matched case-insensitively
+    pub name: String,
+    /// Played in place of the alert's own sound
+    pub sound_file: Option<String>,
+    /// Replaces whether the alert asks to be confirmed
+    pub requires_confirmation: Option<bool>,
+    /// Mark the alert's title with [`EXERCISE_PREFIX`]
+    pub exercise: bool,
+}
+
+impl CategoryConfig {
+    /// A category that changes nothing but the title of exercise alerts
+    pub fn new(name: impl Into<String>) -> Self {
+        let name: String = name.into();
+        Self {
+            exercise: name.eq_ignore_ascii_case(EXERCISE_CATEGORY),
+            name,
+            sound_file: None,
+            requires_confirmation: None,
+        }
+    }
+}
+
+/// The categories this agent knows about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Categories(Vec<CategoryConfig>);
+
+impl Default for Categories {
+    fn default() -> Self {
+        Self(vec![CategoryConfig::new(EXERCISE_CATEGORY)])
+    }
+}
+
+impl Categories {
+    pub fn new(categories: Vec<CategoryConfig>) -> Self {
+        Self(categories)
+    }
+
+    /// Names of the known categories, as reported at registration
+    pub fn names(&self) -> Vec<String> {
+        self.0.iter().map(|c| c.name.clone()).collect()
+    }
+
+    /// Settings for `category`, if it is a known one
+    pub fn get(&self, category: &str) -> Option<&CategoryConfig> {
+        let category: &str = category.trim();
+        self.0
+            .iter()
+            .find(|c| c.name.eq_ignore_ascii_case(category))
+    }
+
+    /// Apply the settings for the alert's category; alerts without a category
+    /// or of an unknown one are left as they are
+    pub fn apply(&self, alert: &mut Alert) {
+        let Some(config) = alert.category.as_deref().and_then(|c| self.get(c)) else {
+            return;
+        };
+        if let Some(sound_file) = &config.sound_file {
+            alert.sound_file = Some(sound_file.clone());
+        }
+        if let Some(requires_confirmation) = config.requires_confirmation {
+            alert.requires_confirmation = requires_confirmation;
+        }
+        if config.exercise {
+            alert.title = exercise_title(&alert.title);
+            for translation in alert.translations.values_mut() {
+                translation.title = exercise_title(&translation.title);
+            }
+        }
+    }
+}
+
+/// `title` marked as an exercise, unless it already says so
+pub fn exercise_title(title: &str) -> String {
+    let marked: bool = title
+        .get(..EXERCISE_PREFIX.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(EXERCISE_PREFIX));
+    if marked {
+        title.to_string()
+    } else {
+        format!("{}: {}", EXERCISE_PREFIX, title)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exercise_title_is_marked_once() {
+        assert_eq!(
+            exercise_title("Shelter in place"),
+            "EXERCISE: Shelter in place"
+        );
+        assert_eq!(
+            exercise_title("Exercise exercise exercise: Shelter in place"),
+            "Exercise exercise exercise: Shelter in place"
+        );
+        assert_eq!(exercise_title("Ex"), "EXERCISE: Ex");
+    }
+
+    #[test]
+    fn test_only_the_exercise_category_is_an_exercise_by_default() {
+        assert!(CategoryConfig::new("Exercise").exercise);
+        assert!(!CategoryConfig::new("weather").exercise);
+        assert!(Categories::default().get(" EXERCISE ").is_some());
+        assert!(Categories::default().get("weather").is_none());
+    }
+}
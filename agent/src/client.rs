@@ -1,90 +1,1001 @@
-use crate::messages::{Alert, Confirmation, Message};
-use anyhow::{Context, Result};
+use crate::backoff::{BackoffConfig, ReconnectBackoff};
+use crate::capture::WireCapture;
+use crate::clock::{ClockSkew, ServerClock, SystemClock};
+use crate::discovery::DnsDiscovery;
+use crate::error::{EmnsError, Result};
+use crate::handler::AlertHandler;
+use crate::maintenance::MaintenanceWindow;
+use crate::messages::{
+    msgpack_value, Alert, AlertBatch, AlertErrorReason, Capabilities, ConfirmationReason, Encoding,
+    Envelope, HeartbeatStats, InvalidAlert, Location, Message, NackReason, ShutdownReason,
+    ShutdownRecord, PROTOCOL_VERSION,
+};
+use crate::multicast::SigningKey;
+use crate::outbound::{OutboundMessage, OutboundQueue, Priority};
+use crate::queue::{AlertQueue, EnqueueOutcome};
+use crate::sealed::{self, AlertKey};
+use crate::settings::{AgentSettings, SharedSettings};
+use crate::signing;
+use crate::sink::Withdrawal;
+use crate::sound_pack::SoundPacks;
+use crate::status::StatusCollector;
+use crate::suppression::SuppressionWindows;
+use crate::timing::{DeliveryTimings, DeliveryTrace};
+use crate::transport::{
+    Connection, Frame, FrameSink, FrameStream, Transport, TungsteniteTransport,
+};
+use crate::update::Updater;
+use crate::watermark::Watermark;
 use futures_util::{SinkExt, StreamExt};
-use tokio::sync::mpsc;
-use tokio::time::{interval, Duration};
-use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{interval, interval_at, Duration, Instant, Interval};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 
+/// Maintains the connection to the notification server
 pub struct WebSocketClient {
     server_url: String,
+    /// Tried in order after `server_url` when it cannot be reached
+    fallback_urls: Vec<String>,
+    /// How often the first server is tried again while connected to another
+    primary_retry: Duration,
+    /// Replaces `server_url` and the fallbacks with servers found in DNS
+    discovery: Option<DnsDiscovery>,
     client_id: String,
     hostname: String,
+    location: Option<Location>,
+    /// Reported in registration for role-based routing
+    machine_role: Option<String>,
+    /// Reported in registration for group targeting
+    groups: Vec<String>,
+    /// Reported in registration so the server can spot unknown categories
+    categories: Vec<String>,
+    transport: Arc<dyn Transport>,
+    outbound: Arc<OutboundQueue>,
+    status: Option<Arc<StatusCollector>>,
+    settings: SharedSettings,
+    /// Backup server held open in standby mode and promoted when the active connection drops
+    standby_url: Option<String>,
+    /// Alerts already queued, so one sent over both connections is shown once
+    seen: Mutex<SeenAlerts>,
+    /// Where server-scheduled suppression windows are kept; ignored when unset
+    suppressions: Option<Arc<SuppressionWindows>>,
+    /// Handler whose pending alerts are reconciled with the server after each registration
+    pending: Option<Arc<AlertHandler>>,
+    /// Copies every frame to a capture file; off when unset
+    capture: Option<Arc<WireCapture>>,
+    /// Where the active connection stands
+    state: watch::Sender<ConnectionState>,
+    /// Announced server downtime, during which reconnects are slower and quieter
+    maintenance: Arc<MaintenanceWindow>,
+    /// Takes releases the server offers; offers are ignored without one
+    updater: Option<Arc<Updater>>,
+    /// Takes sound packs the server offers, and reports the one in use; offers are ignored without one
+    sound_packs: Option<Arc<SoundPacks>>,
+    /// Opens sealed alerts; its public key is sent in registration
+    alert_key: Option<AlertKey>,
+    /// Alerts not signed with it are rejected; any alert is taken when `None`
+    signing_key: Option<SigningKey>,
+    /// Latest self-check, reported in registration
+    capabilities: Option<watch::Receiver<Capabilities>>,
+    /// How the agent's previous run ended, reported in registration
+    previous_shutdown: Option<ShutdownRecord>,
+    /// Newest alert received, reported in registration so missed ones are replayed
+    watermark: Option<Arc<Watermark>>,
+    /// Clock alerts are stamped from on their way to the queue
+    timings: Arc<DeliveryTimings>,
+    /// Set to the server's time from each registration ack
+    server_clock: Arc<ServerClock>,
+    /// How the wait between reconnect attempts grows
+    backoff: BackoffConfig,
+    /// Silence from the server after which its connection is dropped
+    server_timeout: Duration,
+    /// Unacknowledged heartbeats in a row after which the connection is dropped
+    heartbeat_missed_acks: u32,
+    /// Longest one frame may take to write before the connection is dropped
+    send_timeout: Duration,
+    /// Longest the server may take to acknowledge a registration
+    register_timeout: Duration,
+    /// Unreadable server messages in a row after which the connection is dropped
+    max_unreadable_messages: u32,
+}
+
+/// Alert IDs kept to recognise an alert arriving over the second connection
+const SEEN_ALERTS_KEPT: usize = 1000;
+
+/// Default time the server may stay silent before its connection is taken for dead
+pub const DEFAULT_SERVER_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Default longest one frame may take to write before its connection is taken for dead
+pub const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default time the server has to acknowledge a registration before the connection is dropped
+pub const DEFAULT_REGISTER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest the client spends flushing queued messages and unregistering
+/// when it stops, kept under the agent's shutdown timeout so a hung socket
+/// cannot hold up the exit
+pub const GOODBYE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Default wait between attempts to move back to the first server while connected to another
+pub const DEFAULT_PRIMARY_RETRY: Duration = Duration::from_secs(300);
+
+/// Default number of heartbeats in a row a server that acknowledges them may leave unacknowledged
+pub const DEFAULT_HEARTBEAT_MISSED_ACKS: u32 = 3;
+
+/// Default number of unreadable server messages in a row tolerated before reconnecting
+pub const DEFAULT_MAX_UNREADABLE_MESSAGES: u32 = 5;
+
+/// Characters of an unreadable message kept for the log and the server
+const UNREADABLE_EXCERPT_CHARS: usize = 200;
+
+/// Clock skew from the server large enough to warn about
+const CLOCK_SKEW_NOTICE: chrono::TimeDelta = chrono::TimeDelta::seconds(5);
+
+/// Most recent alert IDs received from any server
+#[derive(Debug, Default)]
+struct SeenAlerts {
+    order: VecDeque<uuid::Uuid>,
+    ids: HashSet<uuid::Uuid>,
+}
+
+impl SeenAlerts {
+    /// Remember `id`; `false` if it was already seen
+    fn insert(&mut self, id: uuid::Uuid) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > SEEN_ALERTS_KEPT {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Forget `id`, so a later copy of that alert is taken again
+    fn forget(&mut self, id: uuid::Uuid) {
+        if self.ids.remove(&id) {
+            self.order.retain(|seen| *seen != id);
+        }
+    }
+}
+
+/// Where the client's active connection stands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Trying servers, as at startup and after each reconnect delay
+    Connecting,
+    /// Registered with the server at `url`, connected since `since`
+    Connected {
+        url: String,
+        since: chrono::DateTime<chrono::Utc>,
+    },
+    /// Waiting to reconnect, after what ended the last attempt if it failed
+    Disconnected { last_error: Option<String> },
+}
+
+impl ConnectionState {
+    pub fn is_connected(&self) -> bool {
+        matches!(self, ConnectionState::Connected { .. })
+    }
+}
+
+/// Request for the standby connection, answered with it if there is one
+type Handover = oneshot::Sender<(String, Connection)>;
+
+/// How the active loop talks to the standby loop
+struct StandbyControl {
+    /// URL of the active connection, if any
+    active: watch::Sender<Option<String>>,
+    handover: mpsc::Sender<Handover>,
+}
+
+/// Why a standby connection stopped being held
+enum StandbyEnd {
+    /// Promoted to the active connection
+    HandedOver,
+    /// The active connection moved to the same server
+    Displaced,
+    /// The server refused the registration
+    Rejected,
+    Lost,
 }
 
 impl WebSocketClient {
+    /// Create a client that registers as `client_id` from `hostname`
     pub fn new(server_url: String, client_id: String, hostname: String) -> Self {
         Self {
             server_url,
+            fallback_urls: Vec::new(),
+            primary_retry: DEFAULT_PRIMARY_RETRY,
+            discovery: None,
             client_id,
             hostname,
+            location: None,
+            machine_role: None,
+            groups: Vec::new(),
+            categories: Vec::new(),
+            transport: Arc::new(TungsteniteTransport::default()),
+            outbound: Arc::new(OutboundQueue::default()),
+            status: None,
+            settings: SharedSettings::default(),
+            standby_url: None,
+            seen: Mutex::new(SeenAlerts::default()),
+            suppressions: None,
+            pending: None,
+            capture: None,
+            state: watch::Sender::new(ConnectionState::Connecting),
+            maintenance: Arc::new(MaintenanceWindow::new()),
+            updater: None,
+            sound_packs: None,
+            alert_key: None,
+            signing_key: None,
+            capabilities: None,
+            previous_shutdown: None,
+            watermark: None,
+            timings: Arc::default(),
+            server_clock: Arc::new(ServerClock::new(Arc::new(SystemClock))),
+            backoff: BackoffConfig::default(),
+            server_timeout: DEFAULT_SERVER_TIMEOUT,
+            heartbeat_missed_acks: DEFAULT_HEARTBEAT_MISSED_ACKS,
+            max_unreadable_messages: DEFAULT_MAX_UNREADABLE_MESSAGES,
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+            register_timeout: DEFAULT_REGISTER_TIMEOUT,
         }
     }
 
-    /// Connect to the server and handle messages
-    pub async fn run(
+    /// Replace the WebSocket transport (default: [`TungsteniteTransport`])
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Queue of messages drained to the server whenever connected
+    pub fn with_outbound_queue(mut self, outbound: Arc<OutboundQueue>) -> Self {
+        self.outbound = outbound;
+        self
+    }
+
+    /// Send a status report on connect and then every status interval
+    pub fn with_status(mut self, status: Arc<StatusCollector>) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Look the server up in DNS on every reconnect cycle instead of using the fixed URL
+    pub fn with_discovery(mut self, discovery: DnsDiscovery) -> Self {
+        self.discovery = Some(discovery);
+        self
+    }
+
+    /// Register at `location` and drop alerts targeted elsewhere
+    pub fn with_location(mut self, location: Option<Location>) -> Self {
+        self.location = location;
+        self
+    }
+
+    /// Report `role` in registration, so the server can leave out alerts not meant for it
+    pub fn with_machine_role(mut self, role: impl Into<String>) -> Self {
+        self.machine_role = Some(role.into());
+        self
+    }
+
+    /// Report `groups` in registration, so the server knows which targeted alerts this agent takes
+    pub fn with_groups(mut self, groups: Vec<String>) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    /// Report the alert `categories` this agent has settings for in registration
+    pub fn with_categories(mut self, categories: Vec<String>) -> Self {
+        self.categories = categories;
+        self
+    }
+
+    /// Read heartbeat, status, and reconnect timing from `settings`
+    pub fn with_settings(mut self, settings: SharedSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Keep a second connection to `url` registered in standby mode and switch
+    /// to it without a reconnect delay when the active connection drops
+    pub fn with_standby(mut self, url: Option<String>) -> Self {
+        self.standby_url = url;
+        self
+    }
+
+    /// Store suppression windows the server schedules for this machine in `suppressions`
+    pub fn with_suppressions(mut self, suppressions: Arc<SuppressionWindows>) -> Self {
+        self.suppressions = Some(suppressions);
+        self
+    }
+
+    /// After registering, report `handler`'s pending alerts and withdraw those
+    /// the server has cancelled or expired in the meantime
+    pub fn with_pending_sync(mut self, handler: Arc<AlertHandler>) -> Self {
+        self.pending = Some(handler);
+        self
+    }
+
+    /// Copy every frame sent to or received from a server to `capture`
+    pub fn with_wire_capture(mut self, capture: Arc<WireCapture>) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Record servers' maintenance announcements in `maintenance`, shared with status reports
+    pub fn with_maintenance(mut self, maintenance: Arc<MaintenanceWindow>) -> Self {
+        self.maintenance = maintenance;
+        self
+    }
+
+    /// Hand releases the server offers to `updater`
+    pub fn with_updater(mut self, updater: Arc<Updater>) -> Self {
+        self.updater = Some(updater);
+        self
+    }
+
+    /// Hand sound packs the server offers to `packs`
+    pub fn with_sound_packs(mut self, packs: Arc<SoundPacks>) -> Self {
+        self.sound_packs = Some(packs);
+        self
+    }
+
+    /// Offer `key` for sealing alerts to this client and open sealed alerts with it
+    pub fn with_alert_key(mut self, key: Option<AlertKey>) -> Self {
+        self.alert_key = key;
+        self
+    }
+
+    /// Reject alerts not signed with `key` (default: alerts need no signature)
+    pub fn with_signing_key(mut self, key: Option<SigningKey>) -> Self {
+        self.signing_key = key;
+        self
+    }
+
+    /// Report the latest of these self-check results each time the client registers
+    pub fn with_capabilities(mut self, capabilities: watch::Receiver<Capabilities>) -> Self {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    /// Move `watermark` on with each alert received, and report it each time
+    /// the client registers
+    pub fn with_watermark(mut self, watermark: Arc<Watermark>) -> Self {
+        self.watermark = Some(watermark);
+        self
+    }
+
+    /// Report how the agent's previous run ended each time the client registers
+    pub fn with_previous_shutdown(mut self, record: ShutdownRecord) -> Self {
+        self.previous_shutdown = Some(record);
+        self
+    }
+
+    /// Stamp each alert's arrival, parsing and queueing from the clock of `timings`
+    pub fn with_delivery_timings(mut self, timings: Arc<DeliveryTimings>) -> Self {
+        self.timings = timings;
+        self
+    }
+
+    /// Measure the local clock's skew into `clock` from the time each server
+    /// sends with its registration ack; share it as the handler's clock so
+    /// confirmations are stamped by the server's time
+    pub fn with_server_clock(mut self, clock: Arc<ServerClock>) -> Self {
+        self.server_clock = clock;
+        self
+    }
+
+    /// Grow the wait between failed reconnect cycles as `backoff` says
+    /// (default: the settings' reconnect delay every time)
+    pub fn with_reconnect_backoff(mut self, backoff: BackoffConfig) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Ping a server silent for half of `timeout`, and drop its connection
+    /// once it has been silent for all of it (default: [`DEFAULT_SERVER_TIMEOUT`])
+    pub fn with_server_timeout(mut self, timeout: Duration) -> Self {
+        self.server_timeout = timeout;
+        self
+    }
+
+    /// Drop a connection, keeping what was being sent for the next one, once
+    /// a frame takes longer than `timeout` to write (default: [`DEFAULT_SEND_TIMEOUT`])
+    pub fn with_send_timeout(mut self, timeout: Duration) -> Self {
+        self.send_timeout = timeout;
+        self
+    }
+
+    /// Drop a connection whose server has not acknowledged the registration
+    /// within `timeout` (default: [`DEFAULT_REGISTER_TIMEOUT`])
+    pub fn with_register_timeout(mut self, timeout: Duration) -> Self {
+        self.register_timeout = timeout;
+        self
+    }
+
+    /// Reconnect once `missed` heartbeats in a row go unacknowledged by a
+    /// server that has acknowledged one before (default: [`DEFAULT_HEARTBEAT_MISSED_ACKS`])
+    pub fn with_heartbeat_missed_acks(mut self, missed: u32) -> Self {
+        self.heartbeat_missed_acks = missed;
+        self
+    }
+
+    /// Reconnect once `max` messages in a row from the server cannot be read;
+    /// fewer are reported and skipped (default: [`DEFAULT_MAX_UNREADABLE_MESSAGES`])
+    pub fn with_max_unreadable_messages(mut self, max: u32) -> Self {
+        self.max_unreadable_messages = max;
+        self
+    }
+
+    /// Servers to try in order when the server URL cannot be reached
+    pub fn with_fallbacks(mut self, urls: Vec<String>) -> Self {
+        self.fallback_urls = urls;
+        self
+    }
+
+    /// While connected to any server but the first, try the first again every
+    /// `interval` and move back once it answers (default: [`DEFAULT_PRIMARY_RETRY`])
+    pub fn with_primary_retry(mut self, interval: Duration) -> Self {
+        self.primary_retry = interval;
+        self
+    }
+
+    pub fn outbound_queue(&self) -> &Arc<OutboundQueue> {
+        &self.outbound
+    }
+
+    /// Follows the active connection as it is made, registered and lost
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    /// Publish `state`, waking watchers only if it changed
+    fn set_state(&self, state: ConnectionState) {
+        self.state.send_if_modified(|current| {
+            let changed: bool = *current != state;
+            *current = state;
+            changed
+        });
+    }
+
+    /// Connect to the server and handle messages until `cancel` fires.
+    ///
+    /// Each reconnect cycle tries the candidate servers in order until one
+    /// accepts the connection, then waits the reconnect delay once that
+    /// connection ends or every candidate has failed. The wait grows with the
+    /// reconnect backoff until a connection stays up long enough. While
+    /// connected to any server but the first, the first is tried again every
+    /// primary-retry interval and the connection moves back to it once it answers.
+    ///
+    /// With a standby server, a second connection is held to it alongside and
+    /// takes over at once when the active connection ends.
+    pub async fn run(&self, alert_queue: Arc<AlertQueue>, cancel: CancellationToken) -> Result<()> {
+        let Some(standby_url) = &self.standby_url else {
+            return self.run_active(&alert_queue, &cancel, None).await;
+        };
+
+        let (active_tx, active_rx) = watch::channel::<Option<String>>(None);
+        let (handover_tx, handover_rx) = mpsc::channel::<Handover>(1);
+        let control: StandbyControl = StandbyControl {
+            active: active_tx,
+            handover: handover_tx,
+        };
+        // Joined rather than spawned so both loops stop with the client task
+        let (result, ()) = tokio::join!(
+            self.run_active(&alert_queue, &cancel, Some(&control)),
+            self.run_standby(standby_url, &alert_queue, active_rx, handover_rx, &cancel),
+        );
+        result
+    }
+
+    /// Reconnect loop for the connection that carries confirmations and reports
+    async fn run_active(
         &self,
-        alert_tx: mpsc::Sender<Alert>,
-        mut confirmation_rx: mpsc::Receiver<Confirmation>,
+        alert_queue: &AlertQueue,
+        cancel: &CancellationToken,
+        standby: Option<&StandbyControl>,
     ) -> Result<()> {
+        let mut backoff: ReconnectBackoff = ReconnectBackoff::new(self.backoff.clone());
+        'cycle: loop {
+            let urls: Vec<String> = tokio::select! {
+                _ = cancel.cancelled() => break,
+                urls = self.server_urls() => urls,
+            };
+
+            self.set_state(ConnectionState::Connecting);
+            let mut last_error: Option<String> = None;
+            let mut link: Option<(String, Connection)> = None;
+            for url in &urls {
+                log::info!("Connecting to {}", url);
+                let connection: Result<Connection> = tokio::select! {
+                    _ = cancel.cancelled() => break 'cycle,
+                    connection = self.connect(url) => connection,
+                };
+                match connection {
+                    Ok(connection) => {
+                        link = Some((url.clone(), connection));
+                        break;
+                    }
+                    Err(e) => {
+                        log::log!(self.maintenance.log_level(), "WebSocket error: {}", e);
+                        last_error = Some(e.to_string());
+                    }
+                }
+            }
+            if link.is_none() {
+                link = tokio::select! {
+                    _ = cancel.cancelled() => break 'cycle,
+                    link = take_standby(standby) => link,
+                };
+            }
+
+            while let Some((url, connection)) = link.take() {
+                if let Some(standby) = standby {
+                    standby.active.send_replace(Some(url.clone()));
+                }
+                let connected_at: Instant = Instant::now();
+                let primary: Option<&str> = urls.first().map(String::as_str).filter(|p| *p != url);
+                let result: Result<Option<Connection>> = self
+                    .handle_connection(&url, primary, connection, alert_queue, cancel, &mut backoff)
+                    .await;
+                if cancel.is_cancelled() {
+                    break 'cycle;
+                }
+                backoff.connection_ended(connected_at.elapsed());
+                match result {
+                    Ok(Some(connection)) => {
+                        if let Some(primary) = primary {
+                            log::info!("Primary server {} is back; leaving {}", primary, url);
+                            link = Some((primary.to_string(), connection));
+                        }
+                        continue;
+                    }
+                    Ok(None) => {
+                        log::info!("WebSocket connection closed normally");
+                        last_error = None;
+                    }
+                    Err(e @ EmnsError::Auth { .. }) => {
+                        backoff.rejected();
+                        last_error = Some(e.to_string());
+                    }
+                    Err(e) => {
+                        log::log!(self.maintenance.log_level(), "WebSocket error: {}", e);
+                        last_error = Some(e.to_string());
+                    }
+                }
+                self.set_state(ConnectionState::Disconnected {
+                    last_error: last_error.clone(),
+                });
+                // Promote the standby connection instead of waiting to reconnect
+                link = tokio::select! {
+                    _ = cancel.cancelled() => break 'cycle,
+                    link = take_standby(standby) => link,
+                };
+                if let Some((url, _)) = &link {
+                    log::warn!("Promoting standby connection to {}", url);
+                }
+            }
+            if let Some(standby) = standby {
+                standby.active.send_replace(None);
+            }
+            self.set_state(ConnectionState::Disconnected { last_error });
+
+            // Start the next cycle from the most preferred server
+            let delay: Duration = self.reconnect_delay(&mut backoff);
+            log::info!("Reconnecting in {:.1} seconds...", delay.as_secs_f32());
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+
+        log::info!("WebSocket client stopped");
+        Ok(())
+    }
+
+    /// Hold a standby connection to whichever server the active connection is not using
+    async fn run_standby(
+        &self,
+        standby_url: &str,
+        alert_queue: &AlertQueue,
+        mut active_rx: watch::Receiver<Option<String>>,
+        mut handover_rx: mpsc::Receiver<Handover>,
+        cancel: &CancellationToken,
+    ) {
+        // The URL just handed over is active even if the watch has not caught up
+        let mut handed_over: Option<String> = None;
+        let mut backoff: ReconnectBackoff = ReconnectBackoff::new(self.backoff.clone());
         loop {
-            match self
-                .connect_and_handle(alert_tx.clone(), &mut confirmation_rx)
+            let active: Option<String> = handed_over.take().or_else(|| active_rx.borrow().clone());
+            let target = self.standby_target(standby_url, active.as_deref());
+            let Some(target) = without_standby(target, &mut handover_rx, cancel).await else {
+                return;
+            };
+
+            if let Some(url) = target {
+                log::info!("Connecting to {} as standby", url);
+                let connection = self.connect(&url);
+                match without_standby(connection, &mut handover_rx, cancel).await {
+                    None => return,
+                    Some(Err(e)) => log::log!(
+                        self.maintenance.log_level(),
+                        "Standby connection to {} failed: {}",
+                        url,
+                        e
+                    ),
+                    Some(Ok(connection)) => {
+                        let connected_at: Instant = Instant::now();
+                        let end: StandbyEnd = tokio::select! {
+                            _ = cancel.cancelled() => return,
+                            end = self.hold_standby(&url, connection, alert_queue, &mut active_rx, &mut handover_rx, &mut backoff) => end,
+                        };
+                        backoff.connection_ended(connected_at.elapsed());
+                        match end {
+                            StandbyEnd::HandedOver => {
+                                handed_over = Some(url);
+                                continue;
+                            }
+                            StandbyEnd::Displaced => continue,
+                            StandbyEnd::Rejected => backoff.rejected(),
+                            StandbyEnd::Lost => {}
+                        }
+                    }
+                }
+            }
+
+            let sleep = tokio::time::sleep(self.reconnect_delay(&mut backoff));
+            if without_standby(sleep, &mut handover_rx, cancel)
                 .await
+                .is_none()
             {
-                Ok(_) => {
-                    log::info!("WebSocket connection closed normally");
+                return;
+            }
+        }
+    }
+
+    /// Server to stand by on: the standby server, unless the active connection is using it
+    async fn standby_target(&self, standby_url: &str, active: Option<&str>) -> Option<String> {
+        if active != Some(standby_url) {
+            return Some(standby_url.to_string());
+        }
+        self.server_urls()
+            .await
+            .into_iter()
+            .find(|url| url != standby_url)
+    }
+
+    /// Register `connection` as a standby and queue its alerts until it ends
+    async fn hold_standby(
+        &self,
+        url: &str,
+        connection: Connection,
+        alert_queue: &AlertQueue,
+        active_rx: &mut watch::Receiver<Option<String>>,
+        handover_rx: &mut mpsc::Receiver<Handover>,
+        backoff: &mut ReconnectBackoff,
+    ) -> StandbyEnd {
+        let Connection {
+            sink: mut write,
+            stream: mut read,
+        } = connection;
+
+        let register_msg: Message = Message::Register {
+            client_id: self.client_id.clone(),
+            hostname: self.hostname.clone(),
+            location: self.location.clone(),
+            standby: true,
+            encryption_key: self.alert_key.as_ref().map(AlertKey::public_key),
+            capabilities: self.capabilities.as_ref().map(|c| c.borrow().clone()),
+            previous_shutdown: self.previous_shutdown.clone(),
+            since: self.watermark.as_ref().and_then(|w| w.get()),
+            sound_pack_version: self.sound_pack_version(),
+            machine_role: self.machine_role.clone(),
+            groups: self.groups.clone(),
+            categories: self.categories.clone(),
+            server_url: Some(url.to_string()),
+            supported_encodings: vec![Encoding::Msgpack],
+            protocol_version: Some(PROTOCOL_VERSION),
+            // Acks and confirmations go to the active server only
+            envelopes: false,
+            confirmation_timeouts: false,
+        };
+        if let Err(e) = self.send(url, &mut write, &register_msg).await {
+            log::error!("Standby connection to {} failed: {}", url, e);
+            return StandbyEnd::Lost;
+        }
+        log::info!("Standing by on {}", url);
+        let connected_at: chrono::DateTime<chrono::Utc> = chrono::Utc::now();
+        let mut heartbeat: Interval = interval(self.settings.snapshot().heartbeat_interval());
+        let mut liveness: Liveness = Liveness::new(self.server_timeout);
+        let mut wire: Wire = Wire::default();
+
+        loop {
+            tokio::select! {
+                msg = read.next() => {
+                    if let Some(Ok(_)) = &msg {
+                        liveness.heard();
+                    }
+                    match msg {
+                        Some(Ok(Frame::Ping(data))) => {
+                            if let Err(e) = self.write_frame(url, &mut write, Frame::Pong(data)).await {
+                                log::error!("Standby connection to {} failed: {}", url, e);
+                                return StandbyEnd::Lost;
+                            }
+                        }
+                        Some(Ok(frame @ (Frame::Text(_) | Frame::Binary(_)))) => {
+                            let received = Some(self.timings.now());
+                            // Only alerts matter here; the active connection handles the rest
+                            match decode(&frame) {
+                                Ok(Message::Alert { alert }) => {
+                                    let trace: DeliveryTrace = DeliveryTrace {
+                                        received,
+                                        parsed: Some(self.timings.now()),
+                                        ..DeliveryTrace::default()
+                                    };
+                                    let _ = self.queue_alert(alert, alert_queue, trace).await;
+                                }
+                                Ok(Message::AlertBatch { batch }) => {
+                                    let trace: DeliveryTrace = DeliveryTrace {
+                                        received,
+                                        parsed: Some(self.timings.now()),
+                                        ..DeliveryTrace::default()
+                                    };
+                                    let _ = self.queue_batch(url, batch, alert_queue, trace).await;
+                                }
+                                Ok(Message::RegisterAck { encoding, .. }) => {
+                                    backoff.registered();
+                                    if let Some(chosen) = encoding {
+                                        wire.encoding = chosen;
+                                    }
+                                }
+                                Ok(Message::RegisterRejected { reason }) => {
+                                    log::error!("Standby server {} rejected registration: {}", url, reason);
+                                    return StandbyEnd::Rejected;
+                                }
+                                Ok(_) => {}
+                                Err(e) => log::warn!("Standby server {}: {}", url, e),
+                            }
+                        }
+                        Some(Ok(Frame::Close(_))) | None => {
+                            log::info!("Standby connection to {} closed", url);
+                            return StandbyEnd::Lost;
+                        }
+                        Some(Err(e)) => {
+                            log::error!("Standby connection to {} failed: {}", url, e);
+                            return StandbyEnd::Lost;
+                        }
+                        _ => {}
+                    }
+                }
+
+                Some(responder) = handover_rx.recv() => {
+                    let connection: Connection = Connection { sink: write, stream: read };
+                    if responder.send((url.to_string(), connection)).is_err() {
+                        log::warn!("Standby connection to {} was not taken over", url);
+                    }
+                    return StandbyEnd::HandedOver;
                 }
-                Err(e) => {
-                    log::error!("WebSocket error: {}", e);
+
+                Ok(()) = active_rx.changed() => {
+                    if active_rx.borrow().as_deref() == Some(url) {
+                        log::info!("Active connection moved to {}; dropping standby", url);
+                        return StandbyEnd::Displaced;
+                    }
+                }
+
+                _ = heartbeat.tick() => {
+                    let stats: HeartbeatStats = self.bare_heartbeat(connected_at);
+                    let heartbeat: Message = Message::Heartbeat { stats };
+                    if let Err(e) = self.send_as(url, &mut write, &heartbeat, wire).await {
+                        log::error!("Standby connection to {} failed: {}", url, e);
+                        return StandbyEnd::Lost;
+                    }
+                }
+
+                _ = tokio::time::sleep_until(liveness.deadline()) => {
+                    let probed: Result<()> = if liveness.lapse() {
+                        self.write_frame(url, &mut write, Frame::Ping(Vec::new())).await
+                    } else {
+                        Err(liveness.dead(url))
+                    };
+                    if let Err(e) = probed {
+                        log::error!("Standby connection to {} failed: {}", url, e);
+                        return StandbyEnd::Lost;
+                    }
                 }
             }
+        }
+    }
 
-            log::info!("Reconnecting in 5 seconds...");
-            tokio::time::sleep(Duration::from_secs(5)).await;
+    /// Wait before reconnecting: the next step of `backoff` from the settings'
+    /// reconnect delay, and longer while a server is down for maintenance
+    fn reconnect_delay(&self, backoff: &mut ReconnectBackoff) -> Duration {
+        let first: Duration = self.settings.snapshot().reconnect_delay();
+        // Refusals while a server is down as announced are expected, so they do not grow the wait
+        if self.maintenance.is_active() {
+            return self.maintenance.reconnect_delay(first);
         }
+        let delay: Duration = backoff.next_delay(first, &mut rand::thread_rng());
+        self.maintenance.reconnect_delay(delay)
     }
 
-    async fn connect_and_handle(
-        &self,
-        alert_tx: mpsc::Sender<Alert>,
-        confirmation_rx: &mut mpsc::Receiver<Confirmation>,
-    ) -> Result<()> {
-        log::info!("Connecting to {}", self.server_url);
+    /// Servers to try this cycle, most preferred first
+    async fn server_urls(&self) -> Vec<String> {
+        match &self.discovery {
+            Some(discovery) => discovery.server_urls().await,
+            None => std::iter::once(&self.server_url)
+                .chain(&self.fallback_urls)
+                .cloned()
+                .collect(),
+        }
+    }
 
-        let (ws_stream, _) = connect_async(&self.server_url)
-            .await
-            .context("Failed to connect to WebSocket server")?;
+    /// Try `primary` every primary-retry interval until it accepts a
+    /// connection; never finishes without one
+    async fn reach_primary(&self, primary: Option<&str>) -> Connection {
+        let Some(primary) = primary else {
+            return std::future::pending().await;
+        };
+        loop {
+            tokio::time::sleep(self.primary_retry).await;
+            match self.connect(primary).await {
+                Ok(connection) => return connection,
+                Err(e) => log::debug!("Primary server {} still unreachable: {}", primary, e),
+            }
+        }
+    }
+
+    /// Open a connection to `url`, tapped for capture when enabled, so
+    /// frames are recorded exactly as the transport carried them
+    async fn connect(&self, url: &str) -> Result<Connection> {
+        let connection: Connection = self.transport.connect(url).await?;
+        Ok(match &self.capture {
+            Some(capture) => capture.tap(url, connection),
+            None => connection,
+        })
+    }
 
-        log::info!("Connected to server");
+    /// Register on `connection` and serve it until it ends, or until `cancel`
+    /// fires and the client says goodbye. While connected to a server other
+    /// than `primary`, returns a new connection to `primary` once it answers,
+    /// having closed this one.
+    async fn handle_connection(
+        &self,
+        url: &str,
+        primary: Option<&str>,
+        connection: Connection,
+        alert_queue: &AlertQueue,
+        cancel: &CancellationToken,
+        backoff: &mut ReconnectBackoff,
+    ) -> Result<Option<Connection>> {
+        let Connection {
+            sink: mut write,
+            stream: mut read,
+        } = connection;
 
-        let (mut write, mut read) = ws_stream.split();
+        match primary {
+            Some(primary) => log::warn!(
+                "Connected to fallback server {} ({} is unreachable)",
+                url,
+                primary
+            ),
+            None => log::info!("Connected to server {}", url),
+        }
+        let connected_at: chrono::DateTime<chrono::Utc> = chrono::Utc::now();
 
         // Send registration message
         let register_msg: Message = Message::Register {
             client_id: self.client_id.clone(),
             hostname: self.hostname.clone(),
+            location: self.location.clone(),
+            standby: false,
+            encryption_key: self.alert_key.as_ref().map(AlertKey::public_key),
+            capabilities: self.capabilities.as_ref().map(|c| c.borrow().clone()),
+            previous_shutdown: self.previous_shutdown.clone(),
+            since: self.watermark.as_ref().and_then(|w| w.get()),
+            sound_pack_version: self.sound_pack_version(),
+            machine_role: self.machine_role.clone(),
+            groups: self.groups.clone(),
+            categories: self.categories.clone(),
+            server_url: Some(url.to_string()),
+            supported_encodings: vec![Encoding::Msgpack],
+            protocol_version: Some(PROTOCOL_VERSION),
+            envelopes: true,
+            confirmation_timeouts: true,
         };
-        let json: String = serde_json::to_string(&register_msg)?;
-        write.send(WsMessage::Text(json)).await?;
+        self.send(url, &mut write, &register_msg).await?;
         log::info!("Sent registration message");
+        self.maintenance.end(url);
+        if let Some(handler) = &self.pending {
+            let pending_alert_ids: Vec<uuid::Uuid> = handler.get_pending_alerts().await;
+            log::info!(
+                "Reconciling {} pending alerts with the server",
+                pending_alert_ids.len()
+            );
+            self.send(url, &mut write, &Message::PendingSync { pending_alert_ids })
+                .await?;
+        }
+
+        let mut acks: HeartbeatAcks = HeartbeatAcks::default();
+        // Bare JSON until the server's registration ack picks otherwise
+        let mut wire: Wire = Wire::default();
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                self.leave(url, &mut write, wire).await;
+                return Ok(None);
+            }
+            registered = self.await_registration(
+                url,
+                &mut write,
+                &mut read,
+                alert_queue,
+                &mut acks,
+                &mut wire,
+            ) => registered?,
+        }
+        backoff.registered();
+        self.set_state(ConnectionState::Connected {
+            url: url.to_string(),
+            since: connected_at,
+        });
+
+        // Reports from the last connection are stale; fresh ones are queued below
+        self.outbound.discard_telemetry();
 
-        // Heartbeat timer
-        let mut heartbeat: tokio::time::Interval = interval(Duration::from_secs(30));
+        // Heartbeat and status timers, re-armed when the settings change
+        let mut settings_rx = self.settings.subscribe();
+        let settings: AgentSettings = self.settings.snapshot();
+        let mut heartbeat: Interval = interval(settings.heartbeat_interval());
+        let mut status: Interval = interval(settings.status_interval());
+        let mut liveness: Liveness = Liveness::new(self.server_timeout);
+        let back_to_primary = self.reach_primary(primary);
+        tokio::pin!(back_to_primary);
+        let mut unreadable: u32 = 0;
 
         loop {
             tokio::select! {
+                _ = cancel.cancelled() => {
+                    self.leave(url, &mut write, wire).await;
+                    return Ok(None);
+                }
+
                 // Handle incoming messages from server
                 msg = read.next() => {
+                    if let Some(Ok(_)) = &msg {
+                        liveness.heard();
+                    }
                     match msg {
-                        Some(Ok(WsMessage::Text(text))) => {
-                            self.handle_server_message(&text, &alert_tx).await?;
+                        Some(Ok(frame @ (Frame::Text(_) | Frame::Binary(_)))) => {
+                            let received = self.timings.now();
+                            if let Some((message, message_id)) = self.read_message(url, &frame, &mut unreadable, wire)? {
+                                self.handle_server_message(url, message, message_id, alert_queue, received, &mut acks, &mut wire).await?;
+                            }
+                        }
+                        Some(Ok(Frame::Ping(data))) => {
+                            self.write_frame(url, &mut write, Frame::Pong(data)).await?;
                         }
-                        Some(Ok(WsMessage::Close(_))) => {
-                            log::info!("Server closed connection");
+                        Some(Ok(Frame::Close(frame))) => {
+                            match frame {
+                                Some(frame) => log::info!(
+                                    "Server closed connection ({}: {})",
+                                    frame.code,
+                                    frame.reason
+                                ),
+                                None => log::info!("Server closed connection"),
+                            }
                             break;
                         }
                         Some(Err(e)) => {
-                            return Err(e.into());
+                            return Err(e);
                         }
                         None => {
                             log::info!("Connection closed");
@@ -94,66 +1005,2871 @@ impl WebSocketClient {
                     }
                 }
 
-                // Send confirmations to server
-                Some(confirmation) = confirmation_rx.recv() => {
-                    let msg = Message::Confirmation { confirmation };
-                    let json = serde_json::to_string(&msg)?;
-                    write.send(WsMessage::Text(json)).await?;
-                    log::info!("Sent confirmation to server");
+                // Send confirmations, reports and telemetry, most important first
+                msg = self.outbound.next() => {
+                    let confirmation: bool = msg.priority() == Priority::Confirmation;
+                    if let Err(e) = self.send_as(url, &mut write, &msg.clone().into(), wire).await {
+                        // Only what failed is kept for the next connection
+                        if msg.retry_on_failure() {
+                            self.outbound.requeue(msg);
+                        }
+                        return Err(e);
+                    }
+                    if confirmation {
+                        log::info!("Sent confirmation to server");
+                    } else {
+                        log::debug!("Sent queued message to server");
+                    }
                 }
 
-                // Send heartbeat
+                // Queue a heartbeat, unless the server has stopped acknowledging them
                 _ = heartbeat.tick() => {
-                    let msg = Message::Heartbeat;
-                    let json = serde_json::to_string(&msg)?;
-                    write.send(WsMessage::Text(json)).await?;
-                    log::debug!("Sent heartbeat");
+                    if acks.missed(self.heartbeat_missed_acks) {
+                        return Err(EmnsError::connection(
+                            url,
+                            format!("{} heartbeats in a row went unacknowledged", acks.unacked),
+                        ));
+                    }
+                    let mut stats: HeartbeatStats = match &self.status {
+                        Some(collector) => collector.heartbeat(connected_at),
+                        None => self.bare_heartbeat(connected_at),
+                    };
+                    stats.seq = Some(acks.sent());
+                    stats.clock_skew_ms = self.clock_skew_ms();
+                    self.outbound.push(OutboundMessage::Heartbeat(stats));
+                }
+
+                Ok(()) = settings_rx.changed() => {
+                    let settings: AgentSettings = self.settings.snapshot();
+                    heartbeat = rearm(settings.heartbeat_interval());
+                    status = rearm(settings.status_interval());
+                    log::debug!("Re-armed heartbeat and status timers");
+                }
+
+                // Queue a report of queue depths
+                _ = status.tick(), if self.status.is_some() => {
+                    if let Some(collector) = &self.status {
+                        self.outbound.push(OutboundMessage::Status(Box::new(collector.collect())));
+                    }
+                }
+
+                // Ping a quiet server, and give up on a silent one
+                _ = tokio::time::sleep_until(liveness.deadline()) => {
+                    if !liveness.lapse() {
+                        return Err(liveness.dead(url));
+                    }
+                    self.write_frame(url, &mut write, Frame::Ping(Vec::new())).await?;
+                }
+
+                // The first server is back; anything not yet sent goes to it instead
+                connection = &mut back_to_primary => {
+                    if let Err(e) = write.close().await {
+                        log::debug!("Failed to close connection to {}: {}", url, e);
+                    }
+                    return Ok(Some(connection));
                 }
             }
         }
 
-        Ok(())
+        Ok(None)
     }
 
-    async fn handle_server_message(
+    /// Wait for the server to acknowledge the registration, acting on anything
+    /// it sends first. A server that has not answered within the register
+    /// timeout has ignored the registration, and the connection is dropped.
+    async fn await_registration(
         &self,
-        text: &str,
-        alert_tx: &mpsc::Sender<Alert>,
+        url: &str,
+        write: &mut FrameSink,
+        read: &mut FrameStream,
+        alert_queue: &AlertQueue,
+        acks: &mut HeartbeatAcks,
+        wire: &mut Wire,
     ) -> Result<()> {
-        let message: Message =
-            serde_json::from_str(text).context("Failed to parse server message")?;
-
-        match message {
-            Message::Alert { alert } => {
-                log::info!("Received alert: {} - {}", alert.id, alert.title);
-                alert_tx
-                    .send(alert)
-                    .await
-                    .context("Failed to send alert to handler")?;
-            }
-            Message::Heartbeat => {
-                log::debug!("Received heartbeat from server");
+        let sent: Instant = Instant::now();
+        let deadline: Instant = sent + self.register_timeout;
+        let mut unreadable: u32 = 0;
+        loop {
+            let Ok(msg) = tokio::time::timeout_at(deadline, read.next()).await else {
+                log::error!(
+                    "Server {} did not acknowledge the registration within {:?}",
+                    url,
+                    self.register_timeout
+                );
+                return Err(EmnsError::connection(
+                    url,
+                    "registration was not acknowledged",
+                ));
+            };
+            match msg {
+                Some(Ok(frame @ (Frame::Text(_) | Frame::Binary(_)))) => {
+                    let received = self.timings.now();
+                    let Some((message, message_id)) =
+                        self.read_message(url, &frame, &mut unreadable, *wire)?
+                    else {
+                        continue;
+                    };
+                    let acknowledged: bool = matches!(message, Message::RegisterAck { .. });
+                    if let Message::RegisterAck {
+                        server_time: Some(server_time),
+                        ..
+                    } = &message
+                    {
+                        self.sync_clock(url, *server_time, sent.elapsed());
+                    }
+                    self.handle_server_message(
+                        url,
+                        message,
+                        message_id,
+                        alert_queue,
+                        received,
+                        acks,
+                        wire,
+                    )
+                    .await?;
+                    if acknowledged {
+                        return Ok(());
+                    }
+                }
+                Some(Ok(Frame::Ping(data))) => {
+                    self.write_frame(url, write, Frame::Pong(data)).await?;
+                }
+                Some(Ok(Frame::Close(_))) | None => {
+                    return Err(EmnsError::connection(
+                        url,
+                        "closed before acknowledging the registration",
+                    ));
+                }
+                Some(Err(e)) => return Err(e),
+                Some(Ok(_)) => {}
             }
-            _ => {
-                log::warn!("Unexpected message type from server");
+        }
+    }
+
+    /// Say goodbye to `url`, giving up after [`GOODBYE_TIMEOUT`]
+    async fn leave(&self, url: &str, write: &mut FrameSink, wire: Wire) {
+        if tokio::time::timeout(GOODBYE_TIMEOUT, self.say_goodbye(url, write, wire))
+            .await
+            .is_err()
+        {
+            log::warn!(
+                "Gave up saying goodbye to {} after {:?}",
+                url,
+                GOODBYE_TIMEOUT
+            );
+        }
+    }
+
+    /// Send what is still queued for the server, apart from stale telemetry,
+    /// then unregister and close the connection
+    async fn say_goodbye(&self, url: &str, write: &mut FrameSink, wire: Wire) {
+        let queued: Vec<OutboundMessage> = self
+            .outbound
+            .take_matching(OutboundMessage::retry_on_failure);
+        log::info!(
+            "Flushing {} queued messages before disconnecting",
+            queued.len()
+        );
+        let total: usize = queued.len();
+        for (sent, msg) in queued.into_iter().enumerate() {
+            if let Err(e) = self.send_as(url, write, &msg.into(), wire).await {
+                log::warn!(
+                    "Stopping with {} queued messages unsent: {}",
+                    total - sent,
+                    e
+                );
+                return;
             }
         }
 
-        Ok(())
+        let goodbye: Message = Message::Unregister {
+            client_id: self.client_id.clone(),
+            reason: self.leaving_reason(),
+        };
+        if let Err(e) = self.send_as(url, write, &goodbye, wire).await {
+            log::warn!("Failed to unregister: {}", e);
+            return;
+        }
+        log::info!("Unregistered from server");
+        if let Err(e) = write.close().await {
+            log::debug!("Failed to close connection: {}", e);
+        }
     }
-}
 
-/// Get the hostname of the machine
-pub fn get_hostname() -> String {
-    hostname::get()
-        .ok()
-        .and_then(|h| h.into_string().ok())
-        .unwrap_or_else(|| "unknown".to_string())
-}
+    /// Why the agent is stopping: to restart into an update once the updater asks, otherwise on request
+    fn leaving_reason(&self) -> ShutdownReason {
+        match &self.updater {
+            Some(updater) if updater.restart_requested().is_cancelled() => ShutdownReason::Update,
+            _ => ShutdownReason::Clean,
+        }
+    }
 
-/// Get the current username
-pub fn get_username() -> String {
+    /// Heartbeat for when there is no status collector to fill it in
+    fn bare_heartbeat(&self, connected_at: chrono::DateTime<chrono::Utc>) -> HeartbeatStats {
+        HeartbeatStats {
+            connected_at: Some(connected_at),
+            client_id: Some(self.client_id.clone()),
+            agent_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            clock_skew_ms: self.clock_skew_ms(),
+            ..HeartbeatStats::default()
+        }
+    }
+
+    /// The measured clock skew, as reported in heartbeats
+    fn clock_skew_ms(&self) -> Option<i64> {
+        self.server_clock
+            .skew()
+            .map(|skew| skew.by.num_milliseconds())
+    }
+
+    /// Measure the local clock against `server_time`, sent by the server at
+    /// `url` in reply to a registration sent `round_trip` ago
+    fn sync_clock(
+        &self,
+        url: &str,
+        server_time: chrono::DateTime<chrono::Utc>,
+        round_trip: Duration,
+    ) {
+        let skew: ClockSkew = self.server_clock.sync(server_time, round_trip);
+        if !skew.corrected {
+            log::warn!(
+                "Clock is {} server {}; too far to be drift, so timestamps are left uncorrected",
+                skew,
+                url
+            );
+        } else if skew.by.abs() >= CLOCK_SKEW_NOTICE {
+            log::warn!(
+                "Clock is {} server {}; stamping confirmations by the server's time",
+                skew,
+                url
+            );
+        } else {
+            log::debug!("Clock is {} server {}", skew, url);
+        }
+    }
+
+    fn sound_pack_version(&self) -> Option<u32> {
+        self.sound_packs
+            .as_ref()
+            .and_then(|packs| packs.library().pack_version())
+    }
+
+    async fn send(&self, url: &str, write: &mut FrameSink, message: &Message) -> Result<()> {
+        self.send_as(url, write, message, Wire::default()).await
+    }
+
+    /// Send `message` in the encoding the server chose for the connection, in
+    /// an envelope of its own if the server asked for envelopes. Acks and
+    /// nacks left over from a connection with envelopes are dropped on one
+    /// without, and timeouts are reported the way the server asked.
+    async fn send_as(
+        &self,
+        url: &str,
+        write: &mut FrameSink,
+        message: &Message,
+        wire: Wire,
+    ) -> Result<()> {
+        if !wire.envelopes && matches!(message, Message::Ack { .. } | Message::Nack { .. }) {
+            log::debug!("Not sending {:?} without envelopes", message);
+            return Ok(());
+        }
+        let message: Cow<'_, Message> = wire.outgoing(message);
+        let message: &Message = &message;
+        let envelope: Option<Envelope> = wire.envelopes.then(|| Envelope {
+            message_id: Uuid::new_v4(),
+            message: message.clone(),
+        });
+        let frame: Frame = match wire.encoding {
+            Encoding::Json => Frame::Text(match &envelope {
+                Some(envelope) => serde_json::to_string(envelope)?,
+                None => serde_json::to_string(message)?,
+            }),
+            Encoding::Msgpack => Frame::Binary(
+                match &envelope {
+                    Some(envelope) => envelope.to_msgpack(),
+                    None => message.to_msgpack(),
+                }
+                .map_err(|e| EmnsError::protocol(format!("Failed to encode message: {}", e)))?,
+            ),
+        };
+        self.write_frame(url, write, frame).await
+    }
+
+    /// Write one frame to `url`, failing the connection if the write does not
+    /// finish within the send timeout, as on a link whose TCP window has filled
+    async fn write_frame(&self, url: &str, write: &mut FrameSink, frame: Frame) -> Result<()> {
+        tokio::time::timeout(self.send_timeout, write.send(frame))
+            .await
+            .map_err(|_| {
+                EmnsError::connection(
+                    url,
+                    format!("send did not finish within {:?}", self.send_timeout),
+                )
+            })?
+    }
+
+    /// Read a message from `frame`, along with the id to answer it by when it
+    /// came in an envelope. One that cannot be read is logged, counted,
+    /// reported to the server and nacked, then skipped; `unreadable` of them in
+    /// a row past the limit drop the connection. An alert that cannot be read
+    /// is rejected as invalid instead, and does not count towards the limit.
+    fn read_message(
+        &self,
+        url: &str,
+        frame: &Frame,
+        unreadable: &mut u32,
+        wire: Wire,
+    ) -> Result<Option<(Message, Option<Uuid>)>> {
+        let decoded: Result<(Message, Option<Uuid>)> = if wire.envelopes {
+            decode_envelope(frame).map(|envelope| {
+                let message_id: Option<Uuid> = envelope.wants_ack().then_some(envelope.message_id);
+                (envelope.message, message_id)
+            })
+        } else {
+            decode(frame).map(|message| (message, None))
+        };
+        let e: EmnsError = match decoded {
+            Ok(decoded) => {
+                *unreadable = 0;
+                return Ok(Some(decoded));
+            }
+            Err(e) => e,
+        };
+        let now: chrono::DateTime<chrono::Utc> = chrono::Utc::now();
+        let value: Option<Value> = match frame {
+            Frame::Text(text) => serde_json::from_str(text).ok(),
+            Frame::Binary(bytes) => msgpack_value(bytes).ok(),
+            _ => None,
+        };
+        let (message_id, message): (Option<Uuid>, Option<&Value>) = match &value {
+            Some(envelope) if wire.envelopes => {
+                (Envelope::message_id_of(envelope), envelope.get("message"))
+            }
+            value => (None, value.as_ref()),
+        };
+        if let Some(invalid) = message.and_then(|message| InvalidAlert::from_message(message, now))
+        {
+            self.answer(
+                message_id,
+                Err(Refusal::new(NackReason::Invalid, invalid.to_string())),
+            );
+            self.reject_invalid(invalid);
+            return Ok(None);
+        }
+        self.answer(
+            message_id,
+            Err(Refusal::new(NackReason::Unreadable, e.to_string())),
+        );
+        *unreadable += 1;
+        let context: String = excerpt(frame);
+        log::warn!(
+            "Skipping unreadable message from {}: {}: {}",
+            url,
+            e,
+            context
+        );
+        if let Some(status) = &self.status {
+            status.unreadable_message();
+        }
+        self.outbound.push(OutboundMessage::Error {
+            context,
+            detail: e.to_string(),
+        });
+        if *unreadable >= self.max_unreadable_messages {
+            log::error!(
+                "{} messages in a row from {} could not be read; reconnecting",
+                unreadable,
+                url
+            );
+            return Err(e);
+        }
+        Ok(None)
+    }
+
+    /// Act on a server message, queueing any alert without waiting on the
+    /// handler, then answer `message_id` if the message came in an envelope
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_server_message(
+        &self,
+        url: &str,
+        message: Message,
+        message_id: Option<Uuid>,
+        alert_queue: &AlertQueue,
+        received: chrono::DateTime<chrono::Utc>,
+        acks: &mut HeartbeatAcks,
+        wire: &mut Wire,
+    ) -> Result<()> {
+        let mut outcome: std::result::Result<(), Refusal> = Ok(());
+        match message {
+            Message::Alert { alert } => {
+                let trace: DeliveryTrace = DeliveryTrace {
+                    received: Some(received),
+                    parsed: Some(self.timings.now()),
+                    ..DeliveryTrace::default()
+                };
+                outcome = self.queue_alert(alert, alert_queue, trace).await;
+            }
+            Message::AlertBatch { batch } => {
+                let trace: DeliveryTrace = DeliveryTrace {
+                    received: Some(received),
+                    parsed: Some(self.timings.now()),
+                    ..DeliveryTrace::default()
+                };
+                outcome = self.queue_batch(url, batch, alert_queue, trace).await;
+            }
+            Message::Heartbeat { .. } => {
+                log::debug!("Received heartbeat from server");
+            }
+            Message::HeartbeatAck { seq } => {
+                log::debug!("Server acknowledged heartbeat {:?}", seq);
+                acks.acked();
+            }
+            Message::RegisterAck {
+                server_name,
+                environment,
+                encoding: chosen,
+                server_version,
+                protocol_version,
+                envelopes,
+                confirmation_timeouts,
+                ..
+            } => {
+                if let Some(version) = protocol_version.filter(|v| *v != PROTOCOL_VERSION) {
+                    log::error!(
+                        "Server {} speaks protocol version {}, but this agent speaks version {}",
+                        url,
+                        version,
+                        PROTOCOL_VERSION
+                    );
+                    return Err(EmnsError::protocol(format!(
+                        "server protocol version {} does not match agent protocol version {}",
+                        version, PROTOCOL_VERSION
+                    )));
+                }
+                log::info!(
+                    "Registered with server {:?} ({:?}), running {}",
+                    server_name.as_deref().unwrap_or("unnamed"),
+                    environment.as_deref().unwrap_or("no environment"),
+                    server_version.as_deref().unwrap_or("an unknown version")
+                );
+                if let Some(chosen) = chosen {
+                    log::info!("Server chose {:?} encoding", chosen);
+                    wire.encoding = chosen;
+                }
+                if envelopes {
+                    log::info!("Server asked for messages in envelopes");
+                }
+                wire.envelopes = envelopes;
+                if confirmation_timeouts {
+                    log::info!("Server asked for timeouts as confirmation_timeout messages");
+                }
+                wire.confirmation_timeouts = confirmation_timeouts;
+                // Fields the server leaves out keep the configured values
+                if server_name.is_some() || environment.is_some() {
+                    let _ = self.settings.update(|s| {
+                        let name = server_name.or_else(|| s.server_name().map(String::from));
+                        let environment =
+                            environment.or_else(|| s.server_environment().map(String::from));
+                        s.set_server_identity(name, environment);
+                        Ok(())
+                    });
+                }
+            }
+            Message::RegisterRejected { reason } => {
+                log::error!("Server {} rejected registration: {}", url, reason);
+                return Err(EmnsError::Auth { reason });
+            }
+            Message::ConfigUpdate { sound_policy } => {
+                if let Some(policy) = sound_policy {
+                    log::info!("Server updated the sound policy: {:?}", policy);
+                    // Keep the previous policy rather than dropping the connection
+                    if let Err(e) = self.settings.update(|s| s.set_sound_policy(policy)) {
+                        log::error!("Rejected sound policy from server: {}", e);
+                    }
+                }
+            }
+            Message::Suppression { window } => {
+                if !window.targets(self.location.as_ref()) {
+                    log::info!(
+                        "Ignoring suppression window {} scheduled for another location",
+                        window.id
+                    );
+                } else if let Some(suppressions) = &self.suppressions {
+                    suppressions.insert(window);
+                }
+            }
+            Message::CancelSuppression { id } => {
+                if let Some(suppressions) = &self.suppressions {
+                    if !suppressions.cancel(id) {
+                        log::debug!("No suppression window {} to cancel", id);
+                    }
+                }
+            }
+            Message::ServerShutdown {
+                resume_expected_at,
+                reason,
+            } => {
+                log::info!(
+                    "Server {} is shutting down for maintenance until {} ({})",
+                    url,
+                    resume_expected_at,
+                    reason.as_deref().unwrap_or("no reason given")
+                );
+                self.maintenance.announce(url, resume_expected_at);
+            }
+            Message::UpdateAvailable { manifest } => match &self.updater {
+                Some(updater) => {
+                    log::info!("Server offers agent release {}", manifest.version);
+                    updater.offer(manifest);
+                }
+                None => log::debug!(
+                    "Ignoring agent release {}; self-update is disabled",
+                    manifest.version
+                ),
+            },
+            Message::SoundPack { pack } => match &self.sound_packs {
+                Some(packs) => {
+                    log::info!("Server offers sound pack {}", pack.version);
+                    packs.offer(pack);
+                }
+                None => log::debug!("Ignoring sound pack {}", pack.version),
+            },
+            Message::QuorumMet {
+                alert_id,
+                confirmed_by,
+            } => match &self.pending {
+                Some(handler) => {
+                    handler.quorum_met(alert_id, &confirmed_by).await;
+                }
+                // Session helpers hold the alerts when the agent runs as a broker
+                None => log::info!(
+                    "Quorum met for alert {} by {}",
+                    alert_id,
+                    confirmed_by.join(", ")
+                ),
+            },
+            Message::CancelAlert {
+                alert_id,
+                reason,
+                signature,
+            } => {
+                outcome = self.cancel_alert(alert_id, reason, signature).await;
+            }
+            Message::PendingSyncResult {
+                still_active,
+                cancelled,
+                expired,
+            } => {
+                log::info!(
+                    "Server reports {} pending alerts still active, {} cancelled, {} expired",
+                    still_active.len(),
+                    cancelled.len(),
+                    expired.len()
+                );
+                if let Some(handler) = &self.pending {
+                    let withdrawn = cancelled
+                        .into_iter()
+                        .map(|id| (id, Withdrawal::Cancelled))
+                        .chain(expired.into_iter().map(|id| (id, Withdrawal::Expired)));
+                    for (alert_id, withdrawal) in withdrawn {
+                        handler.withdraw(alert_id, withdrawal).await;
+                    }
+                }
+            }
+            Message::Ack { message_id: acked } => {
+                log::debug!("Server acknowledged message {}", acked);
+            }
+            Message::Nack {
+                message_id: refused,
+                reason,
+                detail,
+            } => {
+                log::warn!(
+                    "Server refused message {} ({:?}): {}",
+                    refused,
+                    reason,
+                    detail.as_deref().unwrap_or("no detail given")
+                );
+            }
+            _ => {
+                log::warn!("Unexpected message type from server");
+            }
+        }
+
+        self.answer(message_id, outcome);
+        Ok(())
+    }
+
+    /// Ack the server's envelope `message_id`, or nack it with the refusal;
+    /// nothing is sent for a message that came without an envelope
+    fn answer(&self, message_id: Option<Uuid>, outcome: std::result::Result<(), Refusal>) {
+        let Some(message_id) = message_id else {
+            return;
+        };
+        self.outbound.push(match outcome {
+            Ok(()) => OutboundMessage::Ack { message_id },
+            Err(refusal) => OutboundMessage::Nack {
+                message_id,
+                reason: refusal.reason,
+                detail: Some(refusal.detail),
+            },
+        });
+    }
+
+    /// Queue an alert meant for this machine that has not already arrived.
+    ///
+    /// An alert for elsewhere, or one already queued, counts as handled; one
+    /// that is invalid, badly signed or shed from a full queue is refused.
+    async fn queue_alert(
+        &self,
+        alert: Alert,
+        alert_queue: &AlertQueue,
+        trace: DeliveryTrace,
+    ) -> std::result::Result<(), Refusal> {
+        log::info!("Received alert: {} ({})", alert.id, alert.level.as_str());
+        if !alert.targets(self.location.as_ref()) {
+            log::info!("Ignoring alert {} targeted at another location", alert.id);
+            return Ok(());
+        }
+        // Checked before the id counts as seen, so an invalid or forged copy on
+        // one link cannot keep the genuine one on the other from being shown
+        if let Err(invalid) = alert.validate(chrono::Utc::now()) {
+            let refusal: Refusal = Refusal::new(NackReason::Invalid, invalid.to_string());
+            self.reject_invalid(invalid);
+            return Err(refusal);
+        }
+        if let Some(key) = &self.signing_key {
+            if let Err(e) = signing::verify(&alert, key) {
+                log::error!("Rejecting alert {}: {}", alert.id, e);
+                self.outbound.push(OutboundMessage::AlertError {
+                    client_id: self.client_id.clone(),
+                    reason: AlertErrorReason::BadSignature,
+                    alert_id: Some(alert.id),
+                    detail: Some(e.to_string()),
+                });
+                return Err(Refusal::new(NackReason::BadSignature, e.to_string()));
+            }
+        }
+        if self.standby_url.is_some() && !self.seen.lock().unwrap().insert(alert.id) {
+            log::debug!("Alert {} already received from the other server", alert.id);
+            return Ok(());
+        }
+        let mut alert: Alert = alert;
+        self.unseal(&mut alert);
+        let trace: DeliveryTrace = DeliveryTrace {
+            enqueued: Some(self.timings.now()),
+            ..trace
+        };
+        let alert_id: Uuid = alert.id;
+        let timestamp: chrono::DateTime<chrono::Utc> = alert.timestamp;
+        // Sheds the lowest-priority alert rather than blocking the read loop
+        let outcome: EnqueueOutcome = alert_queue.enqueue(alert, trace).await;
+        if let EnqueueOutcome::Shed(shed) = &outcome {
+            // Not received after all, so a resend from either server is taken,
+            // and the watermark stays before it so a replay brings it back
+            self.seen.lock().unwrap().forget(shed.alert_id);
+            if let Some(watermark) = &self.watermark {
+                watermark.reopen(shed.alert_id, shed.issued_at, shed.expires_at);
+            }
+        }
+        match outcome {
+            EnqueueOutcome::Shed(shed) if shed.alert_id == alert_id => Err(Refusal::new(
+                NackReason::QueueFull,
+                "the alert queue is full of alerts at least as urgent",
+            )),
+            _ => {
+                if let Some(watermark) = &self.watermark {
+                    watermark.advance(alert_id, timestamp);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Withdraw an alert the server cancelled, once its signature checks out
+    /// if alerts are signed, so nobody else can take a real alert down
+    async fn cancel_alert(
+        &self,
+        alert_id: Uuid,
+        reason: Option<String>,
+        signature: Option<String>,
+    ) -> std::result::Result<(), Refusal> {
+        if let Some(key) = &self.signing_key {
+            let verified =
+                signing::verify_cancel(alert_id, reason.as_deref(), signature.as_deref(), key);
+            if let Err(e) = verified {
+                log::error!("Ignoring cancellation of alert {}: {}", alert_id, e);
+                self.outbound.push(OutboundMessage::AlertError {
+                    client_id: self.client_id.clone(),
+                    reason: AlertErrorReason::BadSignature,
+                    alert_id: Some(alert_id),
+                    detail: Some(e.to_string()),
+                });
+                return Err(Refusal::new(NackReason::BadSignature, e.to_string()));
+            }
+        }
+        match &self.pending {
+            Some(handler) => {
+                handler.cancel(alert_id, reason.as_deref()).await;
+            }
+            None => log::info!(
+                "Server cancelled alert {}: {}",
+                alert_id,
+                reason.as_deref().unwrap_or("no reason given")
+            ),
+        }
+        Ok(())
+    }
+
+    /// Queue a batch's alerts oldest first, each stamped with `trace`; entries
+    /// that could not be read are logged and skipped. The batch is refused
+    /// for the first of its alerts that was, though the rest are still queued.
+    async fn queue_batch(
+        &self,
+        url: &str,
+        batch: AlertBatch,
+        alert_queue: &AlertQueue,
+        trace: DeliveryTrace,
+    ) -> std::result::Result<(), Refusal> {
+        log::info!(
+            "Received a batch of {} alerts from {}",
+            batch.alerts.len() + batch.unreadable.len(),
+            url
+        );
+        for unreadable in &batch.unreadable {
+            log::warn!(
+                "Skipping unreadable alert in batch from {}: {}",
+                url,
+                unreadable
+            );
+        }
+        let mut alerts: Vec<Alert> = batch.alerts;
+        alerts.sort_by_key(|alert| alert.timestamp);
+        let mut outcome: std::result::Result<(), Refusal> = Ok(());
+        for alert in alerts {
+            let alert_id: Uuid = alert.id;
+            if let Err(refusal) = self.queue_alert(alert, alert_queue, trace).await {
+                if outcome.is_ok() {
+                    outcome = Err(Refusal {
+                        detail: format!("alert {}: {}", alert_id, refusal.detail),
+                        ..refusal
+                    });
+                }
+            }
+        }
+        outcome
+    }
+
+    /// Log what is wrong with an alert, field by field, and report it to the
+    /// server instead of showing it
+    fn reject_invalid(&self, invalid: InvalidAlert) {
+        let alert_id: String = invalid
+            .alert_id
+            .map_or_else(|| "with no readable id".to_string(), |id| id.to_string());
+        log::warn!(
+            "Rejecting invalid alert {}: {} problem(s): {}",
+            alert_id,
+            invalid.problems.len(),
+            invalid
+        );
+        self.outbound.push(OutboundMessage::AlertError {
+            client_id: self.client_id.clone(),
+            reason: AlertErrorReason::Invalid,
+            alert_id: invalid.alert_id,
+            detail: Some(invalid.to_string()),
+        });
+    }
+
+    /// Put a sealed alert's real title and message in place of the placeholders.
+    ///
+    /// One that cannot be opened is still shown, with a generic text, and
+    /// reported to the server. The envelope stays on the alert either way, so
+    /// its text is never sent back in an offline bundle.
+    fn unseal(&self, alert: &mut Alert) {
+        let Some(body) = &alert.sealed else {
+            return;
+        };
+        let opened = match &self.alert_key {
+            Some(key) => key.open(alert.id, body),
+            None => Err(EmnsError::protocol(
+                "this agent has no key for sealed alerts",
+            )),
+        };
+        match opened {
+            Ok(content) => {
+                alert.title = content.title;
+                alert.message = content.message;
+            }
+            Err(e) => {
+                log::error!("Sealed alert {} could not be read: {}", alert.id, e);
+                alert.title = sealed::UNREADABLE_TITLE.to_string();
+                alert.message = sealed::UNREADABLE_MESSAGE.to_string();
+                self.outbound.push(OutboundMessage::AlertError {
+                    client_id: self.client_id.clone(),
+                    reason: AlertErrorReason::Undecryptable,
+                    alert_id: Some(alert.id),
+                    detail: Some(e.to_string()),
+                });
+            }
+        }
+    }
+}
+
+/// Ask the standby loop for its connection, if it has one
+async fn take_standby(standby: Option<&StandbyControl>) -> Option<(String, Connection)> {
+    let (responder, connection) = oneshot::channel::<(String, Connection)>();
+    standby?.handover.send(responder).await.ok()?;
+    connection.await.ok()
+}
+
+/// Run `future` while turning down handover requests, as there is no standby connection
+async fn without_standby<F: Future>(
+    future: F,
+    handover_rx: &mut mpsc::Receiver<Handover>,
+    cancel: &CancellationToken,
+) -> Option<F::Output> {
+    tokio::pin!(future);
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return None,
+            Some(declined) = handover_rx.recv() => drop(declined),
+            output = &mut future => return Some(output),
+        }
+    }
+}
+
+/// How messages are framed on one connection, as the server's registration
+/// ack chose
+#[derive(Debug, Clone, Copy, Default)]
+struct Wire {
+    encoding: Encoding,
+    /// Messages go in envelopes both ways, and the server's are answered
+    envelopes: bool,
+    /// Timeouts go as [`Message::ConfirmationTimeout`] instead of as confirmations
+    confirmation_timeouts: bool,
+}
+
+impl Wire {
+    /// `message` as this connection's server reads it: a timed-out
+    /// confirmation becomes a [`Message::ConfirmationTimeout`] if the server
+    /// asked for those
+    fn outgoing<'a>(&self, message: &'a Message) -> Cow<'a, Message> {
+        match message {
+            Message::Confirmation { confirmation } if self.confirmation_timeouts => {
+                let idle: bool = match confirmation.reason {
+                    ConfirmationReason::TimedOut => false,
+                    ConfirmationReason::TimedOutIdle => true,
+                    _ => return Cow::Borrowed(message),
+                };
+                Cow::Owned(Message::ConfirmationTimeout {
+                    alert_id: confirmation.alert_id,
+                    client_id: confirmation.client_id.clone(),
+                    timed_out_at: confirmation.confirmed_at,
+                    idle,
+                })
+            }
+            _ => Cow::Borrowed(message),
+        }
+    }
+}
+
+/// Why a server message was not handled, as told to the server in a nack
+#[derive(Debug)]
+struct Refusal {
+    reason: NackReason,
+    detail: String,
+}
+
+impl Refusal {
+    fn new(reason: NackReason, detail: impl Into<String>) -> Self {
+        Self {
+            reason,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Heartbeats sent on one connection that the server has yet to acknowledge
+#[derive(Debug, Default)]
+struct HeartbeatAcks {
+    /// `seq` of the last heartbeat sent
+    last_seq: u64,
+    unacked: u32,
+    /// The server has acknowledged a heartbeat, so it is expected to go on
+    /// doing so; servers from before acknowledgements never do
+    expected: bool,
+}
+
+impl HeartbeatAcks {
+    /// Count a heartbeat going out, returning its `seq`
+    fn sent(&mut self) -> u64 {
+        self.last_seq += 1;
+        self.unacked += 1;
+        self.last_seq
+    }
+
+    fn acked(&mut self) {
+        self.unacked = 0;
+        self.expected = true;
+    }
+
+    /// Whether `limit` heartbeats in a row have gone unacknowledged by a server that acknowledges them
+    fn missed(&self, limit: u32) -> bool {
+        self.expected && limit > 0 && self.unacked >= limit
+    }
+}
+
+/// Notices a connection the server has stopped answering on, such as one
+/// left half-open when a firewall forgets it
+struct Liveness {
+    timeout: Duration,
+    last_heard: Instant,
+    /// Whether the server has been pinged since it was last heard from
+    pinged: bool,
+}
+
+impl Liveness {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_heard: Instant::now(),
+            pinged: false,
+        }
+    }
+
+    /// Any frame arrived from the server
+    fn heard(&mut self) {
+        self.last_heard = Instant::now();
+        self.pinged = false;
+    }
+
+    /// When to act next: ping halfway through the timeout, give up at its end
+    fn deadline(&self) -> Instant {
+        if self.pinged {
+            self.last_heard + self.timeout
+        } else {
+            self.last_heard + self.timeout / 2
+        }
+    }
+
+    /// The deadline passed: `true` if the server is to be pinged, `false` once it is taken for dead
+    fn lapse(&mut self) -> bool {
+        !std::mem::replace(&mut self.pinged, true)
+    }
+
+    fn dead(&self, url: &str) -> EmnsError {
+        EmnsError::connection(
+            url,
+            format!(
+                "nothing heard from the server in {} seconds",
+                self.timeout.as_secs()
+            ),
+        )
+    }
+}
+
+/// The start of an unreadable frame, for the log and the server
+fn excerpt(frame: &Frame) -> String {
+    match frame {
+        Frame::Text(text) if text.chars().count() > UNREADABLE_EXCERPT_CHARS => {
+            let kept: String = text.chars().take(UNREADABLE_EXCERPT_CHARS).collect();
+            format!("{}... ({} bytes)", kept, text.len())
+        }
+        Frame::Text(text) => text.clone(),
+        Frame::Binary(bytes) => {
+            let kept: Vec<String> = bytes
+                .iter()
+                .take(UNREADABLE_EXCERPT_CHARS / 2)
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            format!("binary {} ({} bytes)", kept.concat(), bytes.len())
+        }
+        other => format!("{:?}", other),
+    }
+}
+
+/// Timer whose first tick is one full period from now
+fn rearm(period: Duration) -> Interval {
+    interval_at(Instant::now() + period, period)
+}
+
+/// Read a server message: JSON from a text frame, MessagePack from a binary one
+/// Read an [`Envelope`] from a connection whose server asked for envelopes
+fn decode_envelope(frame: &Frame) -> Result<Envelope> {
+    match frame {
+        Frame::Text(text) => serde_json::from_str(text)
+            .map_err(|e| EmnsError::protocol(format!("Failed to parse server envelope: {}", e))),
+        Frame::Binary(bytes) => Envelope::from_msgpack(bytes).map_err(|e| {
+            EmnsError::protocol(format!("Failed to parse binary server envelope: {}", e))
+        }),
+        other => Err(EmnsError::protocol(format!(
+            "Not a message frame: {:?}",
+            other
+        ))),
+    }
+}
+
+fn decode(frame: &Frame) -> Result<Message> {
+    match frame {
+        Frame::Text(text) => serde_json::from_str(text)
+            .map_err(|e| EmnsError::protocol(format!("Failed to parse server message: {}", e))),
+        Frame::Binary(bytes) => Message::from_msgpack(bytes).map_err(|e| {
+            EmnsError::protocol(format!("Failed to parse binary server message: {}", e))
+        }),
+        other => Err(EmnsError::protocol(format!(
+            "Not a message frame: {:?}",
+            other
+        ))),
+    }
+}
+
+/// Get the hostname of the machine
+pub fn get_hostname() -> String {
+    hostname::get()
+        .ok()
+        .and_then(|h| h.into_string().ok())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Get the current username
+pub fn get_username() -> String {
     std::env::var("USERNAME")
         .or_else(|_| std::env::var("USER"))
         .unwrap_or_else(|_| "unknown".to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+    use crate::messages::{
+        AgentStatus, AlertErrorReason, AlertLevel, Confirmation, ConfirmationReason,
+        ConfirmationResponse, LocationField, ReceivedVia, SoundPolicy, SuppressionWindow,
+    };
+    use crate::test_support::{alert, ManualClock};
+    use crate::transport::memory::{MemoryListener, MemoryPeer, MemoryTransport};
+    use crate::transport::CloseCode;
+    use crate::watermark::WATERMARK_FILE;
+    use tokio::task::JoinHandle;
+
+    const URL: &str = "ws://server.test/ws";
+
+    /// A client running against an in-memory server
+    struct Harness {
+        transport: Arc<MemoryTransport>,
+        listener: MemoryListener,
+        settings: SharedSettings,
+        queue: Arc<AlertQueue>,
+        outbound: Arc<OutboundQueue>,
+        suppressions: Arc<SuppressionWindows>,
+        maintenance: Arc<MaintenanceWindow>,
+        cancel: CancellationToken,
+        run: JoinHandle<Result<()>>,
+    }
+
+    impl Harness {
+        fn start(queue_capacity: usize) -> Self {
+            Self::start_at(queue_capacity, None, None)
+        }
+
+        fn start_at(
+            queue_capacity: usize,
+            location: Option<Location>,
+            standby: Option<&str>,
+        ) -> Self {
+            Self::start_with(queue_capacity, location, standby, |client| client)
+        }
+
+        /// Start a client further set up by `configure`
+        fn start_with(
+            queue_capacity: usize,
+            location: Option<Location>,
+            standby: Option<&str>,
+            configure: impl FnOnce(WebSocketClient) -> WebSocketClient,
+        ) -> Self {
+            let settings: SharedSettings = SharedSettings::default();
+            let (transport, listener) = MemoryTransport::new();
+            let transport: Arc<MemoryTransport> = Arc::new(transport);
+            let queue: Arc<AlertQueue> = Arc::new(AlertQueue::new(queue_capacity));
+            let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+            let suppressions: Arc<SuppressionWindows> = Arc::new(SuppressionWindows::new());
+            let maintenance: Arc<MaintenanceWindow> = Arc::new(MaintenanceWindow::new());
+            let status: Arc<StatusCollector> = Arc::new(
+                StatusCollector::new("test-client", queue.clone(), outbound.clone())
+                    .with_maintenance(maintenance.clone()),
+            );
+            let client: WebSocketClient = WebSocketClient::new(
+                URL.to_string(),
+                "test-client".to_string(),
+                "test-host".to_string(),
+            )
+            .with_transport(transport.clone())
+            .with_outbound_queue(outbound.clone())
+            .with_status(status)
+            .with_settings(settings.clone())
+            .with_location(location)
+            .with_standby(standby.map(String::from))
+            .with_suppressions(suppressions.clone())
+            .with_maintenance(maintenance.clone());
+            let client: WebSocketClient = configure(client);
+
+            let cancel: CancellationToken = CancellationToken::new();
+            let run = tokio::spawn({
+                let queue: Arc<AlertQueue> = queue.clone();
+                let cancel: CancellationToken = cancel.clone();
+                async move { client.run(queue, cancel).await }
+            });
+
+            Self {
+                transport,
+                listener,
+                settings,
+                queue,
+                outbound,
+                suppressions,
+                maintenance,
+                cancel,
+                run,
+            }
+        }
+
+        /// Accept the next connection, consuming and acknowledging its registration
+        async fn accept(&mut self) -> MemoryPeer {
+            let mut peer: MemoryPeer = self.listener.accept().await.expect("client connected");
+            match peer.recv().await {
+                Some(Message::Register { client_id, .. }) => assert_eq!(client_id, "test-client"),
+                other => panic!("expected register, got {:?}", other),
+            }
+            peer.ack_registration();
+            peer
+        }
+
+        async fn stop(self) {
+            self.cancel.cancel();
+            self.run.await.unwrap().unwrap();
+        }
+    }
+
+    /// Next message from the client that is not a heartbeat or status report
+    async fn recv_significant(peer: &mut MemoryPeer) -> Option<Message> {
+        loop {
+            match peer.recv().await? {
+                Message::Heartbeat { .. } | Message::Status { .. } => continue,
+                other => return Some(other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_garbage_is_protocol_error() {
+        for frame in [
+            Frame::Text("{not json".to_string()),
+            Frame::Binary(vec![0xc1, 0x00]),
+        ] {
+            let err: EmnsError = decode(&frame).unwrap_err();
+            assert!(matches!(err, EmnsError::Protocol { .. }));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_registers_and_dispatches_alerts() {
+        let mut harness: Harness = Harness::start(10);
+        let peer: MemoryPeer = harness.accept().await;
+
+        let sent = alert(AlertLevel::Critical, false);
+        peer.send(&Message::Alert {
+            alert: sent.clone(),
+        });
+        assert_eq!(harness.queue.recv().await.id, sent.id);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_is_dispatched_oldest_first_past_unreadable_entries() {
+        let mut harness: Harness = Harness::start(10);
+        let peer: MemoryPeer = harness.accept().await;
+
+        let now: chrono::DateTime<chrono::Utc> = chrono::Utc::now();
+        // Of equal rank, so the queue hands them out in the order queued
+        let mut entries: Vec<serde_json::Value> = [1, 3, 2]
+            .into_iter()
+            .map(|minutes_ago| {
+                let mut alert: Alert = alert(AlertLevel::Warning, false);
+                alert.timestamp = now - chrono::TimeDelta::minutes(minutes_ago);
+                serde_json::to_value(alert).unwrap()
+            })
+            .collect();
+        entries.insert(
+            1,
+            serde_json::json!({ "id": uuid::Uuid::new_v4(), "level": "catastrophic" }),
+        );
+        peer.send_frame(Frame::Text(
+            serde_json::json!({ "type": "alert_batch", "alerts": entries }).to_string(),
+        ));
+
+        let mut minutes_ago: Vec<i64> = Vec::new();
+        for _ in 0..3 {
+            minutes_ago.push((now - harness.queue.recv().await.timestamp).num_minutes());
+        }
+        assert_eq!(minutes_ago, [3, 2, 1]);
+        assert_eq!(harness.queue.depth(), 0);
+
+        // Still reading the same connection
+        let after = alert(AlertLevel::Critical, false);
+        peer.send(&Message::Alert {
+            alert: after.clone(),
+        });
+        assert_eq!(harness.queue.recv().await.id, after.id);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeats_follow_interval() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+
+        let mut ticks: Vec<Instant> = Vec::new();
+        while ticks.len() < 3 {
+            if let Some(Message::Heartbeat { .. }) = peer.recv().await {
+                ticks.push(Instant::now());
+            }
+        }
+        let period: Duration = harness.settings.snapshot().heartbeat_interval();
+        assert_eq!(ticks[1] - ticks[0], period);
+        assert_eq!(ticks[2] - ticks[1], period);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat_rearms_when_settings_change() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+        while !matches!(peer.recv().await, Some(Message::Heartbeat { .. })) {}
+
+        let changed_at: Instant = Instant::now();
+        harness
+            .settings
+            .update(|s| s.set_heartbeat_interval(Duration::from_secs(2)))
+            .unwrap();
+        while !matches!(peer.recv().await, Some(Message::Heartbeat { .. })) {}
+        assert_eq!(changed_at.elapsed(), Duration::from_secs(2));
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnects_after_refused_connection() {
+        let mut harness: Harness = Harness::start(10);
+        harness.transport.refuse_next("connection refused");
+        let started: Instant = Instant::now();
+
+        harness.accept().await;
+        assert_eq!(
+            started.elapsed(),
+            harness.settings.snapshot().reconnect_delay()
+        );
+
+        harness.stop().await;
+    }
+
+    /// Accept the next connection, returning it with the server URL its registration names
+    async fn accept_registered(harness: &mut Harness) -> (MemoryPeer, Option<String>) {
+        let mut peer: MemoryPeer = harness.listener.accept().await.expect("client connected");
+        match peer.recv().await {
+            Some(Message::Register { server_url, .. }) => {
+                peer.ack_registration();
+                (peer, server_url)
+            }
+            other => panic!("expected register, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unreachable_primary_falls_through_the_list() {
+        const BACKUPS: [&str; 2] = ["ws://backup-1.test/ws", "ws://backup-2.test/ws"];
+        let mut harness: Harness = Harness::start_with(10, None, None, |client| {
+            client.with_fallbacks(BACKUPS.map(String::from).to_vec())
+        });
+        harness.transport.refuse_next("connection refused");
+        harness.transport.refuse_next("connection refused");
+        let started: Instant = Instant::now();
+
+        // Straight on to the next server, without waiting out the reconnect delay
+        let (peer, server_url) = accept_registered(&mut harness).await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
+        assert_eq!(peer.url(), BACKUPS[1]);
+        assert_eq!(server_url.as_deref(), Some(BACKUPS[1]));
+
+        // The next cycle starts over from the primary
+        drop(peer);
+        let (peer, server_url) = accept_registered(&mut harness).await;
+        assert_eq!(peer.url(), URL);
+        assert_eq!(server_url.as_deref(), Some(URL));
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_moves_back_to_primary_once_it_answers() {
+        const BACKUP: &str = "ws://backup.test/ws";
+        let retry: Duration = Duration::from_secs(30);
+        let mut harness: Harness = Harness::start_with(10, None, None, |client| {
+            client
+                .with_fallbacks(vec![BACKUP.to_string()])
+                .with_primary_retry(retry)
+        });
+        harness.transport.refuse_next("connection refused");
+        let (mut backup, _) = accept_registered(&mut harness).await;
+        assert_eq!(backup.url(), BACKUP);
+
+        // Still down at the first retry, back at the second
+        let connected: Instant = Instant::now();
+        harness.transport.refuse_next("connection refused");
+        let (primary, server_url) = accept_registered(&mut harness).await;
+        assert_eq!(connected.elapsed(), retry * 2);
+        assert_eq!(primary.url(), URL);
+        assert_eq!(server_url.as_deref(), Some(URL));
+
+        // The backup connection is let go
+        while backup.recv_frame().await.is_some() {}
+
+        // Once on the primary, it stays there
+        let later = tokio::time::timeout(retry * 2, harness.listener.accept()).await;
+        assert!(later.is_err());
+        drop(primary);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rejected_registration_waits_the_longest_delay() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+
+        peer.send(&Message::RegisterRejected {
+            reason: "invalid token".to_string(),
+        });
+        let rejected: Instant = Instant::now();
+        while peer.recv().await.is_some() {}
+
+        harness.accept().await;
+        assert_eq!(rejected.elapsed(), BackoffConfig::default().max_delay);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_registration_after_a_rejection_restores_the_first_delay() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+        peer.send(&Message::RegisterRejected {
+            reason: "invalid token".to_string(),
+        });
+        while peer.recv().await.is_some() {}
+
+        // Accepted this time, but dropped long before the backoff would reset
+        let peer: MemoryPeer = harness.accept().await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        drop(peer);
+        let dropped: Instant = Instant::now();
+        harness.accept().await;
+        assert_eq!(
+            dropped.elapsed(),
+            AgentSettings::default().reconnect_delay()
+        );
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnect_backoff_grows_and_resets() {
+        let backoff: BackoffConfig = BackoffConfig {
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(15),
+            ..BackoffConfig::default()
+        };
+        let reset_after: Duration = backoff.reset_after;
+        let mut harness: Harness = Harness::start_with(10, None, None, |client| {
+            client.with_reconnect_backoff(backoff)
+        });
+        for _ in 0..4 {
+            harness.transport.refuse_next("connection refused");
+        }
+
+        let started: Instant = Instant::now();
+        let peer: MemoryPeer = harness.accept().await;
+        // Doubling from the settings' 5 seconds, held at the cap
+        assert_eq!(started.elapsed(), Duration::from_secs(5 + 10 + 15 + 15));
+
+        // A connection that stays up starts the wait over
+        tokio::time::sleep(reset_after).await;
+        harness.transport.refuse_next("connection refused");
+        drop(peer);
+        let dropped: Instant = Instant::now();
+        harness.accept().await;
+        assert_eq!(dropped.elapsed(), Duration::from_secs(5 + 10));
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_answers_server_pings() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+
+        peer.send_frame(Frame::Ping(b"are you there".to_vec()));
+        loop {
+            match peer.recv_frame().await {
+                Some(Frame::Pong(data)) => {
+                    assert_eq!(data, b"are you there");
+                    break;
+                }
+                Some(Frame::Text(_)) => continue,
+                other => panic!("expected pong, got {:?}", other),
+            }
+        }
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_server_is_pinged_then_dropped() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+        let connected: Instant = Instant::now();
+
+        // Heartbeats go out, but nothing comes back
+        loop {
+            match peer.recv_frame().await {
+                Some(Frame::Ping(_)) => break,
+                Some(Frame::Text(_)) => continue,
+                other => panic!("expected ping, got {:?}", other),
+            }
+        }
+        assert_eq!(connected.elapsed(), DEFAULT_SERVER_TIMEOUT / 2);
+        while peer.recv_frame().await.is_some() {}
+        assert_eq!(connected.elapsed(), DEFAULT_SERVER_TIMEOUT);
+
+        harness.accept().await;
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pong_keeps_a_quiet_connection() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+        let connected: Instant = Instant::now();
+
+        // Each ping comes over the same connection, well past the timeout
+        for _ in 0..3 {
+            loop {
+                match peer.recv_frame().await {
+                    Some(Frame::Ping(data)) => {
+                        peer.send_frame(Frame::Pong(data));
+                        break;
+                    }
+                    Some(Frame::Text(_)) => continue,
+                    other => panic!("expected ping, got {:?}", other),
+                }
+            }
+        }
+
+        assert_eq!(connected.elapsed(), DEFAULT_SERVER_TIMEOUT / 2 * 3);
+
+        harness.stop().await;
+    }
+
+    /// Next heartbeat from the client, answering pings on the way
+    async fn next_heartbeat(peer: &mut MemoryPeer) -> HeartbeatStats {
+        loop {
+            if let Some(Message::Heartbeat { stats }) = peer.recv().await {
+                return stats;
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeats_identify_the_agent_and_count_up() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+
+        let first: HeartbeatStats = next_heartbeat(&mut peer).await;
+        let second: HeartbeatStats = next_heartbeat(&mut peer).await;
+        assert_eq!(first.client_id.as_deref(), Some("test-client"));
+        assert_eq!(
+            first.agent_version.as_deref(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!((first.seq, second.seq), (Some(1), Some(2)));
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_registration_ack_time_measures_clock_skew() {
+        let local: Arc<ManualClock> = Arc::new(ManualClock::new());
+        let clock: Arc<ServerClock> = Arc::new(ServerClock::new(local.clone()));
+        let mut harness: Harness = Harness::start_with(10, None, None, {
+            let clock: Arc<ServerClock> = clock.clone();
+            |client| client.with_server_clock(clock)
+        });
+        let mut peer: MemoryPeer = harness.listener.accept().await.expect("client connected");
+        assert!(matches!(peer.recv().await, Some(Message::Register { .. })));
+
+        // This machine's clock runs 90 seconds fast
+        let server_time: chrono::DateTime<chrono::Utc> =
+            local.now() - chrono::TimeDelta::seconds(90);
+        peer.send(&Message::RegisterAck {
+            server_name: None,
+            environment: None,
+            encoding: None,
+            server_version: None,
+            protocol_version: None,
+            server_time: Some(server_time),
+            envelopes: false,
+            confirmation_timeouts: false,
+        });
+
+        let stats: HeartbeatStats = next_heartbeat(&mut peer).await;
+        assert_eq!(stats.clock_skew_ms, Some(90_000));
+        assert_eq!(clock.now(), local.now() - chrono::TimeDelta::seconds(90));
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unacknowledged_heartbeats_force_a_reconnect() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+        let period: Duration = harness.settings.snapshot().heartbeat_interval();
+
+        let first: HeartbeatStats = next_heartbeat(&mut peer).await;
+        peer.send(&Message::HeartbeatAck { seq: first.seq });
+        let acked: Instant = Instant::now();
+
+        // Three more go unanswered, then the connection is dropped at the next heartbeat
+        for _ in 0..DEFAULT_HEARTBEAT_MISSED_ACKS {
+            next_heartbeat(&mut peer).await;
+        }
+        while peer.recv().await.is_some() {}
+        assert_eq!(
+            acked.elapsed(),
+            period * (DEFAULT_HEARTBEAT_MISSED_ACKS + 1)
+        );
+
+        harness.accept().await;
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_server_that_never_acknowledges_keeps_its_connection() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+
+        for expected in 1..=(DEFAULT_HEARTBEAT_MISSED_ACKS as u64 + 3) {
+            assert_eq!(next_heartbeat(&mut peer).await.seq, Some(expected));
+        }
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_garbage_is_reported_and_skipped() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+
+        peer.send_frame(Frame::Text("{not json".to_string()));
+        match recv_significant(&mut peer).await {
+            Some(Message::Error { context, detail }) => {
+                assert_eq!(context, "{not json");
+                assert!(detail.contains("Failed to parse server message"));
+            }
+            other => panic!("expected error report, got {:?}", other),
+        }
+
+        // Still reading the same connection
+        let sent = alert(AlertLevel::Critical, false);
+        peer.send(&Message::Alert {
+            alert: sent.clone(),
+        });
+        assert_eq!(harness.queue.recv().await.id, sent.id);
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_alerts_not_signed_with_the_key_are_rejected() {
+        let key: SigningKey = SigningKey::new("site-secret");
+        let mut harness: Harness = Harness::start_with(10, None, None, {
+            let key: SigningKey = key.clone();
+            |client| client.with_signing_key(Some(key))
+        });
+        let mut peer: MemoryPeer = harness.accept().await;
+
+        let unsigned: Alert = alert(AlertLevel::Critical, false);
+        let mut tampered: Alert = alert(AlertLevel::Critical, false);
+        tampered.signature = Some(signing::sign(&tampered, &key));
+        tampered.message = "Evacuate to the parking garage".to_string();
+        let mut signed: Alert = alert(AlertLevel::Critical, false);
+        signed.signature = Some(signing::sign(&signed, &key));
+        for sent in [&unsigned, &tampered, &signed] {
+            peer.send(&Message::Alert {
+                alert: sent.clone(),
+            });
+        }
+
+        for (rejected, problem) in [
+            (&unsigned, "alert is not signed"),
+            (&tampered, "signature does not match"),
+        ] {
+            match recv_significant(&mut peer).await {
+                Some(Message::AlertError {
+                    reason,
+                    alert_id,
+                    detail,
+                    ..
+                }) => {
+                    assert_eq!(reason, AlertErrorReason::BadSignature);
+                    assert_eq!(alert_id, Some(rejected.id));
+                    assert!(detail.unwrap().contains(problem));
+                }
+                other => panic!("expected alert error, got {:?}", other),
+            }
+        }
+        // Only the signed alert is queued
+        assert_eq!(harness.queue.recv().await.id, signed.id);
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancellations_not_signed_with_the_key_are_ignored() {
+        let key: SigningKey = SigningKey::new("site-secret");
+        let mut harness: Harness = Harness::start_with(10, None, None, {
+            let key: SigningKey = key.clone();
+            |client| client.with_signing_key(Some(key))
+        });
+        let mut peer: MemoryPeer = harness.accept().await;
+
+        let alert_id: Uuid = Uuid::new_v4();
+        let reason: Option<String> = Some("sent in error".to_string());
+        let signature: String = signing::sign_cancel(alert_id, reason.as_deref(), &key);
+        let unsigned = Message::CancelAlert {
+            alert_id,
+            reason: reason.clone(),
+            signature: None,
+        };
+        let reworded = Message::CancelAlert {
+            alert_id,
+            reason: Some("drill over".to_string()),
+            signature: Some(signature.clone()),
+        };
+        let signed = Message::CancelAlert {
+            alert_id,
+            reason,
+            signature: Some(signature),
+        };
+        // The signed one is taken without complaint, so the next error is the alert's
+        let marker: Alert = alert(AlertLevel::Info, false);
+        for sent in [
+            unsigned,
+            reworded,
+            signed,
+            Message::Alert {
+                alert: marker.clone(),
+            },
+        ] {
+            peer.send(&sent);
+        }
+
+        for (rejected, problem) in [
+            (alert_id, "cancellation is not signed"),
+            (alert_id, "signature does not match"),
+            (marker.id, "alert is not signed"),
+        ] {
+            match recv_significant(&mut peer).await {
+                Some(Message::AlertError {
+                    reason,
+                    alert_id,
+                    detail,
+                    ..
+                }) => {
+                    assert_eq!(reason, AlertErrorReason::BadSignature);
+                    assert_eq!(alert_id, Some(rejected));
+                    assert!(detail.unwrap().contains(problem));
+                }
+                other => panic!("expected alert error, got {:?}", other),
+            }
+        }
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_invalid_alerts_are_reported_and_skipped() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+
+        // More than enough unparseable ones to reconnect, were they unreadable
+        let mut garbled: serde_json::Value = serde_json::to_value(Message::Alert {
+            alert: alert(AlertLevel::Critical, false),
+        })
+        .unwrap();
+        garbled["alert"]["title"] = "".into();
+        garbled["alert"]["level"] = "sev1".into();
+        for _ in 0..DEFAULT_MAX_UNREADABLE_MESSAGES {
+            peer.send_frame(Frame::Text(garbled.to_string()));
+        }
+        let mut escaping: Alert = alert(AlertLevel::Warning, false);
+        escaping.sound_file = Some("..\\..\\evil.wav".to_string());
+        peer.send(&Message::Alert {
+            alert: escaping.clone(),
+        });
+
+        for _ in 0..DEFAULT_MAX_UNREADABLE_MESSAGES {
+            match recv_significant(&mut peer).await {
+                Some(Message::AlertError {
+                    reason,
+                    alert_id,
+                    detail,
+                    ..
+                }) => {
+                    assert_eq!(reason, AlertErrorReason::Invalid);
+                    assert_eq!(
+                        alert_id.map(|id| id.to_string()).as_deref(),
+                        garbled["alert"]["id"].as_str()
+                    );
+                    assert_eq!(
+                        detail.as_deref(),
+                        Some(
+                            "title: empty; level: unknown level \"sev1\", \
+                             expected info, warning, critical or emergency"
+                        )
+                    );
+                }
+                other => panic!("expected alert error, got {:?}", other),
+            }
+        }
+        match recv_significant(&mut peer).await {
+            Some(Message::AlertError {
+                reason, alert_id, ..
+            }) => {
+                assert_eq!(reason, AlertErrorReason::Invalid);
+                assert_eq!(alert_id, Some(escaping.id));
+            }
+            other => panic!("expected alert error, got {:?}", other),
+        }
+
+        // Still reading the same connection, and nothing invalid was queued
+        let sent = alert(AlertLevel::Critical, false);
+        peer.send(&Message::Alert {
+            alert: sent.clone(),
+        });
+        assert_eq!(harness.queue.recv().await.id, sent.id);
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_repeated_garbage_reconnects() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+
+        for _ in 0..DEFAULT_MAX_UNREADABLE_MESSAGES {
+            peer.send_frame(Frame::Binary(vec![0xc1]));
+        }
+        let mut reported: u32 = 0;
+        while let Some(message) = recv_significant(&mut peer).await {
+            assert!(matches!(message, Message::Error { .. }));
+            reported += 1;
+        }
+
+        // Any report the dropped connection did not carry follows on the next
+        let mut peer: MemoryPeer = harness.accept().await;
+        while reported < DEFAULT_MAX_UNREADABLE_MESSAGES {
+            match recv_significant(&mut peer).await {
+                Some(Message::Error { context, .. }) => assert_eq!(context, "binary c1 (1 bytes)"),
+                Some(Message::PendingSync { .. }) => continue,
+                other => panic!("expected error report, got {:?}", other),
+            }
+            reported += 1;
+        }
+        harness.stop().await;
+    }
+
+    #[test]
+    fn test_unreadable_excerpt_is_truncated() {
+        let long: String = "x".repeat(UNREADABLE_EXCERPT_CHARS * 2);
+        let kept: String = excerpt(&Frame::Text(long));
+        assert!(kept.starts_with(&"x".repeat(UNREADABLE_EXCERPT_CHARS)));
+        assert!(kept.ends_with("x... (400 bytes)"));
+        assert_eq!(
+            excerpt(&Frame::Binary(vec![0xde, 0xad])),
+            "binary dead (2 bytes)"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnects_after_mid_frame_drop() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+
+        peer.fail("connection reset while reading frame");
+        assert!(recv_significant(&mut peer).await.is_none());
+
+        harness.accept().await;
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnects_after_close_codes() {
+        let mut harness: Harness = Harness::start(10);
+        for code in [CloseCode::Normal, CloseCode::Away, CloseCode::Policy] {
+            let mut peer: MemoryPeer = harness.accept().await;
+            peer.close(code, "test");
+            assert!(recv_significant(&mut peer).await.is_none());
+        }
+        harness.accept().await;
+        harness.stop().await;
+    }
+
+    fn confirmation(alert_id: uuid::Uuid) -> Confirmation {
+        Confirmation {
+            alert_id,
+            client_id: "test-client".to_string(),
+            confirmed_at: chrono::Utc::now(),
+            hostname: "test-host".to_string(),
+            username: "tester".to_string(),
+            operator_id: None,
+            reason: ConfirmationReason::User,
+            method: None,
+            user_idle_secs: None,
+            response_id: None,
+            response: ConfirmationResponse::Acknowledged,
+            received_via: ReceivedVia::WebSocket,
+            shown_at: None,
+            response_latency_ms: None,
+            is_preview: false,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeouts_are_reported_as_the_server_asked() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.listener.accept().await.expect("client connected");
+        match peer.recv().await {
+            Some(Message::Register {
+                confirmation_timeouts,
+                ..
+            }) => assert!(confirmation_timeouts),
+            other => panic!("expected register, got {:?}", other),
+        }
+        peer.send(&Message::RegisterAck {
+            server_name: None,
+            environment: None,
+            encoding: None,
+            server_version: None,
+            protocol_version: None,
+            server_time: None,
+            envelopes: false,
+            confirmation_timeouts: true,
+        });
+
+        let (confirmed, timed_out, idle) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        for (alert_id, reason) in [
+            (confirmed, ConfirmationReason::User),
+            (timed_out, ConfirmationReason::TimedOut),
+            (idle, ConfirmationReason::TimedOutIdle),
+        ] {
+            harness
+                .outbound
+                .push(OutboundMessage::Confirmation(Confirmation {
+                    reason,
+                    ..confirmation(alert_id)
+                }));
+            let sent: Option<Message> = recv_significant(&mut peer).await;
+            match (reason, sent) {
+                (ConfirmationReason::User, Some(Message::Confirmation { confirmation })) => {
+                    assert_eq!(confirmation.alert_id, alert_id)
+                }
+                (
+                    _,
+                    Some(Message::ConfirmationTimeout {
+                        alert_id: sent_id,
+                        client_id,
+                        idle,
+                        ..
+                    }),
+                ) => {
+                    assert_eq!(sent_id, alert_id);
+                    assert_eq!(client_id, "test-client");
+                    assert_eq!(idle, reason == ConfirmationReason::TimedOutIdle);
+                }
+                (_, other) => panic!("unexpected message {:?} for {:?}", other, reason),
+            }
+        }
+        harness.stop().await;
+
+        // A server that did not ask gets the confirmation it always has
+        let timeout: Message = Message::Confirmation {
+            confirmation: Confirmation {
+                reason: ConfirmationReason::TimedOut,
+                ..confirmation(timed_out)
+            },
+        };
+        assert!(matches!(
+            *Wire::default().outgoing(&timeout),
+            Message::Confirmation { .. }
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_outbound_queue_drains_by_priority_after_reconnect() {
+        let mut harness: Harness = Harness::start(10);
+        harness.transport.refuse_next("offline");
+
+        // Queued while the server is unreachable, least important first
+        let stale_uptime: u64 = 999_999;
+        harness
+            .outbound
+            .push(OutboundMessage::Heartbeat(HeartbeatStats {
+                uptime_secs: Some(stale_uptime),
+                ..HeartbeatStats::default()
+            }));
+        harness.outbound.push(OutboundMessage::AlertError {
+            client_id: "test-client".to_string(),
+            reason: AlertErrorReason::Overloaded,
+            alert_id: None,
+            detail: None,
+        });
+        let (first, second) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+        harness
+            .outbound
+            .push(OutboundMessage::Confirmation(confirmation(first)));
+        harness
+            .outbound
+            .push(OutboundMessage::Confirmation(confirmation(second)));
+
+        let mut peer: MemoryPeer = harness.accept().await;
+        let mut sent: Vec<String> = Vec::new();
+        while sent.len() < 3 {
+            match peer.recv().await.expect("client connected") {
+                Message::Confirmation { confirmation } => {
+                    sent.push(format!("confirm {}", confirmation.alert_id))
+                }
+                Message::AlertError { .. } => sent.push("error".to_string()),
+                Message::Heartbeat { stats } => assert_ne!(stats.uptime_secs, Some(stale_uptime)),
+                Message::Status { .. } => {}
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        assert_eq!(
+            sent,
+            [
+                format!("confirm {}", first),
+                format!("confirm {}", second),
+                "error".to_string()
+            ]
+        );
+
+        // Nothing is sent twice: the next confirmation is the next thing the server sees
+        let third: uuid::Uuid = uuid::Uuid::new_v4();
+        harness
+            .outbound
+            .push(OutboundMessage::Confirmation(confirmation(third)));
+        match recv_significant(&mut peer).await {
+            Some(Message::Confirmation { confirmation }) => {
+                assert_eq!(confirmation.alert_id, third)
+            }
+            other => panic!("expected confirmation, got {:?}", other),
+        }
+        assert!(harness.outbound.is_empty());
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_confirmation_that_fails_to_send_is_redelivered() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+
+        // The connection breaks as the first confirmation is written
+        peer.break_writes();
+        let (first, second) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+        harness
+            .outbound
+            .push(OutboundMessage::Confirmation(confirmation(first)));
+        harness
+            .outbound
+            .push(OutboundMessage::Confirmation(confirmation(second)));
+
+        // Both go out after registering again, in the order they were confirmed, once each
+        let mut peer: MemoryPeer = harness.accept().await;
+        let mut sent: Vec<uuid::Uuid> = Vec::new();
+        while sent.len() < 2 {
+            match recv_significant(&mut peer).await {
+                Some(Message::Confirmation { confirmation }) => sent.push(confirmation.alert_id),
+                other => panic!("expected confirmation, got {:?}", other),
+            }
+        }
+        assert_eq!(sent, [first, second]);
+        assert!(harness.outbound.is_empty());
+        assert_eq!(harness.outbound.dropped_count(), 0);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unacknowledged_registration_reconnects() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.listener.accept().await.expect("client connected");
+        let connected: Instant = Instant::now();
+        match peer.recv().await {
+            Some(Message::Register {
+                protocol_version, ..
+            }) => assert_eq!(protocol_version, Some(PROTOCOL_VERSION)),
+            other => panic!("expected register, got {:?}", other),
+        }
+
+        // Silence: nothing more is sent until the server answers
+        assert!(peer.recv().await.is_none());
+        assert_eq!(connected.elapsed(), DEFAULT_REGISTER_TIMEOUT);
+        harness.accept().await;
+        assert_eq!(
+            connected.elapsed(),
+            DEFAULT_REGISTER_TIMEOUT + harness.settings.snapshot().reconnect_delay()
+        );
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_protocol_version_mismatch_drops_connection() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.listener.accept().await.expect("client connected");
+        assert!(matches!(peer.recv().await, Some(Message::Register { .. })));
+        peer.send(&Message::RegisterAck {
+            server_name: None,
+            environment: None,
+            encoding: None,
+            server_version: Some("emns-server 9.0.0".to_string()),
+            protocol_version: Some(PROTOCOL_VERSION + 1),
+            server_time: None,
+            envelopes: false,
+            confirmation_timeouts: false,
+        });
+
+        assert!(recv_significant(&mut peer).await.is_none());
+        harness.accept().await;
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_send_reconnects_and_redelivers() {
+        let mut harness: Harness = Harness::start(10);
+        let peer: MemoryPeer = harness.accept().await;
+
+        // Writes hang as on a dead link whose TCP window has filled
+        peer.stall_writes();
+        let stalled: Instant = Instant::now();
+        let confirmed: uuid::Uuid = uuid::Uuid::new_v4();
+        harness
+            .outbound
+            .push(OutboundMessage::Confirmation(confirmation(confirmed)));
+
+        // Given up on after the send timeout, well before the server timeout would notice
+        let mut peer: MemoryPeer = harness.accept().await;
+        assert_eq!(
+            stalled.elapsed(),
+            DEFAULT_SEND_TIMEOUT + harness.settings.snapshot().reconnect_delay()
+        );
+        match recv_significant(&mut peer).await {
+            Some(Message::Confirmation { confirmation }) => {
+                assert_eq!(confirmation.alert_id, confirmed)
+            }
+            other => panic!("expected confirmation, got {:?}", other),
+        }
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stopping_flushes_the_queue_and_unregisters() {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.accept().await;
+
+        // Confirmed just as the agent is told to stop
+        let confirmed: uuid::Uuid = uuid::Uuid::new_v4();
+        harness
+            .outbound
+            .push(OutboundMessage::Confirmation(confirmation(confirmed)));
+        harness.stop().await;
+
+        match recv_significant(&mut peer).await {
+            Some(Message::Confirmation { confirmation }) => {
+                assert_eq!(confirmation.alert_id, confirmed)
+            }
+            other => panic!("expected confirmation, got {:?}", other),
+        }
+        match recv_significant(&mut peer).await {
+            Some(Message::Unregister { client_id, reason }) => {
+                assert_eq!(client_id, "test-client");
+                assert_eq!(reason, ShutdownReason::Clean);
+            }
+            other => panic!("expected unregister, got {:?}", other),
+        }
+        assert!(peer.recv().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeats_flow_while_handler_is_wedged() {
+        // Nothing drains the queue, as if the handler were stuck in an audio driver
+        let mut harness: Harness = Harness::start(2);
+        let mut peer: MemoryPeer = harness.accept().await;
+
+        for _ in 0..20 {
+            peer.send(&Message::Alert {
+                alert: alert(AlertLevel::Info, false),
+            });
+        }
+
+        let mut heartbeats: usize = 0;
+        let mut last_status: Option<AgentStatus> = None;
+        while heartbeats < 3 || last_status.as_ref().is_none_or(|s| s.alerts_shed < 18) {
+            match peer.recv().await {
+                Some(Message::Heartbeat { .. }) => heartbeats += 1,
+                Some(Message::Status { status }) => last_status = Some(status),
+                Some(_) => {}
+                None => panic!("client disconnected while handler was wedged"),
+            }
+        }
+
+        let status: AgentStatus = last_status.unwrap();
+        assert_eq!(status.alert_queue_depth, 2);
+        assert_eq!(status.alerts_shed, 18);
+        assert_eq!(harness.queue.depth(), 2);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_registers_location_and_drops_alerts_for_elsewhere() {
+        let here: Location = Location {
+            building: "C".into(),
+            floor: "3".into(),
+            ..Location::default()
+        };
+        let mut harness: Harness = Harness::start_at(10, Some(here.clone()), None);
+        let mut peer: MemoryPeer = harness.listener.accept().await.expect("client connected");
+        match peer.recv().await {
+            Some(Message::Register { location, .. }) => assert_eq!(location, Some(here)),
+            other => panic!("expected register, got {:?}", other),
+        }
+
+        let mut elsewhere = alert(AlertLevel::Critical, false);
+        elsewhere.location = Some(Location {
+            building: "D".into(),
+            ..Location::default()
+        });
+        let mut targeted = alert(AlertLevel::Critical, false);
+        targeted.location = Some(Location {
+            building: "c".into(),
+            floor: LocationField::new(["2", "3", "4"]),
+            ..Location::default()
+        });
+        peer.send(&Message::Alert { alert: elsewhere });
+        peer.send(&Message::Alert {
+            alert: targeted.clone(),
+        });
+
+        assert_eq!(harness.queue.recv().await.id, targeted.id);
+        assert_eq!(harness.queue.depth(), 0);
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_config_update_sets_sound_policy() {
+        let mut harness: Harness = Harness::start(10);
+        let peer: MemoryPeer = harness.accept().await;
+        let visual_only: SoundPolicy = SoundPolicy {
+            visual_only: Some(true),
+            ..SoundPolicy::default()
+        };
+
+        // Messages are handled in order, so the alert arriving means the update was applied
+        peer.send(&Message::ConfigUpdate {
+            sound_policy: Some(visual_only.clone()),
+        });
+        peer.send(&Message::Alert {
+            alert: alert(AlertLevel::Info, false),
+        });
+        harness.queue.recv().await;
+        assert_eq!(harness.settings.snapshot().sound_policy(), &visual_only);
+
+        // An invalid policy is rejected without dropping the connection
+        peer.send(&Message::ConfigUpdate {
+            sound_policy: Some(SoundPolicy {
+                max_volume: Some(5.0),
+                ..SoundPolicy::default()
+            }),
+        });
+        peer.send(&Message::Alert {
+            alert: alert(AlertLevel::Info, false),
+        });
+        harness.queue.recv().await;
+        assert_eq!(harness.settings.snapshot().sound_policy(), &visual_only);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_register_ack_sets_server_identity() {
+        let mut harness: Harness = Harness::start(10);
+        harness
+            .settings
+            .update(|s| {
+                s.set_server_identity(Some("EMNS".to_string()), Some("test".to_string()));
+                Ok(())
+            })
+            .unwrap();
+        let peer: MemoryPeer = harness.accept().await;
+
+        // Only the environment is sent, so the configured name stays
+        peer.send(&Message::RegisterAck {
+            server_name: None,
+            environment: Some("production".to_string()),
+            encoding: None,
+            server_version: None,
+            protocol_version: None,
+            server_time: None,
+            envelopes: false,
+            confirmation_timeouts: false,
+        });
+        peer.send(&Message::Alert {
+            alert: alert(AlertLevel::Info, false),
+        });
+        harness.queue.recv().await;
+        assert_eq!(
+            harness.settings.snapshot().attribution().as_deref(),
+            Some("EMNS — PRODUCTION")
+        );
+
+        // A bare acknowledgement changes nothing
+        peer.send(&Message::RegisterAck {
+            server_name: None,
+            environment: None,
+            encoding: None,
+            server_version: None,
+            protocol_version: None,
+            server_time: None,
+            envelopes: false,
+            confirmation_timeouts: false,
+        });
+        peer.send(&Message::Alert {
+            alert: alert(AlertLevel::Info, false),
+        });
+        harness.queue.recv().await;
+        assert_eq!(
+            harness.settings.snapshot().attribution().as_deref(),
+            Some("EMNS — PRODUCTION")
+        );
+
+        harness.stop().await;
+    }
+
+    /// Register, answer with `ack`, then stop the client; returns the frame
+    /// its unregistration came in
+    async fn unregister_frame_after(ack: Message) -> Frame {
+        let mut harness: Harness = Harness::start(10);
+        let mut peer: MemoryPeer = harness.listener.accept().await.expect("client connected");
+        match peer.recv().await {
+            Some(Message::Register {
+                supported_encodings,
+                ..
+            }) => assert_eq!(supported_encodings, [Encoding::Msgpack]),
+            other => panic!("expected register, got {:?}", other),
+        }
+
+        // Binary from the server is read whatever the client sends
+        peer.send_msgpack(&ack);
+        let sent = alert(AlertLevel::Critical, false);
+        peer.send_msgpack(&Message::Alert {
+            alert: sent.clone(),
+        });
+        assert_eq!(harness.queue.recv().await.id, sent.id);
+
+        harness.stop().await;
+        loop {
+            let frame: Frame = peer.recv_frame().await.expect("client unregistered");
+            if let Ok(Message::Unregister { .. }) = decode(&frame) {
+                return frame;
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sends_msgpack_once_the_server_chooses_it() {
+        let frame: Frame = unregister_frame_after(Message::RegisterAck {
+            server_name: None,
+            environment: None,
+            encoding: Some(Encoding::Msgpack),
+            server_version: None,
+            protocol_version: None,
+            server_time: None,
+            envelopes: false,
+            confirmation_timeouts: false,
+        })
+        .await;
+        assert!(matches!(frame, Frame::Binary(_)), "got {:?}", frame);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keeps_sending_json_unless_chosen() {
+        let frame: Frame = unregister_frame_after(Message::RegisterAck {
+            server_name: Some("EMNS".to_string()),
+            environment: None,
+            encoding: None,
+            server_version: None,
+            protocol_version: None,
+            server_time: None,
+            envelopes: false,
+            confirmation_timeouts: false,
+        })
+        .await;
+        assert!(matches!(frame, Frame::Text(_)), "got {:?}", frame);
+    }
+
+    /// Next ack or nack from a client sending envelopes
+    async fn recv_answer(peer: &mut MemoryPeer) -> Message {
+        loop {
+            let envelope: Envelope = peer.recv_envelope().await.expect("client answered");
+            if !envelope.wants_ack() {
+                return envelope.message;
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shed_alert_does_not_move_the_replay_watermark() {
+        let dir: std::path::PathBuf =
+            std::env::temp_dir().join(format!("emns-client-watermark-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let watermark: Arc<Watermark> = Arc::new(Watermark::load(dir.join(WATERMARK_FILE)));
+        let mut harness: Harness = Harness::start_with(1, None, None, {
+            let watermark: Arc<Watermark> = watermark.clone();
+            |client| client.with_watermark(watermark)
+        });
+        let mut peer: MemoryPeer = harness.listener.accept().await.expect("client connected");
+        peer.recv().await.expect("client registered");
+        peer.ack_registration_with_envelopes();
+
+        let queued: Alert = alert(AlertLevel::Critical, false);
+        peer.send_envelope(
+            Uuid::new_v4(),
+            &Message::Alert {
+                alert: queued.clone(),
+            },
+        );
+        assert!(matches!(recv_answer(&mut peer).await, Message::Ack { .. }));
+        assert_eq!(watermark.get(), Some(queued.timestamp));
+
+        let mut shed: Alert = alert(AlertLevel::Info, false);
+        shed.timestamp = queued.timestamp + chrono::TimeDelta::seconds(1);
+        peer.send_envelope(Uuid::new_v4(), &Message::Alert { alert: shed });
+        assert!(matches!(
+            recv_answer(&mut peer).await,
+            Message::Nack {
+                reason: NackReason::QueueFull,
+                ..
+            }
+        ));
+        // A replay from the watermark still covers the alert that was shed
+        assert_eq!(watermark.get(), Some(queued.timestamp));
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_alert_evicted_after_its_ack_is_replayed_on_reconnect() {
+        let dir: std::path::PathBuf =
+            std::env::temp_dir().join(format!("emns-client-watermark-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let watermark: Arc<Watermark> = Arc::new(Watermark::load(dir.join(WATERMARK_FILE)));
+        let mut harness: Harness = Harness::start_with(1, None, None, {
+            let watermark: Arc<Watermark> = watermark.clone();
+            |client| client.with_watermark(watermark)
+        });
+        let mut peer: MemoryPeer = harness.listener.accept().await.expect("client connected");
+        peer.recv().await.expect("client registered");
+        peer.ack_registration_with_envelopes();
+
+        // The queue holds one alert, and the critical one displaces it once acked
+        let evicted: Alert = alert(AlertLevel::Info, false);
+        let mut urgent: Alert = alert(AlertLevel::Critical, false);
+        urgent.timestamp = evicted.timestamp + chrono::TimeDelta::seconds(1);
+        for sent in [&evicted, &urgent] {
+            peer.send_envelope(
+                Uuid::new_v4(),
+                &Message::Alert {
+                    alert: sent.clone(),
+                },
+            );
+            assert!(matches!(recv_answer(&mut peer).await, Message::Ack { .. }));
+        }
+        assert_eq!(harness.queue.recv().await.id, urgent.id);
+
+        // The next registration asks for a replay that covers the evicted alert
+        drop(peer);
+        let mut peer: MemoryPeer = harness.listener.accept().await.expect("client reconnected");
+        match peer.recv().await {
+            Some(Message::Register { since, .. }) => {
+                assert!(since.is_some_and(|since| since < evicted.timestamp))
+            }
+            other => panic!("expected register, got {:?}", other),
+        }
+        peer.ack_registration_with_envelopes();
+        let mut replayed: Alert = evicted.clone();
+        replayed.missed = true;
+        peer.send_envelope(
+            Uuid::new_v4(),
+            &Message::AlertBatch {
+                batch: AlertBatch {
+                    alerts: vec![replayed],
+                    unreadable: Vec::new(),
+                },
+            },
+        );
+        assert!(matches!(recv_answer(&mut peer).await, Message::Ack { .. }));
+        assert_eq!(harness.queue.recv().await.id, evicted.id);
+        assert_eq!(watermark.get(), Some(urgent.timestamp));
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_alerts_in_envelopes_are_acked_once_queued_or_nacked() {
+        let mut harness: Harness = Harness::start(1);
+        let mut peer: MemoryPeer = harness.listener.accept().await.expect("client connected");
+        match peer.recv().await {
+            Some(Message::Register { envelopes, .. }) => assert!(envelopes),
+            other => panic!("expected register, got {:?}", other),
+        }
+        peer.ack_registration_with_envelopes();
+
+        let queued: Uuid = Uuid::new_v4();
+        peer.send_envelope(
+            queued,
+            &Message::Alert {
+                alert: alert(AlertLevel::Critical, false),
+            },
+        );
+        match recv_answer(&mut peer).await {
+            Message::Ack { message_id } => assert_eq!(message_id, queued),
+            other => panic!("expected ack, got {:?}", other),
+        }
+
+        // The queue holds one alert, and nothing less urgent displaces it
+        let shed: Uuid = Uuid::new_v4();
+        peer.send_envelope(
+            shed,
+            &Message::Alert {
+                alert: alert(AlertLevel::Info, false),
+            },
+        );
+        match recv_answer(&mut peer).await {
+            Message::Nack {
+                message_id, reason, ..
+            } => {
+                assert_eq!(message_id, shed);
+                assert_eq!(reason, NackReason::QueueFull);
+            }
+            other => panic!("expected nack, got {:?}", other),
+        }
+
+        // Invalid whether it parses or not
+        let mut empty: Alert = alert(AlertLevel::Critical, false);
+        empty.title = String::new();
+        let invalid: Uuid = Uuid::new_v4();
+        peer.send_envelope(invalid, &Message::Alert { alert: empty });
+        let garbled: Uuid = Uuid::new_v4();
+        peer.send_frame(Frame::Text(
+            serde_json::json!({
+                "message_id": garbled,
+                "message": { "type": "alert", "alert": { "id": Uuid::new_v4(), "level": "sev1" } }
+            })
+            .to_string(),
+        ));
+        for expected in [invalid, garbled] {
+            match recv_answer(&mut peer).await {
+                Message::Nack {
+                    message_id,
+                    reason,
+                    detail,
+                } => {
+                    assert_eq!(message_id, expected);
+                    assert_eq!(reason, NackReason::Invalid);
+                    assert!(detail.unwrap().contains("title: "));
+                }
+                other => panic!("expected nack, got {:?}", other),
+            }
+        }
+        assert_eq!(harness.queue.depth(), 1);
+
+        harness.stop().await;
+    }
+
+    const BACKUP: &str = "ws://backup.test/ws";
+
+    /// The active connection to `URL`, registered and acknowledged, and the
+    /// standby connection to `BACKUP`
+    async fn accept_primary_and_backup(harness: &mut Harness) -> (MemoryPeer, MemoryPeer) {
+        let mut primary: Option<MemoryPeer> = None;
+        let mut backup: Option<MemoryPeer> = None;
+        for _ in 0..2 {
+            let mut peer: MemoryPeer = harness.listener.accept().await.unwrap();
+            match (peer.recv().await, peer.url()) {
+                (Some(Message::Register { standby: false, .. }), URL) => {
+                    peer.ack_registration();
+                    primary = Some(peer)
+                }
+                (Some(Message::Register { standby: true, .. }), BACKUP) => backup = Some(peer),
+                (other, url) => panic!("unexpected registration {:?} on {}", other, url),
+            }
+        }
+        (primary.unwrap(), backup.unwrap())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_standby_connection_dedupes_and_takes_over() {
+        let mut harness: Harness = Harness::start_at(10, None, Some(BACKUP));
+        let (primary, mut backup) = accept_primary_and_backup(&mut harness).await;
+
+        // The same alert from both servers is queued once
+        let sent = alert(AlertLevel::Critical, true);
+        primary.send(&Message::Alert {
+            alert: sent.clone(),
+        });
+        backup.send(&Message::Alert {
+            alert: sent.clone(),
+        });
+        let other = alert(AlertLevel::Info, false);
+        backup.send(&Message::Alert {
+            alert: other.clone(),
+        });
+        assert_eq!(harness.queue.recv().await.id, sent.id);
+        assert_eq!(harness.queue.recv().await.id, other.id);
+        assert_eq!(harness.queue.depth(), 0);
+
+        // The standby is promoted without waiting out the reconnect delay
+        let failed_at: Instant = Instant::now();
+        primary.fail("connection reset");
+        match recv_significant(&mut backup).await {
+            Some(Message::Register { standby, .. }) => assert!(!standby),
+            other => panic!("expected re-registration, got {:?}", other),
+        }
+        assert_eq!(failed_at.elapsed(), Duration::ZERO);
+        backup.ack_registration();
+
+        // Confirmations now go to the backup, and the primary becomes the standby
+        harness
+            .outbound
+            .push(OutboundMessage::Confirmation(confirmation(sent.id)));
+        assert!(matches!(
+            recv_significant(&mut backup).await,
+            Some(Message::Confirmation { .. })
+        ));
+        let mut rejoined: MemoryPeer = harness.listener.accept().await.unwrap();
+        assert_eq!(rejoined.url(), URL);
+        assert!(matches!(
+            rejoined.recv().await,
+            Some(Message::Register { standby: true, .. })
+        ));
+
+        // A confirmation that cannot be sent on the dead link goes out on the next one
+        drop(backup);
+        harness
+            .outbound
+            .push(OutboundMessage::Confirmation(confirmation(other.id)));
+        match recv_significant(&mut rejoined).await {
+            Some(Message::Register { standby, .. }) => assert!(!standby),
+            other => panic!("expected re-registration, got {:?}", other),
+        }
+        rejoined.ack_registration();
+        match recv_significant(&mut rejoined).await {
+            Some(Message::Confirmation { confirmation }) => {
+                assert_eq!(confirmation.alert_id, other.id)
+            }
+            other => panic!("expected confirmation, got {:?}", other),
+        }
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rejected_copy_does_not_hide_the_alert_from_the_other_server() {
+        let mut harness: Harness = Harness::start_at(10, None, Some(BACKUP));
+        let (mut primary, backup) = accept_primary_and_backup(&mut harness).await;
+
+        let sent: Alert = alert(AlertLevel::Critical, true);
+        primary.send(&Message::Alert {
+            alert: Alert {
+                title: String::new(),
+                ..sent.clone()
+            },
+        });
+        match recv_significant(&mut primary).await {
+            Some(Message::AlertError { reason, .. }) => {
+                assert_eq!(reason, AlertErrorReason::Invalid)
+            }
+            other => panic!("expected alert error, got {:?}", other),
+        }
+        backup.send(&Message::Alert {
+            alert: sent.clone(),
+        });
+        let delivered: Alert = tokio::time::timeout(Duration::from_secs(5), harness.queue.recv())
+            .await
+            .expect("the valid copy was queued");
+        assert_eq!(delivered.id, sent.id);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_alert_shed_from_a_full_queue_is_taken_when_resent() {
+        let mut harness: Harness = Harness::start_at(1, None, Some(BACKUP));
+        let mut primary: Option<MemoryPeer> = None;
+        let mut backup: Option<MemoryPeer> = None;
+        for _ in 0..2 {
+            let mut peer: MemoryPeer = harness.listener.accept().await.unwrap();
+            match (peer.recv().await, peer.url()) {
+                (Some(Message::Register { standby: false, .. }), URL) => {
+                    peer.ack_registration_with_envelopes();
+                    primary = Some(peer)
+                }
+                (Some(Message::Register { standby: true, .. }), BACKUP) => backup = Some(peer),
+                (other, url) => panic!("unexpected registration {:?} on {}", other, url),
+            }
+        }
+        let (mut primary, backup) = (primary.unwrap(), backup.unwrap());
+
+        let urgent: Alert = alert(AlertLevel::Critical, false);
+        primary.send_envelope(Uuid::new_v4(), &Message::Alert { alert: urgent });
+        assert!(matches!(
+            recv_answer(&mut primary).await,
+            Message::Ack { .. }
+        ));
+        let resent: Alert = alert(AlertLevel::Info, false);
+        primary.send_envelope(
+            Uuid::new_v4(),
+            &Message::Alert {
+                alert: resent.clone(),
+            },
+        );
+        assert!(matches!(
+            recv_answer(&mut primary).await,
+            Message::Nack {
+                reason: NackReason::QueueFull,
+                ..
+            }
+        ));
+
+        // Once there is room, the other server's copy is not taken as a duplicate
+        harness.queue.recv().await;
+        backup.send(&Message::Alert {
+            alert: resent.clone(),
+        });
+        let delivered: Alert = tokio::time::timeout(Duration::from_secs(5), harness.queue.recv())
+            .await
+            .expect("the resent alert was queued");
+        assert_eq!(delivered.id, resent.id);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_forged_copy_does_not_hide_the_signed_alert_from_the_other_server() {
+        let key: SigningKey = SigningKey::new("site-secret");
+        let mut harness: Harness = Harness::start_with(10, None, Some(BACKUP), {
+            let key: SigningKey = key.clone();
+            |client| client.with_signing_key(Some(key))
+        });
+        let (mut primary, backup) = accept_primary_and_backup(&mut harness).await;
+
+        let mut signed: Alert = alert(AlertLevel::Critical, true);
+        signed.signature = Some(signing::sign(&signed, &key));
+        let forged: Alert = Alert {
+            signature: Some(signing::sign(&signed, &SigningKey::new("other-secret"))),
+            ..signed.clone()
+        };
+        primary.send(&Message::Alert { alert: forged });
+        match recv_significant(&mut primary).await {
+            Some(Message::AlertError { reason, .. }) => {
+                assert_eq!(reason, AlertErrorReason::BadSignature)
+            }
+            other => panic!("expected alert error, got {:?}", other),
+        }
+        backup.send(&Message::Alert {
+            alert: signed.clone(),
+        });
+        let delivered: Alert = tokio::time::timeout(Duration::from_secs(5), harness.queue.recv())
+            .await
+            .expect("the signed copy was queued");
+        assert_eq!(delivered.id, signed.id);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_suppression_windows_for_this_location_are_stored() {
+        let here: Location = Location {
+            building: "C".into(),
+            ..Location::default()
+        };
+        let mut harness: Harness = Harness::start_at(10, Some(here.clone()), None);
+        let mut peer: MemoryPeer = harness.listener.accept().await.expect("client connected");
+        assert!(matches!(peer.recv().await, Some(Message::Register { .. })));
+
+        let window = |location: Location| SuppressionWindow {
+            id: uuid::Uuid::new_v4(),
+            starts_at: chrono::Utc::now(),
+            ends_at: chrono::Utc::now() + chrono::Duration::hours(1),
+            levels: Vec::new(),
+            categories: Vec::new(),
+            reason: "Fire alarm testing".to_string(),
+            location: Some(location),
+        };
+        let ours: SuppressionWindow = window(here);
+        peer.send(&Message::Suppression {
+            window: window(Location {
+                building: "D".into(),
+                ..Location::default()
+            }),
+        });
+        peer.send(&Message::Suppression {
+            window: ours.clone(),
+        });
+
+        // Messages are handled in order, so the alert arriving means both windows were seen
+        peer.send(&Message::Alert {
+            alert: alert(AlertLevel::Info, false),
+        });
+        harness.queue.recv().await;
+        assert_eq!(harness.suppressions.scheduled(), vec![ours.clone()]);
+
+        peer.send(&Message::CancelSuppression { id: ours.id });
+        peer.send(&Message::Alert {
+            alert: alert(AlertLevel::Info, false),
+        });
+        harness.queue.recv().await;
+        assert!(harness.suppressions.scheduled().is_empty());
+        harness.stop().await;
+    }
+
+    /// Announce maintenance from `peer` for `secs` and wait for the client to take it in
+    async fn announce_maintenance(harness: &Harness, peer: &MemoryPeer, secs: i64) {
+        peer.send(&Message::ServerShutdown {
+            resume_expected_at: chrono::Utc::now() + chrono::TimeDelta::seconds(secs),
+            reason: Some("patching".to_string()),
+        });
+        while !harness.maintenance.is_active() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_maintenance_announcement_slows_reconnects_until_resume() {
+        let mut harness: Harness = Harness::start(10);
+        let peer: MemoryPeer = harness.accept().await;
+        announce_maintenance(&harness, &peer, 100).await;
+
+        // Attempts 30s apart while the server is down, the last one as the window ends
+        for _ in 0..4 {
+            harness.transport.refuse_next("server is down");
+        }
+        let dropped_at: Instant = Instant::now();
+        peer.fail("server stopped");
+        let peer: MemoryPeer = harness.accept().await;
+        let normal: Duration = harness.settings.snapshot().reconnect_delay();
+        let elapsed: Duration = dropped_at.elapsed();
+        assert!(
+            elapsed > Duration::from_secs(99) && elapsed <= Duration::from_secs(100) + normal,
+            "reconnected after {:?}",
+            elapsed
+        );
+
+        // Back at the normal cadence once the server has returned
+        assert!(!harness.maintenance.is_active());
+        harness.transport.refuse_next("connection refused");
+        let dropped_at: Instant = Instant::now();
+        drop(peer);
+        harness.accept().await;
+        assert_eq!(dropped_at.elapsed(), normal * 2);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refusals_during_maintenance_do_not_grow_the_backoff() {
+        let mut harness: Harness = Harness::start_with(10, None, None, |client| {
+            client.with_reconnect_backoff(BackoffConfig {
+                multiplier: 4.0,
+                ..BackoffConfig::default()
+            })
+        });
+        let peer: MemoryPeer = harness.accept().await;
+        announce_maintenance(&harness, &peer, 100).await;
+        // Back in time for the attempt as the window ends
+        for _ in 0..3 {
+            harness.transport.refuse_next("server is down");
+        }
+        peer.fail("server stopped");
+        let peer: MemoryPeer = harness.accept().await;
+
+        // Growing from the first wait, as if the maintenance had not happened
+        let normal: Duration = harness.settings.snapshot().reconnect_delay();
+        harness.transport.refuse_next("connection refused");
+        let dropped_at: Instant = Instant::now();
+        drop(peer);
+        harness.accept().await;
+        assert_eq!(dropped_at.elapsed(), normal + normal * 4);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_status_through_backup_reports_maintenance_window() {
+        const BACKUP: &str = "ws://backup.test/ws";
+        let mut harness: Harness = Harness::start_at(10, None, Some(BACKUP));
+        let mut primary: Option<MemoryPeer> = None;
+        let mut backup: Option<MemoryPeer> = None;
+        for _ in 0..2 {
+            let mut peer: MemoryPeer = harness.listener.accept().await.unwrap();
+            match peer.recv().await {
+                Some(Message::Register { standby: false, .. }) => {
+                    peer.ack_registration();
+                    primary = Some(peer)
+                }
+                Some(Message::Register { standby: true, .. }) => backup = Some(peer),
+                other => panic!("unexpected registration {:?}", other),
+            }
+        }
+        let (primary, mut backup) = (primary.unwrap(), backup.unwrap());
+
+        announce_maintenance(&harness, &primary, 600).await;
+        primary.fail("server stopped");
+        loop {
+            match backup.recv().await {
+                // Promoted, so registering as the active connection
+                Some(Message::Register { .. }) => backup.ack_registration(),
+                Some(Message::Status { status }) => {
+                    assert!(status.in_maintenance_window);
+                    break;
+                }
+                Some(_) => continue,
+                None => panic!("backup connection closed"),
+            }
+        }
+
+        harness.stop().await;
+    }
+}
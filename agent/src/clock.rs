@@ -0,0 +1,222 @@
+//! The wall clock, and noticing when it jumps.
+//!
+//! Deadlines measured from an alert's arrival (auto-confirm, escalation,
+//! preview expiry) are tokio [`Instant`]s, which never jump; tests drive them
+//! with `tokio::time::pause`. Only comparisons with times a server sent, and
+//! timestamps reported back, read the wall clock, through [`Clock`], so tests
+//! can move it independently.
+//!
+//! A machine whose clock has drifted reads it through a [`ServerClock`],
+//! corrected by the difference from the server's clock measured at
+//! registration.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Smallest disagreement between the wall and monotonic clocks treated as a jump
+pub const CLOCK_JUMP_THRESHOLD: Duration = Duration::from_secs(30);
+
+/// Largest difference from the server's clock that is corrected for; a
+/// larger one is more likely a wrong server time than a drifted clock
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(3600);
+
+/// Source of wall-clock time
+pub trait Clock: Send + Sync {
+    fn now(&self) -> chrono::DateTime<chrono::Utc>;
+
+    /// The time before any correction toward the server's clock, which is
+    /// what jumps are judged by
+    fn uncorrected(&self) -> chrono::DateTime<chrono::Utc> {
+        self.now()
+    }
+}
+
+/// The system's wall clock
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc::now()
+    }
+}
+
+/// How far the local clock was found ahead of the server's; negative when behind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSkew {
+    pub by: chrono::TimeDelta,
+    /// Whether timestamps are corrected for it; not beyond [`MAX_CLOCK_SKEW`]
+    pub corrected: bool,
+}
+
+impl std::fmt::Display for ClockSkew {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let direction: &str = if self.by < chrono::TimeDelta::zero() {
+            "behind"
+        } else {
+            "ahead"
+        };
+        let ms: i64 = self.by.num_milliseconds().abs();
+        write!(f, "{} by {}.{:03}s", direction, ms / 1000, ms % 1000)
+    }
+}
+
+/// A local clock read as the server's: the skew last measured from the
+/// server's time is taken off every reading
+pub struct ServerClock {
+    local: Arc<dyn Clock>,
+    skew: Mutex<Option<ClockSkew>>,
+}
+
+impl ServerClock {
+    pub fn new(local: Arc<dyn Clock>) -> Self {
+        Self {
+            local,
+            skew: Mutex::new(None),
+        }
+    }
+
+    /// Measure the skew from `server_time`, which the server read about
+    /// half of `round_trip` ago
+    pub fn sync(
+        &self,
+        server_time: chrono::DateTime<chrono::Utc>,
+        round_trip: Duration,
+    ) -> ClockSkew {
+        let in_flight: chrono::TimeDelta =
+            chrono::TimeDelta::from_std(round_trip / 2).unwrap_or_default();
+        let by: chrono::TimeDelta = self.local.uncorrected() - (server_time + in_flight);
+        let skew: ClockSkew = ClockSkew {
+            by,
+            corrected: by.abs().to_std().is_ok_and(|by| by <= MAX_CLOCK_SKEW),
+        };
+        *self.skew.lock().unwrap() = Some(skew);
+        skew
+    }
+
+    /// The skew last measured; `None` until a server has sent its time
+    pub fn skew(&self) -> Option<ClockSkew> {
+        *self.skew.lock().unwrap()
+    }
+}
+
+impl Clock for ServerClock {
+    fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        match self.skew() {
+            Some(skew) if skew.corrected => self.local.now() - skew.by,
+            _ => self.local.now(),
+        }
+    }
+
+    fn uncorrected(&self) -> chrono::DateTime<chrono::Utc> {
+        self.local.uncorrected()
+    }
+}
+
+/// The wall clock moved by `by` more than the monotonic clock did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockJump {
+    pub by: chrono::TimeDelta,
+}
+
+impl std::fmt::Display for ClockJump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let direction: &str = if self.by < chrono::TimeDelta::zero() {
+            "back"
+        } else {
+            "forward"
+        };
+        write!(f, "{} by {}s", direction, self.by.num_seconds().abs())
+    }
+}
+
+/// Compares how far the wall clock moved between checks with how far the
+/// monotonic clock did
+pub struct JumpDetector {
+    threshold: Duration,
+    last: Mutex<(chrono::DateTime<chrono::Utc>, Instant)>,
+}
+
+impl JumpDetector {
+    pub fn new(clock: &dyn Clock, threshold: Duration) -> Self {
+        Self {
+            threshold,
+            last: Mutex::new((clock.uncorrected(), Instant::now())),
+        }
+    }
+
+    /// The jump since the previous check, if the clocks disagree by more than the threshold
+    pub fn check(&self, clock: &dyn Clock) -> Option<ClockJump> {
+        let (wall, monotonic) = (clock.uncorrected(), Instant::now());
+        let mut last = self.last.lock().unwrap();
+        let elapsed: chrono::TimeDelta =
+            chrono::TimeDelta::from_std(monotonic.saturating_duration_since(last.1)).ok()?;
+        let by: chrono::TimeDelta = (wall - last.0) - elapsed;
+        *last = (wall, monotonic);
+        (by.abs().to_std().ok()? > self.threshold).then_some(ClockJump { by })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ManualClock;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_detects_jumps_but_not_elapsed_time() {
+        let clock: ManualClock = ManualClock::new();
+        let detector: JumpDetector = JumpDetector::new(&clock, CLOCK_JUMP_THRESHOLD);
+
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        assert_eq!(detector.check(&clock), None);
+
+        clock.jump(chrono::TimeDelta::seconds(20));
+        assert_eq!(detector.check(&clock), None);
+
+        clock.jump(chrono::TimeDelta::minutes(-3));
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let jump: ClockJump = detector.check(&clock).unwrap();
+        assert_eq!(jump.by, chrono::TimeDelta::minutes(-3));
+        assert_eq!(jump.to_string(), "back by 180s");
+
+        // Measured from the last check, so a jump is reported once
+        assert_eq!(detector.check(&clock), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_server_clock_corrects_measured_skew() {
+        let local: Arc<ManualClock> = Arc::new(ManualClock::new());
+        let clock: ServerClock = ServerClock::new(local.clone());
+        let detector: JumpDetector = JumpDetector::new(&clock, CLOCK_JUMP_THRESHOLD);
+        assert_eq!(clock.now(), local.now());
+        assert_eq!(clock.skew(), None);
+
+        // The server read its clock 200ms before the ack arrived
+        let server_now = local.now() - chrono::TimeDelta::seconds(95);
+        let skew: ClockSkew = clock.sync(
+            server_now - chrono::TimeDelta::milliseconds(200),
+            Duration::from_millis(400),
+        );
+        assert!(skew.corrected);
+        assert_eq!(skew.by, chrono::TimeDelta::seconds(95));
+        assert_eq!(skew.to_string(), "ahead by 95.000s");
+        assert_eq!(clock.now(), server_now);
+        assert_eq!(clock.uncorrected(), local.now());
+
+        // Correcting is not the local clock jumping
+        assert_eq!(detector.check(&clock), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_server_clock_ignores_absurd_skew() {
+        let local: Arc<ManualClock> = Arc::new(ManualClock::new());
+        let clock: ServerClock = ServerClock::new(local.clone());
+
+        let skew: ClockSkew = clock.sync(local.now() + chrono::TimeDelta::hours(3), Duration::ZERO);
+        assert!(!skew.corrected);
+        assert_eq!(skew.to_string(), "behind by 10800.000s");
+        assert_eq!(clock.skew(), Some(skew));
+        assert_eq!(clock.now(), local.now());
+    }
+}
@@ -0,0 +1,481 @@
+//! WebSocket connections compressed with permessage-deflate (RFC 7692), for `WS_COMPRESSION`.
+//!
+//! tungstenite cannot negotiate extensions, and drops a connection on the
+//! first compressed frame, so these connections are made with soketto and
+//! carried as the same [`Frame`]s as any other. Either side may decline the
+//! extension; the connection then works as before, uncompressed.
+//!
+//! Only the wire framing lives here. Keepalive pings, silence timeouts,
+//! close handling and the receive limits' meaning stay in the client, which
+//! sees the same [`Frame`]s from either stack, so nothing above the
+//! [`Transport`](crate::transport::Transport) knows which one it has. Once
+//! tungstenite can negotiate permessage-deflate this module goes, and
+//! `WS_COMPRESSION` switches it on there instead.
+//!
+//! soketto holds compressed messages to the receive limits as they arrive;
+//! each is held to the message limit again once inflated, which also drops
+//! the connection, though only after it has been inflated.
+
+use crate::error::{EmnsError, Result};
+use crate::transport::{CloseCode, CloseFrame, Connection, Frame};
+use futures_util::future::BoxFuture;
+use futures_util::{FutureExt, Sink, Stream};
+use soketto::connection::{CloseReason, Error as WsError, Mode, Receiver, Sender};
+use soketto::extension::deflate::Deflate;
+use soketto::extension::Extension;
+use soketto::handshake::client::{Client, Header, ServerResponse};
+use soketto::handshake::server::{Response, Server};
+use soketto::{Data, Incoming};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::http::Uri;
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+/// Largest message and frame accepted from the other side
+#[derive(Debug, Clone, Copy)]
+pub struct ReceiveLimits {
+    pub max_message: usize,
+    pub max_frame: usize,
+}
+
+/// Open a WebSocket to `url` over `socket`, offering permessage-deflate, and
+/// log whether the server took it up
+pub async fn connect<S>(
+    url: &str,
+    socket: S,
+    authorization: Option<&[u8]>,
+    limits: ReceiveLimits,
+) -> Result<Connection>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let uri: Uri = url.parse().map_err(|e| EmnsError::connection(url, e))?;
+    let host: String = uri
+        .authority()
+        .map(|authority| authority.to_string())
+        .ok_or_else(|| EmnsError::connection(url, "URL has no host"))?;
+    let resource: &str = uri.path_and_query().map_or("/", |path| path.as_str());
+    let headers: Vec<Header> = authorization
+        .map(|value| Header {
+            name: "Authorization",
+            value,
+        })
+        .into_iter()
+        .collect();
+
+    let mut client = Client::new(socket.compat(), &host, resource);
+    client.set_headers(&headers);
+    client.add_extension(Box::new(Deflate::new(Mode::Client)));
+    match client
+        .handshake()
+        .await
+        .map_err(|e| EmnsError::connection(url, e))?
+    {
+        ServerResponse::Accepted { .. } => {}
+        ServerResponse::Redirect {
+            status_code,
+            location,
+        } => {
+            return Err(EmnsError::connection(
+                url,
+                format!(
+                    "server redirected the upgrade ({}) to {}",
+                    status_code, location
+                ),
+            ))
+        }
+        ServerResponse::Rejected { status_code } => {
+            return Err(EmnsError::connection(
+                url,
+                format!("server refused the upgrade ({})", status_code),
+            ))
+        }
+    }
+
+    let extensions: Vec<Box<dyn Extension + Send>> = client.drain_extensions().collect();
+    if extensions.iter().any(|extension| extension.is_enabled()) {
+        log::info!("Server {} accepted permessage-deflate compression", url);
+    } else {
+        log::info!(
+            "Server {} declined permessage-deflate compression; messages go uncompressed",
+            url
+        );
+    }
+    let mut builder = client.into_builder();
+    builder.add_extensions(extensions);
+    builder.set_max_message_size(limits.max_message);
+    builder.set_max_frame_size(limits.max_frame);
+    let (sender, receiver) = builder.finish();
+    Ok(frames(url, sender, receiver, limits))
+}
+
+/// Take a WebSocket upgrade from `peer` over `socket`, agreeing to
+/// permessage-deflate if it is offered; also returns whether it was
+pub async fn accept<S>(peer: &str, socket: S, limits: ReceiveLimits) -> Result<(Connection, bool)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut server = Server::new(socket.compat());
+    server.add_extension(Box::new(Deflate::new(Mode::Server)));
+    let key = server
+        .receive_request()
+        .await
+        .map_err(|e| EmnsError::connection(peer, e))?
+        .key();
+    server
+        .send_response(&Response::Accept {
+            key,
+            protocol: None,
+        })
+        .await
+        .map_err(|e| EmnsError::connection(peer, e))?;
+
+    let extensions: Vec<Box<dyn Extension + Send>> = server.drain_extensions().collect();
+    let compressed: bool = extensions.iter().any(|extension| extension.is_enabled());
+    let mut builder = server.into_builder();
+    builder.add_extensions(extensions);
+    builder.set_max_message_size(limits.max_message);
+    builder.set_max_frame_size(limits.max_frame);
+    let (sender, receiver) = builder.finish();
+    Ok((frames(peer, sender, receiver, limits), compressed))
+}
+
+/// Carry a soketto connection as [`Frame`]s. Pings from the other side are
+/// answered by soketto, so they never reach the stream.
+fn frames<S>(
+    url: &str,
+    sender: Sender<Compat<S>>,
+    receiver: Receiver<Compat<S>>,
+    limits: ReceiveLimits,
+) -> Connection
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let stream_url: String = url.to_string();
+    let stream = futures_util::stream::unfold(Some(receiver), move |receiver| {
+        let url: String = stream_url.clone();
+        async move {
+            let mut receiver: Receiver<Compat<S>> = receiver?;
+            let mut message: Vec<u8> = Vec::new();
+            let received: std::result::Result<Frame, WsError> =
+                match receiver.receive(&mut message).await {
+                    Ok(Incoming::Data(_)) if message.len() > limits.max_message => {
+                        Err(WsError::MessageTooLarge {
+                            current: message.len(),
+                            maximum: limits.max_message,
+                        })
+                    }
+                    Ok(Incoming::Data(Data::Text(_))) => String::from_utf8(message)
+                        .map(Frame::Text)
+                        .map_err(|e| WsError::Utf8(e.utf8_error())),
+                    Ok(Incoming::Data(Data::Binary(_))) => Ok(Frame::Binary(message)),
+                    Ok(Incoming::Pong(data)) => Ok(Frame::Pong(data.to_vec())),
+                    Ok(Incoming::Closed(reason)) => {
+                        return Some((Ok(Frame::Close(close_frame(reason))), None))
+                    }
+                    Err(e) => Err(e),
+                };
+            match received {
+                Ok(frame) => Some((Ok(frame), Some(receiver))),
+                Err(WsError::Closed) => None,
+                Err(e) => {
+                    if let WsError::MessageTooLarge { current, maximum } = &e {
+                        log::error!(
+                            "Server {} sent more than this agent accepts ({} bytes, limit {}); dropping the connection",
+                            url,
+                            current,
+                            maximum
+                        );
+                    }
+                    // Nothing more can be read once a message is refused
+                    Some((Err(EmnsError::connection(&url, e)), None))
+                }
+            }
+        }
+    });
+    Connection {
+        sink: Box::pin(FrameSender {
+            url: url.to_string(),
+            state: SenderState::Idle(sender),
+        }),
+        stream: Box::pin(stream) as Pin<Box<dyn Stream<Item = Result<Frame>> + Send>>,
+    }
+}
+
+/// The close frame tungstenite would have reported for `reason`
+fn close_frame(reason: CloseReason) -> Option<CloseFrame<'static>> {
+    Some(CloseFrame {
+        code: CloseCode::from(reason.code),
+        reason: reason.descr.unwrap_or_default().into(),
+    })
+}
+
+/// A write in progress, giving back the sender once done
+type Write<S> = BoxFuture<'static, (Sender<S>, std::result::Result<(), WsError>)>;
+
+/// Where a [`FrameSender`] is
+enum SenderState<S> {
+    Idle(Sender<S>),
+    Sending(Write<S>),
+    Closing(Write<S>),
+    Closed,
+}
+
+/// Writes [`Frame`]s one at a time, each flushed; closing sends a close frame
+struct FrameSender<S> {
+    url: String,
+    state: SenderState<S>,
+}
+
+impl<S> FrameSender<S>
+where
+    S: futures_util::AsyncRead + futures_util::AsyncWrite + Unpin + Send + 'static,
+{
+    /// Finish the write or close in progress, if any
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match std::mem::replace(&mut self.state, SenderState::Closed) {
+            SenderState::Idle(sender) => {
+                self.state = SenderState::Idle(sender);
+                Poll::Ready(Ok(()))
+            }
+            SenderState::Sending(mut write) => match write.poll_unpin(cx) {
+                Poll::Pending => {
+                    self.state = SenderState::Sending(write);
+                    Poll::Pending
+                }
+                Poll::Ready((sender, Ok(()))) => {
+                    self.state = SenderState::Idle(sender);
+                    Poll::Ready(Ok(()))
+                }
+                Poll::Ready((_, Err(e))) => Poll::Ready(Err(EmnsError::connection(&self.url, e))),
+            },
+            SenderState::Closing(mut close) => match close.poll_unpin(cx) {
+                Poll::Pending => {
+                    self.state = SenderState::Closing(close);
+                    Poll::Pending
+                }
+                Poll::Ready(_) => Poll::Ready(Err(self.closed())),
+            },
+            SenderState::Closed => Poll::Ready(Err(self.closed())),
+        }
+    }
+
+    fn closed(&self) -> EmnsError {
+        EmnsError::connection(&self.url, "connection is closed")
+    }
+}
+
+/// Write `frame` and flush it
+async fn send<S>(sender: &mut Sender<S>, frame: Frame) -> std::result::Result<(), WsError>
+where
+    S: futures_util::AsyncRead + futures_util::AsyncWrite + Unpin,
+{
+    match frame {
+        Frame::Text(text) => sender.send_text_owned(text).await?,
+        Frame::Binary(bytes) => sender.send_binary_mut(bytes).await?,
+        Frame::Ping(data) => sender.send_ping(control_payload(&data)?).await?,
+        Frame::Pong(data) => sender.send_pong(control_payload(&data)?).await?,
+        Frame::Close(_) => return sender.close().await,
+        // Raw frames are only ever read, never written
+        Frame::Frame(_) => {}
+    }
+    sender.flush().await
+}
+
+/// A ping or pong payload, which may not exceed 125 bytes
+fn control_payload(data: &[u8]) -> std::result::Result<soketto::data::ByteSlice125<'_>, WsError> {
+    data.try_into()
+        .map_err(|e| WsError::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)))
+}
+
+impl<S> Sink<Frame> for FrameSender<S>
+where
+    S: futures_util::AsyncRead + futures_util::AsyncWrite + Unpin + Send + 'static,
+{
+    type Error = EmnsError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_idle(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Frame) -> Result<()> {
+        let this: &mut Self = self.get_mut();
+        match std::mem::replace(&mut this.state, SenderState::Closed) {
+            SenderState::Idle(mut sender) => {
+                this.state = SenderState::Sending(Box::pin(async move {
+                    let sent = send(&mut sender, frame).await;
+                    (sender, sent)
+                }));
+                Ok(())
+            }
+            _ => Err(this.closed()),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_idle(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this: &mut Self = self.get_mut();
+        loop {
+            match std::mem::replace(&mut this.state, SenderState::Closed) {
+                SenderState::Idle(mut sender) => {
+                    this.state = SenderState::Closing(Box::pin(async move {
+                        let closed = sender.close().await;
+                        (sender, closed)
+                    }));
+                }
+                // A frame in flight goes out before the close frame
+                SenderState::Sending(mut write) => match write.poll_unpin(cx) {
+                    Poll::Pending => {
+                        this.state = SenderState::Sending(write);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready((sender, Ok(()))) => this.state = SenderState::Idle(sender),
+                    Poll::Ready((_, Err(_))) => return Poll::Ready(Ok(())),
+                },
+                SenderState::Closing(mut close) => {
+                    if close.poll_unpin(cx).is_pending() {
+                        this.state = SenderState::Closing(close);
+                        return Poll::Pending;
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                SenderState::Closed => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{Transport, TungsteniteTransport};
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+
+    const LIMITS: ReceiveLimits = ReceiveLimits {
+        max_message: 1 << 20,
+        max_frame: 1 << 20,
+    };
+
+    /// Text that compresses well, as alert JSON does
+    fn long_text() -> String {
+        "{\"title\":\"Shelter in place\",\"level\":\"critical\"}".repeat(400)
+    }
+
+    #[tokio::test]
+    async fn test_deflate_is_agreed_and_carries_frames_both_ways() {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: String = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (tcp, addr) = listener.accept().await.unwrap();
+            let (mut connection, compressed) =
+                accept(&addr.to_string(), tcp, LIMITS).await.unwrap();
+            assert!(compressed);
+            let received: Option<Result<Frame>> = connection.stream.next().await;
+            match received {
+                Some(Ok(Frame::Text(text))) => assert_eq!(text, long_text()),
+                other => panic!("expected the agent's text, got {:?}", other),
+            }
+            connection
+                .sink
+                .send(Frame::Binary(long_text().into_bytes()))
+                .await
+                .unwrap();
+            // The pong goes out unasked, then the agent closes
+            match connection.stream.next().await {
+                Some(Ok(Frame::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Normal),
+                other => panic!("expected a close, got {:?}", other),
+            }
+        });
+
+        let mut connection: Connection = TungsteniteTransport::default()
+            .with_compression(true)
+            .connect(&url)
+            .await
+            .unwrap();
+        connection
+            .sink
+            .send(Frame::Text(long_text()))
+            .await
+            .unwrap();
+        match connection.stream.next().await {
+            Some(Ok(Frame::Binary(bytes))) => assert_eq!(bytes, long_text().into_bytes()),
+            other => panic!("expected the server's binary, got {:?}", other),
+        }
+        connection
+            .sink
+            .send(Frame::Ping(b"heartbeat".to_vec()))
+            .await
+            .unwrap();
+        match connection.stream.next().await {
+            Some(Ok(Frame::Pong(data))) => assert_eq!(data, b"heartbeat"),
+            other => panic!("expected a pong, got {:?}", other),
+        }
+        connection.sink.close().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_without_deflate_leaves_the_connection_plain() {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: String = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            let received = ws.next().await;
+            match received {
+                Some(Ok(Frame::Text(text))) => ws.send(Frame::Text(text)).await.unwrap(),
+                other => panic!("expected the agent's text, got {:?}", other),
+            }
+        });
+
+        let mut connection: Connection = TungsteniteTransport::default()
+            .with_compression(true)
+            .connect(&url)
+            .await
+            .unwrap();
+        connection
+            .sink
+            .send(Frame::Text(long_text()))
+            .await
+            .unwrap();
+        match connection.stream.next().await {
+            Some(Ok(Frame::Text(text))) => assert_eq!(text, long_text()),
+            other => panic!("expected the echo, got {:?}", other),
+        }
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_message_over_the_limit_once_inflated_ends_the_stream() {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: String = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (tcp, addr) = listener.accept().await.unwrap();
+            let (mut connection, _) = accept(&addr.to_string(), tcp, LIMITS).await.unwrap();
+            // Compressed, well under the agent's limit
+            connection
+                .sink
+                .send(Frame::Text("x".repeat(2048)))
+                .await
+                .unwrap();
+        });
+
+        let mut connection: Connection = TungsteniteTransport::default()
+            .with_compression(true)
+            .with_receive_limits(1024, 1024)
+            .connect(&url)
+            .await
+            .unwrap();
+        match connection.stream.next().await {
+            Some(Err(EmnsError::Connection { .. })) => {}
+            other => panic!("expected a size error, got {:?}", other),
+        }
+        assert!(connection.stream.next().await.is_none());
+        server.await.unwrap();
+    }
+}
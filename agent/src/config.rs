@@ -0,0 +1,1905 @@
+use crate::annunciator::{AnnunciatorConfig, SequenceTemplate, DEFAULT_ANNUNCIATOR_BAUD};
+use crate::attachments::AttachmentConfig;
+use crate::backoff::BackoffConfig;
+use crate::board::DEFAULT_RECENT_WINDOW;
+use crate::broker::{SessionMode, DEFAULT_PIPE_NAME};
+use crate::burst::BurstConfig;
+use crate::callback::CallbackConfig;
+use crate::capture::WireCaptureConfig;
+use crate::category::{Categories, CategoryConfig, EXERCISE_CATEGORY};
+use crate::client::{
+    DEFAULT_HEARTBEAT_MISSED_ACKS, DEFAULT_MAX_UNREADABLE_MESSAGES, DEFAULT_PRIMARY_RETRY,
+    DEFAULT_REGISTER_TIMEOUT, DEFAULT_SEND_TIMEOUT, DEFAULT_SERVER_TIMEOUT,
+};
+use crate::dedup::DedupConfig;
+use crate::discovery::ServerDiscovery;
+use crate::error::{EmnsError, Result};
+use crate::escalation::{
+    Escalation, EscalationPolicy, Repeats, DEFAULT_ESCALATION_AFTER, DEFAULT_REPEAT_INTERVAL,
+};
+use crate::history::HISTORY_FILE;
+use crate::http_api::{HttpApiConfig, DEFAULT_MAX_BODY_BYTES};
+use crate::images::ImageConfig;
+use crate::locale;
+use crate::messages::{AlertLevel, Location, LocationField};
+use crate::multicast::{MulticastConfig, SigningKey, DEFAULT_MULTICAST_PORT};
+use crate::offline::OfflineConfig;
+use crate::operator::{OperatorIdConfig, OPERATOR_FILE};
+use crate::outbound::DEFAULT_OUTBOUND_CAPACITY;
+use crate::power::DEFAULT_DISPLAY_WAKE_CAP;
+use crate::queue::DEFAULT_ALERT_QUEUE_CAPACITY;
+use crate::rate_limit::{
+    RateLimitConfig, DEFAULT_ALERT_RATE_PER_MINUTE, DEFAULT_URGENT_ALERT_RATE_PER_MINUTE,
+};
+use crate::reminder::ReminderConfig;
+use crate::retention::RetentionConfig;
+use crate::sanitize::TextLimits;
+use crate::sealed::AlertKey;
+use crate::settings::AgentSettings;
+use crate::shutdown::SHUTDOWN_FILE;
+use crate::startup::DEFAULT_STARTUP_WAIT;
+use crate::storage::{self, DpapiScope, StateStore};
+use crate::supersede::SupersedePolicy;
+use crate::suppression::SUPPRESSION_FILE;
+use crate::toast_style::{ToastDuration, ToastScenario, ToastStyles};
+use crate::transport::{
+    AuthToken, TlsConfig, DEFAULT_CONNECT_TIMEOUT, DEFAULT_MAX_RECEIVE_FRAME_BYTES,
+    DEFAULT_MAX_RECEIVE_MESSAGE_BYTES,
+};
+use crate::update::{self, RestartWindow, UpdateConfig};
+use crate::watchdog::WatchdogConfig;
+use crate::watermark::WATERMARK_FILE;
+use regex::Regex;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Role reported by machines that do not set `MACHINE_ROLE`
+pub const DEFAULT_MACHINE_ROLE: &str = "workstation";
+
+/// Agent configuration loaded from the environment at startup
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct Config {
+    pub server_url: String,
+    /// Servers tried in order when `server_url` cannot be reached
+    pub fallback_server_urls: Vec<String>,
+    /// How often `server_url` is tried again while connected to a fallback
+    pub primary_retry: Duration,
+    /// Find the server in DNS instead of using `server_url` and the fallbacks
+    pub server_discovery: ServerDiscovery,
+    /// Backup server kept connected in standby mode for instant failover; disabled when `None`
+    pub standby_server_url: Option<String>,
+    /// Extra roots trusted for `wss://` servers, beyond the system store
+    pub tls: TlsConfig,
+    /// Sent with every connection for the server to check; none when `None`
+    pub auth_token: Option<AuthToken>,
+    pub client_id: String,
+    pub sounds_dir: PathBuf,
+    pub data_dir: PathBuf,
+    pub dpapi_scope: DpapiScope,
+    /// Key pair sealed alerts are encrypted to; sealed alerts cannot be read when `None`
+    pub alert_key: Option<AlertKey>,
+    /// Key server alerts must be signed with; unsigned alerts are taken when `None`
+    pub alert_hmac_key: Option<SigningKey>,
+    /// Where this machine is; alerts targeted at other locations are ignored
+    pub location: Option<Location>,
+    /// What this machine is used for, e.g. `signage`; alerts whose visibility
+    /// leaves it out are recorded but not shown
+    pub machine_role: String,
+    /// Groups this agent belongs to, e.g. `ops`; alerts targeting other groups
+    /// and hosts are dropped
+    pub groups: Vec<String>,
+    /// Language tag, e.g. `fr-CA`, picking which of an alert's translations
+    /// its toast shows; the alert's own text when `None`
+    pub locale: Option<String>,
+    /// Show a generic toast pointing to a supervisor in place of alerts hidden by role
+    pub hidden_alert_placeholder: bool,
+    pub text_limits: TextLimits,
+    /// Alerts buffered ahead of the handler before the lowest-priority one is shed
+    pub alert_queue_capacity: usize,
+    /// Alerts acted on per minute before the rest are shed
+    pub alert_rate: RateLimitConfig,
+    /// Messages held for the server before the lowest-priority one is dropped
+    pub outbound_queue_capacity: usize,
+    /// Initial values for settings that can change at runtime
+    pub settings: AgentSettings,
+    /// How the wait between reconnect attempts grows from the settings' reconnect delay
+    pub reconnect_backoff: BackoffConfig,
+    /// How long the server may stay silent, even to a ping, before its connection is dropped
+    pub server_timeout: Duration,
+    /// Heartbeats in a row a server that acknowledges them may leave unacknowledged before reconnecting
+    pub heartbeat_missed_acks: u32,
+    /// Unreadable messages in a row from the server before reconnecting; fewer are reported and skipped
+    pub max_unreadable_messages: u32,
+    /// Limit on reaching each of the server's addresses before trying the next
+    pub connect_timeout: Duration,
+    /// Largest message accepted from the server; a larger one drops the connection
+    pub max_receive_message_bytes: usize,
+    /// Largest single frame accepted from the server; a larger one drops the connection
+    pub max_receive_frame_bytes: usize,
+    /// Offer the server permessage-deflate compression; it may decline
+    pub ws_compression: bool,
+    /// Longest one frame may take to write before the connection is dropped and what it carried is resent
+    pub send_timeout: Duration,
+    /// How long the server may take to acknowledge a registration before the connection is dropped
+    pub register_timeout: Duration,
+    /// How long the server may be unreachable before the user is told by toast,
+    /// and told again once it is back; no toasts when `None`
+    pub connection_notice_after: Option<Duration>,
+    /// Local HTTP listener; disabled when `None`
+    pub http_api: Option<HttpApiConfig>,
+    /// Signed alerts received over UDP multicast; disabled when `None`
+    pub multicast: Option<MulticastConfig>,
+    /// Alarm panel lit over a serial port while urgent alerts await confirmation; disabled when `None`
+    pub annunciator: Option<AnnunciatorConfig>,
+    /// JSONL file every frame exchanged with the server is copied to; disabled when `None`
+    pub wire_capture: Option<WireCaptureConfig>,
+    /// Download limits and retention for alert attachments
+    pub attachments: AttachmentConfig,
+    /// Download limits and cache eviction for images shown in toasts
+    pub images: ImageConfig,
+    /// Hosts alerts' confirm callbacks may go to; none by default
+    pub callbacks: CallbackConfig,
+    /// Ask for an operator id at each confirmation, for shared consoles; disabled when `None`
+    pub operator_identity: Option<OperatorIdConfig>,
+    /// Personal webhook reminded of urgent alerts left unconfirmed; disabled when `None`
+    pub reminder: Option<ReminderConfig>,
+    /// File processed alerts are appended to; history is kept in memory only when `None`
+    pub history_file: Option<PathBuf>,
+    /// How much of the history, and the attachments it references, is kept
+    pub retention: RetentionConfig,
+    /// File suppression windows are saved in; kept in memory only when `None`
+    pub suppression_file: Option<PathBuf>,
+    /// File how each run ended is kept in, for the next to report; not kept when `None`
+    pub shutdown_file: Option<PathBuf>,
+    /// File the newest alert received is kept in, for the server to replay
+    /// from after a restart; not kept when `None`
+    pub watermark_file: Option<PathBuf>,
+    /// Let suppression windows that list Emergency silence Emergency alerts
+    pub allow_emergency_suppression: bool,
+    /// Longest an unconfirmed Emergency alert keeps the display awake
+    pub display_wake_cap: Duration,
+    /// How long past the auto-confirm timeout an idle machine may hold an alert; disabled when `None`
+    pub idle_auto_confirm_extension: Option<Duration>,
+    /// Stop the auto-confirm countdown while the workstation is locked
+    pub pause_auto_confirm_while_locked: bool,
+    /// What becomes of a superseded alert still awaiting confirmation
+    pub superseded_pending: SupersedePolicy,
+    /// Alert categories with settings of their own; the rest are handled as sent
+    pub categories: Categories,
+    /// Louder sounds for alerts left unconfirmed, per level
+    pub escalation: EscalationPolicy,
+    /// Toast scenario, duration and popup for each level; alerts can override them
+    pub toast_styles: ToastStyles,
+    /// Summarize bursts of low-severity toasts; disabled when `None`
+    pub burst: Option<BurstConfig>,
+    /// Keep alerts a server sends again silent; disabled when `None`
+    pub dedup: Option<DedupConfig>,
+    /// Watch the alert pipeline for stalls; disabled when `None`
+    pub watchdog: Option<WatchdogConfig>,
+    /// Spool for export what cannot reach the server; disabled when `None`
+    pub offline: Option<OfflineConfig>,
+    /// Download, verify and stage signed agent releases; disabled when `None`
+    pub update: Option<UpdateConfig>,
+    /// Show alerts locally or forward them to per-session helpers
+    pub session_mode: SessionMode,
+    /// Named pipe session helpers connect to in broker mode
+    pub session_pipe_name: String,
+    /// How long startup waits for the network and audio before going on without them
+    pub startup_wait: Duration,
+}
+
+impl Config {
+    /// Configuration with default directories and limits, without touching the environment or disk
+    pub fn new(server_url: impl Into<String>, client_id: impl Into<String>) -> Self {
+        Self {
+            server_url: server_url.into(),
+            fallback_server_urls: Vec::new(),
+            primary_retry: DEFAULT_PRIMARY_RETRY,
+            server_discovery: ServerDiscovery::Static,
+            standby_server_url: None,
+            tls: TlsConfig::default(),
+            auth_token: None,
+            client_id: client_id.into(),
+            sounds_dir: PathBuf::from("./sounds"),
+            data_dir: PathBuf::from("./data"),
+            dpapi_scope: DpapiScope::User,
+            alert_key: None,
+            alert_hmac_key: None,
+            location: None,
+            machine_role: DEFAULT_MACHINE_ROLE.to_string(),
+            groups: Vec::new(),
+            locale: None,
+            hidden_alert_placeholder: false,
+            text_limits: TextLimits::default(),
+            alert_queue_capacity: DEFAULT_ALERT_QUEUE_CAPACITY,
+            alert_rate: RateLimitConfig::default(),
+            outbound_queue_capacity: DEFAULT_OUTBOUND_CAPACITY,
+            settings: AgentSettings::default(),
+            reconnect_backoff: BackoffConfig::default(),
+            server_timeout: DEFAULT_SERVER_TIMEOUT,
+            heartbeat_missed_acks: DEFAULT_HEARTBEAT_MISSED_ACKS,
+            max_unreadable_messages: DEFAULT_MAX_UNREADABLE_MESSAGES,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_receive_message_bytes: DEFAULT_MAX_RECEIVE_MESSAGE_BYTES,
+            max_receive_frame_bytes: DEFAULT_MAX_RECEIVE_FRAME_BYTES,
+            ws_compression: false,
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+            register_timeout: DEFAULT_REGISTER_TIMEOUT,
+            connection_notice_after: None,
+            http_api: None,
+            multicast: None,
+            annunciator: None,
+            wire_capture: None,
+            attachments: AttachmentConfig::default(),
+            images: ImageConfig::default(),
+            callbacks: CallbackConfig::default(),
+            operator_identity: None,
+            reminder: None,
+            history_file: None,
+            retention: RetentionConfig::default(),
+            suppression_file: None,
+            shutdown_file: None,
+            watermark_file: None,
+            allow_emergency_suppression: false,
+            display_wake_cap: DEFAULT_DISPLAY_WAKE_CAP,
+            idle_auto_confirm_extension: None,
+            pause_auto_confirm_while_locked: false,
+            superseded_pending: SupersedePolicy::default(),
+            categories: Categories::default(),
+            escalation: EscalationPolicy::default(),
+            toast_styles: ToastStyles::default(),
+            burst: Some(BurstConfig::default()),
+            dedup: Some(DedupConfig::default()),
+            watchdog: Some(WatchdogConfig::default()),
+            offline: None,
+            update: None,
+            session_mode: SessionMode::Standalone,
+            session_pipe_name: DEFAULT_PIPE_NAME.to_string(),
+            startup_wait: DEFAULT_STARTUP_WAIT,
+        }
+    }
+
+    /// Read the configuration from environment variables, creating directories as needed
+    pub fn from_env() -> Result<Self> {
+        let mut configured_urls: Vec<String> = server_urls_from_env();
+        let configured_url: Option<String> =
+            (!configured_urls.is_empty()).then(|| configured_urls.remove(0));
+        let server_url: String = configured_url
+            .clone()
+            .unwrap_or_else(|| "ws://localhost:8080/ws".to_string());
+        let server_discovery: ServerDiscovery = match std::env::var("SERVER_DISCOVERY") {
+            Ok(value) => match value.trim().to_ascii_lowercase().as_str() {
+                "static" => ServerDiscovery::Static,
+                "dns" => ServerDiscovery::Dns {
+                    domain: std::env::var("SERVER_DISCOVERY_DOMAIN")
+                        .ok()
+                        .filter(|d| !d.trim().is_empty())
+                        .ok_or_else(|| {
+                            EmnsError::config(
+                                "SERVER_DISCOVERY_DOMAIN",
+                                "required when SERVER_DISCOVERY is dns",
+                            )
+                        })?,
+                    fallback_url: configured_url,
+                },
+                _ => {
+                    return Err(EmnsError::config(
+                        "SERVER_DISCOVERY",
+                        format!("expected static or dns, got {}", value),
+                    ))
+                }
+            },
+            Err(_) => ServerDiscovery::Static,
+        };
+
+        let data_dir: PathBuf = std::env::var("DATA_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("./data"));
+
+        let dpapi_scope: DpapiScope = match std::env::var("DPAPI_SCOPE") {
+            Ok(value) => DpapiScope::parse(&value).ok_or_else(|| {
+                EmnsError::config(
+                    "DPAPI_SCOPE",
+                    format!("expected machine or user, got {}", value),
+                )
+            })?,
+            Err(_) => DpapiScope::User,
+        };
+
+        let store: StateStore =
+            StateStore::new(data_dir.clone(), storage::platform_protector(dpapi_scope))?;
+        // An explicit CLIENT_ID wins; otherwise reuse the identity persisted in the data dir
+        let client_id: String = match std::env::var("CLIENT_ID") {
+            Ok(id) => id,
+            Err(_) => storage::load_or_create_client_id(&store)?,
+        };
+        let alert_key: AlertKey = storage::load_or_create_alert_key(&store)?;
+
+        let sounds_dir: PathBuf = std::env::var("SOUNDS_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("./sounds"));
+
+        let defaults: TextLimits = TextLimits::default();
+        let text_limits: TextLimits = TextLimits {
+            max_title_chars: env_usize("MAX_TITLE_CHARS").unwrap_or(defaults.max_title_chars),
+            max_message_chars: env_usize("MAX_MESSAGE_CHARS").unwrap_or(defaults.max_message_chars),
+        };
+
+        let alert_queue_capacity: usize =
+            env_usize("ALERT_QUEUE_CAPACITY").unwrap_or(DEFAULT_ALERT_QUEUE_CAPACITY);
+        // A rate of zero would shed everything, so it is raised to one per minute
+        let alert_rate: RateLimitConfig = RateLimitConfig {
+            per_minute: env_usize("ALERT_RATE_PER_MINUTE")
+                .map_or(DEFAULT_ALERT_RATE_PER_MINUTE, |n| {
+                    n.clamp(1, u32::MAX as usize) as u32
+                }),
+            urgent_per_minute: env_usize("URGENT_ALERT_RATE_PER_MINUTE")
+                .map_or(DEFAULT_URGENT_ALERT_RATE_PER_MINUTE, |n| {
+                    n.clamp(1, u32::MAX as usize) as u32
+                }),
+        };
+        let outbound_queue_capacity: usize =
+            env_usize("OUTBOUND_QUEUE_CAPACITY").unwrap_or(DEFAULT_OUTBOUND_CAPACITY);
+        if std::env::var_os("CONFIRMATION_QUEUE_CAPACITY").is_some() {
+            log::warn!(
+                "CONFIRMATION_QUEUE_CAPACITY is no longer used; confirmations share OUTBOUND_QUEUE_CAPACITY"
+            );
+        }
+
+        let http_api: Option<HttpApiConfig> = match std::env::var("HTTP_LISTEN") {
+            Ok(value) => {
+                let listen: SocketAddr = value
+                    .trim()
+                    .parse()
+                    .map_err(|e| EmnsError::config("HTTP_LISTEN", format!("{}: {}", value, e)))?;
+                Some(HttpApiConfig {
+                    listen,
+                    local_alert_token: std::env::var("LOCAL_ALERT_TOKEN")
+                        .ok()
+                        .filter(|t| !t.is_empty()),
+                    forward_local_alerts: env_bool("FORWARD_LOCAL_ALERTS")?.unwrap_or(true),
+                    max_body_bytes: env_usize("HTTP_MAX_BODY_BYTES")
+                        .unwrap_or(DEFAULT_MAX_BODY_BYTES),
+                    board_recent: env_usize("HTTP_BOARD_RECENT_HOURS")
+                        .map(|hours| Duration::from_secs(hours as u64 * 3600))
+                        .unwrap_or(DEFAULT_RECENT_WINDOW),
+                    board_origins: std::env::var("HTTP_BOARD_ORIGINS")
+                        .map(|origins| {
+                            origins
+                                .split(',')
+                                .map(str::trim)
+                                .filter(|o| !o.is_empty())
+                                .map(String::from)
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                })
+            }
+            Err(_) => None,
+        };
+
+        let display_wake_cap: Duration = env_usize("DISPLAY_WAKE_CAP_SECS")
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(DEFAULT_DISPLAY_WAKE_CAP);
+
+        let session_mode: SessionMode = match std::env::var("SESSION_MODE") {
+            Ok(value) => SessionMode::parse(&value).ok_or_else(|| {
+                EmnsError::config(
+                    "SESSION_MODE",
+                    format!("expected standalone or broker, got {}", value),
+                )
+            })?,
+            Err(_) => SessionMode::Standalone,
+        };
+
+        let superseded_pending: SupersedePolicy = match std::env::var("SUPERSEDED_PENDING") {
+            Ok(value) => SupersedePolicy::parse(&value).ok_or_else(|| {
+                EmnsError::config(
+                    "SUPERSEDED_PENDING",
+                    format!("expected transfer or cancel, got {}", value),
+                )
+            })?,
+            Err(_) => SupersedePolicy::default(),
+        };
+
+        let mut settings: AgentSettings = AgentSettings::default();
+        settings.set_server_identity(
+            std::env::var("SERVER_DISPLAY_NAME").ok(),
+            std::env::var("SERVER_ENVIRONMENT").ok(),
+        );
+        if let Ok(value) = std::env::var("CONFIRM_TIMEOUT_SECS") {
+            settings
+                .set_auto_confirm_timeout(parse_confirm_timeout(&value)?)
+                .map_err(|e| EmnsError::config("CONFIRM_TIMEOUT_SECS", e.to_string()))?;
+        }
+        if let Some(secs) = env_usize("RECONNECT_DELAY_SECS") {
+            settings
+                .set_reconnect_delay(Duration::from_secs(secs as u64))
+                .map_err(|e| EmnsError::config("RECONNECT_DELAY_SECS", e.to_string()))?;
+        }
+
+        // Create sounds directory if it doesn't exist
+        if !sounds_dir.exists() {
+            std::fs::create_dir_all(&sounds_dir).map_err(|e| {
+                EmnsError::storage(
+                    Some(&sounds_dir),
+                    format!("Failed to create sounds directory: {}", e),
+                )
+            })?;
+            log::info!("Created sounds directory: {}", sounds_dir.display());
+        }
+
+        Ok(Self {
+            server_url,
+            fallback_server_urls: configured_urls,
+            primary_retry: env_usize("PRIMARY_RETRY_SECS")
+                .map(|secs| Duration::from_secs(secs as u64))
+                .unwrap_or(DEFAULT_PRIMARY_RETRY),
+            server_discovery,
+            standby_server_url: std::env::var("STANDBY_SERVER_URL")
+                .ok()
+                .filter(|url| !url.trim().is_empty()),
+            tls: tls_from_env()?,
+            auth_token: auth_token_from_env()?,
+            client_id,
+            sounds_dir,
+            dpapi_scope,
+            alert_key: Some(alert_key),
+            alert_hmac_key: std::env::var("ALERT_HMAC_KEY")
+                .ok()
+                .filter(|key| !key.is_empty())
+                .map(SigningKey::new),
+            location: location_from_env(),
+            machine_role: machine_role_from_env()?,
+            groups: groups_from_env(),
+            locale: locale_from_env()?,
+            hidden_alert_placeholder: env_bool("HIDDEN_ALERT_PLACEHOLDER")?.unwrap_or(false),
+            text_limits,
+            alert_queue_capacity,
+            alert_rate,
+            outbound_queue_capacity,
+            settings,
+            reconnect_backoff: reconnect_backoff_from_env()?,
+            server_timeout: env_usize("SERVER_TIMEOUT_SECS")
+                .map(|secs| Duration::from_secs(secs as u64))
+                .unwrap_or(DEFAULT_SERVER_TIMEOUT),
+            heartbeat_missed_acks: env_usize("HEARTBEAT_MISSED_ACKS")
+                .map(|missed| missed as u32)
+                .unwrap_or(DEFAULT_HEARTBEAT_MISSED_ACKS),
+            max_unreadable_messages: env_usize("MAX_UNREADABLE_MESSAGES")
+                .map(|max| max as u32)
+                .unwrap_or(DEFAULT_MAX_UNREADABLE_MESSAGES),
+            connect_timeout: env_usize("CONNECT_TIMEOUT_SECS")
+                .map(|secs| Duration::from_secs(secs as u64))
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            max_receive_message_bytes: env_usize("MAX_RECEIVE_MESSAGE_BYTES")
+                .unwrap_or(DEFAULT_MAX_RECEIVE_MESSAGE_BYTES),
+            max_receive_frame_bytes: env_usize("MAX_RECEIVE_FRAME_BYTES")
+                .unwrap_or(DEFAULT_MAX_RECEIVE_FRAME_BYTES),
+            ws_compression: env_bool("WS_COMPRESSION")?.unwrap_or(false),
+            send_timeout: env_usize("SEND_TIMEOUT_SECS")
+                .map(|secs| Duration::from_secs(secs as u64))
+                .unwrap_or(DEFAULT_SEND_TIMEOUT),
+            register_timeout: env_usize("REGISTER_TIMEOUT_SECS")
+                .map(|secs| Duration::from_secs(secs as u64))
+                .unwrap_or(DEFAULT_REGISTER_TIMEOUT),
+            connection_notice_after: env_usize("CONNECTION_NOTICE_AFTER_SECS")
+                .map(|secs| Duration::from_secs(secs as u64)),
+            http_api,
+            multicast: multicast_from_env()?,
+            annunciator: annunciator_from_env()?,
+            wire_capture: wire_capture_from_env()?,
+            attachments: attachments_from_env(),
+            images: images_from_env(),
+            callbacks: callbacks_from_env(),
+            operator_identity: operator_identity_from_env(&data_dir)?,
+            reminder: reminder_from_env()?,
+            history_file: Some(data_dir.join(HISTORY_FILE)),
+            retention: retention_from_env(),
+            suppression_file: Some(data_dir.join(SUPPRESSION_FILE)),
+            shutdown_file: Some(data_dir.join(SHUTDOWN_FILE)),
+            watermark_file: Some(data_dir.join(WATERMARK_FILE)),
+            allow_emergency_suppression: env_bool("ALLOW_EMERGENCY_SUPPRESSION")?.unwrap_or(false),
+            display_wake_cap,
+            idle_auto_confirm_extension: env_usize("IDLE_AUTO_CONFIRM_EXTENSION_SECS")
+                .map(|secs| Duration::from_secs(secs as u64)),
+            pause_auto_confirm_while_locked: env_bool("PAUSE_AUTO_CONFIRM_WHILE_LOCKED")?
+                .unwrap_or(false),
+            superseded_pending,
+            categories: categories_from_env()?,
+            escalation: escalation_from_env(),
+            toast_styles: toast_styles_from_env()?,
+            burst: burst_from_env()?,
+            dedup: dedup_from_env()?,
+            watchdog: watchdog_from_env()?,
+            offline: offline_from_env(),
+            update: update_from_env()?,
+            session_mode,
+            session_pipe_name: std::env::var("SESSION_PIPE_NAME")
+                .unwrap_or_else(|_| DEFAULT_PIPE_NAME.to_string()),
+            startup_wait: startup_wait_from_env()?,
+            data_dir,
+        })
+    }
+
+    /// The server URL startup waits to reach; `None` with DNS discovery and no fallback
+    pub fn startup_server_url(&self) -> Option<&str> {
+        match &self.server_discovery {
+            ServerDiscovery::Static => Some(&self.server_url),
+            ServerDiscovery::Dns { fallback_url, .. } => fallback_url.as_deref(),
+        }
+    }
+
+    /// Where the agent looks for the server, for logs and the startup toast
+    pub fn server_description(&self) -> String {
+        match &self.server_discovery {
+            ServerDiscovery::Static if self.fallback_server_urls.is_empty() => {
+                self.server_url.clone()
+            }
+            ServerDiscovery::Static => format!(
+                "{} (falling back to {})",
+                self.server_url,
+                self.fallback_server_urls.join(", ")
+            ),
+            ServerDiscovery::Dns { domain, .. } => format!("servers listed in DNS for {}", domain),
+        }
+    }
+}
+
+/// Servers from `SERVER_URLS`, a comma-separated list with the primary first,
+/// or else the one in `SERVER_URL`; empty when neither is set
+fn server_urls_from_env() -> Vec<String> {
+    let urls: Vec<String> = std::env::var("SERVER_URLS")
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    if !urls.is_empty() {
+        return urls;
+    }
+    std::env::var("SERVER_URL").ok().into_iter().collect()
+}
+
+/// Read the machine's location from `LOCATION_*`, or `None` when none are set
+fn location_from_env() -> Option<Location> {
+    let field = |name: &str| {
+        std::env::var(name)
+            .map(|value| LocationField::from(value.as_str()))
+            .unwrap_or_default()
+    };
+    let location: Location = Location {
+        site: field("LOCATION_SITE"),
+        building: field("LOCATION_BUILDING"),
+        floor: field("LOCATION_FLOOR"),
+        room: field("LOCATION_ROOM"),
+    };
+    (!location.is_any()).then_some(location)
+}
+
+/// Read the machine's role from `MACHINE_ROLE`, lowercased; [`DEFAULT_MACHINE_ROLE`] when unset
+fn machine_role_from_env() -> Result<String> {
+    let Some(role) = std::env::var("MACHINE_ROLE")
+        .ok()
+        .map(|role| role.trim().to_ascii_lowercase())
+        .filter(|role| !role.is_empty())
+    else {
+        return Ok(DEFAULT_MACHINE_ROLE.to_string());
+    };
+    if !role
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(EmnsError::config(
+            "MACHINE_ROLE",
+            format!("{} may only hold letters, digits, - and _", role),
+        ));
+    }
+    Ok(role)
+}
+
+/// The locale from `LOCALE`, or the system's display language when unset
+fn locale_from_env() -> Result<Option<String>> {
+    match std::env::var("LOCALE") {
+        Ok(tag) if !tag.trim().is_empty() => match locale::parse_tag(&tag) {
+            Some(tag) => Ok(Some(tag)),
+            None => Err(EmnsError::config(
+                "LOCALE",
+                format!("{} is not a language tag such as fr-CA", tag),
+            )),
+        },
+        _ => Ok(locale::system_locale()),
+    }
+}
+
+/// Groups from `GROUPS`, a comma-separated list; empty when unset
+fn groups_from_env() -> Vec<String> {
+    std::env::var("GROUPS")
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|group| !group.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Read the multicast fallback settings, or `None` when `MULTICAST_GROUP` is unset.
+///
+/// The listener never accepts unsigned alerts, so a group without a key is an error.
+fn multicast_from_env() -> Result<Option<MulticastConfig>> {
+    let Ok(group) = std::env::var("MULTICAST_GROUP") else {
+        return Ok(None);
+    };
+    let group: Ipv4Addr = group
+        .trim()
+        .parse()
+        .map_err(|e| EmnsError::config("MULTICAST_GROUP", format!("{}: {}", group, e)))?;
+    let key: String = std::env::var("MULTICAST_KEY")
+        .ok()
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| {
+            EmnsError::config("MULTICAST_KEY", "required when MULTICAST_GROUP is set")
+        })?;
+    let port: u16 = match std::env::var("MULTICAST_PORT") {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|e| EmnsError::config("MULTICAST_PORT", format!("{}: {}", value, e)))?,
+        Err(_) => DEFAULT_MULTICAST_PORT,
+    };
+    let interface: Ipv4Addr = match std::env::var("MULTICAST_INTERFACE") {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|e| EmnsError::config("MULTICAST_INTERFACE", format!("{}: {}", value, e)))?,
+        Err(_) => Ipv4Addr::UNSPECIFIED,
+    };
+    Ok(Some(MulticastConfig {
+        group,
+        port,
+        interface,
+        key: SigningKey::new(key),
+    }))
+}
+
+/// Read the alarm panel settings, or `None` when `ANNUNCIATOR_PORT` is unset
+fn annunciator_from_env() -> Result<Option<AnnunciatorConfig>> {
+    let Some(port) = std::env::var("ANNUNCIATOR_PORT")
+        .ok()
+        .filter(|port| !port.trim().is_empty())
+    else {
+        return Ok(None);
+    };
+    let mut config: AnnunciatorConfig = AnnunciatorConfig::new(port.trim());
+    config.baud = match std::env::var("ANNUNCIATOR_BAUD") {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|e| EmnsError::config("ANNUNCIATOR_BAUD", format!("{}: {}", value, e)))?,
+        Err(_) => DEFAULT_ANNUNCIATOR_BAUD,
+    };
+    if let Ok(light) = std::env::var("ANNUNCIATOR_LIGHT_SEQUENCE") {
+        config.light = SequenceTemplate::parse(&light)
+            .map_err(|e| EmnsError::config("ANNUNCIATOR_LIGHT_SEQUENCE", e))?;
+    }
+    if let Ok(clear) = std::env::var("ANNUNCIATOR_CLEAR_SEQUENCE") {
+        config.clear = SequenceTemplate::parse(&clear)
+            .map_err(|e| EmnsError::config("ANNUNCIATOR_CLEAR_SEQUENCE", e))?;
+    }
+    Ok(Some(config))
+}
+
+/// Read frame capture settings, or `None` when `WIRE_CAPTURE` is unset
+fn wire_capture_from_env() -> Result<Option<WireCaptureConfig>> {
+    let Some(path) = std::env::var("WIRE_CAPTURE")
+        .ok()
+        .filter(|path| !path.trim().is_empty())
+    else {
+        return Ok(None);
+    };
+    let mut config: WireCaptureConfig = WireCaptureConfig::new(path.trim());
+    if let Some(bytes) = env_usize("WIRE_CAPTURE_MAX_FILE_BYTES") {
+        config.max_file_bytes = bytes as u64;
+    }
+    if let Some(bytes) = env_usize("WIRE_CAPTURE_MAX_TOTAL_BYTES") {
+        config.max_total_bytes = bytes as u64;
+    }
+    if config.max_total_bytes < config.max_file_bytes {
+        return Err(EmnsError::config(
+            "WIRE_CAPTURE_MAX_TOTAL_BYTES",
+            format!(
+                "{} is less than WIRE_CAPTURE_MAX_FILE_BYTES ({})",
+                config.max_total_bytes, config.max_file_bytes
+            ),
+        ));
+    }
+    config.redact = env_bool("WIRE_CAPTURE_REDACT")?.unwrap_or(false);
+    Ok(Some(config))
+}
+
+/// Read attachment limits from `ATTACHMENT_*`, using defaults for anything unset
+pub(crate) fn attachments_from_env() -> AttachmentConfig {
+    let defaults: AttachmentConfig = AttachmentConfig::default();
+    AttachmentConfig {
+        max_bytes: env_usize("ATTACHMENT_MAX_BYTES")
+            .map(|bytes| bytes as u64)
+            .unwrap_or(defaults.max_bytes),
+        timeout: env_usize("ATTACHMENT_TIMEOUT_SECS")
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(defaults.timeout),
+        retention: env_usize("ATTACHMENT_RETENTION_DAYS")
+            .map(|days| Duration::from_secs(days as u64 * 24 * 60 * 60))
+            .unwrap_or(defaults.retention),
+    }
+}
+
+/// Read toast image limits from `IMAGE_*`, using defaults for anything unset
+pub(crate) fn images_from_env() -> ImageConfig {
+    let defaults: ImageConfig = ImageConfig::default();
+    ImageConfig {
+        max_bytes: env_usize("IMAGE_MAX_BYTES")
+            .map(|bytes| bytes as u64)
+            .unwrap_or(defaults.max_bytes),
+        timeout: env_usize("IMAGE_TIMEOUT_SECS")
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(defaults.timeout),
+        wait: env_usize("IMAGE_WAIT_MS")
+            .map(|ms| Duration::from_millis(ms as u64))
+            .unwrap_or(defaults.wait),
+        retention: env_usize("IMAGE_RETENTION_DAYS")
+            .map(|days| Duration::from_secs(days as u64 * 24 * 60 * 60))
+            .unwrap_or(defaults.retention),
+        max_cache_bytes: env_usize("IMAGE_CACHE_MAX_BYTES")
+            .map(|bytes| bytes as u64)
+            .unwrap_or(defaults.max_cache_bytes),
+    }
+}
+
+/// Read the confirm callback allow-list from `CONFIRM_CALLBACK_DOMAINS` (comma separated)
+/// and its timeout from `CONFIRM_CALLBACK_TIMEOUT_SECS`
+pub(crate) fn callbacks_from_env() -> CallbackConfig {
+    let defaults: CallbackConfig = CallbackConfig::default();
+    CallbackConfig {
+        allowed_domains: std::env::var("CONFIRM_CALLBACK_DOMAINS")
+            .map(|domains| {
+                domains
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|d| !d.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or(defaults.allowed_domains),
+        timeout: env_usize("CONFIRM_CALLBACK_TIMEOUT_SECS")
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(defaults.timeout),
+    }
+}
+
+/// Read the operator id prompt settings, or `None` unless `CONFIRMATION_IDENTITY` is prompt.
+///
+/// The last id is remembered in the data directory for `OPERATOR_ID_REMEMBER_MINS`.
+pub(crate) fn operator_identity_from_env(data_dir: &Path) -> Result<Option<OperatorIdConfig>> {
+    match std::env::var("CONFIRMATION_IDENTITY") {
+        Ok(value) => match value.trim().to_ascii_lowercase().as_str() {
+            "session" => return Ok(None),
+            "prompt" => {}
+            _ => {
+                return Err(EmnsError::config(
+                    "CONFIRMATION_IDENTITY",
+                    format!("expected session or prompt, got {}", value),
+                ))
+            }
+        },
+        Err(_) => return Ok(None),
+    }
+    let mut config: OperatorIdConfig = OperatorIdConfig::new();
+    if let Ok(pattern) = std::env::var("OPERATOR_ID_PATTERN") {
+        config.pattern = Regex::new(&pattern)
+            .map_err(|e| EmnsError::config("OPERATOR_ID_PATTERN", e.to_string()))?;
+    }
+    // Zero is allowed here: it turns the prefill off
+    if let Ok(value) = std::env::var("OPERATOR_ID_REMEMBER_MINS") {
+        let mins: u64 = value.trim().parse().map_err(|e| {
+            EmnsError::config("OPERATOR_ID_REMEMBER_MINS", format!("{}: {}", value, e))
+        })?;
+        config.remember = Duration::from_secs(mins * 60);
+    }
+    config.file = Some(data_dir.join(OPERATOR_FILE));
+    Ok(Some(config))
+}
+
+/// Read TLS trust from `TLS_CA_FILE` and `TLS_INSECURE_SKIP_VERIFY`, checking the bundle loads
+fn tls_from_env() -> Result<TlsConfig> {
+    let tls: TlsConfig = TlsConfig {
+        ca_file: std::env::var("TLS_CA_FILE")
+            .ok()
+            .filter(|path| !path.trim().is_empty())
+            .map(PathBuf::from),
+        insecure_skip_verify: env_bool("TLS_INSECURE_SKIP_VERIFY")?.unwrap_or(false),
+    };
+    tls.connector()?;
+    Ok(tls)
+}
+
+/// Read the token sent to the server from `AUTH_TOKEN`; `None` when unset or blank
+fn auth_token_from_env() -> Result<Option<AuthToken>> {
+    std::env::var("AUTH_TOKEN")
+        .ok()
+        .filter(|token| !token.trim().is_empty())
+        .map(|token| AuthToken::new(token.trim()))
+        .transpose()
+}
+
+/// Read the startup wait from `STARTUP_WAIT_SECS`; zero starts without waiting
+fn startup_wait_from_env() -> Result<Duration> {
+    match std::env::var("STARTUP_WAIT_SECS") {
+        Ok(value) => {
+            let secs: u64 = value
+                .trim()
+                .parse()
+                .map_err(|e| EmnsError::config("STARTUP_WAIT_SECS", format!("{}: {}", value, e)))?;
+            Ok(Duration::from_secs(secs))
+        }
+        Err(_) => Ok(DEFAULT_STARTUP_WAIT),
+    }
+}
+
+/// Read the reconnect backoff from `RECONNECT_BACKOFF_MULTIPLIER`, `RECONNECT_MAX_DELAY_SECS`,
+/// `RECONNECT_JITTER_PERCENT` and `RECONNECT_RESET_AFTER_SECS`
+pub(crate) fn reconnect_backoff_from_env() -> Result<BackoffConfig> {
+    let defaults: BackoffConfig = BackoffConfig::default();
+    let multiplier: f64 = match std::env::var("RECONNECT_BACKOFF_MULTIPLIER") {
+        Ok(value) => value
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|m| m.is_finite() && *m >= 1.0)
+            .ok_or_else(|| {
+                EmnsError::config(
+                    "RECONNECT_BACKOFF_MULTIPLIER",
+                    format!("expected a number of at least 1.0, got {}", value),
+                )
+            })?,
+        Err(_) => defaults.multiplier,
+    };
+    let jitter_percent: u32 = match env_usize("RECONNECT_JITTER_PERCENT") {
+        Some(percent) if percent > 100 => {
+            return Err(EmnsError::config(
+                "RECONNECT_JITTER_PERCENT",
+                format!("must be between 0 and 100, got {}", percent),
+            ))
+        }
+        Some(percent) => percent as u32,
+        None => defaults.jitter_percent,
+    };
+    Ok(BackoffConfig {
+        multiplier,
+        max_delay: env_usize("RECONNECT_MAX_DELAY_SECS")
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(defaults.max_delay),
+        jitter_percent,
+        reset_after: env_usize("RECONNECT_RESET_AFTER_SECS")
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(defaults.reset_after),
+    })
+}
+
+/// Read history retention from `HISTORY_RETENTION_DAYS` and `HISTORY_MAX_ENTRIES`
+pub(crate) fn retention_from_env() -> RetentionConfig {
+    let defaults: RetentionConfig = RetentionConfig::default();
+    RetentionConfig {
+        history_max_age: env_usize("HISTORY_RETENTION_DAYS")
+            .map(|days| Duration::from_secs(days as u64 * 24 * 60 * 60))
+            .unwrap_or(defaults.history_max_age),
+        history_max_entries: env_usize("HISTORY_MAX_ENTRIES")
+            .unwrap_or(defaults.history_max_entries),
+    }
+}
+
+/// Read the known alert categories from `CATEGORIES`, each with its
+/// `CATEGORY_<NAME>_SOUND`, `CATEGORY_<NAME>_REQUIRE_CONFIRMATION` and
+/// `CATEGORY_<NAME>_EXERCISE`. `<NAME>` is the category in capitals, with
+/// anything but letters and digits as `_`. Only `exercise` when unset.
+pub(crate) fn categories_from_env() -> Result<Categories> {
+    let names: String =
+        std::env::var("CATEGORIES").unwrap_or_else(|_| EXERCISE_CATEGORY.to_string());
+    let mut categories: Vec<CategoryConfig> = Vec::new();
+    for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let key: String = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let mut category: CategoryConfig = CategoryConfig::new(name);
+        category.sound_file = std::env::var(format!("CATEGORY_{}_SOUND", key))
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+        category.requires_confirmation =
+            env_bool(&format!("CATEGORY_{}_REQUIRE_CONFIRMATION", key))?;
+        if let Some(exercise) = env_bool(&format!("CATEGORY_{}_EXERCISE", key))? {
+            category.exercise = exercise;
+        }
+        categories.push(category);
+    }
+    Ok(Categories::new(categories))
+}
+
+/// Read each level's escalation from `ESCALATION_<LEVEL>_SOUND` and `ESCALATION_<LEVEL>_AFTER_SECS`,
+/// and repeats from `ESCALATION_REPEAT_INTERVAL_SECS` and `ESCALATION_REPEAT_MAX`.
+///
+/// A level escalates only when its sound is set.
+pub(crate) fn escalation_from_env() -> EscalationPolicy {
+    let level = |name: &str| {
+        let sound_file: String = std::env::var(format!("ESCALATION_{}_SOUND", name))
+            .ok()
+            .filter(|s| !s.trim().is_empty())?;
+        Some(Escalation {
+            sound_file,
+            after: env_usize(&format!("ESCALATION_{}_AFTER_SECS", name))
+                .map(|secs| Duration::from_secs(secs as u64))
+                .unwrap_or(DEFAULT_ESCALATION_AFTER),
+        })
+    };
+    EscalationPolicy {
+        info: level("INFO"),
+        warning: level("WARNING"),
+        critical: level("CRITICAL"),
+        emergency: level("EMERGENCY"),
+        repeats: Repeats {
+            interval: env_usize("ESCALATION_REPEAT_INTERVAL_SECS")
+                .filter(|secs| *secs > 0)
+                .map(|secs| Duration::from_secs(secs as u64))
+                .unwrap_or(DEFAULT_REPEAT_INTERVAL),
+            max: env_usize("ESCALATION_REPEAT_MAX").map_or(0, |max| max as u32),
+        },
+    }
+}
+
+/// Read each level's toast style from `TOAST_<LEVEL>_SCENARIO`, `TOAST_<LEVEL>_DURATION`
+/// and `TOAST_<LEVEL>_SUPPRESS_POPUP`, keeping the built-in default for anything unset
+pub(crate) fn toast_styles_from_env() -> Result<ToastStyles> {
+    let mut styles: ToastStyles = ToastStyles::default();
+    for (name, level) in [
+        ("INFO", AlertLevel::Info),
+        ("WARNING", AlertLevel::Warning),
+        ("CRITICAL", AlertLevel::Critical),
+        ("EMERGENCY", AlertLevel::Emergency),
+    ] {
+        let style = styles.for_level_mut(&level);
+        let key: String = format!("TOAST_{}_SCENARIO", name);
+        if let Ok(value) = std::env::var(&key) {
+            style.scenario = ToastScenario::parse(&value).ok_or_else(|| {
+                EmnsError::config(
+                    &key,
+                    format!(
+                        "expected default, alarm, reminder, incomingCall or urgent, got {}",
+                        value
+                    ),
+                )
+            })?;
+        }
+        let key: String = format!("TOAST_{}_DURATION", name);
+        if let Ok(value) = std::env::var(&key) {
+            style.duration = ToastDuration::parse(&value).ok_or_else(|| {
+                EmnsError::config(&key, format!("expected short or long, got {}", value))
+            })?;
+        }
+        if let Some(suppress_popup) = env_bool(&format!("TOAST_{}_SUPPRESS_POPUP", name))? {
+            style.suppress_popup = suppress_popup;
+        }
+    }
+    Ok(styles)
+}
+
+/// Read burst coalescing from `BURST_*`, or `None` when `BURST_COALESCING` is false
+pub(crate) fn burst_from_env() -> Result<Option<BurstConfig>> {
+    if env_bool("BURST_COALESCING")? == Some(false) {
+        return Ok(None);
+    }
+    let defaults: BurstConfig = BurstConfig::default();
+    Ok(Some(BurstConfig {
+        threshold: env_usize("BURST_THRESHOLD").unwrap_or(defaults.threshold),
+        window: env_usize("BURST_WINDOW_SECS")
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(defaults.window),
+        include_warning: env_bool("BURST_INCLUDE_WARNING")?.unwrap_or(defaults.include_warning),
+    }))
+}
+
+/// Read alert deduplication from `DEDUP_*`, or `None` when `DEDUP_ALERTS` is false
+pub(crate) fn dedup_from_env() -> Result<Option<DedupConfig>> {
+    if env_bool("DEDUP_ALERTS")? == Some(false) {
+        return Ok(None);
+    }
+    let defaults: DedupConfig = DedupConfig::default();
+    Ok(Some(DedupConfig {
+        window: env_usize("DEDUP_WINDOW_SECS")
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(defaults.window),
+        capacity: env_usize("DEDUP_CACHE_SIZE")
+            .filter(|size| *size > 0)
+            .unwrap_or(defaults.capacity),
+    }))
+}
+
+/// Read the pipeline watchdog from `PIPELINE_*`, or `None` when `PIPELINE_WATCHDOG` is false
+pub(crate) fn watchdog_from_env() -> Result<Option<WatchdogConfig>> {
+    if env_bool("PIPELINE_WATCHDOG")? == Some(false) {
+        return Ok(None);
+    }
+    let defaults: WatchdogConfig = WatchdogConfig::default();
+    Ok(Some(WatchdogConfig {
+        stall_after: env_usize("PIPELINE_STALL_SECS")
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(defaults.stall_after),
+        restart: env_bool("PIPELINE_RESTART_ON_STALL")?.unwrap_or(defaults.restart),
+    }))
+}
+
+/// Read offline export from `OFFLINE_EXPORT_*`, or `None` when `OFFLINE_EXPORT_KEY` is unset
+pub(crate) fn offline_from_env() -> Option<OfflineConfig> {
+    let key: String = std::env::var("OFFLINE_EXPORT_KEY")
+        .ok()
+        .filter(|key| !key.is_empty())?;
+    let mut config: OfflineConfig = OfflineConfig::new(SigningKey::new(key));
+    if let Some(secs) = env_usize("OFFLINE_EXPORT_AFTER_SECS") {
+        config.after = Duration::from_secs(secs as u64);
+    }
+    Some(config)
+}
+
+/// Read self-update from `UPDATE_*`, or `None` when `UPDATE_PUBLIC_KEY` is unset
+pub(crate) fn update_from_env() -> Result<Option<UpdateConfig>> {
+    let Some(key) = std::env::var("UPDATE_PUBLIC_KEY")
+        .ok()
+        .filter(|key| !key.trim().is_empty())
+    else {
+        return Ok(None);
+    };
+    let mut config: UpdateConfig = UpdateConfig::new(update::parse_public_key(&key)?);
+    if let Ok(url) = std::env::var("UPDATE_MANIFEST_URL") {
+        if !url.starts_with("https://") {
+            return Err(EmnsError::config(
+                "UPDATE_MANIFEST_URL",
+                format!("{} is not an HTTPS URL", url),
+            ));
+        }
+        config.manifest_url = Some(url);
+    }
+    if let Some(minutes) = env_usize("UPDATE_CHECK_INTERVAL_MINS") {
+        config.check_interval = Duration::from_secs(minutes as u64 * 60);
+    }
+    if let Ok(window) = std::env::var("UPDATE_RESTART_WINDOW") {
+        config.restart_window = Some(RestartWindow::parse(&window).ok_or_else(|| {
+            EmnsError::config(
+                "UPDATE_RESTART_WINDOW",
+                format!("expected HH:MM-HH:MM, got {}", window),
+            )
+        })?);
+    }
+    Ok(Some(config))
+}
+
+/// Read confirmation reminders from `REMINDER_*`, or `None` when `REMINDER_WEBHOOK_URL` is unset
+pub(crate) fn reminder_from_env() -> Result<Option<ReminderConfig>> {
+    let Some(url) = std::env::var("REMINDER_WEBHOOK_URL")
+        .ok()
+        .filter(|url| !url.trim().is_empty())
+    else {
+        return Ok(None);
+    };
+    if !url.starts_with("https://") {
+        return Err(EmnsError::config(
+            "REMINDER_WEBHOOK_URL",
+            format!("{} is not an HTTPS URL", url),
+        ));
+    }
+    let mut config: ReminderConfig = ReminderConfig::new(url);
+    if let Some(secs) = env_usize("REMINDER_AFTER_SECS") {
+        config.after = Duration::from_secs(secs as u64);
+    }
+    config.include_body = env_bool("REMINDER_INCLUDE_BODY")?.unwrap_or(false);
+    Ok(Some(config))
+}
+
+/// `CONFIRM_TIMEOUT_SECS` as seconds, or `None` for `0`, `none` or `never`:
+/// alerts then wait until someone confirms them
+fn parse_confirm_timeout(value: &str) -> Result<Option<Duration>> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "0" | "none" | "never" => Ok(None),
+        secs => secs
+            .parse()
+            .map(|secs| Some(Duration::from_secs(secs)))
+            .map_err(|_| {
+                EmnsError::config(
+                    "CONFIRM_TIMEOUT_SECS",
+                    format!("expected seconds, none or never, got {}", value),
+                )
+            }),
+    }
+}
+
+/// Read a positive integer from the environment
+fn env_usize(name: &str) -> Option<usize> {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|n: &usize| *n > 0)
+}
+
+/// Read a true/false flag from the environment
+pub(crate) fn env_bool(name: &str) -> Result<Option<bool>> {
+    match std::env::var(name) {
+        Ok(value) => match value.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" => Ok(Some(true)),
+            "0" | "false" | "no" => Ok(Some(false)),
+            _ => Err(EmnsError::config(
+                name,
+                format!("expected true or false, got {}", value),
+            )),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Serializes tests that mutate the process environment
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    #[allow(clippy::len_zero)]
+    fn test_config_defaults() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::remove_var("SERVER_URL");
+        std::env::remove_var("SERVER_URLS");
+        std::env::remove_var("PRIMARY_RETRY_SECS");
+        std::env::remove_var("AUTH_TOKEN");
+        std::env::remove_var("CLIENT_ID");
+        std::env::remove_var("SOUNDS_DIR");
+        std::env::remove_var("DATA_DIR");
+        std::env::remove_var("DPAPI_SCOPE");
+        std::env::remove_var("MAX_TITLE_CHARS");
+        std::env::remove_var("MAX_MESSAGE_CHARS");
+        std::env::remove_var("ALERT_QUEUE_CAPACITY");
+        std::env::remove_var("OUTBOUND_QUEUE_CAPACITY");
+        std::env::remove_var("GROUPS");
+        std::env::remove_var("ALERT_HMAC_KEY");
+        std::env::remove_var("HTTP_LISTEN");
+        std::env::remove_var("SERVER_TIMEOUT_SECS");
+        std::env::remove_var("HEARTBEAT_MISSED_ACKS");
+        std::env::remove_var("MAX_UNREADABLE_MESSAGES");
+        std::env::remove_var("CONNECT_TIMEOUT_SECS");
+        std::env::remove_var("MAX_RECEIVE_MESSAGE_BYTES");
+        std::env::remove_var("MAX_RECEIVE_FRAME_BYTES");
+        std::env::remove_var("WS_COMPRESSION");
+        std::env::remove_var("SEND_TIMEOUT_SECS");
+        std::env::remove_var("REGISTER_TIMEOUT_SECS");
+        std::env::remove_var("CONNECTION_NOTICE_AFTER_SECS");
+        std::env::remove_var("SUPERSEDED_PENDING");
+        for name in [
+            "LOCATION_SITE",
+            "LOCATION_BUILDING",
+            "LOCATION_FLOOR",
+            "LOCATION_ROOM",
+        ] {
+            std::env::remove_var(name);
+        }
+
+        let config: Config = Config::from_env().unwrap();
+        assert_eq!(config.server_url, "ws://localhost:8080/ws");
+        assert!(config.fallback_server_urls.is_empty());
+        assert_eq!(config.primary_retry, DEFAULT_PRIMARY_RETRY);
+        assert!(config.auth_token.is_none());
+        assert!(config.client_id.len() > 0);
+        assert!(config.groups.is_empty());
+        assert!(config.alert_hmac_key.is_none());
+        assert_eq!(config.sounds_dir, PathBuf::from("./sounds"));
+        assert_eq!(config.data_dir, PathBuf::from("./data"));
+        assert_eq!(config.dpapi_scope, DpapiScope::User);
+        assert_eq!(config.text_limits, TextLimits::default());
+        assert_eq!(config.alert_queue_capacity, DEFAULT_ALERT_QUEUE_CAPACITY);
+        assert_eq!(config.outbound_queue_capacity, DEFAULT_OUTBOUND_CAPACITY);
+        assert!(config.http_api.is_none());
+        assert!(config.location.is_none());
+        assert_eq!(config.server_timeout, DEFAULT_SERVER_TIMEOUT);
+        assert_eq!(config.heartbeat_missed_acks, DEFAULT_HEARTBEAT_MISSED_ACKS);
+        assert_eq!(
+            config.max_unreadable_messages,
+            DEFAULT_MAX_UNREADABLE_MESSAGES
+        );
+        assert_eq!(config.connect_timeout, DEFAULT_CONNECT_TIMEOUT);
+        assert_eq!(
+            config.max_receive_message_bytes,
+            DEFAULT_MAX_RECEIVE_MESSAGE_BYTES
+        );
+        assert_eq!(
+            config.max_receive_frame_bytes,
+            DEFAULT_MAX_RECEIVE_FRAME_BYTES
+        );
+        assert!(!config.ws_compression);
+        assert_eq!(config.send_timeout, DEFAULT_SEND_TIMEOUT);
+        assert_eq!(config.register_timeout, DEFAULT_REGISTER_TIMEOUT);
+        assert!(config.connection_notice_after.is_none());
+        assert_eq!(config.superseded_pending, SupersedePolicy::Transfer);
+        assert_eq!(
+            config.history_file,
+            Some(PathBuf::from("./data").join(HISTORY_FILE))
+        );
+        assert_eq!(
+            config.shutdown_file,
+            Some(PathBuf::from("./data").join(SHUTDOWN_FILE))
+        );
+        assert_eq!(
+            config.watermark_file,
+            Some(PathBuf::from("./data").join(WATERMARK_FILE))
+        );
+    }
+
+    #[test]
+    fn test_invalid_dpapi_scope_is_config_error() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::set_var("DPAPI_SCOPE", "everyone");
+        let result: Result<Config> = Config::from_env();
+        std::env::remove_var("DPAPI_SCOPE");
+
+        match result.unwrap_err() {
+            EmnsError::Config { key, .. } => assert_eq!(key, "DPAPI_SCOPE"),
+            other => panic!("expected config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_superseded_pending_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::set_var("SUPERSEDED_PENDING", "Cancel");
+        let cancel: Result<Config> = Config::from_env();
+        std::env::set_var("SUPERSEDED_PENDING", "replace");
+        let invalid: Result<Config> = Config::from_env();
+        std::env::remove_var("SUPERSEDED_PENDING");
+
+        assert_eq!(cancel.unwrap().superseded_pending, SupersedePolicy::Cancel);
+        match invalid.unwrap_err() {
+            EmnsError::Config { key, .. } => assert_eq!(key, "SUPERSEDED_PENDING"),
+            other => panic!("expected config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_confirm_timeout_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let timeout = |value: &str| -> Result<Option<Duration>> {
+            std::env::set_var("CONFIRM_TIMEOUT_SECS", value);
+            let config: Result<Config> = Config::from_env();
+            std::env::remove_var("CONFIRM_TIMEOUT_SECS");
+            config.map(|config| config.settings.auto_confirm_timeout())
+        };
+        let defaults: Option<Duration> =
+            Config::from_env().unwrap().settings.auto_confirm_timeout();
+
+        assert_eq!(defaults, Some(Duration::from_secs(300)));
+        assert_eq!(timeout(" 90 ").unwrap(), Some(Duration::from_secs(90)));
+        for never in ["0", "none", "Never"] {
+            assert_eq!(timeout(never).unwrap(), None, "{}", never);
+        }
+        for invalid in ["soon", "-5", "90000"] {
+            match timeout(invalid).unwrap_err() {
+                EmnsError::Config { key, .. } => assert_eq!(key, "CONFIRM_TIMEOUT_SECS"),
+                other => panic!("expected config error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_location_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::set_var("LOCATION_BUILDING", "C");
+        std::env::set_var("LOCATION_FLOOR", "3");
+        let location: Option<Location> = location_from_env();
+        std::env::remove_var("LOCATION_BUILDING");
+        std::env::remove_var("LOCATION_FLOOR");
+
+        let location: Location = location.unwrap();
+        assert_eq!(location.building.values(), ["C"]);
+        assert_eq!(location.floor.values(), ["3"]);
+        assert!(location.site.is_any());
+        assert!(location_from_env().is_none());
+    }
+
+    #[test]
+    fn test_tls_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let unset: TlsConfig = tls_from_env().unwrap();
+        let ca_file: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls/ca.pem");
+        std::env::set_var("TLS_CA_FILE", ca_file);
+        let set: TlsConfig = tls_from_env().unwrap();
+        std::env::set_var(
+            "TLS_CA_FILE",
+            concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"),
+        );
+        let not_pem: Result<TlsConfig> = tls_from_env();
+        std::env::set_var("TLS_CA_FILE", "missing-ca.pem");
+        let missing: Result<TlsConfig> = tls_from_env();
+        std::env::remove_var("TLS_CA_FILE");
+        std::env::set_var("TLS_INSECURE_SKIP_VERIFY", "true");
+        let insecure: TlsConfig = tls_from_env().unwrap();
+        std::env::remove_var("TLS_INSECURE_SKIP_VERIFY");
+
+        assert_eq!(unset, TlsConfig::default());
+        assert_eq!(set.ca_file, Some(PathBuf::from(ca_file)));
+        assert!(!set.insecure_skip_verify);
+        assert!(insecure.insecure_skip_verify);
+        for result in [not_pem, missing] {
+            match result.unwrap_err() {
+                EmnsError::Config { key, .. } => assert_eq!(key, "TLS_CA_FILE"),
+                other => panic!("expected config error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_startup_wait_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let unset: Duration = startup_wait_from_env().unwrap();
+        std::env::set_var("STARTUP_WAIT_SECS", "0");
+        let off: Duration = startup_wait_from_env().unwrap();
+        std::env::set_var("STARTUP_WAIT_SECS", "soon");
+        let invalid: Result<Duration> = startup_wait_from_env();
+        std::env::remove_var("STARTUP_WAIT_SECS");
+
+        assert_eq!(unset, DEFAULT_STARTUP_WAIT);
+        assert_eq!(off, Duration::ZERO);
+        match invalid.unwrap_err() {
+            EmnsError::Config { key, .. } => assert_eq!(key, "STARTUP_WAIT_SECS"),
+            other => panic!("expected config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reconnect_backoff_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let unset: BackoffConfig = reconnect_backoff_from_env().unwrap();
+        std::env::set_var("RECONNECT_BACKOFF_MULTIPLIER", "1.5");
+        std::env::set_var("RECONNECT_MAX_DELAY_SECS", "120");
+        std::env::set_var("RECONNECT_JITTER_PERCENT", "25");
+        std::env::set_var("RECONNECT_RESET_AFTER_SECS", "30");
+        let set: BackoffConfig = reconnect_backoff_from_env().unwrap();
+        std::env::set_var("RECONNECT_BACKOFF_MULTIPLIER", "0.5");
+        let shrinking: Result<BackoffConfig> = reconnect_backoff_from_env();
+        std::env::remove_var("RECONNECT_BACKOFF_MULTIPLIER");
+        std::env::set_var("RECONNECT_JITTER_PERCENT", "150");
+        let wild: Result<BackoffConfig> = reconnect_backoff_from_env();
+        std::env::remove_var("RECONNECT_MAX_DELAY_SECS");
+        std::env::remove_var("RECONNECT_JITTER_PERCENT");
+        std::env::remove_var("RECONNECT_RESET_AFTER_SECS");
+
+        assert_eq!(unset, BackoffConfig::default());
+        assert_eq!(
+            set,
+            BackoffConfig {
+                multiplier: 1.5,
+                max_delay: Duration::from_secs(120),
+                jitter_percent: 25,
+                reset_after: Duration::from_secs(30),
+            }
+        );
+        for (result, expected) in [
+            (shrinking, "RECONNECT_BACKOFF_MULTIPLIER"),
+            (wild, "RECONNECT_JITTER_PERCENT"),
+        ] {
+            match result.unwrap_err() {
+                EmnsError::Config { key, .. } => assert_eq!(key, expected),
+                other => panic!("expected config error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_auth_token_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::remove_var("AUTH_TOKEN");
+        let unset: Option<AuthToken> = auth_token_from_env().unwrap();
+        std::env::set_var("AUTH_TOKEN", " s3cret ");
+        let set: Option<AuthToken> = auth_token_from_env().unwrap();
+        std::env::set_var("AUTH_TOKEN", "line\nbreak");
+        let invalid: Result<Option<AuthToken>> = auth_token_from_env();
+        std::env::remove_var("AUTH_TOKEN");
+
+        assert!(unset.is_none());
+        let set: AuthToken = set.unwrap();
+        assert_eq!(set, AuthToken::new("s3cret").unwrap());
+        assert!(!format!("{:?}", set).contains("s3cret"));
+        match invalid.unwrap_err() {
+            EmnsError::Config { key, .. } => assert_eq!(key, "AUTH_TOKEN"),
+            other => panic!("expected config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_server_urls_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::remove_var("SERVER_URLS");
+        std::env::set_var("SERVER_URL", "ws://single:8080/ws");
+        let single: Vec<String> = server_urls_from_env();
+        std::env::set_var(
+            "SERVER_URLS",
+            " ws://primary:8080/ws, ,ws://backup-1:8080/ws,ws://backup-2:8080/ws ",
+        );
+        let listed: Vec<String> = server_urls_from_env();
+        std::env::set_var("SERVER_URLS", " , ");
+        let blank: Vec<String> = server_urls_from_env();
+        std::env::remove_var("SERVER_URLS");
+        std::env::remove_var("SERVER_URL");
+        let unset: Vec<String> = server_urls_from_env();
+
+        assert_eq!(single, ["ws://single:8080/ws"]);
+        // The list wins over SERVER_URL, primary first
+        assert_eq!(
+            listed,
+            [
+                "ws://primary:8080/ws",
+                "ws://backup-1:8080/ws",
+                "ws://backup-2:8080/ws"
+            ]
+        );
+        assert_eq!(blank, ["ws://single:8080/ws"]);
+        assert!(unset.is_empty());
+    }
+
+    #[test]
+    fn test_categories_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let unset: Categories = categories_from_env().unwrap();
+        std::env::set_var("CATEGORIES", "Force Protection, weather,exercise");
+        std::env::set_var("CATEGORY_FORCE_PROTECTION_SOUND", "fpcon.wav");
+        std::env::set_var("CATEGORY_WEATHER_REQUIRE_CONFIRMATION", "false");
+        std::env::set_var("CATEGORY_EXERCISE_EXERCISE", "no");
+        let set: Categories = categories_from_env().unwrap();
+        std::env::set_var("CATEGORY_WEATHER_REQUIRE_CONFIRMATION", "sometimes");
+        let invalid: Result<Categories> = categories_from_env();
+        for key in [
+            "CATEGORIES",
+            "CATEGORY_FORCE_PROTECTION_SOUND",
+            "CATEGORY_WEATHER_REQUIRE_CONFIRMATION",
+            "CATEGORY_EXERCISE_EXERCISE",
+        ] {
+            std::env::remove_var(key);
+        }
+
+        assert_eq!(unset, Categories::default());
+        assert_eq!(set.names(), ["Force Protection", "weather", "exercise"]);
+        let force_protection: &CategoryConfig = set.get("force protection").unwrap();
+        assert_eq!(force_protection.sound_file.as_deref(), Some("fpcon.wav"));
+        assert_eq!(force_protection.requires_confirmation, None);
+        assert_eq!(
+            set.get("weather").unwrap().requires_confirmation,
+            Some(false)
+        );
+        assert!(!set.get("exercise").unwrap().exercise);
+        match invalid.unwrap_err() {
+            EmnsError::Config { key, .. } => {
+                assert_eq!(key, "CATEGORY_WEATHER_REQUIRE_CONFIRMATION")
+            }
+            other => panic!("expected config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_groups_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::set_var("GROUPS", " ops, ,Night-Shift ");
+        let groups: Vec<String> = groups_from_env();
+        std::env::remove_var("GROUPS");
+        let unset: Vec<String> = groups_from_env();
+
+        assert_eq!(groups, ["ops", "Night-Shift"]);
+        assert!(unset.is_empty());
+    }
+
+    #[test]
+    fn test_machine_role_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let unset: String = machine_role_from_env().unwrap();
+        std::env::set_var("MACHINE_ROLE", " Signage ");
+        let set: String = machine_role_from_env().unwrap();
+        std::env::set_var("MACHINE_ROLE", "lobby screen");
+        let invalid: Result<String> = machine_role_from_env();
+        std::env::remove_var("MACHINE_ROLE");
+
+        assert_eq!(unset, DEFAULT_MACHINE_ROLE);
+        assert_eq!(set, "signage");
+        match invalid.unwrap_err() {
+            EmnsError::Config { key, .. } => assert_eq!(key, "MACHINE_ROLE"),
+            other => panic!("expected config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_locale_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::set_var("LOCALE", "fr_CA");
+        let set: Option<String> = locale_from_env().unwrap();
+        std::env::set_var("LOCALE", "français");
+        let invalid: Result<Option<String>> = locale_from_env();
+        std::env::remove_var("LOCALE");
+
+        assert_eq!(set.as_deref(), Some("fr-CA"));
+        match invalid.unwrap_err() {
+            EmnsError::Config { key, .. } => assert_eq!(key, "LOCALE"),
+            other => panic!("expected config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_multicast_requires_a_key() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        assert!(multicast_from_env().unwrap().is_none());
+
+        std::env::set_var("MULTICAST_GROUP", "239.255.40.1");
+        let missing_key: Result<Option<MulticastConfig>> = multicast_from_env();
+        std::env::set_var("MULTICAST_KEY", "site-secret");
+        std::env::set_var("MULTICAST_PORT", "45401");
+        let configured: Result<Option<MulticastConfig>> = multicast_from_env();
+        for name in ["MULTICAST_GROUP", "MULTICAST_KEY", "MULTICAST_PORT"] {
+            std::env::remove_var(name);
+        }
+
+        assert!(matches!(
+            missing_key,
+            Err(EmnsError::Config { ref key, .. }) if key == "MULTICAST_KEY"
+        ));
+        let configured: MulticastConfig = configured.unwrap().unwrap();
+        assert_eq!(configured.group, Ipv4Addr::new(239, 255, 40, 1));
+        assert_eq!(configured.port, 45401);
+        assert_eq!(configured.interface, Ipv4Addr::UNSPECIFIED);
+    }
+
+    #[test]
+    fn test_annunciator_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        assert!(annunciator_from_env().unwrap().is_none());
+
+        std::env::set_var("ANNUNCIATOR_PORT", "COM4");
+        std::env::set_var("ANNUNCIATOR_LIGHT_SEQUENCE", r"\x02L{level}\x03");
+        let configured: Result<Option<AnnunciatorConfig>> = annunciator_from_env();
+        std::env::set_var("ANNUNCIATOR_CLEAR_SEQUENCE", r"\x0");
+        let bad_clear: Result<Option<AnnunciatorConfig>> = annunciator_from_env();
+        for name in [
+            "ANNUNCIATOR_PORT",
+            "ANNUNCIATOR_LIGHT_SEQUENCE",
+            "ANNUNCIATOR_CLEAR_SEQUENCE",
+        ] {
+            std::env::remove_var(name);
+        }
+
+        let configured: AnnunciatorConfig = configured.unwrap().unwrap();
+        assert_eq!(configured.port, "COM4");
+        assert_eq!(configured.baud, DEFAULT_ANNUNCIATOR_BAUD);
+        assert_eq!(
+            configured
+                .light
+                .render(&crate::messages::AlertLevel::Critical),
+            b"\x02LCritical\x03"
+        );
+        assert!(matches!(
+            bad_clear,
+            Err(EmnsError::Config { ref key, .. }) if key == "ANNUNCIATOR_CLEAR_SEQUENCE"
+        ));
+    }
+
+    #[test]
+    fn test_wire_capture_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        assert!(wire_capture_from_env().unwrap().is_none());
+
+        std::env::set_var("WIRE_CAPTURE", "C:\\emns\\wire.jsonl");
+        let defaults: Result<Option<WireCaptureConfig>> = wire_capture_from_env();
+        std::env::set_var("WIRE_CAPTURE_MAX_FILE_BYTES", "1000");
+        std::env::set_var("WIRE_CAPTURE_MAX_TOTAL_BYTES", "5000");
+        std::env::set_var("WIRE_CAPTURE_REDACT", "yes");
+        let configured: Result<Option<WireCaptureConfig>> = wire_capture_from_env();
+        std::env::set_var("WIRE_CAPTURE_MAX_TOTAL_BYTES", "500");
+        let below_file: Result<Option<WireCaptureConfig>> = wire_capture_from_env();
+        for name in [
+            "WIRE_CAPTURE",
+            "WIRE_CAPTURE_MAX_FILE_BYTES",
+            "WIRE_CAPTURE_MAX_TOTAL_BYTES",
+            "WIRE_CAPTURE_REDACT",
+        ] {
+            std::env::remove_var(name);
+        }
+
+        assert_eq!(
+            defaults.unwrap(),
+            Some(WireCaptureConfig::new("C:\\emns\\wire.jsonl"))
+        );
+        let configured: WireCaptureConfig = configured.unwrap().unwrap();
+        assert_eq!(configured.max_file_bytes, 1000);
+        assert_eq!(configured.max_total_bytes, 5000);
+        assert!(configured.redact);
+        assert!(matches!(
+            below_file,
+            Err(EmnsError::Config { ref key, .. }) if key == "WIRE_CAPTURE_MAX_TOTAL_BYTES"
+        ));
+    }
+
+    #[test]
+    fn test_toast_styles_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        assert_eq!(toast_styles_from_env().unwrap(), ToastStyles::default());
+
+        std::env::set_var("TOAST_WARNING_DURATION", "short");
+        std::env::set_var("TOAST_INFO_SUPPRESS_POPUP", "true");
+        std::env::set_var("TOAST_EMERGENCY_SCENARIO", "incomingCall");
+        let configured: Result<ToastStyles> = toast_styles_from_env();
+        std::env::set_var("TOAST_CRITICAL_SCENARIO", "klaxon");
+        let unknown: Result<ToastStyles> = toast_styles_from_env();
+        for name in [
+            "TOAST_WARNING_DURATION",
+            "TOAST_INFO_SUPPRESS_POPUP",
+            "TOAST_EMERGENCY_SCENARIO",
+            "TOAST_CRITICAL_SCENARIO",
+        ] {
+            std::env::remove_var(name);
+        }
+
+        let configured: ToastStyles = configured.unwrap();
+        assert_eq!(configured.warning.duration, ToastDuration::Short);
+        assert_eq!(configured.warning.scenario, ToastScenario::Reminder);
+        assert!(configured.info.suppress_popup);
+        assert_eq!(configured.emergency.scenario, ToastScenario::IncomingCall);
+        assert!(matches!(
+            unknown,
+            Err(EmnsError::Config { ref key, .. }) if key == "TOAST_CRITICAL_SCENARIO"
+        ));
+    }
+
+    #[test]
+    fn test_watchdog_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        assert_eq!(
+            watchdog_from_env().unwrap(),
+            Some(WatchdogConfig::default())
+        );
+
+        std::env::set_var("PIPELINE_STALL_SECS", "30");
+        std::env::set_var("PIPELINE_RESTART_ON_STALL", "true");
+        let configured: Result<Option<WatchdogConfig>> = watchdog_from_env();
+        std::env::set_var("PIPELINE_WATCHDOG", "false");
+        let disabled: Result<Option<WatchdogConfig>> = watchdog_from_env();
+        for name in [
+            "PIPELINE_STALL_SECS",
+            "PIPELINE_RESTART_ON_STALL",
+            "PIPELINE_WATCHDOG",
+        ] {
+            std::env::remove_var(name);
+        }
+
+        assert_eq!(
+            configured.unwrap(),
+            Some(WatchdogConfig {
+                stall_after: Duration::from_secs(30),
+                restart: true,
+            })
+        );
+        assert_eq!(disabled.unwrap(), None);
+    }
+
+    #[test]
+    fn test_offline_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        assert!(offline_from_env().is_none());
+
+        std::env::set_var("OFFLINE_EXPORT_KEY", "site-secret");
+        let defaults: Option<OfflineConfig> = offline_from_env();
+        std::env::set_var("OFFLINE_EXPORT_AFTER_SECS", "300");
+        let configured: Option<OfflineConfig> = offline_from_env();
+        std::env::remove_var("OFFLINE_EXPORT_KEY");
+        std::env::remove_var("OFFLINE_EXPORT_AFTER_SECS");
+
+        assert_eq!(
+            defaults.unwrap().after,
+            crate::offline::DEFAULT_OFFLINE_AFTER
+        );
+        assert_eq!(configured.unwrap().after, Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_callbacks_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        assert!(callbacks_from_env().allowed_domains.is_empty());
+
+        std::env::set_var(
+            "CONFIRM_CALLBACK_DOMAINS",
+            " hooks.example.com, ,ops.example.org ",
+        );
+        std::env::set_var("CONFIRM_CALLBACK_TIMEOUT_SECS", "3");
+        let configured: CallbackConfig = callbacks_from_env();
+        std::env::remove_var("CONFIRM_CALLBACK_DOMAINS");
+        std::env::remove_var("CONFIRM_CALLBACK_TIMEOUT_SECS");
+
+        assert_eq!(
+            configured.allowed_domains,
+            ["hooks.example.com", "ops.example.org"]
+        );
+        assert_eq!(configured.timeout, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_operator_identity_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let data_dir: &Path = Path::new("data");
+        assert!(operator_identity_from_env(data_dir).unwrap().is_none());
+
+        std::env::set_var("CONFIRMATION_IDENTITY", "Prompt");
+        let defaults: OperatorIdConfig = operator_identity_from_env(data_dir).unwrap().unwrap();
+        std::env::set_var("OPERATOR_ID_PATTERN", r"^\d{6}$");
+        std::env::set_var("OPERATOR_ID_REMEMBER_MINS", "0");
+        let configured: OperatorIdConfig = operator_identity_from_env(data_dir).unwrap().unwrap();
+        std::env::set_var("OPERATOR_ID_PATTERN", "(unclosed");
+        let bad_pattern: Result<Option<OperatorIdConfig>> = operator_identity_from_env(data_dir);
+        std::env::set_var("CONFIRMATION_IDENTITY", "badge");
+        let bad_mode: Result<Option<OperatorIdConfig>> = operator_identity_from_env(data_dir);
+        std::env::remove_var("CONFIRMATION_IDENTITY");
+        std::env::remove_var("OPERATOR_ID_PATTERN");
+        std::env::remove_var("OPERATOR_ID_REMEMBER_MINS");
+
+        assert_eq!(
+            defaults.remember,
+            crate::operator::DEFAULT_OPERATOR_REMEMBER
+        );
+        assert_eq!(defaults.file, Some(data_dir.join(OPERATOR_FILE)));
+        assert!(defaults.validate("B-10442").is_some());
+        assert!(configured.validate("104420").is_some());
+        assert!(configured.validate("B-10442").is_none());
+        assert_eq!(configured.remember, Duration::ZERO);
+        assert!(bad_pattern.is_err());
+        assert!(bad_mode.is_err());
+    }
+
+    #[test]
+    fn test_reminder_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        assert!(reminder_from_env().unwrap().is_none());
+
+        std::env::set_var("REMINDER_WEBHOOK_URL", "https://ntfy.example.com/jsmith");
+        let defaults: Option<ReminderConfig> = reminder_from_env().unwrap();
+        std::env::set_var("REMINDER_AFTER_SECS", "120");
+        std::env::set_var("REMINDER_INCLUDE_BODY", "yes");
+        let configured: Option<ReminderConfig> = reminder_from_env().unwrap();
+        std::env::set_var("REMINDER_WEBHOOK_URL", "http://ntfy.example.com/jsmith");
+        let plain_http: Result<Option<ReminderConfig>> = reminder_from_env();
+        for name in [
+            "REMINDER_WEBHOOK_URL",
+            "REMINDER_AFTER_SECS",
+            "REMINDER_INCLUDE_BODY",
+        ] {
+            std::env::remove_var(name);
+        }
+
+        let defaults: ReminderConfig = defaults.unwrap();
+        assert_eq!(defaults.after, crate::reminder::DEFAULT_REMINDER_AFTER);
+        assert!(!defaults.include_body);
+        let configured: ReminderConfig = configured.unwrap();
+        assert_eq!(configured.webhook_url, "https://ntfy.example.com/jsmith");
+        assert_eq!(configured.after, Duration::from_secs(120));
+        assert!(configured.include_body);
+        match plain_http.unwrap_err() {
+            EmnsError::Config { key, .. } => assert_eq!(key, "REMINDER_WEBHOOK_URL"),
+            other => panic!("expected config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_dedup_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let defaults: Option<DedupConfig> = dedup_from_env().unwrap();
+        std::env::set_var("DEDUP_WINDOW_SECS", "30");
+        std::env::set_var("DEDUP_CACHE_SIZE", "50");
+        let configured: Option<DedupConfig> = dedup_from_env().unwrap();
+        std::env::set_var("DEDUP_ALERTS", "false");
+        let disabled: Option<DedupConfig> = dedup_from_env().unwrap();
+        for name in ["DEDUP_ALERTS", "DEDUP_WINDOW_SECS", "DEDUP_CACHE_SIZE"] {
+            std::env::remove_var(name);
+        }
+
+        assert_eq!(defaults, Some(DedupConfig::default()));
+        assert_eq!(
+            configured,
+            Some(DedupConfig {
+                window: Duration::from_secs(30),
+                capacity: 50,
+            })
+        );
+        assert_eq!(disabled, None);
+    }
+
+    #[test]
+    fn test_escalation_repeats_from_env() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let defaults: Repeats = escalation_from_env().repeats;
+        std::env::set_var("ESCALATION_REPEAT_INTERVAL_SECS", "90");
+        std::env::set_var("ESCALATION_REPEAT_MAX", "4");
+        let configured: Repeats = escalation_from_env().repeats;
+        std::env::set_var("ESCALATION_REPEAT_INTERVAL_SECS", "0");
+        let zero_interval: Repeats = escalation_from_env().repeats;
+        std::env::remove_var("ESCALATION_REPEAT_INTERVAL_SECS");
+        std::env::remove_var("ESCALATION_REPEAT_MAX");
+
+        // Off unless a count is set
+        assert_eq!(defaults, Repeats::default());
+        assert_eq!(defaults.max, 0);
+        assert_eq!(configured.interval, Duration::from_secs(90));
+        assert_eq!(configured.max, 4);
+        assert_eq!(zero_interval.interval, DEFAULT_REPEAT_INTERVAL);
+    }
+
+    #[test]
+    fn test_update_from_env() {
+        use base64::Engine;
+        let _guard = ENV_LOCK.lock().unwrap();
+        assert!(update_from_env().unwrap().is_none());
+
+        let key: String = base64::engine::general_purpose::STANDARD.encode(
+            ed25519_dalek::SigningKey::from_bytes(&[7; 32])
+                .verifying_key()
+                .as_bytes(),
+        );
+        std::env::set_var("UPDATE_PUBLIC_KEY", &key);
+        std::env::set_var(
+            "UPDATE_MANIFEST_URL",
+            "https://updates.example.com/agent.json",
+        );
+        std::env::set_var("UPDATE_CHECK_INTERVAL_MINS", "60");
+        std::env::set_var("UPDATE_RESTART_WINDOW", "02:00-04:00");
+        let configured: Result<Option<UpdateConfig>> = update_from_env();
+        std::env::set_var(
+            "UPDATE_MANIFEST_URL",
+            "http://updates.example.com/agent.json",
+        );
+        let plain_http: Result<Option<UpdateConfig>> = update_from_env();
+        std::env::remove_var("UPDATE_MANIFEST_URL");
+        std::env::set_var("UPDATE_PUBLIC_KEY", "c2hvcnQ=");
+        let bad_key: Result<Option<UpdateConfig>> = update_from_env();
+        for name in [
+            "UPDATE_PUBLIC_KEY",
+            "UPDATE_CHECK_INTERVAL_MINS",
+            "UPDATE_RESTART_WINDOW",
+        ] {
+            std::env::remove_var(name);
+        }
+
+        let configured: UpdateConfig = configured.unwrap().unwrap();
+        assert_eq!(
+            configured.manifest_url.as_deref(),
+            Some("https://updates.example.com/agent.json")
+        );
+        assert_eq!(configured.check_interval, Duration::from_secs(3600));
+        assert_eq!(
+            configured.restart_window,
+            RestartWindow::parse("02:00-04:00")
+        );
+        for result in [plain_http, bad_key] {
+            assert!(matches!(result, Err(EmnsError::Config { .. })));
+        }
+    }
+}
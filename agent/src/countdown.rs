@@ -0,0 +1,176 @@
+//! The "Confirm within 4:32" line on toasts awaiting confirmation
+
+use std::time::Duration;
+
+/// How often pending toasts have their countdown refreshed
+pub const COUNTDOWN_REFRESH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Name of the toast binding the countdown text is written to
+pub const COUNTDOWN_BINDING: &str = "countdownText";
+
+/// Time left to confirm an alert
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Countdown {
+    Remaining(Duration),
+    /// Past the deadline, with the alert held because nobody is at the machine
+    Overdue,
+}
+
+impl Countdown {
+    /// Countdown to `deadline`, as seen at `now`
+    pub fn until(deadline: tokio::time::Instant, now: tokio::time::Instant) -> Self {
+        if now >= deadline {
+            Countdown::Overdue
+        } else {
+            Countdown::Remaining(deadline - now)
+        }
+    }
+
+    pub fn is_overdue(&self) -> bool {
+        matches!(self, Countdown::Overdue)
+    }
+
+    /// Text in the user's language
+    pub fn text(&self) -> String {
+        self.text_in(CountdownStrings::for_language(&system_language()))
+    }
+
+    /// `m:ss` (or `h:mm:ss`) down to a minute, then "<1 min"
+    pub fn text_in(&self, strings: &CountdownStrings) -> String {
+        let remaining: Duration = match self {
+            Countdown::Overdue => return strings.overdue.to_string(),
+            Countdown::Remaining(remaining) => *remaining,
+        };
+        let secs: u64 = remaining.as_secs();
+        let amount: String = if secs < 60 {
+            strings.under_a_minute.to_string()
+        } else if secs < 3600 {
+            format!("{}:{:02}", secs / 60, secs % 60)
+        } else {
+            format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+        };
+        strings.within.replace("{}", &amount)
+    }
+}
+
+/// Translations of the countdown line; `within` has a `{}` for the time left
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CountdownStrings {
+    pub within: &'static str,
+    pub under_a_minute: &'static str,
+    pub overdue: &'static str,
+}
+
+impl CountdownStrings {
+    pub const ENGLISH: CountdownStrings = CountdownStrings {
+        within: "Confirm within {}",
+        under_a_minute: "<1 min",
+        overdue: "Response overdue",
+    };
+
+    /// Strings for a BCP 47 tag such as `fr-CA`, falling back to English
+    pub fn for_language(tag: &str) -> &'static CountdownStrings {
+        const FRENCH: CountdownStrings = CountdownStrings {
+            within: "Confirmer d'ici {}",
+            under_a_minute: "<1 min",
+            overdue: "Réponse en retard",
+        };
+        const GERMAN: CountdownStrings = CountdownStrings {
+            within: "Bestätigen innerhalb von {}",
+            under_a_minute: "<1 Min.",
+            overdue: "Antwort überfällig",
+        };
+        const SPANISH: CountdownStrings = CountdownStrings {
+            within: "Confirme en {}",
+            under_a_minute: "<1 min",
+            overdue: "Respuesta atrasada",
+        };
+
+        let language: String = tag
+            .split(['-', '_', '.'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match language.as_str() {
+            "fr" => &FRENCH,
+            "de" => &GERMAN,
+            "es" => &SPANISH,
+            _ => &Self::ENGLISH,
+        }
+    }
+}
+
+/// The user's display language as a BCP 47 tag, e.g. `en-US`
+#[cfg(target_os = "windows")]
+pub fn system_language() -> String {
+    use windows::Win32::Globalization::GetUserDefaultLocaleName;
+
+    // LOCALE_NAME_MAX_LENGTH
+    let mut buffer: [u16; 85] = [0; 85];
+    let len: i32 = unsafe { GetUserDefaultLocaleName(&mut buffer) };
+    if len <= 1 {
+        return String::new();
+    }
+    String::from_utf16_lossy(&buffer[..len as usize - 1])
+}
+
+/// The user's language from `LANG`, e.g. `en_US.UTF-8`
+#[cfg(not(target_os = "windows"))]
+pub fn system_language() -> String {
+    std::env::var("LANG").unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn english(countdown: Countdown) -> String {
+        countdown.text_in(&CountdownStrings::ENGLISH)
+    }
+
+    #[test]
+    fn test_countdown_formatting() {
+        let secs = |s: u64| Countdown::Remaining(Duration::from_secs(s));
+        assert_eq!(english(secs(272)), "Confirm within 4:32");
+        assert_eq!(english(secs(300)), "Confirm within 5:00");
+        assert_eq!(english(secs(60)), "Confirm within 1:00");
+        assert_eq!(english(secs(59)), "Confirm within <1 min");
+        assert_eq!(
+            english(Countdown::Remaining(Duration::from_millis(300))),
+            "Confirm within <1 min"
+        );
+        assert_eq!(english(secs(3 * 3600 + 65)), "Confirm within 3:01:05");
+        assert_eq!(english(Countdown::Overdue), "Response overdue");
+    }
+
+    #[test]
+    fn test_deadline_in_the_past_is_overdue() {
+        let now: tokio::time::Instant = tokio::time::Instant::now();
+        assert_eq!(Countdown::until(now, now), Countdown::Overdue);
+        assert_eq!(
+            Countdown::until(now + Duration::from_secs(90), now),
+            Countdown::Remaining(Duration::from_secs(90))
+        );
+    }
+
+    #[test]
+    fn test_strings_follow_the_language_tag() {
+        let remaining: Countdown = Countdown::Remaining(Duration::from_secs(272));
+        assert_eq!(
+            remaining.text_in(CountdownStrings::for_language("fr-CA")),
+            "Confirmer d'ici 4:32"
+        );
+        assert_eq!(
+            remaining.text_in(CountdownStrings::for_language("de_DE.UTF-8")),
+            "Bestätigen innerhalb von 4:32"
+        );
+        assert_eq!(
+            CountdownStrings::for_language("ja-JP"),
+            &CountdownStrings::ENGLISH
+        );
+        assert_eq!(
+            CountdownStrings::for_language(""),
+            &CountdownStrings::ENGLISH
+        );
+    }
+}
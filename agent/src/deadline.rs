@@ -0,0 +1,116 @@
+//! Keyed deadlines drained in expiry order
+
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+use tokio::time::Instant;
+
+/// Deadlines indexed both by expiry time and by key.
+///
+/// Removing a key is immediate, so cancelled deadlines leave nothing behind.
+#[derive(Debug)]
+pub struct DeadlineQueue<K> {
+    by_deadline: BTreeSet<(Instant, K)>,
+    by_key: HashMap<K, Instant>,
+}
+
+impl<K: Ord + Hash + Clone> DeadlineQueue<K> {
+    pub fn new() -> Self {
+        Self {
+            by_deadline: BTreeSet::new(),
+            by_key: HashMap::new(),
+        }
+    }
+
+    /// Set the deadline for `key`, replacing any earlier one.
+    ///
+    /// Returns `true` if this is now the earliest deadline.
+    pub fn insert(&mut self, key: K, deadline: Instant) -> bool {
+        if let Some(previous) = self.by_key.insert(key.clone(), deadline) {
+            self.by_deadline.remove(&(previous, key.clone()));
+        }
+        self.by_deadline.insert((deadline, key));
+        self.next_deadline() == Some(deadline)
+    }
+
+    /// Drop the deadline for `key`, returning it if there was one
+    pub fn remove(&mut self, key: &K) -> Option<Instant> {
+        let deadline: Instant = self.by_key.remove(key)?;
+        self.by_deadline.remove(&(deadline, key.clone()));
+        Some(deadline)
+    }
+
+    /// Earliest pending deadline
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.by_deadline.first().map(|(deadline, _)| *deadline)
+    }
+
+    /// Remove and return every key whose deadline is at or before `now`, earliest first
+    pub fn pop_expired(&mut self, now: Instant) -> Vec<K> {
+        let mut expired: Vec<K> = Vec::new();
+        while let Some((deadline, _)) = self.by_deadline.first() {
+            if *deadline > now {
+                break;
+            }
+            let (_, key) = self.by_deadline.pop_first().expect("entry exists");
+            self.by_key.remove(&key);
+            expired.push(key);
+        }
+        expired
+    }
+
+    /// The deadline set for `key`
+    pub fn get(&self, key: &K) -> Option<Instant> {
+        self.by_key.get(key).copied()
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.by_key.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_key.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_key.is_empty()
+    }
+}
+
+impl<K: Ord + Hash + Clone> Default for DeadlineQueue<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pops_in_deadline_order_and_skips_removed() {
+        let now: Instant = Instant::now();
+        let mut queue: DeadlineQueue<u32> = DeadlineQueue::new();
+        assert!(queue.insert(1, now + Duration::from_secs(30)));
+        assert!(queue.insert(2, now + Duration::from_secs(10)));
+        assert!(!queue.insert(3, now + Duration::from_secs(20)));
+        assert_eq!(queue.remove(&3), Some(now + Duration::from_secs(20)));
+
+        assert_eq!(queue.next_deadline(), Some(now + Duration::from_secs(10)));
+        assert!(queue.pop_expired(now).is_empty());
+        assert_eq!(queue.pop_expired(now + Duration::from_secs(60)), vec![2, 1]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_reinsert_replaces_deadline() {
+        let now: Instant = Instant::now();
+        let mut queue: DeadlineQueue<u32> = DeadlineQueue::new();
+        queue.insert(1, now + Duration::from_secs(10));
+        queue.insert(1, now + Duration::from_secs(50));
+
+        assert_eq!(queue.len(), 1);
+        assert!(queue.pop_expired(now + Duration::from_secs(20)).is_empty());
+        assert_eq!(queue.pop_expired(now + Duration::from_secs(50)), vec![1]);
+    }
+}
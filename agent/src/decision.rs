@@ -0,0 +1,250 @@
+//! Why an alert made sound, was shown, both, or neither: every rule the
+//! handler consulted for it, in order, and what each concluded
+
+use crate::messages::DecisionSummary;
+use serde::{Deserialize, Serialize};
+
+/// What a rule can hold back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Governs {
+    Sound,
+    Toast,
+    Both,
+}
+
+impl Governs {
+    fn covers(self, output: Governs) -> bool {
+        self == Governs::Both || self == output
+    }
+}
+
+/// A named step in deciding how an alert is delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// Alerts arriving faster than the rate limit are recorded only; applied
+    /// before alerts reach the handler
+    RateLimit,
+    /// The alert's `visibility` leaves out this machine's role
+    Visibility,
+    SuppressionWindow,
+    /// Past its `expires_at` on arrival; recorded and reported only
+    Expiry,
+    /// Missed while offline and needing no confirmation; recapped in a digest
+    MissedDigest,
+    /// One of a burst of low-severity alerts, summarized in one toast
+    Burst,
+    /// Missed alerts replayed back to back after a reconnect; the first one's
+    /// sound covers the rest
+    Replay,
+    /// Revises an earlier alert that already sounded, and is no more severe
+    Supersede,
+    /// The server's sound policy
+    SoundPolicy,
+    /// An output device to play on
+    AudioDevice,
+    /// Sounds turned off in the settings
+    Mute,
+    QuietHours,
+    /// Urgent alerts sound at once on a locked workstation and are shown on unlock
+    LockScreen,
+    /// A toast, the details window, or the history only, as the self-check allows
+    Presentation,
+    /// A fullscreen app would swallow a critical toast; shown once it ends
+    Fullscreen,
+}
+
+impl Rule {
+    /// Rules the handler consults, in order. A rule is skipped once earlier
+    /// ones have held back everything it governs.
+    pub const PIPELINE: [Rule; 14] = [
+        Rule::Visibility,
+        Rule::SuppressionWindow,
+        Rule::Expiry,
+        Rule::MissedDigest,
+        Rule::Burst,
+        Rule::Replay,
+        Rule::Supersede,
+        Rule::SoundPolicy,
+        Rule::AudioDevice,
+        Rule::Mute,
+        Rule::QuietHours,
+        Rule::LockScreen,
+        Rule::Presentation,
+        Rule::Fullscreen,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rule::RateLimit => "rate_limit",
+            Rule::Visibility => "visibility",
+            Rule::SuppressionWindow => "suppression_window",
+            Rule::Expiry => "expiry",
+            Rule::MissedDigest => "missed_digest",
+            Rule::Burst => "burst",
+            Rule::Replay => "replay",
+            Rule::Supersede => "supersede",
+            Rule::SoundPolicy => "sound_policy",
+            Rule::AudioDevice => "audio_device",
+            Rule::Mute => "mute",
+            Rule::QuietHours => "quiet_hours",
+            Rule::LockScreen => "lock_screen",
+            Rule::Presentation => "presentation",
+            Rule::Fullscreen => "fullscreen",
+        }
+    }
+
+    pub fn governs(&self) -> Governs {
+        match self {
+            Rule::RateLimit
+            | Rule::Visibility
+            | Rule::SuppressionWindow
+            | Rule::Expiry
+            | Rule::MissedDigest
+            | Rule::Burst => Governs::Both,
+            Rule::Replay
+            | Rule::Supersede
+            | Rule::SoundPolicy
+            | Rule::AudioDevice
+            | Rule::Mute
+            | Rule::QuietHours => Governs::Sound,
+            Rule::LockScreen | Rule::Presentation | Rule::Fullscreen => Governs::Toast,
+        }
+    }
+}
+
+/// What a rule concluded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    /// Let the alert through
+    Allow,
+    /// Recorded only; neither sounded nor shown
+    Withhold,
+    /// Shown without a sound
+    Silence,
+    /// Held back for now, to be shown later
+    Hold,
+    /// Shown in the details window instead of a toast
+    Window,
+    /// Recorded only, having no way to show it
+    HistoryOnly,
+}
+
+/// One rule consulted, and what it concluded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleOutcome {
+    pub rule: Rule,
+    pub verdict: Verdict,
+    /// e.g. the suppression window's reason, or the sound the policy refused
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// The rules consulted for one alert, in order
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliveryDecision {
+    pub steps: Vec<RuleOutcome>,
+}
+
+impl DeliveryDecision {
+    /// An alert shed by the rate limit before the handler saw it
+    pub fn rate_limited() -> Self {
+        let mut decision: DeliveryDecision = DeliveryDecision::default();
+        decision.record(Rule::RateLimit, Verdict::Withhold, None);
+        decision
+    }
+
+    /// Whether anything `rule` governs is still undecided
+    pub fn consults(&self, rule: Rule) -> bool {
+        match rule.governs() {
+            Governs::Both => !self.settled(Governs::Sound) || !self.settled(Governs::Toast),
+            output => !self.settled(output),
+        }
+    }
+
+    pub fn record(&mut self, rule: Rule, verdict: Verdict, detail: Option<String>) {
+        self.steps.push(RuleOutcome {
+            rule,
+            verdict,
+            detail,
+        });
+    }
+
+    /// Whether a rule has already held back or changed `output`
+    fn settled(&self, output: Governs) -> bool {
+        self.decided_by(output).is_some()
+    }
+
+    /// What `rule` concluded; `None` if it was not consulted
+    pub fn outcome(&self, rule: Rule) -> Option<&RuleOutcome> {
+        self.steps.iter().find(|step| step.rule == rule)
+    }
+
+    pub fn verdict(&self, rule: Rule) -> Option<Verdict> {
+        self.outcome(rule).map(|step| step.verdict)
+    }
+
+    /// The rule that kept the alert from being sounded or shown at all
+    pub fn withheld_by(&self) -> Option<&RuleOutcome> {
+        self.steps
+            .iter()
+            .find(|step| step.verdict == Verdict::Withhold)
+    }
+
+    /// The first rule that held back `output`, or changed how the alert is shown
+    pub fn decided_by(&self, output: Governs) -> Option<&RuleOutcome> {
+        self.steps.iter().find(|step| {
+            step.verdict == Verdict::Withhold
+                || (step.verdict != Verdict::Allow && step.rule.governs().covers(output))
+        })
+    }
+
+    /// Whether the alert is to make a sound
+    pub fn sound(&self) -> bool {
+        !self.settled(Governs::Sound)
+    }
+
+    /// Whether the alert is to be put on screen at once, as a toast or in the details window
+    pub fn toast(&self) -> bool {
+        !self.settled(Governs::Toast) || self.verdict(Rule::Presentation) == Some(Verdict::Window)
+    }
+
+    /// In brief, for the server
+    pub fn summary(&self) -> DecisionSummary {
+        DecisionSummary {
+            sound: self.sound(),
+            toast: self.toast(),
+            decided_by: self
+                .steps
+                .iter()
+                .filter(|step| step.verdict != Verdict::Allow)
+                .map(|step| step.rule.as_str().to_string())
+                .collect(),
+        }
+    }
+
+    /// One line for people, e.g. `sound: none (quiet_hours); toast: shown`
+    pub fn describe(&self) -> String {
+        let because = |output: Governs| {
+            self.decided_by(output)
+                .map(|step| match &step.detail {
+                    Some(detail) => format!("{}: {}", step.rule.as_str(), detail),
+                    None => step.rule.as_str().to_string(),
+                })
+                .unwrap_or_default()
+        };
+        let sound: String = if self.sound() {
+            "played".to_string()
+        } else {
+            format!("none ({})", because(Governs::Sound))
+        };
+        let toast: String = match self.decided_by(Governs::Toast).map(|step| step.verdict) {
+            None => "shown".to_string(),
+            Some(Verdict::Window) => "details window".to_string(),
+            Some(Verdict::Hold) => format!("held ({})", because(Governs::Toast)),
+            Some(_) => format!("none ({})", because(Governs::Toast)),
+        };
+        format!("sound: {}; toast: {}", sound, toast)
+    }
+}
@@ -0,0 +1,222 @@
+//! Recognising alerts a server sent again, under the same id or a new one
+
+use crate::messages::Alert;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long after an alert was last seen a copy of it counts as a duplicate
+pub const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_secs(300);
+
+/// Alerts remembered at most, the least recently seen forgotten first
+pub const DEFAULT_DEDUP_CAPACITY: usize = 1_000;
+
+/// When an alert counts as one already received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DedupConfig {
+    pub window: Duration,
+    pub capacity: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            window: DEFAULT_DEDUP_WINDOW,
+            capacity: DEFAULT_DEDUP_CAPACITY,
+        }
+    }
+}
+
+/// The alert a duplicate repeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Duplicate {
+    /// The same id arrived again
+    Id,
+    /// The same content arrived under a new id; holds the id it was first seen under
+    Content(uuid::Uuid),
+}
+
+#[derive(Debug)]
+struct Seen {
+    alert_id: uuid::Uuid,
+    content: [u8; 32],
+    at: Instant,
+}
+
+/// Recently seen alerts, most recently seen last
+#[derive(Debug)]
+pub(crate) struct DuplicateFilter {
+    config: DedupConfig,
+    seen: VecDeque<Seen>,
+}
+
+impl DuplicateFilter {
+    pub fn new(config: DedupConfig) -> Self {
+        Self {
+            config,
+            seen: VecDeque::new(),
+        }
+    }
+
+    /// Note an alert's arrival, returning what it duplicates if it matches
+    /// one seen within the window.
+    ///
+    /// A duplicate counts as a sighting, so a server that keeps retrying
+    /// keeps being recognised.
+    pub fn arrive(&mut self, alert: &Alert, now: Instant) -> Option<Duplicate> {
+        let window: Duration = self.config.window;
+        self.seen
+            .retain(|seen| now.saturating_duration_since(seen.at) <= window);
+        let content: [u8; 32] = content_hash(alert);
+        let found: Option<(usize, Duplicate)> =
+            self.seen
+                .iter()
+                .enumerate()
+                .rev()
+                .find_map(|(index, seen)| {
+                    if seen.alert_id == alert.id {
+                        Some((index, Duplicate::Id))
+                    } else if seen.content == content {
+                        Some((index, Duplicate::Content(seen.alert_id)))
+                    } else {
+                        None
+                    }
+                });
+        match found {
+            Some((index, duplicate)) => {
+                if let Some(mut seen) = self.seen.remove(index) {
+                    seen.at = now;
+                    self.seen.push_back(seen);
+                }
+                Some(duplicate)
+            }
+            None => {
+                self.seen.push_back(Seen {
+                    alert_id: alert.id,
+                    content,
+                    at: now,
+                });
+                while self.seen.len() > self.config.capacity {
+                    self.seen.pop_front();
+                }
+                None
+            }
+        }
+    }
+}
+
+/// SHA-256 over what the user would see of an alert, leaving out its id and
+/// timestamp, which a server may regenerate when it sends the alert again.
+///
+/// Previews hash apart from the real alert, so sending one first never
+/// swallows the alert itself.
+fn content_hash(alert: &Alert) -> [u8; 32] {
+    let mut translations: Vec<_> = alert.translations.iter().collect();
+    translations.sort_by_key(|(tag, _)| *tag);
+    let mut hasher = Sha256::new();
+    let fields = [
+        alert.level.as_str(),
+        alert.category.as_deref().unwrap_or_default(),
+        &alert.title,
+        &alert.message,
+        if alert.is_preview { "preview" } else { "" },
+    ]
+    .into_iter()
+    .chain(translations.into_iter().flat_map(|(tag, translation)| {
+        [
+            tag.as_str(),
+            translation.title.as_str(),
+            translation.message.as_str(),
+        ]
+    }));
+    // Length-prefixed, so text moving between fields changes the hash
+    for field in fields {
+        hasher.update(format!("{}:", field.len()).as_bytes());
+        hasher.update(field.as_bytes());
+    }
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::AlertLevel;
+    use crate::test_support::alert;
+
+    fn filter(capacity: usize) -> DuplicateFilter {
+        DuplicateFilter::new(DedupConfig {
+            window: Duration::from_secs(60),
+            capacity,
+        })
+    }
+
+    #[test]
+    fn test_content_ignores_id_and_timestamp_only() {
+        let original: Alert = alert(AlertLevel::Warning, true);
+        let resent: Alert = Alert {
+            id: uuid::Uuid::new_v4(),
+            timestamp: original.timestamp + chrono::TimeDelta::seconds(30),
+            ..original.clone()
+        };
+        assert_eq!(content_hash(&original), content_hash(&resent));
+
+        let preview: Alert = Alert {
+            is_preview: true,
+            ..original.clone()
+        };
+        let shifted: Alert = Alert {
+            title: format!("{}T", original.title),
+            message: original.message.trim_start_matches('T').to_string(),
+            ..original.clone()
+        };
+        let critical: Alert = Alert {
+            level: AlertLevel::Critical,
+            ..original.clone()
+        };
+        for other in [preview, shifted, critical] {
+            assert_ne!(content_hash(&original), content_hash(&other));
+        }
+    }
+
+    #[test]
+    fn test_sightings_refresh_the_window() {
+        let mut filter: DuplicateFilter = filter(10);
+        let original: Alert = alert(AlertLevel::Warning, false);
+        let start: Instant = Instant::now();
+        assert_eq!(filter.arrive(&original, start), None);
+        assert_eq!(
+            filter.arrive(&original, start + Duration::from_secs(50)),
+            Some(Duplicate::Id)
+        );
+        // A minute after the first copy, but within one of the second
+        assert_eq!(
+            filter.arrive(&original, start + Duration::from_secs(100)),
+            Some(Duplicate::Id)
+        );
+        assert_eq!(
+            filter.arrive(&original, start + Duration::from_secs(161)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_capacity_forgets_the_least_recently_seen() {
+        let mut filter: DuplicateFilter = filter(2);
+        let now: Instant = Instant::now();
+        let alerts: Vec<Alert> = (0..3)
+            .map(|n| Alert {
+                title: format!("alert {}", n),
+                ..alert(AlertLevel::Info, false)
+            })
+            .collect();
+        filter.arrive(&alerts[0], now);
+        filter.arrive(&alerts[1], now);
+        // Seeing the first again keeps it over the second
+        assert_eq!(filter.arrive(&alerts[0], now), Some(Duplicate::Id));
+        filter.arrive(&alerts[2], now);
+        assert_eq!(filter.seen.len(), 2);
+        assert_eq!(filter.arrive(&alerts[0], now), Some(Duplicate::Id));
+        assert_eq!(filter.arrive(&alerts[1], now), None);
+    }
+}
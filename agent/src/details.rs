@@ -0,0 +1,373 @@
+//! Full-text view of an alert, opened by clicking its toast
+
+use crate::decision::DeliveryDecision;
+use crate::error::Result;
+use crate::history::HistoryEntry;
+use crate::messages::{Alert, AlertLevel, ResponseOption};
+use crate::notification::NotificationManager;
+use chrono::{DateTime, Local, Utc};
+use uuid::Uuid;
+
+/// Everything the details window shows for one alert
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertDetails {
+    pub alert_id: Uuid,
+    pub level: AlertLevel,
+    pub title: String,
+    pub message: String,
+    pub sent_at: DateTime<Utc>,
+    /// The alert is still waiting for the user to confirm it
+    pub awaiting_confirmation: bool,
+    /// Answers offered instead of a plain confirm
+    pub response_options: Vec<ResponseOption>,
+    /// Server identity, as on the toast's attribution line
+    pub attribution: Option<String>,
+    /// Why the alert did or did not sound and show
+    pub decision: Option<DeliveryDecision>,
+}
+
+impl AlertDetails {
+    pub fn from_alert(alert: &Alert, awaiting_confirmation: bool) -> Self {
+        Self {
+            alert_id: alert.id,
+            level: alert.level.clone(),
+            title: NotificationManager::display_title(alert),
+            message: alert.message.clone(),
+            sent_at: alert.timestamp,
+            awaiting_confirmation,
+            response_options: alert.response_options.clone().unwrap_or_default(),
+            attribution: None,
+            decision: None,
+        }
+    }
+
+    /// Details of an alert that is no longer pending, e.g. after a restart
+    pub fn from_history(entry: &HistoryEntry) -> Self {
+        Self {
+            alert_id: entry.alert_id,
+            level: entry.level.clone(),
+            title: entry.title.clone(),
+            message: entry.message.clone(),
+            sent_at: entry.sent_at,
+            awaiting_confirmation: false,
+            response_options: Vec::new(),
+            attribution: None,
+            decision: entry.decision.clone(),
+        }
+    }
+
+    pub fn with_attribution(mut self, attribution: Option<String>) -> Self {
+        self.attribution = attribution;
+        self
+    }
+
+    pub fn with_decision(mut self, decision: Option<DeliveryDecision>) -> Self {
+        self.decision = decision;
+        self
+    }
+
+    pub fn window_title(&self) -> String {
+        format!("{} alert", self.level.as_str().to_uppercase())
+    }
+
+    /// Message followed by the alert's metadata, with CRLF line endings for edit controls
+    pub fn body(&self) -> String {
+        let message: String = self
+            .message
+            .replace("\r\n", "\n")
+            .replace('\r', "\n")
+            .replace('\n', "\r\n");
+        let from: String = self
+            .attribution
+            .as_ref()
+            .map(|attribution| format!("\r\nFrom: {}", attribution))
+            .unwrap_or_default();
+        let delivery: String = self
+            .decision
+            .as_ref()
+            .map(|decision| format!("\r\nDelivery: {}", decision.describe()))
+            .unwrap_or_default();
+        format!(
+            "{}\r\n\r\nLevel: {}\r\nSent: {}\r\nAlert ID: {}{}{}",
+            message,
+            self.level.as_str().to_uppercase(),
+            self.sent_at
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S %:z"),
+            self.alert_id,
+            from,
+            delivery
+        )
+    }
+
+    /// Whether to offer the Confirm button
+    pub fn can_confirm(&self) -> bool {
+        self.awaiting_confirmation
+    }
+}
+
+/// How the user left the details window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetailsChoice {
+    Confirm,
+    /// One of the alert's response options, by position
+    Respond(usize),
+    Dismiss,
+    Closed,
+}
+
+/// Show the details window and block until the user closes it.
+///
+/// Runs its own message loop, so call it from a dedicated thread.
+#[cfg(target_os = "windows")]
+pub fn show_details_window(details: &AlertDetails) -> Result<DetailsChoice> {
+    win32::show(details)
+}
+
+/// The details window is only available on Windows; elsewhere the text is logged
+#[cfg(not(target_os = "windows"))]
+pub fn show_details_window(details: &AlertDetails) -> Result<DetailsChoice> {
+    log::info!(
+        "{}: {}\n{}",
+        details.window_title(),
+        details.title,
+        details.body()
+    );
+    Ok(DetailsChoice::Closed)
+}
+
+#[cfg(target_os = "windows")]
+mod win32 {
+    use super::{AlertDetails, DetailsChoice};
+    use crate::error::{EmnsError, Result};
+    use std::cell::Cell;
+    use windows::core::{w, HSTRING, PCWSTR};
+    use windows::Win32::Foundation::{HINSTANCE, HWND, LPARAM, LRESULT, WPARAM};
+    use windows::Win32::Graphics::Gdi::{GetStockObject, COLOR_WINDOW, DEFAULT_GUI_FONT, HBRUSH};
+    use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+    use windows::Win32::UI::WindowsAndMessaging::*;
+
+    const CLASS_NAME: PCWSTR = w!("EmnsAlertDetails");
+    const ID_CONFIRM: i32 = 1;
+    const ID_DISMISS: i32 = 2;
+    /// Response option buttons use this id plus their position
+    const ID_RESPONSE_BASE: i32 = 100;
+    const WIDTH: i32 = 560;
+    const HEIGHT: i32 = 440;
+
+    thread_local! {
+        static CHOICE: Cell<DetailsChoice> = const { Cell::new(DetailsChoice::Closed) };
+    }
+
+    pub(super) fn show(details: &AlertDetails) -> Result<DetailsChoice> {
+        let fail = |what: &str, e: windows::core::Error| {
+            EmnsError::notification(Some(details.alert_id), format!("{}: {}", what, e))
+        };
+
+        CHOICE.with(|c| c.set(DetailsChoice::Closed));
+        unsafe {
+            let instance: HINSTANCE = GetModuleHandleW(None)
+                .map_err(|e| fail("Failed to get module handle", e))?
+                .into();
+
+            let class: WNDCLASSW = WNDCLASSW {
+                lpfnWndProc: Some(window_proc),
+                hInstance: instance,
+                hCursor: LoadCursorW(None, IDC_ARROW).unwrap_or_default(),
+                hbrBackground: HBRUSH(COLOR_WINDOW.0 as isize + 1),
+                lpszClassName: CLASS_NAME,
+                ..Default::default()
+            };
+            // Fails harmlessly when a previous window already registered the class
+            RegisterClassW(&class);
+
+            let window: HWND = CreateWindowExW(
+                WS_EX_TOPMOST,
+                CLASS_NAME,
+                &HSTRING::from(details.window_title()),
+                WS_OVERLAPPED | WS_CAPTION | WS_SYSMENU | WS_VISIBLE,
+                CW_USEDEFAULT,
+                CW_USEDEFAULT,
+                WIDTH,
+                HEIGHT,
+                None,
+                None,
+                instance,
+                None,
+            );
+            if window.0 == 0 {
+                return Err(fail(
+                    "Failed to create details window",
+                    windows::core::Error::from_win32(),
+                ));
+            }
+
+            let font = WPARAM(GetStockObject(DEFAULT_GUI_FONT).0 as usize);
+            let child = |class: PCWSTR,
+                         text: &str,
+                         style: WINDOW_STYLE,
+                         rect: (i32, i32, i32, i32),
+                         id: i32| {
+                let control: HWND = CreateWindowExW(
+                    WINDOW_EX_STYLE::default(),
+                    class,
+                    &HSTRING::from(text),
+                    WS_CHILD | WS_VISIBLE | style,
+                    rect.0,
+                    rect.1,
+                    rect.2,
+                    rect.3,
+                    window,
+                    HMENU(id as isize),
+                    instance,
+                    None,
+                );
+                SendMessageW(control, WM_SETFONT, font, LPARAM(1));
+            };
+
+            child(
+                w!("STATIC"),
+                &details.title,
+                WINDOW_STYLE::default(),
+                (16, 12, WIDTH - 48, 40),
+                0,
+            );
+            child(
+                w!("EDIT"),
+                &details.body(),
+                WS_BORDER
+                    | WS_VSCROLL
+                    | WS_TABSTOP
+                    | WINDOW_STYLE((ES_MULTILINE | ES_READONLY | ES_AUTOVSCROLL) as u32),
+                (16, 56, WIDTH - 48, HEIGHT - 160),
+                0,
+            );
+            let buttons_y: i32 = HEIGHT - 92;
+            if details.can_confirm() && !details.response_options.is_empty() {
+                // Share the row left of Dismiss between the options
+                let count: i32 = details.response_options.len() as i32;
+                let width: i32 = ((WIDTH - 184 - 8 * count) / count).min(140);
+                for (index, option) in details.response_options.iter().enumerate() {
+                    let style: u32 = if index == 0 {
+                        BS_DEFPUSHBUTTON as u32
+                    } else {
+                        BS_PUSHBUTTON as u32
+                    };
+                    child(
+                        w!("BUTTON"),
+                        &option.label,
+                        WS_TABSTOP | WINDOW_STYLE(style),
+                        (16 + index as i32 * (width + 8), buttons_y, width, 32),
+                        ID_RESPONSE_BASE + index as i32,
+                    );
+                }
+            } else if details.can_confirm() {
+                child(
+                    w!("BUTTON"),
+                    "Confirm Receipt",
+                    WS_TABSTOP | WINDOW_STYLE(BS_DEFPUSHBUTTON as u32),
+                    (WIDTH - 292, buttons_y, 140, 32),
+                    ID_CONFIRM,
+                );
+            }
+            child(
+                w!("BUTTON"),
+                "Dismiss",
+                WS_TABSTOP | WINDOW_STYLE(BS_PUSHBUTTON as u32),
+                (WIDTH - 144, buttons_y, 112, 32),
+                ID_DISMISS,
+            );
+
+            ShowWindow(window, SW_SHOW);
+            SetForegroundWindow(window);
+
+            let mut message: MSG = MSG::default();
+            while GetMessageW(&mut message, None, 0, 0).as_bool() {
+                if !IsDialogMessageW(window, &message).as_bool() {
+                    TranslateMessage(&message);
+                    DispatchMessageW(&message);
+                }
+            }
+        }
+
+        Ok(CHOICE.with(|c| c.get()))
+    }
+
+    extern "system" fn window_proc(
+        window: HWND,
+        message: u32,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        unsafe {
+            match message {
+                WM_COMMAND => {
+                    let choice: Option<DetailsChoice> = match (wparam.0 & 0xffff) as i32 {
+                        ID_CONFIRM => Some(DetailsChoice::Confirm),
+                        ID_DISMISS => Some(DetailsChoice::Dismiss),
+                        id if id >= ID_RESPONSE_BASE => {
+                            Some(DetailsChoice::Respond((id - ID_RESPONSE_BASE) as usize))
+                        }
+                        _ => None,
+                    };
+                    if let Some(choice) = choice {
+                        CHOICE.with(|c| c.set(choice));
+                        let _ = DestroyWindow(window);
+                    }
+                    LRESULT(0)
+                }
+                WM_DESTROY => {
+                    PostQuitMessage(0);
+                    LRESULT(0)
+                }
+                _ => DefWindowProcW(window, message, wparam, lparam),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::SanitizeReport;
+    use crate::test_support::alert;
+
+    #[test]
+    fn test_body_uses_crlf_and_includes_metadata() {
+        let mut alert: Alert = alert(AlertLevel::Emergency, true);
+        alert.message =
+            "Evacuate building 4\nUse the north stairwell\r\nDo not use elevators".to_string();
+        let details: AlertDetails = AlertDetails::from_alert(&alert, true);
+
+        let body: String = details.body();
+        assert!(body.starts_with(
+            "Evacuate building 4\r\nUse the north stairwell\r\nDo not use elevators\r\n\r\n"
+        ));
+        assert!(!body.replace("\r\n", "").contains('\n'));
+        assert!(body.contains("Level: EMERGENCY"));
+        assert!(body.contains(&format!("Alert ID: {}", alert.id)));
+        assert!(!body.contains("From:"));
+        assert_eq!(details.window_title(), "EMERGENCY alert");
+        assert!(details.can_confirm());
+
+        let attributed: AlertDetails =
+            details.with_attribution(Some("EMNS — PRODUCTION".to_string()));
+        assert!(attributed.body().ends_with("\r\nFrom: EMNS — PRODUCTION"));
+    }
+
+    #[test]
+    fn test_history_details_cannot_be_confirmed() {
+        let alert: Alert = alert(AlertLevel::Critical, true);
+        let report: SanitizeReport = SanitizeReport {
+            original_title_len: alert.title.len(),
+            original_message_len: alert.message.len(),
+            title_truncated: false,
+            message_truncated: false,
+        };
+        let details: AlertDetails = AlertDetails::from_history(&HistoryEntry::new(&alert, &report));
+
+        assert_eq!(details.message, alert.message);
+        assert_eq!(details.sent_at, alert.timestamp);
+        assert!(!details.can_confirm());
+    }
+}
@@ -0,0 +1,348 @@
+//! Finding the notification server through DNS SRV and TXT records
+
+use crate::error::{EmnsError, Result};
+use futures_util::future::BoxFuture;
+use rand::Rng;
+use std::sync::Arc;
+
+/// Service label looked up under the discovery domain
+pub const SRV_SERVICE: &str = "_emns._tcp";
+
+/// WebSocket path used when no TXT record sets one
+pub const DEFAULT_PATH: &str = "/ws";
+
+/// How the agent finds the server it connects to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ServerDiscovery {
+    /// Connect to the configured `server_url`
+    #[default]
+    Static,
+    /// Look up `_emns._tcp.<domain>`, using `fallback_url` when the lookup fails
+    Dns {
+        domain: String,
+        fallback_url: Option<String>,
+    },
+}
+
+/// One SRV record: a server and its place in the failover order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Connection details carried by the optional TXT record, e.g. `path=/ws tls=1`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxtSettings {
+    pub path: String,
+    /// Connect with `wss://` rather than `ws://`
+    pub tls: bool,
+}
+
+impl Default for TxtSettings {
+    fn default() -> Self {
+        Self {
+            path: DEFAULT_PATH.to_string(),
+            tls: true,
+        }
+    }
+}
+
+impl TxtSettings {
+    /// Read `key=value` pairs from TXT strings; unknown keys and bad values are ignored
+    pub fn parse<S: AsRef<str>>(strings: &[S]) -> Self {
+        let mut settings: TxtSettings = TxtSettings::default();
+        for pair in strings.iter().flat_map(|s| s.as_ref().split_whitespace()) {
+            let Some((key, value)) = pair.split_once('=') else {
+                continue;
+            };
+            match key.to_ascii_lowercase().as_str() {
+                "path" if !value.is_empty() => {
+                    settings.path = if value.starts_with('/') {
+                        value.to_string()
+                    } else {
+                        format!("/{}", value)
+                    };
+                }
+                "tls" => match value.to_ascii_lowercase().as_str() {
+                    "1" | "true" | "yes" => settings.tls = true,
+                    "0" | "false" | "no" => settings.tls = false,
+                    _ => log::warn!("Ignoring TXT tls={}", value),
+                },
+                _ => {}
+            }
+        }
+        settings
+    }
+}
+
+/// Order records for failover as RFC 2782 describes.
+///
+/// Lower priorities come first. Within a priority, each pick is random in
+/// proportion to weight; `pick(total)` must return a value in `0..=total`.
+/// Records whose target is `.` mean the service is unavailable and are dropped.
+pub fn order_srv(records: Vec<SrvRecord>, pick: &mut impl FnMut(u32) -> u32) -> Vec<SrvRecord> {
+    let mut records: Vec<SrvRecord> = records
+        .into_iter()
+        .filter(|r| !r.target.trim_end_matches('.').is_empty())
+        .collect();
+    // Zero-weight records first, so they are only chosen when nothing else is left
+    records.sort_by_key(|r| (r.priority, r.weight != 0));
+
+    let mut ordered: Vec<SrvRecord> = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        let priority: u16 = records[0].priority;
+        let mut group: Vec<SrvRecord> = Vec::new();
+        while records.first().is_some_and(|r| r.priority == priority) {
+            group.push(records.remove(0));
+        }
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|r| r.weight as u32).sum();
+            let chosen: u32 = pick(total);
+            let mut running: u32 = 0;
+            let index: usize = group
+                .iter()
+                .position(|r| {
+                    running += r.weight as u32;
+                    running >= chosen
+                })
+                .unwrap_or(group.len() - 1);
+            ordered.push(group.remove(index));
+        }
+    }
+    ordered
+}
+
+/// Server URLs for ordered records, most preferred first
+pub fn candidate_urls(records: &[SrvRecord], txt: &TxtSettings) -> Vec<String> {
+    let scheme: &str = if txt.tls { "wss" } else { "ws" };
+    records
+        .iter()
+        .map(|r| {
+            format!(
+                "{}://{}:{}{}",
+                scheme,
+                r.target.trim_end_matches('.'),
+                r.port,
+                txt.path
+            )
+        })
+        .collect()
+}
+
+/// DNS lookups needed for discovery
+pub trait DnsResolver: Send + Sync {
+    fn srv<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<SrvRecord>>>;
+    /// Each TXT record's strings joined together
+    fn txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>>>;
+}
+
+/// Resolves through the system's DNS configuration
+#[derive(Default)]
+pub struct SystemResolver {
+    resolver: tokio::sync::OnceCell<hickory_resolver::TokioAsyncResolver>,
+}
+
+impl SystemResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    async fn resolver(&self, name: &str) -> Result<&hickory_resolver::TokioAsyncResolver> {
+        self.resolver
+            .get_or_try_init(|| async {
+                hickory_resolver::TokioAsyncResolver::tokio_from_system_conf()
+                    .map_err(|e| EmnsError::connection(name, e))
+            })
+            .await
+    }
+}
+
+impl DnsResolver for SystemResolver {
+    fn srv<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<SrvRecord>>> {
+        Box::pin(async move {
+            let lookup = self
+                .resolver(name)
+                .await?
+                .srv_lookup(name)
+                .await
+                .map_err(|e| EmnsError::connection(name, e))?;
+            Ok(lookup
+                .iter()
+                .map(|srv| SrvRecord {
+                    priority: srv.priority(),
+                    weight: srv.weight(),
+                    port: srv.port(),
+                    target: srv.target().to_utf8(),
+                })
+                .collect())
+        })
+    }
+
+    fn txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>>> {
+        Box::pin(async move {
+            let lookup = self
+                .resolver(name)
+                .await?
+                .txt_lookup(name)
+                .await
+                .map_err(|e| EmnsError::connection(name, e))?;
+            Ok(lookup
+                .iter()
+                .map(|txt| {
+                    txt.txt_data()
+                        .iter()
+                        .map(|chunk| String::from_utf8_lossy(chunk))
+                        .collect::<String>()
+                })
+                .collect())
+        })
+    }
+}
+
+/// Builds the server list from DNS on every reconnect cycle
+pub struct DnsDiscovery {
+    domain: String,
+    fallback_url: Option<String>,
+    resolver: Arc<dyn DnsResolver>,
+}
+
+impl DnsDiscovery {
+    pub fn new(domain: impl Into<String>, resolver: Arc<dyn DnsResolver>) -> Self {
+        Self {
+            domain: domain.into(),
+            fallback_url: None,
+            resolver,
+        }
+    }
+
+    /// URL to use when the lookup fails or finds no servers
+    pub fn with_fallback(mut self, fallback_url: Option<String>) -> Self {
+        self.fallback_url = fallback_url;
+        self
+    }
+
+    /// Candidate URLs in failover order; empty when discovery failed and there is no fallback
+    pub async fn server_urls(&self) -> Vec<String> {
+        let name: String = format!("{}.{}", SRV_SERVICE, self.domain.trim_end_matches('.'));
+        let records: Vec<SrvRecord> = match self.resolver.srv(&name).await {
+            Ok(records) => records,
+            Err(e) => {
+                log::warn!("Server discovery failed: {}", e);
+                Vec::new()
+            }
+        };
+        let ordered: Vec<SrvRecord> = order_srv(records, &mut |total| {
+            rand::thread_rng().gen_range(0..=total)
+        });
+        if ordered.is_empty() {
+            if let Some(url) = &self.fallback_url {
+                log::warn!("No servers found at {}; falling back to {}", name, url);
+                return vec![url.clone()];
+            }
+            log::error!(
+                "No servers found at {} and no SERVER_URL to fall back to",
+                name
+            );
+            return Vec::new();
+        }
+
+        let txt: TxtSettings = match self.resolver.txt(&name).await {
+            Ok(strings) => TxtSettings::parse(&strings),
+            Err(e) => {
+                log::debug!("No TXT record at {}, using defaults: {}", name, e);
+                TxtSettings::default()
+            }
+        };
+        let urls: Vec<String> = candidate_urls(&ordered, &txt);
+        log::info!("Discovered servers: {}", urls.join(", "));
+        urls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port: 8443,
+            target: target.to_string(),
+        }
+    }
+
+    fn targets(records: &[SrvRecord]) -> Vec<&str> {
+        records.iter().map(|r| r.target.as_str()).collect()
+    }
+
+    #[test]
+    fn test_priority_orders_groups() {
+        let records: Vec<SrvRecord> = vec![
+            record(20, 0, "backup.example."),
+            record(10, 0, "primary.example."),
+            record(30, 0, "."),
+        ];
+        let ordered: Vec<SrvRecord> = order_srv(records, &mut |_| 0);
+        assert_eq!(targets(&ordered), ["primary.example.", "backup.example."]);
+    }
+
+    #[test]
+    fn test_weight_decides_within_a_priority() {
+        let records = || {
+            vec![
+                record(10, 0, "spare.example."),
+                record(10, 60, "a.example."),
+                record(10, 40, "b.example."),
+            ]
+        };
+
+        // Running sums are spare=0, a=60, b=100
+        let mut low = |total: u32| if total == 100 { 30 } else { 0 };
+        let ordered: Vec<SrvRecord> = order_srv(records(), &mut low);
+        assert_eq!(
+            targets(&ordered),
+            ["a.example.", "spare.example.", "b.example."]
+        );
+
+        let mut high = |total: u32| total;
+        let ordered: Vec<SrvRecord> = order_srv(records(), &mut high);
+        assert_eq!(
+            targets(&ordered),
+            ["b.example.", "a.example.", "spare.example."]
+        );
+    }
+
+    #[test]
+    fn test_urls_from_records_and_txt() {
+        let records: Vec<SrvRecord> = vec![record(10, 1, "emns1.corp.example."), {
+            let mut second = record(20, 1, "emns2.corp.example");
+            second.port = 80;
+            second
+        }];
+
+        assert_eq!(
+            candidate_urls(&records, &TxtSettings::default()),
+            [
+                "wss://emns1.corp.example:8443/ws",
+                "wss://emns2.corp.example:80/ws"
+            ]
+        );
+
+        let txt: TxtSettings = TxtSettings::parse(&["path=alerts/ws", "tls=0 other=x"]);
+        assert_eq!(
+            txt,
+            TxtSettings {
+                path: "/alerts/ws".to_string(),
+                tls: false,
+            }
+        );
+        assert_eq!(
+            candidate_urls(&records[..1], &txt),
+            ["ws://emns1.corp.example:8443/alerts/ws"]
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! Error type for the agent library
+
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Result alias used throughout the library
+pub type Result<T, E = EmnsError> = std::result::Result<T, E>;
+
+/// Errors surfaced by the agent library.
+///
+/// Variants carry the alert id or file path involved so callers can report
+/// failures without parsing messages.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum EmnsError {
+    /// The server could not be reached or the link failed
+    #[error("connection to {url} failed: {detail}")]
+    Connection { url: String, detail: String },
+
+    /// The server refused our credentials or registration
+    #[error("authentication rejected: {reason}")]
+    Auth { reason: String },
+
+    /// A message could not be encoded or decoded
+    #[error("protocol error: {detail}")]
+    Protocol { detail: String },
+
+    /// A toast could not be displayed
+    #[error("notification failed for alert {}: {detail}", display_id(.alert_id))]
+    Notification {
+        alert_id: Option<Uuid>,
+        detail: String,
+    },
+
+    /// Toast activation arguments could not be understood
+    #[error("invalid toast arguments {arguments:?}: {detail}")]
+    Activation { arguments: String, detail: String },
+
+    /// A toast was clicked for an alert the agent no longer has
+    #[error("alert {alert_id} is no longer known")]
+    StaleAlert { alert_id: Uuid },
+
+    /// A sound could not be played
+    #[error("audio error for {}: {detail}", display_path(.path))]
+    Audio {
+        path: Option<PathBuf>,
+        detail: String,
+    },
+
+    /// Agent state could not be read or written
+    #[error("storage error for {}: {detail}", display_path(.path))]
+    Storage {
+        path: Option<PathBuf>,
+        detail: String,
+    },
+
+    /// A configuration value is invalid
+    #[error("invalid configuration {key}: {detail}")]
+    Config { key: String, detail: String },
+
+    /// An internal channel was closed because its receiver went away
+    #[error("{channel} channel closed")]
+    ChannelClosed { channel: &'static str },
+}
+
+impl EmnsError {
+    pub fn connection(url: impl Into<String>, detail: impl ToString) -> Self {
+        EmnsError::Connection {
+            url: url.into(),
+            detail: detail.to_string(),
+        }
+    }
+
+    pub fn protocol(detail: impl ToString) -> Self {
+        EmnsError::Protocol {
+            detail: detail.to_string(),
+        }
+    }
+
+    pub fn notification(alert_id: Option<Uuid>, detail: impl ToString) -> Self {
+        EmnsError::Notification {
+            alert_id,
+            detail: detail.to_string(),
+        }
+    }
+
+    pub fn activation(arguments: impl Into<String>, detail: impl ToString) -> Self {
+        EmnsError::Activation {
+            arguments: arguments.into(),
+            detail: detail.to_string(),
+        }
+    }
+
+    pub fn audio(path: Option<&Path>, detail: impl ToString) -> Self {
+        EmnsError::Audio {
+            path: path.map(Path::to_path_buf),
+            detail: detail.to_string(),
+        }
+    }
+
+    pub fn storage(path: Option<&Path>, detail: impl ToString) -> Self {
+        EmnsError::Storage {
+            path: path.map(Path::to_path_buf),
+            detail: detail.to_string(),
+        }
+    }
+
+    pub fn config(key: impl Into<String>, detail: impl ToString) -> Self {
+        EmnsError::Config {
+            key: key.into(),
+            detail: detail.to_string(),
+        }
+    }
+}
+
+impl From<serde_json::Error> for EmnsError {
+    fn from(e: serde_json::Error) -> Self {
+        EmnsError::protocol(e)
+    }
+}
+
+fn display_id(id: &Option<Uuid>) -> String {
+    id.map(|id| id.to_string())
+        .unwrap_or_else(|| "<none>".to_string())
+}
+
+fn display_path(path: &Option<PathBuf>) -> String {
+    path.as_ref()
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|| "<unknown>".to_string())
+}
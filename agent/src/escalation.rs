@@ -0,0 +1,90 @@
+//! Louder sounds and repeated toasts for alerts that stay unconfirmed
+
+use crate::messages::{Alert, AlertLevel};
+use std::time::Duration;
+
+/// Wait before escalating when no delay is configured
+pub const DEFAULT_ESCALATION_AFTER: Duration = Duration::from_secs(180);
+
+/// Escalation sounds play at full volume whatever the volume setting
+pub const ESCALATION_VOLUME: f32 = 1.0;
+
+/// Time between repeats when no interval is configured
+pub const DEFAULT_REPEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The sound an unconfirmed alert switches to, and when
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Escalation {
+    pub sound_file: String,
+    /// Time after the alert arrives, if it is still unconfirmed
+    pub after: Duration,
+}
+
+/// How often an unconfirmed alert's toast is shown again and its sound replayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Repeats {
+    /// Time between showings, the first repeat coming this long after the alert
+    pub interval: Duration,
+    /// Repeats before the alert is left to auto-confirm; none when `0`
+    pub max: u32,
+}
+
+impl Default for Repeats {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_REPEAT_INTERVAL,
+            max: 0,
+        }
+    }
+}
+
+impl Repeats {
+    /// The schedule for `alert`, with whatever its `escalation` sets in
+    /// place of this one. Only alerts awaiting confirmation repeat, and
+    /// previews never do
+    pub fn for_alert(&self, alert: &Alert) -> Self {
+        if !alert.requires_confirmation || alert.is_preview {
+            return Self { max: 0, ..*self };
+        }
+        let Some(escalation) = &alert.escalation else {
+            return *self;
+        };
+        Self {
+            interval: escalation
+                .repeat_interval_secs
+                .map_or(self.interval, |secs| Duration::from_secs(u64::from(secs))),
+            max: escalation.max_repeats.unwrap_or(self.max),
+        }
+    }
+
+    /// The shortest auto-confirm timeout that leaves the last repeat a full
+    /// interval to be answered
+    pub fn auto_confirm_after(&self, timeout: Duration) -> Duration {
+        if self.max == 0 {
+            return timeout;
+        }
+        timeout.max(self.interval.saturating_mul(self.max.saturating_add(1)))
+    }
+}
+
+/// Escalation for each alert level; levels without one never escalate
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EscalationPolicy {
+    pub info: Option<Escalation>,
+    pub warning: Option<Escalation>,
+    pub critical: Option<Escalation>,
+    pub emergency: Option<Escalation>,
+    /// Repeats for alerts of every level that require confirmation
+    pub repeats: Repeats,
+}
+
+impl EscalationPolicy {
+    pub fn for_level(&self, level: &AlertLevel) -> Option<&Escalation> {
+        match level {
+            AlertLevel::Info => self.info.as_ref(),
+            AlertLevel::Warning => self.warning.as_ref(),
+            AlertLevel::Critical => self.critical.as_ref(),
+            AlertLevel::Emergency => self.emergency.as_ref(),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Entries in the Windows Application event log for problems an administrator should see
+
+/// Source name entries are written under
+pub const EVENT_SOURCE: &str = "EMNS Agent";
+
+/// Write an error entry; failures are logged, never returned.
+///
+/// Elsewhere than Windows there is no event log and this does nothing.
+pub fn error(event_id: u32, message: &str) {
+    #[cfg(target_os = "windows")]
+    if let Err(e) = win32::report(event_id, message) {
+        log::warn!("Failed to write event log entry {}: {}", event_id, e);
+    }
+    #[cfg(not(target_os = "windows"))]
+    let _ = (event_id, message);
+}
+
+/// Whether entries can be written under [`EVENT_SOURCE`].
+///
+/// Elsewhere than Windows there is no event log, so never.
+pub fn available() -> bool {
+    #[cfg(target_os = "windows")]
+    return win32::can_register();
+    #[cfg(not(target_os = "windows"))]
+    false
+}
+
+#[cfg(target_os = "windows")]
+mod win32 {
+    use super::EVENT_SOURCE;
+    use windows::core::{HSTRING, PCWSTR};
+    use windows::Win32::Foundation::{HANDLE, PSID};
+    use windows::Win32::System::EventLog::{
+        DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+    };
+
+    pub fn report(event_id: u32, message: &str) -> windows::core::Result<()> {
+        let source: HSTRING = HSTRING::from(EVENT_SOURCE);
+        let text: HSTRING = HSTRING::from(message);
+        unsafe {
+            let log: HANDLE = RegisterEventSourceW(PCWSTR::null(), &source)?;
+            let result = ReportEventW(
+                log,
+                EVENTLOG_ERROR_TYPE,
+                0,
+                event_id,
+                PSID::default(),
+                0,
+                Some(&[PCWSTR(text.as_ptr())]),
+                None,
+            );
+            let _ = DeregisterEventSource(log);
+            result
+        }
+    }
+
+    pub fn can_register() -> bool {
+        let source: HSTRING = HSTRING::from(EVENT_SOURCE);
+        unsafe {
+            match RegisterEventSourceW(PCWSTR::null(), &source) {
+                Ok(log) => {
+                    let _ = DeregisterEventSource(log);
+                    true
+                }
+                Err(e) => {
+                    log::debug!("Failed to register event source: {}", e);
+                    false
+                }
+            }
+        }
+    }
+}
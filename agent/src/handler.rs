@@ -1,133 +1,5611 @@
-use crate::audio::AudioPlayer;
+use crate::attachments::{AttachmentConfig, AttachmentStore, DocumentLauncher, ShellLauncher};
+use crate::attention::{
+    delivery_for, AttentionBackend, Delivery, SystemAttention, UserNotificationState,
+    DEFERRED_POLL_INTERVAL,
+};
+use crate::audio::{AudioBackend, AudioPlayer, PlaybackHandle};
+use crate::board::{AlertBoard, BoardChanges, PendingItem, RecentItem, BOARD_VERSION};
+use crate::bulk::{BulkFilter, BulkSummary};
+use crate::burst::{BurstConfig, BurstDecision, BurstTracker};
+use crate::callback::{CallbackBody, CallbackConfig, CallbackSender};
+use crate::capabilities::{self, DeliveryPlan, Presentation};
+use crate::category::Categories;
 use crate::client::{get_hostname, get_username};
-use crate::messages::{Alert, Confirmation};
-use crate::notification::NotificationManager;
-use anyhow::Result;
+use crate::clock::{Clock, JumpDetector, SystemClock, CLOCK_JUMP_THRESHOLD};
+use crate::config::DEFAULT_MACHINE_ROLE;
+use crate::countdown::{Countdown, COUNTDOWN_REFRESH_INTERVAL};
+use crate::deadline::DeadlineQueue;
+use crate::decision::{DeliveryDecision, Governs, Rule, Verdict};
+use crate::dedup::{DedupConfig, Duplicate, DuplicateFilter};
+use crate::details::AlertDetails;
+use crate::error::{EmnsError, Result};
+use crate::escalation::{EscalationPolicy, Repeats, ESCALATION_VOLUME};
+use crate::history::{AlertHistory, HistoryEntry};
+use crate::idle::{IdleProbe, SystemIdle, IDLE_RECHECK_INTERVAL};
+use crate::images::{ImageCache, ImageConfig};
+use crate::locale;
+use crate::lock::{LockMonitor, LockState, SystemLock, LOCKED_RECHECK_INTERVAL};
+use crate::messages::{
+    Alert, AlertLevel, AlertOrigin, AttachmentState, CallbackState, Capabilities, Confirmation,
+    ConfirmationMethod, ConfirmationReason, ConfirmationResponse, DeliveryOutcome, DeliveryStatus,
+    ReceivedVia, ResponseOption, SoundDelivery, SoundPolicy,
+};
+use crate::missed::MissedDigest;
+use crate::notification::{
+    simple_alert, ActivationArgs, NotificationBackend, NotificationManager, ToastAction,
+};
+use crate::operator::OperatorIdentity;
+use crate::outbound::{OutboundMessage, OutboundQueue};
+use crate::power::{DisplayWake, PowerBackend, SystemPower, WakeGuard, DEFAULT_DISPLAY_WAKE_CAP};
+use crate::reminder::{ReminderBody, ReminderConfig, ReminderSender};
+use crate::sanitize::{sanitize_alert, sanitize_text, SanitizeReport, TextLimits};
+use crate::settings::{AgentSettings, SharedSettings};
+use crate::sink::{AlertSink, Resolution, Withdrawal};
+use crate::sounds::SoundLibrary;
+use crate::supersede::{self, SupersedePolicy};
+use crate::suppression::SuppressionWindows;
+use crate::timing::{DeliveryTimings, DeliveryTrace};
+use crate::toast_style::ToastStyles;
+use crate::watchdog::PipelineWatchdog;
 use std::collections::HashMap;
 use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch, Mutex, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 
+/// How long a preview alert stays up before it is taken down
+pub const PREVIEW_LIFETIME: Duration = Duration::from_secs(60);
+
+/// What the delivery rules judge an alert against, taken once as it is handled
+struct DecisionInputs {
+    now: chrono::DateTime<chrono::Utc>,
+    settings: AgentSettings,
+    plan: DeliveryPlan,
+    sound_file: String,
+}
+
+/// An alert waiting for the user to confirm it
+struct PendingAlert {
+    alert: Alert,
+    /// When it arrived; bulk operations go oldest first and filter by age
+    received: Instant,
+    /// Held for Emergency alerts until confirmed or the wake cap passes
+    wake: Option<WakeGuard>,
+    /// `None` when it waits until someone confirms it
+    window: Option<ConfirmWindow>,
+    /// When it is taken down unconfirmed, if it expires before it would auto-confirm
+    expires: Option<Instant>,
+    /// Reported back with the confirmation
+    received_via: ReceivedVia,
+    /// The escalation sound, stopped when the alert leaves the pending set
+    escalation: Option<PlaybackHandle>,
+    /// Cleared once the toast is gone, so its countdown stops being updated
+    countdown_live: bool,
+    /// When the toast appeared; `None` while it is deferred or if showing it failed
+    shown: Option<Shown>,
+    /// Earlier alerts it superseded, confirmed along with it
+    supersedes: Vec<uuid::Uuid>,
+    /// How often it is shown again while unconfirmed, and how many times it has been
+    repeats: Repeats,
+    repeated: u32,
+    /// Replayed with each repeat; `None` if the alert arrived silently
+    sound: Option<String>,
+}
+
+impl PendingAlert {
+    /// When it stops awaiting confirmation unless confirmed first; `None` if it never does
+    fn deadline(&self) -> Option<Instant> {
+        self.expires
+            .or_else(|| self.window.as_ref().map(ConfirmWindow::deadline))
+    }
+}
+
+/// When a toast appeared, as reported in confirmations and measured for their latency
+#[derive(Debug, Clone, Copy)]
+struct Shown(Instant);
+
+impl Shown {
+    fn now() -> Self {
+        Self(Instant::now())
+    }
+
+    /// `shown_at` and `response_latency_ms` for a confirmation sent at `now`,
+    /// `confirmed_at` on the wall clock.
+    ///
+    /// `shown_at` is counted back from `confirmed_at`, so the two agree even
+    /// if the wall clock was corrected while the toast was up.
+    fn report(
+        shown: Option<Shown>,
+        now: Instant,
+        confirmed_at: chrono::DateTime<chrono::Utc>,
+    ) -> (Option<chrono::DateTime<chrono::Utc>>, Option<u64>) {
+        let Some(Shown(at)) = shown else {
+            return (None, None);
+        };
+        let latency: Duration = now.saturating_duration_since(at);
+        let shown_at: chrono::DateTime<chrono::Utc> = chrono::TimeDelta::from_std(latency)
+            .ok()
+            .and_then(|latency| confirmed_at.checked_sub_signed(latency))
+            .unwrap_or(confirmed_at);
+        (Some(shown_at), Some(latency.as_millis() as u64))
+    }
+}
+
+/// Log and count a jump in the wall clock since `detector` last looked
+fn check_clock(clock: &dyn Clock, detector: &JumpDetector, stats: &HandlerStats) {
+    let Some(jump) = detector.check(clock) else {
+        return;
+    };
+    stats.clock_jumps.fetch_add(1, Ordering::Relaxed);
+    log::warn!(
+        "System clock jumped {}; alert timers run on the monotonic clock and keep their deadlines, suppression windows are judged by the new time",
+        jump
+    );
+}
+
+/// When an unconfirmed alert times out, and how long an idle machine may hold it
+#[derive(Debug, Clone, Copy)]
+struct ConfirmWindow {
+    /// When the alert arrived, or when the user came back to an idle machine
+    start: Instant,
+    timeout: Duration,
+    /// Latest time the timeout may be pushed back for an idle machine; `None` when disabled
+    idle_limit: Option<Instant>,
+    /// The timeout has already been pushed back because nobody was at the machine
+    extended: bool,
+    /// Time the workstation had spent locked when the window opened; `None`
+    /// unless the countdown pauses while locked
+    locked_before: Option<Duration>,
+    /// Time locked since the window opened, added to the deadline
+    paused: Duration,
+}
+
+/// What the sweeper does when an alert's auto-confirm deadline passes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimeoutOutcome {
+    Confirm(ConfirmationReason),
+    RecheckAt(Instant),
+}
+
+impl ConfirmWindow {
+    fn new(start: Instant, timeout: Duration, idle_extension: Option<Duration>) -> Self {
+        Self {
+            start,
+            timeout,
+            idle_limit: idle_extension.map(|extension| start + timeout + extension),
+            extended: false,
+            locked_before: None,
+            paused: Duration::ZERO,
+        }
+    }
+
+    /// Stop the countdown whenever the workstation is locked
+    fn pause_while_locked(mut self, lock: &LockState) -> Self {
+        self.locked_before = Some(lock.locked_time(self.start));
+        self
+    }
+
+    /// When the alert times out, counting lock time seen so far
+    fn deadline(&self) -> Instant {
+        self.start + self.timeout + self.paused
+    }
+
+    /// Push the deadline back by the time spent locked; `None` once it has really passed
+    fn hold_while_locked(&mut self, now: Instant, lock: &LockState) -> Option<Instant> {
+        let before: Duration = self.locked_before?;
+        self.paused = lock.locked_time(now).saturating_sub(before);
+        if lock.is_locked() {
+            return Some(now + LOCKED_RECHECK_INTERVAL);
+        }
+        let deadline: Instant = self.deadline();
+        (deadline > now).then_some(deadline)
+    }
+
+    /// Decide what to do at the deadline, given how long the user has been idle
+    fn on_timeout(&mut self, now: Instant, idle: Option<Duration>) -> TimeoutOutcome {
+        let (Some(limit), Some(idle)) = (self.idle_limit, idle) else {
+            return TimeoutOutcome::Confirm(ConfirmationReason::TimedOut);
+        };
+
+        let limit: Instant = limit + self.paused;
+
+        // No input since the window opened: nobody has seen the alert yet
+        if idle >= now.saturating_duration_since(self.start) {
+            if now >= limit {
+                return TimeoutOutcome::Confirm(ConfirmationReason::TimedOutIdle);
+            }
+            self.extended = true;
+            return TimeoutOutcome::RecheckAt((now + IDLE_RECHECK_INTERVAL).min(limit));
+        }
+
+        // The user came back while the alert was held: give them one full window
+        if self.extended {
+            // Lock time before the new start no longer delays the deadline
+            if let Some(before) = &mut self.locked_before {
+                *before += self.paused;
+            }
+            self.paused = Duration::ZERO;
+            self.start = now.checked_sub(idle).unwrap_or(now);
+            self.extended = false;
+            self.idle_limit = None;
+            let deadline: Instant = self.start + self.timeout;
+            if deadline > now {
+                return TimeoutOutcome::RecheckAt(deadline);
+            }
+        }
+        TimeoutOutcome::Confirm(ConfirmationReason::TimedOut)
+    }
+}
+
+/// Timed actions the sweeper task performs for pending alerts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Deadline {
+    AutoConfirm(uuid::Uuid),
+    ReleaseWake(uuid::Uuid),
+    Escalate(uuid::Uuid),
+    /// Take a preview alert down, answered or not
+    ExpirePreview(uuid::Uuid),
+    /// Take an alert down unconfirmed at its `expires_at`, in place of auto-confirming it
+    Expire(uuid::Uuid),
+    /// Nudge the user on their reminder webhook
+    Remind(uuid::Uuid),
+    /// Show an unconfirmed alert's toast again and replay its sound
+    Repeat(uuid::Uuid),
+    /// Rewrite the countdown on every pending alert's toast
+    RefreshCountdowns,
+}
+
+/// Activity counters the handler keeps for heartbeats
+#[derive(Debug, Default)]
+pub struct HandlerStats {
+    last_alert: std::sync::Mutex<Option<Instant>>,
+    pending_confirmations: AtomicUsize,
+    clock_jumps: AtomicU64,
+    /// Bumped as alerts arrive and leave the pending set
+    board: BoardChanges,
+}
+
+impl HandlerStats {
+    /// Time since the handler last processed a new alert; `None` before the first
+    pub fn since_last_alert(&self) -> Option<Duration> {
+        self.last_alert
+            .lock()
+            .unwrap()
+            .map(|at| Instant::now().saturating_duration_since(at))
+    }
+
+    /// Alerts waiting for the user to confirm them
+    pub fn pending_confirmations(&self) -> usize {
+        self.pending_confirmations.load(Ordering::Relaxed)
+    }
+
+    /// Jumps of the wall clock noticed since startup
+    pub fn clock_jumps(&self) -> u64 {
+        self.clock_jumps.load(Ordering::Relaxed)
+    }
+
+    fn alert_handled(&self) {
+        *self.last_alert.lock().unwrap() = Some(Instant::now());
+        self.board.bump();
+    }
+
+    /// Record the pending map's size; called with its lock held
+    fn set_pending(&self, count: usize) {
+        self.pending_confirmations.store(count, Ordering::Relaxed);
+        self.board.bump();
+    }
+}
+
+/// Plays, displays, and tracks confirmation of incoming alerts
 pub struct AlertHandler {
-    notification_manager: NotificationManager,
-    audio_player: AudioPlayer,
-    pending_confirmations: Arc<Mutex<HashMap<uuid::Uuid, Alert>>>,
-    confirmation_tx: mpsc::Sender<Confirmation>,
+    notifier: Arc<dyn NotificationBackend>,
+    audio: Arc<dyn AudioBackend>,
+    /// Picks each level's sound from the active sound pack; built-in names only when `None`
+    sounds: Option<SoundLibrary>,
+    pending_confirmations: Arc<Mutex<HashMap<uuid::Uuid, PendingAlert>>>,
+    stats: Arc<HandlerStats>,
+    /// Auto-confirm and wake-release deadlines, drained by a single sweeper task
+    deadlines: Arc<std::sync::Mutex<DeadlineQueue<Deadline>>>,
+    deadline_wake: Arc<Notify>,
+    sweeper_started: Once,
+    /// Where confirmations and delivery reports go
+    outbound: Arc<OutboundQueue>,
     client_id: String,
+    text_limits: TextLimits,
+    settings: SharedSettings,
+    history: Arc<AlertHistory>,
+    suppressions: Arc<SuppressionWindows>,
+    display_wake: DisplayWake,
+    display_wake_cap: Duration,
+    attention: Arc<dyn AttentionBackend>,
+    idle: Arc<dyn IdleProbe>,
+    idle_extension: Option<Duration>,
+    escalation: EscalationPolicy,
+    /// Burst coalescing of low-severity toasts; disabled when `None`
+    bursts: Option<Arc<std::sync::Mutex<BurstTracker>>>,
+    /// Alerts seen recently, so copies a server sends again stay silent; disabled when `None`
+    duplicates: Option<std::sync::Mutex<DuplicateFilter>>,
+    /// Alerts issued while the machine was offline, held for one digest toast
+    missed: Arc<std::sync::Mutex<MissedDigest>>,
+    attachments: Arc<AttachmentStore>,
+    /// Images toasts show; a toast waits a bounded time for its own
+    images: Arc<ImageCache>,
+    launcher: Arc<dyn DocumentLauncher>,
+    callbacks: Arc<CallbackSender>,
+    /// Reminds the user of urgent alerts left unconfirmed; disabled when `None`
+    reminders: Option<Arc<ReminderSender>>,
+    /// What this machine is used for; alerts whose visibility leaves it out are hidden
+    role: String,
+    /// Groups this agent belongs to; alerts targeting other groups and hosts are dropped
+    groups: Vec<String>,
+    /// Show a generic toast in place of an alert hidden by role
+    hidden_placeholder: bool,
+    /// Asks who is confirming on shared consoles; the session's user only when `None`
+    operator: Option<Arc<OperatorIdentity>>,
+    /// Critical alerts held back while a fullscreen app suppresses toasts
+    deferred: Arc<std::sync::Mutex<Vec<Alert>>>,
+    deferred_poller_running: Arc<AtomicBool>,
+    lock: watch::Receiver<LockState>,
+    /// Critical and Emergency toasts held back while the workstation is locked
+    held_for_unlock: Arc<std::sync::Mutex<Vec<Alert>>>,
+    unlock_waiter_running: Arc<AtomicBool>,
+    pause_while_locked: bool,
+    /// What becomes of a superseded alert still awaiting confirmation
+    supersede: SupersedePolicy,
+    /// Sound, confirmation and exercise marking for known categories
+    categories: Categories,
+    /// Picks the translation alerts are shown in, reported with each decision
+    locale: Option<String>,
+    /// Told about every delivered and resolved alert
+    sinks: Arc<Vec<Arc<dyn AlertSink>>>,
+    /// Told when playback holds up the alert pipeline
+    watchdog: Option<Arc<PipelineWatchdog>>,
+    /// Wall clock for suppression windows and reported timestamps
+    clock: Arc<dyn Clock>,
+    clock_jumps: Arc<JumpDetector>,
+    /// Stage times of recently handled alerts, stamped from `clock`
+    timings: Arc<DeliveryTimings>,
+    /// What the last self-check found, deciding how each alert is delivered
+    capabilities: watch::Receiver<Capabilities>,
+    /// Where details windows are requested for alerts toasts cannot show
+    windows: Option<mpsc::UnboundedSender<ActivationArgs>>,
+    cancel: CancellationToken,
+    tracker: TaskTracker,
 }
 
-impl AlertHandler {
-    pub fn new(
-        sounds_dir: PathBuf,
-        confirmation_tx: mpsc::Sender<Confirmation>,
-        client_id: String,
-    ) -> Self {
-        Self {
-            notification_manager: NotificationManager::new("NotificationAgent"),
-            audio_player: AudioPlayer::new(sounds_dir),
+/// Builder for [`AlertHandler`]
+pub struct AlertHandlerBuilder {
+    outbound: Arc<OutboundQueue>,
+    client_id: String,
+    sounds_dir: PathBuf,
+    sound_library: Option<SoundLibrary>,
+    app_id: String,
+    text_limits: TextLimits,
+    settings: SharedSettings,
+    notifier: Option<Arc<dyn NotificationBackend>>,
+    audio: Option<Arc<dyn AudioBackend>>,
+    activations: Option<mpsc::UnboundedSender<ActivationArgs>>,
+    history: Option<AlertHistory>,
+    suppressions: Option<Arc<SuppressionWindows>>,
+    power: Option<Arc<dyn PowerBackend>>,
+    display_wake_cap: Duration,
+    attention: Option<Arc<dyn AttentionBackend>>,
+    idle: Option<Arc<dyn IdleProbe>>,
+    idle_extension: Option<Duration>,
+    lock: Option<Arc<dyn LockMonitor>>,
+    pause_while_locked: bool,
+    supersede: SupersedePolicy,
+    categories: Categories,
+    locale: Option<String>,
+    escalation: EscalationPolicy,
+    burst: Option<BurstConfig>,
+    dedup: Option<DedupConfig>,
+    toast_styles: ToastStyles,
+    attachments: Option<Arc<AttachmentStore>>,
+    images: Option<Arc<ImageCache>>,
+    launcher: Option<Arc<dyn DocumentLauncher>>,
+    callbacks: Option<Arc<CallbackSender>>,
+    reminders: Option<Arc<ReminderSender>>,
+    role: String,
+    groups: Vec<String>,
+    hidden_placeholder: bool,
+    operator: Option<Arc<OperatorIdentity>>,
+    sinks: Vec<Arc<dyn AlertSink>>,
+    watchdog: Option<Arc<PipelineWatchdog>>,
+    clock: Option<Arc<dyn Clock>>,
+    capabilities: Option<watch::Receiver<Capabilities>>,
+    board: Option<BoardChanges>,
+    cancel: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl AlertHandlerBuilder {
+    /// Directory sound files are loaded from (default `./sounds`)
+    pub fn sounds_dir(mut self, sounds_dir: impl Into<PathBuf>) -> Self {
+        self.sounds_dir = sounds_dir.into();
+        self
+    }
+
+    /// Sounds to play from, shared with whatever replaces them (default: opened from `sounds_dir`)
+    pub fn sound_library(mut self, sounds: SoundLibrary) -> Self {
+        self.sound_library = Some(sounds);
+        self
+    }
+
+    /// AppUserModelID toasts are posted under (default `NotificationAgent`)
+    pub fn app_id(mut self, app_id: impl Into<String>) -> Self {
+        self.app_id = app_id.into();
+        self
+    }
+
+    /// Limits applied when sanitizing alert text
+    pub fn text_limits(mut self, text_limits: TextLimits) -> Self {
+        self.text_limits = text_limits;
+        self
+    }
+
+    /// Runtime settings read by the handler and its default backends
+    pub fn settings(mut self, settings: SharedSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Replace the toast backend (default: [`NotificationManager`])
+    pub fn notification_backend(mut self, notifier: Arc<dyn NotificationBackend>) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Send clicks on the default backend's toasts to `tx`, and ask it for the
+    /// details window when toasts are unavailable
+    pub fn toast_activations(mut self, tx: mpsc::UnboundedSender<ActivationArgs>) -> Self {
+        self.activations = Some(tx);
+        self
+    }
+
+    /// History to record alerts in (default: in memory only)
+    pub fn history(mut self, history: AlertHistory) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Suppression windows to consult before showing an alert (default: none, in memory)
+    pub fn suppressions(mut self, suppressions: Arc<SuppressionWindows>) -> Self {
+        self.suppressions = Some(suppressions);
+        self
+    }
+
+    /// Replace the sound backend (default: [`AudioPlayer`])
+    pub fn audio_backend(mut self, audio: Arc<dyn AudioBackend>) -> Self {
+        self.audio = Some(audio);
+        self
+    }
+
+    /// Replace the display power backend (default: [`SystemPower`])
+    pub fn power_backend(mut self, power: Arc<dyn PowerBackend>) -> Self {
+        self.power = Some(power);
+        self
+    }
+
+    /// Replace the fullscreen probe and taskbar flasher (default: [`SystemAttention`])
+    pub fn attention_backend(mut self, attention: Arc<dyn AttentionBackend>) -> Self {
+        self.attention = Some(attention);
+        self
+    }
+
+    /// Replace the user idle-time probe (default: [`SystemIdle`])
+    pub fn idle_probe(mut self, idle: Arc<dyn IdleProbe>) -> Self {
+        self.idle = Some(idle);
+        self
+    }
+
+    /// Hold alerts past their auto-confirm timeout for up to `extension` while
+    /// nobody is using the machine (default: no extension)
+    pub fn idle_extension(mut self, extension: Option<Duration>) -> Self {
+        self.idle_extension = extension;
+        self
+    }
+
+    /// Replace the workstation lock monitor (default: [`SystemLock`])
+    pub fn lock_monitor(mut self, lock: Arc<dyn LockMonitor>) -> Self {
+        self.lock = Some(lock);
+        self
+    }
+
+    /// Stop auto-confirm countdowns while the workstation is locked (default: keep counting)
+    pub fn pause_while_locked(mut self, pause: bool) -> Self {
+        self.pause_while_locked = pause;
+        self
+    }
+
+    /// What becomes of a superseded alert still awaiting confirmation
+    /// (default: [`SupersedePolicy::Transfer`])
+    pub fn superseded_pending(mut self, policy: SupersedePolicy) -> Self {
+        self.supersede = policy;
+        self
+    }
+
+    /// Handle alerts of the known `categories` as configured for each
+    /// (default: only `exercise`, marked as an exercise)
+    pub fn categories(mut self, categories: Categories) -> Self {
+        self.categories = categories;
+        self
+    }
+
+    /// Show alerts in their translation best matching `locale` (default: the
+    /// alert's own title and message)
+    pub fn locale(mut self, locale: Option<String>) -> Self {
+        self.locale = locale;
+        self
+    }
+
+    /// Where alert attachments are downloaded to (default: under `./data`)
+    /// Switch unconfirmed alerts to a louder sound (default: no escalation)
+    pub fn escalation(mut self, escalation: EscalationPolicy) -> Self {
+        self.escalation = escalation;
+        self
+    }
+
+    /// Toast scenario, duration and popup for each level, used by the default notifier
+    pub fn toast_styles(mut self, styles: ToastStyles) -> Self {
+        self.toast_styles = styles;
+        self
+    }
+
+    /// Coalesce bursts of Info (and optionally Warning) toasts into one summary (default: disabled)
+    pub fn burst_coalescing(mut self, burst: Option<BurstConfig>) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    /// Keep alerts received again within the window, by id or content, off
+    /// screen and silent, reporting each as a duplicate (default: disabled)
+    pub fn deduplicate(mut self, dedup: Option<DedupConfig>) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    pub fn attachment_store(mut self, attachments: Arc<AttachmentStore>) -> Self {
+        self.attachments = Some(attachments);
+        self
+    }
+
+    /// Where images shown in toasts are cached (default: under `./data`)
+    pub fn image_cache(mut self, images: Arc<ImageCache>) -> Self {
+        self.images = Some(images);
+        self
+    }
+
+    /// Replace what opens verified attachments (default: [`ShellLauncher`])
+    pub fn document_launcher(mut self, launcher: Arc<dyn DocumentLauncher>) -> Self {
+        self.launcher = Some(launcher);
+        self
+    }
+
+    /// Sends alerts' confirm callbacks (default: refuses every callback)
+    pub fn callback_sender(mut self, callbacks: Arc<CallbackSender>) -> Self {
+        self.callbacks = Some(callbacks);
+        self
+    }
+
+    /// Remind the user on their own webhook of urgent alerts left unconfirmed (default: never)
+    pub fn reminders(mut self, reminders: Option<ReminderConfig>) -> Self {
+        self.reminders = reminders.map(|config| Arc::new(ReminderSender::new(config)));
+        self
+    }
+
+    /// Hide alerts whose visibility leaves out `role` (default: [`DEFAULT_MACHINE_ROLE`])
+    pub fn machine_role(mut self, role: impl Into<String>) -> Self {
+        self.role = role.into();
+        self
+    }
+
+    /// Drop alerts targeting groups and hosts other than `groups` and this
+    /// machine (default: no groups, so only alerts naming this host or
+    /// targeting no one in particular are shown)
+    pub fn groups(mut self, groups: Vec<String>) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    /// Show a generic toast pointing to a supervisor in place of each alert
+    /// hidden by role (default: show nothing)
+    pub fn hidden_alert_placeholder(mut self, show: bool) -> Self {
+        self.hidden_placeholder = show;
+        self
+    }
+
+    /// Ask for an operator id before each confirmation by the user (default: never)
+    pub fn operator_identity(mut self, operator: Arc<OperatorIdentity>) -> Self {
+        self.operator = Some(operator);
+        self
+    }
+
+    /// Also report delivered and resolved alerts to `sink`; may be called more than once
+    pub fn sink(mut self, sink: Arc<dyn AlertSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Keep time spent starting sounds out of `watchdog`'s stall calculation
+    pub fn watchdog(mut self, watchdog: Arc<PipelineWatchdog>) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Wall clock read for suppression windows and reported timestamps (default: [`SystemClock`])
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Self-check results to deliver alerts by (default: everything available)
+    pub fn capabilities(mut self, capabilities: watch::Receiver<Capabilities>) -> Self {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    /// Bump `changes` whenever an alert arrives or leaves the pending set (default: a private counter)
+    pub fn board_changes(mut self, changes: BoardChanges) -> Self {
+        self.board = Some(changes);
+        self
+    }
+
+    /// Longest an unconfirmed Emergency alert keeps the display awake (default 15 minutes)
+    pub fn display_wake_cap(mut self, cap: Duration) -> Self {
+        self.display_wake_cap = cap;
+        self
+    }
+
+    /// Token that stops the handler's timers when cancelled
+    pub fn cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Tracker the handler spawns its timer tasks on
+    pub fn task_tracker(mut self, tracker: TaskTracker) -> Self {
+        self.tracker = tracker;
+        self
+    }
+
+    pub fn build(self) -> AlertHandler {
+        let cancel: CancellationToken = self.cancel;
+        let clock: Arc<dyn Clock> = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        let settings: SharedSettings = self.settings;
+        let images: Arc<ImageCache> = self
+            .images
+            .unwrap_or_else(|| Arc::new(ImageCache::new("./data", &ImageConfig::default())));
+        let notifier: Arc<dyn NotificationBackend> = self.notifier.unwrap_or_else(|| {
+            let mut manager: NotificationManager = NotificationManager::new(self.app_id)
+                .with_settings(settings.clone())
+                .with_toast_styles(self.toast_styles)
+                .with_images(images.clone())
+                .with_locale(self.locale.clone());
+            if let Some(tx) = self.activations.clone() {
+                manager = manager.with_activation_sender(tx);
+            }
+            Arc::new(manager)
+        });
+        let (audio, sounds): (Arc<dyn AudioBackend>, Option<SoundLibrary>) = match self.audio {
+            Some(audio) => (audio, self.sound_library),
+            None => {
+                let sounds: SoundLibrary = self
+                    .sound_library
+                    .unwrap_or_else(|| SoundLibrary::open(self.sounds_dir));
+                let player: AudioPlayer = AudioPlayer::from_library(sounds.clone())
+                    .with_cancellation(cancel.child_token())
+                    .with_settings(settings.clone());
+                (Arc::new(player), Some(sounds))
+            }
+        };
+
+        AlertHandler {
+            notifier,
+            audio,
+            sounds,
             pending_confirmations: Arc::new(Mutex::new(HashMap::new())),
-            confirmation_tx,
-            client_id,
+            stats: Arc::new(HandlerStats {
+                board: self.board.unwrap_or_default(),
+                ..HandlerStats::default()
+            }),
+            deadlines: Arc::new(std::sync::Mutex::new(DeadlineQueue::new())),
+            deadline_wake: Arc::new(Notify::new()),
+            sweeper_started: Once::new(),
+            outbound: self.outbound,
+            client_id: self.client_id,
+            text_limits: self.text_limits,
+            settings,
+            history: Arc::new(self.history.unwrap_or_default()),
+            suppressions: self.suppressions.unwrap_or_default(),
+            display_wake: DisplayWake::new(
+                self.power.unwrap_or_else(|| Arc::new(SystemPower::new())),
+            ),
+            display_wake_cap: self.display_wake_cap,
+            attention: self
+                .attention
+                .unwrap_or_else(|| Arc::new(SystemAttention::new())),
+            idle: self.idle.unwrap_or_else(|| Arc::new(SystemIdle::new())),
+            idle_extension: self.idle_extension,
+            escalation: self.escalation,
+            bursts: self
+                .burst
+                .map(|config| Arc::new(std::sync::Mutex::new(BurstTracker::new(config)))),
+            duplicates: self
+                .dedup
+                .map(|config| std::sync::Mutex::new(DuplicateFilter::new(config))),
+            missed: Arc::default(),
+            attachments: self.attachments.unwrap_or_else(|| {
+                Arc::new(AttachmentStore::new("./data", &AttachmentConfig::default()))
+            }),
+            images,
+            launcher: self
+                .launcher
+                .unwrap_or_else(|| Arc::new(ShellLauncher::new())),
+            callbacks: self
+                .callbacks
+                .unwrap_or_else(|| Arc::new(CallbackSender::new(&CallbackConfig::default()))),
+            reminders: self.reminders,
+            role: self.role,
+            groups: self.groups,
+            hidden_placeholder: self.hidden_placeholder,
+            operator: self.operator,
+            deferred: Arc::new(std::sync::Mutex::new(Vec::new())),
+            deferred_poller_running: Arc::new(AtomicBool::new(false)),
+            lock: self
+                .lock
+                .unwrap_or_else(|| Arc::new(SystemLock::new()))
+                .subscribe(),
+            held_for_unlock: Arc::new(std::sync::Mutex::new(Vec::new())),
+            unlock_waiter_running: Arc::new(AtomicBool::new(false)),
+            pause_while_locked: self.pause_while_locked,
+            supersede: self.supersede,
+            categories: self.categories,
+            locale: self.locale,
+            sinks: Arc::new(self.sinks),
+            watchdog: self.watchdog,
+            clock_jumps: Arc::new(JumpDetector::new(&*clock, CLOCK_JUMP_THRESHOLD)),
+            timings: Arc::new(DeliveryTimings::new(clock.clone())),
+            clock,
+            capabilities: self
+                .capabilities
+                .unwrap_or_else(|| watch::channel(Capabilities::all()).1),
+            windows: self.activations,
+            cancel,
+            tracker: self.tracker,
         }
     }
+}
 
-    /// Handle an incoming alert
-    pub async fn handle_alert(&self, alert: Alert) -> Result<()> {
-        log::info!(
-            "Processing alert {}: {} - {}",
-            alert.id,
-            alert.level.as_str(),
-            alert.title
-        );
+impl AlertHandler {
+    /// Start building a handler that queues confirmations and delivery reports on `outbound`
+    pub fn builder(
+        outbound: Arc<OutboundQueue>,
+        client_id: impl Into<String>,
+    ) -> AlertHandlerBuilder {
+        AlertHandlerBuilder {
+            outbound,
+            client_id: client_id.into(),
+            sounds_dir: PathBuf::from("./sounds"),
+            sound_library: None,
+            app_id: "NotificationAgent".to_string(),
+            text_limits: TextLimits::default(),
+            settings: SharedSettings::default(),
+            notifier: None,
+            audio: None,
+            activations: None,
+            history: None,
+            suppressions: None,
+            power: None,
+            display_wake_cap: DEFAULT_DISPLAY_WAKE_CAP,
+            attention: None,
+            idle: None,
+            idle_extension: None,
+            lock: None,
+            pause_while_locked: false,
+            supersede: SupersedePolicy::default(),
+            categories: Categories::default(),
+            locale: None,
+            escalation: EscalationPolicy::default(),
+            burst: None,
+            dedup: None,
+            toast_styles: ToastStyles::default(),
+            attachments: None,
+            images: None,
+            callbacks: None,
+            reminders: None,
+            role: DEFAULT_MACHINE_ROLE.to_string(),
+            groups: Vec::new(),
+            hidden_placeholder: false,
+            operator: None,
+            launcher: None,
+            sinks: Vec::new(),
+            watchdog: None,
+            clock: None,
+            capabilities: None,
+            board: None,
+            cancel: CancellationToken::new(),
+            tracker: TaskTracker::new(),
+        }
+    }
+
+    /// The wall-clock time, noting first whether the clock jumped since it was last read
+    fn wall_clock(&self) -> chrono::DateTime<chrono::Utc> {
+        check_clock(&*self.clock, &self.clock_jumps, &self.stats);
+        self.clock.now()
+    }
+
+    /// History of alerts this handler has processed
+    pub fn history(&self) -> &AlertHistory {
+        &self.history
+    }
+
+    /// Settings this handler reads when alerts arrive
+    pub fn settings(&self) -> &SharedSettings {
+        &self.settings
+    }
+
+    /// Counters reported in heartbeats
+    pub fn stats(&self) -> &Arc<HandlerStats> {
+        &self.stats
+    }
 
-        // Play sound (async, non-blocking)
-        let sound_file = alert.get_sound_file();
-        self.audio_player.play_sound_async(sound_file);
+    /// Stage times of recently handled alerts, and the clock they are stamped from
+    pub fn delivery_timings(&self) -> &Arc<DeliveryTimings> {
+        &self.timings
+    }
 
-        // Show notification
-        if let Err(e) = self.notification_manager.show_notification(&alert) {
-            log::error!("Failed to show notification: {}", e);
+    /// Pending alerts, alerts needing no confirmation shown within `recent`,
+    /// and the suppression windows that have not ended, for kiosk displays
+    pub async fn board(&self, recent: Duration) -> AlertBoard {
+        let (now, wall): (Instant, chrono::DateTime<chrono::Utc>) =
+            (Instant::now(), self.clock.now());
+        let at = |deadline: Instant| {
+            wall + chrono::TimeDelta::from_std(deadline.saturating_duration_since(now))
+                .unwrap_or_default()
+        };
+        let mut pending: Vec<PendingItem> = {
+            let pending = self.pending_confirmations.lock().await;
+            let deadlines = self.deadlines.lock().unwrap();
+            pending
+                .values()
+                .map(|entry| {
+                    let alert: &Alert = &entry.alert;
+                    let recorded: Option<HistoryEntry> = self.history.get(alert.id);
+                    PendingItem {
+                        alert_id: alert.id,
+                        level: alert.level.clone(),
+                        title: alert.title.clone(),
+                        message: alert.message.clone(),
+                        sent_at: alert.timestamp,
+                        received_at: recorded.as_ref().map_or(wall, |r| r.received_at),
+                        auto_confirm_at: entry.deadline().map(at),
+                        escalates_at: deadlines.get(&Deadline::Escalate(alert.id)).map(at),
+                        escalated_at: recorded.as_ref().and_then(|r| r.escalated_at),
+                        decision: recorded.and_then(|r| r.decision),
+                    }
+                })
+                .collect()
+        };
+        pending.sort_by_key(|item| item.received_at);
+        let since: chrono::DateTime<chrono::Utc> =
+            wall - chrono::TimeDelta::from_std(recent).unwrap_or(chrono::TimeDelta::MAX);
+        AlertBoard {
+            version: BOARD_VERSION,
+            generated_at: wall,
+            pending,
+            recent: self
+                .history
+                .received_since(since)
+                .iter()
+                .filter(|entry| !entry.requires_confirmation && entry.withheld.is_none())
+                .map(RecentItem::from)
+                .collect(),
+            suppressions: self.suppressions.scheduled(),
         }
+    }
 
-        // Track for confirmation if required
-        if alert.requires_confirmation {
-            let alert_id = alert.id;
-            self.pending_confirmations
-                .lock()
-                .await
-                .insert(alert_id, alert.clone());
+    /// Follows changes to what [`board`](Self::board) returns, other than the passing of time
+    pub fn board_changes(&self) -> watch::Receiver<u64> {
+        self.stats.board.subscribe()
+    }
 
-            // Auto-confirm after timeout (e.g., 5 minutes)
-            let pending = self.pending_confirmations.clone();
-            let tx = self.confirmation_tx.clone();
-            let client_id = self.client_id.clone();
+    /// Backend alerts are shown with
+    pub fn notification_backend(&self) -> &Arc<dyn NotificationBackend> {
+        &self.notifier
+    }
 
-            tokio::spawn(async move {
-                tokio::time::sleep(tokio::time::Duration::from_secs(300)).await;
+    /// Backend alert sounds are played with
+    pub fn audio_backend(&self) -> &Arc<dyn AudioBackend> {
+        &self.audio
+    }
 
-                let mut pending = pending.lock().await;
-                if pending.contains_key(&alert_id) {
-                    log::warn!(
-                        "Alert {} not confirmed within timeout, auto-confirming",
-                        alert_id
-                    );
-                    pending.remove(&alert_id);
+    /// What the details window shows for an alert, pending, from history, or a burst summary
+    pub async fn alert_details(&self, alert_id: uuid::Uuid) -> Option<AlertDetails> {
+        let details: AlertDetails = self.find_details(alert_id).await?;
+        Some(details.with_attribution(self.settings.snapshot().attribution()))
+    }
 
-                    let confirmation = Confirmation {
-                        alert_id,
-                        client_id,
-                        confirmed_at: chrono::Utc::now(),
-                        hostname: get_hostname(),
-                        username: get_username(),
-                    };
+    async fn find_details(&self, alert_id: uuid::Uuid) -> Option<AlertDetails> {
+        if let Some(pending) = self.pending_confirmations.lock().await.get(&alert_id) {
+            let decision: Option<DeliveryDecision> =
+                self.history.get(alert_id).and_then(|entry| entry.decision);
+            return Some(AlertDetails::from_alert(&pending.alert, true).with_decision(decision));
+        }
+        if let Some(summary) = self
+            .bursts
+            .as_ref()
+            .and_then(|bursts| bursts.lock().unwrap().summary(alert_id).cloned())
+        {
+            return Some(AlertDetails::from_alert(&summary, false));
+        }
+        if let Some(digest) = self.missed.lock().unwrap().digest(alert_id).cloned() {
+            return Some(AlertDetails::from_alert(&digest, false));
+        }
+        self.history
+            .get(alert_id)
+            .map(|entry| AlertDetails::from_history(&entry))
+    }
 
-                    let _ = tx.send(confirmation).await;
-                }
-            });
+    /// Report `alert` as suppressed if it is not meant for this machine's
+    /// role, or a suppression window covers it now.
+    ///
+    /// Suppressed alerts stay in the history but get no toast, sound, or
+    /// confirmation prompt.
+    pub fn suppress(&self, alert: &Alert) -> bool {
+        let inputs: DecisionInputs = self.decision_inputs(alert);
+        let decision: DeliveryDecision =
+            self.decide(alert, &[Rule::Visibility, Rule::SuppressionWindow], &inputs);
+        self.withhold(alert, &decision, inputs.now)
+    }
+
+    /// What the delivery rules judge `alert` against, as things stand now
+    fn decision_inputs(&self, alert: &Alert) -> DecisionInputs {
+        DecisionInputs {
+            now: self.wall_clock(),
+            settings: self.settings.snapshot(),
+            plan: capabilities::plan(
+                &self.capabilities.borrow(),
+                &alert.level,
+                alert.requires_confirmation,
+            ),
+            sound_file: sound_for(self.sounds.as_ref(), alert),
         }
+    }
 
-        Ok(())
+    /// Consult each of `rules` in turn that still has something to decide about `alert`
+    fn decide(&self, alert: &Alert, rules: &[Rule], inputs: &DecisionInputs) -> DeliveryDecision {
+        let mut decision: DeliveryDecision = DeliveryDecision::default();
+        for &rule in rules {
+            if decision.consults(rule) {
+                let (verdict, detail) = self.consult(rule, alert, inputs);
+                decision.record(rule, verdict, detail);
+            }
+        }
+        decision
     }
 
-    /// Manually confirm an alert
-    pub async fn confirm_alert(&self, alert_id: uuid::Uuid) -> Result<()> {
-        let mut pending = self.pending_confirmations.lock().await;
+    /// What `rule` makes of `alert`, with a detail worth reporting
+    fn consult(
+        &self,
+        rule: Rule,
+        alert: &Alert,
+        inputs: &DecisionInputs,
+    ) -> (Verdict, Option<String>) {
+        let allow: (Verdict, Option<String>) = (Verdict::Allow, None);
+        let routine: bool = matches!(alert.level, AlertLevel::Info | AlertLevel::Warning);
+        match rule {
+            // Applied before alerts reach the handler
+            Rule::RateLimit => allow,
+            Rule::Visibility if !alert.visible_to(&self.role) => {
+                (Verdict::Withhold, Some(self.role.clone()))
+            }
+            Rule::SuppressionWindow => match self.suppressions.suppressing(alert, inputs.now) {
+                Some(window) => (Verdict::Withhold, Some(window.reason)),
+                None => allow,
+            },
+            Rule::Expiry => match alert.expires_at {
+                Some(expires_at) if expires_at <= inputs.now => {
+                    (Verdict::Withhold, Some(expires_at.to_rfc3339()))
+                }
+                _ => allow,
+            },
+            Rule::MissedDigest if alert.missed && !alert.requires_confirmation => {
+                (Verdict::Hold, None)
+            }
+            // A replay can carry hundreds of alerts needing confirmation; one sound will do
+            Rule::Replay
+                if alert.missed && self.missed.lock().unwrap().sounded_recently(Instant::now()) =>
+            {
+                (Verdict::Silence, None)
+            }
+            // The earlier alert's sound already went off; only a more severe update repeats it
+            Rule::Supersede => match self.superseded(alert) {
+                Some(earlier)
+                    if earlier
+                        .decision
+                        .as_ref()
+                        .is_some_and(DeliveryDecision::sound)
+                        && supersede::no_louder(&alert.level, &earlier.level) =>
+                {
+                    (Verdict::Silence, Some(earlier.alert_id.to_string()))
+                }
+                _ => allow,
+            },
+            // A preview is shown on its own so its author sees what recipients would
+            Rule::Burst => {
+                let Some(bursts) = self.bursts.as_ref().filter(|_| !alert.is_preview) else {
+                    return allow;
+                };
+                let arrival: BurstDecision = bursts.lock().unwrap().arrive(alert, Instant::now());
+                match arrival {
+                    BurstDecision::Coalesce { started } => {
+                        if started {
+                            self.spawn_burst_summary(alert.level.clone());
+                        }
+                        (Verdict::Hold, None)
+                    }
+                    BurstDecision::Show => allow,
+                }
+            }
+            Rule::SoundPolicy if !inputs.settings.sound_policy().permits(&inputs.sound_file) => {
+                (Verdict::Silence, Some(inputs.sound_file.clone()))
+            }
+            Rule::AudioDevice if !inputs.plan.sound => (Verdict::Silence, None),
+            Rule::Mute if !inputs.settings.sounds_enabled() => (Verdict::Silence, None),
+            Rule::QuietHours => match inputs.settings.quiet_hours() {
+                Some(window)
+                    if routine
+                        && window.contains(inputs.now.with_timezone(&chrono::Local).time()) =>
+                {
+                    (Verdict::Silence, None)
+                }
+                _ => allow,
+            },
+            // The lock screen would swallow the toast
+            Rule::LockScreen if !routine && self.lock.borrow().is_locked() => (Verdict::Hold, None),
+            Rule::Presentation => match inputs.plan.presentation {
+                Presentation::Toast => allow,
+                Presentation::Window if self.windows.is_some() => (Verdict::Window, None),
+                Presentation::Window | Presentation::HistoryOnly => (Verdict::HistoryOnly, None),
+            },
+            Rule::Fullscreen => {
+                match delivery_for(&alert.level, self.attention.notification_state()) {
+                    Delivery::Show => allow,
+                    Delivery::Defer => (Verdict::Hold, None),
+                }
+            }
+            Rule::Visibility
+            | Rule::MissedDigest
+            | Rule::Replay
+            | Rule::SoundPolicy
+            | Rule::AudioDevice
+            | Rule::Mute
+            | Rule::LockScreen => allow,
+        }
+    }
+
+    /// Record and report `alert` as held back entirely if `decision` says so,
+    /// showing the placeholder toast for an alert hidden by role if configured
+    fn withhold(
+        &self,
+        alert: &Alert,
+        decision: &DeliveryDecision,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> bool {
+        let Some(withheld) = decision.withheld_by() else {
+            return false;
+        };
+        let hidden: bool = withheld.rule == Rule::Visibility;
+        let outcome: DeliveryOutcome = match withheld.rule {
+            Rule::Visibility => {
+                log::info!(
+                    "Alert {} is not visible to {} machines; recording it only",
+                    alert.id,
+                    self.role
+                );
+                DeliveryOutcome::HiddenByRole
+            }
+            Rule::Expiry => {
+                log::info!(
+                    "Alert {} expired at {} before it arrived; recording it only: {} - {}",
+                    alert.id,
+                    withheld.detail.as_deref().unwrap_or_default(),
+                    alert.level.as_str(),
+                    alert.title
+                );
+                DeliveryOutcome::Expired
+            }
+            _ => {
+                log::info!(
+                    "Alert {} suppressed by window ({}): {} - {}",
+                    alert.id,
+                    withheld.detail.as_deref().unwrap_or_default(),
+                    alert.level.as_str(),
+                    alert.title
+                );
+                DeliveryOutcome::SuppressedByWindow
+            }
+        };
+        self.history.mark_withheld(alert.id, outcome);
+        self.history.mark_decision(alert.id, decision.clone());
+        self.stats.board.bump();
+        if outcome == DeliveryOutcome::Expired {
+            self.outbound.push(OutboundMessage::AlertExpired {
+                client_id: self.client_id.clone(),
+                alert_id: alert.id,
+                unconfirmed: false,
+            });
+            return true;
+        }
+        self.outbound
+            .push(OutboundMessage::DeliveryStatus(DeliveryStatus {
+                alert_id: alert.id,
+                client_id: self.client_id.clone(),
+                reported_at: now,
+                attachment: None,
+                sound: None,
+                annunciator: None,
+                callback: None,
+                outcome: Some(outcome),
+                detail: withheld.detail.clone(),
+                decision: Some(decision.summary()),
+                locale: None,
+            }));
+        if hidden && self.hidden_placeholder {
+            if let Err(e) = self.notifier.show_notification(&hidden_placeholder(now)) {
+                log::error!("Failed to show placeholder notification: {}", e);
+            }
+        }
+        true
+    }
 
-        if pending.remove(&alert_id).is_some() {
-            log::info!("Alert {} confirmed by user", alert_id);
+    /// Whether `alert` repeats one seen within the dedup window, in which case
+    /// it is only reported to the server, so that a server retrying delivery
+    /// stops
+    fn duplicate(&self, alert: &Alert, via: ReceivedVia) -> bool {
+        let Some(duplicates) = &self.duplicates else {
+            return false;
+        };
+        let Some(duplicate) = duplicates.lock().unwrap().arrive(alert, Instant::now()) else {
+            return false;
+        };
+        let detail: Option<String> = match duplicate {
+            Duplicate::Id => {
+                log::info!("Alert {} received again ({:?}), ignoring", alert.id, via);
+                None
+            }
+            Duplicate::Content(original) => {
+                log::info!(
+                    "Alert {} repeats alert {} ({:?}), ignoring",
+                    alert.id,
+                    original,
+                    via
+                );
+                Some(format!("same content as alert {}", original))
+            }
+        };
+        self.outbound
+            .push(OutboundMessage::DeliveryStatus(DeliveryStatus {
+                alert_id: alert.id,
+                client_id: self.client_id.clone(),
+                reported_at: self.wall_clock(),
+                attachment: None,
+                sound: None,
+                annunciator: None,
+                callback: None,
+                outcome: Some(DeliveryOutcome::Duplicate),
+                detail,
+                decision: None,
+                locale: None,
+            }));
+        true
+    }
 
-            let confirmation = Confirmation {
+    /// Tell a server sending an alert already in the history again, once the
+    /// dedup window has passed or with dedup off, what became of it the first
+    /// time, so that it stops retrying
+    fn report_again(&self, alert_id: uuid::Uuid) {
+        let entry: Option<HistoryEntry> = self.history.get(alert_id);
+        self.outbound
+            .push(OutboundMessage::DeliveryStatus(DeliveryStatus {
                 alert_id,
                 client_id: self.client_id.clone(),
-                confirmed_at: chrono::Utc::now(),
-                hostname: get_hostname(),
-                username: get_username(),
-            };
-
-            self.confirmation_tx
-                .send(confirmation)
-                .await
-                .map_err(|e| anyhow::anyhow!("Failed to send confirmation: {}", e))?;
+                reported_at: self.wall_clock(),
+                attachment: None,
+                sound: None,
+                annunciator: None,
+                callback: entry.as_ref().and_then(|entry| entry.callback),
+                outcome: Some(
+                    entry
+                        .as_ref()
+                        .and_then(|entry| entry.withheld)
+                        .unwrap_or(DeliveryOutcome::Duplicate),
+                ),
+                detail: entry
+                    .as_ref()
+                    .map(|entry| format!("already received at {}", entry.received_at.to_rfc3339())),
+                decision: entry
+                    .as_ref()
+                    .and_then(|entry| entry.decision.as_ref())
+                    .map(DeliveryDecision::summary),
+                locale: None,
+            }));
+    }
 
-            Ok(())
-        } else {
-            log::warn!("Alert {} not found in pending confirmations", alert_id);
-            Ok(())
-        }
+    /// Record `decision` for `alert` and tell the server about it
+    fn report_decision(
+        &self,
+        alert: &Alert,
+        decision: &DeliveryDecision,
+        now: chrono::DateTime<chrono::Utc>,
+    ) {
+        self.history.mark_decision(alert.id, decision.clone());
+        let refused: bool = decision.verdict(Rule::SoundPolicy) == Some(Verdict::Silence);
+        self.outbound
+            .push(OutboundMessage::DeliveryStatus(DeliveryStatus {
+                alert_id: alert.id,
+                client_id: self.client_id.clone(),
+                reported_at: now,
+                attachment: None,
+                sound: refused.then_some(SoundDelivery::SuppressedByPolicy),
+                annunciator: None,
+                callback: None,
+                outcome: None,
+                detail: None,
+                decision: Some(decision.summary()),
+                locale: self
+                    .locale
+                    .as_deref()
+                    .and_then(|locale| locale::translation(alert, locale))
+                    .map(|(tag, _)| tag.to_string()),
+            }));
     }
 
-    /// Get pending confirmations count
-    pub async fn pending_count(&self) -> usize {
-        self.pending_confirmations.lock().await.len()
+    /// Handle an incoming alert from the server connection
+    pub async fn handle_alert(&self, alert: Alert) -> Result<()> {
+        self.handle_alert_via(alert, ReceivedVia::WebSocket).await
     }
 
-    /// Get all pending alert IDs
-    pub async fn get_pending_alerts(&self) -> Vec<uuid::Uuid> {
-        self.pending_confirmations
-            .lock()
+    /// Handle an incoming alert delivered over `via`.
+    ///
+    /// Alerts already in the history are not shown again, so one that arrives
+    /// over more than one channel is only shown once; the server is told again
+    /// what became of it.
+    pub async fn handle_alert_via(&self, alert: Alert, via: ReceivedVia) -> Result<()> {
+        self.handle_alert_traced(alert, via, DeliveryTrace::default())
             .await
-            .keys()
-            .copied()
-            .collect()
+    }
+
+    /// Handle an alert delivered over `via` that was stamped on its way to
+    /// the handler, recording how long each stage of its delivery took
+    pub async fn handle_alert_traced(
+        &self,
+        mut alert: Alert,
+        via: ReceivedVia,
+        mut trace: DeliveryTrace,
+    ) -> Result<()> {
+        self.categories.apply(&mut alert);
+        // Sanitize once, before anything displays or logs the text
+        let report: SanitizeReport = sanitize_alert(&mut alert, &self.text_limits);
+        if !alert.addressed_to(&get_hostname(), &self.groups) {
+            log::debug!(
+                "Dropping alert {} targeted at other groups or hosts ({:?})",
+                alert.id,
+                via
+            );
+            return Ok(());
+        }
+        if self.duplicate(&alert, via) {
+            return Ok(());
+        }
+        if !self.history.record_new(HistoryEntry::new(&alert, &report)) {
+            log::info!(
+                "Alert {} already received, reporting it again ({:?})",
+                alert.id,
+                via
+            );
+            self.report_again(alert.id);
+            return Ok(());
+        }
+        self.stats.alert_handled();
+        if report.title_truncated || report.message_truncated {
+            log::warn!(
+                "Alert {} text truncated (title {} chars, message {} chars)",
+                alert.id,
+                report.original_title_len,
+                report.original_message_len
+            );
+        }
+
+        let inputs: DecisionInputs = self.decision_inputs(&alert);
+        let decision: DeliveryDecision = self.decide(&alert, &Rule::PIPELINE, &inputs);
+        if self.withhold(&alert, &decision, inputs.now) {
+            return Ok(());
+        }
+        self.report_decision(&alert, &decision, inputs.now);
+
+        let earlier: Option<HistoryEntry> = self.superseded(&alert);
+        match (&earlier, alert.supersedes) {
+            // Sanitized already, so the mention is held to the message limit again
+            (Some(earlier), _) => {
+                alert.message = sanitize_text(
+                    &supersede::mention(&alert.message, &earlier.title),
+                    self.text_limits.max_message_chars,
+                )
+                .0
+            }
+            (None, Some(earlier_id)) => log::info!(
+                "Alert {} supersedes alert {}, which never reached this machine",
+                alert.id,
+                earlier_id
+            ),
+            (None, None) => {}
+        }
+
+        log::info!(
+            "Processing alert {}: {} - {} ({})",
+            alert.id,
+            alert.level.as_str(),
+            alert.title,
+            decision.describe()
+        );
+
+        // Missed alerts are recapped in one silent digest instead of sounding at login
+        if decision.verdict(Rule::MissedDigest) == Some(Verdict::Hold) {
+            log::info!(
+                "Alert {} was issued while this machine was offline; holding it for the digest",
+                alert.id
+            );
+            if self
+                .missed
+                .lock()
+                .unwrap()
+                .hold(alert.clone(), Instant::now())
+            {
+                self.spawn_missed_digest();
+            }
+            self.fetch_attachment(&alert);
+            return Ok(());
+        }
+
+        let image: Option<JoinHandle<()>> = self.fetch_image(&alert);
+        for sink in self.sinks.iter() {
+            sink.delivered(&alert);
+        }
+
+        let settings: AgentSettings = inputs.settings;
+        let mut shown: Option<Shown> = None;
+        let mut open_window: bool = false;
+
+        if decision.sound() {
+            let _playing = self.watchdog.as_ref().map(|watchdog| watchdog.playback());
+            self.audio.play(&inputs.sound_file);
+            trace.sound_started = Some(self.timings.now());
+            if alert.missed {
+                self.missed.lock().unwrap().sounded(Instant::now());
+            }
+        }
+        match decision.decided_by(Governs::Toast).map(|step| step.rule) {
+            None => {
+                self.wait_for_image(alert.id, image).await;
+                match self.notifier.show_notification(&alert) {
+                    Ok(()) => {
+                        trace.toast_shown = Some(self.timings.now());
+                        shown = Some(Shown::now());
+                    }
+                    Err(e) => log::error!("Failed to show notification: {}", e),
+                }
+            }
+            // Held for a summary while a burst is under way
+            Some(Rule::Burst) => {}
+            Some(Rule::LockScreen) => self.hold_until_unlock(alert.clone()),
+            Some(Rule::Fullscreen) => self.defer(alert.clone()),
+            // Opened once the alert is tracked, so the window offers Confirm
+            Some(_) if decision.toast() => {
+                open_window = true;
+                shown = Some(Shown::now());
+            }
+            Some(_) => log::warn!(
+                "Toasts are unavailable; alert {} is only recorded in the history",
+                alert.id
+            ),
+        }
+        // Only alerts that came through the queue were stamped on the way in
+        if trace.dequeued.is_some() {
+            self.history.mark_timing(alert.id, trace);
+            self.timings.record(alert.timestamp, &trace);
+        }
+        self.fetch_attachment(&alert);
+
+        if alert.is_preview {
+            log::info!(
+                "Alert {} is a preview; taking it down in {:?}",
+                alert.id,
+                PREVIEW_LIFETIME
+            );
+            let expire: bool = self.deadlines.lock().unwrap().insert(
+                Deadline::ExpirePreview(alert.id),
+                Instant::now() + PREVIEW_LIFETIME,
+            );
+            if expire {
+                self.deadline_wake.notify_one();
+            }
+            self.sweeper_started.call_once(|| self.spawn_sweeper());
+        }
+
+        let supersedes: Vec<uuid::Uuid> = match &earlier {
+            Some(earlier) => {
+                self.take_over(earlier.alert_id, alert.id, alert.requires_confirmation)
+                    .await
+            }
+            None => Vec::new(),
+        };
+
+        // Track for confirmation if required
+        let alert_id: uuid::Uuid = alert.id;
+        let emergency: bool = alert.level == AlertLevel::Emergency;
+        if alert.requires_confirmation {
+            // Keep the display on until someone confirms the alert
+            let wake: Option<WakeGuard> = emergency.then(|| self.display_wake.acquire());
+            // Auto-confirm after the alert's own timeout or the one in effect
+            // when it arrived, and not before the last repeat has gone
+            // unanswered; never, if the timeout is off
+            let now: Instant = Instant::now();
+            let repeats: Repeats = self.escalation.repeats.for_alert(&alert);
+            let window: Option<ConfirmWindow> =
+                settings.auto_confirm_timeout_for(&alert).map(|timeout| {
+                    let window: ConfirmWindow = ConfirmWindow::new(
+                        now,
+                        repeats.auto_confirm_after(timeout),
+                        self.idle_extension,
+                    );
+                    if self.pause_while_locked {
+                        window.pause_while_locked(&self.lock.borrow())
+                    } else {
+                        window
+                    }
+                });
+            // Taken down unconfirmed instead if it expires before it would auto-confirm
+            let expires: Option<Instant> = alert
+                .expires_at
+                .and_then(|at| (at - inputs.now).to_std().ok())
+                .map(|left| now + left)
+                .filter(|at| window.as_ref().is_none_or(|window| *at < window.deadline()));
+            // Previews never escalate
+            let escalate_after: Option<Duration> = self
+                .escalation
+                .for_level(&alert.level)
+                .filter(|_| !alert.is_preview)
+                .map(|escalation| escalation.after);
+            let remind_after: Option<Duration> = self
+                .reminders
+                .as_ref()
+                .filter(|reminders| reminders.config().applies_to(&alert))
+                .map(|reminders| reminders.config().after);
+            let mut pending = self.pending_confirmations.lock().await;
+            pending.insert(
+                alert_id,
+                PendingAlert {
+                    alert,
+                    received: now,
+                    wake,
+                    window,
+                    expires,
+                    received_via: via,
+                    escalation: None,
+                    countdown_live: true,
+                    shown,
+                    supersedes,
+                    repeats,
+                    repeated: 0,
+                    sound: decision.sound().then(|| inputs.sound_file.clone()),
+                },
+            );
+            self.stats.set_pending(pending.len());
+            drop(pending);
+
+            let earliest: bool = {
+                let mut deadlines = self.deadlines.lock().unwrap();
+                let mut earliest: bool = match (expires, &window) {
+                    (Some(at), _) => deadlines.insert(Deadline::Expire(alert_id), at),
+                    (None, Some(window)) => {
+                        deadlines.insert(Deadline::AutoConfirm(alert_id), window.deadline())
+                    }
+                    (None, None) => false,
+                };
+                if emergency {
+                    earliest |= deadlines
+                        .insert(Deadline::ReleaseWake(alert_id), now + self.display_wake_cap);
+                }
+                if let Some(after) = escalate_after {
+                    earliest |= deadlines.insert(Deadline::Escalate(alert_id), now + after);
+                }
+                if let Some(after) = remind_after {
+                    earliest |= deadlines.insert(Deadline::Remind(alert_id), now + after);
+                }
+                if repeats.max > 0 {
+                    earliest |=
+                        deadlines.insert(Deadline::Repeat(alert_id), now + repeats.interval);
+                }
+                if !deadlines.contains(&Deadline::RefreshCountdowns) {
+                    earliest |= deadlines.insert(
+                        Deadline::RefreshCountdowns,
+                        now + COUNTDOWN_REFRESH_INTERVAL,
+                    );
+                }
+                earliest
+            };
+            if earliest {
+                self.deadline_wake.notify_one();
+            }
+            self.sweeper_started.call_once(|| self.spawn_sweeper());
+        } else if emergency {
+            self.display_wake.pulse();
+        }
+
+        if open_window {
+            log::info!(
+                "Toasts are unavailable; opening the details window for alert {}",
+                alert_id
+            );
+            if let Some(tx) = &self.windows {
+                let _ = tx.send(ActivationArgs::new(ToastAction::Details, alert_id));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The earlier alert `alert` revises, if it reached this machine
+    fn superseded(&self, alert: &Alert) -> Option<HistoryEntry> {
+        alert
+            .supersedes
+            .filter(|earlier_id| *earlier_id != alert.id)
+            .and_then(|earlier_id| self.history.get(earlier_id))
+    }
+
+    /// Withdraw `earlier_id`, superseded by `alert_id`, if it still awaits
+    /// confirmation, returning the alerts the update now confirms along with
+    /// itself.
+    ///
+    /// Under [`SupersedePolicy::Transfer`] an update that needs no
+    /// confirmation leaves the earlier alert pending.
+    async fn take_over(
+        &self,
+        earlier_id: uuid::Uuid,
+        alert_id: uuid::Uuid,
+        tracked: bool,
+    ) -> Vec<uuid::Uuid> {
+        let transfer: bool = self.supersede == SupersedePolicy::Transfer;
+        if transfer && !tracked {
+            return Vec::new();
+        }
+        let carried: Option<Vec<uuid::Uuid>> = self
+            .pending_confirmations
+            .lock()
+            .await
+            .get(&earlier_id)
+            .map(|entry| entry.supersedes.clone());
+        let Some(mut carried) = carried else {
+            return Vec::new();
+        };
+        if !self.withdraw(earlier_id, Withdrawal::Superseded).await || !transfer {
+            return Vec::new();
+        }
+        log::info!(
+            "Confirming alert {} will also confirm alert {}",
+            alert_id,
+            earlier_id
+        );
+        carried.push(earlier_id);
+        carried
+    }
+
+    /// Download the alert's attachment in the background and report how it went.
+    ///
+    /// The toast and sound never wait on this; the "Open document" action
+    /// refuses the file until it has verified.
+    fn fetch_attachment(&self, alert: &Alert) {
+        let Some(attachment) = alert.attachment.clone() else {
+            return;
+        };
+        let alert_id: uuid::Uuid = alert.id;
+        let attachments: Arc<AttachmentStore> = self.attachments.clone();
+        let outbound: Arc<OutboundQueue> = self.outbound.clone();
+        let client_id: String = self.client_id.clone();
+        let cancel: CancellationToken = self.cancel.clone();
+        self.tracker.spawn(async move {
+            let fetched = tokio::select! {
+                _ = cancel.cancelled() => return,
+                fetched = attachments.fetch(alert_id, &attachment) => fetched,
+            };
+            let (state, detail) = match fetched {
+                Ok(_) => (AttachmentState::Verified, None),
+                Err(e) => {
+                    log::error!("Attachment for alert {} failed: {}", alert_id, e);
+                    (AttachmentState::Failed, Some(e.to_string()))
+                }
+            };
+            outbound.push(OutboundMessage::DeliveryStatus(DeliveryStatus {
+                alert_id,
+                client_id,
+                reported_at: chrono::Utc::now(),
+                attachment: Some(state),
+                sound: None,
+                annunciator: None,
+                callback: None,
+                outcome: None,
+                detail,
+                decision: None,
+                locale: None,
+            }));
+        });
+    }
+
+    /// Start downloading the alert's image, if it has one, for its toast.
+    ///
+    /// A toast shown later, e.g. once the workstation unlocks, finds the
+    /// image already cached.
+    fn fetch_image(&self, alert: &Alert) -> Option<JoinHandle<()>> {
+        let url: String = alert.image_url.clone()?;
+        let alert_id: uuid::Uuid = alert.id;
+        let images: Arc<ImageCache> = self.images.clone();
+        let cancel: CancellationToken = self.cancel.clone();
+        Some(self.tracker.spawn(async move {
+            let fetched = tokio::select! {
+                _ = cancel.cancelled() => return,
+                fetched = images.fetch(alert_id, &url) => fetched,
+            };
+            if let Err(e) = fetched {
+                log::warn!("Image for alert {} not shown: {}", alert_id, e);
+            }
+        }))
+    }
+
+    /// Hold the toast back until its image is cached, for no longer than the
+    /// image cache's wait; a slow download carries on for later toasts
+    async fn wait_for_image(&self, alert_id: uuid::Uuid, fetching: Option<JoinHandle<()>>) {
+        let Some(fetching) = fetching else {
+            return;
+        };
+        if tokio::time::timeout(self.images.wait(), fetching)
+            .await
+            .is_err()
+        {
+            log::warn!(
+                "Image for alert {} is still downloading after {:?}; showing the toast without it",
+                alert_id,
+                self.images.wait()
+            );
+        }
+    }
+
+    /// Record an alert the rate limit shed, without showing it, and tell the server.
+    ///
+    /// Like [`handle_alert`](Self::handle_alert), an alert already in the history is ignored.
+    pub fn record_rate_limited(&self, mut alert: Alert) {
+        let report: SanitizeReport = sanitize_alert(&mut alert, &self.text_limits);
+        let mut entry: HistoryEntry = HistoryEntry::new(&alert, &report);
+        let decision: DeliveryDecision = DeliveryDecision::rate_limited();
+        entry.withheld = Some(DeliveryOutcome::RateLimited);
+        entry.decision = Some(decision.clone());
+        if !self.history.record_new(entry) {
+            return;
+        }
+        log::warn!(
+            "Alert {} shed by the rate limit: {} - {}",
+            alert.id,
+            alert.level.as_str(),
+            alert.title
+        );
+        self.outbound
+            .push(OutboundMessage::DeliveryStatus(DeliveryStatus {
+                alert_id: alert.id,
+                client_id: self.client_id.clone(),
+                reported_at: chrono::Utc::now(),
+                attachment: None,
+                sound: None,
+                annunciator: None,
+                callback: None,
+                outcome: Some(DeliveryOutcome::RateLimited),
+                detail: None,
+                decision: Some(decision.summary()),
+                locale: None,
+            }));
+    }
+
+    /// Open the alert's attachment if it downloaded and verified; otherwise
+    /// show a toast saying it cannot be opened
+    pub async fn open_attachment(&self, alert_id: uuid::Uuid) -> Result<()> {
+        let attachments: Arc<AttachmentStore> = self.attachments.clone();
+        let launcher: Arc<dyn DocumentLauncher> = self.launcher.clone();
+        // Re-hashing the file and launching it both block
+        let opened: bool =
+            tokio::task::spawn_blocking(move || match attachments.verified_path(alert_id) {
+                Some(path) => launcher.open(&path).map(|()| true),
+                None => Ok(false),
+            })
+            .await
+            .map_err(|e| EmnsError::storage(None, e))??;
+        if opened {
+            return Ok(());
+        }
+
+        log::warn!(
+            "Attachment for alert {} is not verified; not opening it",
+            alert_id
+        );
+        self.notifier.show_notification(&Alert {
+            id: uuid::Uuid::new_v4(),
+            title: "Document unavailable".to_string(),
+            message: "The document attached to this alert has not been downloaded and verified, so it cannot be opened.".to_string(),
+            level: AlertLevel::Warning,
+            requires_confirmation: false,
+            sound_file: None,
+            timestamp: chrono::Utc::now(),
+            origin: AlertOrigin::Local,
+            ..Default::default()
+        })
+    }
+
+    /// Show one summary toast once the burst at `level` has been quiet for a full window
+    fn spawn_burst_summary(&self, level: AlertLevel) {
+        let Some(bursts) = self.bursts.clone() else {
+            return;
+        };
+        let notifier = self.notifier.clone();
+        let audio = self.audio.clone();
+        let sounds: Option<SoundLibrary> = self.sounds.clone();
+        let attention = self.attention.clone();
+        let settings: SharedSettings = self.settings.clone();
+        let cancel: CancellationToken = self.cancel.clone();
+        self.tracker.spawn(async move {
+            loop {
+                let Some(quiet) = bursts.lock().unwrap().quiet_at(&level) else {
+                    return;
+                };
+                tokio::select! {
+                    _ = cancel.cancelled() => return,
+                    _ = tokio::time::sleep_until(quiet) => {}
+                }
+                // Keep holding while a fullscreen app would swallow the summary
+                if delivery_for(&level, attention.notification_state()) == Delivery::Defer {
+                    tokio::select! {
+                        _ = cancel.cancelled() => return,
+                        _ = tokio::time::sleep(DEFERRED_POLL_INTERVAL) => continue,
+                    }
+                }
+                let Some(summary) = bursts.lock().unwrap().finish(&level, Instant::now()) else {
+                    continue;
+                };
+
+                log::info!("Burst over: {}", summary.title);
+                let settings: AgentSettings = settings.snapshot();
+                let sound_file: String = sound_for(sounds.as_ref(), &summary);
+                if should_play_sound(&settings, &level)
+                    && settings.sound_policy().permits(&sound_file)
+                {
+                    audio.play(&sound_file);
+                }
+                if let Err(e) = notifier.show_notification(&summary) {
+                    log::error!("Failed to show notification: {}", e);
+                }
+                return;
+            }
+        });
+    }
+
+    /// Show the digest of missed alerts once the replay after a reconnect has settled
+    fn spawn_missed_digest(&self) {
+        let missed = self.missed.clone();
+        let notifier = self.notifier.clone();
+        let attention = self.attention.clone();
+        let cancel: CancellationToken = self.cancel.clone();
+        self.tracker.spawn(async move {
+            loop {
+                let (Some(settled), Some(level)) = ({
+                    let missed = missed.lock().unwrap();
+                    (missed.settled_at(), missed.level())
+                }) else {
+                    return;
+                };
+                tokio::select! {
+                    _ = cancel.cancelled() => return,
+                    _ = tokio::time::sleep_until(settled) => {}
+                }
+                // Keep holding while a fullscreen app would swallow the digest
+                if delivery_for(&level, attention.notification_state()) == Delivery::Defer {
+                    tokio::select! {
+                        _ = cancel.cancelled() => return,
+                        _ = tokio::time::sleep(DEFERRED_POLL_INTERVAL) => continue,
+                    }
+                }
+                let Some(digest) = missed.lock().unwrap().finish(Instant::now()) else {
+                    continue;
+                };
+
+                log::info!("Showing digest: {}", digest.title);
+                if let Err(e) = notifier.show_notification(&digest) {
+                    log::error!("Failed to show notification: {}", e);
+                }
+                return;
+            }
+        });
+    }
+
+    /// Flash the taskbar and hold the toast until notifications are accepted again
+    fn defer(&self, alert: Alert) {
+        log::info!(
+            "Fullscreen app in the foreground; deferring toast for alert {}",
+            alert.id
+        );
+        self.attention.request_attention(&alert);
+
+        let mut deferred = self.deferred.lock().unwrap();
+        deferred.push(alert);
+        if self.deferred_poller_running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let deferred_alerts = self.deferred.clone();
+        let running = self.deferred_poller_running.clone();
+        let attention = self.attention.clone();
+        let notifier = self.notifier.clone();
+        let pending = self.pending_confirmations.clone();
+        let cancel: CancellationToken = self.cancel.clone();
+        self.tracker.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = tokio::time::sleep(DEFERRED_POLL_INTERVAL) => {}
+                }
+                if attention.notification_state() != UserNotificationState::AcceptsNotifications {
+                    continue;
+                }
+
+                // Clear the flag under the lock so a new deferral starts a new poller
+                let alerts: Vec<Alert> = {
+                    let mut deferred = deferred_alerts.lock().unwrap();
+                    running.store(false, Ordering::SeqCst);
+                    std::mem::take(&mut *deferred)
+                };
+                attention.clear_attention();
+                for alert in alerts {
+                    log::info!("Showing deferred alert {}", alert.id);
+                    match notifier.show_notification(&alert) {
+                        Ok(()) => {
+                            // Latency counts from now, not from when the alert arrived
+                            if let Some(entry) = pending.lock().await.get_mut(&alert.id) {
+                                entry.shown = Some(Shown::now());
+                            }
+                        }
+                        Err(e) => log::error!("Failed to show notification: {}", e),
+                    }
+                }
+                break;
+            }
+        });
+    }
+
+    /// Alerts waiting for a fullscreen app to leave the foreground
+    pub fn deferred_count(&self) -> usize {
+        self.deferred.lock().unwrap().len()
+    }
+
+    /// Hold the toast until the workstation is unlocked; the sound has already played
+    fn hold_until_unlock(&self, alert: Alert) {
+        log::info!(
+            "Workstation locked; showing alert {} when it is unlocked",
+            alert.id
+        );
+        let mut held = self.held_for_unlock.lock().unwrap();
+        held.push(alert);
+        if self.unlock_waiter_running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let held_alerts = self.held_for_unlock.clone();
+        let running = self.unlock_waiter_running.clone();
+        let mut lock: watch::Receiver<LockState> = self.lock.clone();
+        let notifier = self.notifier.clone();
+        let pending = self.pending_confirmations.clone();
+        let outbound = self.outbound.clone();
+        let client_id: String = self.client_id.clone();
+        let cancel: CancellationToken = self.cancel.clone();
+        self.tracker.spawn(async move {
+            // A monitor that has gone away can no longer report the unlock, so show them now
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = lock.wait_for(|state| !state.is_locked()) => {}
+            }
+
+            // Clear the flag under the lock so a new hold starts a new waiter
+            let alerts: Vec<Alert> = {
+                let mut held = held_alerts.lock().unwrap();
+                running.store(false, Ordering::SeqCst);
+                std::mem::take(&mut *held)
+            };
+            for alert in alerts {
+                log::info!("Workstation unlocked; showing alert {}", alert.id);
+                if let Err(e) = notifier.show_notification(&alert) {
+                    log::error!("Failed to show notification: {}", e);
+                    continue;
+                }
+                // Latency counts from now, not from when the alert arrived
+                if let Some(entry) = pending.lock().await.get_mut(&alert.id) {
+                    entry.shown = Some(Shown::now());
+                }
+                outbound.push(OutboundMessage::DeliveryStatus(DeliveryStatus {
+                    alert_id: alert.id,
+                    client_id: client_id.clone(),
+                    reported_at: chrono::Utc::now(),
+                    attachment: None,
+                    sound: None,
+                    annunciator: None,
+                    callback: None,
+                    outcome: Some(DeliveryOutcome::ShownOnUnlock),
+                    detail: None,
+                    decision: None,
+                    locale: None,
+                }));
+            }
+        });
+    }
+
+    /// Alerts waiting for the workstation to be unlocked
+    pub fn held_for_unlock_count(&self) -> usize {
+        self.held_for_unlock.lock().unwrap().len()
+    }
+
+    /// Manually confirm an alert through `method`
+    pub async fn confirm_alert(
+        &self,
+        alert_id: uuid::Uuid,
+        method: ConfirmationMethod,
+    ) -> Result<()> {
+        self.confirm(alert_id, None, method).await
+    }
+
+    /// Confirm an alert with the answer at `option` in its `response_options`
+    pub async fn respond_to_alert(
+        &self,
+        alert_id: uuid::Uuid,
+        option: usize,
+        method: ConfirmationMethod,
+    ) -> Result<()> {
+        self.confirm(alert_id, Some(option), method).await
+    }
+
+    /// Confirm an alert with the answer whose id is `option_id`
+    pub async fn respond_with_option(
+        &self,
+        alert_id: uuid::Uuid,
+        option_id: &str,
+        method: ConfirmationMethod,
+    ) -> Result<()> {
+        let position: Option<usize> = self
+            .pending_confirmations
+            .lock()
+            .await
+            .get(&alert_id)
+            .and_then(|entry| entry.alert.response_options.as_ref())
+            .and_then(|options| options.iter().position(|option| option.id == option_id));
+        match position {
+            Some(position) => self.confirm(alert_id, Some(position), method).await,
+            None => Err(EmnsError::notification(
+                Some(alert_id),
+                format!("no response option {:?}", option_id),
+            )),
+        }
+    }
+
+    async fn confirm(
+        &self,
+        alert_id: uuid::Uuid,
+        option: Option<usize>,
+        method: ConfirmationMethod,
+    ) -> Result<()> {
+        let operator_id: Option<String> = match &self.operator {
+            Some(operator) => {
+                let Some(title) = self.pending_title(alert_id).await else {
+                    log::warn!("Alert {} not found in pending confirmations", alert_id);
+                    return Ok(());
+                };
+                match self.ask_operator(operator, title).await {
+                    Some(operator_id) => Some(operator_id),
+                    None => {
+                        log::info!(
+                            "Operator id prompt for alert {} cancelled, leaving it pending",
+                            alert_id
+                        );
+                        return Ok(());
+                    }
+                }
+            }
+            None => None,
+        };
+        let mut pending = self.pending_confirmations.lock().await;
+
+        let Some(entry) = pending.get(&alert_id) else {
+            log::warn!("Alert {} not found in pending confirmations", alert_id);
+            return Ok(());
+        };
+        let answer: Option<ResponseOption> = match option {
+            Some(index) => {
+                let chosen = entry
+                    .alert
+                    .response_options
+                    .as_ref()
+                    .and_then(|options| options.get(index));
+                match chosen {
+                    Some(chosen) => Some(chosen.clone()),
+                    None => {
+                        return Err(EmnsError::notification(
+                            Some(alert_id),
+                            format!("no response option {}", index),
+                        ))
+                    }
+                }
+            }
+            None => None,
+        };
+
+        if let Some(entry) = pending.remove(&alert_id) {
+            self.stats.set_pending(pending.len());
+            self.resolved_by_user(entry, ConfirmationReason::User, answer, operator_id, method);
+        }
+        Ok(())
+    }
+
+    /// Confirm every pending alert `filter` selects, oldest first, each with a confirmation of its own.
+    ///
+    /// Emergency alerts are always left for the user to confirm one by one.
+    pub async fn confirm_all(
+        &self,
+        filter: &BulkFilter,
+        method: ConfirmationMethod,
+    ) -> BulkSummary {
+        self.resolve_all(filter, ConfirmationReason::User, method)
+            .await
+    }
+
+    /// Dismiss every pending alert `filter` selects, oldest first, each reported
+    /// as [`ConfirmationReason::Dismissed`].
+    ///
+    /// Emergency alerts are always left for the user to confirm one by one.
+    pub async fn dismiss_all(
+        &self,
+        filter: &BulkFilter,
+        method: ConfirmationMethod,
+    ) -> BulkSummary {
+        self.resolve_all(filter, ConfirmationReason::Dismissed, method)
+            .await
+    }
+
+    async fn resolve_all(
+        &self,
+        filter: &BulkFilter,
+        reason: ConfirmationReason,
+        method: ConfirmationMethod,
+    ) -> BulkSummary {
+        let now: Instant = Instant::now();
+        let (selected, total): (Vec<uuid::Uuid>, usize) = {
+            let pending = self.pending_confirmations.lock().await;
+            let mut selected: Vec<(Instant, uuid::Uuid)> = pending
+                .values()
+                .filter(|entry| {
+                    filter.matches(
+                        &entry.alert.level,
+                        now.saturating_duration_since(entry.received),
+                    )
+                })
+                .map(|entry| (entry.received, entry.alert.id))
+                .collect();
+            selected.sort();
+            (
+                selected.into_iter().map(|(_, alert_id)| alert_id).collect(),
+                pending.len(),
+            )
+        };
+        if selected.is_empty() {
+            return BulkSummary {
+                resolved: Vec::new(),
+                skipped: total,
+            };
+        }
+        // One prompt answers for every alert in the batch
+        let operator_id: Option<String> = match &self.operator {
+            Some(operator) => {
+                let title: String = format!("{} pending alerts", selected.len());
+                match self.ask_operator(operator, title).await {
+                    Some(operator_id) => Some(operator_id),
+                    None => {
+                        log::info!(
+                            "Operator id prompt cancelled, leaving {} alerts pending",
+                            selected.len()
+                        );
+                        return BulkSummary {
+                            resolved: Vec::new(),
+                            skipped: total,
+                        };
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let mut pending = self.pending_confirmations.lock().await;
+        let mut resolved: Vec<uuid::Uuid> = Vec::with_capacity(selected.len());
+        for alert_id in selected {
+            // Confirmed or timed out while the operator was being asked
+            let Some(entry) = pending.remove(&alert_id) else {
+                continue;
+            };
+            self.stats.set_pending(pending.len());
+            self.deferred.lock().unwrap().retain(|a| a.id != alert_id);
+            self.held_for_unlock
+                .lock()
+                .unwrap()
+                .retain(|a| a.id != alert_id);
+            if let Err(e) = self.notifier.remove_notification(alert_id) {
+                log::warn!("Failed to remove toast for alert {}: {}", alert_id, e);
+            }
+            self.resolved_by_user(entry, reason, None, operator_id.clone(), method);
+            resolved.push(alert_id);
+        }
+        log::info!(
+            "{} {} alerts at once, {} left pending",
+            if reason == ConfirmationReason::Dismissed {
+                "Dismissed"
+            } else {
+                "Confirmed"
+            },
+            resolved.len(),
+            pending.len()
+        );
+        BulkSummary {
+            resolved,
+            skipped: pending.len(),
+        }
+    }
+
+    /// Report an alert the user confirmed or dismissed through `method`,
+    /// already taken out of the pending set, with the `answer` chosen from
+    /// its response options
+    fn resolved_by_user(
+        &self,
+        entry: PendingAlert,
+        reason: ConfirmationReason,
+        answer: Option<ResponseOption>,
+        operator_id: Option<String>,
+        method: ConfirmationMethod,
+    ) {
+        let (response_id, response) = match answer {
+            Some(answer) => (Some(answer.id), answer.response),
+            None => (None, ConfirmationResponse::Acknowledged),
+        };
+        let alert_id: uuid::Uuid = entry.alert.id;
+        let confirmed_at: chrono::DateTime<chrono::Utc> = self.wall_clock();
+        let (shown_at, response_latency_ms) =
+            Shown::report(entry.shown, Instant::now(), confirmed_at);
+        let mut deadlines = self.deadlines.lock().unwrap();
+        deadlines.remove(&Deadline::AutoConfirm(alert_id));
+        deadlines.remove(&Deadline::ReleaseWake(alert_id));
+        deadlines.remove(&Deadline::Escalate(alert_id));
+        deadlines.remove(&Deadline::Repeat(alert_id));
+        deadlines.remove(&Deadline::ExpirePreview(alert_id));
+        deadlines.remove(&Deadline::Expire(alert_id));
+        deadlines.remove(&Deadline::Remind(alert_id));
+        drop(deadlines);
+        for sink in self.sinks.iter() {
+            sink.resolved(alert_id, Resolution::Confirmed(reason));
+        }
+        match (&response_id, reason) {
+            (_, ConfirmationReason::Dismissed) => {
+                log::info!("Alert {} dismissed by user", alert_id)
+            }
+            (Some(response_id), _) => log::info!(
+                "Alert {} answered {:?} ({:?}) by user",
+                alert_id,
+                response_id,
+                response
+            ),
+            (None, _) => log::info!("Alert {} confirmed by user", alert_id),
+        }
+
+        let confirmation = Confirmation {
+            alert_id,
+            client_id: self.client_id.clone(),
+            confirmed_at,
+            hostname: get_hostname(),
+            username: get_username(),
+            operator_id,
+            reason,
+            method: Some(method),
+            user_idle_secs: self.idle.idle_time().map(|idle| idle.as_secs()),
+            response_id,
+            response,
+            received_via: entry.received_via,
+            shown_at,
+            response_latency_ms,
+            is_preview: entry.alert.is_preview,
+        };
+        // Only confirmations call back; a dismissal is not one
+        let callback: Option<(String, CallbackBody)> = entry
+            .alert
+            .confirm_callback_url
+            .filter(|_| reason == ConfirmationReason::User)
+            .map(|url| (url, CallbackBody::from(&confirmation)));
+        let carried: Vec<Confirmation> = supersede::carried_over(&confirmation, &entry.supersedes);
+
+        self.outbound
+            .push(OutboundMessage::Confirmation(confirmation));
+        for confirmation in carried {
+            self.outbound
+                .push(OutboundMessage::Confirmation(confirmation));
+        }
+        if let Some((url, body)) = callback {
+            self.send_confirm_callback(url, body);
+        }
+    }
+
+    async fn pending_title(&self, alert_id: uuid::Uuid) -> Option<String> {
+        self.pending_confirmations
+            .lock()
+            .await
+            .get(&alert_id)
+            .map(|entry| NotificationManager::display_title(&entry.alert))
+    }
+
+    /// The operator id for confirming the alert titled `title`, or `None` if the prompt was cancelled
+    async fn ask_operator(
+        &self,
+        operator: &Arc<OperatorIdentity>,
+        title: String,
+    ) -> Option<String> {
+        // The prompt blocks until answered, so it gets a thread of its own
+        let operator: Arc<OperatorIdentity> = operator.clone();
+        let asking = tokio::task::spawn_blocking(move || operator.ask(&title));
+        tokio::select! {
+            _ = self.cancel.cancelled() => None,
+            answer = asking => answer.unwrap_or_else(|e| {
+                log::error!("Operator id prompt failed: {}", e);
+                None
+            }),
+        }
+    }
+
+    /// POST a confirmation to its alert's callback URL in the background and
+    /// record how it went; the confirmation to the server never waits on this
+    fn send_confirm_callback(&self, url: String, body: CallbackBody) {
+        let callbacks: Arc<CallbackSender> = self.callbacks.clone();
+        let history: Arc<AlertHistory> = self.history.clone();
+        let outbound: Arc<OutboundQueue> = self.outbound.clone();
+        let client_id: String = self.client_id.clone();
+        let cancel: CancellationToken = self.cancel.clone();
+        self.tracker.spawn(async move {
+            let sent = tokio::select! {
+                _ = cancel.cancelled() => return,
+                sent = callbacks.send(&url, &body) => sent,
+            };
+            let (state, detail) = match sent {
+                Ok(()) => {
+                    log::info!("Confirm callback for alert {} delivered", body.alert_id);
+                    (CallbackState::Delivered, None)
+                }
+                Err(e) => {
+                    log::warn!("Confirm callback for alert {} failed: {}", body.alert_id, e);
+                    (CallbackState::Failed, Some(e.to_string()))
+                }
+            };
+            history.mark_callback(body.alert_id, state);
+            outbound.push(OutboundMessage::DeliveryStatus(DeliveryStatus {
+                alert_id: body.alert_id,
+                client_id,
+                reported_at: chrono::Utc::now(),
+                attachment: None,
+                sound: None,
+                outcome: None,
+                annunciator: None,
+                callback: Some(state),
+                detail,
+                decision: None,
+                locale: None,
+            }));
+        });
+    }
+
+    /// Start the task that auto-confirms alerts and releases display wakes as deadlines pass
+    fn spawn_sweeper(&self) {
+        let pending = self.pending_confirmations.clone();
+        let stats = self.stats.clone();
+        let deadlines = self.deadlines.clone();
+        let wake = self.deadline_wake.clone();
+        let outbound = self.outbound.clone();
+        let client_id = self.client_id.clone();
+        let idle_probe = self.idle.clone();
+        let audio = self.audio.clone();
+        let history = self.history.clone();
+        let escalation: EscalationPolicy = self.escalation.clone();
+        let settings: SharedSettings = self.settings.clone();
+        let notifier = self.notifier.clone();
+        let deferred = self.deferred.clone();
+        let held_for_unlock = self.held_for_unlock.clone();
+        let lock: watch::Receiver<LockState> = self.lock.clone();
+        let sinks = self.sinks.clone();
+        let reminders: Option<Arc<ReminderSender>> = self.reminders.clone();
+        let clock: Arc<dyn Clock> = self.clock.clone();
+        let clock_jumps: Arc<JumpDetector> = self.clock_jumps.clone();
+        let cancel: CancellationToken = self.cancel.clone();
+        let tracker: TaskTracker = self.tracker.clone();
+
+        self.tracker.spawn(async move {
+            loop {
+                let next: Option<Instant> = deadlines.lock().unwrap().next_deadline();
+                tokio::select! {
+                    _ = cancel.cancelled() => {
+                        // Never hold the display awake or keep sounding past shutdown
+                        for alert in pending.lock().await.values_mut() {
+                            alert.wake.take();
+                            alert.escalation.take();
+                        }
+                        break;
+                    }
+                    // A new earliest deadline was added
+                    _ = wake.notified() => continue,
+                    _ = tokio::time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {}
+                }
+                check_clock(&*clock, &clock_jumps, &stats);
+
+                let expired: Vec<Deadline> = deadlines.lock().unwrap().pop_expired(Instant::now());
+                for deadline in expired {
+                    let alert_id: uuid::Uuid = match deadline {
+                        Deadline::AutoConfirm(alert_id) => alert_id,
+                        Deadline::ReleaseWake(alert_id) => {
+                            if let Some(alert) = pending.lock().await.get_mut(&alert_id) {
+                                if alert.wake.take().is_some() {
+                                    log::info!("Releasing display wake for alert {}", alert_id);
+                                }
+                            }
+                            continue;
+                        }
+                        Deadline::Escalate(alert_id) => {
+                            let mut pending = pending.lock().await;
+                            let Some(alert) = pending.get_mut(&alert_id) else {
+                                continue;
+                            };
+                            let Some(step) = escalation.for_level(&alert.alert.level) else {
+                                continue;
+                            };
+                            log::warn!(
+                                "Alert {} still unconfirmed after {:?}, escalating",
+                                alert_id,
+                                step.after
+                            );
+                            let settings: AgentSettings = settings.snapshot();
+                            let policy: &SoundPolicy = settings.sound_policy();
+                            if settings.sounds_enabled() && policy.permits(&step.sound_file) {
+                                alert.escalation = Some(audio.play_at(
+                                    &step.sound_file,
+                                    policy.limit_volume(ESCALATION_VOLUME),
+                                ));
+                            }
+                            history.mark_escalated(alert_id, clock.now());
+                            stats.board.bump();
+                            continue;
+                        }
+                        Deadline::ExpirePreview(alert_id) | Deadline::Expire(alert_id) => {
+                            let preview: bool = matches!(deadline, Deadline::ExpirePreview(_));
+                            let entry: Option<PendingAlert> = pending.lock().await.remove(&alert_id);
+                            if entry.is_some() {
+                                stats.set_pending(pending.lock().await.len());
+                                let mut deadlines = deadlines.lock().unwrap();
+                                deadlines.remove(&Deadline::AutoConfirm(alert_id));
+                                deadlines.remove(&Deadline::ReleaseWake(alert_id));
+                                deadlines.remove(&Deadline::Escalate(alert_id));
+                                deadlines.remove(&Deadline::Repeat(alert_id));
+                                deadlines.remove(&Deadline::Remind(alert_id));
+                                deadlines.remove(&Deadline::ExpirePreview(alert_id));
+                                deadlines.remove(&Deadline::Expire(alert_id));
+                            }
+                            deferred.lock().unwrap().retain(|a| a.id != alert_id);
+                            held_for_unlock.lock().unwrap().retain(|a| a.id != alert_id);
+                            if preview {
+                                log::info!("Preview alert {} expired; removing it", alert_id);
+                            } else {
+                                log::warn!("Alert {} expired unconfirmed; removing it", alert_id);
+                            }
+                            if let Err(e) = notifier.remove_notification(alert_id) {
+                                log::warn!("Failed to remove toast for alert {}: {}", alert_id, e);
+                            }
+                            // Nobody answered, so it is dropped rather than auto-confirmed
+                            if entry.is_some() {
+                                for sink in sinks.iter() {
+                                    sink.resolved(alert_id, Resolution::Withdrawn(Withdrawal::Expired));
+                                }
+                            }
+                            // Reported along with the earlier alerts it superseded
+                            if let Some(entry) = entry.filter(|_| !preview) {
+                                for alert_id in [alert_id].into_iter().chain(entry.supersedes) {
+                                    outbound.push(OutboundMessage::AlertExpired {
+                                        client_id: client_id.clone(),
+                                        alert_id,
+                                        unconfirmed: true,
+                                    });
+                                }
+                            }
+                            continue;
+                        }
+                        Deadline::Remind(alert_id) => {
+                            let Some(reminders) = reminders.clone() else {
+                                continue;
+                            };
+                            let body: ReminderBody = {
+                                let pending = pending.lock().await;
+                                let Some(entry) = pending.get(&alert_id) else {
+                                    continue;
+                                };
+                                let now: chrono::DateTime<chrono::Utc> = clock.now();
+                                let deadline: Option<chrono::DateTime<chrono::Utc>> =
+                                    entry.deadline().map(|deadline| {
+                                        let left: Duration =
+                                            deadline.saturating_duration_since(Instant::now());
+                                        now + chrono::TimeDelta::from_std(left).unwrap_or_default()
+                                    });
+                                ReminderBody::new(
+                                    &entry.alert,
+                                    deadline,
+                                    reminders.config().include_body,
+                                )
+                            };
+                            log::info!("Alert {} still unconfirmed, sending a reminder", alert_id);
+                            // Sent off the sweeper, so a slow webhook holds up no other deadline
+                            let cancel: CancellationToken = cancel.clone();
+                            tracker.spawn(async move {
+                                tokio::select! {
+                                    _ = cancel.cancelled() => {}
+                                    sent = reminders.send(&body) => match sent {
+                                        Ok(()) => log::info!("Reminder for alert {} delivered", alert_id),
+                                        Err(e) => log::warn!("Reminder for alert {} failed: {}", alert_id, e),
+                                    },
+                                }
+                            });
+                            continue;
+                        }
+                        Deadline::Repeat(alert_id) => {
+                            let mut pending = pending.lock().await;
+                            let Some(entry) = pending.get_mut(&alert_id) else {
+                                continue;
+                            };
+                            let now: Instant = Instant::now();
+                            let mut deadlines = deadlines.lock().unwrap();
+                            // Still held behind a fullscreen app or the lock screen;
+                            // repeats only count once the user could have seen it
+                            if entry.shown.is_none() {
+                                deadlines.insert(Deadline::Repeat(alert_id), now + entry.repeats.interval);
+                                continue;
+                            }
+                            entry.repeated += 1;
+                            log::warn!(
+                                "Alert {} still unconfirmed, showing it again ({} of {})",
+                                alert_id,
+                                entry.repeated,
+                                entry.repeats.max
+                            );
+                            let reminder: Alert =
+                                repeat_of(&entry.alert, entry.repeated, entry.repeats.max);
+                            match notifier.show_notification(&reminder) {
+                                Ok(()) => entry.countdown_live = true,
+                                Err(e) => log::warn!("Failed to show alert {} again: {}", alert_id, e),
+                            }
+                            if let Some(sound_file) = &entry.sound {
+                                let settings: AgentSettings = settings.snapshot();
+                                if settings.sounds_enabled() && settings.sound_policy().permits(sound_file) {
+                                    audio.play(sound_file);
+                                }
+                            }
+                            if entry.repeated < entry.repeats.max {
+                                deadlines.insert(Deadline::Repeat(alert_id), now + entry.repeats.interval);
+                            }
+                            if !deadlines.contains(&Deadline::RefreshCountdowns) {
+                                deadlines.insert(
+                                    Deadline::RefreshCountdowns,
+                                    now + COUNTDOWN_REFRESH_INTERVAL,
+                                );
+                            }
+                            continue;
+                        }
+                        Deadline::RefreshCountdowns => {
+                            // Held toasts are not on screen yet
+                            let mut hidden: Vec<uuid::Uuid> =
+                                deferred.lock().unwrap().iter().map(|a| a.id).collect();
+                            hidden.extend(held_for_unlock.lock().unwrap().iter().map(|a| a.id));
+                            if refresh_countdowns(&pending, &hidden, notifier.as_ref()).await {
+                                deadlines.lock().unwrap().insert(
+                                    Deadline::RefreshCountdowns,
+                                    Instant::now() + COUNTDOWN_REFRESH_INTERVAL,
+                                );
+                            }
+                            continue;
+                        }
+                    };
+                    let idle: Option<Duration> = idle_probe.idle_time();
+                    let lock_state: LockState = *lock.borrow();
+                    let (reason, received_via, shown, is_preview, supersedes) = {
+                        let mut pending = pending.lock().await;
+                        let Some(entry) = pending.get_mut(&alert_id) else {
+                            continue;
+                        };
+                        let Some(window) = entry.window.as_mut() else {
+                            continue;
+                        };
+                        if let Some(at) = window.hold_while_locked(Instant::now(), &lock_state)
+                        {
+                            log::info!(
+                                "Alert {} auto-confirm paused for time the workstation was locked",
+                                alert_id
+                            );
+                            deadlines
+                                .lock()
+                                .unwrap()
+                                .insert(Deadline::AutoConfirm(alert_id), at);
+                            continue;
+                        }
+                        match window.on_timeout(Instant::now(), idle) {
+                            TimeoutOutcome::RecheckAt(at) => {
+                                log::info!(
+                                    "Alert {} timed out but the machine is idle, holding it",
+                                    alert_id
+                                );
+                                deadlines
+                                    .lock()
+                                    .unwrap()
+                                    .insert(Deadline::AutoConfirm(alert_id), at);
+                                continue;
+                            }
+                            TimeoutOutcome::Confirm(reason) => {
+                                let received_via: ReceivedVia = entry.received_via;
+                                let shown: Option<Shown> = entry.shown;
+                                let is_preview: bool = entry.alert.is_preview;
+                                let supersedes: Vec<uuid::Uuid> = std::mem::take(&mut entry.supersedes);
+                                pending.remove(&alert_id);
+                                stats.set_pending(pending.len());
+                                (reason, received_via, shown, is_preview, supersedes)
+                            }
+                        }
+                    };
+                    {
+                        let mut deadlines = deadlines.lock().unwrap();
+                        deadlines.remove(&Deadline::ReleaseWake(alert_id));
+                        deadlines.remove(&Deadline::Escalate(alert_id));
+                        deadlines.remove(&Deadline::Repeat(alert_id));
+                        deadlines.remove(&Deadline::ExpirePreview(alert_id));
+                        deadlines.remove(&Deadline::Expire(alert_id));
+                        deadlines.remove(&Deadline::Remind(alert_id));
+                    }
+                    if reason == ConfirmationReason::TimedOutIdle {
+                        log::warn!(
+                            "Alert {} timed out with nobody at the machine, dismissing",
+                            alert_id
+                        );
+                    } else {
+                        log::warn!(
+                            "Alert {} not confirmed within timeout, auto-confirming",
+                            alert_id
+                        );
+                    }
+                    for sink in sinks.iter() {
+                        sink.resolved(alert_id, Resolution::Confirmed(reason));
+                    }
+
+                    // The toast was up for the whole timeout; `reason` marks it as unanswered
+                    let confirmed_at: chrono::DateTime<chrono::Utc> = clock.now();
+                    let (shown_at, response_latency_ms) =
+                        Shown::report(shown, Instant::now(), confirmed_at);
+                    let confirmation = Confirmation {
+                        alert_id,
+                        client_id: client_id.clone(),
+                        confirmed_at,
+                        hostname: get_hostname(),
+                        username: get_username(),
+                        operator_id: None,
+                        reason,
+                        method: Some(ConfirmationMethod::AutoTimeout),
+                        user_idle_secs: idle.map(|idle| idle.as_secs()),
+                        response_id: None,
+                        response: ConfirmationResponse::Acknowledged,
+                        received_via,
+                        shown_at,
+                        response_latency_ms,
+                        is_preview,
+                    };
+
+                    let carried: Vec<Confirmation> =
+                        supersede::carried_over(&confirmation, &supersedes);
+                    outbound.push(OutboundMessage::Confirmation(confirmation));
+                    for confirmation in carried {
+                        outbound.push(OutboundMessage::Confirmation(confirmation));
+                    }
+                }
+            }
+            log::debug!("Auto-confirm sweeper stopped");
+        });
+    }
+
+    /// Stop waiting on an alert the server no longer considers active.
+    ///
+    /// Its toast is removed and no confirmation is ever sent; an alert still
+    /// held back for a fullscreen app or the lock screen is never shown.
+    /// Returns `false` if the alert was not pending.
+    pub async fn withdraw(&self, alert_id: uuid::Uuid, withdrawal: Withdrawal) -> bool {
+        let mut pending = self.pending_confirmations.lock().await;
+        let Some(entry) = pending.remove(&alert_id) else {
+            return false;
+        };
+        self.stats.set_pending(pending.len());
+        drop(pending);
+        {
+            let mut deadlines = self.deadlines.lock().unwrap();
+            deadlines.remove(&Deadline::AutoConfirm(alert_id));
+            deadlines.remove(&Deadline::ReleaseWake(alert_id));
+            deadlines.remove(&Deadline::Escalate(alert_id));
+            deadlines.remove(&Deadline::Repeat(alert_id));
+            deadlines.remove(&Deadline::ExpirePreview(alert_id));
+            deadlines.remove(&Deadline::Expire(alert_id));
+            deadlines.remove(&Deadline::Remind(alert_id));
+        }
+        self.deferred.lock().unwrap().retain(|a| a.id != alert_id);
+        self.held_for_unlock
+            .lock()
+            .unwrap()
+            .retain(|a| a.id != alert_id);
+        // Releases the display wake and stops any escalation sound
+        drop(entry);
+
+        log::info!(
+            "Alert {} was {:?} by the server; withdrawing it",
+            alert_id,
+            withdrawal
+        );
+        if let Err(e) = self.notifier.remove_notification(alert_id) {
+            log::warn!("Failed to remove toast for alert {}: {}", alert_id, e);
+        }
+        for sink in self.sinks.iter() {
+            sink.resolved(alert_id, Resolution::Withdrawn(withdrawal));
+        }
+        true
+    }
+
+    /// The server took back an alert, e.g. one sent by mistake: withdraw it and
+    /// say so in a short info toast.
+    ///
+    /// Returns `false` if it was not pending, e.g. because it was confirmed
+    /// here first; cancelling it again changes nothing.
+    pub async fn cancel(&self, alert_id: uuid::Uuid, reason: Option<&str>) -> bool {
+        let title: Option<String> = self
+            .pending_confirmations
+            .lock()
+            .await
+            .get(&alert_id)
+            .map(|entry| entry.alert.title.clone());
+        let Some(title) = title else {
+            log::debug!("Cancelled alert {} is not pending here", alert_id);
+            return false;
+        };
+        if !self.withdraw(alert_id, Withdrawal::Cancelled).await {
+            return false;
+        }
+        let notice: Alert = simple_alert(
+            &match reason {
+                Some(reason) => format!("Alert cancelled: {}", reason),
+                None => "Alert cancelled".to_string(),
+            },
+            &format!("\"{}\" was withdrawn by the sender.", title),
+        );
+        if let Err(e) = self.notifier.show_notification(&notice) {
+            log::warn!("Failed to show cancellation of alert {}: {}", alert_id, e);
+        }
+        true
+    }
+
+    /// Enough of the team has acknowledged a quorum alert: stop escalating it
+    /// and say on its toast who responded.
+    ///
+    /// The alert stays pending, so its own user can still confirm it and the
+    /// confirmation is sent as usual. Returns `false` if it was not pending,
+    /// e.g. because it was confirmed here first.
+    pub async fn quorum_met(&self, alert_id: uuid::Uuid, confirmed_by: &[String]) -> bool {
+        let mut pending = self.pending_confirmations.lock().await;
+        let Some(entry) = pending.get_mut(&alert_id) else {
+            log::debug!(
+                "Quorum met for alert {}, which is not pending here",
+                alert_id
+            );
+            return false;
+        };
+        // Stops any escalation sound already playing
+        entry.escalation = None;
+        // The countdown would overwrite the acknowledgment
+        entry.countdown_live = false;
+        {
+            let mut deadlines = self.deadlines.lock().unwrap();
+            deadlines.remove(&Deadline::Escalate(alert_id));
+            deadlines.remove(&Deadline::Repeat(alert_id));
+            // The team has it in hand; no need to chase this user too
+            deadlines.remove(&Deadline::Remind(alert_id));
+        }
+        drop(pending);
+
+        log::info!(
+            "Quorum met for alert {} by {}; no longer escalating it",
+            alert_id,
+            confirmed_by.join(", ")
+        );
+        if let Err(e) = self.notifier.show_acknowledged(alert_id, confirmed_by) {
+            log::warn!("Failed to update toast for alert {}: {}", alert_id, e);
+        }
+        self.stats.board.bump();
+        true
+    }
+
+    /// The user closed an alert's toast; stop updating its countdown
+    pub async fn toast_dismissed(&self, alert_id: uuid::Uuid) {
+        if let Some(alert) = self.pending_confirmations.lock().await.get_mut(&alert_id) {
+            alert.countdown_live = false;
+        }
+    }
+
+    /// Alerts currently waiting for confirmation
+    pub async fn pending_alerts(&self) -> Vec<Alert> {
+        self.pending_confirmations
+            .lock()
+            .await
+            .values()
+            .map(|p| p.alert.clone())
+            .collect()
+    }
+
+    /// Whether `alert_id` is waiting for confirmation
+    pub async fn is_pending(&self, alert_id: uuid::Uuid) -> bool {
+        self.pending_confirmations
+            .lock()
+            .await
+            .contains_key(&alert_id)
+    }
+
+    /// Whether an Emergency alert is waiting for confirmation
+    pub async fn emergency_pending(&self) -> bool {
+        self.pending_confirmations
+            .lock()
+            .await
+            .values()
+            .any(|entry| entry.alert.level == AlertLevel::Emergency)
+    }
+
+    /// Get pending confirmations count
+    pub async fn pending_count(&self) -> usize {
+        self.pending_confirmations.lock().await.len()
+    }
+
+    /// Get all pending alert IDs
+    pub async fn get_pending_alerts(&self) -> Vec<uuid::Uuid> {
+        self.pending_confirmations
+            .lock()
+            .await
+            .keys()
+            .copied()
+            .collect()
+    }
+}
+
+/// Sound `alert` plays, taking level sounds from the active sound pack if there is one
+fn sound_for(sounds: Option<&SoundLibrary>, alert: &Alert) -> String {
+    sounds.map_or_else(|| alert.get_sound_file(), |sounds| sounds.sound_for(alert))
+}
+
+/// Whether the settings allow a sound for an alert of `level` right now
+fn should_play_sound(settings: &AgentSettings, level: &AlertLevel) -> bool {
+    if !settings.sounds_enabled() {
+        return false;
+    }
+    let routine: bool = matches!(level, AlertLevel::Info | AlertLevel::Warning);
+    match settings.quiet_hours() {
+        Some(window) if routine => !window.contains(chrono::Local::now().time()),
+        _ => true,
+    }
+}
+
+/// Report that the sound policy kept an alert silent
+pub(crate) fn sound_suppressed_status(alert_id: uuid::Uuid, client_id: &str) -> OutboundMessage {
+    OutboundMessage::DeliveryStatus(DeliveryStatus {
+        alert_id,
+        client_id: client_id.to_string(),
+        reported_at: chrono::Utc::now(),
+        attachment: None,
+        sound: Some(SoundDelivery::SuppressedByPolicy),
+        annunciator: None,
+        callback: None,
+        outcome: None,
+        detail: None,
+        decision: None,
+        locale: None,
+    })
+}
+
+/// Silent toast shown in place of an alert hidden by role; says nothing of its content
+fn hidden_placeholder(now: chrono::DateTime<chrono::Utc>) -> Alert {
+    Alert {
+        id: uuid::Uuid::new_v4(),
+        title: "Alert issued".to_string(),
+        message: "An alert was issued \u{2014} see your supervisor.".to_string(),
+        level: AlertLevel::Info,
+        requires_confirmation: false,
+        sound_file: None,
+        timestamp: now,
+        origin: AlertOrigin::Local,
+        ..Default::default()
+    }
+}
+
+/// `alert` as shown on its `repeat`th repeat of `of`, noting which in its
+/// message and in each translation's
+fn repeat_of(alert: &Alert, repeat: u32, of: u32) -> Alert {
+    let note = |message: &str| format!("{}\n\nReminder {} of {}", message, repeat, of);
+    let mut alert: Alert = alert.clone();
+    alert.message = note(&alert.message);
+    for translation in alert.translations.values_mut() {
+        translation.message = note(&translation.message);
+    }
+    alert
+}
+
+/// Bring the countdown on each pending alert's toast up to date.
+///
+/// Toasts found to be gone are not updated again. Returns whether any
+/// countdown is still live.
+async fn refresh_countdowns(
+    pending: &Mutex<HashMap<uuid::Uuid, PendingAlert>>,
+    hidden: &[uuid::Uuid],
+    notifier: &dyn NotificationBackend,
+) -> bool {
+    let now: Instant = Instant::now();
+    let due: Vec<(uuid::Uuid, Countdown)> = pending
+        .lock()
+        .await
+        .iter()
+        .filter(|(id, p)| p.countdown_live && !hidden.contains(id))
+        .filter_map(|(id, p)| Some((*id, Countdown::until(p.deadline()?, now))))
+        .collect();
+
+    let mut gone: Vec<uuid::Uuid> = Vec::new();
+    for (alert_id, countdown) in due {
+        match notifier.update_countdown(alert_id, &countdown) {
+            Ok(true) => {}
+            Ok(false) => {
+                log::debug!(
+                    "Toast for alert {} is gone, stopping its countdown",
+                    alert_id
+                );
+                gone.push(alert_id);
+            }
+            Err(e) => {
+                log::warn!("Failed to update countdown: {}", e);
+                gone.push(alert_id);
+            }
+        }
+    }
+
+    let mut pending = pending.lock().await;
+    for alert_id in gone {
+        if let Some(alert) = pending.get_mut(&alert_id) {
+            alert.countdown_live = false;
+        }
+    }
+    pending.values().any(|p| p.countdown_live)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::category::CategoryConfig;
+    use crate::lock::LockTracker;
+    use crate::messages::{AlertEscalation, SuppressionWindow};
+    use crate::messages::{DecisionSummary, DeliveryTiming, StageTiming};
+    use crate::operator::{OperatorIdConfig, OperatorQuestion};
+    use crate::settings::QuietHours;
+    use crate::test_support::{
+        alert, Confirmations, ManualClock, MockAttention, MockAudio, MockIdle, MockNotifier,
+        MockOperatorPrompt, MockPower,
+    };
+    use crate::timing::Stage;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_next_alert_observes_new_auto_confirm_timeout() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let settings: SharedSettings = SharedSettings::default();
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(Arc::new(MockNotifier::default()))
+            .audio_backend(audio.clone())
+            .settings(settings.clone())
+            .build();
+
+        let before: Alert = alert(AlertLevel::Warning, true);
+        handler.handle_alert(before.clone()).await.unwrap();
+
+        settings
+            .update(|s| {
+                s.set_auto_confirm_timeout(Some(Duration::from_secs(10)))?;
+                s.set_sounds_enabled(false);
+                Ok(())
+            })
+            .unwrap();
+        let after: Alert = alert(AlertLevel::Warning, true);
+        handler.handle_alert(after.clone()).await.unwrap();
+
+        let start: tokio::time::Instant = tokio::time::Instant::now();
+        let first: Confirmation = confirmations.recv().await;
+        assert_eq!(first.alert_id, after.id);
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        assert_eq!(handler.get_pending_alerts().await, vec![before.id]);
+
+        // Only the alert handled before sounds were disabled played one
+        assert_eq!(audio.played().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_alerts_own_confirm_timeout_wins_and_zero_never_times_out() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let settings: SharedSettings = SharedSettings::default();
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(Arc::new(MockNotifier::default()))
+            .audio_backend(Arc::new(MockAudio::default()))
+            .settings(settings.clone())
+            .build();
+
+        settings
+            .update(|s| s.set_auto_confirm_timeout(None))
+            .unwrap();
+        let waiting: Alert = alert(AlertLevel::Warning, true);
+        let quick: Alert = Alert {
+            confirm_timeout_secs: Some(30),
+            ..alert(AlertLevel::Warning, true)
+        };
+        handler.handle_alert(waiting.clone()).await.unwrap();
+        handler.handle_alert(quick.clone()).await.unwrap();
+
+        let start: tokio::time::Instant = tokio::time::Instant::now();
+        let timed_out: Confirmation = confirmations.recv().await;
+        assert_eq!(timed_out.alert_id, quick.id);
+        assert_eq!(timed_out.reason, ConfirmationReason::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+
+        // With a timeout set again, an alert may still opt out of it
+        settings
+            .update(|s| s.set_auto_confirm_timeout(Some(Duration::from_secs(60))))
+            .unwrap();
+        let held: Alert = Alert {
+            confirm_timeout_secs: Some(0),
+            ..alert(AlertLevel::Critical, true)
+        };
+        handler.handle_alert(held.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(2 * 86_400)).await;
+        assert!(confirmations.try_recv().is_none());
+        let mut pending: Vec<uuid::Uuid> = handler.get_pending_alerts().await;
+        pending.sort();
+        let mut expected: Vec<uuid::Uuid> = vec![waiting.id, held.id];
+        expected.sort();
+        assert_eq!(pending, expected);
+
+        // Only someone confirming ends the wait
+        handler
+            .confirm_alert(held.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        let confirmed: Confirmation = confirmations.recv().await;
+        assert_eq!(confirmed.alert_id, held.id);
+        assert_eq!(confirmed.reason, ConfirmationReason::User);
+    }
+
+    fn handler_with(
+        outbound: Arc<OutboundQueue>,
+        cancel: CancellationToken,
+        tracker: TaskTracker,
+    ) -> AlertHandler {
+        AlertHandler::builder(outbound, "test-client")
+            .notification_backend(Arc::new(MockNotifier::default()))
+            .audio_backend(Arc::new(MockAudio::default()))
+            .cancellation(cancel)
+            .task_tracker(tracker)
+            .build()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_confirm_before_deadline_sends_once() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let handler: AlertHandler = handler_with(
+            outbound.clone(),
+            CancellationToken::new(),
+            TaskTracker::new(),
+        );
+
+        let pending: Alert = alert(AlertLevel::Critical, true);
+        handler.handle_alert(pending.clone()).await.unwrap();
+        handler
+            .confirm_alert(pending.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        assert_eq!(confirmations.recv().await.alert_id, pending.id);
+
+        // Well past the deadline, nothing more is sent
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert!(confirmations.try_recv().is_none());
+        assert_eq!(handler.pending_count().await, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_thousands_of_deadlines_use_one_task() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let tracker: TaskTracker = TaskTracker::new();
+        let handler: AlertHandler =
+            handler_with(outbound.clone(), CancellationToken::new(), tracker.clone());
+
+        let mut alerts: Vec<Alert> = Vec::new();
+        for _ in 0..2000 {
+            let alert: Alert = alert(AlertLevel::Warning, true);
+            handler.handle_alert(alert.clone()).await.unwrap();
+            alerts.push(alert);
+        }
+        assert_eq!(tracker.len(), 1);
+
+        for alert in alerts.iter().step_by(2) {
+            handler
+                .confirm_alert(alert.id, ConfirmationMethod::ToastButton)
+                .await
+                .unwrap();
+        }
+        for _ in 0..1000 {
+            confirmations.recv().await;
+        }
+
+        let start: Instant = Instant::now();
+        let mut timed_out: Vec<uuid::Uuid> = Vec::new();
+        for _ in 0..1000 {
+            timed_out.push(confirmations.recv().await.alert_id);
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(300));
+        let mut expected: Vec<uuid::Uuid> =
+            alerts.iter().skip(1).step_by(2).map(|a| a.id).collect();
+        expected.sort();
+        timed_out.sort();
+        assert_eq!(timed_out, expected);
+        assert_eq!(handler.pending_count().await, 0);
+        assert_eq!(tracker.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancellation_stops_sweeper_without_confirming() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let cancel: CancellationToken = CancellationToken::new();
+        let tracker: TaskTracker = TaskTracker::new();
+        let handler: AlertHandler = handler_with(outbound.clone(), cancel.clone(), tracker.clone());
+
+        for _ in 0..1000 {
+            handler
+                .handle_alert(alert(AlertLevel::Warning, true))
+                .await
+                .unwrap();
+        }
+
+        cancel.cancel();
+        tracker.close();
+        tracker.wait().await;
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert!(confirmations.try_recv().is_none());
+        assert_eq!(handler.pending_count().await, 1000);
+    }
+
+    #[tokio::test]
+    async fn test_details_available_after_restart() {
+        let path: PathBuf = std::env::temp_dir()
+            .join(format!("emns-details-{}", uuid::Uuid::new_v4()))
+            .join(crate::history::HISTORY_FILE);
+        let build = || {
+            let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+            AlertHandler::builder(outbound.clone(), "test-client")
+                .notification_backend(Arc::new(MockNotifier::default()))
+                .audio_backend(Arc::new(MockAudio::default()))
+                .history(AlertHistory::open(&path).unwrap())
+                .build()
+        };
+
+        let alert: Alert = alert(AlertLevel::Critical, true);
+        let handler: AlertHandler = build();
+        handler.handle_alert(alert.clone()).await.unwrap();
+        assert!(handler.alert_details(alert.id).await.unwrap().can_confirm());
+        drop(handler);
+
+        let restarted: AlertHandler = build();
+        let details: AlertDetails = restarted.alert_details(alert.id).await.unwrap();
+        assert_eq!(details.message, alert.message);
+        assert!(!details.can_confirm());
+        assert!(restarted
+            .alert_details(uuid::Uuid::new_v4())
+            .await
+            .is_none());
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    fn handler_with_power(
+        outbound: Arc<OutboundQueue>,
+        power: Arc<MockPower>,
+        cancel: CancellationToken,
+        tracker: TaskTracker,
+    ) -> AlertHandler {
+        AlertHandler::builder(outbound, "test-client")
+            .notification_backend(Arc::new(MockNotifier::default()))
+            .audio_backend(Arc::new(MockAudio::default()))
+            .power_backend(power)
+            .display_wake_cap(Duration::from_secs(60))
+            .cancellation(cancel)
+            .task_tracker(tracker)
+            .build()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emergency_holds_display_until_confirmed() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let power: Arc<MockPower> = Arc::new(MockPower::default());
+        let handler: AlertHandler = handler_with_power(
+            outbound.clone(),
+            power.clone(),
+            CancellationToken::new(),
+            TaskTracker::new(),
+        );
+
+        // Only Emergency alerts touch the display
+        handler
+            .handle_alert(alert(AlertLevel::Critical, true))
+            .await
+            .unwrap();
+        assert!(power.keep_awake_calls().is_empty());
+
+        let emergency: Alert = alert(AlertLevel::Emergency, true);
+        handler.handle_alert(emergency.clone()).await.unwrap();
+        assert_eq!(power.keep_awake_calls(), vec![true]);
+        assert_eq!(power.wakes(), 1);
+
+        handler
+            .confirm_alert(emergency.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        assert_eq!(power.keep_awake_calls(), vec![true, false]);
+
+        // Nothing fires later for the confirmed alert
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert_eq!(power.keep_awake_calls(), vec![true, false]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_display_wake_released_at_cap() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let power: Arc<MockPower> = Arc::new(MockPower::default());
+        let handler: AlertHandler = handler_with_power(
+            outbound.clone(),
+            power.clone(),
+            CancellationToken::new(),
+            TaskTracker::new(),
+        );
+
+        let emergency: Alert = alert(AlertLevel::Emergency, true);
+        handler.handle_alert(emergency.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        assert_eq!(power.keep_awake_calls(), vec![true]);
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(power.keep_awake_calls(), vec![true, false]);
+        // The alert itself still waits for confirmation
+        assert_eq!(handler.get_pending_alerts().await, vec![emergency.id]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_display_wake_released_on_shutdown() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let power: Arc<MockPower> = Arc::new(MockPower::default());
+        let cancel: CancellationToken = CancellationToken::new();
+        let tracker: TaskTracker = TaskTracker::new();
+        let handler: AlertHandler = handler_with_power(
+            outbound.clone(),
+            power.clone(),
+            cancel.clone(),
+            tracker.clone(),
+        );
+
+        handler
+            .handle_alert(alert(AlertLevel::Emergency, true))
+            .await
+            .unwrap();
+        handler
+            .handle_alert(alert(AlertLevel::Emergency, true))
+            .await
+            .unwrap();
+        assert_eq!(power.keep_awake_calls(), vec![true]);
+
+        cancel.cancel();
+        tracker.close();
+        tracker.wait().await;
+        assert_eq!(power.keep_awake_calls(), vec![true, false]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_critical_alert_redelivered_after_fullscreen_ends() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let attention: Arc<MockAttention> = Arc::new(MockAttention::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .attention_backend(attention.clone())
+            .build();
+
+        attention.set_state(UserNotificationState::Fullscreen);
+        let critical: Alert = alert(AlertLevel::Critical, false);
+        let emergency: Alert = alert(AlertLevel::Emergency, false);
+        handler.handle_alert(critical.clone()).await.unwrap();
+        handler.handle_alert(emergency.clone()).await.unwrap();
+
+        // Emergency goes straight through; Critical waits and flashes the taskbar
+        let shown: Vec<uuid::Uuid> = notifier.shown().iter().map(|a| a.id).collect();
+        assert_eq!(shown, vec![emergency.id]);
+        assert_eq!(attention.requested(), vec![critical.id]);
+        assert_eq!(handler.deferred_count(), 1);
+
+        tokio::time::sleep(DEFERRED_POLL_INTERVAL * 3).await;
+        assert_eq!(notifier.shown().len(), 1);
+
+        attention.set_state(UserNotificationState::AcceptsNotifications);
+        tokio::time::sleep(DEFERRED_POLL_INTERVAL * 2).await;
+        let shown: Vec<uuid::Uuid> = notifier.shown().iter().map(|a| a.id).collect();
+        assert_eq!(shown, vec![emergency.id, critical.id]);
+        assert_eq!(attention.cleared(), 1);
+        assert_eq!(handler.deferred_count(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_confirmations_report_latency_from_toast_shown() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let settings: SharedSettings = SharedSettings::default();
+        settings
+            .update(|s| s.set_auto_confirm_timeout(Some(Duration::from_secs(60))))
+            .unwrap();
+        let attention: Arc<MockAttention> = Arc::new(MockAttention::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(Arc::new(MockNotifier::default()))
+            .audio_backend(Arc::new(MockAudio::default()))
+            .attention_backend(attention.clone())
+            .settings(settings)
+            .build();
+
+        // Sent by a server whose clock runs hours behind; latency counts from the toast
+        let mut answered: Alert = alert(AlertLevel::Warning, true);
+        answered.timestamp -= chrono::Duration::hours(3);
+        let ignored: Alert = alert(AlertLevel::Warning, true);
+        handler.handle_alert(answered.clone()).await.unwrap();
+        handler.handle_alert(ignored.clone()).await.unwrap();
+        // Held behind a fullscreen app until the first poll after it closes, 4s in
+        attention.set_state(UserNotificationState::Fullscreen);
+        let deferred: Alert = alert(AlertLevel::Critical, true);
+        handler.handle_alert(deferred.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        attention.set_state(UserNotificationState::AcceptsNotifications);
+
+        tokio::time::sleep(Duration::from_secs(39)).await;
+        handler
+            .confirm_alert(answered.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        let confirmation: Confirmation = confirmations.recv().await;
+        assert_eq!(confirmation.alert_id, answered.id);
+        assert_eq!(confirmation.reason, ConfirmationReason::User);
+        assert_eq!(confirmation.response_latency_ms, Some(42_000));
+        assert_eq!(confirmation.method, Some(ConfirmationMethod::ToastButton));
+        let shown_at: chrono::DateTime<chrono::Utc> = confirmation.shown_at.unwrap();
+        assert!(shown_at <= confirmation.confirmed_at);
+
+        // Timeouts report how long the toast was up, flagged by their reason
+        let mut timed_out: Vec<Confirmation> =
+            vec![confirmations.recv().await, confirmations.recv().await];
+        timed_out.sort_by_key(|c| c.alert_id != ignored.id);
+        assert_eq!(timed_out[0].alert_id, ignored.id);
+        assert_eq!(timed_out[0].reason, ConfirmationReason::TimedOut);
+        assert_eq!(timed_out[0].response_latency_ms, Some(60_000));
+        assert_eq!(timed_out[0].method, Some(ConfirmationMethod::AutoTimeout));
+        assert_eq!(timed_out[1].alert_id, deferred.id);
+        assert_eq!(timed_out[1].response_latency_ms, Some(56_000));
+        assert!(timed_out[1].shown_at.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clock_jumps_leave_auto_confirm_on_time() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let settings: SharedSettings = SharedSettings::default();
+        settings
+            .update(|s| s.set_auto_confirm_timeout(Some(Duration::from_secs(300))))
+            .unwrap();
+        let clock: Arc<ManualClock> = Arc::new(ManualClock::new());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(Arc::new(MockNotifier::default()))
+            .audio_backend(Arc::new(MockAudio::default()))
+            .attention_backend(Arc::new(MockAttention::default()))
+            .settings(settings)
+            .clock(clock.clone())
+            .build();
+
+        let pending: Alert = alert(AlertLevel::Warning, true);
+        handler.handle_alert(pending.clone()).await.unwrap();
+
+        // NTP pulls the clock back three minutes, then a BIOS clock pushes it an hour ahead
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        clock.jump(chrono::TimeDelta::minutes(-3));
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        clock.jump(chrono::TimeDelta::hours(1));
+        tokio::time::sleep(Duration::from_secs(179)).await;
+        assert!(confirmations.try_recv().is_none());
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let confirmation: Confirmation = confirmations.recv().await;
+        assert_eq!(confirmation.alert_id, pending.id);
+        assert_eq!(confirmation.reason, ConfirmationReason::TimedOut);
+        assert_eq!(confirmation.response_latency_ms, Some(300_000));
+        assert_eq!(confirmation.confirmed_at, clock.now());
+        // Counted back on the corrected clock, not stamped before the jumps
+        assert_eq!(
+            confirmation.confirmed_at - confirmation.shown_at.unwrap(),
+            chrono::TimeDelta::seconds(300)
+        );
+        // The toast's countdown refreshes wake the sweeper, which notices each jump
+        assert_eq!(handler.stats().clock_jumps(), 2);
+
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert!(confirmations.try_recv().is_none());
+    }
+
+    /// Takes `spent` of the manual clock to play or show anything
+    struct SlowBackend {
+        clock: Arc<ManualClock>,
+        spent: chrono::TimeDelta,
+    }
+
+    impl AudioBackend for SlowBackend {
+        fn play(&self, _sound_file: &str) {
+            self.clock.jump(self.spent);
+        }
+    }
+
+    impl NotificationBackend for SlowBackend {
+        fn show_notification(&self, _alert: &Alert) -> Result<()> {
+            self.clock.jump(self.spent);
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delivery_stages_are_timed() {
+        let clock: Arc<ManualClock> = Arc::new(ManualClock::new());
+        let handler: AlertHandler =
+            AlertHandler::builder(Arc::new(OutboundQueue::default()), "test-client")
+                .notification_backend(Arc::new(SlowBackend {
+                    clock: clock.clone(),
+                    spent: chrono::TimeDelta::milliseconds(120),
+                }))
+                .audio_backend(Arc::new(SlowBackend {
+                    clock: clock.clone(),
+                    spent: chrono::TimeDelta::milliseconds(30),
+                }))
+                .attention_backend(Arc::new(MockAttention::default()))
+                .clock(clock.clone())
+                .build();
+
+        let mut timed: Alert = alert(AlertLevel::Warning, false);
+        timed.timestamp = clock.now() - chrono::TimeDelta::milliseconds(40);
+        let trace: DeliveryTrace = DeliveryTrace {
+            received: Some(clock.now()),
+            parsed: Some(clock.now() + chrono::TimeDelta::milliseconds(1)),
+            enqueued: Some(clock.now() + chrono::TimeDelta::milliseconds(2)),
+            dequeued: Some(clock.now() + chrono::TimeDelta::milliseconds(10)),
+            ..DeliveryTrace::default()
+        };
+        clock.jump(chrono::TimeDelta::milliseconds(10));
+        handler
+            .handle_alert_traced(timed.clone(), ReceivedVia::WebSocket, trace)
+            .await
+            .unwrap();
+
+        let recorded: DeliveryTrace = handler.history().get(timed.id).unwrap().timing.unwrap();
+        let stage = |stage: Stage| recorded.stage(stage, timed.timestamp).unwrap();
+        assert_eq!(stage(Stage::Transit), Duration::from_millis(40));
+        assert_eq!(stage(Stage::Queued), Duration::from_millis(8));
+        assert_eq!(stage(Stage::Sound), Duration::from_millis(30));
+        assert_eq!(stage(Stage::Toast), Duration::from_millis(150));
+
+        let summary: DeliveryTiming = handler.delivery_timings().summary().unwrap();
+        assert_eq!(summary.samples, 1);
+        assert_eq!(
+            summary.toast,
+            Some(StageTiming {
+                p50_ms: 150,
+                p95_ms: 150
+            })
+        );
+
+        // Alerts that skipped the queue have nothing to time
+        handler
+            .handle_alert_via(alert(AlertLevel::Warning, false), ReceivedVia::Multicast)
+            .await
+            .unwrap();
+        assert_eq!(handler.delivery_timings().summary().unwrap().samples, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_suppression_judged_by_corrected_clock() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let suppressions: Arc<SuppressionWindows> = Arc::new(SuppressionWindows::new());
+        let clock: Arc<ManualClock> = Arc::new(ManualClock::new());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(Arc::new(MockNotifier::default()))
+            .audio_backend(Arc::new(MockAudio::default()))
+            .attention_backend(Arc::new(MockAttention::default()))
+            .suppressions(suppressions.clone())
+            .clock(clock.clone())
+            .build();
+        suppressions.insert(SuppressionWindow {
+            id: uuid::Uuid::new_v4(),
+            starts_at: clock.now() + chrono::Duration::minutes(30),
+            ends_at: clock.now() + chrono::Duration::hours(2),
+            levels: Vec::new(),
+            categories: Vec::new(),
+            reason: "Generator test".to_string(),
+            location: None,
+        });
+
+        assert!(!handler.suppress(&alert(AlertLevel::Info, false)));
+        assert_eq!(handler.stats().clock_jumps(), 0);
+
+        clock.jump(chrono::TimeDelta::hours(1));
+        assert!(handler.suppress(&alert(AlertLevel::Info, false)));
+        assert_eq!(handler.stats().clock_jumps(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_without_toasts_or_audio_alerts_open_the_details_window() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let (windows_tx, mut windows_rx) = mpsc::unbounded_channel::<ActivationArgs>();
+        let (_capabilities_tx, capabilities_rx) = watch::channel(Capabilities {
+            toasts: false,
+            audio: false,
+            ..Capabilities::all()
+        });
+        let handler: AlertHandler = AlertHandler::builder(outbound, "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .attention_backend(Arc::new(MockAttention::default()))
+            .toast_activations(windows_tx)
+            .capabilities(capabilities_rx)
+            .build();
+
+        // Opened only once the alert is pending, so the window offers Confirm
+        let critical: Alert = alert(AlertLevel::Critical, true);
+        handler.handle_alert(critical.clone()).await.unwrap();
+        assert_eq!(
+            windows_rx.try_recv().unwrap(),
+            ActivationArgs::new(ToastAction::Details, critical.id)
+        );
+        assert!(
+            handler
+                .alert_details(critical.id)
+                .await
+                .unwrap()
+                .awaiting_confirmation
+        );
+
+        // Info that needs no confirmation is only recorded
+        let info: Alert = alert(AlertLevel::Info, false);
+        handler.handle_alert(info.clone()).await.unwrap();
+        assert!(windows_rx.try_recv().is_err());
+        assert!(handler.history().get(info.id).is_some());
+
+        assert!(notifier.shown().is_empty());
+        assert!(audio.played().is_empty());
+    }
+
+    #[test]
+    fn test_confirm_window_outcomes() {
+        use ConfirmationReason::*;
+        use TimeoutOutcome::*;
+
+        let start: Instant = Instant::now();
+        let timeout: Duration = Duration::from_secs(300);
+        let deadline: Instant = start + timeout;
+        let secs = |s: u64| Some(Duration::from_secs(s));
+
+        // Without an extension, or without an idle reading, the deadline is final
+        let mut disabled: ConfirmWindow = ConfirmWindow::new(start, timeout, None);
+        assert_eq!(
+            disabled.on_timeout(deadline, secs(10_000)),
+            Confirm(TimedOut)
+        );
+        let mut unknown: ConfirmWindow = ConfirmWindow::new(start, timeout, secs(600));
+        assert_eq!(unknown.on_timeout(deadline, None), Confirm(TimedOut));
+
+        // Input since the alert arrived: the user had their chance
+        let mut active: ConfirmWindow = ConfirmWindow::new(start, timeout, secs(600));
+        assert_eq!(active.on_timeout(deadline, secs(30)), Confirm(TimedOut));
+
+        // Idle since before the alert: held in steps up to the limit, then dismissed
+        let mut idle: ConfirmWindow = ConfirmWindow::new(start, timeout, secs(45));
+        let recheck: Instant = deadline + IDLE_RECHECK_INTERVAL;
+        assert_eq!(idle.on_timeout(deadline, secs(400)), RecheckAt(recheck));
+        let limit: Instant = deadline + Duration::from_secs(45);
+        assert_eq!(idle.on_timeout(recheck, secs(430)), RecheckAt(limit));
+        assert_eq!(idle.on_timeout(limit, secs(445)), Confirm(TimedOutIdle));
+
+        // Back at the machine after being held: a full window from their return
+        let mut returned: ConfirmWindow = ConfirmWindow::new(start, timeout, secs(600));
+        assert_eq!(returned.on_timeout(deadline, secs(400)), RecheckAt(recheck));
+        let reopened: Instant = recheck - Duration::from_secs(5) + timeout;
+        assert_eq!(returned.on_timeout(recheck, secs(5)), RecheckAt(reopened));
+        assert_eq!(returned.on_timeout(reopened, secs(300)), Confirm(TimedOut));
+    }
+
+    fn handler_with_idle(
+        outbound: Arc<OutboundQueue>,
+        idle: Arc<MockIdle>,
+        extension: Option<Duration>,
+    ) -> AlertHandler {
+        AlertHandler::builder(outbound, "test-client")
+            .notification_backend(Arc::new(MockNotifier::default()))
+            .audio_backend(Arc::new(MockAudio::default()))
+            .idle_probe(idle)
+            .idle_extension(extension)
+            .build()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_confirmations_report_idle_time() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let idle: Arc<MockIdle> = Arc::new(MockIdle::default());
+        let handler: AlertHandler = handler_with_idle(outbound.clone(), idle.clone(), None);
+
+        let confirmed: Alert = alert(AlertLevel::Warning, true);
+        let timed_out: Alert = alert(AlertLevel::Warning, true);
+        handler.handle_alert(confirmed.clone()).await.unwrap();
+        handler.handle_alert(timed_out.clone()).await.unwrap();
+
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        idle.input();
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        handler
+            .confirm_alert(confirmed.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        let by_user: Confirmation = confirmations.recv().await;
+        assert_eq!(by_user.reason, ConfirmationReason::User);
+        assert_eq!(by_user.user_idle_secs, Some(3));
+
+        // Extension disabled: an idle machine still times out at the usual deadline
+        let start: Instant = Instant::now();
+        let auto: Confirmation = confirmations.recv().await;
+        assert_eq!(auto.alert_id, timed_out.id);
+        assert_eq!(start.elapsed(), Duration::from_secs(277));
+        assert_eq!(auto.reason, ConfirmationReason::TimedOut);
+        assert_eq!(auto.user_idle_secs, Some(280));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_machine_holds_alert_then_dismisses() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let idle: Arc<MockIdle> = Arc::new(MockIdle::default());
+        let handler: AlertHandler = handler_with_idle(
+            outbound.clone(),
+            idle.clone(),
+            Some(Duration::from_secs(600)),
+        );
+
+        let held: Alert = alert(AlertLevel::Critical, true);
+        handler.handle_alert(held.clone()).await.unwrap();
+
+        tokio::time::sleep(Duration::from_secs(301)).await;
+        assert!(confirmations.try_recv().is_none());
+        assert_eq!(handler.get_pending_alerts().await, vec![held.id]);
+
+        let start: Instant = Instant::now();
+        let dismissal: Confirmation = confirmations.recv().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(599));
+        assert_eq!(dismissal.alert_id, held.id);
+        assert_eq!(dismissal.reason, ConfirmationReason::TimedOutIdle);
+        assert_eq!(dismissal.user_idle_secs, Some(900));
+        assert_eq!(handler.pending_count().await, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_countdown_updates_stop_once_the_toast_is_gone() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .idle_probe(Arc::new(MockIdle::default()))
+            .idle_extension(Some(Duration::from_secs(600)))
+            .build();
+        let updates = |id: uuid::Uuid| -> Vec<Countdown> {
+            notifier
+                .countdowns()
+                .into_iter()
+                .filter(|(alert_id, _)| *alert_id == id)
+                .map(|(_, countdown)| countdown)
+                .collect()
+        };
+
+        let closed: Alert = alert(AlertLevel::Critical, true);
+        let held: Alert = alert(AlertLevel::Critical, true);
+        let vanished: Alert = alert(AlertLevel::Critical, true);
+        let plain: Alert = alert(AlertLevel::Critical, false);
+        for alert in [&closed, &held, &vanished, &plain] {
+            handler.handle_alert(alert.clone()).await.unwrap();
+        }
+
+        tokio::time::sleep(COUNTDOWN_REFRESH_INTERVAL * 2 + Duration::from_secs(1)).await;
+        assert_eq!(
+            updates(held.id),
+            [
+                Countdown::Remaining(Duration::from_secs(285)),
+                Countdown::Remaining(Duration::from_secs(270)),
+            ]
+        );
+        assert!(updates(plain.id).is_empty());
+
+        // Closed by the user: no further updates
+        handler.toast_dismissed(closed.id).await;
+        // Gone without the handler hearing about it: the next update finds out
+        notifier.dismiss(vanished.id);
+        tokio::time::sleep(COUNTDOWN_REFRESH_INTERVAL * 2).await;
+        assert_eq!(updates(closed.id).len(), 2);
+        assert_eq!(updates(vanished.id).len(), 3);
+
+        // Nobody is at the machine, so the alert is held past its deadline
+        tokio::time::sleep(Duration::from_secs(300)).await;
+        assert!(handler.get_pending_alerts().await.contains(&held.id));
+        assert_eq!(updates(held.id).last(), Some(&Countdown::Overdue));
+        assert_eq!(updates(closed.id).len(), 2);
+        assert_eq!(updates(vanished.id).len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_user_returning_to_idle_machine_gets_full_window() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let idle: Arc<MockIdle> = Arc::new(MockIdle::default());
+        let handler: AlertHandler = handler_with_idle(
+            outbound.clone(),
+            idle.clone(),
+            Some(Duration::from_secs(3600)),
+        );
+
+        let held: Alert = alert(AlertLevel::Critical, true);
+        handler.handle_alert(held.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(1000)).await;
+        assert!(confirmations.try_recv().is_none());
+
+        // The user is back but does not confirm; the alert times out normally
+        idle.input();
+        let start: Instant = Instant::now();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        idle.input();
+        let auto: Confirmation = confirmations.recv().await;
+        assert_eq!(auto.reason, ConfirmationReason::TimedOut);
+        assert!(start.elapsed() >= Duration::from_secs(300));
+        assert!(start.elapsed() <= Duration::from_secs(300) + IDLE_RECHECK_INTERVAL);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_response_options_and_timeout() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let handler: AlertHandler = handler_with(
+            outbound.clone(),
+            CancellationToken::new(),
+            TaskTracker::new(),
+        );
+
+        let mut drill: Alert = alert(AlertLevel::Critical, true);
+        drill.response_options = Some(vec![crate::messages::ResponseOption {
+            id: "safe".to_string(),
+            label: "Safe".to_string(),
+            response: ConfirmationResponse::Acknowledged,
+        }]);
+        handler.handle_alert(drill.clone()).await.unwrap();
+
+        // An option the alert does not offer leaves it pending
+        let err: EmnsError = handler
+            .respond_to_alert(drill.id, 3, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, EmnsError::Notification { .. }));
+        assert_eq!(handler.pending_count().await, 1);
+
+        let timed_out: Confirmation = confirmations.recv().await;
+        assert_eq!(timed_out.reason, ConfirmationReason::TimedOut);
+        assert_eq!(timed_out.response_id, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_operator_id_prompt_gates_user_confirmations() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let prompt: Arc<MockOperatorPrompt> =
+            Arc::new(MockOperatorPrompt::answering([None, Some("B-10442")]));
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(Arc::new(MockNotifier::default()))
+            .audio_backend(Arc::new(MockAudio::default()))
+            .operator_identity(Arc::new(
+                OperatorIdentity::new(OperatorIdConfig::new()).with_prompt(prompt.clone()),
+            ))
+            .build();
+
+        let muster: Alert = alert(AlertLevel::Critical, true);
+        let unattended: Alert = alert(AlertLevel::Warning, true);
+        handler.handle_alert(muster.clone()).await.unwrap();
+        handler.handle_alert(unattended.clone()).await.unwrap();
+
+        // Cancelling the prompt leaves the alert pending
+        handler
+            .confirm_alert(muster.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        assert!(confirmations.try_recv().is_none());
+        assert!(handler.is_pending(muster.id).await);
+
+        handler
+            .confirm_alert(muster.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        let confirmed: Confirmation = confirmations.recv().await;
+        assert_eq!(confirmed.alert_id, muster.id);
+        assert_eq!(confirmed.operator_id.as_deref(), Some("B-10442"));
+        assert_eq!(confirmed.username, get_username());
+        let questions: Vec<OperatorQuestion> = prompt.questions();
+        assert_eq!(questions.len(), 2);
+        assert_eq!(
+            questions[1].alert_title,
+            NotificationManager::display_title(&muster)
+        );
+
+        // Nobody is asked when the alert times out
+        let timed_out: Confirmation = confirmations.recv().await;
+        assert_eq!(timed_out.alert_id, unattended.id);
+        assert_eq!(timed_out.reason, ConfirmationReason::TimedOut);
+        assert_eq!(timed_out.operator_id, None);
+        assert_eq!(prompt.questions().len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_burst_of_info_alerts_becomes_one_summary_toast() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .attention_backend(Arc::new(MockAttention::default()))
+            .burst_coalescing(Some(BurstConfig::default()))
+            .build();
+
+        let mut burst: Vec<Alert> = Vec::new();
+        for _ in 0..50 {
+            let info: Alert = alert(AlertLevel::Info, false);
+            handler.handle_alert(info.clone()).await.unwrap();
+            burst.push(info);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        // Exempt alerts still show during the burst
+        let critical: Alert = alert(AlertLevel::Critical, false);
+        handler.handle_alert(critical.clone()).await.unwrap();
+        let confirm: Alert = alert(AlertLevel::Info, true);
+        handler.handle_alert(confirm.clone()).await.unwrap();
+        assert_eq!(notifier.shown().len(), 7);
+
+        tokio::time::sleep(crate::burst::DEFAULT_BURST_WINDOW).await;
+        let shown: Vec<Alert> = notifier.shown();
+        assert_eq!(shown.len(), 8);
+        let summaries: Vec<&Alert> = shown
+            .iter()
+            .filter(|a| a.title.ends_with("informational alerts received"))
+            .collect();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].title, "45 informational alerts received");
+        assert_eq!(audio.played().len(), 8);
+
+        // Every alert is in history, and the summary opens a list of the held ones
+        assert!(burst.iter().all(|a| handler.history().get(a.id).is_some()));
+        let details: AlertDetails = handler.alert_details(summaries[0].id).await.unwrap();
+        assert_eq!(details.message.lines().count(), 47);
+        assert!(!details.can_confirm());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_missed_alerts_are_recapped_in_one_silent_digest() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .attention_backend(Arc::new(MockAttention::default()))
+            .build();
+        let missed = |level: AlertLevel, requires_confirmation: bool| Alert {
+            missed: true,
+            ..alert(level, requires_confirmation)
+        };
+
+        let overnight: Vec<Alert> = vec![
+            missed(AlertLevel::Warning, false),
+            missed(AlertLevel::Emergency, false),
+        ];
+        let must_confirm: Alert = missed(AlertLevel::Critical, true);
+        handler.handle_alert(overnight[0].clone()).await.unwrap();
+        handler.handle_alert(must_confirm.clone()).await.unwrap();
+        handler.handle_alert(overnight[1].clone()).await.unwrap();
+
+        // Confirmation-required alerts are still shown, and sound, on their own
+        assert_eq!(notifier.shown().len(), 1);
+        assert_eq!(notifier.shown()[0].id, must_confirm.id);
+        assert_eq!(audio.played().len(), 1);
+        assert_eq!(handler.get_pending_alerts().await, vec![must_confirm.id]);
+
+        tokio::time::sleep(crate::missed::MISSED_SETTLE + Duration::from_millis(100)).await;
+        let shown: Vec<Alert> = notifier.shown();
+        assert_eq!(shown.len(), 2);
+        let digest: &Alert = &shown[1];
+        assert_eq!(
+            digest.title,
+            "2 alerts were issued while this machine was offline"
+        );
+        assert_eq!(digest.level, AlertLevel::Emergency);
+        assert_eq!(audio.played().len(), 1);
+
+        // The details window lists what was missed
+        assert!(overnight
+            .iter()
+            .all(|a| handler.history().get(a.id).is_some()));
+        let details: AlertDetails = handler.alert_details(digest.id).await.unwrap();
+        assert_eq!(details.message.lines().count(), 4);
+        assert!(overnight.iter().all(|a| details.message.contains(&a.title)));
+        assert!(!details.can_confirm());
+
+        // Alerts that were not missed are handled as usual
+        let live: Alert = alert(AlertLevel::Info, false);
+        handler.handle_alert(live.clone()).await.unwrap();
+        assert_eq!(notifier.shown()[2].id, live.id);
+        assert_eq!(audio.played().len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replayed_alerts_share_one_sound() {
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler =
+            AlertHandler::builder(Arc::new(OutboundQueue::default()), "test-client")
+                .notification_backend(notifier.clone())
+                .audio_backend(audio.clone())
+                .attention_backend(Arc::new(MockAttention::default()))
+                .build();
+        let missed = |level: AlertLevel| Alert {
+            missed: true,
+            ..alert(level, true)
+        };
+
+        // A backlog delivered at once: every alert is shown, one sound plays
+        let backlog: Vec<Alert> = [AlertLevel::Emergency, AlertLevel::Critical]
+            .into_iter()
+            .cycle()
+            .take(50)
+            .map(missed)
+            .collect();
+        for alert in &backlog {
+            handler.handle_alert(alert.clone()).await.unwrap();
+        }
+        assert_eq!(notifier.shown().len(), 50);
+        assert_eq!(audio.played().len(), 1);
+        let decision: DeliveryDecision = handler
+            .history()
+            .get(backlog[1].id)
+            .unwrap()
+            .decision
+            .unwrap();
+        assert_eq!(decision.verdict(Rule::Replay), Some(Verdict::Silence));
+
+        // Live alerts sound as usual
+        handler
+            .handle_alert(alert(AlertLevel::Critical, true))
+            .await
+            .unwrap();
+        assert_eq!(audio.played().len(), 2);
+
+        // A later replay sounds again
+        tokio::time::sleep(crate::missed::MISSED_SETTLE).await;
+        handler
+            .handle_alert(missed(AlertLevel::Warning))
+            .await
+            .unwrap();
+        assert_eq!(audio.played().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unconfirmed_critical_alert_escalates_once() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(Arc::new(MockNotifier::default()))
+            .audio_backend(audio.clone())
+            .escalation(EscalationPolicy {
+                critical: Some(crate::escalation::Escalation {
+                    sound_file: "air_horn.wav".to_string(),
+                    after: Duration::from_millis(500),
+                }),
+                ..EscalationPolicy::default()
+            })
+            .build();
+
+        let ignored: Alert = alert(AlertLevel::Critical, true);
+        let confirmed: Alert = alert(AlertLevel::Critical, true);
+        let warning: Alert = alert(AlertLevel::Warning, true);
+        for a in [&ignored, &confirmed, &warning] {
+            handler.handle_alert(a.clone()).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        handler
+            .confirm_alert(confirmed.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(
+            audio.played_at(),
+            [("air_horn.wav".to_string(), ESCALATION_VOLUME, false)]
+        );
+        assert!(handler
+            .history()
+            .get(ignored.id)
+            .unwrap()
+            .escalated_at
+            .is_some());
+        assert!(handler
+            .history()
+            .get(confirmed.id)
+            .unwrap()
+            .escalated_at
+            .is_none());
+        assert!(handler
+            .history()
+            .get(warning.id)
+            .unwrap()
+            .escalated_at
+            .is_none());
+
+        // Confirming stops the escalation sound, and it never plays again
+        handler
+            .confirm_alert(ignored.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        assert_eq!(
+            audio.played_at(),
+            [("air_horn.wav".to_string(), ESCALATION_VOLUME, true)]
+        );
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(audio.played_at().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_repeats_stop_when_confirmed_mid_schedule() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .escalation(EscalationPolicy {
+                repeats: Repeats {
+                    interval: Duration::from_secs(60),
+                    max: 3,
+                },
+                ..EscalationPolicy::default()
+            })
+            .build();
+
+        let confirmed: Alert = alert(AlertLevel::Emergency, true);
+        let cancelled: Alert = alert(AlertLevel::Emergency, true);
+        let unconfirmable: Alert = alert(AlertLevel::Emergency, false);
+        for a in [&confirmed, &cancelled, &unconfirmable] {
+            handler.handle_alert(a.clone()).await.unwrap();
+        }
+        let shown = |id: uuid::Uuid| -> Vec<String> {
+            notifier
+                .shown()
+                .into_iter()
+                .filter(|a| a.id == id)
+                .map(|a| a.message)
+                .collect()
+        };
+
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(
+            shown(confirmed.id),
+            [
+                confirmed.message.clone(),
+                format!("{}\n\nReminder 1 of 3", confirmed.message)
+            ]
+        );
+        assert_eq!(audio.played().len(), 5);
+        assert!(handler.cancel(cancelled.id, None).await);
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(shown(confirmed.id).len(), 3);
+        assert!(shown(confirmed.id)[2].ends_with("Reminder 2 of 3"));
+        handler
+            .confirm_alert(confirmed.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        assert_eq!(confirmations.recv().await.alert_id, confirmed.id);
+
+        // Nothing repeats once confirmed or cancelled, and alerts that need
+        // no confirmation never did
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert_eq!(shown(confirmed.id).len(), 3);
+        assert_eq!(shown(cancelled.id).len(), 2);
+        assert_eq!(shown(unconfirmable.id).len(), 1);
+        assert_eq!(audio.played().len(), 6);
+        assert!(confirmations.try_recv().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_auto_confirm_waits_for_the_alerts_own_repeats() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let settings: SharedSettings = SharedSettings::default();
+        settings
+            .update(|s| s.set_auto_confirm_timeout(Some(Duration::from_secs(300))))
+            .unwrap();
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .settings(settings)
+            .escalation(EscalationPolicy {
+                repeats: Repeats {
+                    interval: Duration::from_secs(60),
+                    max: 2,
+                },
+                ..EscalationPolicy::default()
+            })
+            .build();
+
+        let mut slower: Alert = alert(AlertLevel::Emergency, true);
+        slower.escalation = Some(AlertEscalation {
+            repeat_interval_secs: Some(120),
+            max_repeats: Some(3),
+        });
+        let mut quiet: Alert = alert(AlertLevel::Emergency, true);
+        quiet.escalation = Some(AlertEscalation {
+            repeat_interval_secs: None,
+            max_repeats: Some(0),
+        });
+        handler.handle_alert(slower.clone()).await.unwrap();
+        handler.handle_alert(quiet.clone()).await.unwrap();
+
+        // The agent's own timeout for the alert that turned repeats off
+        tokio::time::sleep(Duration::from_secs(301)).await;
+        let timed_out: Confirmation = confirmations.recv().await;
+        assert_eq!(timed_out.alert_id, quiet.id);
+        assert!(confirmations.try_recv().is_none());
+
+        // The last repeat, at 360s, gets a full interval before auto-confirm
+        tokio::time::sleep(Duration::from_secs(178)).await;
+        assert!(confirmations.try_recv().is_none());
+        let shown: Vec<Alert> = notifier.shown();
+        assert_eq!(shown.iter().filter(|a| a.id == quiet.id).count(), 1);
+        let repeats: Vec<&Alert> = shown.iter().filter(|a| a.id == slower.id).collect();
+        assert_eq!(repeats.len(), 4);
+        assert!(repeats[3].message.ends_with("Reminder 3 of 3"));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let timed_out: Confirmation = confirmations.recv().await;
+        assert_eq!(timed_out.alert_id, slower.id);
+        assert_eq!(timed_out.reason, ConfirmationReason::TimedOut);
+        assert_eq!(timed_out.response_latency_ms, Some(480_000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_duplicates_are_reported_but_not_shown() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .deduplicate(Some(DedupConfig {
+                window: Duration::from_secs(60),
+                capacity: 10,
+            }))
+            .build();
+        let next_status = || async {
+            match outbound.next().await {
+                OutboundMessage::DeliveryStatus(status) => status,
+                other => panic!("unexpected message {:?}", other),
+            }
+        };
+
+        let original: Alert = alert(AlertLevel::Critical, false);
+        handler.handle_alert(original.clone()).await.unwrap();
+        assert_eq!(next_status().await.outcome, None);
+
+        // A retry under the same id
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        handler.handle_alert(original.clone()).await.unwrap();
+        let status: DeliveryStatus = next_status().await;
+        assert_eq!(status.alert_id, original.id);
+        assert_eq!(status.outcome, Some(DeliveryOutcome::Duplicate));
+        assert_eq!(status.detail, None);
+
+        // A retry from a server that gave it a new id
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let renamed: Alert = Alert {
+            id: uuid::Uuid::new_v4(),
+            timestamp: original.timestamp + chrono::TimeDelta::seconds(20),
+            ..original.clone()
+        };
+        handler.handle_alert(renamed.clone()).await.unwrap();
+        let status: DeliveryStatus = next_status().await;
+        assert_eq!(status.alert_id, renamed.id);
+        assert_eq!(status.outcome, Some(DeliveryOutcome::Duplicate));
+        assert_eq!(
+            status.detail,
+            Some(format!("same content as alert {}", original.id))
+        );
+        assert!(handler.alert_details(renamed.id).await.is_none());
+        assert_eq!(notifier.shown().len(), 1);
+        assert_eq!(audio.played().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_same_id_after_the_window_is_reported_from_history() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .deduplicate(Some(DedupConfig {
+                window: Duration::from_secs(60),
+                capacity: 10,
+            }))
+            .build();
+        let next_status = || async {
+            match outbound.next().await {
+                OutboundMessage::DeliveryStatus(status) => status,
+                other => panic!("unexpected message {:?}", other),
+            }
+        };
+
+        let original: Alert = alert(AlertLevel::Warning, false);
+        handler.handle_alert(original.clone()).await.unwrap();
+        let first: DeliveryStatus = next_status().await;
+        assert!(first.decision.is_some());
+
+        // A server still retrying once the window has passed
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        handler.handle_alert(original.clone()).await.unwrap();
+        let again: DeliveryStatus = next_status().await;
+        assert_eq!(again.alert_id, original.id);
+        assert_eq!(again.outcome, Some(DeliveryOutcome::Duplicate));
+        assert_eq!(again.decision, first.decision);
+        assert!(again
+            .detail
+            .is_some_and(|detail| detail.starts_with("already received at")));
+        assert_eq!(notifier.shown().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_same_content_is_shown_again_after_the_window() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .deduplicate(Some(DedupConfig {
+                window: Duration::from_secs(60),
+                capacity: 10,
+            }))
+            .build();
+
+        let weekly: Alert = alert(AlertLevel::Warning, false);
+        handler.handle_alert(weekly.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        let resent: Alert = Alert {
+            id: uuid::Uuid::new_v4(),
+            ..weekly.clone()
+        };
+        handler.handle_alert(resent.clone()).await.unwrap();
+        assert_eq!(notifier.shown().len(), 2);
+        assert_eq!(audio.played().len(), 2);
+        assert!(handler.alert_details(resent.id).await.is_some());
+        for _ in 0..2 {
+            assert!(matches!(
+                outbound.next().await,
+                OutboundMessage::DeliveryStatus(DeliveryStatus { outcome: None, .. })
+            ));
+        }
+
+        // An id already handled is still never shown twice, whatever the window
+        handler.handle_alert(weekly).await.unwrap();
+        assert_eq!(notifier.shown().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sound_policy_silences_alerts_and_reports_it() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let settings: SharedSettings = SharedSettings::default();
+        settings
+            .update(|s| {
+                s.set_sound_policy(SoundPolicy {
+                    allowed_sounds: Some(vec!["notification.wav".to_string()]),
+                    ..SoundPolicy::default()
+                })
+            })
+            .unwrap();
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .attention_backend(Arc::new(MockAttention::default()))
+            .settings(settings.clone())
+            .build();
+
+        // The library's chime is allowed; the siren is not, but the toast still shows
+        let chime: Alert = alert(AlertLevel::Info, false);
+        handler.handle_alert(chime.clone()).await.unwrap();
+        let siren: Alert = alert(AlertLevel::Critical, false);
+        handler.handle_alert(siren.clone()).await.unwrap();
+        assert_eq!(audio.played(), ["notification.wav"]);
+        assert_eq!(notifier.shown().len(), 2);
+
+        // Visual-only machines play nothing at all
+        settings
+            .update(|s| {
+                s.set_sound_policy(SoundPolicy {
+                    visual_only: Some(true),
+                    ..SoundPolicy::default()
+                })
+            })
+            .unwrap();
+        let quiet: Alert = alert(AlertLevel::Info, false);
+        handler.handle_alert(quiet.clone()).await.unwrap();
+        assert_eq!(audio.played().len(), 1);
+
+        for (expected, refused) in [(chime.id, false), (siren.id, true), (quiet.id, true)] {
+            match outbound.next().await {
+                OutboundMessage::DeliveryStatus(status) => {
+                    assert_eq!(status.alert_id, expected);
+                    assert_eq!(
+                        status.sound,
+                        refused.then_some(SoundDelivery::SuppressedByPolicy)
+                    );
+                    assert_eq!(status.attachment, None);
+                    assert_eq!(status.decision.unwrap().sound, !refused);
+                }
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        assert!(outbound.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_suppression_window_records_alert_without_showing_it() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let suppressions: Arc<SuppressionWindows> = Arc::new(SuppressionWindows::new());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .attention_backend(Arc::new(MockAttention::default()))
+            .power_backend(Arc::new(MockPower::default()))
+            .suppressions(suppressions.clone())
+            .build();
+
+        let now = chrono::Utc::now();
+        let window = |starts_at, categories: Vec<String>| SuppressionWindow {
+            id: uuid::Uuid::new_v4(),
+            starts_at,
+            ends_at: now + chrono::Duration::hours(1),
+            levels: Vec::new(),
+            categories,
+            reason: "Fire alarm testing".to_string(),
+            location: None,
+        };
+        // Not started yet, and for another category: neither silences this alert
+        suppressions.insert(window(now + chrono::Duration::minutes(30), Vec::new()));
+        suppressions.insert(window(now, vec!["network".to_string()]));
+        let mut drill: Alert = alert(AlertLevel::Critical, true);
+        drill.category = Some("fire_alarm".to_string());
+        handler.handle_alert(drill).await.unwrap();
+        assert_eq!(notifier.shown().len(), 1);
+        assert!(matches!(
+            outbound.next().await,
+            OutboundMessage::DeliveryStatus(DeliveryStatus { outcome: None, .. })
+        ));
+
+        let active = window(
+            now - chrono::Duration::minutes(1),
+            vec!["FIRE_ALARM".into()],
+        );
+        suppressions.insert(active.clone());
+        let mut suppressed: Alert = alert(AlertLevel::Critical, true);
+        suppressed.category = Some("fire_alarm".to_string());
+        handler.handle_alert(suppressed.clone()).await.unwrap();
+        assert_eq!(notifier.shown().len(), 1);
+        assert_eq!(audio.played().len(), 1);
+        assert!(!handler.is_pending(suppressed.id).await);
+        assert!(handler.alert_details(suppressed.id).await.is_some());
+        match outbound.next().await {
+            OutboundMessage::DeliveryStatus(status) => {
+                assert_eq!(status.alert_id, suppressed.id);
+                assert_eq!(status.outcome, Some(DeliveryOutcome::SuppressedByWindow));
+                assert_eq!(status.detail.as_deref(), Some("Fire alarm testing"));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+
+        // Emergencies get through, and cancelling the window ends it early
+        let mut emergency: Alert = alert(AlertLevel::Emergency, true);
+        emergency.category = Some("fire_alarm".to_string());
+        handler.handle_alert(emergency).await.unwrap();
+        assert_eq!(notifier.shown().len(), 2);
+        assert!(suppressions.cancel(active.id));
+        let mut after: Alert = alert(AlertLevel::Critical, true);
+        after.category = Some("fire_alarm".to_string());
+        handler.handle_alert(after).await.unwrap();
+        assert_eq!(notifier.shown().len(), 3);
+        // Only how each was delivered is reported
+        for _ in 0..2 {
+            assert!(matches!(
+                outbound.next().await,
+                OutboundMessage::DeliveryStatus(DeliveryStatus { outcome: None, .. })
+            ));
+        }
+        assert!(outbound.is_empty());
+    }
+
+    /// `alert` handled by `handler`, and the rules consulted for it as its history records them
+    async fn decision_trace(handler: &AlertHandler, alert: &Alert) -> Vec<(Rule, Verdict)> {
+        handler.handle_alert(alert.clone()).await.unwrap();
+        let decision: DeliveryDecision = handler.history().get(alert.id).unwrap().decision.unwrap();
+        decision
+            .steps
+            .iter()
+            .map(|step| (step.rule, step.verdict))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_decision_trace_explains_each_delivery() {
+        use Verdict::{Allow, Hold, Silence, Window, Withhold};
+        let builder = || {
+            AlertHandler::builder(Arc::new(OutboundQueue::default()), "test-client")
+                .notification_backend(Arc::new(MockNotifier::default()))
+                .audio_backend(Arc::new(MockAudio::default()))
+                .attention_backend(Arc::new(MockAttention::default()))
+                .power_backend(Arc::new(MockPower::default()))
+        };
+        let sound_rules = |verdicts: [Verdict; 4]| {
+            [
+                Rule::SoundPolicy,
+                Rule::AudioDevice,
+                Rule::Mute,
+                Rule::QuietHours,
+            ]
+            .into_iter()
+            .zip(verdicts)
+        };
+        let toast_rules = |verdicts: [Verdict; 3]| {
+            [Rule::LockScreen, Rule::Presentation, Rule::Fullscreen]
+                .into_iter()
+                .zip(verdicts)
+        };
+        let admitted: Vec<(Rule, Verdict)> = vec![
+            (Rule::Visibility, Allow),
+            (Rule::SuppressionWindow, Allow),
+            (Rule::Expiry, Allow),
+            (Rule::MissedDigest, Allow),
+            (Rule::Burst, Allow),
+            (Rule::Replay, Allow),
+            (Rule::Supersede, Allow),
+        ];
+
+        // Nothing in the way: every rule is consulted and lets it through
+        let handler: AlertHandler = builder().build();
+        let plain: Alert = alert(AlertLevel::Warning, false);
+        let expected: Vec<(Rule, Verdict)> = admitted
+            .iter()
+            .copied()
+            .chain(sound_rules([Allow; 4]))
+            .chain(toast_rules([Allow; 3]))
+            .collect();
+        assert_eq!(decision_trace(&handler, &plain).await, expected);
+        let details: AlertDetails = handler.alert_details(plain.id).await.unwrap();
+        assert!(details
+            .body()
+            .ends_with("Delivery: sound: played; toast: shown"));
+
+        // Quiet hours silence routine alerts only
+        let settings: SharedSettings = SharedSettings::default();
+        let local: chrono::NaiveTime = chrono::Local::now().time();
+        settings
+            .update(|s| {
+                s.set_quiet_hours(Some(QuietHours {
+                    start: local - chrono::TimeDelta::hours(1),
+                    end: local + chrono::TimeDelta::hours(1),
+                }))
+            })
+            .unwrap();
+        let handler: AlertHandler = builder().settings(settings).build();
+        let routine: Vec<(Rule, Verdict)> = admitted
+            .iter()
+            .copied()
+            .chain(sound_rules([Allow, Allow, Allow, Silence]))
+            .chain(toast_rules([Allow; 3]))
+            .collect();
+        let quiet: Alert = alert(AlertLevel::Info, false);
+        assert_eq!(decision_trace(&handler, &quiet).await, routine);
+        assert_eq!(
+            handler
+                .history()
+                .get(quiet.id)
+                .unwrap()
+                .decision
+                .unwrap()
+                .summary(),
+            DecisionSummary {
+                sound: false,
+                toast: true,
+                decided_by: vec!["quiet_hours".to_string()],
+            }
+        );
+        let urgent: Vec<(Rule, Verdict)> =
+            decision_trace(&handler, &alert(AlertLevel::Critical, false)).await;
+        assert!(urgent.iter().all(|(_, verdict)| *verdict == Allow));
+
+        // The first rule to silence it settles the sound; mute is never asked
+        let settings: SharedSettings = SharedSettings::default();
+        settings
+            .update(|s| {
+                s.set_sounds_enabled(false);
+                s.set_sound_policy(SoundPolicy {
+                    visual_only: Some(true),
+                    ..SoundPolicy::default()
+                })
+            })
+            .unwrap();
+        let handler: AlertHandler = builder().settings(settings).build();
+        let mut expected: Vec<(Rule, Verdict)> = admitted.clone();
+        expected.push((Rule::SoundPolicy, Silence));
+        expected.extend(toast_rules([Allow; 3]));
+        assert_eq!(
+            decision_trace(&handler, &alert(AlertLevel::Warning, false)).await,
+            expected
+        );
+
+        // Hidden from this role: nothing after visibility is consulted
+        let handler: AlertHandler = builder().machine_role("signage").build();
+        let mut incident: Alert = alert(AlertLevel::Critical, true);
+        incident.visibility = Some(vec!["workstation".to_string()]);
+        assert_eq!(
+            decision_trace(&handler, &incident).await,
+            [(Rule::Visibility, Withhold)]
+        );
+
+        // A suppression window, with its reason
+        let suppressions: Arc<SuppressionWindows> = Arc::new(SuppressionWindows::new());
+        suppressions.insert(SuppressionWindow {
+            id: uuid::Uuid::new_v4(),
+            starts_at: chrono::Utc::now() - chrono::TimeDelta::minutes(1),
+            ends_at: chrono::Utc::now() + chrono::TimeDelta::hours(1),
+            levels: Vec::new(),
+            categories: Vec::new(),
+            reason: "Fire alarm testing".to_string(),
+            location: None,
+        });
+        let handler: AlertHandler = builder().suppressions(suppressions).build();
+        let suppressed: Alert = alert(AlertLevel::Critical, true);
+        assert_eq!(
+            decision_trace(&handler, &suppressed).await,
+            [
+                (Rule::Visibility, Allow),
+                (Rule::SuppressionWindow, Withhold)
+            ]
+        );
+        assert_eq!(
+            handler
+                .history()
+                .get(suppressed.id)
+                .unwrap()
+                .decision
+                .unwrap()
+                .describe(),
+            "sound: none (suppression_window: Fire alarm testing); \
+             toast: none (suppression_window: Fire alarm testing)"
+        );
+
+        // Missed while offline: held for the digest, neither sounded nor shown now
+        let handler: AlertHandler = builder().build();
+        let mut missed: Alert = alert(AlertLevel::Info, false);
+        missed.missed = true;
+        assert_eq!(
+            decision_trace(&handler, &missed).await,
+            [
+                (Rule::Visibility, Allow),
+                (Rule::SuppressionWindow, Allow),
+                (Rule::Expiry, Allow),
+                (Rule::MissedDigest, Hold)
+            ]
+        );
+
+        // Expired before it arrived: reported back, never sounded or shown
+        let mut stale: Alert = alert(AlertLevel::Critical, true);
+        stale.expires_at = Some(chrono::Utc::now() - chrono::TimeDelta::minutes(1));
+        assert_eq!(
+            decision_trace(&handler, &stale).await,
+            [
+                (Rule::Visibility, Allow),
+                (Rule::SuppressionWindow, Allow),
+                (Rule::Expiry, Withhold)
+            ]
+        );
+
+        // Locked: an urgent alert sounds now and its toast waits for the unlock
+        let lock: Arc<LockTracker> = Arc::new(LockTracker::new());
+        lock.set_locked(true);
+        let handler: AlertHandler = builder().lock_monitor(lock).build();
+        let mut expected: Vec<(Rule, Verdict)> = admitted.clone();
+        expected.extend(sound_rules([Allow; 4]));
+        expected.push((Rule::LockScreen, Hold));
+        assert_eq!(
+            decision_trace(&handler, &alert(AlertLevel::Critical, true)).await,
+            expected
+        );
+
+        // Without toasts or audio, the details window opens in silence
+        let (windows_tx, _windows_rx) = mpsc::unbounded_channel::<ActivationArgs>();
+        let (_capabilities_tx, capabilities_rx) = watch::channel(Capabilities {
+            toasts: false,
+            audio: false,
+            ..Capabilities::all()
+        });
+        let handler: AlertHandler = builder()
+            .toast_activations(windows_tx)
+            .capabilities(capabilities_rx)
+            .build();
+        let mut expected: Vec<(Rule, Verdict)> = admitted.clone();
+        expected.extend([
+            (Rule::SoundPolicy, Allow),
+            (Rule::AudioDevice, Silence),
+            (Rule::LockScreen, Allow),
+            (Rule::Presentation, Window),
+        ]);
+        let windowed: Alert = alert(AlertLevel::Critical, true);
+        assert_eq!(decision_trace(&handler, &windowed).await, expected);
+        let summary: DecisionSummary = handler
+            .history()
+            .get(windowed.id)
+            .unwrap()
+            .decision
+            .unwrap()
+            .summary();
+        assert!(!summary.sound && summary.toast);
+    }
+
+    #[tokio::test]
+    async fn test_alerts_hidden_from_other_roles() {
+        let signage = |placeholder: bool| {
+            let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+            let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+            let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+            let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+                .notification_backend(notifier.clone())
+                .audio_backend(audio.clone())
+                .attention_backend(Arc::new(MockAttention::default()))
+                .power_backend(Arc::new(MockPower::default()))
+                .machine_role("signage")
+                .hidden_alert_placeholder(placeholder)
+                .build();
+            (handler, outbound, notifier, audio)
+        };
+        let restricted = || {
+            let mut incident: Alert = alert(AlertLevel::Critical, true);
+            incident.title = "Badge reader compromised".to_string();
+            incident.visibility = Some(vec!["workstation".to_string(), "kiosk".to_string()]);
+            incident
+        };
+
+        let (handler, outbound, notifier, audio) = signage(false);
+        let hidden: Alert = restricted();
+        handler.handle_alert(hidden.clone()).await.unwrap();
+        assert!(notifier.shown().is_empty());
+        assert!(audio.played().is_empty());
+        assert!(!handler.is_pending(hidden.id).await);
+        assert_eq!(
+            handler.history().get(hidden.id).unwrap().withheld,
+            Some(DeliveryOutcome::HiddenByRole)
+        );
+        match outbound.next().await {
+            OutboundMessage::DeliveryStatus(status) => {
+                assert_eq!(status.alert_id, hidden.id);
+                assert_eq!(status.outcome, Some(DeliveryOutcome::HiddenByRole));
+                assert_eq!(status.detail.as_deref(), Some("signage"));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+
+        // Alerts naming the role, in any case, or naming no roles are shown as usual
+        let mut for_signage: Alert = restricted();
+        for_signage.visibility = Some(vec!["Signage".to_string()]);
+        handler.handle_alert(for_signage).await.unwrap();
+        handler
+            .handle_alert(alert(AlertLevel::Warning, false))
+            .await
+            .unwrap();
+        assert_eq!(notifier.shown().len(), 2);
+        assert_eq!(audio.played().len(), 2);
+
+        // The placeholder is silent and says nothing of the hidden alert
+        let (handler, _outbound, notifier, audio) = signage(true);
+        handler.handle_alert(restricted()).await.unwrap();
+        let shown: Vec<Alert> = notifier.shown();
+        assert_eq!(shown.len(), 1);
+        assert!(shown[0].message.contains("see your supervisor"));
+        assert!(!shown[0].title.contains("Badge"));
+        assert!(!shown[0].requires_confirmation);
+        assert!(audio.played().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_category_settings_apply_to_known_categories() {
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler =
+            AlertHandler::builder(Arc::new(OutboundQueue::default()), "test-client")
+                .notification_backend(notifier.clone())
+                .audio_backend(audio.clone())
+                .attention_backend(Arc::new(MockAttention::default()))
+                .power_backend(Arc::new(MockPower::default()))
+                .categories(Categories::new(vec![
+                    CategoryConfig {
+                        sound_file: Some("weather.wav".to_string()),
+                        requires_confirmation: Some(false),
+                        ..CategoryConfig::new("weather")
+                    },
+                    CategoryConfig::new("exercise"),
+                ]))
+                .build();
+        let categorised = |category: &str| {
+            let mut alert: Alert = alert(AlertLevel::Critical, true);
+            alert.category = Some(category.to_string());
+            alert.title = "Tornado warning".to_string();
+            alert
+        };
+
+        let weather: Alert = categorised("Weather");
+        handler.handle_alert(weather.clone()).await.unwrap();
+        assert!(!handler.is_pending(weather.id).await);
+        let drill: Alert = categorised("exercise");
+        handler.handle_alert(drill.clone()).await.unwrap();
+        assert!(handler.is_pending(drill.id).await);
+        // Unknown categories are handled as sent
+        handler
+            .handle_alert(categorised("it_outage"))
+            .await
+            .unwrap();
+
+        let shown: Vec<Alert> = notifier.shown();
+        let titles: Vec<&str> = shown.iter().map(|a| a.title.as_str()).collect();
+        assert_eq!(
+            titles,
+            [
+                "Tornado warning",
+                "EXERCISE: Tornado warning",
+                "Tornado warning"
+            ]
+        );
+        assert!(!shown[0].requires_confirmation);
+        assert!(shown[2].requires_confirmation);
+        assert_eq!(
+            handler.history().get(drill.id).unwrap().title,
+            "EXERCISE: Tornado warning"
+        );
+        let played: Vec<String> = audio.played();
+        assert_eq!(played[0], "weather.wav");
+        assert_ne!(played[1], "weather.wav");
+        assert_ne!(played[2], "weather.wav");
+    }
+
+    #[tokio::test]
+    async fn test_translation_shown_is_reported_with_the_decision() {
+        use crate::messages::Translation;
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(Arc::new(MockNotifier::default()))
+            .audio_backend(Arc::new(MockAudio::default()))
+            .attention_backend(Arc::new(MockAttention::default()))
+            .power_backend(Arc::new(MockPower::default()))
+            .locale(Some("fr-CA".to_string()))
+            .build();
+        let mut translated: Alert = alert(AlertLevel::Warning, false);
+        translated.translations.insert(
+            "fr".to_string(),
+            Translation {
+                title: "Exercice".to_string(),
+                message: "Ceci est un exercice".to_string(),
+            },
+        );
+        handler.handle_alert(translated.clone()).await.unwrap();
+        handler
+            .handle_alert(alert(AlertLevel::Warning, false))
+            .await
+            .unwrap();
+
+        let mut locales: Vec<Option<String>> = Vec::new();
+        for _ in 0..2 {
+            match outbound.next().await {
+                OutboundMessage::DeliveryStatus(status) => locales.push(status.locale),
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        assert_eq!(locales, [Some("fr".to_string()), None]);
+    }
+
+    #[tokio::test]
+    async fn test_alerts_for_other_groups_and_hosts_are_dropped() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .attention_backend(Arc::new(MockAttention::default()))
+            .power_backend(Arc::new(MockPower::default()))
+            .groups(vec!["ops".to_string()])
+            .build();
+
+        // Neither the toast nor the history nor the server hear of it
+        let mut elsewhere: Alert = alert(AlertLevel::Critical, true);
+        elsewhere.target_groups = vec!["finance".to_string()];
+        elsewhere.target_hosts = vec!["finance-ws-*".to_string()];
+        handler.handle_alert(elsewhere.clone()).await.unwrap();
+        assert!(notifier.shown().is_empty());
+        assert!(handler.history().get(elsewhere.id).is_none());
+        assert!(!handler.is_pending(elsewhere.id).await);
+        assert!(outbound.is_empty());
+
+        // Host patterns are matched in `Alert::addressed_to`, tested against fixed names there
+        let mut for_ops: Alert = alert(AlertLevel::Warning, false);
+        for_ops.target_groups = vec!["OPS".to_string()];
+        handler.handle_alert(for_ops).await.unwrap();
+        assert_eq!(notifier.shown().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_image_delays_the_toast_only_briefly() {
+        use crate::images::{ImageCache, ImageConfig};
+
+        // Accepts the connection and never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: std::net::SocketAddr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _held = listener.accept().await;
+            std::future::pending::<()>().await
+        });
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("emns-image-{}", uuid::Uuid::new_v4()));
+        let images: Arc<ImageCache> = Arc::new(ImageCache::new(
+            &dir,
+            &ImageConfig {
+                wait: Duration::from_millis(100),
+                ..ImageConfig::default()
+            },
+        ));
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let handler: AlertHandler =
+            AlertHandler::builder(Arc::new(OutboundQueue::default()), "test-client")
+                .notification_backend(notifier.clone())
+                .audio_backend(Arc::new(MockAudio::default()))
+                .attention_backend(Arc::new(MockAttention::default()))
+                .power_backend(Arc::new(MockPower::default()))
+                .image_cache(images.clone())
+                .build();
+
+        let mut alert: Alert = alert(AlertLevel::Warning, false);
+        alert.image_url = Some(format!("http://{}/radar.png", addr));
+        let started: std::time::Instant = std::time::Instant::now();
+        handler.handle_alert(alert.clone()).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(notifier.shown().len(), 1);
+        assert_eq!(images.cached(alert.id), None);
+    }
+
+    #[tokio::test]
+    async fn test_suppression_windows_outlast_handler_restart() {
+        let path: PathBuf = std::env::temp_dir()
+            .join(format!("emns-suppression-{}", uuid::Uuid::new_v4()))
+            .join(crate::suppression::SUPPRESSION_FILE);
+        let build = |notifier: Arc<MockNotifier>| {
+            let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+            AlertHandler::builder(outbound.clone(), "test-client")
+                .notification_backend(notifier)
+                .audio_backend(Arc::new(MockAudio::default()))
+                .attention_backend(Arc::new(MockAttention::default()))
+                .suppressions(Arc::new(SuppressionWindows::open(&path).unwrap()))
+                .build()
+        };
+
+        let first: AlertHandler = build(Arc::new(MockNotifier::default()));
+        first.suppressions.insert(SuppressionWindow {
+            id: uuid::Uuid::new_v4(),
+            starts_at: chrono::Utc::now() - chrono::Duration::minutes(1),
+            ends_at: chrono::Utc::now() + chrono::Duration::hours(1),
+            levels: vec![AlertLevel::Warning],
+            categories: Vec::new(),
+            reason: "Fire alarm testing".to_string(),
+            location: None,
+        });
+        drop(first);
+
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let second: AlertHandler = build(notifier.clone());
+        second
+            .handle_alert(alert(AlertLevel::Warning, false))
+            .await
+            .unwrap();
+        second
+            .handle_alert(alert(AlertLevel::Info, false))
+            .await
+            .unwrap();
+        assert_eq!(notifier.shown().len(), 1);
+        assert_eq!(notifier.shown()[0].level, AlertLevel::Info);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_locked_workstation_sounds_urgent_alerts_and_shows_them_on_unlock() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let lock: Arc<LockTracker> = Arc::new(LockTracker::new());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .attention_backend(Arc::new(MockAttention::default()))
+            .power_backend(Arc::new(MockPower::default()))
+            .lock_monitor(lock.clone())
+            .build();
+
+        lock.set_locked(true);
+        let critical: Alert = alert(AlertLevel::Critical, true);
+        let info: Alert = alert(AlertLevel::Info, false);
+        handler.handle_alert(critical.clone()).await.unwrap();
+        handler.handle_alert(info.clone()).await.unwrap();
+
+        // Both sound at once; only the Critical toast waits for the unlock
+        assert_eq!(audio.played().len(), 2);
+        let shown: Vec<uuid::Uuid> = notifier.shown().iter().map(|a| a.id).collect();
+        assert_eq!(shown, vec![info.id]);
+        assert_eq!(handler.held_for_unlock_count(), 1);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(notifier.shown().len(), 1);
+
+        lock.set_locked(false);
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        let shown: Vec<uuid::Uuid> = notifier.shown().iter().map(|a| a.id).collect();
+        assert_eq!(shown, vec![info.id, critical.id]);
+        assert_eq!(handler.held_for_unlock_count(), 0);
+        for expected in [critical.id, info.id] {
+            match outbound.next().await {
+                OutboundMessage::DeliveryStatus(status) => assert_eq!(status.alert_id, expected),
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        match outbound.next().await {
+            OutboundMessage::DeliveryStatus(status) => {
+                assert_eq!(status.alert_id, critical.id);
+                assert_eq!(status.outcome, Some(DeliveryOutcome::ShownOnUnlock));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+
+        // Latency counts from the unlock, not from the arrival
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        handler
+            .confirm_alert(critical.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        let confirmation: Confirmation = confirmations.recv().await;
+        assert_eq!(confirmation.response_latency_ms, Some(5_001));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_auto_confirm_countdown_pauses_while_locked() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let lock: Arc<LockTracker> = Arc::new(LockTracker::new());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(Arc::new(MockNotifier::default()))
+            .audio_backend(Arc::new(MockAudio::default()))
+            .attention_backend(Arc::new(MockAttention::default()))
+            .lock_monitor(lock.clone())
+            .pause_while_locked(true)
+            .build();
+        let start: Instant = Instant::now();
+        let warning: Alert = alert(AlertLevel::Warning, true);
+        handler.handle_alert(warning.clone()).await.unwrap();
+
+        // 100 of the 300 seconds pass, then the machine is locked well past the timeout
+        tokio::time::sleep(Duration::from_secs(100)).await;
+        lock.set_locked(true);
+        tokio::time::sleep(Duration::from_secs(1000)).await;
+        assert!(confirmations.try_recv().is_none());
+        assert_eq!(handler.get_pending_alerts().await, vec![warning.id]);
+
+        // The remaining 200 seconds run from the unlock
+        lock.set_locked(false);
+        let confirmation: Confirmation = confirmations.recv().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1300));
+        assert_eq!(confirmation.alert_id, warning.id);
+        assert_eq!(confirmation.reason, ConfirmationReason::TimedOut);
+    }
+
+    /// Sink that records what it was told
+    #[derive(Default)]
+    struct RecordingSink(std::sync::Mutex<Vec<(uuid::Uuid, Option<Resolution>)>>);
+
+    impl AlertSink for RecordingSink {
+        fn delivered(&self, alert: &Alert) {
+            self.0.lock().unwrap().push((alert.id, None));
+        }
+
+        fn resolved(&self, alert_id: uuid::Uuid, resolution: Resolution) {
+            self.0.lock().unwrap().push((alert_id, Some(resolution)));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sinks_follow_alerts_from_delivery_to_resolution() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let sink: Arc<RecordingSink> = Arc::new(RecordingSink::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(Arc::new(MockNotifier::default()))
+            .audio_backend(Arc::new(MockAudio::default()))
+            .attention_backend(Arc::new(MockAttention::default()))
+            .sink(sink.clone())
+            .build();
+
+        let confirmed: Alert = alert(AlertLevel::Emergency, true);
+        let expired: Alert = alert(AlertLevel::Critical, true);
+        let cancelled: Alert = alert(AlertLevel::Critical, true);
+        handler.handle_alert(confirmed.clone()).await.unwrap();
+        handler.handle_alert(expired.clone()).await.unwrap();
+        handler.handle_alert(cancelled.clone()).await.unwrap();
+        // Duplicates are not delivered twice
+        handler.handle_alert(confirmed.clone()).await.unwrap();
+        handler
+            .confirm_alert(confirmed.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        assert!(handler.withdraw(cancelled.id, Withdrawal::Cancelled).await);
+        confirmations.recv().await;
+        assert_eq!(confirmations.recv().await.alert_id, expired.id);
+
+        assert_eq!(
+            *sink.0.lock().unwrap(),
+            vec![
+                (confirmed.id, None),
+                (expired.id, None),
+                (cancelled.id, None),
+                (
+                    confirmed.id,
+                    Some(Resolution::Confirmed(ConfirmationReason::User))
+                ),
+                (
+                    cancelled.id,
+                    Some(Resolution::Withdrawn(Withdrawal::Cancelled))
+                ),
+                (
+                    expired.id,
+                    Some(Resolution::Confirmed(ConfirmationReason::TimedOut))
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_withdrawn_alert_is_removed_without_confirming() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let power: Arc<MockPower> = Arc::new(MockPower::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .attention_backend(Arc::new(MockAttention::default()))
+            .power_backend(power.clone())
+            .build();
+
+        let emergency: Alert = alert(AlertLevel::Emergency, true);
+        handler.handle_alert(emergency.clone()).await.unwrap();
+        assert_eq!(power.keep_awake_calls(), vec![true]);
+
+        assert!(handler.withdraw(emergency.id, Withdrawal::Expired).await);
+        assert!(!handler.withdraw(emergency.id, Withdrawal::Expired).await);
+        assert_eq!(notifier.removed(), vec![emergency.id]);
+        assert_eq!(handler.pending_count().await, 0);
+        assert_eq!(power.keep_awake_calls(), vec![true, false]);
+
+        // Well past the auto-confirm timeout, nothing is sent
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert!(confirmations.try_recv().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancelled_alert_is_replaced_by_a_notice() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .attention_backend(Arc::new(MockAttention::default()))
+            .build();
+
+        let emergency: Alert = alert(AlertLevel::Emergency, true);
+        handler.handle_alert(emergency.clone()).await.unwrap();
+        assert!(handler.cancel(emergency.id, Some("sent in error")).await);
+        assert_eq!(handler.pending_count().await, 0);
+        assert_eq!(notifier.removed(), vec![emergency.id]);
+        let notice: Alert = notifier.shown().pop().unwrap();
+        assert_eq!(notice.title, "Alert cancelled: sent in error");
+        assert!(notice.message.contains(&emergency.title));
+        assert_eq!(notice.level, AlertLevel::Info);
+        assert!(!notice.requires_confirmation);
+
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert!(confirmations.try_recv().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancelling_unknown_or_confirmed_alert_does_nothing() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .attention_backend(Arc::new(MockAttention::default()))
+            .build();
+
+        assert!(!handler.cancel(uuid::Uuid::new_v4(), None).await);
+
+        let critical: Alert = alert(AlertLevel::Critical, true);
+        handler.handle_alert(critical.clone()).await.unwrap();
+        handler
+            .confirm_alert(critical.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        assert_eq!(confirmations.recv().await.alert_id, critical.id);
+        let shown: usize = notifier.shown().len();
+
+        assert!(!handler.cancel(critical.id, Some("sent in error")).await);
+        assert_eq!(notifier.shown().len(), shown);
+        assert!(!notifier.removed().contains(&critical.id));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_preview_never_escalates_and_is_taken_down_unanswered() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .escalation(EscalationPolicy {
+                critical: Some(crate::escalation::Escalation {
+                    sound_file: "air_horn.wav".to_string(),
+                    after: Duration::from_secs(10),
+                }),
+                ..EscalationPolicy::default()
+            })
+            .build();
+
+        let mut preview: Alert = alert(AlertLevel::Critical, true);
+        preview.is_preview = true;
+        handler.handle_alert(preview.clone()).await.unwrap();
+        assert_eq!(notifier.shown().len(), 1);
+
+        tokio::time::sleep(PREVIEW_LIFETIME - Duration::from_secs(1)).await;
+        assert!(audio.played_at().is_empty());
+        assert_eq!(handler.pending_alerts().await.len(), 1);
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(notifier.removed(), [preview.id]);
+        assert!(handler.pending_alerts().await.is_empty());
+        assert!(confirmations.try_recv().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_confirming_preview_is_reported_as_preview() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .build();
+
+        let mut preview: Alert = alert(AlertLevel::Warning, true);
+        preview.is_preview = true;
+        handler.handle_alert(preview.clone()).await.unwrap();
+        handler
+            .confirm_alert(preview.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        assert!(confirmations.recv().await.is_preview);
+
+        // Answered previews are taken down by the user; the expiry has nothing left to do
+        tokio::time::sleep(PREVIEW_LIFETIME * 2).await;
+        assert!(notifier.removed().is_empty());
+    }
+
+    fn expiry_reports(outbound: &OutboundQueue) -> Vec<(uuid::Uuid, bool)> {
+        std::iter::from_fn(|| outbound.try_next())
+            .filter_map(|message| match message {
+                OutboundMessage::AlertExpired {
+                    alert_id,
+                    unconfirmed,
+                    ..
+                } => Some((alert_id, unconfirmed)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_alert_expired_on_arrival_is_only_reported() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .build();
+
+        let mut stale: Alert = alert(AlertLevel::Emergency, true);
+        stale.expires_at = Some(chrono::Utc::now() - chrono::TimeDelta::minutes(5));
+        handler.handle_alert(stale.clone()).await.unwrap();
+        assert!(notifier.shown().is_empty());
+        assert!(audio.played_at().is_empty());
+        assert_eq!(handler.pending_count().await, 0);
+        assert_eq!(
+            handler.history().get(stale.id).unwrap().withheld,
+            Some(DeliveryOutcome::Expired)
+        );
+        assert_eq!(expiry_reports(&outbound), [(stale.id, false)]);
+
+        // Not yet expired, it is delivered as usual
+        let mut fresh: Alert = alert(AlertLevel::Warning, false);
+        fresh.expires_at = Some(chrono::Utc::now() + chrono::TimeDelta::minutes(5));
+        handler.handle_alert(fresh).await.unwrap();
+        assert_eq!(notifier.shown().len(), 1);
+        assert!(expiry_reports(&outbound).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_alert_expiring_while_pending_is_taken_down_unconfirmed() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .build();
+
+        let mut critical: Alert = alert(AlertLevel::Critical, true);
+        critical.expires_at = Some(chrono::Utc::now() + chrono::TimeDelta::seconds(30));
+        handler.handle_alert(critical.clone()).await.unwrap();
+        assert_eq!(handler.pending_count().await, 1);
+
+        tokio::time::sleep(Duration::from_secs(29)).await;
+        assert_eq!(handler.pending_count().await, 1);
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(handler.pending_count().await, 0);
+        assert_eq!(notifier.removed(), [critical.id]);
+        let reports: Vec<(uuid::Uuid, bool)> = expiry_reports(&outbound);
+        assert_eq!(reports, [(critical.id, true)]);
+
+        // Never auto-confirmed afterwards
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert!(std::iter::from_fn(|| outbound.try_next())
+            .all(|message| !matches!(message, OutboundMessage::Confirmation(_))));
+    }
+
+    fn superseding(earlier: &Alert, level: AlertLevel, requires_confirmation: bool) -> Alert {
+        let mut update: Alert = alert(level, requires_confirmation);
+        update.supersedes = Some(earlier.id);
+        update
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_superseding_unknown_alert_is_delivered_as_usual() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .build();
+
+        let never_received: Alert = alert(AlertLevel::Critical, true);
+        let update: Alert = superseding(&never_received, AlertLevel::Critical, true);
+        handler.handle_alert(update.clone()).await.unwrap();
+        assert_eq!(audio.played().len(), 1);
+        assert_eq!(notifier.shown()[0].message, update.message);
+        assert!(notifier.removed().is_empty());
+        assert_eq!(handler.pending_count().await, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_superseding_confirmed_alert_updates_quietly() {
+        use Verdict::Silence;
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .build();
+
+        let watch: Alert = alert(AlertLevel::Critical, true);
+        handler.handle_alert(watch.clone()).await.unwrap();
+        handler
+            .confirm_alert(watch.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        assert_eq!(confirmations.recv().await.alert_id, watch.id);
+
+        // Same level: shown as an update, without a second alarm
+        let revised: Alert = superseding(&watch, AlertLevel::Critical, true);
+        handler.handle_alert(revised.clone()).await.unwrap();
+        assert_eq!(audio.played().len(), 1);
+        let shown: Alert = notifier.shown().pop().unwrap();
+        assert_eq!(
+            shown.message,
+            format!(
+                "Updates earlier alert \"{}\".\n{}",
+                watch.title, revised.message
+            )
+        );
+        let decision: DeliveryDecision =
+            handler.history().get(revised.id).unwrap().decision.unwrap();
+        assert_eq!(decision.verdict(Rule::Supersede), Some(Silence));
+
+        // Nothing was pending to take over, so only the update is confirmed
+        handler
+            .confirm_alert(revised.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        assert_eq!(confirmations.recv().await.alert_id, revised.id);
+        assert!(confirmations.try_recv().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_update_at_the_message_limit_stays_within_it() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let limits: TextLimits = TextLimits::default();
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .text_limits(limits)
+            .build();
+
+        let watch: Alert = alert(AlertLevel::Warning, false);
+        handler.handle_alert(watch.clone()).await.unwrap();
+        let mut revised: Alert = superseding(&watch, AlertLevel::Warning, false);
+        revised.message = "x".repeat(limits.max_message_chars);
+        handler.handle_alert(revised).await.unwrap();
+
+        let shown: Alert = notifier.shown().pop().unwrap();
+        assert_eq!(shown.message.chars().count(), limits.max_message_chars);
+        assert!(shown
+            .message
+            .starts_with(&format!("Updates earlier alert \"{}\".\n", watch.title)));
+        assert!(shown.message.ends_with('…'));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_more_severe_update_sounds_and_takes_over_pending_alert() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .build();
+
+        let watch: Alert = alert(AlertLevel::Warning, true);
+        handler.handle_alert(watch.clone()).await.unwrap();
+        let warning: Alert = superseding(&watch, AlertLevel::Emergency, true);
+        handler.handle_alert(warning.clone()).await.unwrap();
+        assert_eq!(audio.played().len(), 2);
+        assert_eq!(notifier.removed(), [watch.id]);
+        let pending: Vec<uuid::Uuid> = handler
+            .pending_alerts()
+            .await
+            .iter()
+            .map(|alert| alert.id)
+            .collect();
+        assert_eq!(pending, [warning.id]);
+
+        // Confirming the update answers for both
+        handler
+            .confirm_alert(warning.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        assert_eq!(confirmations.recv().await.alert_id, warning.id);
+        assert_eq!(confirmations.recv().await.alert_id, watch.id);
+
+        // Nor is the earlier alert auto-confirmed on its own later
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert!(confirmations.try_recv().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_superseded_pending_alert_can_be_cancelled_instead() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let builder = |outbound: &Arc<OutboundQueue>, policy| {
+            AlertHandler::builder(outbound.clone(), "test-client")
+                .notification_backend(notifier.clone())
+                .audio_backend(Arc::new(MockAudio::default()))
+                .superseded_pending(policy)
+                .build()
+        };
+
+        // Transferred only to an update that itself awaits confirmation
+        let transfer: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let handler: AlertHandler = builder(&transfer, SupersedePolicy::Transfer);
+        let warning: Alert = alert(AlertLevel::Critical, true);
+        handler.handle_alert(warning.clone()).await.unwrap();
+        let all_clear: Alert = superseding(&warning, AlertLevel::Info, false);
+        handler.handle_alert(all_clear).await.unwrap();
+        assert_eq!(handler.pending_count().await, 1);
+        assert!(notifier.removed().is_empty());
+
+        let handler: AlertHandler = builder(&outbound, SupersedePolicy::Cancel);
+        let warning: Alert = alert(AlertLevel::Critical, true);
+        handler.handle_alert(warning.clone()).await.unwrap();
+        let all_clear: Alert = superseding(&warning, AlertLevel::Info, false);
+        handler.handle_alert(all_clear).await.unwrap();
+        assert_eq!(handler.pending_count().await, 0);
+        assert_eq!(notifier.removed(), [warning.id]);
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert!(confirmations.try_recv().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bulk_confirm_and_dismiss_follow_filter() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .build();
+
+        let old_info: Alert = alert(AlertLevel::Info, true);
+        handler.handle_alert(old_info.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(120)).await;
+        let emergency: Alert = alert(AlertLevel::Emergency, true);
+        let warning: Alert = alert(AlertLevel::Warning, true);
+        let info: Alert = alert(AlertLevel::Info, true);
+        let critical: Alert = alert(AlertLevel::Critical, true);
+        for pending in [&emergency, &warning, &info, &critical] {
+            handler.handle_alert(pending.clone()).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        // Recent Info and Warning alerts only, oldest first
+        let filter: BulkFilter = BulkFilter::all()
+            .level(AlertLevel::Info)
+            .level(AlertLevel::Warning)
+            .max_age(Duration::from_secs(60));
+        let summary: BulkSummary = handler.confirm_all(&filter, ConfirmationMethod::Api).await;
+        assert_eq!(summary.resolved, vec![warning.id, info.id]);
+        assert_eq!(summary.skipped, 3);
+        for expected in [&warning, &info] {
+            let confirmed: Confirmation = confirmations.recv().await;
+            assert_eq!(confirmed.alert_id, expected.id);
+            assert_eq!(confirmed.reason, ConfirmationReason::User);
+        }
+        assert_eq!(notifier.removed(), vec![warning.id, info.id]);
+
+        // Emergency alerts stay even when asked for by level
+        let summary: BulkSummary = handler
+            .dismiss_all(
+                &BulkFilter::all().level(AlertLevel::Emergency),
+                ConfirmationMethod::Api,
+            )
+            .await;
+        assert!(summary.resolved.is_empty());
+        assert_eq!(summary.skipped, 3);
+
+        let summary: BulkSummary = handler
+            .dismiss_all(&BulkFilter::all(), ConfirmationMethod::Api)
+            .await;
+        assert_eq!(summary.resolved, vec![old_info.id, critical.id]);
+        assert_eq!(summary.skipped, 1);
+        for expected in [&old_info, &critical] {
+            let dismissed: Confirmation = confirmations.recv().await;
+            assert_eq!(dismissed.alert_id, expected.id);
+            assert_eq!(dismissed.reason, ConfirmationReason::Dismissed);
+        }
+        assert!(confirmations.try_recv().is_none());
+        assert!(handler.is_pending(emergency.id).await);
+    }
+
+    fn critical_escalation() -> EscalationPolicy {
+        EscalationPolicy {
+            critical: Some(crate::escalation::Escalation {
+                sound_file: "air_horn.wav".to_string(),
+                after: Duration::from_millis(500),
+            }),
+            ..EscalationPolicy::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_quorum_met_softens_alert_but_keeps_it_confirmable() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let audio: Arc<MockAudio> = Arc::new(MockAudio::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(audio.clone())
+            .escalation(critical_escalation())
+            .build();
+        let team: Vec<String> = vec!["J. Smith".to_string()];
+
+        let escalated: Alert = alert(AlertLevel::Critical, true);
+        handler.handle_alert(escalated.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(audio.played_at().len(), 1);
+        let quiet: Alert = alert(AlertLevel::Critical, true);
+        handler.handle_alert(quiet.clone()).await.unwrap();
+
+        // The sound already playing stops, and the other alert never escalates
+        assert!(handler.quorum_met(escalated.id, &team).await);
+        assert!(handler.quorum_met(quiet.id, &team).await);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(
+            audio.played_at(),
+            [("air_horn.wav".to_string(), ESCALATION_VOLUME, true)]
+        );
+        assert!(handler
+            .history()
+            .get(quiet.id)
+            .unwrap()
+            .escalated_at
+            .is_none());
+        assert_eq!(
+            notifier.acknowledged(),
+            vec![(escalated.id, team.clone()), (quiet.id, team.clone())]
+        );
+        // Nor does the countdown overwrite the acknowledgment
+        let countdowns: usize = notifier.countdowns().len();
+        tokio::time::sleep(COUNTDOWN_REFRESH_INTERVAL * 2).await;
+        assert_eq!(notifier.countdowns().len(), countdowns);
+
+        // Still up, and confirming it is reported as usual
+        assert!(handler.is_pending(quiet.id).await);
+        assert!(notifier.removed().is_empty());
+        handler
+            .confirm_alert(quiet.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        let confirmed: Confirmation = confirmations.recv().await;
+        assert_eq!(confirmed.alert_id, quiet.id);
+        assert_eq!(confirmed.reason, ConfirmationReason::User);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_quorum_met_racing_local_confirm_sends_one_confirmation() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "test-client")
+            .notification_backend(notifier.clone())
+            .audio_backend(Arc::new(MockAudio::default()))
+            .escalation(critical_escalation())
+            .build();
+        let team: Vec<String> = vec!["J. Smith".to_string(), "B-10442".to_string()];
+
+        // Confirmed here just before the server's notice arrives
+        let first: Alert = alert(AlertLevel::Critical, true);
+        handler.handle_alert(first.clone()).await.unwrap();
+        handler
+            .confirm_alert(first.id, ConfirmationMethod::ToastButton)
+            .await
+            .unwrap();
+        assert!(!handler.quorum_met(first.id, &team).await);
+        assert_eq!(confirmations.recv().await.alert_id, first.id);
+
+        // Both at once, in either order
+        let raced: Alert = alert(AlertLevel::Critical, true);
+        let reversed: Alert = alert(AlertLevel::Critical, true);
+        handler.handle_alert(raced.clone()).await.unwrap();
+        handler.handle_alert(reversed.clone()).await.unwrap();
+        let (confirmed, _) = tokio::join!(
+            handler.confirm_alert(raced.id, ConfirmationMethod::ToastButton),
+            handler.quorum_met(raced.id, &team)
+        );
+        confirmed.unwrap();
+        let (_, confirmed) = tokio::join!(
+            handler.quorum_met(reversed.id, &team),
+            handler.confirm_alert(reversed.id, ConfirmationMethod::ToastButton)
+        );
+        confirmed.unwrap();
+        assert_eq!(confirmations.recv().await.alert_id, raced.id);
+        assert_eq!(confirmations.recv().await.alert_id, reversed.id);
+
+        // Neither is left pending nor confirmed twice
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(confirmations.try_recv().is_none());
+        assert_eq!(handler.pending_count().await, 0);
+        assert!(notifier
+            .acknowledged()
+            .iter()
+            .all(|(alert_id, _)| *alert_id != first.id));
     }
 }
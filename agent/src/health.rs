@@ -0,0 +1,311 @@
+//! Host health sampled for status reports
+
+use crate::messages::SystemHealth;
+use crate::settings::SharedSettings;
+use crate::status::StatusCollector;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+/// Source of CPU, memory, disk, and audio readings
+pub trait SystemProbe: Send + Sync {
+    /// Read the current health; fields that cannot be read are left `None`.
+    ///
+    /// May block, so it is only called from the blocking thread pool.
+    fn sample(&self) -> SystemHealth;
+}
+
+/// Reads the local machine through Win32 APIs
+pub struct HostProbe {
+    /// Disk free space is reported for the volume holding this directory
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    data_dir: PathBuf,
+    /// CPU times at the previous sample, for the usage delta
+    #[cfg(target_os = "windows")]
+    last_cpu: std::sync::Mutex<Option<win32::CpuTimes>>,
+}
+
+impl HostProbe {
+    pub fn new(data_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            data_dir: data_dir.into(),
+            #[cfg(target_os = "windows")]
+            last_cpu: std::sync::Mutex::new(None),
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl SystemProbe for HostProbe {
+    fn sample(&self) -> SystemHealth {
+        let (audio_available, system_muted) = win32::audio_state();
+        SystemHealth {
+            cpu_percent: win32::cpu_times().and_then(|now| {
+                let previous = self.last_cpu.lock().unwrap().replace(now);
+                previous.and_then(|previous| now.percent_since(&previous))
+            }),
+            memory_available_bytes: win32::memory_available(),
+            data_disk_free_bytes: win32::disk_free(&self.data_dir),
+            audio_available,
+            system_muted,
+        }
+    }
+}
+
+/// Health probing is only available on Windows; elsewhere every field is omitted
+#[cfg(not(target_os = "windows"))]
+impl SystemProbe for HostProbe {
+    fn sample(&self) -> SystemHealth {
+        SystemHealth::default()
+    }
+}
+
+/// Sample `probe` now and after every status interval, handing each result to `status`.
+///
+/// Samples are taken on the blocking pool, so a slow probe delays the next
+/// sample rather than a status report. A probe that panics clears the
+/// readings instead of reporting stale ones.
+pub async fn run_sampler(
+    probe: Arc<dyn SystemProbe>,
+    status: Arc<StatusCollector>,
+    settings: SharedSettings,
+    cancel: CancellationToken,
+) {
+    loop {
+        let sampling = tokio::task::spawn_blocking({
+            let probe: Arc<dyn SystemProbe> = probe.clone();
+            move || probe.sample()
+        });
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            sampled = sampling => match sampled {
+                Ok(health) => status.set_system_health(health),
+                Err(e) => {
+                    log::warn!("System health probe failed: {}", e);
+                    status.set_system_health(SystemHealth::default());
+                }
+            },
+        }
+
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = tokio::time::sleep(settings.snapshot().status_interval()) => {}
+        }
+    }
+    log::debug!("System health sampler stopped");
+}
+
+#[cfg(target_os = "windows")]
+mod win32 {
+    use std::path::Path;
+    use windows::core::HSTRING;
+    use windows::Win32::Foundation::FILETIME;
+    use windows::Win32::Media::Audio::Endpoints::IAudioEndpointVolume;
+    use windows::Win32::Media::Audio::{
+        eConsole, eRender, IMMDevice, IMMDeviceEnumerator, MMDeviceEnumerator,
+    };
+    use windows::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+    use windows::Win32::System::Com::{
+        CoCreateInstance, CoInitializeEx, CoUninitialize, CLSCTX_ALL, COINIT_MULTITHREADED,
+    };
+    use windows::Win32::System::SystemInformation::{GlobalMemoryStatusEx, MEMORYSTATUSEX};
+    use windows::Win32::System::Threading::GetSystemTimes;
+
+    /// Cumulative system CPU times, in 100 ns units
+    #[derive(Debug, Clone, Copy)]
+    pub(super) struct CpuTimes {
+        idle: u64,
+        /// Kernel time includes idle time
+        kernel: u64,
+        user: u64,
+    }
+
+    impl CpuTimes {
+        pub(super) fn percent_since(&self, earlier: &CpuTimes) -> Option<f32> {
+            let idle: u64 = self.idle.checked_sub(earlier.idle)?;
+            let total: u64 =
+                (self.kernel + self.user).checked_sub(earlier.kernel + earlier.user)?;
+            if total == 0 {
+                return None;
+            }
+            Some(total.saturating_sub(idle) as f32 * 100.0 / total as f32)
+        }
+    }
+
+    fn ticks(time: FILETIME) -> u64 {
+        ((time.dwHighDateTime as u64) << 32) | time.dwLowDateTime as u64
+    }
+
+    pub(super) fn cpu_times() -> Option<CpuTimes> {
+        let (mut idle, mut kernel, mut user) = (
+            FILETIME::default(),
+            FILETIME::default(),
+            FILETIME::default(),
+        );
+        unsafe { GetSystemTimes(Some(&mut idle), Some(&mut kernel), Some(&mut user)) }
+            .map_err(|e| log::debug!("GetSystemTimes failed: {}", e))
+            .ok()?;
+        Some(CpuTimes {
+            idle: ticks(idle),
+            kernel: ticks(kernel),
+            user: ticks(user),
+        })
+    }
+
+    pub(super) fn memory_available() -> Option<u64> {
+        let mut status: MEMORYSTATUSEX = MEMORYSTATUSEX {
+            dwLength: std::mem::size_of::<MEMORYSTATUSEX>() as u32,
+            ..Default::default()
+        };
+        unsafe { GlobalMemoryStatusEx(&mut status) }
+            .map_err(|e| log::debug!("GlobalMemoryStatusEx failed: {}", e))
+            .ok()?;
+        Some(status.ullAvailPhys)
+    }
+
+    pub(super) fn disk_free(dir: &Path) -> Option<u64> {
+        let mut free: u64 = 0;
+        unsafe {
+            GetDiskFreeSpaceExW(&HSTRING::from(dir.as_os_str()), Some(&mut free), None, None)
+        }
+        .map_err(|e| log::debug!("GetDiskFreeSpaceExW failed for {}: {}", dir.display(), e))
+        .ok()?;
+        Some(free)
+    }
+
+    /// Whether a default output device exists, and whether it is muted
+    pub(super) fn audio_state() -> (Option<bool>, Option<bool>) {
+        unsafe {
+            // S_FALSE when this pool thread already joined the MTA; either way it must be balanced
+            if CoInitializeEx(None, COINIT_MULTITHREADED).is_err() {
+                return (None, None);
+            }
+            let state = default_device().map(|device| match device {
+                Some(device) => (
+                    Some(true),
+                    device
+                        .Activate::<IAudioEndpointVolume>(CLSCTX_ALL, None)
+                        .and_then(|volume| volume.GetMute())
+                        .map(|muted| muted.as_bool())
+                        .ok(),
+                ),
+                None => (Some(false), None),
+            });
+            CoUninitialize();
+            state.unwrap_or((None, None))
+        }
+    }
+
+    /// The default render endpoint, or `None` when the machine has no output device
+    unsafe fn default_device() -> windows::core::Result<Option<IMMDevice>> {
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
+        match enumerator.GetDefaultAudioEndpoint(eRender, eConsole) {
+            Ok(device) => Ok(Some(device)),
+            // E_NOTFOUND: no output device is present
+            Err(e) if e.code().0 as u32 == 0x8007_0490 => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::OutboundQueue;
+    use crate::queue::AlertQueue;
+    use std::sync::mpsc as std_mpsc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Blocks each sample until the test hands it a reading
+    struct GatedProbe {
+        readings: Mutex<std_mpsc::Receiver<SystemHealth>>,
+    }
+
+    impl SystemProbe for GatedProbe {
+        fn sample(&self) -> SystemHealth {
+            match self.readings.lock().unwrap().recv() {
+                Ok(health) => health,
+                Err(_) => panic!("probe abandoned"),
+            }
+        }
+    }
+
+    fn collector() -> Arc<StatusCollector> {
+        Arc::new(StatusCollector::new(
+            "test-client",
+            Arc::new(AlertQueue::new(10)),
+            Arc::new(OutboundQueue::default()),
+        ))
+    }
+
+    async fn wait_for(status: &StatusCollector, expected: &SystemHealth) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while status.collect().system != *expected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("sample reached the status collector");
+    }
+
+    #[tokio::test]
+    async fn test_slow_probe_does_not_hold_up_status() {
+        let (readings_tx, readings_rx) = std_mpsc::channel::<SystemHealth>();
+        let probe: Arc<GatedProbe> = Arc::new(GatedProbe {
+            readings: Mutex::new(readings_rx),
+        });
+        let status: Arc<StatusCollector> = collector();
+        let cancel: CancellationToken = CancellationToken::new();
+        let sampler = tokio::spawn(run_sampler(
+            probe,
+            status.clone(),
+            SharedSettings::default(),
+            cancel.clone(),
+        ));
+
+        // The probe is stuck; reports still go out, just without health fields
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let started: std::time::Instant = std::time::Instant::now();
+        assert!(status.collect().system.is_empty());
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        let health: SystemHealth = SystemHealth {
+            cpu_percent: Some(97.5),
+            memory_available_bytes: Some(128 * 1024 * 1024),
+            ..SystemHealth::default()
+        };
+        readings_tx.send(health.clone()).unwrap();
+        wait_for(&status, &health).await;
+
+        cancel.cancel();
+        sampler.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_sample_clears_readings() {
+        let (readings_tx, readings_rx) = std_mpsc::channel::<SystemHealth>();
+        let status: Arc<StatusCollector> = collector();
+        status.set_system_health(SystemHealth {
+            audio_available: Some(true),
+            ..SystemHealth::default()
+        });
+
+        // The sender is gone, so the probe panics
+        drop(readings_tx);
+        let cancel: CancellationToken = CancellationToken::new();
+        let sampler = tokio::spawn(run_sampler(
+            Arc::new(GatedProbe {
+                readings: Mutex::new(readings_rx),
+            }),
+            status.clone(),
+            SharedSettings::default(),
+            cancel.clone(),
+        ));
+        wait_for(&status, &SystemHealth::default()).await;
+
+        cancel.cancel();
+        sampler.await.unwrap();
+    }
+}
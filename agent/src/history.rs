@@ -0,0 +1,531 @@
+use crate::decision::DeliveryDecision;
+use crate::error::{EmnsError, Result};
+use crate::messages::{Alert, AlertLevel, AlertOrigin, CallbackState, DeliveryOutcome};
+use crate::sanitize::SanitizeReport;
+use crate::storage::{self, LineProtector};
+use crate::timing::DeliveryTrace;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Number of entries kept in memory
+const DEFAULT_CAPACITY: usize = 500;
+
+/// File name of the persisted history inside the data directory
+pub const HISTORY_FILE: &str = "history.jsonl";
+
+/// Record of an alert the agent has processed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub alert_id: uuid::Uuid,
+    pub level: AlertLevel,
+    pub title: String,
+    #[serde(default)]
+    pub message: String,
+    /// When the alert was raised, as stamped by its sender
+    #[serde(default = "chrono::Utc::now")]
+    pub sent_at: chrono::DateTime<chrono::Utc>,
+    pub received_at: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    pub requires_confirmation: bool,
+    #[serde(default)]
+    pub origin: AlertOrigin,
+    /// Title length before sanitization
+    pub original_title_len: usize,
+    /// Message length before sanitization
+    pub original_message_len: usize,
+    /// When the alert switched to its escalation sound for going unconfirmed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalated_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Arrived sealed to this agent's key; its text stays on this machine
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sealed: bool,
+    /// How the POST to the alert's confirm callback went
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback: Option<CallbackState>,
+    /// Why the alert was recorded without being shown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub withheld: Option<DeliveryOutcome>,
+    /// When the alert reached each stage on its way to the user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timing: Option<DeliveryTrace>,
+    /// The rules consulted in deciding whether it sounded and was shown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decision: Option<DeliveryDecision>,
+}
+
+impl HistoryEntry {
+    pub fn new(alert: &Alert, report: &SanitizeReport) -> Self {
+        Self {
+            alert_id: alert.id,
+            level: alert.level.clone(),
+            title: alert.title.clone(),
+            message: alert.message.clone(),
+            sent_at: alert.timestamp,
+            received_at: chrono::Utc::now(),
+            requires_confirmation: alert.requires_confirmation,
+            origin: alert.origin,
+            original_title_len: report.original_title_len,
+            original_message_len: report.original_message_len,
+            escalated_at: None,
+            sealed: alert.sealed.is_some(),
+            callback: None,
+            withheld: None,
+            timing: None,
+            decision: None,
+        }
+    }
+}
+
+/// Every entry in a history file in the order written, without opening it for appending.
+///
+/// An alert updated after it was recorded appears once per update. A missing
+/// file has no entries.
+pub fn read_entries(path: &Path, protector: &LineProtector) -> Result<Vec<HistoryEntry>> {
+    read_lines(path, protector).map(|(entries, _)| entries)
+}
+
+/// The entries in a history file, and whether any line was left unprotected
+fn read_lines(path: &Path, protector: &LineProtector) -> Result<(Vec<HistoryEntry>, bool)> {
+    let file: File = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), false)),
+        Err(e) => return Err(EmnsError::storage(Some(path), e)),
+    };
+    let mut entries: Vec<HistoryEntry> = Vec::new();
+    let mut unprotected: bool = false;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line: String = line.map_err(|e| EmnsError::storage(Some(path), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        unprotected |= protector.needs_migration(&line);
+        let parsed: Result<HistoryEntry> = protector
+            .unprotect(&line)
+            .and_then(|line| Ok(serde_json::from_str::<HistoryEntry>(&line)?));
+        match parsed {
+            Ok(entry) => entries.push(entry),
+            Err(e) => log::warn!(
+                "Skipping unreadable history line {} in {}: {}",
+                number + 1,
+                path.display(),
+                e
+            ),
+        }
+    }
+    Ok((entries, unprotected))
+}
+
+/// What [`AlertHistory::compact`] took out of the history
+#[derive(Debug, Default)]
+pub struct Compaction {
+    /// Latest entry of every alert dropped
+    pub removed: Vec<HistoryEntry>,
+    /// Lines dropped because a later line updated the same alert
+    pub superseded_lines: usize,
+}
+
+/// Open a history file for appending, creating it owner-only
+fn open_append(path: &Path) -> Result<File> {
+    storage::open_private_append(path)
+        .map_err(|e| EmnsError::storage(Some(path), format!("Failed to open history file: {}", e)))
+}
+
+/// Bounded history of processed alerts, oldest first.
+///
+/// When opened from a file, every entry is also appended to it as a JSON line
+/// so the history survives restarts, each line protected on Windows.
+pub struct AlertHistory {
+    entries: Mutex<VecDeque<HistoryEntry>>,
+    capacity: usize,
+    file: Mutex<Option<(PathBuf, File)>>,
+    protector: LineProtector,
+}
+
+impl AlertHistory {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            file: Mutex::new(None),
+            protector: LineProtector::default(),
+        }
+    }
+
+    /// Load the most recent entries from a JSON-lines file and append new ones to it
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_protected(path, LineProtector::default())
+    }
+
+    /// As [`open`](Self::open), with each line protected by `protector`.
+    ///
+    /// Plaintext lines left by older agents are read as they are, and the
+    /// file is rewritten protected straight away.
+    pub fn open_protected(path: impl AsRef<Path>, protector: LineProtector) -> Result<Self> {
+        let path: &Path = path.as_ref();
+        let history: AlertHistory = Self {
+            protector,
+            ..Self::new()
+        };
+        let (entries, unprotected) = read_lines(path, &history.protector)?;
+        for entry in entries {
+            history.load(entry);
+        }
+
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).map_err(|e| EmnsError::storage(Some(parent), e))?;
+        }
+        let file: File = open_append(path)?;
+        *history.file.lock().unwrap() = Some((path.to_path_buf(), file));
+
+        if unprotected {
+            history.rewrite(|all| all, true)?;
+            log::info!(
+                "Migrated plaintext history {} to protected storage",
+                path.display()
+            );
+        }
+        Ok(history)
+    }
+
+    /// Add an entry, evicting the oldest one when full
+    pub fn record(&self, entry: HistoryEntry) {
+        self.append(&entry);
+        self.push(entry);
+    }
+
+    /// Add an entry unless the alert is already in the history; returns whether it was added.
+    ///
+    /// The check and the insert happen under one lock, so an alert arriving
+    /// over two channels at once is only recorded for the first.
+    pub fn record_new(&self, entry: HistoryEntry) -> bool {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.iter().any(|e| e.alert_id == entry.alert_id) {
+                return false;
+            }
+            self.push_locked(&mut entries, entry.clone());
+        }
+        self.append(&entry);
+        true
+    }
+
+    fn append(&self, entry: &HistoryEntry) {
+        if let Some((path, file)) = self.file.lock().unwrap().as_mut() {
+            let appended: std::io::Result<()> =
+                self.line(entry).and_then(|line| writeln!(file, "{}", line));
+            if let Err(e) = appended {
+                log::error!("Failed to append to history {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Add a line read back from the file; a later line for the same alert replaces the earlier one
+    fn load(&self, entry: HistoryEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.iter_mut().find(|e| e.alert_id == entry.alert_id) {
+            Some(existing) => *existing = entry,
+            None => self.push_locked(&mut entries, entry),
+        }
+    }
+
+    fn push(&self, entry: HistoryEntry) {
+        self.push_locked(&mut self.entries.lock().unwrap(), entry);
+    }
+
+    fn push_locked(&self, entries: &mut VecDeque<HistoryEntry>, entry: HistoryEntry) {
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Note that an alert escalated
+    pub fn mark_escalated(&self, alert_id: uuid::Uuid, at: chrono::DateTime<chrono::Utc>) {
+        self.update(alert_id, |entry| entry.escalated_at = Some(at));
+    }
+
+    /// Note that the alert was recorded without being shown
+    pub fn mark_withheld(&self, alert_id: uuid::Uuid, outcome: DeliveryOutcome) {
+        self.update(alert_id, |entry| entry.withheld = Some(outcome));
+    }
+
+    /// Note how the handler decided to deliver the alert
+    pub fn mark_decision(&self, alert_id: uuid::Uuid, decision: DeliveryDecision) {
+        self.update(alert_id, |entry| entry.decision = Some(decision));
+    }
+
+    /// Note when the alert reached each stage of its delivery
+    pub fn mark_timing(&self, alert_id: uuid::Uuid, trace: DeliveryTrace) {
+        self.update(alert_id, |entry| entry.timing = Some(trace));
+    }
+
+    /// Note how the alert's confirm callback went
+    pub fn mark_callback(&self, alert_id: uuid::Uuid, state: CallbackState) {
+        self.update(alert_id, |entry| entry.callback = Some(state));
+    }
+
+    /// Change an alert's entry; the updated entry is appended to the file,
+    /// where it supersedes the original when the history is reloaded
+    fn update(&self, alert_id: uuid::Uuid, change: impl FnOnce(&mut HistoryEntry)) {
+        let updated: Option<HistoryEntry> = {
+            let mut entries = self.entries.lock().unwrap();
+            entries
+                .iter_mut()
+                .rev()
+                .find(|e| e.alert_id == alert_id)
+                .map(|entry| {
+                    change(entry);
+                    entry.clone()
+                })
+        };
+        if let Some(entry) = updated {
+            self.append(&entry);
+        }
+    }
+
+    /// Rewrite the history with one line per alert, keeping only the entries `retain` returns.
+    ///
+    /// `retain` is given the latest entry of every alert in the file (or in
+    /// memory, without one), oldest first. Entries recorded meanwhile wait for
+    /// the rewrite and are appended to the new file.
+    pub fn compact(
+        &self,
+        retain: impl FnOnce(Vec<HistoryEntry>) -> Vec<HistoryEntry>,
+    ) -> Result<Compaction> {
+        self.rewrite(retain, false)
+    }
+
+    /// [`compact`](Self::compact), rewriting the file even when nothing is
+    /// dropped if `always` is set
+    fn rewrite(
+        &self,
+        retain: impl FnOnce(Vec<HistoryEntry>) -> Vec<HistoryEntry>,
+        always: bool,
+    ) -> Result<Compaction> {
+        let mut file = self.file.lock().unwrap();
+        let lines: Vec<HistoryEntry> = match file.as_ref() {
+            Some((path, _)) => read_entries(path, &self.protector)?,
+            None => self.entries.lock().unwrap().iter().cloned().collect(),
+        };
+        let line_count: usize = lines.len();
+
+        // A later line for an alert replaces the earlier one in place, as on reload
+        let mut latest: Vec<HistoryEntry> = Vec::with_capacity(line_count);
+        for entry in lines {
+            match latest.iter_mut().find(|e| e.alert_id == entry.alert_id) {
+                Some(existing) => *existing = entry,
+                None => latest.push(entry),
+            }
+        }
+        let superseded_lines: usize = line_count - latest.len();
+        let all: Vec<HistoryEntry> = latest.clone();
+        let kept: Vec<HistoryEntry> = retain(latest);
+        let kept_ids: HashSet<uuid::Uuid> = kept.iter().map(|e| e.alert_id).collect();
+        let removed: Vec<HistoryEntry> = all
+            .into_iter()
+            .filter(|e| !kept_ids.contains(&e.alert_id))
+            .collect();
+        if removed.is_empty() && superseded_lines == 0 && !always {
+            return Ok(Compaction::default());
+        }
+
+        if let Some((path, handle)) = file.take() {
+            // Windows will not replace a file that is still open
+            drop(handle);
+            let rewritten: PathBuf = path.with_extension("jsonl.tmp");
+            let written: std::io::Result<()> = (|| {
+                let mut out: File = storage::create_private_file(&rewritten)?;
+                for entry in &kept {
+                    writeln!(out, "{}", self.line(entry)?)?;
+                }
+                out.sync_all()?;
+                std::fs::rename(&rewritten, &path)
+            })();
+            // Appending resumes on whichever file is now in place
+            match open_append(&path) {
+                Ok(handle) => *file = Some((path.clone(), handle)),
+                Err(e) => log::error!("History is no longer written to disk: {}", e),
+            }
+            if let Err(e) = written {
+                let _ = std::fs::remove_file(&rewritten);
+                return Err(EmnsError::storage(Some(&path), e));
+            }
+        }
+
+        let dropped: HashSet<uuid::Uuid> = removed.iter().map(|e| e.alert_id).collect();
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|e| !dropped.contains(&e.alert_id));
+        Ok(Compaction {
+            removed,
+            superseded_lines,
+        })
+    }
+
+    /// The line written to the file for `entry`
+    fn line(&self, entry: &HistoryEntry) -> std::io::Result<String> {
+        let json: String = serde_json::to_string(entry).map_err(std::io::Error::other)?;
+        self.protector.protect(&json).map_err(std::io::Error::other)
+    }
+
+    /// Alerts currently in the in-memory history
+    pub fn alert_ids(&self) -> HashSet<uuid::Uuid> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| e.alert_id)
+            .collect()
+    }
+
+    /// Entries received at or after `since`, newest first
+    pub fn received_since(&self, since: chrono::DateTime<chrono::Utc>) -> Vec<HistoryEntry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|e| e.received_at >= since)
+            .cloned()
+            .collect()
+    }
+
+    /// Look up the most recent entry for an alert
+    pub fn get(&self, alert_id: uuid::Uuid) -> Option<HistoryEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .rev()
+            .find(|e| e.alert_id == alert_id)
+            .cloned()
+    }
+}
+
+impl Default for AlertHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{alert, XorProtector};
+    use std::sync::Arc;
+
+    fn report() -> SanitizeReport {
+        SanitizeReport {
+            original_title_len: 10,
+            original_message_len: 12,
+            title_truncated: false,
+            message_truncated: false,
+        }
+    }
+
+    #[test]
+    fn test_entries_survive_reopen() {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("emns-history-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path: PathBuf = dir.join(HISTORY_FILE);
+
+        let recorded: Alert = alert(AlertLevel::Critical, true);
+        {
+            let history: AlertHistory = AlertHistory::open(&path).unwrap();
+            history.record(HistoryEntry::new(&recorded, &report()));
+        }
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{not json\n")
+            .unwrap();
+
+        let reopened: AlertHistory = AlertHistory::open(&path).unwrap();
+        let entry: HistoryEntry = reopened.get(recorded.id).expect("entry reloaded");
+        assert_eq!(entry.message, recorded.message);
+        assert_eq!(entry.sent_at, recorded.timestamp);
+        assert!(entry.requires_confirmation);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_protected_history_migrates_plaintext_and_survives_reopen() {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("emns-history-{}", uuid::Uuid::new_v4()));
+        let path: PathBuf = dir.join(HISTORY_FILE);
+        let protector: LineProtector = LineProtector::new(Arc::new(XorProtector));
+
+        let legacy: Alert = alert(AlertLevel::Critical, true);
+        {
+            let history: AlertHistory = AlertHistory::open(&path).unwrap();
+            history.record(HistoryEntry::new(&legacy, &report()));
+        }
+        let recorded: Alert = alert(AlertLevel::Info, false);
+        {
+            let history: AlertHistory =
+                AlertHistory::open_protected(&path, protector.clone()).unwrap();
+            history.record(HistoryEntry::new(&recorded, &report()));
+        }
+
+        let on_disk: String = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(on_disk.lines().count(), 2);
+        assert!(!on_disk.contains(&legacy.message) && !on_disk.contains('{'));
+        let reopened: AlertHistory = AlertHistory::open_protected(&path, protector).unwrap();
+        assert!(reopened.get(legacy.id).is_some());
+        assert!(reopened.get(recorded.id).is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_record_new_skips_seen_alerts() {
+        let history: AlertHistory = AlertHistory::with_capacity(2);
+        let first: Alert = alert(AlertLevel::Warning, false);
+        assert!(history.record_new(HistoryEntry::new(&first, &report())));
+        assert!(!history.record_new(HistoryEntry::new(&first, &report())));
+
+        // Once evicted the alert counts as new again
+        history.record(HistoryEntry::new(
+            &alert(AlertLevel::Info, false),
+            &report(),
+        ));
+        history.record(HistoryEntry::new(
+            &alert(AlertLevel::Info, false),
+            &report(),
+        ));
+        assert!(history.record_new(HistoryEntry::new(&first, &report())));
+    }
+
+    #[test]
+    fn test_escalation_survives_reopen() {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("emns-history-{}", uuid::Uuid::new_v4()));
+        let path: PathBuf = dir.join(HISTORY_FILE);
+
+        let escalated: Alert = alert(AlertLevel::Critical, true);
+        let at: chrono::DateTime<chrono::Utc> = chrono::Utc::now();
+        {
+            let history: AlertHistory = AlertHistory::open(&path).unwrap();
+            history.record(HistoryEntry::new(&escalated, &report()));
+            history.mark_escalated(escalated.id, at);
+            assert_eq!(history.get(escalated.id).unwrap().escalated_at, Some(at));
+        }
+
+        let reopened: AlertHistory = AlertHistory::open(&path).unwrap();
+        assert_eq!(reopened.get(escalated.id).unwrap().escalated_at, Some(at));
+        assert_eq!(reopened.entries.lock().unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,403 @@
+//! Localhost HTTP listener for status queries and locally raised alerts
+
+use crate::board::{AlertBoard, BOARD_REFRESH_INTERVAL, DEFAULT_RECENT_WINDOW};
+use crate::bulk::BulkFilter;
+use crate::error::{EmnsError, Result};
+use crate::handler::AlertHandler;
+use crate::messages::{Alert, AlertOrigin, ConfirmationMethod};
+use crate::outbound::{OutboundMessage, OutboundQueue};
+use crate::queue::{AlertQueue, EnqueueOutcome};
+use crate::status::StatusCollector;
+use crate::timing::DeliveryTrace;
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+/// Header carrying the shared secret for `POST /local/alerts` and `/pending/*`
+pub const TOKEN_HEADER: &str = "x-emns-token";
+
+/// Header the agent's own `confirm-all` and `dismiss-all` commands send as
+/// `cli`, so their confirmations are reported as given on the command line
+pub const CALLER_HEADER: &str = "x-emns-caller";
+
+/// Default limit on request bodies
+pub const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Settings for the local HTTP listener
+#[derive(Debug, Clone)]
+pub struct HttpApiConfig {
+    /// Loopback address to listen on
+    pub listen: SocketAddr,
+    /// Shared token required to post local alerts and resolve pending ones; those
+    /// endpoints are disabled without one
+    pub local_alert_token: Option<String>,
+    /// Send a copy of each local alert to the server
+    pub forward_local_alerts: bool,
+    pub max_body_bytes: usize,
+    /// How long alerts that need no confirmation stay on the alert board
+    pub board_recent: Duration,
+    /// Web page origins allowed to read the alert board, e.g. a kiosk page's
+    pub board_origins: Vec<String>,
+}
+
+impl HttpApiConfig {
+    pub fn new(listen: SocketAddr) -> Self {
+        Self {
+            listen,
+            local_alert_token: None,
+            forward_local_alerts: true,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            board_recent: DEFAULT_RECENT_WINDOW,
+            board_origins: Vec::new(),
+        }
+    }
+}
+
+/// Components the HTTP handlers read from and feed into
+#[derive(Clone)]
+pub struct HttpApiState {
+    pub(crate) client_id: String,
+    pub(crate) alert_queue: Arc<AlertQueue>,
+    pub(crate) outbound: Arc<OutboundQueue>,
+    pub(crate) status: Arc<StatusCollector>,
+    pub(crate) local_alert_token: Option<Arc<str>>,
+    pub(crate) forward_local_alerts: bool,
+    /// Source of the alert board; the board is not served without one
+    pub(crate) handler: Option<Arc<AlertHandler>>,
+    pub(crate) board_recent: Duration,
+    pub(crate) board_origins: Arc<[String]>,
+}
+
+/// A bound listener ready to serve requests
+pub struct HttpApi {
+    listener: std::net::TcpListener,
+    router: Router,
+}
+
+impl HttpApi {
+    /// Bind the listener; only loopback addresses are accepted
+    pub fn bind(config: &HttpApiConfig, state: HttpApiState) -> Result<Self> {
+        if !config.listen.ip().is_loopback() {
+            return Err(EmnsError::config(
+                "HTTP_LISTEN",
+                format!("{} is not a loopback address", config.listen),
+            ));
+        }
+        let listener: std::net::TcpListener = std::net::TcpListener::bind(config.listen)
+            .and_then(|l| l.set_nonblocking(true).map(|_| l))
+            .map_err(|e| {
+                EmnsError::config(
+                    "HTTP_LISTEN",
+                    format!("failed to bind {}: {}", config.listen, e),
+                )
+            })?;
+
+        let local_alerts: Router<HttpApiState> = Router::new()
+            .route("/local/alerts", post(post_local_alert))
+            .route("/pending/confirm", post(post_confirm_all))
+            .route("/pending/dismiss", post(post_dismiss_all))
+            .route_layer(middleware::from_fn_with_state(state.clone(), require_token));
+        let board: Router<HttpApiState> = Router::new()
+            .route("/status/alerts", get(get_board))
+            .route("/status/alerts/stream", get(stream_board))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                allow_board_origin,
+            ));
+        let router: Router = Router::new()
+            .route("/status", get(get_status))
+            .merge(board)
+            .merge(local_alerts)
+            .layer(middleware::from_fn(require_loopback))
+            .layer(DefaultBodyLimit::max(config.max_body_bytes))
+            .with_state(state);
+
+        Ok(Self { listener, router })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener
+            .local_addr()
+            .map_err(|e| EmnsError::config("HTTP_LISTEN", e))
+    }
+
+    /// Serve requests until `cancel` fires
+    pub async fn serve(self, cancel: CancellationToken) -> Result<()> {
+        let addr: SocketAddr = self.local_addr()?;
+        let listener: tokio::net::TcpListener = tokio::net::TcpListener::from_std(self.listener)
+            .map_err(|e| EmnsError::config("HTTP_LISTEN", e))?;
+        log::info!("Local HTTP API listening on {}", addr);
+
+        // Board streams end when `cancel` fires, so shutdown does not wait on them
+        axum::serve(
+            listener,
+            self.router
+                .layer(Extension(cancel.clone()))
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(cancel.cancelled_owned())
+        .await
+        .map_err(|e| EmnsError::config("HTTP_LISTEN", e))
+    }
+}
+
+/// Refuse connections that did not come from this machine
+async fn require_loopback(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !peer.ip().is_loopback() {
+        log::warn!("Rejected HTTP request from non-local peer {}", peer);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+}
+
+/// Check the shared token before the body is read
+async fn require_token(
+    State(state): State<HttpApiState>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(expected) = state.local_alert_token.as_deref() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let presented: &[u8] = headers
+        .get(TOKEN_HEADER)
+        .map(|v| v.as_bytes())
+        .unwrap_or_default();
+    if !constant_time_eq(presented, expected.as_bytes()) {
+        log::warn!(
+            "Rejected {} with missing or invalid token",
+            request.uri().path()
+        );
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
+/// Let pages from the configured origins read the alert board
+async fn allow_board_origin(
+    State(state): State<HttpApiState>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Response {
+    let origin: Option<HeaderValue> = headers
+        .get(header::ORIGIN)
+        .filter(|origin| {
+            state
+                .board_origins
+                .iter()
+                .any(|allowed| origin.as_bytes() == allowed.as_bytes())
+        })
+        .cloned();
+    let mut response: Response = next.run(request).await;
+    if let Some(origin) = origin {
+        response
+            .headers_mut()
+            .insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    }
+    response
+        .headers_mut()
+        .insert(header::VARY, HeaderValue::from_static("origin"));
+    response
+}
+
+async fn get_status(State(state): State<HttpApiState>) -> impl IntoResponse {
+    Json(state.status.collect())
+}
+
+async fn get_board(State(state): State<HttpApiState>) -> Response {
+    match &state.handler {
+        Some(handler) => Json(handler.board(state.board_recent).await).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// The alert board as server-sent `board` events: one at once, then another
+/// whenever its contents change
+async fn stream_board(
+    State(state): State<HttpApiState>,
+    Extension(cancel): Extension<CancellationToken>,
+) -> Response {
+    let Some(handler) = state.handler.clone() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let follower: BoardFollower = BoardFollower {
+        changes: handler.board_changes(),
+        handler,
+        recent: state.board_recent,
+        last: None,
+        cancel,
+    };
+    let events = futures_util::stream::unfold(follower, |mut follower| async move {
+        let board: AlertBoard = follower.next().await?;
+        let event = Event::default().event("board").json_data(&board);
+        follower.last = Some(board);
+        Some((event, follower))
+    });
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Rebuilds the board for one stream as it changes
+struct BoardFollower {
+    handler: Arc<AlertHandler>,
+    changes: watch::Receiver<u64>,
+    recent: Duration,
+    /// The board last sent
+    last: Option<AlertBoard>,
+    cancel: CancellationToken,
+}
+
+impl BoardFollower {
+    /// The next board to send; `None` once the stream should end.
+    ///
+    /// Rebuilt on every change and every [`BOARD_REFRESH_INTERVAL`], so recent
+    /// alerts age out, but only returned when its contents differ.
+    async fn next(&mut self) -> Option<AlertBoard> {
+        loop {
+            if self.last.is_some() {
+                tokio::select! {
+                    _ = self.cancel.cancelled() => return None,
+                    changed = self.changes.changed() => changed.ok()?,
+                    _ = tokio::time::sleep(BOARD_REFRESH_INTERVAL) => {}
+                }
+            }
+            let board: AlertBoard = self.handler.board(self.recent).await;
+            match &self.last {
+                Some(last) if last.same_contents(&board) => continue,
+                _ => return Some(board),
+            }
+        }
+    }
+}
+
+async fn post_local_alert(
+    State(state): State<HttpApiState>,
+    Json(mut alert): Json<Alert>,
+) -> Response {
+    alert.origin = AlertOrigin::Local;
+    let alert_id: uuid::Uuid = alert.id;
+    log::info!(
+        "Received local alert: {} ({})",
+        alert_id,
+        alert.level.as_str()
+    );
+
+    if state.forward_local_alerts {
+        state.outbound.push(OutboundMessage::LocalAlert {
+            client_id: state.client_id.clone(),
+            alert: Box::new(alert.clone()),
+        });
+    }
+
+    let trace: DeliveryTrace = DeliveryTrace {
+        enqueued: Some(chrono::Utc::now()),
+        ..DeliveryTrace::default()
+    };
+    match state.alert_queue.enqueue(alert, trace).await {
+        EnqueueOutcome::Shed(event) if event.alert_id == alert_id => {
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+        _ => (
+            StatusCode::ACCEPTED,
+            Json(serde_json::json!({ "id": alert_id })),
+        )
+            .into_response(),
+    }
+}
+
+/// How a bulk confirmation reached the API, by its [`CALLER_HEADER`]
+fn confirmation_method(headers: &HeaderMap) -> ConfirmationMethod {
+    match headers.get(CALLER_HEADER).map(HeaderValue::as_bytes) {
+        Some(b"cli") => ConfirmationMethod::Cli,
+        _ => ConfirmationMethod::Api,
+    }
+}
+
+/// Confirm the pending alerts the filter in the body selects
+async fn post_confirm_all(
+    State(state): State<HttpApiState>,
+    headers: HeaderMap,
+    Json(filter): Json<BulkFilter>,
+) -> Response {
+    let method: ConfirmationMethod = confirmation_method(&headers);
+    match &state.handler {
+        Some(handler) => Json(handler.confirm_all(&filter, method).await).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Dismiss the pending alerts the filter in the body selects
+async fn post_dismiss_all(
+    State(state): State<HttpApiState>,
+    headers: HeaderMap,
+    Json(filter): Json<BulkFilter>,
+) -> Response {
+    let method: ConfirmationMethod = confirmation_method(&headers);
+    match &state.handler {
+        Some(handler) => Json(handler.dismiss_all(&filter, method).await).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Compare secrets without leaking where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_non_loopback_listen_address() {
+        let config: HttpApiConfig = HttpApiConfig::new("0.0.0.0:0".parse().unwrap());
+        let alert_queue: Arc<AlertQueue> = Arc::new(AlertQueue::new(1));
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let state: HttpApiState = HttpApiState {
+            client_id: "test-client".to_string(),
+            status: Arc::new(StatusCollector::new(
+                "test-client",
+                alert_queue.clone(),
+                outbound.clone(),
+            )),
+            alert_queue,
+            outbound,
+            local_alert_token: None,
+            forward_local_alerts: false,
+            handler: None,
+            board_recent: DEFAULT_RECENT_WINDOW,
+            board_origins: Arc::from([]),
+        };
+
+        match HttpApi::bind(&config, state).err().unwrap() {
+            EmnsError::Config { key, .. } => assert_eq!(key, "HTTP_LISTEN"),
+            other => panic!("expected config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+}
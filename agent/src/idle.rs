@@ -0,0 +1,55 @@
+//! How long the user has been away from the keyboard and mouse
+
+use std::time::Duration;
+
+/// How often an idle machine is re-checked once its auto-confirm deadline has passed
+pub const IDLE_RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Source of the time since the last user input
+pub trait IdleProbe: Send + Sync {
+    /// Time since the last keyboard or mouse input, or `None` if unknown
+    fn idle_time(&self) -> Option<Duration>;
+}
+
+/// Uses `GetLastInputInfo`.
+///
+/// Input is tracked per session, so the service's own probe only sees input
+/// on the console session; session helpers see their user's input.
+#[derive(Debug, Default)]
+pub struct SystemIdle;
+
+impl SystemIdle {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl IdleProbe for SystemIdle {
+    fn idle_time(&self) -> Option<Duration> {
+        use windows::Win32::System::SystemInformation::GetTickCount;
+        use windows::Win32::UI::Input::KeyboardAndMouse::{GetLastInputInfo, LASTINPUTINFO};
+
+        let mut info: LASTINPUTINFO = LASTINPUTINFO {
+            cbSize: std::mem::size_of::<LASTINPUTINFO>() as u32,
+            dwTime: 0,
+        };
+        unsafe {
+            if !GetLastInputInfo(&mut info).as_bool() {
+                log::warn!("Failed to query last input time");
+                return None;
+            }
+            // Both are 32-bit tick counts; wrapping keeps this right across the 49-day rollover
+            let idle_ms: u32 = GetTickCount().wrapping_sub(info.dwTime);
+            Some(Duration::from_millis(idle_ms as u64))
+        }
+    }
+}
+
+/// Input tracking is only available on Windows
+#[cfg(not(target_os = "windows"))]
+impl IdleProbe for SystemIdle {
+    fn idle_time(&self) -> Option<Duration> {
+        None
+    }
+}
@@ -0,0 +1,412 @@
+//! Images shown in alert toasts, e.g. a radar snapshot: bounded download,
+//! a cache the toast can point at, and eviction by age and size
+
+use crate::error::{EmnsError, Result};
+use crate::messages::is_http_url;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// Directory under the data dir that holds one image per alert
+pub const IMAGES_DIR: &str = "images";
+
+/// Largest image downloaded by default; Windows drops bigger toast images
+/// on metered connections
+pub const DEFAULT_MAX_IMAGE_BYTES: u64 = 1024 * 1024;
+
+/// Default limit on one whole download
+pub const DEFAULT_IMAGE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Default time a toast waits for its image before being shown without it
+pub const DEFAULT_IMAGE_WAIT: Duration = Duration::from_secs(2);
+
+/// Default age after which cached images are removed
+pub const DEFAULT_IMAGE_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Default size the cache is trimmed to, oldest images first
+pub const DEFAULT_IMAGE_CACHE_BYTES: u64 = 50 * 1024 * 1024;
+
+/// How often old images are swept
+pub const IMAGE_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Download limits and cache eviction for toast images
+#[derive(Debug, Clone)]
+pub struct ImageConfig {
+    pub max_bytes: u64,
+    pub timeout: Duration,
+    /// How long a toast is held back for its image
+    pub wait: Duration,
+    pub retention: Duration,
+    pub max_cache_bytes: u64,
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: DEFAULT_MAX_IMAGE_BYTES,
+            timeout: DEFAULT_IMAGE_TIMEOUT,
+            wait: DEFAULT_IMAGE_WAIT,
+            retention: DEFAULT_IMAGE_RETENTION,
+            max_cache_bytes: DEFAULT_IMAGE_CACHE_BYTES,
+        }
+    }
+}
+
+/// Downloads alerts' images into `<data dir>/images/<alert id>.<ext>` and
+/// remembers which ones arrived
+pub struct ImageCache {
+    root: PathBuf,
+    config: ImageConfig,
+    client: reqwest::Client,
+    cached: Mutex<HashMap<Uuid, PathBuf>>,
+}
+
+impl ImageCache {
+    pub fn new(data_dir: impl AsRef<Path>, config: &ImageConfig) -> Self {
+        Self {
+            root: data_dir.as_ref().join(IMAGES_DIR),
+            config: config.clone(),
+            client: reqwest::Client::new(),
+            cached: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long a toast is held back for its image
+    pub fn wait(&self) -> Duration {
+        self.config.wait
+    }
+
+    /// Download the alert's image, accepting only a PNG, JPEG or GIF within
+    /// the size limit, then trim the cache to its size limit.
+    ///
+    /// The file only gets its real name once complete, so a toast never
+    /// points at half an image.
+    pub async fn fetch(&self, alert_id: Uuid, url: &str) -> Result<PathBuf> {
+        if !is_http_url(url) {
+            return Err(EmnsError::protocol(format!(
+                "image URL {} is not http(s)",
+                url
+            )));
+        }
+        let body: Vec<u8> = tokio::time::timeout(self.config.timeout, self.download(url))
+            .await
+            .map_err(|_| {
+                EmnsError::connection(
+                    url,
+                    format!("download timed out after {:?}", self.config.timeout),
+                )
+            })??;
+        let extension: &str = image_extension(&body).ok_or_else(|| {
+            EmnsError::protocol(format!("image {} is not a PNG, JPEG or GIF", url))
+        })?;
+
+        let path: PathBuf = self.root.join(format!("{}.{}", alert_id, extension));
+        let partial: PathBuf = path.with_extension("part");
+        tokio::fs::create_dir_all(&self.root)
+            .await
+            .map_err(|e| EmnsError::storage(Some(&self.root), e))?;
+        tokio::fs::write(&partial, &body)
+            .await
+            .map_err(|e| EmnsError::storage(Some(&partial), e))?;
+        tokio::fs::rename(&partial, &path)
+            .await
+            .map_err(|e| EmnsError::storage(Some(&path), e))?;
+        self.cached.lock().unwrap().insert(alert_id, path.clone());
+        log::info!("Image for alert {} saved to {}", alert_id, path.display());
+
+        if let Err(e) = self.sweep(SystemTime::now()) {
+            log::warn!("Image cache sweep failed: {}", e);
+        }
+        Ok(path)
+    }
+
+    /// Read the response body, refusing anything not served as an image or
+    /// running past the size limit
+    async fn download(&self, url: &str) -> Result<Vec<u8>> {
+        let max_bytes: u64 = self.config.max_bytes;
+        let mut response: reqwest::Response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| EmnsError::connection(url, e))?;
+
+        if let Some(content_type) = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+        {
+            if !content_type
+                .trim()
+                .to_ascii_lowercase()
+                .starts_with("image/")
+            {
+                return Err(EmnsError::protocol(format!(
+                    "image {} is served as {}",
+                    url, content_type
+                )));
+            }
+        }
+        let too_large = || {
+            EmnsError::protocol(format!(
+                "image {} is over the {} byte limit",
+                url, max_bytes
+            ))
+        };
+        if response.content_length().is_some_and(|len| len > max_bytes) {
+            return Err(too_large());
+        }
+
+        let mut body: Vec<u8> = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| EmnsError::connection(url, e))?
+        {
+            if (body.len() + chunk.len()) as u64 > max_bytes {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    /// The alert's image, if it downloaded and is still cached
+    pub fn cached(&self, alert_id: Uuid) -> Option<PathBuf> {
+        self.cached
+            .lock()
+            .unwrap()
+            .get(&alert_id)
+            .filter(|path| path.is_file())
+            .cloned()
+    }
+
+    /// Remove images last modified more than the retention before `now`, then
+    /// the oldest of the rest until the cache fits its size limit.
+    ///
+    /// Returns how many files were removed.
+    pub fn sweep(&self, now: SystemTime) -> Result<usize> {
+        let entries = match std::fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(EmnsError::storage(Some(&self.root), e)),
+        };
+        let mut files: Vec<(PathBuf, SystemTime, u64)> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let metadata: std::fs::Metadata = entry.metadata().ok()?;
+                let modified: SystemTime = metadata.modified().ok()?;
+                metadata
+                    .is_file()
+                    .then(|| (entry.path(), modified, metadata.len()))
+            })
+            .collect();
+        // Newest first, so whatever is over the limit comes last
+        files.sort_by_key(|(_, modified, _)| std::cmp::Reverse(*modified));
+
+        let mut kept_bytes: u64 = 0;
+        let mut removed: usize = 0;
+        for (path, modified, len) in files {
+            let expired: bool = now
+                .duration_since(modified)
+                .is_ok_and(|age| age > self.config.retention);
+            if !expired && kept_bytes + len <= self.config.max_cache_bytes {
+                kept_bytes += len;
+                continue;
+            }
+            match std::fs::remove_file(&path) {
+                Ok(()) => removed += 1,
+                Err(e) => log::warn!("Failed to remove {}: {}", path.display(), e),
+            }
+        }
+        if removed > 0 {
+            self.cached.lock().unwrap().retain(|_, path| path.is_file());
+            log::info!("Removed {} cached image(s)", removed);
+        }
+        Ok(removed)
+    }
+}
+
+/// Sweep the image cache now and every [`IMAGE_SWEEP_INTERVAL`] until `cancel` fires
+pub async fn run_sweeper(cache: Arc<ImageCache>, cancel: CancellationToken) {
+    loop {
+        let sweeping = tokio::task::spawn_blocking({
+            let cache: Arc<ImageCache> = cache.clone();
+            move || cache.sweep(SystemTime::now())
+        });
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            swept = sweeping => match swept {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => log::warn!("Image cache sweep failed: {}", e),
+                Err(e) => log::warn!("Image cache sweep panicked: {}", e),
+            },
+        }
+
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = tokio::time::sleep(IMAGE_SWEEP_INTERVAL) => {}
+        }
+    }
+    log::debug!("Image cache sweeper stopped");
+}
+
+/// File extension for the image formats toasts show, going by the content
+/// rather than what the server says it is
+fn image_extension(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("jpg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("gif")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header::CONTENT_TYPE;
+    use axum::routing::get;
+    use std::net::SocketAddr;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR radar";
+
+    /// Serve `PNG` at `/radar.png`, a page at `/page.png`, 2 KiB at
+    /// `/large.png` and an image that never arrives at `/slow.png`
+    async fn fixture() -> SocketAddr {
+        let app = axum::Router::new()
+            .route(
+                "/radar.png",
+                get(|| async { ([(CONTENT_TYPE, "image/png")], PNG) }),
+            )
+            .route(
+                "/page.png",
+                get(|| async { ([(CONTENT_TYPE, "text/html")], "<html></html>") }),
+            )
+            .route(
+                "/large.png",
+                get(|| async { ([(CONTENT_TYPE, "image/png")], vec![0x89; 2048]) }),
+            )
+            .route(
+                "/slow.png",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(30)).await;
+                    PNG
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    fn cache(dir: &Path) -> ImageCache {
+        ImageCache::new(
+            dir,
+            &ImageConfig {
+                max_bytes: 1024,
+                timeout: Duration::from_millis(500),
+                ..ImageConfig::default()
+            },
+        )
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir: PathBuf = std::env::temp_dir().join(format!("emns-{}-{}", name, Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_image_is_cached_under_its_alert() {
+        let addr: SocketAddr = fixture().await;
+        let dir: PathBuf = temp_dir("image-ok");
+        let cache: ImageCache = cache(&dir);
+        let alert_id: Uuid = Uuid::new_v4();
+
+        let path: PathBuf = cache
+            .fetch(alert_id, &format!("http://{}/radar.png", addr))
+            .await
+            .unwrap();
+        assert_eq!(path, dir.join(IMAGES_DIR).join(format!("{}.png", alert_id)));
+        assert_eq!(std::fs::read(&path).unwrap(), PNG);
+        assert_eq!(cache.cached(alert_id), Some(path));
+        assert_eq!(cache.cached(Uuid::new_v4()), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_non_images_oversize_and_slow_downloads_are_refused() {
+        let addr: SocketAddr = fixture().await;
+        let dir: PathBuf = temp_dir("image-refused");
+        let cache: ImageCache = cache(&dir);
+
+        let cases: [(&str, &str); 4] = [
+            ("/page.png", "served as text/html"),
+            ("/large.png", "over the 1024 byte limit"),
+            ("/slow.png", "timed out"),
+            ("file:///C:/radar.png", "not http(s)"),
+        ];
+        for (path, expected) in cases {
+            let url: String = match path.strip_prefix('/') {
+                Some(_) => format!("http://{}{}", addr, path),
+                None => path.to_string(),
+            };
+            let alert_id: Uuid = Uuid::new_v4();
+            let err: EmnsError = cache.fetch(alert_id, &url).await.unwrap_err();
+            assert!(err.to_string().contains(expected), "{}: {}", path, err);
+            assert_eq!(cache.cached(alert_id), None);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sweep_evicts_expired_then_oldest_over_the_size_limit() {
+        let dir: PathBuf = temp_dir("image-sweep");
+        let cache: ImageCache = ImageCache::new(
+            &dir,
+            &ImageConfig {
+                retention: Duration::from_secs(24 * 60 * 60),
+                max_cache_bytes: 250,
+                ..ImageConfig::default()
+            },
+        );
+        let root: PathBuf = dir.join(IMAGES_DIR);
+        std::fs::create_dir_all(&root).unwrap();
+        let now: SystemTime = SystemTime::now();
+        let write = |name: &str, len: usize, age_hours: u64| {
+            let path: PathBuf = root.join(name);
+            std::fs::write(&path, vec![0; len]).unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(now - Duration::from_secs(age_hours * 60 * 60))
+                .unwrap();
+            path
+        };
+        let expired: PathBuf = write("expired.png", 10, 48);
+        let oldest: PathBuf = write("oldest.png", 100, 3);
+        let older: PathBuf = write("older.png", 100, 2);
+        let newest: PathBuf = write("newest.png", 100, 1);
+
+        assert_eq!(cache.sweep(now).unwrap(), 2);
+        assert!(!expired.exists());
+        assert!(!oldest.exists());
+        assert!(older.exists());
+        assert!(newest.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_image_type_is_read_from_the_content() {
+        assert_eq!(image_extension(PNG), Some("png"));
+        assert_eq!(image_extension(&[0xff, 0xd8, 0xff, 0xe0]), Some("jpg"));
+        assert_eq!(image_extension(b"GIF89a..."), Some("gif"));
+        assert_eq!(image_extension(b"<svg/>"), None);
+    }
+}
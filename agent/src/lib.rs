@@ -0,0 +1,94 @@
+//! Emergency Management Notification System agent.
+//!
+//! The library exposes the pieces the `emns-agent` binary wires together so the
+//! server, test harnesses, and integrators can reuse them.
+
+pub mod agent;
+pub mod annunciator;
+pub mod attachments;
+pub mod attention;
+pub mod audio;
+pub mod backoff;
+pub mod board;
+pub mod broker;
+pub mod bulk;
+pub mod burst;
+pub mod callback;
+pub mod capabilities;
+pub mod capture;
+pub mod category;
+pub mod client;
+pub mod clock;
+pub mod compression;
+pub mod config;
+pub mod countdown;
+pub mod deadline;
+pub mod decision;
+pub mod dedup;
+pub mod details;
+pub mod discovery;
+pub mod error;
+pub mod escalation;
+pub mod eventlog;
+pub mod handler;
+pub mod health;
+pub mod history;
+pub mod http_api;
+pub mod idle;
+pub mod images;
+pub mod locale;
+pub mod lock;
+pub mod maintenance;
+pub mod messages;
+pub mod missed;
+pub mod multicast;
+pub mod notification;
+pub mod offline;
+pub mod operator;
+pub mod outage;
+pub mod outbound;
+pub mod power;
+pub mod queue;
+pub mod rate_limit;
+pub mod reminder;
+pub mod retention;
+pub mod sanitize;
+pub mod sealed;
+pub mod session_helper;
+pub mod settings;
+pub mod shutdown;
+pub mod signing;
+pub mod sink;
+pub mod sound_pack;
+pub mod sounds;
+pub mod startup;
+pub mod status;
+pub mod storage;
+pub mod supersede;
+pub mod suppression;
+pub mod takeover;
+pub mod timing;
+pub mod toast_style;
+pub mod transport;
+pub mod update;
+pub mod watchdog;
+pub mod watermark;
+
+#[cfg(test)]
+pub(crate) mod test_support;
+
+pub use agent::{Agent, AgentBuilder};
+pub use attention::AttentionBackend;
+pub use audio::{AudioBackend, AudioPlayer};
+pub use client::WebSocketClient;
+pub use config::Config;
+pub use error::{EmnsError, Result};
+pub use handler::{AlertHandler, AlertHandlerBuilder};
+pub use health::SystemProbe;
+pub use idle::IdleProbe;
+pub use notification::{NotificationBackend, NotificationManager};
+pub use outbound::{OutboundMessage, OutboundQueue};
+pub use power::PowerBackend;
+pub use queue::AlertQueue;
+pub use settings::{AgentSettings, SharedSettings};
+pub use transport::Transport;
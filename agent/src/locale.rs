@@ -0,0 +1,138 @@
+//! The language this machine shows alerts in, for alerts that carry
+//! translations of their title and message
+
+use crate::messages::{is_language_tag, Alert, Translation};
+
+/// `tag` as a BCP 47 language tag, or `None` if it is not one.
+///
+/// POSIX locale names are accepted too: `fr_CA.UTF-8` reads as `fr-CA`.
+pub fn parse_tag(tag: &str) -> Option<String> {
+    let tag: &str = tag.trim();
+    let tag: &str = tag.split(['.', '@']).next().unwrap_or(tag);
+    let tag: String = tag.replace('_', "-");
+    is_language_tag(&tag).then_some(tag)
+}
+
+/// The primary language of `tag`, e.g. `fr` for `fr-CA`
+fn language(tag: &str) -> &str {
+    tag.split('-').next().unwrap_or(tag)
+}
+
+/// The translation of `alert` to show in `locale`, with the tag it is keyed by.
+///
+/// The tag matching `locale` exactly wins, then one for its language alone,
+/// then any other for the same language, first by tag; tags compare
+/// case-insensitively. `None` means the alert's own title and message, as
+/// does a sealed alert, whose translations could give away what it seals.
+pub fn translation<'a>(alert: &'a Alert, locale: &str) -> Option<(&'a str, &'a Translation)> {
+    if alert.sealed.is_some() || alert.translations.is_empty() {
+        return None;
+    }
+    let mut candidates: Vec<(&'a str, &'a Translation)> = alert
+        .translations
+        .iter()
+        .map(|(tag, translation)| (tag.as_str(), translation))
+        .filter(|(tag, _)| language(tag).eq_ignore_ascii_case(language(locale)))
+        .collect();
+    candidates.sort_by_key(|(tag, _)| {
+        (
+            !tag.eq_ignore_ascii_case(locale),
+            !tag.eq_ignore_ascii_case(language(locale)),
+            tag.to_ascii_lowercase(),
+        )
+    });
+    candidates.into_iter().next()
+}
+
+/// The user's Windows display language, e.g. `fr-CA`
+#[cfg(target_os = "windows")]
+pub fn system_locale() -> Option<String> {
+    use windows::Win32::Globalization::{GetUserDefaultUILanguage, LCIDToLocaleName};
+
+    // LOCALE_NAME_MAX_LENGTH
+    let mut name: [u16; 85] = [0; 85];
+    let len: i32 =
+        unsafe { LCIDToLocaleName(u32::from(GetUserDefaultUILanguage()), Some(&mut name), 0) };
+    // The length counts the terminating null
+    let name: String =
+        String::from_utf16_lossy(&name[..usize::try_from(len).ok()?.checked_sub(1)?]);
+    parse_tag(&name)
+}
+
+/// The locale from `LC_ALL`, `LC_MESSAGES` or `LANG`, as on other Unix tools
+#[cfg(not(target_os = "windows"))]
+pub fn system_locale() -> Option<String> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty())
+        .and_then(|value| parse_tag(&value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{AlertLevel, SealedBody};
+    use crate::test_support::alert;
+
+    fn translated(tags: &[&str]) -> Alert {
+        let mut alert: Alert = alert(AlertLevel::Warning, false);
+        for tag in tags {
+            alert.translations.insert(
+                tag.to_string(),
+                Translation {
+                    title: format!("title {}", tag),
+                    message: format!("message {}", tag),
+                },
+            );
+        }
+        alert
+    }
+
+    fn chosen(alert: &Alert, locale: &str) -> Option<String> {
+        translation(alert, locale).map(|(tag, _)| tag.to_string())
+    }
+
+    #[test]
+    fn test_parse_tag_accepts_bcp47_and_posix_names() {
+        assert_eq!(parse_tag("fr-CA").as_deref(), Some("fr-CA"));
+        assert_eq!(parse_tag(" fr_CA.UTF-8 ").as_deref(), Some("fr-CA"));
+        assert_eq!(parse_tag("de_DE@euro").as_deref(), Some("de-DE"));
+        assert_eq!(parse_tag("zh-Hant-TW").as_deref(), Some("zh-Hant-TW"));
+        assert_eq!(parse_tag("C"), None);
+        assert_eq!(parse_tag(""), None);
+        assert_eq!(parse_tag("fr CA"), None);
+        assert_eq!(parse_tag("en-toolongsubtag"), None);
+    }
+
+    #[test]
+    fn test_exact_tag_beats_language_beats_other_regions() {
+        let alert: Alert = translated(&["fr", "fr-CA", "fr-BE", "es"]);
+        assert_eq!(chosen(&alert, "fr-CA").as_deref(), Some("fr-CA"));
+        assert_eq!(chosen(&alert, "FR-ca").as_deref(), Some("fr-CA"));
+        assert_eq!(chosen(&alert, "fr-FR").as_deref(), Some("fr"));
+        assert_eq!(chosen(&alert, "es-MX").as_deref(), Some("es"));
+
+        // Without one for the language alone, the first by tag of the same language
+        let regional: Alert = translated(&["fr-CA", "fr-BE"]);
+        assert_eq!(chosen(&regional, "fr").as_deref(), Some("fr-BE"));
+        assert_eq!(chosen(&regional, "fr-FR").as_deref(), Some("fr-BE"));
+    }
+
+    #[test]
+    fn test_falls_back_to_the_alert_text() {
+        assert_eq!(chosen(&translated(&["fr", "es"]), "en-US"), None);
+        assert_eq!(chosen(&translated(&[]), "fr"), None);
+        // "f" is not the language of "fr"
+        assert_eq!(chosen(&translated(&["fr"]), "f"), None);
+
+        let mut sealed: Alert = translated(&["fr"]);
+        sealed.sealed = Some(SealedBody {
+            key_id: String::new(),
+            ephemeral_key: String::new(),
+            nonce: String::new(),
+            ciphertext: String::new(),
+        });
+        assert_eq!(chosen(&sealed, "fr"), None);
+    }
+}
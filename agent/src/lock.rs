@@ -0,0 +1,230 @@
+//! Whether the workstation is locked, since toasts do not show on the lock screen
+
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// How often a locked machine is re-checked once an alert's auto-confirm deadline has passed
+pub const LOCKED_RECHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Lock state of the session, with how long it has spent locked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LockState {
+    /// When the current lock began; `None` while unlocked
+    locked_since: Option<Instant>,
+    /// Time spent locked in earlier, finished locks
+    locked_before: Duration,
+}
+
+impl LockState {
+    pub fn is_locked(&self) -> bool {
+        self.locked_since.is_some()
+    }
+
+    /// Total time spent locked up to `now`, including the current lock
+    pub fn locked_time(&self, now: Instant) -> Duration {
+        let current: Duration = self
+            .locked_since
+            .map_or(Duration::ZERO, |since| now.saturating_duration_since(since));
+        self.locked_before + current
+    }
+}
+
+/// Source of lock and unlock events for the agent's session
+pub trait LockMonitor: Send + Sync {
+    /// Current lock state, updated on every lock and unlock
+    fn subscribe(&self) -> watch::Receiver<LockState>;
+}
+
+/// Keeps a [`LockState`] up to date from lock and unlock events
+#[derive(Debug)]
+pub struct LockTracker {
+    state: watch::Sender<LockState>,
+}
+
+impl Default for LockTracker {
+    fn default() -> Self {
+        Self {
+            state: watch::Sender::new(LockState::default()),
+        }
+    }
+}
+
+impl LockTracker {
+    /// Starts unlocked
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a lock or unlock; repeating the current state changes nothing
+    pub fn set_locked(&self, locked: bool) {
+        let now: Instant = Instant::now();
+        self.state
+            .send_if_modified(|state| match (state.locked_since, locked) {
+                (None, true) => {
+                    log::info!("Workstation locked");
+                    state.locked_since = Some(now);
+                    true
+                }
+                (Some(since), false) => {
+                    log::info!("Workstation unlocked");
+                    state.locked_before += now.saturating_duration_since(since);
+                    state.locked_since = None;
+                    true
+                }
+                _ => false,
+            });
+    }
+
+    pub fn state(&self) -> LockState {
+        *self.state.borrow()
+    }
+}
+
+impl LockMonitor for LockTracker {
+    fn subscribe(&self) -> watch::Receiver<LockState> {
+        self.state.subscribe()
+    }
+}
+
+/// Follows `WM_WTSSESSION_CHANGE` lock and unlock notifications on a hidden window.
+///
+/// The window is created on first use and only sees its own session, so a
+/// service in session 0 never sees a lock; session helpers see their user's.
+/// The session is assumed unlocked until the first notification.
+#[derive(Debug, Default)]
+pub struct SystemLock;
+
+impl SystemLock {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl LockMonitor for SystemLock {
+    fn subscribe(&self) -> watch::Receiver<LockState> {
+        win32::tracker().subscribe()
+    }
+}
+
+/// Session notifications are only available on Windows; elsewhere the session is never locked
+#[cfg(not(target_os = "windows"))]
+impl LockMonitor for SystemLock {
+    fn subscribe(&self) -> watch::Receiver<LockState> {
+        LockTracker::new().subscribe()
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod win32 {
+    use super::LockTracker;
+    use std::sync::OnceLock;
+    use windows::core::{w, PCWSTR};
+    use windows::Win32::Foundation::{HINSTANCE, HWND, LPARAM, LRESULT, WPARAM};
+    use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+    use windows::Win32::System::RemoteDesktop::{
+        WTSRegisterSessionNotification, NOTIFY_FOR_THIS_SESSION,
+    };
+    use windows::Win32::UI::WindowsAndMessaging::*;
+
+    const CLASS_NAME: PCWSTR = w!("EmnsSessionLock");
+
+    /// `wParam` values of `WM_WTSSESSION_CHANGE`
+    const WTS_SESSION_LOCK: usize = 0x7;
+    const WTS_SESSION_UNLOCK: usize = 0x8;
+
+    static TRACKER: OnceLock<LockTracker> = OnceLock::new();
+
+    /// The process-wide tracker, starting the notification window the first time
+    pub(super) fn tracker() -> &'static LockTracker {
+        TRACKER.get_or_init(|| {
+            std::thread::spawn(|| unsafe {
+                if let Err(e) = run_window() {
+                    log::error!("Failed to watch for workstation lock: {}", e);
+                }
+            });
+            LockTracker::new()
+        })
+    }
+
+    unsafe fn run_window() -> windows::core::Result<()> {
+        let instance: HINSTANCE = GetModuleHandleW(None)?.into();
+        let class: WNDCLASSW = WNDCLASSW {
+            lpfnWndProc: Some(window_proc),
+            hInstance: instance,
+            lpszClassName: CLASS_NAME,
+            ..Default::default()
+        };
+        RegisterClassW(&class);
+
+        // Message-only windows do not receive session notifications, so this is
+        // an ordinary window that is never shown
+        let hwnd: HWND = CreateWindowExW(
+            WINDOW_EX_STYLE::default(),
+            CLASS_NAME,
+            w!("EMNS session lock"),
+            WS_OVERLAPPED,
+            0,
+            0,
+            0,
+            0,
+            None,
+            None,
+            instance,
+            None,
+        );
+        if hwnd.0 == 0 {
+            return Err(windows::core::Error::from_win32());
+        }
+        WTSRegisterSessionNotification(hwnd, NOTIFY_FOR_THIS_SESSION)?;
+
+        let mut message: MSG = MSG::default();
+        while GetMessageW(&mut message, None, 0, 0).as_bool() {
+            TranslateMessage(&message);
+            DispatchMessageW(&message);
+        }
+        Ok(())
+    }
+
+    extern "system" fn window_proc(
+        window: HWND,
+        message: u32,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        if message == WM_WTSSESSION_CHANGE {
+            if let Some(tracker) = TRACKER.get() {
+                match wparam.0 {
+                    WTS_SESSION_LOCK => tracker.set_locked(true),
+                    WTS_SESSION_UNLOCK => tracker.set_locked(false),
+                    _ => {}
+                }
+            }
+            return LRESULT(0);
+        }
+        unsafe { DefWindowProcW(window, message, wparam, lparam) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_locked_time_adds_up_across_locks() {
+        let tracker: LockTracker = LockTracker::new();
+        let rx: watch::Receiver<LockState> = tracker.subscribe();
+        tracker.set_locked(true);
+        tokio::time::advance(Duration::from_secs(60)).await;
+        tracker.set_locked(true);
+        tracker.set_locked(false);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        tracker.set_locked(true);
+        tokio::time::advance(Duration::from_secs(10)).await;
+
+        let state: LockState = *rx.borrow();
+        assert!(state.is_locked());
+        assert_eq!(state.locked_time(Instant::now()), Duration::from_secs(70));
+    }
+}
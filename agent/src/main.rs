@@ -1,120 +1,359 @@
-mod audio;
-mod client;
-mod handler;
-mod messages;
-mod notification;
-
-use crate::client::WebSocketClient;
-use crate::handler::AlertHandler;
-use crate::messages::{Alert, Confirmation};
-use anyhow::{Context, Result};
+use anyhow::Result;
+use emns_agent::attachments::AttachmentStore;
+use emns_agent::bulk::{BulkFilter, BulkSummary};
+use emns_agent::capabilities::{self, SelfCheck};
+use emns_agent::capture::{self, CaptureFilter};
+use emns_agent::history::AlertHistory;
+use emns_agent::http_api::{CALLER_HEADER, TOKEN_HEADER};
+use emns_agent::messages::{AgentStatus, ShutdownReason};
+use emns_agent::session_helper::{self, SessionHelperConfig};
+use emns_agent::shutdown::{self, ShutdownLog};
+use emns_agent::sound_pack;
+use emns_agent::sounds::SoundLibrary;
+use emns_agent::startup::{self, AudioProbe, NetworkProbe, ReadinessProbe, StartupReport};
+use emns_agent::storage::LineProtector;
+use emns_agent::timing;
+use emns_agent::{
+    client, notification, offline, retention, update, Agent, AudioPlayer, Config,
+    NotificationManager,
+};
+use std::collections::HashSet;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
-#[derive(Debug)]
-pub struct Config {
-    pub server_url: String,
-    pub client_id: String,
-    pub sounds_dir: PathBuf,
-}
+/// How long shutdown waits for background tasks to finish
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Initialize logging
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-impl Config {
-    pub fn from_env() -> Result<Self> {
-        let server_url: String =
-            std::env::var("SERVER_URL").unwrap_or_else(|_| "ws://localhost:8080/ws".to_string());
+    // Pretty-print a wire capture instead of running the agent
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("inspect-capture") {
+        let (filter, files) = CaptureFilter::from_args(args)?;
+        let mut out = std::io::stdout().lock();
+        for file in files {
+            let reader = std::io::BufReader::new(std::fs::File::open(&file)?);
+            capture::inspect(reader, &filter, &mut out)?;
+        }
+        return Ok(());
+    }
+
+    // Confirm or dismiss pending alerts in the running agent, through its local HTTP API
+    if let Some(action @ ("confirm-all" | "dismiss-all")) = std::env::args().nth(1).as_deref() {
+        let filter: BulkFilter = BulkFilter::from_args(std::env::args().skip(2))?;
+        let config: Config = Config::from_env()?;
+        let http_api = config
+            .http_api
+            .ok_or_else(|| anyhow::anyhow!("HTTP_LISTEN is not set"))?;
+        let token: String = http_api
+            .local_alert_token
+            .ok_or_else(|| anyhow::anyhow!("LOCAL_ALERT_TOKEN is not set"))?;
+        let path: &str = if action == "confirm-all" {
+            "confirm"
+        } else {
+            "dismiss"
+        };
+        let summary: BulkSummary = reqwest::Client::new()
+            .post(format!("http://{}/pending/{}", http_api.listen, path))
+            .header(TOKEN_HEADER, token)
+            .header(CALLER_HEADER, "cli")
+            .json(&filter)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        println!(
+            "{} {} alert(s), {} left pending",
+            if action == "confirm-all" {
+                "Confirmed"
+            } else {
+                "Dismissed"
+            },
+            summary.resolved.len(),
+            summary.skipped
+        );
+        for alert_id in &summary.resolved {
+            println!("  {}", alert_id);
+        }
+        return Ok(());
+    }
 
-        let client_id: String =
-            std::env::var("CLIENT_ID").unwrap_or_else(|_| uuid::Uuid::new_v4().to_string());
+    // Put back the binary the last self-update replaced, with the service stopped
+    if std::env::args().nth(1).as_deref() == Some("rollback-update") {
+        let exe: PathBuf = std::env::current_exe()?;
+        update::rollback(&exe)?;
+        println!(
+            "Restored {} from {}",
+            exe.display(),
+            update::rollback_path(&exe).display()
+        );
+        return Ok(());
+    }
 
-        let sounds_dir: PathBuf = std::env::var("SOUNDS_DIR")
+    // Sign what is waiting for the server into a bundle for removable media
+    if std::env::args().nth(1).as_deref() == Some("export-offline") {
+        let out: PathBuf = std::env::args()
+            .skip_while(|arg| arg != "--out")
+            .nth(1)
             .map(PathBuf::from)
-            .unwrap_or_else(|_| PathBuf::from("./sounds"));
+            .ok_or_else(|| anyhow::anyhow!("usage: emns-agent export-offline --out <file>"))?;
+        let config: Config = Config::from_env()?;
+        let offline = config
+            .offline
+            .ok_or_else(|| anyhow::anyhow!("OFFLINE_EXPORT_KEY is not set"))?;
+        let bundle = offline::export(
+            &config.data_dir,
+            &config.client_id,
+            &client::get_hostname(),
+            &offline.key,
+            &LineProtector::platform(config.dpapi_scope),
+            &out,
+        )?;
+        println!(
+            "Wrote bundle {} to {}: {} record(s), {} alert(s)",
+            bundle.sequence,
+            out.display(),
+            bundle.records.len(),
+            bundle.alerts.len()
+        );
+        return Ok(());
+    }
 
-        // Create sounds directory if it doesn't exist
-        if !sounds_dir.exists() {
-            std::fs::create_dir_all(&sounds_dir).context("Failed to create sounds directory")?;
-            log::info!("Created sounds directory: {}", sounds_dir.display());
+    // Prune the data directory once, with the service stopped, and say what went
+    if std::env::args().any(|arg| arg == "--prune-now") {
+        let config: Config = Config::from_env()?;
+        let history: AlertHistory = match &config.history_file {
+            Some(path) => {
+                AlertHistory::open_protected(path, LineProtector::platform(config.dpapi_scope))?
+            }
+            None => AlertHistory::new(),
+        };
+        let attachments: AttachmentStore =
+            AttachmentStore::new(&config.data_dir, &config.attachments);
+        // Alerts awaiting confirmation are held by the running agent, so none are pending here
+        let report = retention::prune(
+            &history,
+            &attachments,
+            &config.retention,
+            &HashSet::new(),
+            chrono::Utc::now(),
+        )?;
+        println!("Pruned {}: {}", config.data_dir.display(), report);
+        for alert_id in &report.history_removed {
+            println!("  removed alert {}", alert_id);
         }
+        return Ok(());
+    }
 
-        Ok(Self {
-            server_url,
-            client_id,
-            sounds_dir,
-        })
+    // Install a sound pack from a file, for sites the server cannot push packs to
+    if std::env::args().any(|arg| arg == "--install-sound-pack") {
+        let file: PathBuf = std::env::args()
+            .skip_while(|arg| arg != "--install-sound-pack")
+            .nth(1)
+            .map(PathBuf::from)
+            .ok_or_else(|| anyhow::anyhow!("usage: emns-agent --install-sound-pack <file>"))?;
+        let config: Config = Config::from_env()?;
+        let library: SoundLibrary = SoundLibrary::open(&config.sounds_dir);
+        if sound_pack::install_pack(&library, &std::fs::read(&file)?)? {
+            println!(
+                "Installed sound pack {} into {}",
+                library.pack_version().unwrap_or_default(),
+                library.active_dir().display()
+            );
+        } else {
+            println!(
+                "Kept sound pack {}; {} is not newer",
+                library.pack_version().unwrap_or_default(),
+                file.display()
+            );
+        }
+        return Ok(());
     }
-}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize logging
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    // Show where the running agent's delivery time goes, through its local HTTP API
+    if std::env::args().any(|arg| arg == "--timing-report") {
+        let config: Config = Config::from_env()?;
+        let http_api = config
+            .http_api
+            .ok_or_else(|| anyhow::anyhow!("HTTP_LISTEN is not set"))?;
+        let status: AgentStatus = reqwest::Client::new()
+            .get(format!("http://{}/status", http_api.listen))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        match status.delivery_timing {
+            Some(delivery) => {
+                println!(
+                    "Delivery timing over the last {} alert(s):",
+                    delivery.samples
+                );
+                for line in timing::report(&delivery) {
+                    println!("  {}", line);
+                }
+            }
+            None => println!("No alerts handled yet"),
+        }
+        return Ok(());
+    }
+
+    // Load the configuration and run the self-check, then say what was found
+    if std::env::args().any(|arg| arg == "--check-config") {
+        let config: Config = Config::from_env()?;
+        println!("Configuration OK");
+        println!("  Server: {}", config.server_description());
+        println!("  Client ID: {}", config.client_id);
+        println!("  Data Dir: {}", config.data_dir.display());
+        let check: SelfCheck = SelfCheck::new(
+            Arc::new(NotificationManager::new("NotificationAgent")),
+            Arc::new(AudioPlayer::from_library(SoundLibrary::open(
+                &config.sounds_dir,
+            ))),
+            &config.data_dir,
+        );
+        println!("Self-check:");
+        for finding in capabilities::findings(&check.run()) {
+            println!("  {}", finding);
+        }
+        return Ok(());
+    }
+
+    // Per-session helper started by a broker-mode service
+    if std::env::args().any(|arg| arg == "--session-helper") {
+        log::info!("Starting session helper");
+        let cancel: CancellationToken = CancellationToken::new();
+        let helper = tokio::spawn(session_helper::run(
+            SessionHelperConfig::from_env(),
+            cancel.clone(),
+        ));
+        stop_requested().await?;
+        cancel.cancel();
+        helper.await??;
+        return Ok(());
+    }
 
     log::info!("Starting Notification Agent");
 
     // Load configuration
     let config: Config = Config::from_env()?;
     log::info!("Configuration loaded:");
-    log::info!("  Server URL: {}", config.server_url);
+    log::info!("  Server: {}", config.server_description());
     log::info!("  Client ID: {}", config.client_id);
     log::info!("  Sounds Dir: {}", config.sounds_dir.display());
+    log::info!("  Data Dir: {}", config.data_dir.display());
+
+    // Panics are reported when the agent next registers
+    shutdown::install_panic_hook(ShutdownLog::new(config.shutdown_file.clone()));
 
-    // Create channels
-    let (alert_tx, mut alert_rx) = mpsc::channel::<Alert>(100);
-    let (confirmation_tx, confirmation_rx) = mpsc::channel::<Confirmation>(100);
-
-    // Create alert handler
-    let handler: Arc<AlertHandler> = Arc::new(AlertHandler::new(
-        config.sounds_dir.clone(),
-        confirmation_tx,
-        config.client_id.clone(),
-    ));
-
-    // Spawn alert processing task
-    let handler_clone: Arc<AlertHandler> = handler.clone();
-    tokio::spawn(async move {
-        while let Some(alert) = alert_rx.recv().await {
-            if let Err(e) = handler_clone.handle_alert(alert).await {
-                log::error!("Failed to handle alert: {}", e);
+    // Give a slow boot time to bring up the network and audio before they are judged missing
+    let mut degraded: bool = false;
+    if !config.startup_wait.is_zero() {
+        let mut probes: Vec<Arc<dyn ReadinessProbe>> = vec![Arc::new(AudioProbe)];
+        if let Some(probe) = config.startup_server_url().and_then(NetworkProbe::for_url) {
+            probes.insert(0, Arc::new(probe));
+        }
+        let cancel: CancellationToken = CancellationToken::new();
+        let interrupted = tokio::spawn({
+            let cancel: CancellationToken = cancel.clone();
+            async move {
+                if stop_requested().await.is_ok() {
+                    cancel.cancel();
+                }
             }
+        });
+        let report: StartupReport =
+            startup::wait_for_dependencies(probes, config.startup_wait, &cancel).await;
+        interrupted.abort();
+        if cancel.is_cancelled() {
+            return Ok(());
         }
-    });
+        if !report.absent.is_empty() {
+            log::warn!(
+                "Startup: not present on this machine: {}",
+                report.absent.join(", ")
+            );
+        }
+        degraded = report.degraded();
+    }
 
-    // Create WebSocket client
-    let hostname: String = client::get_hostname();
-    let ws_client: WebSocketClient = WebSocketClient::new(
-        config.server_url.clone(),
-        config.client_id.clone(),
-        hostname,
-    );
+    let server: String = config.server_description();
+    let mut agent: Agent = Agent::builder(config).degraded_start(degraded).build();
+    if let Err(e) = agent.start() {
+        agent.shutdown_log().record(ShutdownReason::Crash);
+        return Err(e.into());
+    }
 
     // Show startup notification
     if let Err(e) = notification::show_simple_notification(
         "Notification Agent Started",
-        &format!("Connected to: {}", config.server_url),
+        &format!("Connected to: {}", server),
     ) {
         log::warn!("Failed to show startup notification: {}", e);
     }
 
-    // Run the WebSocket client (this will reconnect on failures)
-    ws_client.run(alert_tx, confirmation_rx).await?;
+    // Run until told to stop, or until a staged update is due and the service wrapper should restart us
+    let restart: CancellationToken = agent
+        .updater()
+        .map(|updater| updater.restart_requested().clone())
+        .unwrap_or_default();
+    let restarting: bool = tokio::select! {
+        signal = stop_requested() => {
+            if let Err(e) = signal {
+                agent.shutdown_log().record(ShutdownReason::Crash);
+                return Err(e.into());
+            }
+            false
+        }
+        _ = restart.cancelled() => true,
+    };
+    // Unregisters from the server, and records the stop as clean, or as an update when restarting
+    if !agent.shutdown(SHUTDOWN_TIMEOUT).await {
+        log::warn!("Agent did not stop cleanly");
+    }
+    if restarting {
+        std::process::exit(update::RESTART_EXIT_CODE);
+    }
 
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_config_defaults() {
-        std::env::remove_var("SERVER_URL");
-        std::env::remove_var("CLIENT_ID");
-        std::env::remove_var("SOUNDS_DIR");
+/// Wait until the process is asked to stop: Ctrl+C, or SIGTERM from a service manager
+#[cfg(unix)]
+async fn stop_requested() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        signal = tokio::signal::ctrl_c() => signal,
+        _ = terminate.recv() => {
+            log::info!("Received SIGTERM");
+            Ok(())
+        }
+    }
+}
 
-        let config: Config = Config::from_env().unwrap();
-        assert_eq!(config.server_url, "ws://localhost:8080/ws");
-        assert!(config.client_id.len() > 0);
-        assert_eq!(config.sounds_dir, PathBuf::from("./sounds"));
+/// Wait until the process is asked to stop: Ctrl+C, which is how a service
+/// wrapper such as NSSM stops it, or the console closing or Windows shutting down
+#[cfg(windows)]
+async fn stop_requested() -> std::io::Result<()> {
+    use tokio::signal::windows::{ctrl_close, ctrl_shutdown};
+    let mut close = ctrl_close()?;
+    let mut shutdown = ctrl_shutdown()?;
+    tokio::select! {
+        signal = tokio::signal::ctrl_c() => signal,
+        _ = close.recv() => {
+            log::info!("Console closing");
+            Ok(())
+        }
+        _ = shutdown.recv() => {
+            log::info!("System shutting down");
+            Ok(())
+        }
     }
 }
@@ -0,0 +1,133 @@
+//! Quiet reconnects while a server is down for announced maintenance
+
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Least time between reconnect attempts while the server is down for maintenance
+pub const MAINTENANCE_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Longest maintenance window honored; a later resume time is cut to this
+pub const MAX_MAINTENANCE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+struct Announced {
+    url: String,
+    until: Instant,
+}
+
+/// The window a server gave in [`Message::ServerShutdown`](crate::messages::Message::ServerShutdown)
+#[derive(Debug, Default)]
+pub struct MaintenanceWindow {
+    announced: Mutex<Option<Announced>>,
+}
+
+impl MaintenanceWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The server at `url` is stopping and expects to be back at `resume_expected_at`
+    pub fn announce(&self, url: &str, resume_expected_at: chrono::DateTime<chrono::Utc>) {
+        // Resume times are given to the second; round up so the window is not cut short
+        let requested: Duration = (resume_expected_at - chrono::Utc::now())
+            .to_std()
+            .map_or(Duration::ZERO, |left| {
+                Duration::from_secs(left.as_secs() + u64::from(left.subsec_nanos() > 0))
+            });
+        if requested > MAX_MAINTENANCE_WINDOW {
+            log::warn!(
+                "Server {} expects to be down until {}; treating it as down for {} minutes",
+                url,
+                resume_expected_at,
+                MAX_MAINTENANCE_WINDOW.as_secs() / 60
+            );
+        }
+        let window: Duration = requested.min(MAX_MAINTENANCE_WINDOW);
+        *self.announced.lock().unwrap() = Some(Announced {
+            url: url.to_string(),
+            until: Instant::now() + window,
+        });
+    }
+
+    /// The server at `url` accepted a connection again; its window is over
+    pub fn end(&self, url: &str) {
+        let mut announced = self.announced.lock().unwrap();
+        if announced.as_ref().is_some_and(|a| a.url == url) {
+            log::info!("Server {} is back from maintenance", url);
+            *announced = None;
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.remaining().is_some()
+    }
+
+    /// Time left in the window, if one is open
+    fn remaining(&self) -> Option<Duration> {
+        let announced = self.announced.lock().unwrap();
+        let until: Instant = announced.as_ref()?.until;
+        let now: Instant = Instant::now();
+        (until > now).then(|| until - now)
+    }
+
+    /// Wait before the next reconnect: at least `normal`, and during the window
+    /// [`MAINTENANCE_RECONNECT_DELAY`] unless the window ends sooner
+    pub fn reconnect_delay(&self, normal: Duration) -> Duration {
+        match self.remaining() {
+            Some(remaining) => MAINTENANCE_RECONNECT_DELAY.min(remaining).max(normal),
+            None => normal,
+        }
+    }
+
+    /// Level for connection failures: expected ones during the window are not errors
+    pub fn log_level(&self) -> log::Level {
+        if self.is_active() {
+            log::Level::Info
+        } else {
+            log::Level::Error
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "ws://server.test/ws";
+    const NORMAL: Duration = Duration::from_secs(5);
+
+    #[tokio::test(start_paused = true)]
+    async fn test_window_slows_reconnects_until_it_elapses() {
+        let window: MaintenanceWindow = MaintenanceWindow::new();
+        assert_eq!(window.reconnect_delay(NORMAL), NORMAL);
+
+        window.announce(URL, chrono::Utc::now() + chrono::TimeDelta::seconds(100));
+        assert!(window.is_active());
+        assert_eq!(window.log_level(), log::Level::Info);
+        assert_eq!(window.reconnect_delay(NORMAL), MAINTENANCE_RECONNECT_DELAY);
+
+        // The last wait ends with the window
+        tokio::time::advance(Duration::from_secs(85)).await;
+        assert_eq!(window.reconnect_delay(NORMAL), Duration::from_secs(15));
+        tokio::time::advance(Duration::from_secs(13)).await;
+        assert_eq!(window.reconnect_delay(NORMAL), NORMAL);
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert!(!window.is_active());
+        assert_eq!(window.log_level(), log::Level::Error);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_window_ends_early_only_for_the_announcing_server() {
+        let window: MaintenanceWindow = MaintenanceWindow::new();
+        window.announce(URL, chrono::Utc::now() + chrono::TimeDelta::days(7));
+        tokio::time::advance(MAX_MAINTENANCE_WINDOW - Duration::from_secs(1)).await;
+        assert!(window.is_active());
+
+        window.end("ws://backup.test/ws");
+        assert!(window.is_active());
+        window.end(URL);
+        assert!(!window.is_active());
+    }
+}
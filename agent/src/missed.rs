@@ -0,0 +1,150 @@
+//! The recap of alerts issued while this machine was offline
+
+use crate::burst::list_alerts;
+use crate::messages::{Alert, AlertLevel, AlertOrigin};
+use crate::queue::priority;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Missed alerts replayed after a reconnect arrive back to back; the digest
+/// is shown once none has arrived for this long
+pub const MISSED_SETTLE: Duration = Duration::from_secs(2);
+
+/// Digests kept so clicking an older digest toast still opens its details
+const DIGESTS_KEPT: usize = 4;
+
+/// Missed alerts held for the digest, and the digests already shown
+#[derive(Debug, Default)]
+pub(crate) struct MissedDigest {
+    held: Vec<Alert>,
+    last_arrival: Option<Instant>,
+    digests: VecDeque<Alert>,
+    /// When a missed alert last sounded
+    last_sound: Option<Instant>,
+}
+
+impl MissedDigest {
+    /// Hold a missed alert for the digest; returns `true` if it is the first one held
+    pub fn hold(&mut self, alert: Alert, now: Instant) -> bool {
+        let started: bool = self.held.is_empty();
+        self.held.push(alert);
+        self.last_arrival = Some(now);
+        started
+    }
+
+    /// When the digest can be shown if nothing else arrives, or `None` if nothing is held
+    pub fn settled_at(&self) -> Option<Instant> {
+        if self.held.is_empty() {
+            return None;
+        }
+        self.last_arrival.map(|at| at + MISSED_SETTLE)
+    }
+
+    /// Most severe level among the held alerts
+    pub fn level(&self) -> Option<AlertLevel> {
+        self.held
+            .iter()
+            .map(|alert| alert.level.clone())
+            .max_by_key(priority)
+    }
+
+    /// Take the held alerts once the replay has settled, returning the digest toast
+    pub fn finish(&mut self, now: Instant) -> Option<Alert> {
+        if self.settled_at().is_some_and(|at| now < at) {
+            return None;
+        }
+        let level: AlertLevel = self.level()?;
+        let held: Vec<Alert> = std::mem::take(&mut self.held);
+        let digest: Alert = digest(level, &held);
+        let toast: Alert = Alert {
+            message: "Click for details".to_string(),
+            ..digest.clone()
+        };
+
+        if self.digests.len() == DIGESTS_KEPT {
+            self.digests.pop_front();
+        }
+        self.digests.push_back(digest);
+        Some(toast)
+    }
+
+    /// Whether a missed alert sounded within [`MISSED_SETTLE`] of `now`, so
+    /// another replayed with it need not sound too
+    pub fn sounded_recently(&self, now: Instant) -> bool {
+        self.last_sound.is_some_and(|at| now < at + MISSED_SETTLE)
+    }
+
+    pub fn sounded(&mut self, now: Instant) {
+        self.last_sound = Some(now);
+    }
+
+    /// A digest shown earlier, with the missed alerts listed in its message
+    pub fn digest(&self, id: uuid::Uuid) -> Option<&Alert> {
+        self.digests.iter().find(|d| d.id == id)
+    }
+}
+
+/// The digest toast for `missed`, listing each alert in its message for the details window
+fn digest(level: AlertLevel, missed: &[Alert]) -> Alert {
+    let title: String = match missed.len() {
+        1 => "1 alert was issued while this machine was offline".to_string(),
+        n => format!("{} alerts were issued while this machine was offline", n),
+    };
+    Alert {
+        id: uuid::Uuid::new_v4(),
+        title,
+        message: list_alerts("Issued while this machine was offline:", missed, "%a %H:%M"),
+        level,
+        requires_confirmation: false,
+        sound_file: None,
+        timestamp: chrono::Utc::now(),
+        origin: AlertOrigin::Local,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::alert;
+
+    #[test]
+    fn test_digest_waits_for_the_replay_to_settle() {
+        let mut missed: MissedDigest = MissedDigest::default();
+        let start: Instant = Instant::now();
+        assert!(missed.hold(alert(AlertLevel::Info, false), start));
+        assert!(!missed.hold(
+            alert(AlertLevel::Critical, false),
+            start + Duration::from_secs(1)
+        ));
+        assert_eq!(missed.level(), Some(AlertLevel::Critical));
+
+        let settled: Instant = missed.settled_at().unwrap();
+        assert_eq!(settled, start + Duration::from_secs(1) + MISSED_SETTLE);
+        assert!(missed.finish(settled - Duration::from_millis(1)).is_none());
+
+        let toast: Alert = missed.finish(settled).unwrap();
+        assert_eq!(
+            toast.title,
+            "2 alerts were issued while this machine was offline"
+        );
+        assert_eq!(toast.level, AlertLevel::Critical);
+        assert_eq!(toast.message, "Click for details");
+        let digest: &Alert = missed.digest(toast.id).unwrap();
+        assert_eq!(digest.message.lines().count(), 4);
+        assert!(missed.settled_at().is_none());
+        assert!(missed.finish(settled).is_none());
+    }
+
+    #[test]
+    fn test_single_missed_alert_title() {
+        let mut missed: MissedDigest = MissedDigest::default();
+        let now: Instant = Instant::now();
+        missed.hold(alert(AlertLevel::Warning, false), now);
+        assert_eq!(
+            missed.finish(now + MISSED_SETTLE).unwrap().title,
+            "1 alert was issued while this machine was offline"
+        );
+    }
+}
@@ -0,0 +1,302 @@
+//! Fallback alert channel over UDP multicast, for when the server is unreachable
+
+use crate::error::{EmnsError, Result};
+use crate::handler::AlertHandler;
+use crate::messages::{Alert, AlertEnvelope, Location, ReceivedVia};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Default UDP port for the multicast group
+pub const DEFAULT_MULTICAST_PORT: u16 = 45400;
+
+/// Largest envelope sent or accepted; alerts are never split across datagrams
+pub const MAX_DATAGRAM_BYTES: usize = 1472;
+
+/// Envelopes whose alert timestamp is further than this from now are treated as replays
+pub const MAX_ALERT_AGE: Duration = Duration::from_secs(10 * 60);
+
+pub(crate) type HmacSha256 = Hmac<Sha256>;
+
+/// Shared secret envelopes are signed with
+#[derive(Clone)]
+pub struct SigningKey(Vec<u8>);
+
+impl SigningKey {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        Self(key.into())
+    }
+
+    pub(crate) fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.0).expect("HMAC accepts keys of any length")
+    }
+}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SigningKey(..)")
+    }
+}
+
+/// Settings for the multicast listener and sender
+#[derive(Debug, Clone)]
+pub struct MulticastConfig {
+    pub group: Ipv4Addr,
+    pub port: u16,
+    /// Local interface to join the group on; the system picks one when unspecified
+    pub interface: Ipv4Addr,
+    pub key: SigningKey,
+}
+
+impl MulticastConfig {
+    pub fn new(group: Ipv4Addr, key: SigningKey) -> Self {
+        Self {
+            group,
+            port: DEFAULT_MULTICAST_PORT,
+            interface: Ipv4Addr::UNSPECIFIED,
+            key,
+        }
+    }
+}
+
+/// Sign an alert into an envelope that fits in one datagram
+pub fn seal(alert: &Alert, key: &SigningKey) -> Result<Vec<u8>> {
+    let payload: String = serde_json::to_string(alert)?;
+    let mut mac: HmacSha256 = key.mac();
+    mac.update(payload.as_bytes());
+    let envelope: AlertEnvelope = AlertEnvelope {
+        signature: BASE64.encode(mac.finalize().into_bytes()),
+        payload,
+    };
+    let datagram: Vec<u8> = serde_json::to_vec(&envelope)?;
+    if datagram.len() > MAX_DATAGRAM_BYTES {
+        return Err(EmnsError::protocol(format!(
+            "alert {} is {} bytes signed, over the {} byte datagram limit",
+            alert.id,
+            datagram.len(),
+            MAX_DATAGRAM_BYTES
+        )));
+    }
+    Ok(datagram)
+}
+
+/// Verify an envelope and return its alert
+pub fn open(datagram: &[u8], key: &SigningKey) -> Result<Alert> {
+    if datagram.len() > MAX_DATAGRAM_BYTES {
+        return Err(EmnsError::protocol(format!(
+            "datagram over the {} byte limit",
+            MAX_DATAGRAM_BYTES
+        )));
+    }
+    let envelope: AlertEnvelope = serde_json::from_slice(datagram)?;
+    let signature: Vec<u8> = BASE64
+        .decode(envelope.signature.as_bytes())
+        .map_err(|e| EmnsError::protocol(format!("malformed signature: {}", e)))?;
+    let mut mac: HmacSha256 = key.mac();
+    mac.update(envelope.payload.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| EmnsError::protocol("signature does not match"))?;
+
+    let alert: Alert = serde_json::from_str(&envelope.payload)?;
+    let now: chrono::DateTime<chrono::Utc> = chrono::Utc::now();
+    let age: chrono::TimeDelta = now - alert.timestamp;
+    if age.abs().to_std().map_or(true, |age| age > MAX_ALERT_AGE) {
+        // Name this machine's time too, since its clock may be the one that is off
+        return Err(EmnsError::protocol(format!(
+            "alert {} sent at {} is too old or too far in the future for this machine's clock ({})",
+            alert.id, alert.timestamp, now
+        )));
+    }
+    Ok(alert)
+}
+
+/// A socket joined to the multicast group, ready to receive envelopes
+pub struct MulticastListener {
+    socket: UdpSocket,
+    key: SigningKey,
+    location: Option<Location>,
+}
+
+impl MulticastListener {
+    /// Bind the group's port and join the group
+    pub fn bind(config: &MulticastConfig) -> Result<Self> {
+        if !config.group.is_multicast() {
+            return Err(EmnsError::config(
+                "MULTICAST_GROUP",
+                format!("{} is not a multicast address", config.group),
+            ));
+        }
+        let bind_error = |e: std::io::Error| {
+            EmnsError::config(
+                "MULTICAST_GROUP",
+                format!("failed to join {}:{}: {}", config.group, config.port, e),
+            )
+        };
+        let socket: Socket =
+            Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).map_err(bind_error)?;
+        // Other listeners on the machine may share the group's port
+        socket.set_reuse_address(true).map_err(bind_error)?;
+        socket
+            .bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.port).into())
+            .map_err(bind_error)?;
+        socket
+            .join_multicast_v4(&config.group, &config.interface)
+            .map_err(bind_error)?;
+        socket.set_nonblocking(true).map_err(bind_error)?;
+
+        Ok(Self {
+            socket: socket.into(),
+            key: config.key.clone(),
+            location: None,
+        })
+    }
+
+    /// Ignore alerts targeted at other locations, as the server connection does
+    pub fn with_location(mut self, location: Option<Location>) -> Self {
+        self.location = location;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket
+            .local_addr()
+            .map_err(|e| EmnsError::config("MULTICAST_PORT", e))
+    }
+
+    /// Feed verified alerts into `handler` until `cancel` fires
+    pub async fn run(self, handler: Arc<AlertHandler>, cancel: CancellationToken) -> Result<()> {
+        let socket: tokio::net::UdpSocket = tokio::net::UdpSocket::from_std(self.socket)
+            .map_err(|e| EmnsError::config("MULTICAST_GROUP", e))?;
+        // One spare byte to tell an oversize datagram from one that exactly fits
+        let mut buffer: Vec<u8> = vec![0; MAX_DATAGRAM_BYTES + 1];
+
+        loop {
+            let (len, from) = tokio::select! {
+                _ = cancel.cancelled() => break,
+                received = socket.recv_from(&mut buffer) => match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        log::warn!("Multicast receive failed: {}", e);
+                        continue;
+                    }
+                },
+            };
+            let alert: Alert = match open(&buffer[..len], &self.key) {
+                Ok(alert) => alert,
+                Err(e) => {
+                    log::warn!("Dropping multicast datagram from {}: {}", from, e);
+                    continue;
+                }
+            };
+            if !alert.targets(self.location.as_ref()) {
+                log::debug!("Ignoring multicast alert {} for another location", alert.id);
+                continue;
+            }
+            log::info!("Received alert {} over multicast from {}", alert.id, from);
+            if let Err(e) = handler
+                .handle_alert_via(alert, ReceivedVia::Multicast)
+                .await
+            {
+                log::error!("Failed to handle multicast alert: {}", e);
+            }
+        }
+        log::debug!("Multicast listener stopped");
+        Ok(())
+    }
+}
+
+/// Broadcasts signed alerts to the group
+pub struct MulticastSender {
+    socket: UdpSocket,
+    destination: SocketAddrV4,
+    key: SigningKey,
+}
+
+impl MulticastSender {
+    pub fn new(config: &MulticastConfig) -> Result<Self> {
+        let socket_error = |e: std::io::Error| EmnsError::connection(config.group.to_string(), e);
+        let socket: Socket =
+            Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).map_err(socket_error)?;
+        socket
+            .set_multicast_if_v4(&config.interface)
+            .map_err(socket_error)?;
+        // Agents on the sending machine receive the alert too
+        socket.set_multicast_loop_v4(true).map_err(socket_error)?;
+        Ok(Self {
+            socket: socket.into(),
+            destination: SocketAddrV4::new(config.group, config.port),
+            key: config.key.clone(),
+        })
+    }
+
+    pub fn send(&self, alert: &Alert) -> Result<()> {
+        let datagram: Vec<u8> = seal(alert, &self.key)?;
+        self.socket
+            .send_to(&datagram, self.destination)
+            .map_err(|e| EmnsError::connection(self.destination.to_string(), e))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::AlertLevel;
+    use crate::test_support::alert;
+
+    fn key() -> SigningKey {
+        SigningKey::new("site-secret")
+    }
+
+    #[test]
+    fn test_sealed_alert_opens_with_the_same_key() {
+        let sent: Alert = alert(AlertLevel::Emergency, true);
+        let datagram: Vec<u8> = seal(&sent, &key()).unwrap();
+        let received: Alert = open(&datagram, &key()).unwrap();
+        assert_eq!(received.id, sent.id);
+        assert_eq!(received.message, sent.message);
+
+        assert!(open(&datagram, &SigningKey::new("other-secret")).is_err());
+    }
+
+    #[test]
+    fn test_tampered_and_unsigned_envelopes_are_rejected() {
+        let sent: Alert = alert(AlertLevel::Critical, false);
+        let datagram: Vec<u8> = seal(&sent, &key()).unwrap();
+        let mut envelope: AlertEnvelope = serde_json::from_slice(&datagram).unwrap();
+        envelope.payload = envelope.payload.replace("Critical", "Info");
+        assert!(open(&serde_json::to_vec(&envelope).unwrap(), &key()).is_err());
+
+        let unsigned: AlertEnvelope = AlertEnvelope {
+            payload: serde_json::to_string(&sent).unwrap(),
+            signature: String::new(),
+        };
+        assert!(open(&serde_json::to_vec(&unsigned).unwrap(), &key()).is_err());
+        assert!(open(&serde_json::to_vec(&sent).unwrap(), &key()).is_err());
+    }
+
+    #[test]
+    fn test_replayed_alerts_are_rejected() {
+        let mut old: Alert = alert(AlertLevel::Emergency, true);
+        old.timestamp = chrono::Utc::now() - chrono::Duration::hours(1);
+        let datagram: Vec<u8> = seal(&old, &key()).unwrap();
+        assert!(open(&datagram, &key()).is_err());
+    }
+
+    #[test]
+    fn test_oversize_alerts_are_not_sent() {
+        let mut large: Alert = alert(AlertLevel::Warning, false);
+        large.message = "x".repeat(MAX_DATAGRAM_BYTES);
+        assert!(matches!(
+            seal(&large, &key()),
+            Err(EmnsError::Protocol { .. })
+        ));
+        assert!(open(&vec![b' '; MAX_DATAGRAM_BYTES + 1], &key()).is_err());
+    }
+}
@@ -1,49 +1,713 @@
-use crate::messages::{Alert, AlertLevel};
-use anyhow::{Context, Result};
-use windows::{
-    core::HSTRING,
-    Data::Xml::Dom::XmlDocument,
-    UI::Notifications::{ToastNotification, ToastNotificationManager},
+use crate::countdown::{Countdown, COUNTDOWN_BINDING};
+use crate::details::{self, DetailsChoice};
+use crate::error::{EmnsError, Result};
+use crate::handler::AlertHandler;
+use crate::images::ImageCache;
+use crate::locale;
+use crate::messages::{
+    is_http_url, Alert, AlertLevel, AlertOrigin, ConfirmationMethod, ResponseOption,
 };
+use crate::settings::SharedSettings;
+use crate::toast_style::{ToastStyle, ToastStyles};
+use std::borrow::Cow;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use uuid::Uuid;
 
+/// Windows shows at most this many buttons on one toast
+const MAX_TOAST_ACTIONS: usize = 5;
+
+/// Put before a preview alert's title so nobody takes it for a real one
+pub const PREVIEW_LABEL: &str = "[PREVIEW]";
+
+/// Toasts kept referenced so their activation handlers stay registered
+#[cfg(target_os = "windows")]
+const LIVE_TOAST_LIMIT: usize = 64;
+
+/// Toast group that alert toasts are tagged under, so they can be updated by alert id
+#[cfg(target_os = "windows")]
+const TOAST_GROUP: &str = "emns-alerts";
+
+/// Something that can put an alert in front of the user
+pub trait NotificationBackend: Send + Sync {
+    fn show_notification(&self, alert: &Alert) -> Result<()>;
+
+    /// Rewrite the countdown on a confirmation-required alert's toast.
+    ///
+    /// Returns `Ok(false)` once the toast is gone, so the caller can stop
+    /// updating it; backends without live toasts always do.
+    fn update_countdown(&self, alert_id: Uuid, countdown: &Countdown) -> Result<bool> {
+        let _ = (alert_id, countdown);
+        Ok(false)
+    }
+
+    /// Replace the countdown on an alert's toast with who has already acknowledged it.
+    ///
+    /// Returns `Ok(false)` if the toast is gone; backends without live toasts always do.
+    fn show_acknowledged(&self, alert_id: Uuid, acknowledged_by: &[String]) -> Result<bool> {
+        let _ = (alert_id, acknowledged_by);
+        Ok(false)
+    }
+
+    /// Take an alert's toast down, wherever it is; backends without live toasts do nothing
+    fn remove_notification(&self, alert_id: Uuid) -> Result<()> {
+        let _ = alert_id;
+        Ok(())
+    }
+
+    /// Whether alerts shown now would reach the user; asked by the self-check.
+    ///
+    /// Backends that cannot tell say they would.
+    fn available(&self) -> bool {
+        true
+    }
+}
+
+/// What a click on a toast or one of its buttons asks for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToastAction {
+    /// The toast body: open the details window
+    Details,
+    Confirm,
+    /// One of the alert's response options
+    Respond(ResponseChoice),
+    /// Open the alert's downloaded attachment
+    OpenAttachment,
+    Dismiss,
+}
+
+impl ToastAction {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Details => "details",
+            Self::Confirm => "confirm",
+            Self::Respond(_) => "respond",
+            Self::OpenAttachment => "open",
+            Self::Dismiss => "dismiss",
+        }
+    }
+}
+
+/// The response option a toast button stands for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResponseChoice {
+    /// The option's id, which stays right if the server reorders options
+    Id(String),
+    /// Position in `response_options`, as written by older agents
+    Position(usize),
+}
+
+/// A toast's activation arguments, which Windows hands back on a click.
+///
+/// Encoded as a query string such as `action=respond&alert=<id>&option=safe`;
+/// parsing also accepts the `<action>:<alert id>[:<option>]` form written by
+/// older agents, whose toasts can still be in the Action Center after an upgrade.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActivationArgs {
+    pub action: ToastAction,
+    pub alert_id: Uuid,
+}
+
+impl ActivationArgs {
+    pub fn new(action: ToastAction, alert_id: Uuid) -> Self {
+        Self { action, alert_id }
+    }
+
+    /// A button answering with the response option `option_id`
+    pub fn respond(alert_id: Uuid, option_id: impl Into<String>) -> Self {
+        Self::new(
+            ToastAction::Respond(ResponseChoice::Id(option_id.into())),
+            alert_id,
+        )
+    }
+
+    /// Parse arguments from a toast shown by this or an older agent
+    pub fn parse(arguments: &str) -> Result<Self> {
+        let arguments: &str = arguments.trim();
+        let invalid = |detail: &str| EmnsError::activation(arguments, detail);
+        if !arguments.contains('=') {
+            return Self::parse_legacy(arguments).ok_or_else(|| invalid("unrecognised format"));
+        }
+
+        let mut action: Option<String> = None;
+        let mut alert_id: Option<String> = None;
+        let mut option: Option<String> = None;
+        let mut position: Option<String> = None;
+        // Keys added by newer agents are ignored
+        for (key, value) in form_urlencoded::parse(arguments.as_bytes()) {
+            let slot: &mut Option<String> = match key.as_ref() {
+                "action" => &mut action,
+                "alert" => &mut alert_id,
+                "option" => &mut option,
+                "position" => &mut position,
+                _ => continue,
+            };
+            *slot = Some(value.into_owned());
+        }
+
+        let alert_id: Uuid = alert_id
+            .ok_or_else(|| invalid("missing alert"))
+            .and_then(|id| Uuid::parse_str(&id).map_err(|_| invalid("alert is not a UUID")))?;
+        let action: ToastAction = match action.as_deref() {
+            Some("details") => ToastAction::Details,
+            Some("confirm") => ToastAction::Confirm,
+            Some("open") => ToastAction::OpenAttachment,
+            Some("dismiss") => ToastAction::Dismiss,
+            Some("respond") => match (option, position) {
+                (Some(id), _) if !id.is_empty() => ToastAction::Respond(ResponseChoice::Id(id)),
+                (_, Some(position)) => ToastAction::Respond(ResponseChoice::Position(
+                    position
+                        .parse()
+                        .map_err(|_| invalid("position is not a number"))?,
+                )),
+                _ => return Err(invalid("respond needs an option")),
+            },
+            Some(_) => return Err(invalid("unknown action")),
+            None => return Err(invalid("missing action")),
+        };
+        Ok(Self::new(action, alert_id))
+    }
+
+    /// `<action>:<alert id>`, or `respond:<alert id>:<position>`
+    fn parse_legacy(arguments: &str) -> Option<Self> {
+        let (action, rest) = arguments.split_once(':')?;
+        if action == "respond" {
+            let (id, position) = rest.split_once(':')?;
+            return Some(Self::new(
+                ToastAction::Respond(ResponseChoice::Position(position.parse().ok()?)),
+                Uuid::parse_str(id).ok()?,
+            ));
+        }
+        let action: ToastAction = match action {
+            "details" => ToastAction::Details,
+            "confirm" => ToastAction::Confirm,
+            "open" => ToastAction::OpenAttachment,
+            "dismiss" => ToastAction::Dismiss,
+            _ => return None,
+        };
+        Some(Self::new(action, Uuid::parse_str(rest).ok()?))
+    }
+
+    /// Encode as a query string, before XML escaping
+    pub fn arguments(&self) -> String {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        query.append_pair("action", self.action.name());
+        query.append_pair("alert", &self.alert_id.to_string());
+        match &self.action {
+            ToastAction::Respond(ResponseChoice::Id(id)) => {
+                query.append_pair("option", id);
+            }
+            ToastAction::Respond(ResponseChoice::Position(position)) => {
+                query.append_pair("position", &position.to_string());
+            }
+            _ => {}
+        }
+        query.finish()
+    }
+}
+
+/// Act on a click on a toast or one of its buttons.
+///
+/// Clicks on toasts for alerts that have since been confirmed, timed out, or
+/// forgotten fail with [`EmnsError::StaleAlert`].
+pub(crate) async fn dispatch_activation(
+    handler: &Arc<AlertHandler>,
+    args: ActivationArgs,
+    cancel: &CancellationToken,
+    tracker: &TaskTracker,
+) -> Result<()> {
+    let alert_id: Uuid = args.alert_id;
+    let stale = || EmnsError::StaleAlert { alert_id };
+    match args.action {
+        ToastAction::Confirm => {
+            if !handler.is_pending(alert_id).await {
+                return Err(stale());
+            }
+            handler
+                .confirm_alert(alert_id, ConfirmationMethod::ToastButton)
+                .await
+        }
+        ToastAction::Respond(choice) => {
+            if !handler.is_pending(alert_id).await {
+                return Err(stale());
+            }
+            match choice {
+                ResponseChoice::Id(option_id) => {
+                    handler
+                        .respond_with_option(alert_id, &option_id, ConfirmationMethod::ToastButton)
+                        .await
+                }
+                ResponseChoice::Position(position) => {
+                    handler
+                        .respond_to_alert(alert_id, position, ConfirmationMethod::ToastButton)
+                        .await
+                }
+            }
+        }
+        ToastAction::OpenAttachment => handler.open_attachment(alert_id).await,
+        ToastAction::Dismiss => {
+            log::debug!("Toast for alert {} dismissed", alert_id);
+            handler.toast_dismissed(alert_id).await;
+            Ok(())
+        }
+        ToastAction::Details => {
+            let details = handler.alert_details(alert_id).await.ok_or_else(stale)?;
+
+            // The window runs its own message loop, so it gets a thread of its own
+            let (choice_tx, choice_rx) = oneshot::channel::<Result<DetailsChoice>>();
+            std::thread::spawn(move || {
+                let _ = choice_tx.send(details::show_details_window(&details));
+            });
+
+            let handler: Arc<AlertHandler> = handler.clone();
+            let cancel: CancellationToken = cancel.clone();
+            tracker.spawn(async move {
+                let choice = tokio::select! {
+                    _ = cancel.cancelled() => return,
+                    choice = choice_rx => choice,
+                };
+                match choice {
+                    Ok(Ok(DetailsChoice::Confirm)) => {
+                        if let Err(e) = handler
+                            .confirm_alert(alert_id, ConfirmationMethod::DetailsWindow)
+                            .await
+                        {
+                            log::error!("Failed to confirm alert {}: {}", alert_id, e);
+                        }
+                    }
+                    Ok(Ok(DetailsChoice::Respond(option))) => {
+                        if let Err(e) = handler
+                            .respond_to_alert(alert_id, option, ConfirmationMethod::DetailsWindow)
+                            .await
+                        {
+                            log::error!("Failed to record response to alert {}: {}", alert_id, e);
+                        }
+                    }
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => log::error!("Details window failed: {}", e),
+                    Err(_) => log::error!("Details window for alert {} exited", alert_id),
+                }
+            });
+            Ok(())
+        }
+    }
+}
+
+/// Displays alerts as Windows toast notifications
 pub struct NotificationManager {
     app_id: String,
+    settings: SharedSettings,
+    toast_styles: ToastStyles,
+    activations: Option<mpsc::UnboundedSender<ActivationArgs>>,
+    /// Where alerts' images are looked up; toasts go without images when `None`
+    images: Option<Arc<ImageCache>>,
+    /// Picks the translation toasts show; always the alert's own text when `None`
+    locale: Option<String>,
+    #[cfg(target_os = "windows")]
+    live_toasts:
+        std::sync::Mutex<std::collections::VecDeque<windows::UI::Notifications::ToastNotification>>,
+    /// Alerts whose toast the user closed or Windows removed
+    #[cfg(target_os = "windows")]
+    closed_toasts: std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<Uuid>>>,
+    /// Toast data must arrive with increasing sequence numbers
+    #[cfg(target_os = "windows")]
+    sequence: std::sync::atomic::AtomicU32,
 }
 
 impl NotificationManager {
+    /// Create a manager that posts toasts under the given AppUserModelID
     pub fn new(app_id: impl Into<String>) -> Self {
         Self {
             app_id: app_id.into(),
+            settings: SharedSettings::default(),
+            toast_styles: ToastStyles::default(),
+            activations: None,
+            images: None,
+            locale: None,
+            #[cfg(target_os = "windows")]
+            live_toasts: std::sync::Mutex::new(std::collections::VecDeque::new()),
+            #[cfg(target_os = "windows")]
+            closed_toasts: Default::default(),
+            #[cfg(target_os = "windows")]
+            sequence: std::sync::atomic::AtomicU32::new(1),
         }
     }
 
+    /// Read toast display options from `settings`
+    pub fn with_settings(mut self, settings: SharedSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Present each level's toasts with `styles` unless the alert says otherwise
+    pub fn with_toast_styles(mut self, styles: ToastStyles) -> Self {
+        self.toast_styles = styles;
+        self
+    }
+
+    /// Show each alert's image from `images` once it has downloaded
+    pub fn with_images(mut self, images: Arc<ImageCache>) -> Self {
+        self.images = Some(images);
+        self
+    }
+
+    /// Show alerts in their translation best matching `locale`, when they have one
+    pub fn with_locale(mut self, locale: Option<String>) -> Self {
+        self.locale = locale;
+        self
+    }
+
+    /// Forward clicks on toasts and their buttons to `tx`
+    pub fn with_activation_sender(mut self, tx: mpsc::UnboundedSender<ActivationArgs>) -> Self {
+        self.activations = Some(tx);
+        self
+    }
+
     /// Display a Windows toast notification for the alert
+    #[cfg(target_os = "windows")]
     pub fn show_notification(&self, alert: &Alert) -> Result<()> {
-        let xml: XmlDocument = self.create_toast_xml(alert)?;
+        use crate::error::EmnsError;
+        use windows::{
+            core::{ComInterface, IInspectable, HSTRING},
+            Data::Xml::Dom::XmlDocument,
+            Foundation::TypedEventHandler,
+            UI::Notifications::{
+                ToastActivatedEventArgs, ToastDismissalReason, ToastDismissedEventArgs,
+                ToastNotification, ToastNotificationManager, ToastNotifier,
+            },
+        };
+
+        let fail = |what: &str, e: windows::core::Error| {
+            EmnsError::notification(Some(alert.id), format!("{}: {}", what, e))
+        };
+
+        let style: ToastStyle = self.toast_styles.resolve(alert);
+        let xml = XmlDocument::new().map_err(|e| fail("Failed to create XML document", e))?;
+        xml.LoadXml(&HSTRING::from(self.toast_xml(alert, &style)))
+            .map_err(|e| fail("Failed to load XML", e))?;
+
         let toast: ToastNotification = ToastNotification::CreateToastNotification(&xml)
-            .context("Failed to create toast notification")?;
+            .map_err(|e| fail("Failed to create toast notification", e))?;
+        if style.suppress_popup {
+            toast
+                .SetSuppressPopup(true)
+                .map_err(|e| fail("Failed to suppress popup", e))?;
+        }
+
+        if let Some(tx) = self.activations.clone() {
+            toast
+                .Activated(&TypedEventHandler::<ToastNotification, IInspectable>::new(
+                    move |_, args: &Option<IInspectable>| {
+                        let arguments: Option<HSTRING> = args
+                            .as_ref()
+                            .and_then(|a| a.cast::<ToastActivatedEventArgs>().ok())
+                            .and_then(|a| a.Arguments().ok());
+                        match arguments.as_ref().map(|a| a.to_string()) {
+                            Some(a) => match ActivationArgs::parse(&a) {
+                                Ok(activation) => {
+                                    let _ = tx.send(activation);
+                                }
+                                Err(e) => log::warn!("Ignoring toast activation: {}", e),
+                            },
+                            None => log::warn!("Toast activated without arguments"),
+                        }
+                        Ok(())
+                    },
+                ))
+                .map_err(|e| fail("Failed to register toast activation handler", e))?;
+        }
+
+        // Confirmation-required toasts carry a countdown that is updated by tag
+        if alert.requires_confirmation {
+            let countdown: Option<Countdown> = self
+                .settings
+                .snapshot()
+                .auto_confirm_timeout_for(alert)
+                .map(Countdown::Remaining);
+            toast
+                .SetTag(&HSTRING::from(Self::toast_tag(alert.id)))
+                .map_err(|e| fail("Failed to tag toast", e))?;
+            toast
+                .SetGroup(&HSTRING::from(TOAST_GROUP))
+                .map_err(|e| fail("Failed to tag toast", e))?;
+            toast
+                .SetData(
+                    &self
+                        .binding_data(
+                            &self
+                                .attribution_line(countdown.as_ref())
+                                .unwrap_or_default(),
+                        )
+                        .map_err(|e| fail("Failed to bind countdown", e))?,
+                )
+                .map_err(|e| fail("Failed to bind countdown", e))?;
+
+            // Timed-out toasts move to the Action Center and can still be updated
+            let closed = self.closed_toasts.clone();
+            let alert_id: Uuid = alert.id;
+            toast
+                .Dismissed(&TypedEventHandler::<
+                    ToastNotification,
+                    ToastDismissedEventArgs,
+                >::new(
+                    move |_, args: &Option<ToastDismissedEventArgs>| {
+                        let reason: Option<ToastDismissalReason> =
+                            args.as_ref().and_then(|a| a.Reason().ok());
+                        if reason != Some(ToastDismissalReason::TimedOut) {
+                            let mut closed = closed.lock().unwrap();
+                            if closed.len() >= LIVE_TOAST_LIMIT {
+                                closed.pop_front();
+                            }
+                            closed.push_back(alert_id);
+                        }
+                        Ok(())
+                    },
+                ))
+                .map_err(|e| fail("Failed to register toast dismissal handler", e))?;
+        }
 
-        let notifier: windows::UI::Notifications::ToastNotifier = ToastNotificationManager::CreateToastNotifierWithId(&HSTRING::from(
-            &self.app_id,
-        ))
-        .context("Failed to create toast notifier")?;
+        let notifier: ToastNotifier =
+            ToastNotificationManager::CreateToastNotifierWithId(&HSTRING::from(&self.app_id))
+                .map_err(|e| fail("Failed to create toast notifier", e))?;
 
         notifier
             .Show(&toast)
-            .context("Failed to show notification")?;
+            .map_err(|e| fail("Failed to show notification", e))?;
+
+        // Activation events only arrive while the toast object is alive
+        let mut live = self.live_toasts.lock().unwrap();
+        if live.len() >= LIVE_TOAST_LIMIT {
+            live.pop_front();
+        }
+        live.push_back(toast);
 
         log::info!("Displayed notification for alert {}", alert.id);
         Ok(())
     }
 
-    /// Create the XML template for the toast notification
-    fn create_toast_xml(&self, alert: &Alert) -> Result<XmlDocument> {
-        let (scenario, duration) = match alert.level {
-            AlertLevel::Emergency | AlertLevel::Critical => ("urgent", "long"),
-            AlertLevel::Warning => ("reminder", "long"),
-            AlertLevel::Info => ("default", "short"),
+    /// Rewrite the countdown on an alert's toast, unless the toast is gone
+    #[cfg(target_os = "windows")]
+    pub fn update_countdown(&self, alert_id: Uuid, countdown: &Countdown) -> Result<bool> {
+        self.update_binding(
+            alert_id,
+            self.attribution_line(Some(countdown)).unwrap_or_default(),
+        )
+    }
+
+    /// Say on an alert's toast who acknowledged it, in place of the countdown
+    #[cfg(target_os = "windows")]
+    pub fn show_acknowledged(&self, alert_id: Uuid, acknowledged_by: &[String]) -> Result<bool> {
+        self.update_binding(alert_id, self.acknowledged_line(acknowledged_by))
+    }
+
+    /// Rewrite the countdown binding on an alert's toast to `text`, unless the toast is gone
+    #[cfg(target_os = "windows")]
+    fn update_binding(&self, alert_id: Uuid, text: String) -> Result<bool> {
+        use crate::error::EmnsError;
+        use windows::core::HSTRING;
+        use windows::UI::Notifications::{NotificationUpdateResult, ToastNotificationManager};
+
+        {
+            let mut closed = self.closed_toasts.lock().unwrap();
+            if let Some(index) = closed.iter().position(|id| *id == alert_id) {
+                closed.remove(index);
+                return Ok(false);
+            }
+        }
+
+        let fail = |what: &str, e: windows::core::Error| {
+            EmnsError::notification(Some(alert_id), format!("{}: {}", what, e))
         };
+        let data = self
+            .binding_data(&text)
+            .map_err(|e| fail("Failed to bind countdown", e))?;
+        let result: NotificationUpdateResult =
+            ToastNotificationManager::CreateToastNotifierWithId(&HSTRING::from(&self.app_id))
+                .and_then(|notifier| {
+                    notifier.UpdateWithTagAndGroup(
+                        &data,
+                        &HSTRING::from(Self::toast_tag(alert_id)),
+                        &HSTRING::from(TOAST_GROUP),
+                    )
+                })
+                .map_err(|e| fail("Failed to update toast", e))?;
+        match result {
+            NotificationUpdateResult::Succeeded => Ok(true),
+            NotificationUpdateResult::NotificationNotFound => Ok(false),
+            _ => Err(EmnsError::notification(
+                Some(alert_id),
+                "Windows did not apply the toast update",
+            )),
+        }
+    }
+
+    /// Data for the countdown binding, stamped with the next sequence number
+    #[cfg(target_os = "windows")]
+    fn binding_data(
+        &self,
+        text: &str,
+    ) -> windows::core::Result<windows::UI::Notifications::NotificationData> {
+        use windows::core::HSTRING;
+        use windows::UI::Notifications::NotificationData;
+
+        let data: NotificationData = NotificationData::new()?;
+        data.Values()?
+            .Insert(&HSTRING::from(COUNTDOWN_BINDING), &HSTRING::from(text))?;
+        data.SetSequenceNumber(
+            self.sequence
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        )?;
+        Ok(data)
+    }
+
+    /// Remove an alert's toast from the screen and the Action Center
+    #[cfg(target_os = "windows")]
+    pub fn remove_notification(&self, alert_id: Uuid) -> Result<()> {
+        use crate::error::EmnsError;
+        use windows::core::HSTRING;
+        use windows::UI::Notifications::ToastNotificationManager;
+
+        ToastNotificationManager::History()
+            .and_then(|history| {
+                history.RemoveGroupedTagWithId(
+                    &HSTRING::from(Self::toast_tag(alert_id)),
+                    &HSTRING::from(TOAST_GROUP),
+                    &HSTRING::from(&self.app_id),
+                )
+            })
+            .map_err(|e| {
+                EmnsError::notification(Some(alert_id), format!("Failed to remove toast: {}", e))
+            })?;
+        log::info!("Removed notification for alert {}", alert_id);
+        Ok(())
+    }
+
+    /// Whether Windows will show this agent's toasts, i.e. neither the user
+    /// nor policy has turned them off
+    #[cfg(target_os = "windows")]
+    pub fn toasts_enabled(&self) -> bool {
+        use windows::core::HSTRING;
+        use windows::UI::Notifications::{NotificationSetting, ToastNotificationManager};
+
+        ToastNotificationManager::CreateToastNotifierWithId(&HSTRING::from(&self.app_id))
+            .and_then(|notifier| notifier.Setting())
+            .map(|setting| setting == NotificationSetting::Enabled)
+            .unwrap_or_else(|e| {
+                log::debug!("Failed to read toast setting: {}", e);
+                false
+            })
+    }
+
+    /// Toasts are only available on Windows
+    #[cfg(not(target_os = "windows"))]
+    pub fn toasts_enabled(&self) -> bool {
+        false
+    }
+
+    /// Toast tags are limited to 64 characters, so the hyphenless id is used
+    #[cfg(target_os = "windows")]
+    fn toast_tag(alert_id: Uuid) -> String {
+        alert_id.simple().to_string()
+    }
+
+    /// Toasts are only available on Windows; elsewhere the alert is logged
+    #[cfg(not(target_os = "windows"))]
+    pub fn show_notification(&self, alert: &Alert) -> Result<()> {
+        let alert: &Alert = &self.localize(alert);
+        log::info!(
+            "[{}] {}{} - {}: {}{}",
+            self.app_id,
+            alert.level.as_str(),
+            alert
+                .category
+                .as_deref()
+                .map(|category| format!(" ({})", category))
+                .unwrap_or_default(),
+            Self::display_title(alert),
+            alert.message,
+            self.attribution_line(None)
+                .map(|line| format!(" ({})", line))
+                .unwrap_or_default()
+        );
+        Ok(())
+    }
+
+    /// Countdown line for the toast, flagged once the response is overdue
+    pub fn countdown_text(countdown: &Countdown) -> String {
+        if countdown.is_overdue() {
+            format!("⚠️ {}", countdown.text())
+        } else {
+            countdown.text()
+        }
+    }
+
+    /// The toast's attribution line: the server identity, then any countdown
+    pub fn attribution_line(&self, countdown: Option<&Countdown>) -> Option<String> {
+        let parts: Vec<String> = self
+            .settings
+            .snapshot()
+            .attribution()
+            .into_iter()
+            .chain(countdown.map(Self::countdown_text))
+            .collect();
+        (!parts.is_empty()).then(|| parts.join(" · "))
+    }
+
+    /// Line saying others are already responding, e.g.
+    /// "Acknowledged by J. Smith — response in progress"
+    pub fn acknowledged_text(acknowledged_by: &[String]) -> String {
+        format!(
+            "Acknowledged by {} — response in progress",
+            acknowledged_by.join(", ")
+        )
+    }
+
+    /// The attribution line once the quorum is met: the server identity, then who acknowledged
+    pub fn acknowledged_line(&self, acknowledged_by: &[String]) -> String {
+        self.settings
+            .snapshot()
+            .attribution()
+            .into_iter()
+            .chain(std::iter::once(Self::acknowledged_text(acknowledged_by)))
+            .collect::<Vec<String>>()
+            .join(" · ")
+    }
+
+    /// The title as shown, labelled if the alert is only a preview
+    pub fn display_title(alert: &Alert) -> String {
+        if alert.is_preview {
+            format!("{} {}", PREVIEW_LABEL, alert.title)
+        } else {
+            alert.title.clone()
+        }
+    }
+
+    /// `alert` with its title and message in the translation for the
+    /// manager's locale, if it has one
+    pub fn localize<'a>(&self, alert: &'a Alert) -> Cow<'a, Alert> {
+        let translation = self
+            .locale
+            .as_deref()
+            .and_then(|locale| locale::translation(alert, locale));
+        match translation {
+            Some((_, translation)) => Cow::Owned(Alert {
+                title: translation.title.clone(),
+                message: translation.message.clone(),
+                ..alert.clone()
+            }),
+            None => Cow::Borrowed(alert),
+        }
+    }
+
+    /// Create the XML template for the toast notification
+    pub fn create_toast_xml(&self, alert: &Alert) -> String {
+        self.toast_xml(alert, &self.toast_styles.resolve(alert))
+    }
 
+    fn toast_xml(&self, alert: &Alert, style: &ToastStyle) -> String {
+        let alert: &Alert = &self.localize(alert);
         let icon: &str = match alert.level {
             AlertLevel::Emergency => "⚠️",
             AlertLevel::Critical => "🔴",
@@ -51,42 +715,149 @@ impl NotificationManager {
             AlertLevel::Info => "ℹ️",
         };
 
-        let confirmation_button: &str = if alert.requires_confirmation {
-            r#"<action content="Confirm Receipt" arguments="confirm" activationType="background"/>"#
+        let id_line: String = if self.settings.snapshot().show_alert_id() {
+            format!("<text>Alert ID: {}</text>", alert.id)
+        } else {
+            String::new()
+        };
+
+        // Windows shows one attribution line, so a countdown shares it with
+        // the server identity and is filled in through the toast's data binding
+        let attribution_line: String = if alert.requires_confirmation {
+            format!(
+                r#"<text placement="attribution">{{{}}}</text>"#,
+                COUNTDOWN_BINDING
+            )
+        } else {
+            match self.attribution_line(None) {
+                Some(line) => format!(
+                    r#"<text placement="attribution">{}</text>"#,
+                    Self::escape_xml(&line)
+                ),
+                None => String::new(),
+            }
+        };
+
+        // Action Center groups toasts under their category's header
+        let header: String = match &alert.category {
+            Some(category) => format!(
+                r#"<header id="{}" title="{}" arguments="{}"/>"#,
+                Self::escape_xml(&category.trim().to_lowercase()),
+                Self::escape_xml(category.trim()),
+                Self::escape_xml(&ActivationArgs::new(ToastAction::Details, alert.id).arguments())
+            ),
+            None => String::new(),
+        };
+
+        let open_button: String = match &alert.attachment {
+            Some(_) => Self::action_xml(
+                "Open document",
+                &ActivationArgs::new(ToastAction::OpenAttachment, alert.id),
+            ),
+            None => String::new(),
+        };
+        // The browser opens the page itself, so the agent never sees this click
+        let more_info: Option<&str> = alert.url.as_deref().filter(|url| is_http_url(url));
+        let more_info_button: String = match more_info {
+            Some(url) => format!(
+                r#"<action content="More Info" arguments="{}" activationType="protocol"/>"#,
+                Self::escape_xml(url)
+            ),
+            None => String::new(),
+        };
+        let image: String = match self.images.as_ref().and_then(|i| i.cached(alert.id)) {
+            Some(path) => format!(
+                r#"<image src="{}"/>"#,
+                Self::escape_xml(&path.to_string_lossy())
+            ),
+            None => String::new(),
+        };
+        // Dismiss always has a slot, and so do Open document and More Info when there are any
+        let option_slots: usize = MAX_TOAST_ACTIONS
+            - 1
+            - usize::from(alert.attachment.is_some())
+            - usize::from(more_info.is_some());
+
+        let options: &[ResponseOption] = alert.response_options.as_deref().unwrap_or_default();
+        let confirmation_buttons: String = if !alert.requires_confirmation {
+            String::new()
+        } else if options.is_empty() {
+            Self::action_xml(
+                "Confirm Receipt",
+                &ActivationArgs::new(ToastAction::Confirm, alert.id),
+            )
         } else {
-            ""
+            // Leave room for Dismiss; the details window shows every option
+            if options.len() > option_slots {
+                log::warn!(
+                    "Alert {} has {} response options; the toast shows the first {}",
+                    alert.id,
+                    options.len(),
+                    option_slots
+                );
+            }
+            options
+                .iter()
+                .take(option_slots)
+                .map(|option| {
+                    Self::action_xml(
+                        &option.label,
+                        &ActivationArgs::respond(alert.id, &option.id),
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n        ")
         };
 
-        let xml_string: String = format!(
+        format!(
             r#"<?xml version="1.0" encoding="utf-8"?>
-<toast scenario="{scenario}" duration="{duration}">
+<toast scenario="{scenario}" duration="{duration}" launch="{launch}" activationType="foreground">
+    {header}
     <visual>
         <binding template="ToastGeneric">
             <text>{icon} {title}</text>
             <text>{message}</text>
-            <text>Alert ID: {id}</text>
+            {image}
+            {id_line}
+            {attribution_line}
         </binding>
     </visual>
     <audio src="ms-winsoundevent:Notification.Default" loop="false"/>
     <actions>
-        {confirmation_button}
-        <action content="Dismiss" arguments="dismiss" activationType="background"/>
+        {confirmation_buttons}
+        {open_button}
+        {more_info_button}
+        {dismiss_button}
     </actions>
 </toast>"#,
-            scenario = scenario,
-            duration = duration,
+            scenario = style.scenario.as_str(),
+            duration = style.duration.as_str(),
+            launch =
+                Self::escape_xml(&ActivationArgs::new(ToastAction::Details, alert.id).arguments()),
+            dismiss_button = Self::action_xml(
+                "Dismiss",
+                &ActivationArgs::new(ToastAction::Dismiss, alert.id)
+            ),
+            header = header,
             icon = icon,
-            title = Self::escape_xml(&alert.title),
+            title = Self::escape_xml(&Self::display_title(alert)),
             message = Self::escape_xml(&alert.message),
-            id = alert.id,
-            confirmation_button = confirmation_button
-        );
-
-        let xml = XmlDocument::new().context("Failed to create XML document")?;
-        xml.LoadXml(&HSTRING::from(&xml_string))
-            .context("Failed to load XML")?;
+            id_line = id_line,
+            attribution_line = attribution_line,
+            confirmation_buttons = confirmation_buttons,
+            open_button = open_button,
+            more_info_button = more_info_button,
+            image = image
+        )
+    }
 
-        Ok(xml)
+    /// A button that hands `args` back to the agent without bringing it to the foreground
+    fn action_xml(content: &str, args: &ActivationArgs) -> String {
+        format!(
+            r#"<action content="{}" arguments="{}" activationType="background"/>"#,
+            Self::escape_xml(content),
+            Self::escape_xml(&args.arguments())
+        )
     }
 
     /// Escape XML special characters
@@ -99,10 +870,40 @@ impl NotificationManager {
     }
 }
 
+impl NotificationBackend for NotificationManager {
+    fn show_notification(&self, alert: &Alert) -> Result<()> {
+        NotificationManager::show_notification(self, alert)
+    }
+
+    #[cfg(target_os = "windows")]
+    fn update_countdown(&self, alert_id: Uuid, countdown: &Countdown) -> Result<bool> {
+        NotificationManager::update_countdown(self, alert_id, countdown)
+    }
+
+    #[cfg(target_os = "windows")]
+    fn show_acknowledged(&self, alert_id: Uuid, acknowledged_by: &[String]) -> Result<bool> {
+        NotificationManager::show_acknowledged(self, alert_id, acknowledged_by)
+    }
+
+    #[cfg(target_os = "windows")]
+    fn remove_notification(&self, alert_id: Uuid) -> Result<()> {
+        NotificationManager::remove_notification(self, alert_id)
+    }
+
+    fn available(&self) -> bool {
+        self.toasts_enabled()
+    }
+}
+
 /// Show a simple notification (for testing or status updates)
 pub fn show_simple_notification(title: &str, message: &str) -> Result<()> {
     let manager = NotificationManager::new("NotificationAgent");
-    let alert = Alert {
+    manager.show_notification(&simple_alert(title, message))
+}
+
+/// An informational toast that is not from the server, for status updates
+pub fn simple_alert(title: &str, message: &str) -> Alert {
+    Alert {
         id: uuid::Uuid::new_v4(),
         title: title.to_string(),
         message: message.to_string(),
@@ -110,6 +911,474 @@ pub fn show_simple_notification(title: &str, message: &str) -> Result<()> {
         requires_confirmation: false,
         sound_file: None,
         timestamp: chrono::Utc::now(),
-    };
-    manager.show_notification(&alert)
+        origin: AlertOrigin::Local,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{Confirmation, ConfirmationResponse, ToastOptions};
+    use crate::outbound::OutboundQueue;
+    use crate::test_support::{alert, Confirmations, MockAudio, MockNotifier};
+    use crate::toast_style::{ToastDuration, ToastScenario};
+
+    /// Arguments as they appear in the toast XML
+    fn xml_arguments(action: ToastAction, alert_id: Uuid) -> String {
+        NotificationManager::escape_xml(&ActivationArgs::new(action, alert_id).arguments())
+    }
+
+    fn every_action() -> Vec<ToastAction> {
+        vec![
+            ToastAction::Details,
+            ToastAction::Confirm,
+            ToastAction::Respond(ResponseChoice::Id("safe".to_string())),
+            ToastAction::Respond(ResponseChoice::Id("need help & more=yes/é".to_string())),
+            ToastAction::Respond(ResponseChoice::Position(2)),
+            ToastAction::OpenAttachment,
+            ToastAction::Dismiss,
+        ]
+    }
+
+    #[test]
+    fn test_activation_arguments_round_trip() {
+        let id: Uuid = Uuid::new_v4();
+        for action in every_action() {
+            let args: ActivationArgs = ActivationArgs::new(action, id);
+            assert_eq!(ActivationArgs::parse(&args.arguments()).unwrap(), args);
+        }
+    }
+
+    #[test]
+    fn test_activation_arguments_are_a_query_string() {
+        let id: Uuid = Uuid::new_v4();
+        assert_eq!(
+            ActivationArgs::new(ToastAction::Confirm, id).arguments(),
+            format!("action=confirm&alert={}", id)
+        );
+        assert_eq!(
+            ActivationArgs::respond(id, "safe").arguments(),
+            format!("action=respond&alert={}&option=safe", id)
+        );
+
+        // Keys from a newer agent are ignored, in any order
+        assert_eq!(
+            ActivationArgs::parse(&format!("alert={}&snooze=300&action=dismiss", id)).unwrap(),
+            ActivationArgs::new(ToastAction::Dismiss, id)
+        );
+    }
+
+    #[test]
+    fn test_parses_arguments_from_older_agents() {
+        let id: Uuid = Uuid::new_v4();
+        for (arguments, action) in [
+            ("details", ToastAction::Details),
+            ("confirm", ToastAction::Confirm),
+            ("open", ToastAction::OpenAttachment),
+            ("dismiss", ToastAction::Dismiss),
+        ] {
+            assert_eq!(
+                ActivationArgs::parse(&format!("{}:{}", arguments, id)).unwrap(),
+                ActivationArgs::new(action, id)
+            );
+        }
+        assert_eq!(
+            ActivationArgs::parse(&format!("respond:{}:1", id)).unwrap(),
+            ActivationArgs::new(ToastAction::Respond(ResponseChoice::Position(1)), id)
+        );
+    }
+
+    #[test]
+    fn test_rejects_malformed_activation_arguments() {
+        let id: Uuid = Uuid::new_v4();
+        for arguments in [
+            String::new(),
+            "confirm".to_string(),
+            "dismiss".to_string(),
+            "details:not-a-uuid".to_string(),
+            format!("respond:{}", id),
+            format!("respond:{}:safe", id),
+            format!("snooze:{}", id),
+            "action=confirm".to_string(),
+            "action=confirm&alert=not-a-uuid".to_string(),
+            format!("alert={}", id),
+            format!("action=snooze&alert={}", id),
+            format!("action=respond&alert={}", id),
+            format!("action=respond&alert={}&option=", id),
+            format!("action=respond&alert={}&position=first", id),
+        ] {
+            assert!(
+                matches!(
+                    ActivationArgs::parse(&arguments),
+                    Err(EmnsError::Activation { .. })
+                ),
+                "accepted {:?}",
+                arguments
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_rejects_stale_arguments() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let confirmations: Confirmations = Confirmations::new(&outbound);
+        let handler: Arc<AlertHandler> = Arc::new(
+            AlertHandler::builder(outbound, "test-client")
+                .notification_backend(Arc::new(MockNotifier::default()))
+                .audio_backend(Arc::new(MockAudio::default()))
+                .build(),
+        );
+        let cancel: CancellationToken = CancellationToken::new();
+        let tracker: TaskTracker = TaskTracker::new();
+
+        // Nothing is known about this alert; a dismissal is harmless, and an
+        // unopenable document gets a toast of its own
+        let unknown: Uuid = Uuid::new_v4();
+        for action in every_action() {
+            let harmless: bool =
+                matches!(action, ToastAction::Dismiss | ToastAction::OpenAttachment);
+            let result = dispatch_activation(
+                &handler,
+                ActivationArgs::new(action, unknown),
+                &cancel,
+                &tracker,
+            )
+            .await;
+            match result {
+                Ok(()) => assert!(harmless),
+                Err(EmnsError::StaleAlert { alert_id }) => assert_eq!(alert_id, unknown),
+                Err(e) => panic!("unexpected error {}", e),
+            }
+        }
+
+        // A response is matched by option id, so reordering cannot change the answer
+        let mut pending: Alert = alert(AlertLevel::Critical, true);
+        pending.response_options = Some(vec![
+            ResponseOption {
+                id: "safe".to_string(),
+                label: "Safe".to_string(),
+                response: ConfirmationResponse::Acknowledged,
+            },
+            ResponseOption {
+                id: "off-site".to_string(),
+                label: "Cannot comply".to_string(),
+                response: ConfirmationResponse::CannotComply {
+                    note: Some("Off site".to_string()),
+                },
+            },
+        ]);
+        handler.handle_alert(pending.clone()).await.unwrap();
+        let wrong_option = ActivationArgs::respond(pending.id, "evacuated");
+        assert!(matches!(
+            dispatch_activation(&handler, wrong_option, &cancel, &tracker).await,
+            Err(EmnsError::Notification { .. })
+        ));
+        let answer = ActivationArgs::respond(pending.id, "off-site");
+        dispatch_activation(&handler, answer.clone(), &cancel, &tracker)
+            .await
+            .unwrap();
+        let confirmation: Confirmation = confirmations.recv().await;
+        assert_eq!(confirmation.response_id.as_deref(), Some("off-site"));
+        assert_eq!(
+            confirmation.response,
+            ConfirmationResponse::CannotComply {
+                note: Some("Off site".to_string())
+            }
+        );
+
+        // A second click on the same toast arrives after the alert was answered
+        assert!(matches!(
+            dispatch_activation(&handler, answer, &cancel, &tracker).await,
+            Err(EmnsError::StaleAlert { .. })
+        ));
+
+        tracker.close();
+        tracker.wait().await;
+    }
+
+    #[test]
+    fn test_toast_body_opens_details() {
+        let alert = alert(AlertLevel::Critical, true);
+        let xml: String = NotificationManager::new("test").create_toast_xml(&alert);
+
+        assert!(xml.contains(&format!(
+            r#"launch="{}" activationType="foreground""#,
+            xml_arguments(ToastAction::Details, alert.id)
+        )));
+        assert!(xml.contains(&format!(
+            r#"arguments="{}""#,
+            xml_arguments(ToastAction::Confirm, alert.id)
+        )));
+        assert!(xml.contains(&format!(
+            r#"arguments="{}""#,
+            xml_arguments(ToastAction::Dismiss, alert.id)
+        )));
+        // Windows unescapes the attribute before handing it back
+        assert!(xml.contains(&format!("action=confirm&amp;alert={}", alert.id)));
+    }
+
+    #[test]
+    fn test_response_options_replace_confirm_button() {
+        let mut alert = alert(AlertLevel::Emergency, true);
+        alert.response_options = Some(vec![
+            ResponseOption {
+                id: "safe".to_string(),
+                label: "Safe".to_string(),
+                response: ConfirmationResponse::Acknowledged,
+            },
+            ResponseOption {
+                id: "help".to_string(),
+                label: "Need <assistance>".to_string(),
+                response: ConfirmationResponse::Acknowledged,
+            },
+        ]);
+        let xml: String = NotificationManager::new("test").create_toast_xml(&alert);
+
+        let respond = |option: &str| {
+            NotificationManager::escape_xml(&ActivationArgs::respond(alert.id, option).arguments())
+        };
+        assert!(!xml.contains("action=confirm"));
+        assert!(xml.contains(&format!(
+            r#"<action content="Safe" arguments="{}""#,
+            respond("safe")
+        )));
+        assert!(xml.contains(&format!(
+            r#"<action content="Need &lt;assistance&gt;" arguments="{}""#,
+            respond("help")
+        )));
+        assert!(xml.contains(&format!(
+            r#"arguments="{}""#,
+            xml_arguments(ToastAction::Dismiss, alert.id)
+        )));
+    }
+
+    #[test]
+    fn test_toast_buttons_stay_within_windows_limit() {
+        let mut alert = alert(AlertLevel::Critical, true);
+        alert.response_options = Some(
+            (0..7)
+                .map(|i| ResponseOption {
+                    id: format!("option-{}", i),
+                    label: format!("Option {}", i),
+                    response: ConfirmationResponse::Acknowledged,
+                })
+                .collect(),
+        );
+        let xml: String = NotificationManager::new("test").create_toast_xml(&alert);
+
+        assert_eq!(xml.matches("<action ").count(), MAX_TOAST_ACTIONS);
+        assert!(xml.contains("action=dismiss"));
+    }
+
+    #[test]
+    fn test_attachment_adds_open_button() {
+        let mut alert = alert(AlertLevel::Emergency, true);
+        let plain: String = NotificationManager::new("test").create_toast_xml(&alert);
+        assert!(!plain.contains("Open document"));
+
+        alert.attachment = Some(crate::messages::Attachment {
+            url: "https://emns.example/procedure.pdf".to_string(),
+            filename: "procedure.pdf".to_string(),
+            sha256: "00".repeat(32),
+            size: 1024,
+        });
+        alert.response_options = Some(
+            (0..7)
+                .map(|i| ResponseOption {
+                    id: format!("option-{}", i),
+                    label: format!("Option {}", i),
+                    response: ConfirmationResponse::Acknowledged,
+                })
+                .collect(),
+        );
+        let xml: String = NotificationManager::new("test").create_toast_xml(&alert);
+        assert!(xml.contains(&format!(
+            r#"<action content="Open document" arguments="{}""#,
+            xml_arguments(ToastAction::OpenAttachment, alert.id)
+        )));
+        assert_eq!(xml.matches("<action ").count(), MAX_TOAST_ACTIONS);
+        assert!(xml.contains("action=dismiss"));
+    }
+
+    #[tokio::test]
+    async fn test_url_adds_more_info_button_and_image_once_cached() {
+        use crate::images::{ImageCache, ImageConfig};
+        use axum::http::header::CONTENT_TYPE;
+
+        let app = axum::Router::new().route(
+            "/radar.gif",
+            axum::routing::get(|| async { ([(CONTENT_TYPE, "image/gif")], "GIF89a radar") }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: std::net::SocketAddr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let dir: std::path::PathBuf =
+            std::env::temp_dir().join(format!("emns-toast-image-{}", Uuid::new_v4()));
+        let images: Arc<ImageCache> = Arc::new(ImageCache::new(&dir, &ImageConfig::default()));
+        let manager: NotificationManager =
+            NotificationManager::new("test").with_images(images.clone());
+
+        let mut alert = alert(AlertLevel::Warning, false);
+        alert.url = Some("https://wx.example.com/warnings?id=7&lang=en".to_string());
+        alert.image_url = Some(format!("http://{}/radar.gif", addr));
+        let xml: String = manager.create_toast_xml(&alert);
+        assert!(xml.contains(
+            r#"<action content="More Info" arguments="https://wx.example.com/warnings?id=7&amp;lang=en" activationType="protocol"/>"#
+        ));
+        assert!(!xml.contains("<image "));
+
+        let path = images
+            .fetch(alert.id, alert.image_url.as_deref().unwrap())
+            .await
+            .unwrap();
+        let xml: String = manager.create_toast_xml(&alert);
+        assert!(xml.contains(&format!(r#"<image src="{}"/>"#, path.display())));
+
+        // Only web pages are offered, never another scheme's handler
+        alert.url = Some("ms-settings:privacy".to_string());
+        assert!(!manager.create_toast_xml(&alert).contains("More Info"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_attribution_line_shows_server_identity() {
+        let settings: SharedSettings = SharedSettings::default();
+        let manager: NotificationManager =
+            NotificationManager::new("test").with_settings(settings.clone());
+        let info: Alert = alert(AlertLevel::Info, false);
+
+        // No identity configured: no attribution line
+        assert!(!manager
+            .create_toast_xml(&info)
+            .contains(r#"placement="attribution""#));
+
+        // Later alerts pick up a changed identity, escaped for XML
+        settings
+            .update(|s| {
+                s.set_server_identity(Some("R&D <EMNS>".to_string()), Some("test".to_string()));
+                Ok(())
+            })
+            .unwrap();
+        assert!(manager
+            .create_toast_xml(&info)
+            .contains(r#"<text placement="attribution">R&amp;D &lt;EMNS&gt; — TEST</text>"#));
+
+        // Alerts awaiting confirmation bind the line, which also carries the countdown
+        let pending: Alert = alert(AlertLevel::Critical, true);
+        assert!(manager
+            .create_toast_xml(&pending)
+            .contains(r#"<text placement="attribution">{countdownText}</text>"#));
+        let countdown: Countdown = Countdown::Remaining(std::time::Duration::from_secs(272));
+        assert_eq!(
+            manager.attribution_line(Some(&countdown)),
+            Some(format!(
+                "R&D <EMNS> — TEST · {}",
+                NotificationManager::countdown_text(&countdown)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_category_heads_the_toast() {
+        let manager: NotificationManager = NotificationManager::new("test");
+        let mut weather: Alert = alert(AlertLevel::Warning, false);
+        assert!(!manager.create_toast_xml(&weather).contains("<header"));
+
+        weather.category = Some("Weather & Sea".to_string());
+        let xml: String = manager.create_toast_xml(&weather);
+        assert!(xml.contains(&format!(
+            r#"<header id="weather &amp; sea" title="Weather &amp; Sea" arguments="action=details&amp;alert={}"/>"#,
+            weather.id
+        )));
+        assert!(xml.find("<header").unwrap() < xml.find("<visual>").unwrap());
+    }
+
+    #[test]
+    fn test_toast_shows_the_translation_for_the_locale() {
+        let mut translated: Alert = alert(AlertLevel::Warning, false);
+        translated.translations.insert(
+            "fr".to_string(),
+            crate::messages::Translation {
+                title: "Alerte météo".to_string(),
+                message: "Restez à l'intérieur".to_string(),
+            },
+        );
+        let english: String = NotificationManager::new("test").create_toast_xml(&translated);
+        assert!(english.contains(&translated.title));
+
+        let french: String = NotificationManager::new("test")
+            .with_locale(Some("fr-CA".to_string()))
+            .create_toast_xml(&translated);
+        assert!(french.contains("Alerte météo"));
+        assert!(french.contains("Restez à l&apos;intérieur"));
+        assert!(!french.contains(&translated.title));
+    }
+
+    #[test]
+    fn test_toast_scenario_follows_level_defaults_then_alert() {
+        let styles: ToastStyles = ToastStyles {
+            warning: ToastStyle::new(ToastScenario::Default, ToastDuration::Short),
+            emergency: ToastStyle::new(ToastScenario::IncomingCall, ToastDuration::Long),
+            ..ToastStyles::default()
+        };
+        let manager: NotificationManager =
+            NotificationManager::new("test").with_toast_styles(styles);
+        let toast_tag = |alert: &Alert| {
+            let xml: String = manager.create_toast_xml(alert);
+            xml.lines()
+                .find(|line| line.starts_with("<toast "))
+                .unwrap()
+                .split(" launch=")
+                .next()
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(
+            toast_tag(&alert(AlertLevel::Warning, false)),
+            r#"<toast scenario="default" duration="short""#
+        );
+        assert_eq!(
+            toast_tag(&alert(AlertLevel::Emergency, true)),
+            r#"<toast scenario="incomingCall" duration="long""#
+        );
+        // Levels left alone keep the built-in mapping
+        assert_eq!(
+            toast_tag(&alert(AlertLevel::Critical, true)),
+            r#"<toast scenario="urgent" duration="long""#
+        );
+
+        // The alert's block wins field by field, and unknown values fall back
+        let mut overridden = alert(AlertLevel::Emergency, true);
+        overridden.toast = Some(ToastOptions {
+            scenario: Some("alarm".to_string()),
+            ..ToastOptions::default()
+        });
+        assert_eq!(
+            toast_tag(&overridden),
+            r#"<toast scenario="alarm" duration="long""#
+        );
+        overridden.toast = Some(ToastOptions {
+            scenario: Some("siren".to_string()),
+            duration: Some("short".to_string()),
+            suppress_popup: None,
+        });
+        assert_eq!(
+            toast_tag(&overridden),
+            r#"<toast scenario="incomingCall" duration="short""#
+        );
+    }
+
+    #[test]
+    fn test_preview_toast_is_labelled() {
+        let mut alert = alert(AlertLevel::Warning, false);
+        alert.is_preview = true;
+        let xml: String = NotificationManager::new("test").create_toast_xml(&alert);
+
+        assert!(xml.contains(&format!(
+            "<text>⚡ {} {}</text>",
+            PREVIEW_LABEL, alert.title
+        )));
+    }
 }
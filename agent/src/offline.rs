@@ -0,0 +1,671 @@
+//! Carrying confirmations and delivery records to the server on removable media.
+//!
+//! At sites with no network path to the server, the agent moves what it
+//! cannot send into a spool file once it has been offline long enough. The
+//! `export-offline` command signs everything spooled since the last export,
+//! with the alerts received in that time, into a bundle the server imports.
+
+use crate::client::ConnectionState;
+use crate::error::{EmnsError, Result};
+use crate::history::{self, HistoryEntry, HISTORY_FILE};
+use crate::messages::{OfflineAlert, OfflineBundle, OfflineRecord, SignedBundle};
+use crate::multicast::{HmacSha256, SigningKey};
+use crate::outbound::{OutboundMessage, OutboundQueue};
+use crate::sealed;
+use crate::storage::{self, LineProtector};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Default time without a server connection before messages are spooled for export
+pub const DEFAULT_OFFLINE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// File name of the spool inside the data directory
+pub const SPOOL_FILE: &str = "offline-spool.jsonl";
+
+/// File name of the record of the last export inside the data directory
+pub const EXPORT_STATE_FILE: &str = "offline-export.json";
+
+/// Longest the spooler waits between looks at the connection
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// When messages go to the spool instead of waiting for the server, and how bundles are signed
+#[derive(Debug, Clone)]
+pub struct OfflineConfig {
+    /// Time without a server connection before spooling starts
+    pub after: Duration,
+    /// Shared with the server, which refuses bundles it cannot verify
+    pub key: SigningKey,
+}
+
+impl OfflineConfig {
+    pub fn new(key: SigningKey) -> Self {
+        Self {
+            after: DEFAULT_OFFLINE_AFTER,
+            key,
+        }
+    }
+
+    fn check_interval(&self) -> Duration {
+        (self.after / 4).clamp(Duration::from_millis(10), MAX_CHECK_INTERVAL)
+    }
+}
+
+/// A spool line: a record and its place in the spool
+#[derive(Debug, Serialize, Deserialize)]
+struct SpooledRecord {
+    seq: u64,
+    record: OfflineRecord,
+}
+
+/// What the previous export covered; written only by [`export`]
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ExportState {
+    /// Sequence number of the last bundle
+    sequence: u64,
+    /// Highest spool `seq` included in a bundle
+    last_record: u64,
+    exported_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl ExportState {
+    fn load(data_dir: &Path) -> Result<Self> {
+        let path: PathBuf = data_dir.join(EXPORT_STATE_FILE);
+        match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| EmnsError::storage(Some(&path), format!("unreadable: {}", e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(EmnsError::storage(Some(&path), e)),
+        }
+    }
+
+    fn save(&self, data_dir: &Path) -> Result<()> {
+        let path: PathBuf = data_dir.join(EXPORT_STATE_FILE);
+        storage::write_private_file(&path, &serde_json::to_vec_pretty(self)?)
+            .map_err(|e| EmnsError::storage(Some(&path), e))
+    }
+}
+
+/// Records in a spool file, skipping unreadable lines, and whether any line
+/// was left unprotected
+fn read_spool(path: &Path, protector: &LineProtector) -> Result<(Vec<SpooledRecord>, bool)> {
+    let file: File = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), false)),
+        Err(e) => return Err(EmnsError::storage(Some(path), e)),
+    };
+    let mut records: Vec<SpooledRecord> = Vec::new();
+    let mut unprotected: bool = false;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line: String = line.map_err(|e| EmnsError::storage(Some(path), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        unprotected |= protector.needs_migration(&line);
+        let parsed: Result<SpooledRecord> = protector
+            .unprotect(&line)
+            .and_then(|line| Ok(serde_json::from_str::<SpooledRecord>(&line)?));
+        match parsed {
+            Ok(record) => records.push(record),
+            Err(e) => log::warn!(
+                "Skipping unreadable spool line {} in {}: {}",
+                number + 1,
+                path.display(),
+                e
+            ),
+        }
+    }
+    Ok((records, unprotected))
+}
+
+/// The line written to a spool for `record`
+fn spool_line(record: &SpooledRecord, protector: &LineProtector) -> Result<String> {
+    protector.protect(&serde_json::to_string(record)?)
+}
+
+/// Messages held for export, appended to a JSON-lines file in the data
+/// directory, each line protected on Windows
+pub struct OfflineSpool {
+    path: PathBuf,
+    /// The open file and the `seq` the next record gets
+    file: Mutex<(File, u64)>,
+    protector: LineProtector,
+}
+
+impl OfflineSpool {
+    /// Open the spool in `data_dir`, first dropping records an export has already taken
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        Self::open_protected(data_dir, LineProtector::default())
+    }
+
+    /// As [`open`](Self::open), with each line protected by `protector`.
+    ///
+    /// A spool holding plaintext lines left by older agents is rewritten
+    /// protected straight away.
+    pub fn open_protected(data_dir: &Path, protector: LineProtector) -> Result<Self> {
+        std::fs::create_dir_all(data_dir).map_err(|e| EmnsError::storage(Some(data_dir), e))?;
+        let path: PathBuf = data_dir.join(SPOOL_FILE);
+        let exported: u64 = ExportState::load(data_dir)?.last_record;
+        let (records, unprotected) = read_spool(&path, &protector)?;
+        let next: u64 = records
+            .iter()
+            .map(|r| r.seq)
+            .max()
+            .unwrap_or(0)
+            .max(exported)
+            + 1;
+
+        let unexported: Vec<&SpooledRecord> = records.iter().filter(|r| r.seq > exported).collect();
+        if unexported.len() < records.len() || unprotected {
+            let mut compacted: String = String::new();
+            for record in unexported {
+                compacted.push_str(&spool_line(record, &protector)?);
+                compacted.push('\n');
+            }
+            let temp: PathBuf = path.with_extension("jsonl.tmp");
+            storage::write_private_file(&temp, compacted.as_bytes())
+                .and_then(|()| std::fs::rename(&temp, &path))
+                .map_err(|e| EmnsError::storage(Some(&path), e))?;
+        }
+
+        let file: File =
+            storage::open_private_append(&path).map_err(|e| EmnsError::storage(Some(&path), e))?;
+        Ok(Self {
+            path,
+            file: Mutex::new((file, next)),
+            protector,
+        })
+    }
+
+    /// Add a record for the next export
+    pub fn append(&self, record: OfflineRecord) -> Result<()> {
+        let mut guard = self.file.lock().unwrap();
+        let (file, next) = &mut *guard;
+        let mut line: String = spool_line(&SpooledRecord { seq: *next, record }, &self.protector)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())
+            .and_then(|()| file.flush())
+            .map_err(|e| EmnsError::storage(Some(&self.path), e))?;
+        *next += 1;
+        Ok(())
+    }
+}
+
+/// The spooled form of a message, if it is one the server needs however late
+fn offline_record(message: OutboundMessage) -> Option<OfflineRecord> {
+    match message {
+        OutboundMessage::Confirmation(confirmation) => {
+            Some(OfflineRecord::Confirmation { confirmation })
+        }
+        OutboundMessage::DeliveryStatus(status) => Some(OfflineRecord::DeliveryStatus { status }),
+        _ => None,
+    }
+}
+
+/// Move confirmations and delivery reports from `outbound` to `spool` whenever the
+/// server has been unreachable for `config.after`, until `cancel` fires.
+///
+/// `connection` follows the client's connection. Messages already spooled stay
+/// there when the connection returns; they reach the server in a bundle.
+pub async fn run_spooler(
+    spool: Arc<OfflineSpool>,
+    outbound: Arc<OutboundQueue>,
+    connection: watch::Receiver<ConnectionState>,
+    config: OfflineConfig,
+    cancel: CancellationToken,
+) {
+    let mut offline_since: Option<Instant> = None;
+    let mut spooling: bool = false;
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = tokio::time::sleep(config.check_interval()) => {}
+        }
+        if connection.borrow().is_connected() {
+            if spooling {
+                log::info!("Server reachable again; no longer spooling for offline export");
+            }
+            offline_since = None;
+            spooling = false;
+            continue;
+        }
+        let since: Instant = *offline_since.get_or_insert_with(Instant::now);
+        if since.elapsed() < config.after {
+            continue;
+        }
+        if !spooling {
+            log::warn!(
+                "No server for {}s; spooling confirmations and delivery reports for offline export",
+                since.elapsed().as_secs()
+            );
+            spooling = true;
+        }
+
+        let taken: Vec<OutboundMessage> = outbound.take_matching(|message| {
+            matches!(
+                message,
+                OutboundMessage::Confirmation(_) | OutboundMessage::DeliveryStatus(_)
+            )
+        });
+        for message in taken {
+            let Some(record) = offline_record(message.clone()) else {
+                continue;
+            };
+            if let Err(e) = spool.append(record) {
+                log::error!("Failed to spool record for offline export: {}", e);
+                // Back to the queue, so it is sent if the server returns
+                outbound.requeue(message);
+            }
+        }
+    }
+    log::debug!("Offline spooler stopped");
+}
+
+/// Sign `bundle` with `key`
+pub fn seal(bundle: &OfflineBundle, key: &SigningKey) -> Result<SignedBundle> {
+    let payload: String = serde_json::to_string(bundle)?;
+    let mut mac: HmacSha256 = key.mac();
+    mac.update(payload.as_bytes());
+    Ok(SignedBundle {
+        signature: BASE64.encode(mac.finalize().into_bytes()),
+        payload,
+    })
+}
+
+/// Verify a signed bundle and return it, as the server does before importing.
+///
+/// `last_imported` is the highest sequence already imported from the bundle's
+/// agent; a bundle at or below it is a replay and is refused.
+pub fn open_bundle(
+    signed: &[u8],
+    key: &SigningKey,
+    last_imported: Option<u64>,
+) -> Result<OfflineBundle> {
+    let signed: SignedBundle = serde_json::from_slice(signed)?;
+    let signature: Vec<u8> = BASE64
+        .decode(signed.signature.as_bytes())
+        .map_err(|e| EmnsError::protocol(format!("malformed bundle signature: {}", e)))?;
+    let mut mac: HmacSha256 = key.mac();
+    mac.update(signed.payload.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| EmnsError::protocol("bundle signature does not match"))?;
+
+    let bundle: OfflineBundle = serde_json::from_str(&signed.payload)?;
+    if let Some(last) = last_imported.filter(|last| bundle.sequence <= *last) {
+        return Err(EmnsError::protocol(format!(
+            "bundle {} from {} is already imported (last was {})",
+            bundle.sequence, bundle.client_id, last
+        )));
+    }
+    Ok(bundle)
+}
+
+/// Alerts in the history received after `since`, once each
+fn alerts_since(
+    entries: Vec<HistoryEntry>,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+) -> Vec<OfflineAlert> {
+    let mut seen: HashSet<uuid::Uuid> = HashSet::new();
+    entries
+        .into_iter()
+        .filter(|entry| since.is_none_or(|since| entry.received_at > since))
+        .filter(|entry| seen.insert(entry.alert_id))
+        .map(|entry| OfflineAlert {
+            alert_id: entry.alert_id,
+            level: entry.level,
+            // The server was never meant to read a sealed alert's text
+            title: if entry.sealed {
+                sealed::UNREADABLE_TITLE.to_string()
+            } else {
+                entry.title
+            },
+            sent_at: entry.sent_at,
+            received_at: entry.received_at,
+            requires_confirmation: entry.requires_confirmation,
+            origin: entry.origin,
+        })
+        .collect()
+}
+
+/// Write a signed bundle of everything in `data_dir` since the last export to `out`.
+///
+/// The export is recorded only once the bundle is written, so a failed export
+/// can be repeated. The running agent drops exported records from its spool
+/// the next time it starts.
+///
+/// `protector` is the one the agent's spool and history are written with.
+pub fn export(
+    data_dir: &Path,
+    client_id: &str,
+    hostname: &str,
+    key: &SigningKey,
+    protector: &LineProtector,
+    out: &Path,
+) -> Result<OfflineBundle> {
+    let state: ExportState = ExportState::load(data_dir)?;
+    let spooled: Vec<SpooledRecord> = read_spool(&data_dir.join(SPOOL_FILE), protector)?
+        .0
+        .into_iter()
+        .filter(|r| r.seq > state.last_record)
+        .collect();
+    let last_record: u64 = spooled
+        .iter()
+        .map(|r| r.seq)
+        .max()
+        .unwrap_or(state.last_record);
+    let exported_at: chrono::DateTime<chrono::Utc> = chrono::Utc::now();
+
+    let bundle: OfflineBundle = OfflineBundle {
+        client_id: client_id.to_string(),
+        hostname: hostname.to_string(),
+        sequence: state.sequence + 1,
+        exported_at,
+        since: state.exported_at,
+        records: spooled.into_iter().map(|r| r.record).collect(),
+        alerts: alerts_since(
+            history::read_entries(&data_dir.join(HISTORY_FILE), protector)?,
+            state.exported_at,
+        ),
+    };
+    let signed: SignedBundle = seal(&bundle, key)?;
+    std::fs::write(out, serde_json::to_vec_pretty(&signed)?)
+        .map_err(|e| EmnsError::storage(Some(out), e))?;
+
+    ExportState {
+        sequence: bundle.sequence,
+        last_record,
+        exported_at: Some(exported_at),
+    }
+    .save(data_dir)?;
+    Ok(bundle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::AlertHistory;
+    use crate::messages::{
+        Alert, AlertErrorReason, AlertLevel, Confirmation, ConfirmationReason,
+        ConfirmationResponse, DeliveryStatus,
+    };
+    use crate::sanitize::{self, TextLimits};
+    use crate::test_support::XorProtector;
+
+    fn key() -> SigningKey {
+        SigningKey::new("site-offline-secret")
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("emns-offline-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn confirmation(alert_id: uuid::Uuid) -> Confirmation {
+        Confirmation {
+            alert_id,
+            client_id: "airgap-01".to_string(),
+            confirmed_at: chrono::Utc::now(),
+            hostname: "h".to_string(),
+            username: "u".to_string(),
+            operator_id: None,
+            reason: ConfirmationReason::User,
+            method: None,
+            user_idle_secs: None,
+            response_id: None,
+            response: ConfirmationResponse::Acknowledged,
+            received_via: Default::default(),
+            shown_at: None,
+            response_latency_ms: None,
+            is_preview: false,
+        }
+    }
+
+    fn record_alert(history: &AlertHistory, title: &str) -> uuid::Uuid {
+        let mut alert: Alert = Alert {
+            id: uuid::Uuid::new_v4(),
+            title: title.to_string(),
+            message: "Offline test".to_string(),
+            level: AlertLevel::Critical,
+            requires_confirmation: true,
+            sound_file: None,
+            timestamp: chrono::Utc::now(),
+            ..Default::default()
+        };
+        let report = sanitize::sanitize_alert(&mut alert, &TextLimits::default());
+        history.record(HistoryEntry::new(&alert, &report));
+        alert.id
+    }
+
+    fn confirmed_ids(bundle: &OfflineBundle) -> Vec<uuid::Uuid> {
+        bundle
+            .records
+            .iter()
+            .filter_map(|record| match record {
+                OfflineRecord::Confirmation { confirmation } => Some(confirmation.alert_id),
+                OfflineRecord::DeliveryStatus { .. } => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_export_round_trips_and_next_export_has_only_new_records() {
+        let dir: PathBuf = temp_dir();
+        let history: AlertHistory = AlertHistory::open(dir.join(HISTORY_FILE)).unwrap();
+        let first: uuid::Uuid = record_alert(&history, "First");
+        let spool: OfflineSpool = OfflineSpool::open(&dir).unwrap();
+        spool
+            .append(OfflineRecord::Confirmation {
+                confirmation: confirmation(first),
+            })
+            .unwrap();
+
+        let out: PathBuf = dir.join("bundle-1.json");
+        let exported: OfflineBundle = export(
+            &dir,
+            "airgap-01",
+            "h",
+            &key(),
+            &LineProtector::default(),
+            &out,
+        )
+        .unwrap();
+        let opened: OfflineBundle =
+            open_bundle(&std::fs::read(&out).unwrap(), &key(), None).unwrap();
+        assert_eq!(opened.sequence, 1);
+        assert_eq!(opened.since, None);
+        assert_eq!(confirmed_ids(&opened), [first]);
+        assert_eq!(opened.alerts.len(), 1);
+        assert_eq!(opened.alerts[0].alert_id, first);
+        assert_eq!(opened.alerts, exported.alerts);
+
+        let second: uuid::Uuid = record_alert(&history, "Second");
+        spool
+            .append(OfflineRecord::Confirmation {
+                confirmation: confirmation(second),
+            })
+            .unwrap();
+        let out: PathBuf = dir.join("bundle-2.json");
+        export(
+            &dir,
+            "airgap-01",
+            "h",
+            &key(),
+            &LineProtector::default(),
+            &out,
+        )
+        .unwrap();
+        let opened: OfflineBundle =
+            open_bundle(&std::fs::read(&out).unwrap(), &key(), Some(1)).unwrap();
+        assert_eq!(opened.sequence, 2);
+        assert!(opened.since.is_some());
+        assert_eq!(confirmed_ids(&opened), [second]);
+        assert_eq!(
+            opened.alerts.iter().map(|a| a.alert_id).collect::<Vec<_>>(),
+            [second]
+        );
+
+        // Reopening drops what was exported and keeps numbering after it
+        drop(spool);
+        let spool: OfflineSpool = OfflineSpool::open(&dir).unwrap();
+        assert!(read_spool(&dir.join(SPOOL_FILE), &LineProtector::default())
+            .unwrap()
+            .0
+            .is_empty());
+        spool
+            .append(OfflineRecord::Confirmation {
+                confirmation: confirmation(first),
+            })
+            .unwrap();
+        assert_eq!(
+            read_spool(&dir.join(SPOOL_FILE), &LineProtector::default())
+                .unwrap()
+                .0[0]
+                .seq,
+            3
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_protected_spool_migrates_plaintext_and_still_exports() {
+        let dir: PathBuf = temp_dir();
+        let protector: LineProtector = LineProtector::new(Arc::new(XorProtector));
+        let history: AlertHistory =
+            AlertHistory::open_protected(dir.join(HISTORY_FILE), protector.clone()).unwrap();
+        let legacy: uuid::Uuid = record_alert(&history, "Legacy");
+        let recorded: uuid::Uuid = record_alert(&history, "Recorded");
+        OfflineSpool::open(&dir)
+            .unwrap()
+            .append(OfflineRecord::Confirmation {
+                confirmation: confirmation(legacy),
+            })
+            .unwrap();
+
+        let spool: OfflineSpool = OfflineSpool::open_protected(&dir, protector.clone()).unwrap();
+        spool
+            .append(OfflineRecord::Confirmation {
+                confirmation: confirmation(recorded),
+            })
+            .unwrap();
+        let on_disk: String = std::fs::read_to_string(dir.join(SPOOL_FILE)).unwrap();
+        assert_eq!(on_disk.lines().count(), 2);
+        assert!(!on_disk.contains("airgap-01") && !on_disk.contains('{'));
+
+        let out: PathBuf = dir.join("bundle-1.json");
+        let exported: OfflineBundle =
+            export(&dir, "airgap-01", "h", &key(), &protector, &out).unwrap();
+        assert_eq!(confirmed_ids(&exported), [legacy, recorded]);
+        assert_eq!(exported.alerts.len(), 2);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_tampered_and_replayed_bundles_are_refused() {
+        let bundle: OfflineBundle = OfflineBundle {
+            client_id: "airgap-01".to_string(),
+            hostname: "h".to_string(),
+            sequence: 4,
+            exported_at: chrono::Utc::now(),
+            since: None,
+            records: vec![OfflineRecord::Confirmation {
+                confirmation: confirmation(uuid::Uuid::new_v4()),
+            }],
+            alerts: Vec::new(),
+        };
+        let signed: SignedBundle = seal(&bundle, &key()).unwrap();
+        let bytes: Vec<u8> = serde_json::to_vec(&signed).unwrap();
+        assert!(open_bundle(&bytes, &key(), Some(3)).is_ok());
+
+        // Importing the same bundle again, or an older one, is a replay
+        assert!(open_bundle(&bytes, &key(), Some(4)).is_err());
+        assert!(open_bundle(&bytes, &key(), Some(9)).is_err());
+
+        let tampered: SignedBundle = SignedBundle {
+            payload: signed.payload.replace("\"sequence\":4", "\"sequence\":40"),
+            ..signed.clone()
+        };
+        assert_ne!(tampered.payload, signed.payload);
+        let bytes: Vec<u8> = serde_json::to_vec(&tampered).unwrap();
+        assert!(open_bundle(&bytes, &key(), None).is_err());
+
+        let bytes: Vec<u8> = serde_json::to_vec(&signed).unwrap();
+        assert!(open_bundle(&bytes, &SigningKey::new("other-secret"), None).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_spooler_takes_reports_only_after_being_offline_long_enough() {
+        let dir: PathBuf = temp_dir();
+        let spool: Arc<OfflineSpool> = Arc::new(OfflineSpool::open(&dir).unwrap());
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let (connection_tx, connection_rx) = watch::channel(ConnectionState::Connected {
+            url: "ws://emns.test/ws".to_string(),
+            since: chrono::Utc::now(),
+        });
+        let config: OfflineConfig = OfflineConfig {
+            after: Duration::from_secs(60),
+            key: key(),
+        };
+        let cancel: CancellationToken = CancellationToken::new();
+        let spooler = tokio::spawn(run_spooler(
+            spool,
+            outbound.clone(),
+            connection_rx,
+            config,
+            cancel.clone(),
+        ));
+
+        let alert_id: uuid::Uuid = uuid::Uuid::new_v4();
+        outbound.push(OutboundMessage::Confirmation(confirmation(alert_id)));
+        outbound.push(OutboundMessage::DeliveryStatus(DeliveryStatus {
+            alert_id,
+            client_id: "airgap-01".to_string(),
+            reported_at: chrono::Utc::now(),
+            attachment: None,
+            sound: None,
+            outcome: None,
+            annunciator: None,
+            callback: None,
+            detail: None,
+            decision: None,
+            locale: None,
+        }));
+        outbound.push(OutboundMessage::AlertError {
+            client_id: "airgap-01".to_string(),
+            reason: AlertErrorReason::Overloaded,
+            alert_id: None,
+            detail: None,
+        });
+
+        connection_tx.send_replace(ConnectionState::Disconnected { last_error: None });
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert_eq!(outbound.len(), 3);
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        // The error is only of interest to a connected server
+        assert_eq!(outbound.len(), 1);
+        let spooled: Vec<SpooledRecord> =
+            read_spool(&dir.join(SPOOL_FILE), &LineProtector::default())
+                .unwrap()
+                .0;
+        assert_eq!(spooled.len(), 2);
+        assert!(matches!(
+            spooled[0].record,
+            OfflineRecord::Confirmation { .. }
+        ));
+
+        cancel.cancel();
+        spooler.await.unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
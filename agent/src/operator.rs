@@ -0,0 +1,475 @@
+//! Operator ids asked for at confirmation, for shared consoles whose
+//! logged-in user says nothing about who confirmed
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::Result;
+use crate::storage::write_private_file;
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// File name of the last operator id inside the data directory
+pub const OPERATOR_FILE: &str = "last_operator.json";
+
+/// Letters, digits and dashes, as printed on most badges
+pub const DEFAULT_OPERATOR_ID_PATTERN: &str = "^[A-Za-z0-9][A-Za-z0-9-]{0,31}$";
+
+/// How long the last operator id is offered to the next confirmation
+pub const DEFAULT_OPERATOR_REMEMBER: Duration = Duration::from_secs(15 * 60);
+
+/// How operator ids are checked and remembered
+#[derive(Debug, Clone)]
+pub struct OperatorIdConfig {
+    /// What an id must match, after surrounding whitespace is trimmed
+    pub pattern: Regex,
+    /// How long the last id prefills the prompt; never when zero
+    pub remember: Duration,
+    /// File the last id is kept in across restarts; in memory only when `None`
+    pub file: Option<PathBuf>,
+}
+
+impl OperatorIdConfig {
+    pub fn new() -> Self {
+        Self {
+            pattern: Regex::new(DEFAULT_OPERATOR_ID_PATTERN).unwrap(),
+            remember: DEFAULT_OPERATOR_REMEMBER,
+            file: None,
+        }
+    }
+
+    /// `input` trimmed, if it is an acceptable operator id
+    pub fn validate(&self, input: &str) -> Option<String> {
+        let id: &str = input.trim();
+        (!id.is_empty() && self.pattern.is_match(id)).then(|| id.to_string())
+    }
+}
+
+impl Default for OperatorIdConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the prompt shows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperatorQuestion {
+    /// Title of the alert being confirmed
+    pub alert_title: String,
+    /// Text the field starts with
+    pub prefill: Option<String>,
+    /// Why the previous answer was refused
+    pub error: Option<String>,
+}
+
+/// Asks whoever is at the console for their operator id
+pub trait OperatorPrompt: Send + Sync {
+    /// The id typed in, or `None` if the prompt was cancelled. Blocks until answered.
+    fn ask(&self, question: &OperatorQuestion) -> Option<String>;
+}
+
+/// Asks in a small window on the interactive desktop
+#[derive(Debug, Default)]
+pub struct DialogPrompt;
+
+impl OperatorPrompt for DialogPrompt {
+    fn ask(&self, question: &OperatorQuestion) -> Option<String> {
+        match show_operator_dialog(question) {
+            Ok(answer) => answer,
+            Err(e) => {
+                log::error!("Failed to show the operator id prompt: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// The last id accepted, and when
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Remembered {
+    operator_id: String,
+    entered_at: DateTime<Utc>,
+}
+
+/// Asks for, checks and remembers operator ids
+pub struct OperatorIdentity {
+    config: OperatorIdConfig,
+    prompt: Arc<dyn OperatorPrompt>,
+    clock: Arc<dyn Clock>,
+    last: Mutex<Option<Remembered>>,
+}
+
+impl OperatorIdentity {
+    /// Prompt with [`DialogPrompt`], prefilling the id left in `config.file` if still fresh
+    pub fn new(config: OperatorIdConfig) -> Self {
+        let last: Option<Remembered> = config.file.as_deref().and_then(load);
+        Self {
+            config,
+            prompt: Arc::new(DialogPrompt),
+            clock: Arc::new(SystemClock),
+            last: Mutex::new(last),
+        }
+    }
+
+    pub fn with_prompt(mut self, prompt: Arc<dyn OperatorPrompt>) -> Self {
+        self.prompt = prompt;
+        self
+    }
+
+    /// Age remembered ids by `clock` instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The last id accepted, unless it is older than the configured `remember`
+    pub fn prefill(&self) -> Option<String> {
+        let last = self.last.lock().unwrap();
+        let last: &Remembered = last.as_ref()?;
+        let age: Duration = (self.clock.now() - last.entered_at).to_std().ok()?;
+        (age < self.config.remember).then(|| last.operator_id.clone())
+    }
+
+    /// Ask until an acceptable id is typed in, or `None` if the operator cancels.
+    ///
+    /// Blocks while the prompt is up.
+    pub fn ask(&self, alert_title: &str) -> Option<String> {
+        let mut question: OperatorQuestion = OperatorQuestion {
+            alert_title: alert_title.to_string(),
+            prefill: self.prefill(),
+            error: None,
+        };
+        loop {
+            let answer: String = self.prompt.ask(&question)?;
+            if let Some(id) = self.config.validate(&answer) {
+                self.remember(&id);
+                return Some(id);
+            }
+            question.error = Some(format!("{:?} is not a valid operator ID", answer.trim()));
+            question.prefill = Some(answer);
+        }
+    }
+
+    fn remember(&self, operator_id: &str) {
+        let remembered: Remembered = Remembered {
+            operator_id: operator_id.to_string(),
+            entered_at: self.clock.now(),
+        };
+        if let Some(path) = &self.config.file {
+            save(path, &remembered);
+        }
+        *self.last.lock().unwrap() = Some(remembered);
+    }
+}
+
+/// A missing or unreadable file only means nothing is prefilled
+fn load(path: &Path) -> Option<Remembered> {
+    let data: Vec<u8> = std::fs::read(path).ok()?;
+    serde_json::from_slice(&data)
+        .map_err(|e| log::warn!("Ignoring unreadable {}: {}", path.display(), e))
+        .ok()
+}
+
+/// Failing to save only loses the prefill at the next restart, so it is logged
+fn save(path: &Path, remembered: &Remembered) {
+    let tmp: PathBuf = path.with_extension("tmp");
+    let result = serde_json::to_vec(remembered)
+        .map_err(std::io::Error::other)
+        .and_then(|data| write_private_file(&tmp, &data))
+        .and_then(|()| std::fs::rename(&tmp, path));
+    if let Err(e) = result {
+        log::error!(
+            "Failed to save the last operator id to {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+/// Show the operator id prompt and block until it is answered or cancelled.
+///
+/// Runs its own message loop, so call it from a thread that may block.
+#[cfg(target_os = "windows")]
+pub fn show_operator_dialog(question: &OperatorQuestion) -> Result<Option<String>> {
+    win32::show(question)
+}
+
+/// The prompt is only available on Windows; elsewhere it is always cancelled
+#[cfg(not(target_os = "windows"))]
+pub fn show_operator_dialog(question: &OperatorQuestion) -> Result<Option<String>> {
+    log::warn!(
+        "Cannot ask for an operator id to confirm {:?} on this platform",
+        question.alert_title
+    );
+    Ok(None)
+}
+
+#[cfg(target_os = "windows")]
+mod win32 {
+    use super::OperatorQuestion;
+    use crate::error::{EmnsError, Result};
+    use std::cell::RefCell;
+    use windows::core::{w, HSTRING, PCWSTR};
+    use windows::Win32::Foundation::{HINSTANCE, HWND, LPARAM, LRESULT, WPARAM};
+    use windows::Win32::Graphics::Gdi::{GetStockObject, COLOR_WINDOW, DEFAULT_GUI_FONT, HBRUSH};
+    use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+    use windows::Win32::UI::Input::KeyboardAndMouse::SetFocus;
+    use windows::Win32::UI::WindowsAndMessaging::*;
+
+    const CLASS_NAME: PCWSTR = w!("EmnsOperatorPrompt");
+    /// The ids dialog navigation sends for Enter and Escape
+    const ID_OK: i32 = 1;
+    const ID_CANCEL: i32 = 2;
+    const ID_INPUT: i32 = 3;
+    /// Select text in an edit control; declared here rather than enabling `Win32_UI_Controls` for it
+    const EM_SETSEL: u32 = 0x00B1;
+    const WIDTH: i32 = 400;
+    const HEIGHT: i32 = 210;
+
+    thread_local! {
+        static ANSWER: RefCell<Option<String>> = const { RefCell::new(None) };
+    }
+
+    pub(super) fn show(question: &OperatorQuestion) -> Result<Option<String>> {
+        let fail = |what: &str, e: windows::core::Error| {
+            EmnsError::notification(None, format!("{}: {}", what, e))
+        };
+
+        ANSWER.with(|a| a.borrow_mut().take());
+        unsafe {
+            let instance: HINSTANCE = GetModuleHandleW(None)
+                .map_err(|e| fail("Failed to get module handle", e))?
+                .into();
+
+            let class: WNDCLASSW = WNDCLASSW {
+                lpfnWndProc: Some(window_proc),
+                hInstance: instance,
+                hCursor: LoadCursorW(None, IDC_ARROW).unwrap_or_default(),
+                hbrBackground: HBRUSH(COLOR_WINDOW.0 as isize + 1),
+                lpszClassName: CLASS_NAME,
+                ..Default::default()
+            };
+            // Fails harmlessly when a previous prompt already registered the class
+            RegisterClassW(&class);
+
+            let window: HWND = CreateWindowExW(
+                WS_EX_TOPMOST,
+                CLASS_NAME,
+                w!("Confirm alert"),
+                WS_OVERLAPPED | WS_CAPTION | WS_SYSMENU | WS_VISIBLE,
+                CW_USEDEFAULT,
+                CW_USEDEFAULT,
+                WIDTH,
+                HEIGHT,
+                None,
+                None,
+                instance,
+                None,
+            );
+            if window.0 == 0 {
+                return Err(fail(
+                    "Failed to create operator prompt",
+                    windows::core::Error::from_win32(),
+                ));
+            }
+
+            let font = WPARAM(GetStockObject(DEFAULT_GUI_FONT).0 as usize);
+            let child = |class: PCWSTR,
+                         text: &str,
+                         style: WINDOW_STYLE,
+                         rect: (i32, i32, i32, i32),
+                         id: i32|
+             -> HWND {
+                let control: HWND = CreateWindowExW(
+                    WINDOW_EX_STYLE::default(),
+                    class,
+                    &HSTRING::from(text),
+                    WS_CHILD | WS_VISIBLE | style,
+                    rect.0,
+                    rect.1,
+                    rect.2,
+                    rect.3,
+                    window,
+                    HMENU(id as isize),
+                    instance,
+                    None,
+                );
+                SendMessageW(control, WM_SETFONT, font, LPARAM(1));
+                control
+            };
+
+            child(
+                w!("STATIC"),
+                &format!(
+                    "Enter your operator ID to confirm:\r\n{}",
+                    question.alert_title
+                ),
+                WINDOW_STYLE::default(),
+                (16, 12, WIDTH - 48, 40),
+                0,
+            );
+            if let Some(error) = &question.error {
+                child(
+                    w!("STATIC"),
+                    error,
+                    WINDOW_STYLE::default(),
+                    (16, 54, WIDTH - 48, 20),
+                    0,
+                );
+            }
+            let input: HWND = child(
+                w!("EDIT"),
+                question.prefill.as_deref().unwrap_or_default(),
+                WS_BORDER | WS_TABSTOP | WINDOW_STYLE(ES_AUTOHSCROLL as u32),
+                (16, 78, WIDTH - 48, 24),
+                ID_INPUT,
+            );
+            let buttons_y: i32 = HEIGHT - 92;
+            child(
+                w!("BUTTON"),
+                "Confirm",
+                WS_TABSTOP | WINDOW_STYLE(BS_DEFPUSHBUTTON as u32),
+                (WIDTH - 264, buttons_y, 112, 32),
+                ID_OK,
+            );
+            child(
+                w!("BUTTON"),
+                "Cancel",
+                WS_TABSTOP | WINDOW_STYLE(BS_PUSHBUTTON as u32),
+                (WIDTH - 144, buttons_y, 112, 32),
+                ID_CANCEL,
+            );
+
+            ShowWindow(window, SW_SHOW);
+            SetForegroundWindow(window);
+            // A prefilled id is selected, so typing replaces it
+            SendMessageW(input, EM_SETSEL, WPARAM(0), LPARAM(-1));
+            SetFocus(input);
+
+            let mut message: MSG = MSG::default();
+            while GetMessageW(&mut message, None, 0, 0).as_bool() {
+                if !IsDialogMessageW(window, &message).as_bool() {
+                    TranslateMessage(&message);
+                    DispatchMessageW(&message);
+                }
+            }
+        }
+
+        Ok(ANSWER.with(|a| a.borrow_mut().take()))
+    }
+
+    extern "system" fn window_proc(
+        window: HWND,
+        message: u32,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        unsafe {
+            match message {
+                WM_COMMAND => {
+                    match (wparam.0 & 0xffff) as i32 {
+                        ID_OK => {
+                            let input: HWND = GetDlgItem(window, ID_INPUT);
+                            let mut text: Vec<u16> =
+                                vec![0; GetWindowTextLengthW(input).max(0) as usize + 1];
+                            let len: usize = GetWindowTextW(input, &mut text).max(0) as usize;
+                            let answer: String = String::from_utf16_lossy(&text[..len]);
+                            ANSWER.with(|a| *a.borrow_mut() = Some(answer));
+                            let _ = DestroyWindow(window);
+                        }
+                        ID_CANCEL => {
+                            let _ = DestroyWindow(window);
+                        }
+                        _ => {}
+                    }
+                    LRESULT(0)
+                }
+                WM_DESTROY => {
+                    PostQuitMessage(0);
+                    LRESULT(0)
+                }
+                _ => DefWindowProcW(window, message, wparam, lparam),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{ManualClock, MockOperatorPrompt};
+
+    #[test]
+    fn test_validate_trims_and_matches_pattern() {
+        let config: OperatorIdConfig = OperatorIdConfig::new();
+        assert_eq!(config.validate("  B-10442 "), Some("B-10442".to_string()));
+        assert_eq!(config.validate(""), None);
+        assert_eq!(config.validate("   "), None);
+        assert_eq!(config.validate("-leading-dash"), None);
+        assert_eq!(config.validate("jane doe"), None);
+
+        let badges: OperatorIdConfig = OperatorIdConfig {
+            pattern: Regex::new(r"^\d{6}$").unwrap(),
+            ..OperatorIdConfig::new()
+        };
+        assert_eq!(badges.validate("104420"), Some("104420".to_string()));
+        assert_eq!(badges.validate("B-10442"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_invalid_answers_are_asked_again_until_cancelled() {
+        let prompt: Arc<MockOperatorPrompt> =
+            Arc::new(MockOperatorPrompt::answering([Some("not valid!"), None]));
+        let identity: OperatorIdentity =
+            OperatorIdentity::new(OperatorIdConfig::new()).with_prompt(prompt.clone());
+
+        assert_eq!(identity.ask("Shelter in place"), None);
+        let questions: Vec<OperatorQuestion> = prompt.questions();
+        assert_eq!(questions.len(), 2);
+        assert_eq!(questions[0].error, None);
+        assert_eq!(questions[1].prefill.as_deref(), Some("not valid!"));
+        assert_eq!(
+            questions[1].error.as_deref(),
+            Some("\"not valid!\" is not a valid operator ID")
+        );
+        // Nothing accepted, so nothing to offer next time
+        assert_eq!(identity.prefill(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_last_id_prefills_until_it_expires_and_outlasts_restarts() {
+        let file: PathBuf =
+            std::env::temp_dir().join(format!("emns-operator-{}.json", uuid::Uuid::new_v4()));
+        let config: OperatorIdConfig = OperatorIdConfig {
+            remember: Duration::from_secs(600),
+            file: Some(file.clone()),
+            ..OperatorIdConfig::new()
+        };
+        let clock: Arc<ManualClock> = Arc::new(ManualClock::new());
+        let prompt: Arc<MockOperatorPrompt> =
+            Arc::new(MockOperatorPrompt::answering([Some(" B-10442 ")]));
+        let identity: OperatorIdentity = OperatorIdentity::new(config.clone())
+            .with_prompt(prompt.clone())
+            .with_clock(clock.clone());
+
+        assert_eq!(identity.ask("Muster check").as_deref(), Some("B-10442"));
+        assert_eq!(prompt.questions()[0].prefill, None);
+        assert_eq!(identity.prefill().as_deref(), Some("B-10442"));
+
+        let restarted: OperatorIdentity =
+            OperatorIdentity::new(config.clone()).with_clock(clock.clone());
+        assert_eq!(restarted.prefill().as_deref(), Some("B-10442"));
+        let forgetful: OperatorIdentity = OperatorIdentity::new(OperatorIdConfig {
+            remember: Duration::ZERO,
+            ..config
+        })
+        .with_clock(clock);
+        assert_eq!(forgetful.prefill(), None);
+
+        tokio::time::advance(Duration::from_secs(600)).await;
+        assert_eq!(restarted.prefill(), None);
+        std::fs::remove_file(&file).unwrap();
+    }
+}
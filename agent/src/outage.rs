@@ -0,0 +1,175 @@
+//! Telling the user when the server has been out of reach long enough that
+//! alerts may not arrive, and again when it is back
+
+use crate::client::ConnectionState;
+use crate::notification::{simple_alert, NotificationBackend};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+/// Show a toast once `connection` has been down for `grace`, and another
+/// when it is registered again, until `cancel` fires. Outages shorter than
+/// `grace` go unmentioned.
+pub async fn run_notices(
+    mut connection: watch::Receiver<ConnectionState>,
+    notifier: Arc<dyn NotificationBackend>,
+    grace: Duration,
+    cancel: CancellationToken,
+) {
+    loop {
+        // Connected, or not yet: wait for the connection to be down
+        let down: bool = tokio::select! {
+            _ = cancel.cancelled() => return,
+            down = connection.wait_for(|state| !state.is_connected()) => down.is_ok(),
+        };
+        if !down {
+            return;
+        }
+
+        let back: bool = tokio::select! {
+            _ = cancel.cancelled() => return,
+            back = connection.wait_for(ConnectionState::is_connected) => back.is_ok(),
+            _ = tokio::time::sleep(grace) => false,
+        };
+        if back {
+            continue;
+        }
+        if let ConnectionState::Disconnected {
+            last_error: Some(error),
+        } = &*connection.borrow()
+        {
+            log::warn!("Server unreachable for {}: {}", span(grace), error);
+        }
+        show(
+            &notifier,
+            "Alert server unreachable",
+            &format!(
+                "This computer has not been connected to the alert server for {}. \
+                 Emergency alerts may not arrive until the connection is restored.",
+                span(grace)
+            ),
+        );
+
+        let back: bool = tokio::select! {
+            _ = cancel.cancelled() => return,
+            back = connection.wait_for(ConnectionState::is_connected) => back.is_ok(),
+        };
+        if !back {
+            return;
+        }
+        show(
+            &notifier,
+            "Alert server connection restored",
+            "This computer is connected to the alert server again.",
+        );
+    }
+}
+
+fn show(notifier: &Arc<dyn NotificationBackend>, title: &str, message: &str) {
+    if let Err(e) = notifier.show_notification(&simple_alert(title, message)) {
+        log::error!("Failed to show \"{}\": {}", title, e);
+    }
+}
+
+/// e.g. "5 minutes", or "90 seconds" where whole minutes would round
+fn span(duration: Duration) -> String {
+    let secs: u64 = duration.as_secs();
+    if secs >= 120 && secs.is_multiple_of(60) {
+        format!("{} minutes", secs / 60)
+    } else {
+        format!("{} seconds", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockNotifier;
+
+    const GRACE: Duration = Duration::from_secs(120);
+
+    fn connected() -> ConnectionState {
+        ConnectionState::Connected {
+            url: "ws://emns.test/ws".to_string(),
+            since: chrono::Utc::now(),
+        }
+    }
+
+    fn disconnected() -> ConnectionState {
+        ConnectionState::Disconnected {
+            last_error: Some("connection refused".to_string()),
+        }
+    }
+
+    fn titles(notifier: &MockNotifier) -> Vec<String> {
+        notifier
+            .shown()
+            .into_iter()
+            .map(|alert| alert.title)
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_outage_past_grace_is_announced_then_recovery() {
+        let (state_tx, state_rx) = watch::channel(connected());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let cancel: CancellationToken = CancellationToken::new();
+        let notices = tokio::spawn(run_notices(
+            state_rx,
+            notifier.clone(),
+            GRACE,
+            cancel.clone(),
+        ));
+
+        state_tx.send_replace(disconnected());
+        tokio::time::sleep(GRACE - Duration::from_secs(1)).await;
+        assert!(titles(&notifier).is_empty());
+
+        // Still down through reconnect attempts
+        state_tx.send_replace(ConnectionState::Connecting);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(titles(&notifier), ["Alert server unreachable"]);
+        assert!(notifier.shown()[0].message.contains("2 minutes"));
+
+        state_tx.send_replace(connected());
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(
+            titles(&notifier),
+            [
+                "Alert server unreachable",
+                "Alert server connection restored"
+            ]
+        );
+
+        cancel.cancel();
+        notices.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_short_outage_goes_unmentioned() {
+        let (state_tx, state_rx) = watch::channel(connected());
+        let notifier: Arc<MockNotifier> = Arc::new(MockNotifier::default());
+        let cancel: CancellationToken = CancellationToken::new();
+        let notices = tokio::spawn(run_notices(
+            state_rx,
+            notifier.clone(),
+            GRACE,
+            cancel.clone(),
+        ));
+
+        state_tx.send_replace(disconnected());
+        tokio::time::sleep(GRACE / 2).await;
+        state_tx.send_replace(connected());
+        tokio::time::sleep(GRACE * 2).await;
+        assert!(titles(&notifier).is_empty());
+
+        // The grace period starts over with the next outage
+        state_tx.send_replace(disconnected());
+        tokio::time::sleep(GRACE + Duration::from_secs(1)).await;
+        assert_eq!(titles(&notifier), ["Alert server unreachable"]);
+
+        cancel.cancel();
+        notices.await.unwrap();
+    }
+}
@@ -0,0 +1,448 @@
+//! Messages waiting to be sent to the server
+
+use crate::messages::{
+    AgentStatus, Alert, AlertErrorReason, Confirmation, DeliveryStatus, HeartbeatStats, Message,
+    NackReason,
+};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// Default number of messages held while the server is unreachable or slow
+pub const DEFAULT_OUTBOUND_CAPACITY: usize = 1000;
+
+/// Something the agent tells the server, apart from the registration handshake
+#[derive(Debug, Clone)]
+pub enum OutboundMessage {
+    /// The answer to an alert: a confirm, a chosen response option, or a timeout
+    Confirmation(Confirmation),
+    /// How delivery of an alert went on this machine
+    DeliveryStatus(DeliveryStatus),
+    /// An alert reached its expiry here; sent instead of a confirmation
+    AlertExpired {
+        client_id: String,
+        alert_id: uuid::Uuid,
+        unconfirmed: bool,
+    },
+    /// The agent cannot handle alerts as sent
+    AlertError {
+        client_id: String,
+        reason: AlertErrorReason,
+        alert_id: Option<uuid::Uuid>,
+        detail: Option<String>,
+    },
+    /// A message from the server could not be read
+    Error {
+        context: String,
+        detail: String,
+    },
+    /// Copy of an alert raised on this machine
+    LocalAlert {
+        client_id: String,
+        alert: Box<Alert>,
+    },
+    Status(Box<AgentStatus>),
+    Heartbeat(HeartbeatStats),
+    /// The server's envelope with this id was handled
+    Ack {
+        message_id: uuid::Uuid,
+    },
+    /// The server's envelope with this id was not handled
+    Nack {
+        message_id: uuid::Uuid,
+        reason: NackReason,
+        detail: Option<String>,
+    },
+}
+
+/// Order messages leave the queue in, most important last
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Status and heartbeats; only the newest of each is worth sending
+    Telemetry,
+    /// Delivery reports, errors and local alerts
+    Report,
+    /// Confirmations, expiry reports and acks, which the server is waiting on
+    Confirmation,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [
+        Priority::Telemetry,
+        Priority::Report,
+        Priority::Confirmation,
+    ];
+
+    fn lane(self) -> usize {
+        self as usize
+    }
+}
+
+impl OutboundMessage {
+    pub fn priority(&self) -> Priority {
+        match self {
+            OutboundMessage::Confirmation(_)
+            | OutboundMessage::AlertExpired { .. }
+            | OutboundMessage::Ack { .. }
+            | OutboundMessage::Nack { .. } => Priority::Confirmation,
+            OutboundMessage::DeliveryStatus(_)
+            | OutboundMessage::AlertError { .. }
+            | OutboundMessage::Error { .. }
+            | OutboundMessage::LocalAlert { .. } => Priority::Report,
+            OutboundMessage::Status(_) | OutboundMessage::Heartbeat(_) => Priority::Telemetry,
+        }
+    }
+
+    /// Whether a message that failed to send should be kept for the next connection.
+    ///
+    /// Telemetry is stale by then and is rebuilt on connect instead. Acks
+    /// answer one connection's envelopes; the server resends what went
+    /// unanswered, and it is answered again.
+    pub fn retry_on_failure(&self) -> bool {
+        self.priority() != Priority::Telemetry && !self.answers_envelope()
+    }
+
+    /// Whether this is an ack or nack, which only a connection using
+    /// envelopes can carry
+    pub fn answers_envelope(&self) -> bool {
+        matches!(
+            self,
+            OutboundMessage::Ack { .. } | OutboundMessage::Nack { .. }
+        )
+    }
+
+    /// Whether `other` makes this message redundant, so only the newer is kept
+    fn superseded_by(&self, other: &OutboundMessage) -> bool {
+        matches!(
+            (self, other),
+            (OutboundMessage::Status(_), OutboundMessage::Status(_))
+                | (OutboundMessage::Heartbeat(_), OutboundMessage::Heartbeat(_))
+        )
+    }
+}
+
+impl From<OutboundMessage> for Message {
+    fn from(message: OutboundMessage) -> Self {
+        match message {
+            OutboundMessage::Confirmation(confirmation) => Message::Confirmation { confirmation },
+            OutboundMessage::DeliveryStatus(status) => Message::DeliveryStatus { status },
+            OutboundMessage::AlertExpired {
+                client_id,
+                alert_id,
+                unconfirmed,
+            } => Message::AlertExpired {
+                client_id,
+                alert_id,
+                unconfirmed,
+            },
+            OutboundMessage::AlertError {
+                client_id,
+                reason,
+                alert_id,
+                detail,
+            } => Message::AlertError {
+                client_id,
+                reason,
+                alert_id,
+                detail,
+            },
+            OutboundMessage::Error { context, detail } => Message::Error { context, detail },
+            OutboundMessage::LocalAlert { client_id, alert } => Message::LocalAlert {
+                client_id,
+                alert: *alert,
+            },
+            OutboundMessage::Status(status) => Message::Status { status: *status },
+            OutboundMessage::Heartbeat(stats) => Message::Heartbeat { stats },
+            OutboundMessage::Ack { message_id } => Message::Ack { message_id },
+            OutboundMessage::Nack {
+                message_id,
+                reason,
+                detail,
+            } => Message::Nack {
+                message_id,
+                reason,
+                detail,
+            },
+        }
+    }
+}
+
+/// Bounded queue of messages for the server that survives reconnects.
+///
+/// Messages leave highest [`Priority`] first, and in order within a priority.
+/// Producers never wait; when the queue is full the oldest message of the
+/// lowest priority present is dropped, unless the new message ranks lower
+/// still, in which case it is the one dropped.
+pub struct OutboundQueue {
+    lanes: Mutex<[VecDeque<OutboundMessage>; 3]>,
+    capacity: usize,
+    notify: Notify,
+    dropped: [AtomicU64; 3],
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lanes: Mutex::new(Default::default()),
+            capacity: capacity.max(1),
+            notify: Notify::new(),
+            dropped: Default::default(),
+        }
+    }
+
+    /// Queue a message to be sent after anything already waiting at its priority
+    pub fn push(&self, message: OutboundMessage) {
+        self.insert(message, false);
+    }
+
+    /// Put back a message that failed to send so it goes out first at its priority
+    pub fn requeue(&self, message: OutboundMessage) {
+        self.insert(message, true);
+    }
+
+    fn insert(&self, message: OutboundMessage, front: bool) {
+        let priority: Priority = message.priority();
+        let mut lanes = self.lanes.lock().unwrap();
+        let lane: &mut VecDeque<OutboundMessage> = &mut lanes[priority.lane()];
+        let superseded: Option<usize> = lane.iter().position(|m| m.superseded_by(&message));
+        if let Some(at) = superseded {
+            // A newer report replaces the queued one and keeps its place
+            if !front {
+                lane[at] = message;
+            }
+            drop(lanes);
+            self.notify.notify_one();
+            return;
+        }
+
+        let len: usize = lanes.iter().map(VecDeque::len).sum();
+        if len >= self.capacity {
+            let victim: Priority = Priority::ALL
+                .into_iter()
+                .find(|p| !lanes[p.lane()].is_empty())
+                .unwrap_or(priority);
+            if victim > priority {
+                self.record_drop(priority);
+                return;
+            }
+            // A requeued message is older than anything waiting, so the newest goes
+            if front {
+                lanes[victim.lane()].pop_back();
+            } else {
+                lanes[victim.lane()].pop_front();
+            }
+            self.record_drop(victim);
+        }
+        let lane: &mut VecDeque<OutboundMessage> = &mut lanes[priority.lane()];
+        if front {
+            lane.push_front(message);
+        } else {
+            lane.push_back(message);
+        }
+        drop(lanes);
+        self.notify.notify_one();
+    }
+
+    fn record_drop(&self, priority: Priority) {
+        self.dropped[priority.lane()].fetch_add(1, Ordering::Relaxed);
+        log::error!(
+            "Outbound queue full ({} messages), dropped a {:?} message",
+            self.capacity,
+            priority
+        );
+    }
+
+    /// Wait for the next message to send
+    pub async fn next(&self) -> OutboundMessage {
+        loop {
+            let notified = self.notify.notified();
+            if let Some(message) = self.try_next() {
+                return message;
+            }
+            notified.await;
+        }
+    }
+
+    /// The next message to send, if one is waiting
+    pub fn try_next(&self) -> Option<OutboundMessage> {
+        let mut lanes = self.lanes.lock().unwrap();
+        Priority::ALL
+            .into_iter()
+            .rev()
+            .find_map(|p| lanes[p.lane()].pop_front())
+    }
+
+    /// Remove and return every waiting message `take` picks, in the order they would have been sent
+    pub fn take_matching(
+        &self,
+        mut take: impl FnMut(&OutboundMessage) -> bool,
+    ) -> Vec<OutboundMessage> {
+        let mut lanes = self.lanes.lock().unwrap();
+        let mut taken: Vec<OutboundMessage> = Vec::new();
+        for priority in Priority::ALL.into_iter().rev() {
+            let lane: &mut VecDeque<OutboundMessage> = &mut lanes[priority.lane()];
+            let (matched, kept): (VecDeque<_>, VecDeque<_>) = lane.drain(..).partition(&mut take);
+            *lane = kept;
+            taken.extend(matched);
+        }
+        taken
+    }
+
+    /// Forget queued status and heartbeats, which are stale once a connection is lost
+    pub fn discard_telemetry(&self) {
+        self.lanes.lock().unwrap()[Priority::Telemetry.lane()].clear();
+    }
+
+    /// Number of messages waiting to be sent
+    pub fn len(&self) -> usize {
+        self.lanes.lock().unwrap().iter().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of messages of `priority` waiting to be sent
+    pub fn depth(&self, priority: Priority) -> usize {
+        self.lanes.lock().unwrap()[priority.lane()].len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Messages dropped because the queue was full
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Messages of `priority` dropped because the queue was full
+    pub fn dropped(&self, priority: Priority) -> u64 {
+        self.dropped[priority.lane()].load(Ordering::Relaxed)
+    }
+}
+
+impl Default for OutboundQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_OUTBOUND_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{ConfirmationReason, ConfirmationResponse};
+
+    fn confirmation(n: u128) -> OutboundMessage {
+        OutboundMessage::Confirmation(Confirmation {
+            alert_id: uuid::Uuid::from_u128(n),
+            client_id: "test-client".to_string(),
+            confirmed_at: chrono::Utc::now(),
+            hostname: "h".to_string(),
+            username: "u".to_string(),
+            operator_id: None,
+            reason: ConfirmationReason::User,
+            method: None,
+            user_idle_secs: None,
+            response_id: None,
+            response: ConfirmationResponse::Acknowledged,
+            received_via: Default::default(),
+            shown_at: None,
+            response_latency_ms: None,
+            is_preview: false,
+        })
+    }
+
+    fn error(detail: &str) -> OutboundMessage {
+        OutboundMessage::AlertError {
+            client_id: "test-client".to_string(),
+            reason: AlertErrorReason::Overloaded,
+            alert_id: None,
+            detail: Some(detail.to_string()),
+        }
+    }
+
+    /// What a drained message was, compared without timestamps
+    fn describe(message: OutboundMessage) -> String {
+        match message {
+            OutboundMessage::Confirmation(c) => format!("confirm {}", c.alert_id.as_u128()),
+            OutboundMessage::AlertError { detail, .. } => format!("error {}", detail.unwrap()),
+            OutboundMessage::Heartbeat(stats) => format!("heartbeat {:?}", stats.uptime_secs),
+            other => format!("{:?}", other.priority()),
+        }
+    }
+
+    fn drain(queue: &OutboundQueue) -> Vec<String> {
+        std::iter::from_fn(|| queue.try_next())
+            .map(describe)
+            .collect()
+    }
+
+    #[test]
+    fn test_higher_priority_first_and_fifo_within_priority() {
+        let queue: OutboundQueue = OutboundQueue::default();
+        queue.push(OutboundMessage::Heartbeat(HeartbeatStats::default()));
+        queue.push(error("a"));
+        queue.push(confirmation(1));
+        queue.push(error("b"));
+        queue.push(confirmation(2));
+        assert_eq!(queue.depth(Priority::Confirmation), 2);
+
+        assert_eq!(
+            drain(&queue),
+            [
+                "confirm 1",
+                "confirm 2",
+                "error a",
+                "error b",
+                "heartbeat None"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_newer_telemetry_replaces_queued_telemetry() {
+        let queue: OutboundQueue = OutboundQueue::default();
+        let heartbeat = |uptime: u64| {
+            OutboundMessage::Heartbeat(HeartbeatStats {
+                uptime_secs: Some(uptime),
+                ..HeartbeatStats::default()
+            })
+        };
+        queue.push(heartbeat(1));
+        queue.push(heartbeat(2));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.dropped_count(), 0);
+        assert_eq!(drain(&queue), ["heartbeat Some(2)"]);
+
+        queue.push(heartbeat(3));
+        queue.discard_telemetry();
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_full_queue_drops_lowest_priority_first() {
+        let queue: OutboundQueue = OutboundQueue::new(2);
+        queue.push(error("a"));
+        queue.push(confirmation(1));
+        // The error makes way for the confirmation
+        queue.push(confirmation(2));
+        assert_eq!(queue.dropped(Priority::Report), 1);
+        // Nothing ranks below this error, so it is the one dropped
+        queue.push(error("b"));
+        assert_eq!(queue.dropped(Priority::Report), 2);
+        // Among confirmations the oldest goes
+        queue.push(confirmation(3));
+        assert_eq!(queue.dropped(Priority::Confirmation), 1);
+        assert_eq!(queue.dropped_count(), 3);
+
+        // A requeued message failed before anything else was sent, so it goes first
+        let next: OutboundMessage = queue.try_next().unwrap();
+        queue.requeue(next);
+        assert_eq!(drain(&queue), ["confirm 2", "confirm 3"]);
+    }
+}
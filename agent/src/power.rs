@@ -0,0 +1,196 @@
+//! Keeping the display awake while Emergency alerts are outstanding
+
+use std::sync::{Arc, Mutex};
+
+/// Default limit on how long one alert may hold the display awake
+pub const DEFAULT_DISPLAY_WAKE_CAP: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+
+/// Control over the machine's sleep and display power state
+pub trait PowerBackend: Send + Sync {
+    /// Keep the display and system awake (`true`) or let them sleep again (`false`)
+    fn set_keep_awake(&self, keep_awake: bool);
+    /// Turn the display back on if it has gone to sleep
+    fn wake_display(&self);
+}
+
+/// Commands for the thread that owns the execution state
+#[cfg(target_os = "windows")]
+enum PowerCommand {
+    KeepAwake(bool),
+    Wake,
+}
+
+/// Uses `SetThreadExecutionState` and a zero-distance mouse move.
+///
+/// The execution state belongs to the thread that set it, so a dedicated
+/// thread applies every change.
+pub struct SystemPower {
+    #[cfg(target_os = "windows")]
+    commands: std::sync::mpsc::Sender<PowerCommand>,
+}
+
+impl SystemPower {
+    #[cfg(target_os = "windows")]
+    pub fn new() -> Self {
+        use windows::Win32::System::Power::{
+            SetThreadExecutionState, ES_CONTINUOUS, ES_DISPLAY_REQUIRED, ES_SYSTEM_REQUIRED,
+        };
+        use windows::Win32::UI::Input::KeyboardAndMouse::{
+            SendInput, INPUT, INPUT_0, INPUT_MOUSE, MOUSEEVENTF_MOVE, MOUSEINPUT,
+        };
+
+        let (commands, rx) = std::sync::mpsc::channel::<PowerCommand>();
+        std::thread::spawn(move || {
+            for command in rx {
+                unsafe {
+                    match command {
+                        PowerCommand::KeepAwake(true) => {
+                            SetThreadExecutionState(
+                                ES_CONTINUOUS | ES_DISPLAY_REQUIRED | ES_SYSTEM_REQUIRED,
+                            );
+                        }
+                        PowerCommand::KeepAwake(false) => {
+                            SetThreadExecutionState(ES_CONTINUOUS);
+                        }
+                        PowerCommand::Wake => {
+                            SetThreadExecutionState(ES_DISPLAY_REQUIRED);
+                            let input: INPUT = INPUT {
+                                r#type: INPUT_MOUSE,
+                                Anonymous: INPUT_0 {
+                                    mi: MOUSEINPUT {
+                                        dwFlags: MOUSEEVENTF_MOVE,
+                                        ..Default::default()
+                                    },
+                                },
+                            };
+                            if SendInput(&[input], std::mem::size_of::<INPUT>() as i32) == 0 {
+                                log::warn!("Failed to send input to wake the display");
+                            }
+                        }
+                    }
+                }
+            }
+            // Channel closed: never leave sleep blocked behind us
+            unsafe {
+                SetThreadExecutionState(ES_CONTINUOUS);
+            }
+        });
+        Self { commands }
+    }
+
+    /// Power management is only available on Windows; elsewhere changes are logged
+    #[cfg(not(target_os = "windows"))]
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Default for SystemPower {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PowerBackend for SystemPower {
+    #[cfg(target_os = "windows")]
+    fn set_keep_awake(&self, keep_awake: bool) {
+        let _ = self.commands.send(PowerCommand::KeepAwake(keep_awake));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    fn set_keep_awake(&self, keep_awake: bool) {
+        log::debug!("Keep display awake: {}", keep_awake);
+    }
+
+    #[cfg(target_os = "windows")]
+    fn wake_display(&self) {
+        let _ = self.commands.send(PowerCommand::Wake);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    fn wake_display(&self) {
+        log::debug!("Wake display");
+    }
+}
+
+/// Reference-counted hold on the display.
+///
+/// The backend is told to keep the display awake when the first
+/// [`WakeGuard`] is taken and released when the last one drops.
+#[derive(Clone)]
+pub struct DisplayWake {
+    backend: Arc<dyn PowerBackend>,
+    holders: Arc<Mutex<usize>>,
+}
+
+impl DisplayWake {
+    pub fn new(backend: Arc<dyn PowerBackend>) -> Self {
+        Self {
+            backend,
+            holders: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// Wake the display and keep it on until the guard drops
+    pub fn acquire(&self) -> WakeGuard {
+        let mut holders = self.holders.lock().unwrap();
+        *holders += 1;
+        if *holders == 1 {
+            self.backend.set_keep_awake(true);
+        }
+        self.backend.wake_display();
+        WakeGuard {
+            backend: self.backend.clone(),
+            holders: self.holders.clone(),
+        }
+    }
+
+    /// Wake the display once without holding it on
+    pub fn pulse(&self) {
+        self.backend.wake_display();
+    }
+
+    /// Number of outstanding guards
+    pub fn holders(&self) -> usize {
+        *self.holders.lock().unwrap()
+    }
+}
+
+/// Keeps the display awake while alive
+pub struct WakeGuard {
+    backend: Arc<dyn PowerBackend>,
+    holders: Arc<Mutex<usize>>,
+}
+
+impl Drop for WakeGuard {
+    fn drop(&mut self) {
+        let mut holders = self.holders.lock().unwrap();
+        *holders -= 1;
+        if *holders == 0 {
+            self.backend.set_keep_awake(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockPower;
+
+    #[test]
+    fn test_hold_spans_overlapping_guards() {
+        let power: Arc<MockPower> = Arc::new(MockPower::default());
+        let wake: DisplayWake = DisplayWake::new(power.clone());
+
+        let first: WakeGuard = wake.acquire();
+        let second: WakeGuard = wake.acquire();
+        assert_eq!(power.keep_awake_calls(), vec![true]);
+        assert_eq!(power.wakes(), 2);
+
+        drop(first);
+        assert_eq!(power.keep_awake_calls(), vec![true]);
+        drop(second);
+        assert_eq!(power.keep_awake_calls(), vec![true, false]);
+        assert_eq!(wake.holders(), 0);
+    }
+}
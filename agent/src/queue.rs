@@ -0,0 +1,381 @@
+//! Bounded alert queue between the server connection and the handler
+
+use crate::messages::{Alert, AlertLevel, DeliveryOutcome, DeliveryStatus};
+use crate::outbound::{OutboundMessage, OutboundQueue};
+use crate::timing::DeliveryTrace;
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Default number of alerts buffered ahead of the handler
+pub const DEFAULT_ALERT_QUEUE_CAPACITY: usize = 100;
+
+/// Attempts made before shedding when the queue is full
+const ENQUEUE_RETRIES: u32 = 3;
+
+/// Pause between enqueue attempts
+const ENQUEUE_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Number of shed events kept for inspection
+const SHED_LOG_CAPACITY: usize = 100;
+
+/// An alert dropped because the queue stayed full
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShedEvent {
+    pub alert_id: uuid::Uuid,
+    pub level: AlertLevel,
+    /// The shed alert's own `timestamp` and `expires_at`, for asking for a replay
+    pub issued_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub shed_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Result of offering an alert to the queue
+#[derive(Debug, PartialEq)]
+pub enum EnqueueOutcome {
+    Queued,
+    /// The queue was full; this alert was dropped to make room (or was itself dropped)
+    Shed(ShedEvent),
+}
+
+/// Bounded queue of alerts that never blocks the producer.
+///
+/// Alerts are handed out highest [`rank`] first, and in arrival order among
+/// equals, so a burst's emergencies are shown and sounded before its routine
+/// alerts. When the handler falls behind, the lowest-ranked alert is dropped
+/// rather than stalling the connection's read loop.
+pub struct AlertQueue {
+    /// Each alert with the stamps taken on its way in, in arrival order
+    alerts: Mutex<VecDeque<(Alert, DeliveryTrace)>>,
+    capacity: usize,
+    notify: Notify,
+    shed_count: AtomicU64,
+    shed_log: Mutex<VecDeque<ShedEvent>>,
+    /// Where shed alerts are reported, and as which client
+    reports: Option<(Arc<OutboundQueue>, String)>,
+}
+
+impl AlertQueue {
+    pub fn new(capacity: usize) -> Self {
+        let capacity: usize = capacity.max(1);
+        Self {
+            alerts: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            notify: Notify::new(),
+            shed_count: AtomicU64::new(0),
+            shed_log: Mutex::new(VecDeque::new()),
+            reports: None,
+        }
+    }
+
+    /// Report each alert shed to the server as `client_id`
+    pub fn reporting_to(
+        mut self,
+        outbound: Arc<OutboundQueue>,
+        client_id: impl Into<String>,
+    ) -> Self {
+        self.reports = Some((outbound, client_id.into()));
+        self
+    }
+
+    /// Add an alert if there is room, handing it back when full
+    pub fn try_push(&self, alert: Alert) -> Result<(), Box<Alert>> {
+        self.try_push_traced(alert, DeliveryTrace::default())
+            .map_err(|rejected| Box::new(rejected.0))
+    }
+
+    fn try_push_traced(
+        &self,
+        alert: Alert,
+        trace: DeliveryTrace,
+    ) -> Result<(), Box<(Alert, DeliveryTrace)>> {
+        let mut alerts = self.alerts.lock().unwrap();
+        if alerts.len() >= self.capacity {
+            return Err(Box::new((alert, trace)));
+        }
+        alerts.push_back((alert, trace));
+        drop(alerts);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Add an alert with the stamps taken so far, retrying briefly and then
+    /// shedding the lowest-ranked alert if still full
+    pub async fn enqueue(&self, alert: Alert, trace: DeliveryTrace) -> EnqueueOutcome {
+        let mut queued: (Alert, DeliveryTrace) = (alert, trace);
+        for _ in 0..ENQUEUE_RETRIES {
+            match self.try_push_traced(queued.0, queued.1) {
+                Ok(()) => return EnqueueOutcome::Queued,
+                Err(rejected) => queued = *rejected,
+            }
+            tokio::time::sleep(ENQUEUE_RETRY_DELAY).await;
+        }
+        self.push_shedding_traced(queued.0, queued.1)
+    }
+
+    /// Add an alert, dropping the lowest-ranked one (oldest first among equals) when full.
+    ///
+    /// The incoming alert is the one dropped when nothing queued ranks below it.
+    pub fn push_shedding(&self, alert: Alert) -> EnqueueOutcome {
+        self.push_shedding_traced(alert, DeliveryTrace::default())
+    }
+
+    fn push_shedding_traced(&self, alert: Alert, trace: DeliveryTrace) -> EnqueueOutcome {
+        let mut alerts = self.alerts.lock().unwrap();
+        if alerts.len() < self.capacity {
+            alerts.push_back((alert, trace));
+            drop(alerts);
+            self.notify.notify_one();
+            return EnqueueOutcome::Queued;
+        }
+
+        let lowest: Option<usize> = alerts
+            .iter()
+            .enumerate()
+            .min_by_key(|(index, (queued, _))| (rank(queued), *index))
+            .map(|(index, _)| index);
+
+        let shed: Alert = match lowest {
+            Some(index) if rank(&alerts[index].0) < rank(&alert) => {
+                let (shed, _) = alerts.remove(index).expect("index in range");
+                alerts.push_back((alert, trace));
+                drop(alerts);
+                self.notify.notify_one();
+                shed
+            }
+            _ => alert,
+        };
+
+        EnqueueOutcome::Shed(self.record_shed(&shed))
+    }
+
+    fn record_shed(&self, alert: &Alert) -> ShedEvent {
+        let event: ShedEvent = ShedEvent {
+            alert_id: alert.id,
+            level: alert.level.clone(),
+            issued_at: alert.timestamp,
+            expires_at: alert.expires_at,
+            shed_at: chrono::Utc::now(),
+        };
+        log::error!(
+            "Alert queue full ({} queued), shed {} alert {}",
+            self.capacity,
+            alert.level.as_str(),
+            alert.id
+        );
+
+        self.shed_count.fetch_add(1, Ordering::Relaxed);
+        let mut log = self.shed_log.lock().unwrap();
+        if log.len() >= SHED_LOG_CAPACITY {
+            log.pop_front();
+        }
+        log.push_back(event.clone());
+        drop(log);
+        if let Some((outbound, client_id)) = &self.reports {
+            outbound.push(OutboundMessage::DeliveryStatus(DeliveryStatus {
+                alert_id: alert.id,
+                client_id: client_id.clone(),
+                reported_at: event.shed_at,
+                attachment: None,
+                sound: None,
+                annunciator: None,
+                callback: None,
+                outcome: Some(DeliveryOutcome::QueueFull),
+                detail: Some(format!(
+                    "{} alerts queued ahead of it, none ranking lower",
+                    self.capacity
+                )),
+                decision: None,
+                locale: None,
+            }));
+        }
+        event
+    }
+
+    /// Wait for the next alert
+    pub async fn recv(&self) -> Alert {
+        self.recv_traced().await.0
+    }
+
+    /// Wait for the highest-ranked alert and the stamps taken on its way in
+    pub async fn recv_traced(&self) -> (Alert, DeliveryTrace) {
+        loop {
+            let notified = self.notify.notified();
+            if let Some(queued) = Self::pop_highest(&mut self.alerts.lock().unwrap()) {
+                return queued;
+            }
+            notified.await;
+        }
+    }
+
+    /// The highest-ranked alert, the first to arrive among equals
+    fn pop_highest(
+        alerts: &mut VecDeque<(Alert, DeliveryTrace)>,
+    ) -> Option<(Alert, DeliveryTrace)> {
+        let highest: usize = alerts
+            .iter()
+            .enumerate()
+            .max_by_key(|(index, (queued, _))| (rank(queued), Reverse(*index)))
+            .map(|(index, _)| index)?;
+        alerts.remove(highest)
+    }
+
+    /// Number of alerts waiting for the handler
+    pub fn depth(&self) -> usize {
+        self.alerts.lock().unwrap().len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Total alerts shed since startup
+    pub fn shed_count(&self) -> u64 {
+        self.shed_count.load(Ordering::Relaxed)
+    }
+
+    /// Most recent shed events, oldest first
+    pub fn shed_events(&self) -> Vec<ShedEvent> {
+        self.shed_log.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Order in which queued alerts are handled and, reversed, shed: by level,
+/// then by the alert's `priority`, with none ranking below any
+fn rank(alert: &Alert) -> (u8, Option<u8>) {
+    (priority(&alert.level), alert.priority)
+}
+
+/// Relative importance used when shedding
+pub(crate) fn priority(level: &AlertLevel) -> u8 {
+    match level {
+        AlertLevel::Info => 0,
+        AlertLevel::Warning => 1,
+        AlertLevel::Critical => 2,
+        AlertLevel::Emergency => 3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::alert;
+
+    #[test]
+    fn test_sheds_lowest_priority_queued_alert() {
+        let queue: AlertQueue = AlertQueue::new(2);
+        let info: Alert = alert(AlertLevel::Info, false);
+        let warning: Alert = alert(AlertLevel::Warning, false);
+        let emergency: Alert = alert(AlertLevel::Emergency, true);
+        let info_id: uuid::Uuid = info.id;
+
+        queue.try_push(warning.clone()).unwrap();
+        queue.try_push(info).unwrap();
+
+        match queue.push_shedding(emergency.clone()) {
+            EnqueueOutcome::Shed(event) => assert_eq!(event.alert_id, info_id),
+            other => panic!("expected shed, got {:?}", other),
+        }
+        assert_eq!(queue.depth(), 2);
+        assert_eq!(queue.shed_count(), 1);
+        assert_eq!(queue.shed_events()[0].level, AlertLevel::Info);
+    }
+
+    #[test]
+    fn test_sheds_incoming_when_it_ranks_lowest() {
+        let queue: AlertQueue = AlertQueue::new(1);
+        queue.try_push(alert(AlertLevel::Critical, true)).unwrap();
+
+        let incoming: Alert = alert(AlertLevel::Critical, true);
+        let incoming_id: uuid::Uuid = incoming.id;
+        match queue.push_shedding(incoming) {
+            EnqueueOutcome::Shed(event) => assert_eq!(event.alert_id, incoming_id),
+            other => panic!("expected shed, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_never_blocks_when_consumer_is_wedged() {
+        let queue: AlertQueue = AlertQueue::new(2);
+        let result = tokio::time::timeout(Duration::from_secs(5), async {
+            for _ in 0..50 {
+                queue
+                    .enqueue(alert(AlertLevel::Info, false), DeliveryTrace::default())
+                    .await;
+            }
+        })
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(queue.depth(), 2);
+        assert_eq!(queue.shed_count(), 48);
+    }
+
+    fn prioritised(level: AlertLevel, priority: Option<u8>) -> Alert {
+        Alert {
+            priority,
+            ..alert(level, false)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recv_takes_highest_rank_then_arrival_order() {
+        let queue: AlertQueue = AlertQueue::new(10);
+        let info: Alert = prioritised(AlertLevel::Info, Some(100));
+        let first: Alert = prioritised(AlertLevel::Warning, None);
+        let second: Alert = prioritised(AlertLevel::Warning, None);
+        let urgent: Alert = prioritised(AlertLevel::Warning, Some(90));
+        let routine: Alert = prioritised(AlertLevel::Warning, Some(10));
+        let emergency: Alert = prioritised(AlertLevel::Emergency, None);
+        for queued in [&info, &first, &second, &urgent, &routine, &emergency] {
+            queue.try_push(queued.clone()).unwrap();
+        }
+
+        let mut order: Vec<uuid::Uuid> = Vec::new();
+        for _ in 0..6 {
+            order.push(queue.recv().await.id);
+        }
+        assert_eq!(
+            order,
+            [
+                emergency.id,
+                urgent.id,
+                routine.id,
+                first.id,
+                second.id,
+                info.id
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shed_alerts_are_reported() {
+        let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+        let queue: AlertQueue = AlertQueue::new(2).reporting_to(outbound.clone(), "test-client");
+        let low: Alert = prioritised(AlertLevel::Critical, Some(5));
+        queue
+            .try_push(prioritised(AlertLevel::Critical, Some(50)))
+            .unwrap();
+        queue.try_push(low.clone()).unwrap();
+        assert!(outbound.is_empty());
+
+        // Outranks the queued priority 5 alert, which is dropped for it
+        let incoming: Alert = prioritised(AlertLevel::Critical, Some(60));
+        match queue.push_shedding(incoming) {
+            EnqueueOutcome::Shed(event) => assert_eq!(event.alert_id, low.id),
+            other => panic!("expected shed, got {:?}", other),
+        }
+        match outbound.next().await {
+            OutboundMessage::DeliveryStatus(status) => {
+                assert_eq!(status.alert_id, low.id);
+                assert_eq!(status.client_id, "test-client");
+                assert_eq!(status.outcome, Some(DeliveryOutcome::QueueFull));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+        assert!(outbound.is_empty());
+    }
+}
@@ -0,0 +1,257 @@
+//! Cap on how fast the agent acts on alerts, so a runaway server cannot flood the desktop
+
+use crate::messages::{Alert, AlertErrorReason, AlertLevel, AlertOrigin};
+use crate::outbound::OutboundMessage;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::time::Instant;
+
+/// Info and Warning alerts acted on per minute before the rest are shed
+pub const DEFAULT_ALERT_RATE_PER_MINUTE: u32 = 30;
+
+/// Critical and Emergency alerts acted on per minute
+pub const DEFAULT_URGENT_ALERT_RATE_PER_MINUTE: u32 = 120;
+
+/// Alerts shed in one overload before the user and server are told about it
+const OVERLOAD_SHED_THRESHOLD: u64 = 30;
+
+/// Per-minute allowances; each is also the largest burst accepted at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    pub per_minute: u32,
+    /// Separate, higher allowance for Critical and Emergency alerts
+    pub urgent_per_minute: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_minute: DEFAULT_ALERT_RATE_PER_MINUTE,
+            urgent_per_minute: DEFAULT_URGENT_ALERT_RATE_PER_MINUTE,
+        }
+    }
+}
+
+/// Whether an alert may be acted on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateDecision {
+    Admit,
+    Shed,
+    /// Shed, and enough have been shed in this overload to warn about it; once per overload
+    ShedOverloaded,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_sec: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute: u32, now: Instant) -> Self {
+        let capacity: f64 = f64::from(per_minute.max(1));
+        Self {
+            capacity,
+            tokens: capacity,
+            per_sec: capacity / 60.0,
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed: f64 = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    routine: TokenBucket,
+    urgent: TokenBucket,
+    /// Shed since the buckets were last full
+    overload_shed: u64,
+    overload_reported: bool,
+}
+
+/// Token buckets for routine and urgent alerts, with counts of what each shed
+#[derive(Debug)]
+pub struct AlertRateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<Buckets>,
+    routine_shed: AtomicU64,
+    urgent_shed: AtomicU64,
+}
+
+impl AlertRateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let now: Instant = Instant::now();
+        Self {
+            config,
+            buckets: Mutex::new(Buckets {
+                routine: TokenBucket::new(config.per_minute, now),
+                urgent: TokenBucket::new(config.urgent_per_minute, now),
+                overload_shed: 0,
+                overload_reported: false,
+            }),
+            routine_shed: AtomicU64::new(0),
+            urgent_shed: AtomicU64::new(0),
+        }
+    }
+
+    /// Take a token for `alert`, or count it as shed
+    pub fn admit(&self, alert: &Alert, now: Instant) -> RateDecision {
+        let mut buckets = self.buckets.lock().unwrap();
+        // An overload ends once both allowances have fully recovered
+        if buckets.routine.is_full(now) && buckets.urgent.is_full(now) {
+            buckets.overload_shed = 0;
+            buckets.overload_reported = false;
+        }
+
+        let urgent: bool = matches!(alert.level, AlertLevel::Critical | AlertLevel::Emergency);
+        let (bucket, shed) = if urgent {
+            (&mut buckets.urgent, &self.urgent_shed)
+        } else {
+            (&mut buckets.routine, &self.routine_shed)
+        };
+        if bucket.try_take(now) {
+            return RateDecision::Admit;
+        }
+
+        shed.fetch_add(1, Ordering::Relaxed);
+        buckets.overload_shed += 1;
+        if buckets.overload_shed >= OVERLOAD_SHED_THRESHOLD && !buckets.overload_reported {
+            buckets.overload_reported = true;
+            return RateDecision::ShedOverloaded;
+        }
+        RateDecision::Shed
+    }
+
+    /// Allowances the limiter was created with
+    pub fn config(&self) -> RateLimitConfig {
+        self.config
+    }
+
+    /// Info and Warning alerts shed since startup
+    pub fn routine_shed(&self) -> u64 {
+        self.routine_shed.load(Ordering::Relaxed)
+    }
+
+    /// Critical and Emergency alerts shed since startup, past their higher allowance
+    pub fn urgent_shed(&self) -> u64 {
+        self.urgent_shed.load(Ordering::Relaxed)
+    }
+}
+
+fn overload_detail(config: &RateLimitConfig) -> String {
+    format!(
+        "Shedding alerts: {} per minute allowed for Info and Warning, {} for Critical and Emergency",
+        config.per_minute, config.urgent_per_minute
+    )
+}
+
+/// Toast warning the user that alerts are being dropped
+pub(crate) fn overload_warning(config: &RateLimitConfig) -> Alert {
+    Alert {
+        id: uuid::Uuid::new_v4(),
+        title: "Too many alerts; some are not being shown".to_string(),
+        message: format!(
+            "{}. Check with your administrator if you expected these alerts.",
+            overload_detail(config)
+        ),
+        level: AlertLevel::Warning,
+        requires_confirmation: false,
+        sound_file: None,
+        timestamp: chrono::Utc::now(),
+        origin: AlertOrigin::Local,
+        ..Default::default()
+    }
+}
+
+/// Report to the server that this client is shedding alerts
+pub(crate) fn overload_error(client_id: &str, config: &RateLimitConfig) -> OutboundMessage {
+    OutboundMessage::AlertError {
+        client_id: client_id.to_string(),
+        reason: AlertErrorReason::Overloaded,
+        alert_id: None,
+        detail: Some(overload_detail(config)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::alert;
+    use std::time::Duration;
+
+    #[test]
+    fn test_routine_alerts_shed_past_the_allowance() {
+        let limiter: AlertRateLimiter = AlertRateLimiter::new(RateLimitConfig {
+            per_minute: 2,
+            urgent_per_minute: 1,
+        });
+        let start: Instant = Instant::now();
+        let info: Alert = alert(AlertLevel::Info, false);
+        let emergency: Alert = alert(AlertLevel::Emergency, false);
+
+        assert_eq!(limiter.admit(&info, start), RateDecision::Admit);
+        assert_eq!(limiter.admit(&info, start), RateDecision::Admit);
+        assert_eq!(limiter.admit(&info, start), RateDecision::Shed);
+        // Urgent alerts draw on their own allowance
+        assert_eq!(limiter.admit(&emergency, start), RateDecision::Admit);
+        assert_eq!(limiter.admit(&emergency, start), RateDecision::Shed);
+        assert_eq!((limiter.routine_shed(), limiter.urgent_shed()), (1, 1));
+
+        // Half a minute refills one of two routine tokens
+        let later: Instant = start + Duration::from_secs(30);
+        assert_eq!(limiter.admit(&info, later), RateDecision::Admit);
+        assert_eq!(limiter.admit(&info, later), RateDecision::Shed);
+    }
+
+    #[test]
+    fn test_overload_is_reported_once_until_it_ends() {
+        let limiter: AlertRateLimiter = AlertRateLimiter::new(RateLimitConfig {
+            per_minute: 1,
+            urgent_per_minute: 1,
+        });
+        let start: Instant = Instant::now();
+        let info: Alert = alert(AlertLevel::Info, false);
+        let decisions: Vec<RateDecision> = (0..100).map(|_| limiter.admit(&info, start)).collect();
+        assert_eq!(decisions[0], RateDecision::Admit);
+        assert_eq!(
+            decisions
+                .iter()
+                .filter(|d| **d == RateDecision::ShedOverloaded)
+                .count(),
+            1
+        );
+        assert_eq!(
+            decisions[OVERLOAD_SHED_THRESHOLD as usize],
+            RateDecision::ShedOverloaded
+        );
+
+        // After a quiet minute a new flood is reported again
+        let later: Instant = start + Duration::from_secs(60);
+        let decisions: Vec<RateDecision> = (0..100).map(|_| limiter.admit(&info, later)).collect();
+        assert!(decisions.contains(&RateDecision::ShedOverloaded));
+    }
+}
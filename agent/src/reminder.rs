@@ -0,0 +1,125 @@
+//! Reminders POSTed to the user's own webhook for urgent alerts left unconfirmed
+
+use crate::error::{EmnsError, Result};
+use crate::messages::{Alert, AlertLevel};
+use crate::sanitize::sanitize_text;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Default wait before an unconfirmed alert is reminded about
+pub const DEFAULT_REMINDER_AFTER: Duration = Duration::from_secs(300);
+
+/// Limit on one reminder request
+pub const REMINDER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait before the one retry of a failed reminder
+pub const REMINDER_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Longest title a reminder carries
+pub const REMINDER_TITLE_CHARS: usize = 60;
+
+/// Where reminders go and when
+#[derive(Debug, Clone)]
+pub struct ReminderConfig {
+    pub webhook_url: String,
+    /// How long a Critical or Emergency alert waits for confirmation before the reminder
+    pub after: Duration,
+    /// Send the alert's message too; only the title is sent otherwise
+    pub include_body: bool,
+}
+
+impl ReminderConfig {
+    pub fn new(webhook_url: impl Into<String>) -> Self {
+        Self {
+            webhook_url: webhook_url.into(),
+            after: DEFAULT_REMINDER_AFTER,
+            include_body: false,
+        }
+    }
+
+    /// Whether `alert` gets a reminder while it waits for confirmation
+    pub fn applies_to(&self, alert: &Alert) -> bool {
+        alert.requires_confirmation
+            && !alert.is_preview
+            && matches!(alert.level, AlertLevel::Critical | AlertLevel::Emergency)
+    }
+}
+
+/// JSON body POSTed to the reminder webhook
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReminderBody {
+    pub title: String,
+    pub level: AlertLevel,
+    /// When the alert is auto-confirmed if nobody answers it; absent if it
+    /// waits until someone does
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl ReminderBody {
+    pub fn new(
+        alert: &Alert,
+        deadline: Option<chrono::DateTime<chrono::Utc>>,
+        include_body: bool,
+    ) -> Self {
+        Self {
+            title: sanitize_text(&alert.title, REMINDER_TITLE_CHARS).0,
+            level: alert.level.clone(),
+            deadline,
+            message: include_body.then(|| alert.message.clone()),
+        }
+    }
+}
+
+/// Sends reminders to the configured webhook
+pub struct ReminderSender {
+    config: ReminderConfig,
+    client: reqwest::Client,
+}
+
+impl ReminderSender {
+    pub fn new(config: ReminderConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn config(&self) -> &ReminderConfig {
+        &self.config
+    }
+
+    /// POST `body` to the webhook, retrying once after [`REMINDER_RETRY_DELAY`]
+    pub async fn send(&self, body: &ReminderBody) -> Result<()> {
+        if let Err(e) = self.post(body).await {
+            log::warn!("Reminder for {:?} failed, retrying: {}", body.title, e);
+            tokio::time::sleep(REMINDER_RETRY_DELAY).await;
+            self.post(body).await?;
+        }
+        Ok(())
+    }
+
+    async fn post(&self, body: &ReminderBody) -> Result<()> {
+        let url: &str = &self.config.webhook_url;
+        let response: reqwest::Response = self
+            .client
+            .post(url)
+            .json(body)
+            .timeout(REMINDER_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| EmnsError::connection(url, e))?;
+        if !response.status().is_success() {
+            return Err(EmnsError::connection(
+                url,
+                format!("answered {}", response.status()),
+            ));
+        }
+        Ok(())
+    }
+}
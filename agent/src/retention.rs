@@ -0,0 +1,221 @@
+//! Pruning the alert history and the attachments it references, so a machine
+//! nobody looks after does not fill its disk
+
+use crate::attachments::AttachmentStore;
+use crate::error::Result;
+use crate::handler::AlertHandler;
+use crate::history::{AlertHistory, Compaction, HistoryEntry};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// Default age after which history entries are pruned
+pub const DEFAULT_HISTORY_RETENTION: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// Default number of alerts the history file is cut back to
+pub const DEFAULT_HISTORY_MAX_ENTRIES: usize = 10_000;
+
+/// How often the running agent prunes
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How much of the history is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionConfig {
+    pub history_max_age: Duration,
+    /// Most alerts kept in the history file; the oldest go first
+    pub history_max_entries: usize,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            history_max_age: DEFAULT_HISTORY_RETENTION,
+            history_max_entries: DEFAULT_HISTORY_MAX_ENTRIES,
+        }
+    }
+}
+
+/// What one pruning pass removed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    /// Alerts dropped from the history for their age or to stay under the cap
+    pub history_removed: Vec<Uuid>,
+    /// History lines folded into a later update of the same alert
+    pub history_lines_compacted: usize,
+    /// Attachment folders of alerts no longer in the history
+    pub attachments_removed: usize,
+}
+
+impl std::fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} history entr{} removed, {} superseded line(s) compacted, {} attachment folder(s) removed",
+            self.history_removed.len(),
+            if self.history_removed.len() == 1 { "y" } else { "ies" },
+            self.history_lines_compacted,
+            self.attachments_removed
+        )
+    }
+}
+
+/// The entries to keep, oldest first: those newer than the retention age, cut
+/// to the cap, plus every alert in `pending` whatever its age
+fn retained(
+    entries: Vec<HistoryEntry>,
+    config: &RetentionConfig,
+    pending: &HashSet<Uuid>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<HistoryEntry> {
+    let cutoff: chrono::DateTime<chrono::Utc> = chrono::TimeDelta::from_std(config.history_max_age)
+        .ok()
+        .and_then(|age| now.checked_sub_signed(age))
+        .unwrap_or(chrono::DateTime::<chrono::Utc>::MIN_UTC);
+    let mut kept: Vec<HistoryEntry> = entries
+        .into_iter()
+        .filter(|e| pending.contains(&e.alert_id) || e.received_at >= cutoff)
+        .collect();
+
+    let mut over: usize = kept
+        .iter()
+        .filter(|e| !pending.contains(&e.alert_id))
+        .count()
+        .saturating_sub(config.history_max_entries);
+    kept.retain(|e| {
+        if over > 0 && !pending.contains(&e.alert_id) {
+            over -= 1;
+            false
+        } else {
+            true
+        }
+    });
+    kept
+}
+
+/// Prune `history` and remove the attachments of alerts it no longer holds.
+///
+/// Alerts in `pending` are awaiting confirmation and are never pruned.
+pub fn prune(
+    history: &AlertHistory,
+    attachments: &AttachmentStore,
+    config: &RetentionConfig,
+    pending: &HashSet<Uuid>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<PruneReport> {
+    let mut kept_ids: HashSet<Uuid> = HashSet::new();
+    let compaction: Compaction = history.compact(|entries| {
+        let kept: Vec<HistoryEntry> = retained(entries, config, pending, now);
+        kept_ids.extend(kept.iter().map(|e| e.alert_id));
+        kept
+    })?;
+
+    // Alerts recorded since the rewrite may already be downloading into their folders
+    let mut referenced: HashSet<Uuid> = history.alert_ids();
+    referenced.extend(kept_ids);
+    referenced.extend(pending.iter().copied());
+    let attachments_removed: usize = attachments.remove_unreferenced(&referenced)?;
+
+    Ok(PruneReport {
+        history_removed: compaction.removed.iter().map(|e| e.alert_id).collect(),
+        history_lines_compacted: compaction.superseded_lines,
+        attachments_removed,
+    })
+}
+
+/// Prune now and every [`PRUNE_INTERVAL`] until `cancel` fires
+pub async fn run_pruner(
+    handler: Arc<AlertHandler>,
+    attachments: Arc<AttachmentStore>,
+    config: RetentionConfig,
+    cancel: CancellationToken,
+) {
+    loop {
+        let pending: HashSet<Uuid> = handler
+            .pending_alerts()
+            .await
+            .iter()
+            .map(|alert| alert.id)
+            .collect();
+        let pruning = tokio::task::spawn_blocking({
+            let handler: Arc<AlertHandler> = handler.clone();
+            let attachments: Arc<AttachmentStore> = attachments.clone();
+            move || {
+                prune(
+                    handler.history(),
+                    &attachments,
+                    &config,
+                    &pending,
+                    chrono::Utc::now(),
+                )
+            }
+        });
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            pruned = pruning => match pruned {
+                Ok(Ok(report)) => log::info!("Pruned agent data: {}", report),
+                Ok(Err(e)) => log::warn!("Pruning agent data failed: {}", e),
+                Err(e) => log::warn!("Pruning agent data panicked: {}", e),
+            },
+        }
+
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = tokio::time::sleep(PRUNE_INTERVAL) => {}
+        }
+    }
+    log::debug!("Pruner stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{AlertLevel, AlertOrigin};
+
+    fn entry(days_old: i64, now: chrono::DateTime<chrono::Utc>) -> HistoryEntry {
+        let received_at = now - chrono::TimeDelta::days(days_old);
+        HistoryEntry {
+            alert_id: Uuid::new_v4(),
+            level: AlertLevel::Info,
+            title: format!("{} days old", days_old),
+            message: String::new(),
+            sent_at: received_at,
+            received_at,
+            requires_confirmation: false,
+            origin: AlertOrigin::Server,
+            original_title_len: 0,
+            original_message_len: 0,
+            escalated_at: None,
+            sealed: false,
+            callback: None,
+            withheld: None,
+            timing: None,
+            decision: None,
+        }
+    }
+
+    #[test]
+    fn test_cap_drops_oldest_but_never_pending() {
+        let now = chrono::Utc::now();
+        let entries: Vec<HistoryEntry> = [5, 4, 3, 2, 1].map(|days| entry(days, now)).to_vec();
+        let pending: HashSet<Uuid> = HashSet::from([entries[0].alert_id]);
+        let config: RetentionConfig = RetentionConfig {
+            history_max_entries: 2,
+            ..RetentionConfig::default()
+        };
+
+        let kept: Vec<Uuid> = retained(entries.clone(), &config, &pending, now)
+            .iter()
+            .map(|e| e.alert_id)
+            .collect();
+        assert_eq!(
+            kept,
+            [
+                entries[0].alert_id,
+                entries[3].alert_id,
+                entries[4].alert_id
+            ]
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use crate::messages::Alert;
+use unicode_normalization::UnicodeNormalization;
+
+/// Default maximum number of characters kept in an alert title
+pub const DEFAULT_MAX_TITLE_CHARS: usize = 200;
+
+/// Default maximum number of characters kept in an alert message
+pub const DEFAULT_MAX_MESSAGE_CHARS: usize = 2000;
+
+const ELLIPSIS: char = '…';
+
+/// Upper bounds applied to alert text before it reaches the toast, audio, or logs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextLimits {
+    pub max_title_chars: usize,
+    pub max_message_chars: usize,
+}
+
+impl Default for TextLimits {
+    fn default() -> Self {
+        Self {
+            max_title_chars: DEFAULT_MAX_TITLE_CHARS,
+            max_message_chars: DEFAULT_MAX_MESSAGE_CHARS,
+        }
+    }
+}
+
+/// Summary of what sanitization changed, kept for forensics
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SanitizeReport {
+    /// Title length in characters as received from the server
+    pub original_title_len: usize,
+    /// Message length in characters as received from the server
+    pub original_message_len: usize,
+    pub title_truncated: bool,
+    pub message_truncated: bool,
+}
+
+/// Sanitize the title and message of an alert in place
+pub fn sanitize_alert(alert: &mut Alert, limits: &TextLimits) -> SanitizeReport {
+    let original_title_len: usize = alert.title.chars().count();
+    let original_message_len: usize = alert.message.chars().count();
+
+    let (title, title_truncated) = sanitize_text(&alert.title, limits.max_title_chars);
+    let (message, message_truncated) = sanitize_text(&alert.message, limits.max_message_chars);
+    alert.title = title;
+    alert.message = message;
+    for translation in alert.translations.values_mut() {
+        translation.title = sanitize_text(&translation.title, limits.max_title_chars).0;
+        translation.message = sanitize_text(&translation.message, limits.max_message_chars).0;
+    }
+
+    SanitizeReport {
+        original_title_len,
+        original_message_len,
+        title_truncated,
+        message_truncated,
+    }
+}
+
+/// Strip control and bidi override characters, normalize to NFC, and cap the length.
+///
+/// Returns the cleaned string and whether it had to be truncated.
+pub fn sanitize_text(input: &str, max_chars: usize) -> (String, bool) {
+    let cleaned: String = input.chars().filter(|c| is_allowed(*c)).nfc().collect();
+
+    if cleaned.chars().count() <= max_chars {
+        return (cleaned, false);
+    }
+
+    let mut truncated: String = cleaned.chars().take(max_chars.saturating_sub(1)).collect();
+    if max_chars > 0 {
+        truncated.push(ELLIPSIS);
+    }
+    (truncated, true)
+}
+
+/// Whether a character may appear in displayed alert text
+fn is_allowed(c: char) -> bool {
+    if c == '\n' || c == '\t' {
+        return true;
+    }
+    // C0, DEL, and C1 control characters
+    if c.is_control() {
+        return false;
+    }
+    // Bidi embeddings/overrides (LRE..RLO) and isolates (LRI..PDI)
+    if ('\u{202A}'..='\u{202E}').contains(&c) || ('\u{2066}'..='\u{2069}').contains(&c) {
+        return false;
+    }
+    // Noncharacters that are not valid in XML documents
+    !matches!(c, '\u{FFFE}' | '\u{FFFF}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Characters allowed by the XML 1.0 `Char` production
+    fn is_xml_char(c: char) -> bool {
+        matches!(c,
+            '\t' | '\n' | '\r'
+            | '\u{20}'..='\u{D7FF}'
+            | '\u{E000}'..='\u{FFFD}'
+            | '\u{10000}'..='\u{10FFFF}')
+    }
+
+    #[test]
+    fn test_strips_controls_and_bidi() {
+        let (out, truncated) = sanitize_text("a\u{0007}b\u{202E}c\u{2066}d\u{0085}e\r\n\tf", 100);
+        assert_eq!(out, "abcde\n\tf");
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn test_normalizes_to_nfc() {
+        let (out, _) = sanitize_text("e\u{0301}", 100);
+        assert_eq!(out, "\u{00E9}");
+    }
+
+    #[test]
+    fn test_truncates_with_ellipsis() {
+        let (out, truncated) = sanitize_text("abcdefghij", 5);
+        assert_eq!(out, "abcd…");
+        assert!(truncated);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_output_within_bounds_and_xml_safe(input in any::<String>(), max in 0usize..300) {
+            let (out, _) = sanitize_text(&input, max);
+            prop_assert!(out.chars().count() <= max);
+            prop_assert!(out.chars().all(is_xml_char));
+            prop_assert!(!out.contains('\r'));
+        }
+    }
+}
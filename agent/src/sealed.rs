@@ -0,0 +1,262 @@
+//! Alert bodies sealed to this agent's key, so the server routing them cannot read them
+
+use crate::error::{EmnsError, Result};
+use crate::messages::{SealedBody, SealedContent};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// HKDF info string binding derived keys to this envelope format
+const KDF_INFO: &[u8] = b"emns sealed alert v1";
+
+/// Title given to a sealed alert wherever its own cannot be read
+pub const UNREADABLE_TITLE: &str = "Encrypted alert";
+
+/// Message shown for a sealed alert this agent cannot decrypt
+pub const UNREADABLE_MESSAGE: &str =
+    "An encrypted alert could not be read on this machine. Contact your security office.";
+
+/// This agent's X25519 key pair; alerts sealed to its public key are opened with it
+#[derive(Clone)]
+pub struct AlertKey {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl std::fmt::Debug for AlertKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AlertKey({})", self.key_id())
+    }
+}
+
+impl AlertKey {
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::random_from_rng(rand::rngs::OsRng))
+    }
+
+    pub fn from_secret_bytes(secret: [u8; 32]) -> Self {
+        Self::from_secret(StaticSecret::from(secret))
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public: PublicKey = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub(crate) fn secret_bytes(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
+
+    /// The public key, base64, as sent in registration
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.public.as_bytes())
+    }
+
+    /// Fingerprint of the public key, as carried in [`SealedBody::key_id`]
+    pub fn key_id(&self) -> String {
+        key_id(&self.public)
+    }
+
+    /// Decrypt `sealed`, which must have been sealed to this key for `alert_id`
+    pub fn open(&self, alert_id: uuid::Uuid, sealed: &SealedBody) -> Result<SealedContent> {
+        if sealed.key_id != self.key_id() {
+            return Err(EmnsError::protocol(format!(
+                "sealed to key {}, this agent's key is {}",
+                sealed.key_id,
+                self.key_id()
+            )));
+        }
+        let ephemeral: PublicKey =
+            PublicKey::from(decode_array::<32>("ephemeral_key", &sealed.ephemeral_key)?);
+        let nonce: [u8; 12] = decode_array("nonce", &sealed.nonce)?;
+        let ciphertext: Vec<u8> = BASE64
+            .decode(&sealed.ciphertext)
+            .map_err(|e| EmnsError::protocol(format!("ciphertext is not base64: {}", e)))?;
+
+        let cipher: ChaCha20Poly1305 = cipher(&self.secret, &ephemeral, &ephemeral, &self.public)?;
+        let plaintext: Vec<u8> = cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: alert_id.as_bytes(),
+                },
+            )
+            .map_err(|_| EmnsError::protocol("ciphertext does not authenticate"))?;
+        serde_json::from_slice(&plaintext)
+            .map_err(|e| EmnsError::protocol(format!("sealed content is not valid: {}", e)))
+    }
+}
+
+/// Seal `content` for alert `alert_id` to a client's base64 `encryption_key`
+pub fn seal(
+    content: &SealedContent,
+    alert_id: uuid::Uuid,
+    encryption_key: &str,
+) -> Result<SealedBody> {
+    let recipient: PublicKey =
+        PublicKey::from(decode_array::<32>("encryption_key", encryption_key)?);
+    let mut nonce: [u8; 12] = [0; 12];
+    rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut nonce);
+    seal_with(
+        content,
+        alert_id,
+        &recipient,
+        StaticSecret::random_from_rng(rand::rngs::OsRng),
+        nonce,
+    )
+}
+
+fn seal_with(
+    content: &SealedContent,
+    alert_id: uuid::Uuid,
+    recipient: &PublicKey,
+    ephemeral: StaticSecret,
+    nonce: [u8; 12],
+) -> Result<SealedBody> {
+    let ephemeral_public: PublicKey = PublicKey::from(&ephemeral);
+    let cipher: ChaCha20Poly1305 = cipher(&ephemeral, recipient, &ephemeral_public, recipient)?;
+    let plaintext: Vec<u8> = serde_json::to_vec(content)?;
+    let ciphertext: Vec<u8> = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &plaintext,
+                aad: alert_id.as_bytes(),
+            },
+        )
+        .map_err(|_| EmnsError::protocol("alert body could not be encrypted"))?;
+    Ok(SealedBody {
+        key_id: key_id(recipient),
+        ephemeral_key: BASE64.encode(ephemeral_public.as_bytes()),
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(ciphertext),
+    })
+}
+
+/// The cipher for one envelope: X25519 of `secret` with `peer`, stretched by
+/// HKDF-SHA256 salted with the one-time key then the recipient's
+fn cipher(
+    secret: &StaticSecret,
+    peer: &PublicKey,
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> Result<ChaCha20Poly1305> {
+    let shared = secret.diffie_hellman(peer);
+    // A low-order key would make the secret predictable
+    if !shared.was_contributory() {
+        return Err(EmnsError::protocol("key agreement produced no secret"));
+    }
+    let salt: Vec<u8> = [ephemeral.as_bytes().as_slice(), recipient.as_bytes()].concat();
+    let mut key: [u8; 32] = [0; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(KDF_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+fn key_id(public: &PublicKey) -> String {
+    Sha256::digest(public.as_bytes())
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn decode_array<const N: usize>(field: &str, value: &str) -> Result<[u8; N]> {
+    BASE64
+        .decode(value)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| EmnsError::protocol(format!("{} is not {} base64 bytes", field, N)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Alice's and Bob's keys from RFC 7748 section 6.1
+    const ALICE_SECRET: &str = "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a";
+    const ALICE_PUBLIC: &str = "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a";
+    const BOB_SECRET: &str = "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb";
+    const BOB_PUBLIC: &str = "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f";
+
+    fn hex32(hex: &str) -> [u8; 32] {
+        let bytes: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        bytes.try_into().unwrap()
+    }
+
+    fn alert_id() -> uuid::Uuid {
+        uuid::Uuid::parse_str("123e4567-e89b-12d3-a456-426614174000").unwrap()
+    }
+
+    fn content() -> SealedContent {
+        SealedContent {
+            title: "Protective detail".to_string(),
+            message: "Principal arriving at the north entrance at 14:00".to_string(),
+        }
+    }
+
+    /// The envelope Bob's one-time key and a zero nonce give for Alice
+    fn vector() -> SealedBody {
+        SealedBody {
+            key_id: "300c9c9603b92a4b".to_string(),
+            ephemeral_key: BASE64.encode(hex32(BOB_PUBLIC)),
+            nonce: "AAAAAAAAAAAAAAAA".to_string(),
+            ciphertext: "wyJgo2o+CuXkjKOVzO1Jnq2JmZ5RUcExxur44nqyT7/wF6BCPm1ZtGV5CodE4K0F\
+                         1yT7u2Xm04cZBEeJKeYxZqUvwbNArNARjtJ7s8CauwWFr2afG6YnU2pVd1DlicgT\
+                         om4c/35H41Zrr0g="
+                .to_string(),
+        }
+    }
+
+    #[test]
+    fn test_key_matches_rfc_7748() {
+        let alice: AlertKey = AlertKey::from_secret_bytes(hex32(ALICE_SECRET));
+        assert_eq!(alice.public_key(), BASE64.encode(hex32(ALICE_PUBLIC)));
+    }
+
+    #[test]
+    fn test_envelope_matches_fixed_vector() {
+        let alice: AlertKey = AlertKey::from_secret_bytes(hex32(ALICE_SECRET));
+        let sealed: SealedBody = seal_with(
+            &content(),
+            alert_id(),
+            &alice.public,
+            StaticSecret::from(hex32(BOB_SECRET)),
+            [0; 12],
+        )
+        .unwrap();
+        assert_eq!(sealed, vector());
+        assert_eq!(alice.open(alert_id(), &vector()).unwrap(), content());
+    }
+
+    #[test]
+    fn test_open_refuses_tampering_and_other_keys() {
+        let alice: AlertKey = AlertKey::from_secret_bytes(hex32(ALICE_SECRET));
+        let sealed: SealedBody = seal(&content(), alert_id(), &alice.public_key()).unwrap();
+        assert_eq!(alice.open(alert_id(), &sealed).unwrap(), content());
+
+        // Moved onto another alert
+        assert!(alice.open(uuid::Uuid::new_v4(), &sealed).is_err());
+
+        let mut flipped: Vec<u8> = BASE64.decode(&sealed.ciphertext).unwrap();
+        flipped[0] ^= 1;
+        let tampered: SealedBody = SealedBody {
+            ciphertext: BASE64.encode(flipped),
+            ..sealed.clone()
+        };
+        assert!(alice.open(alert_id(), &tampered).is_err());
+
+        let other: AlertKey = AlertKey::generate();
+        let err: EmnsError = other.open(alert_id(), &sealed).unwrap_err();
+        assert!(err.to_string().contains(&alice.key_id()));
+    }
+}
@@ -0,0 +1,260 @@
+//! The `--session-helper` process: shows broker alerts in one user session
+
+use crate::agent;
+use crate::attachments::{AttachmentConfig, AttachmentStore};
+use crate::broker::{self, read_message, write_message, PipeMessage, DEFAULT_PIPE_NAME};
+use crate::burst::BurstConfig;
+use crate::client;
+use crate::config;
+use crate::error::{EmnsError, Result};
+use crate::escalation::EscalationPolicy;
+use crate::handler::AlertHandler;
+use crate::notification::ActivationArgs;
+use crate::operator::{OperatorIdConfig, OperatorIdentity};
+use crate::outbound::{OutboundMessage, OutboundQueue};
+use crate::sanitize::TextLimits;
+use crate::toast_style::ToastStyles;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Wait between attempts to reach the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Settings for a session helper, read from the environment
+#[derive(Debug, Clone)]
+pub struct SessionHelperConfig {
+    pub pipe_name: String,
+    pub sounds_dir: PathBuf,
+    /// Attachments are downloaded under this directory, in the user's session
+    pub data_dir: PathBuf,
+    pub attachments: AttachmentConfig,
+    pub text_limits: TextLimits,
+    pub idle_auto_confirm_extension: Option<Duration>,
+    pub pause_auto_confirm_while_locked: bool,
+    pub escalation: EscalationPolicy,
+    pub burst: Option<BurstConfig>,
+    pub toast_styles: ToastStyles,
+    /// Ask for an operator id at each confirmation; disabled when `None`
+    pub operator_identity: Option<OperatorIdConfig>,
+}
+
+impl SessionHelperConfig {
+    pub fn from_env() -> Self {
+        let defaults: TextLimits = TextLimits::default();
+        let limit = |name: &str, default: usize| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .filter(|n: &usize| *n > 0)
+                .unwrap_or(default)
+        };
+        let data_dir: PathBuf = std::env::var("DATA_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("./data"));
+        Self {
+            pipe_name: std::env::var("SESSION_PIPE_NAME")
+                .unwrap_or_else(|_| DEFAULT_PIPE_NAME.to_string()),
+            sounds_dir: std::env::var("SOUNDS_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| PathBuf::from("./sounds")),
+            attachments: config::attachments_from_env(),
+            text_limits: TextLimits {
+                max_title_chars: limit("MAX_TITLE_CHARS", defaults.max_title_chars),
+                max_message_chars: limit("MAX_MESSAGE_CHARS", defaults.max_message_chars),
+            },
+            idle_auto_confirm_extension: std::env::var("IDLE_AUTO_CONFIRM_EXTENSION_SECS")
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .filter(|secs: &u64| *secs > 0)
+                .map(Duration::from_secs),
+            pause_auto_confirm_while_locked: config::env_bool("PAUSE_AUTO_CONFIRM_WHILE_LOCKED")
+                .unwrap_or_else(|e| {
+                    log::warn!("{}; auto-confirm keeps counting while locked", e);
+                    None
+                })
+                .unwrap_or(false),
+            escalation: config::escalation_from_env(),
+            burst: config::burst_from_env().unwrap_or_else(|e| {
+                log::warn!("{}; using default burst coalescing", e);
+                Some(BurstConfig::default())
+            }),
+            toast_styles: config::toast_styles_from_env().unwrap_or_else(|e| {
+                log::warn!("{}; using default toast styles", e);
+                ToastStyles::default()
+            }),
+            operator_identity: config::operator_identity_from_env(&data_dir).unwrap_or_else(|e| {
+                log::warn!("{}; confirming without an operator id", e);
+                None
+            }),
+            data_dir,
+        }
+    }
+}
+
+/// Relay one broker connection: alerts into `handler`, confirmations back out.
+///
+/// Returns when the broker closes the pipe or `cancel` fires.
+pub async fn serve<S>(
+    stream: S,
+    session_id: u32,
+    username: &str,
+    handler: &AlertHandler,
+    outbound: &OutboundQueue,
+    cancel: &CancellationToken,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    write_message(
+        &mut writer,
+        &PipeMessage::Hello {
+            session_id,
+            username: username.to_string(),
+        },
+    )
+    .await?;
+
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            message = read_message(&mut lines) => match message? {
+                Some(PipeMessage::Alert {
+                    alert,
+                    sound_policy,
+                    server_name,
+                    server_environment,
+                }) => {
+                    if let Some(policy) = sound_policy {
+                        if let Err(e) = handler.settings().update(|s| s.set_sound_policy(policy)) {
+                            log::error!("Rejected sound policy from broker: {}", e);
+                        }
+                    }
+                    // Brokers that predate server identity send neither; keep our own
+                    if server_name.is_some() || server_environment.is_some() {
+                        let _ = handler.settings().update(|s| {
+                            s.set_server_identity(server_name, server_environment);
+                            Ok(())
+                        });
+                    }
+                    if let Err(e) = handler.handle_alert(*alert).await {
+                        log::error!("Failed to handle alert: {}", e);
+                    }
+                }
+                Some(other) => log::warn!("Unexpected message from broker: {:?}", other),
+                None => return Ok(()),
+            },
+            message = outbound.next() => {
+                // The service holding the connection reports delivery itself
+                let OutboundMessage::Confirmation(confirmation) = message else {
+                    log::debug!("Not relaying {:?} message to the broker", message.priority());
+                    continue;
+                };
+                let relayed: PipeMessage = PipeMessage::Confirm {
+                    alert_id: confirmation.alert_id,
+                    username: confirmation.username.clone(),
+                    operator_id: confirmation.operator_id.clone(),
+                    confirmed_at: confirmation.confirmed_at,
+                    reason: confirmation.reason,
+                    method: confirmation.method,
+                    user_idle_secs: confirmation.user_idle_secs,
+                    response_id: confirmation.response_id.clone(),
+                    response: confirmation.response.clone(),
+                    shown_at: confirmation.shown_at,
+                    response_latency_ms: confirmation.response_latency_ms,
+                    is_preview: confirmation.is_preview,
+                };
+                if let Err(e) = write_message(&mut writer, &relayed).await {
+                    // Sent again once the helper reconnects to the broker
+                    outbound.requeue(OutboundMessage::Confirmation(confirmation));
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// Run the helper until `cancel` fires, reconnecting to the broker as needed
+pub async fn run(config: SessionHelperConfig, cancel: CancellationToken) -> Result<()> {
+    let tracker: TaskTracker = TaskTracker::new();
+    let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+    let (activation_tx, activation_rx) = mpsc::unbounded_channel::<ActivationArgs>();
+    let mut handler = AlertHandler::builder(outbound.clone(), "session-helper")
+        .sounds_dir(config.sounds_dir.clone())
+        .text_limits(config.text_limits)
+        .idle_extension(config.idle_auto_confirm_extension)
+        .pause_while_locked(config.pause_auto_confirm_while_locked)
+        .escalation(config.escalation.clone())
+        .burst_coalescing(config.burst)
+        .toast_styles(config.toast_styles)
+        .attachment_store(Arc::new(AttachmentStore::new(
+            &config.data_dir,
+            &config.attachments,
+        )))
+        .toast_activations(activation_tx)
+        .cancellation(cancel.child_token())
+        .task_tracker(tracker.clone());
+    if let Some(operator) = config.operator_identity {
+        handler = handler.operator_identity(Arc::new(OperatorIdentity::new(operator)));
+    }
+    let handler: Arc<AlertHandler> = Arc::new(handler.build());
+    tracker.spawn(agent::run_activation_loop(
+        handler.clone(),
+        activation_rx,
+        cancel.child_token(),
+        tracker.clone(),
+    ));
+
+    let session_id: u32 = broker::current_session_id();
+    let username: String = client::get_username();
+    log::info!(
+        "Session helper for {} (session {}) connecting to {}",
+        username,
+        session_id,
+        config.pipe_name
+    );
+
+    while !cancel.is_cancelled() {
+        match connect(&config.pipe_name).await {
+            Ok(pipe) => {
+                log::info!("Connected to session broker");
+                if let Err(e) =
+                    serve(pipe, session_id, &username, &handler, &outbound, &cancel).await
+                {
+                    log::warn!("Session broker connection failed: {}", e);
+                }
+            }
+            Err(e) => log::debug!("Session broker not reachable: {}", e),
+        }
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+        }
+    }
+
+    tracker.close();
+    tracker.wait().await;
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+async fn connect(pipe_name: &str) -> Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    tokio::net::windows::named_pipe::ClientOptions::new()
+        .open(pipe_name)
+        .map_err(|e| EmnsError::connection(pipe_name, e))
+}
+
+/// Named pipes are only available on Windows
+#[cfg(not(target_os = "windows"))]
+async fn connect(pipe_name: &str) -> Result<tokio::io::DuplexStream> {
+    Err(EmnsError::config(
+        "SESSION_PIPE_NAME",
+        format!("session helpers need Windows named pipes ({})", pipe_name),
+    ))
+}
@@ -0,0 +1,431 @@
+//! Runtime-tunable settings shared by the agent's components
+
+use crate::error::{EmnsError, Result};
+use crate::messages::{Alert, SoundPolicy};
+use chrono::NaiveTime;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// A daily window during which routine alert sounds are muted
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    /// Whether `time` falls in the window; windows may wrap past midnight
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Settings that may change while the agent is running.
+///
+/// Fields are only changed through validated setters; deserialized values
+/// should be checked with [`AgentSettings::validate`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AgentSettings {
+    auto_confirm_timeout_secs: u64,
+    heartbeat_interval_secs: u64,
+    status_interval_secs: u64,
+    reconnect_delay_secs: u64,
+    volume: f32,
+    sounds_enabled: bool,
+    quiet_hours: Option<QuietHours>,
+    show_alert_id: bool,
+    sound_policy: SoundPolicy,
+    /// Name of the server shown on toasts, e.g. "EMNS"
+    server_name: Option<String>,
+    /// Environment shown after the server name, e.g. "production" or "test"
+    server_environment: Option<String>,
+}
+
+impl Default for AgentSettings {
+    fn default() -> Self {
+        Self {
+            auto_confirm_timeout_secs: 300,
+            heartbeat_interval_secs: 30,
+            status_interval_secs: 60,
+            reconnect_delay_secs: 5,
+            volume: 1.0,
+            sounds_enabled: true,
+            quiet_hours: None,
+            show_alert_id: true,
+            sound_policy: SoundPolicy::default(),
+            server_name: None,
+            server_environment: None,
+        }
+    }
+}
+
+impl AgentSettings {
+    /// How long an alert waits for the user before it is confirmed
+    /// automatically; `None` waits until someone confirms it
+    pub fn auto_confirm_timeout(&self) -> Option<Duration> {
+        (self.auto_confirm_timeout_secs > 0)
+            .then(|| Duration::from_secs(self.auto_confirm_timeout_secs))
+    }
+
+    /// The timeout for `alert`: its own `confirm_timeout_secs` when it sets
+    /// one, `0` there too meaning it waits until someone confirms it
+    pub fn auto_confirm_timeout_for(&self, alert: &Alert) -> Option<Duration> {
+        match alert.confirm_timeout_secs {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(u64::from(secs))),
+            None => self.auto_confirm_timeout(),
+        }
+    }
+
+    /// Stored as 0 seconds when `None`
+    pub fn set_auto_confirm_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.auto_confirm_timeout_secs = match timeout {
+            Some(timeout) => secs_in_range("auto_confirm_timeout", timeout, 1, 86_400)?,
+            None => 0,
+        };
+        Ok(())
+    }
+
+    /// Interval between heartbeats sent to the server
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs)
+    }
+
+    pub fn set_heartbeat_interval(&mut self, interval: Duration) -> Result<()> {
+        self.heartbeat_interval_secs = secs_in_range("heartbeat_interval", interval, 1, 3_600)?;
+        Ok(())
+    }
+
+    /// Interval between status reports sent to the server
+    pub fn status_interval(&self) -> Duration {
+        Duration::from_secs(self.status_interval_secs)
+    }
+
+    pub fn set_status_interval(&mut self, interval: Duration) -> Result<()> {
+        self.status_interval_secs = secs_in_range("status_interval", interval, 1, 86_400)?;
+        Ok(())
+    }
+
+    /// Pause before reconnecting after the connection ends
+    pub fn reconnect_delay(&self) -> Duration {
+        Duration::from_secs(self.reconnect_delay_secs)
+    }
+
+    pub fn set_reconnect_delay(&mut self, delay: Duration) -> Result<()> {
+        self.reconnect_delay_secs = secs_in_range("reconnect_delay", delay, 1, 3_600)?;
+        Ok(())
+    }
+
+    /// Playback volume from 0.0 (silent) to 1.0 (full)
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    pub fn set_volume(&mut self, volume: f32) -> Result<()> {
+        if !(0.0..=1.0).contains(&volume) {
+            return Err(EmnsError::config(
+                "volume",
+                format!("must be between 0.0 and 1.0, got {}", volume),
+            ));
+        }
+        self.volume = volume;
+        Ok(())
+    }
+
+    /// Volume sounds actually play at: the volume setting, capped by the sound policy
+    pub fn playback_volume(&self) -> f32 {
+        self.sound_policy.limit_volume(self.volume)
+    }
+
+    /// Whether alert sounds are played at all
+    pub fn sounds_enabled(&self) -> bool {
+        self.sounds_enabled
+    }
+
+    pub fn set_sounds_enabled(&mut self, enabled: bool) {
+        self.sounds_enabled = enabled;
+    }
+
+    /// Window during which Info and Warning sounds are muted
+    pub fn quiet_hours(&self) -> Option<QuietHours> {
+        self.quiet_hours
+    }
+
+    pub fn set_quiet_hours(&mut self, quiet_hours: Option<QuietHours>) -> Result<()> {
+        if let Some(window) = quiet_hours {
+            if window.start == window.end {
+                return Err(EmnsError::config(
+                    "quiet_hours",
+                    "start and end must differ",
+                ));
+            }
+        }
+        self.quiet_hours = quiet_hours;
+        Ok(())
+    }
+
+    /// Whether toasts include the alert id
+    pub fn show_alert_id(&self) -> bool {
+        self.show_alert_id
+    }
+
+    pub fn set_show_alert_id(&mut self, show: bool) {
+        self.show_alert_id = show;
+    }
+
+    /// Limits on alert sounds pushed by the server
+    pub fn sound_policy(&self) -> &SoundPolicy {
+        &self.sound_policy
+    }
+
+    pub fn set_sound_policy(&mut self, policy: SoundPolicy) -> Result<()> {
+        if let Some(max) = policy.max_volume {
+            if !(0.0..=1.0).contains(&max) {
+                return Err(EmnsError::config(
+                    "sound_policy.max_volume",
+                    format!("must be between 0.0 and 1.0, got {}", max),
+                ));
+            }
+        }
+        self.sound_policy = policy;
+        Ok(())
+    }
+
+    /// Display name of the server alerts come from
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// Environment the server runs in, such as production or test
+    pub fn server_environment(&self) -> Option<&str> {
+        self.server_environment.as_deref()
+    }
+
+    /// Set the server identity shown on toasts; blank values clear it
+    pub fn set_server_identity(&mut self, name: Option<String>, environment: Option<String>) {
+        let clean = |value: Option<String>| {
+            value
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        self.server_name = clean(name);
+        self.server_environment = clean(environment);
+    }
+
+    /// "EMNS — PRODUCTION": who sent an alert, or `None` when no identity is configured
+    pub fn attribution(&self) -> Option<String> {
+        match (self.server_name(), self.server_environment()) {
+            (Some(name), Some(environment)) => {
+                Some(format!("{} — {}", name, environment.to_uppercase()))
+            }
+            (Some(name), None) => Some(name.to_string()),
+            (None, Some(environment)) => Some(format!("EMNS — {}", environment.to_uppercase())),
+            (None, None) => None,
+        }
+    }
+
+    /// Check every field, e.g. after deserializing settings from a file or the server
+    pub fn validate(&self) -> Result<()> {
+        let mut checked: AgentSettings = AgentSettings::default();
+        checked.set_auto_confirm_timeout(self.auto_confirm_timeout())?;
+        checked.set_heartbeat_interval(self.heartbeat_interval())?;
+        checked.set_status_interval(self.status_interval())?;
+        checked.set_reconnect_delay(self.reconnect_delay())?;
+        checked.set_volume(self.volume)?;
+        checked.set_quiet_hours(self.quiet_hours)?;
+        checked.set_sound_policy(self.sound_policy.clone())?;
+        Ok(())
+    }
+}
+
+/// Whole seconds of `value`, rejected unless within `min..=max`
+fn secs_in_range(key: &str, value: Duration, min: u64, max: u64) -> Result<u64> {
+    let secs: u64 = value.as_secs();
+    if value.subsec_nanos() != 0 || secs < min || secs > max {
+        return Err(EmnsError::config(
+            key,
+            format!(
+                "must be a whole number of seconds between {} and {}, got {:?}",
+                min, max, value
+            ),
+        ));
+    }
+    Ok(secs)
+}
+
+/// Handle to the current [`AgentSettings`], cloned into every component.
+///
+/// Readers take a snapshot at the point of use; writers publish a new
+/// generation on a watch channel so timers can be re-armed.
+#[derive(Clone)]
+pub struct SharedSettings {
+    current: Arc<RwLock<AgentSettings>>,
+    generation: Arc<watch::Sender<u64>>,
+}
+
+impl SharedSettings {
+    pub fn new(settings: AgentSettings) -> Self {
+        let (generation, _) = watch::channel(0);
+        Self {
+            current: Arc::new(RwLock::new(settings)),
+            generation: Arc::new(generation),
+        }
+    }
+
+    /// Copy of the current settings
+    pub fn snapshot(&self) -> AgentSettings {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Apply a change; nothing is stored if `change` or validation fails
+    pub fn update(&self, change: impl FnOnce(&mut AgentSettings) -> Result<()>) -> Result<()> {
+        let mut current = self.current.write().unwrap();
+        let mut next: AgentSettings = current.clone();
+        change(&mut next)?;
+        next.validate()?;
+        *current = next;
+        drop(current);
+
+        self.generation.send_modify(|generation| *generation += 1);
+        Ok(())
+    }
+
+    /// Replace all settings, e.g. from a reloaded file
+    pub fn replace(&self, settings: AgentSettings) -> Result<()> {
+        self.update(|current| {
+            *current = settings;
+            Ok(())
+        })
+    }
+
+    /// Receiver that is notified after every successful change
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.generation.subscribe()
+    }
+}
+
+impl Default for SharedSettings {
+    fn default() -> Self {
+        Self::new(AgentSettings::default())
+    }
+}
+
+impl std::fmt::Debug for SharedSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("SharedSettings")
+            .field(&*self.current.read().unwrap())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setters_reject_out_of_range_values() {
+        let mut settings: AgentSettings = AgentSettings::default();
+        assert!(settings.set_volume(1.5).is_err());
+        assert!(settings
+            .set_auto_confirm_timeout(Some(Duration::from_secs(0)))
+            .is_err());
+        assert!(settings
+            .set_heartbeat_interval(Duration::from_millis(1500))
+            .is_err());
+        let noon: NaiveTime = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        assert!(settings
+            .set_quiet_hours(Some(QuietHours {
+                start: noon,
+                end: noon
+            }))
+            .is_err());
+        assert_eq!(settings, AgentSettings::default());
+    }
+
+    #[test]
+    fn test_zero_auto_confirm_timeout_means_never() {
+        let mut settings: AgentSettings = AgentSettings::default();
+        settings.set_auto_confirm_timeout(None).unwrap();
+        assert_eq!(settings.auto_confirm_timeout(), None);
+
+        let read: AgentSettings =
+            serde_json::from_str(r#"{"auto_confirm_timeout_secs": 0}"#).unwrap();
+        assert!(read.validate().is_ok());
+        assert_eq!(read, settings);
+    }
+
+    #[test]
+    fn test_deserialized_settings_are_validated() {
+        let settings: AgentSettings = serde_json::from_str(r#"{"volume": 3.0}"#).unwrap();
+        match settings.validate().unwrap_err() {
+            EmnsError::Config { key, .. } => assert_eq!(key, "volume"),
+            other => panic!("expected config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_attribution_combines_name_and_environment() {
+        let mut settings: AgentSettings = AgentSettings::default();
+        assert_eq!(settings.attribution(), None);
+
+        settings.set_server_identity(Some("EMNS".to_string()), Some("production".to_string()));
+        assert_eq!(settings.attribution().as_deref(), Some("EMNS — PRODUCTION"));
+        settings.set_server_identity(None, Some("test".to_string()));
+        assert_eq!(settings.attribution().as_deref(), Some("EMNS — TEST"));
+        settings.set_server_identity(Some(" Base EMNS ".to_string()), Some("  ".to_string()));
+        assert_eq!(settings.attribution().as_deref(), Some("Base EMNS"));
+    }
+
+    #[test]
+    fn test_quiet_hours_wrap_midnight() {
+        let window: QuietHours = QuietHours {
+            start: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+        };
+        assert!(window.contains(NaiveTime::from_hms_opt(23, 30, 0).unwrap()));
+        assert!(window.contains(NaiveTime::from_hms_opt(5, 59, 0).unwrap()));
+        assert!(!window.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn test_failed_update_keeps_settings_and_does_not_notify() {
+        let shared: SharedSettings = SharedSettings::default();
+        let mut changes: watch::Receiver<u64> = shared.subscribe();
+
+        assert!(shared.update(|s| s.set_volume(-1.0)).is_err());
+        assert!(!changes.has_changed().unwrap());
+
+        shared.update(|s| s.set_volume(0.5)).unwrap();
+        assert!(changes.has_changed().unwrap());
+        changes.mark_unchanged();
+        assert_eq!(shared.snapshot().volume(), 0.5);
+    }
+
+    #[test]
+    fn test_sound_policy_caps_playback_volume() {
+        let mut settings: AgentSettings = AgentSettings::default();
+        settings.set_volume(0.8).unwrap();
+        assert!(settings
+            .set_sound_policy(SoundPolicy {
+                max_volume: Some(2.0),
+                ..SoundPolicy::default()
+            })
+            .is_err());
+
+        settings
+            .set_sound_policy(SoundPolicy {
+                max_volume: Some(0.25),
+                ..SoundPolicy::default()
+            })
+            .unwrap();
+        assert_eq!(settings.volume(), 0.8);
+        assert_eq!(settings.playback_volume(), 0.25);
+    }
+}
@@ -0,0 +1,177 @@
+//! How the agent's previous run ended, reported to the server when it registers
+
+use crate::messages::{ShutdownReason, ShutdownRecord};
+use crate::storage::write_private_file;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+/// File under the data directory holding the last shutdown record
+pub const SHUTDOWN_FILE: &str = "last_shutdown.json";
+
+/// The shutdown record kept in a file, normally [`SHUTDOWN_FILE`] in the data directory.
+///
+/// The record is removed when it is read at startup, so a run that ends
+/// without writing one, e.g. on a power loss, is reported as unknown.
+#[derive(Debug, Clone)]
+pub struct ShutdownLog {
+    path: Option<PathBuf>,
+}
+
+impl ShutdownLog {
+    /// Keep the record in `file`; without one nothing is recorded, and every
+    /// previous run is reported as unknown
+    pub fn new(file: Option<PathBuf>) -> Self {
+        Self { path: file }
+    }
+
+    /// Read and remove the record of how the previous run ended
+    pub fn take(&self) -> ShutdownRecord {
+        let Some(path) = &self.path else {
+            return ShutdownRecord::default();
+        };
+        let Ok(data) = std::fs::read(path) else {
+            return ShutdownRecord::default();
+        };
+        if let Err(e) = std::fs::remove_file(path) {
+            log::warn!("Failed to remove {}: {}", path.display(), e);
+        }
+        serde_json::from_slice(&data)
+            .map_err(|e| log::warn!("Ignoring unreadable {}: {}", path.display(), e))
+            .unwrap_or_default()
+    }
+
+    /// Record that this run is ending for `reason`.
+    ///
+    /// Failing to write only leaves the next run reporting an unknown reason, so it is logged.
+    pub fn record(&self, reason: ShutdownReason) {
+        self.write(&ShutdownRecord {
+            reason,
+            at: Some(chrono::Utc::now()),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            panic_digest: None,
+        });
+    }
+
+    /// Record a panic with `message`, of which only a digest is kept
+    pub fn record_panic(&self, message: &str) {
+        self.write(&ShutdownRecord {
+            reason: ShutdownReason::Panic,
+            at: Some(chrono::Utc::now()),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            panic_digest: Some(panic_digest(message)),
+        });
+    }
+
+    fn write(&self, record: &ShutdownRecord) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let tmp: PathBuf = path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(record)
+            .map_err(std::io::Error::other)
+            .and_then(|data| write_private_file(&tmp, &data))
+            .and_then(|()| std::fs::rename(&tmp, path));
+        if let Err(e) = result {
+            log::error!(
+                "Failed to save the shutdown record to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// First 8 bytes of the SHA-256 of `message`, in hex
+pub fn panic_digest(message: &str) -> String {
+    Sha256::digest(message.as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Record panics in `log`, then hand them on to the hook already installed
+pub fn install_panic_hook(log: ShutdownLog) {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message: &str = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("");
+        log.record_panic(message);
+        previous(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("emns-shutdown-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_record_is_taken_once() {
+        let dir: PathBuf = temp_dir();
+        let log: ShutdownLog = ShutdownLog::new(Some(dir.join(SHUTDOWN_FILE)));
+        // Nothing recorded yet, as after a power loss
+        assert_eq!(log.take().reason, ShutdownReason::Unknown);
+
+        for reason in [
+            ShutdownReason::Clean,
+            ShutdownReason::Update,
+            ShutdownReason::Crash,
+        ] {
+            log.record(reason);
+            let record: ShutdownRecord = log.take();
+            assert_eq!(record.reason, reason);
+            assert_eq!(record.version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+            assert!(record.at.is_some());
+            assert_eq!(record.panic_digest, None);
+            assert_eq!(log.take(), ShutdownRecord::default());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_panic_keeps_only_a_digest() {
+        let dir: PathBuf = temp_dir();
+        let log: ShutdownLog = ShutdownLog::new(Some(dir.join(SHUTDOWN_FILE)));
+        log.record_panic("index out of bounds: the len is 3 but the index is 7");
+        let saved: String = std::fs::read_to_string(dir.join(SHUTDOWN_FILE)).unwrap();
+        assert!(!saved.contains("index out of bounds"));
+
+        let record: ShutdownRecord = log.take();
+        assert_eq!(record.reason, ShutdownReason::Panic);
+        let digest: String = record.panic_digest.unwrap();
+        assert_eq!(digest.len(), 16);
+        assert_eq!(
+            digest,
+            panic_digest("index out of bounds: the len is 3 but the index is 7")
+        );
+        assert_ne!(digest, panic_digest("another panic"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_unreadable_record_is_unknown() {
+        let dir: PathBuf = temp_dir();
+        std::fs::write(dir.join(SHUTDOWN_FILE), b"{ not json").unwrap();
+        let log: ShutdownLog = ShutdownLog::new(Some(dir.join(SHUTDOWN_FILE)));
+        assert_eq!(log.take().reason, ShutdownReason::Unknown);
+        assert!(!dir.join(SHUTDOWN_FILE).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_log_without_a_file_keeps_nothing() {
+        let log: ShutdownLog = ShutdownLog::new(None);
+        log.record(ShutdownReason::Clean);
+        assert_eq!(log.take(), ShutdownRecord::default());
+    }
+}
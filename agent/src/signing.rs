@@ -0,0 +1,200 @@
+//! Signatures proving an alert, or its cancellation, came from the EMNS
+//! server, even over a WebSocket path someone else has taken over
+
+use crate::error::{EmnsError, Result};
+use crate::messages::{Alert, Message};
+use crate::multicast::{HmacSha256, SigningKey};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::Mac;
+use uuid::Uuid;
+
+/// The signature the server puts in the alert's `signature`
+pub fn sign(alert: &Alert, key: &SigningKey) -> String {
+    mac(&alert.signing_payload(), key)
+}
+
+/// Check that the alert is signed with `key`
+pub fn verify(alert: &Alert, key: &SigningKey) -> Result<()> {
+    check(
+        "alert",
+        &alert.signing_payload(),
+        alert.signature.as_deref(),
+        key,
+    )
+}
+
+/// The signature the server puts in a [`Message::CancelAlert`]'s `signature`
+pub fn sign_cancel(alert_id: Uuid, reason: Option<&str>, key: &SigningKey) -> String {
+    mac(&Message::cancel_signing_payload(alert_id, reason), key)
+}
+
+/// Check that a [`Message::CancelAlert`] is signed with `key`
+pub fn verify_cancel(
+    alert_id: Uuid,
+    reason: Option<&str>,
+    signature: Option<&str>,
+    key: &SigningKey,
+) -> Result<()> {
+    check(
+        "cancellation",
+        &Message::cancel_signing_payload(alert_id, reason),
+        signature,
+        key,
+    )
+}
+
+/// Base64 HMAC-SHA256 of `payload`
+fn mac(payload: &[u8], key: &SigningKey) -> String {
+    let mut mac: HmacSha256 = key.mac();
+    mac.update(payload);
+    BASE64.encode(mac.finalize().into_bytes())
+}
+
+/// Check `signature` over `payload`, naming `what` was signed if it is missing
+fn check(what: &str, payload: &[u8], signature: Option<&str>, key: &SigningKey) -> Result<()> {
+    let Some(signature) = signature else {
+        return Err(EmnsError::protocol(format!("{} is not signed", what)));
+    };
+    let signature: Vec<u8> = BASE64
+        .decode(signature.trim().as_bytes())
+        .map_err(|e| EmnsError::protocol(format!("malformed signature: {}", e)))?;
+    let mut mac: HmacSha256 = key.mac();
+    mac.update(payload);
+    mac.verify_slice(&signature)
+        .map_err(|_| EmnsError::protocol("signature does not match"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    /// The vectors committed for server implementers, alerts under `vectors`
+    /// and cancellations under `cancel_vectors`
+    fn vectors_of(kind: &str) -> Vec<Value> {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../protocol/tests/vectors/alert_signature.json");
+        let vectors: Value = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        vectors[kind].as_array().unwrap().clone()
+    }
+
+    fn vectors() -> Vec<Value> {
+        vectors_of("vectors")
+    }
+
+    #[test]
+    fn test_signatures_match_the_vectors() {
+        for vector in vectors() {
+            let key: SigningKey = SigningKey::new(vector["key"].as_str().unwrap());
+            let alert: Alert = serde_json::from_value(vector["alert"].clone()).unwrap();
+            let name: &str = vector["name"].as_str().unwrap();
+            assert_eq!(
+                Some(sign(&alert, &key).as_str()),
+                alert.signature.as_deref(),
+                "{}",
+                name
+            );
+            assert!(verify(&alert, &key).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_tampered_unsigned_and_foreign_alerts_fail() {
+        let vector: Value = vectors().remove(0);
+        let key: SigningKey = SigningKey::new(vector["key"].as_str().unwrap());
+        let signed: Alert = serde_json::from_value(vector["alert"].clone()).unwrap();
+
+        let mut tampered: Alert = signed.clone();
+        tampered.title.push('!');
+        let unsigned: Alert = Alert {
+            signature: None,
+            url: None,
+            image_url: None,
+            ..signed.clone()
+        };
+        let garbled: Alert = Alert {
+            signature: Some("not base64!".to_string()),
+            ..signed.clone()
+        };
+        let failures: Vec<String> = [
+            verify(&tampered, &key),
+            verify(&unsigned, &key),
+            verify(&garbled, &key),
+            verify(&signed, &SigningKey::new("other-secret")),
+        ]
+        .into_iter()
+        .map(|result| result.unwrap_err().to_string())
+        .collect();
+        assert!(failures[0].contains("signature does not match"));
+        assert!(failures[1].contains("alert is not signed"));
+        assert!(failures[2].contains("malformed signature"));
+        assert!(failures[3].contains("signature does not match"));
+
+        // Translations are signed too
+        let translated: Value = vectors()
+            .into_iter()
+            .find(|vector| !vector["alert"]["translations"].is_null())
+            .unwrap();
+        let mut reworded: Alert = serde_json::from_value(translated["alert"].clone()).unwrap();
+        let key: SigningKey = SigningKey::new(translated["key"].as_str().unwrap());
+        assert!(verify(&reworded, &key).is_ok());
+        reworded.translations.get_mut("es").unwrap().message = "Salga ahora".to_string();
+        assert!(verify(&reworded, &key).is_err());
+    }
+
+    #[test]
+    fn test_every_field_that_changes_behavior_is_signed() {
+        let vector: Value = vectors()
+            .into_iter()
+            .find(|vector| vector["name"] == "every signed field")
+            .unwrap();
+        let key: SigningKey = SigningKey::new(vector["key"].as_str().unwrap());
+        let signed: Alert = serde_json::from_value(vector["alert"].clone()).unwrap();
+        assert!(verify(&signed, &key).is_ok());
+
+        type Rewrite = fn(&mut Alert);
+        let rewrites: Vec<(&str, Rewrite)> = vec![
+            ("sealed", |a| {
+                a.sealed.as_mut().unwrap().ciphertext = "AAAA".into()
+            }),
+            ("sound_file", |a| a.sound_file = Some("quiet.wav".into())),
+            ("requires_confirmation", |a| a.requires_confirmation = false),
+            ("confirm_callback_url", |a| {
+                a.confirm_callback_url = Some("https://attacker.example/".into())
+            }),
+            ("expires_at", |a| a.expires_at = None),
+            ("supersedes", |a| a.supersedes = Some(uuid::Uuid::nil())),
+            ("escalation", |a| a.escalation = None),
+            ("attachment", |a| {
+                a.attachment.as_mut().unwrap().url = "https://attacker.example/".into()
+            }),
+            ("response_options", |a| {
+                a.response_options.as_mut().unwrap().pop();
+            }),
+            ("target_hosts", |a| a.target_hosts.push("*".into())),
+            ("visibility", |a| a.visibility = None),
+        ];
+        for (name, rewrite) in rewrites {
+            let mut tampered: Alert = signed.clone();
+            rewrite(&mut tampered);
+            assert!(verify(&tampered, &key).is_err(), "{} is not signed", name);
+        }
+    }
+
+    #[test]
+    fn test_cancellations_match_the_vectors_and_cover_the_reason() {
+        for vector in vectors_of("cancel_vectors") {
+            let key: SigningKey = SigningKey::new(vector["key"].as_str().unwrap());
+            let alert_id: Uuid = vector["alert_id"].as_str().unwrap().parse().unwrap();
+            let reason: Option<&str> = vector["reason"].as_str();
+            let signature: &str = vector["signature"].as_str().unwrap();
+            assert_eq!(sign_cancel(alert_id, reason, &key), signature);
+            assert!(verify_cancel(alert_id, reason, Some(signature), &key).is_ok());
+            assert!(verify_cancel(alert_id, Some("drill over"), Some(signature), &key).is_err());
+            assert!(verify_cancel(Uuid::nil(), reason, Some(signature), &key).is_err());
+            let unsigned = verify_cancel(alert_id, reason, None, &key).unwrap_err();
+            assert!(unsigned.to_string().contains("cancellation is not signed"));
+        }
+    }
+}
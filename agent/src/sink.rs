@@ -0,0 +1,34 @@
+//! Outputs that follow alerts alongside the toast, such as a local alarm panel
+
+use crate::messages::{Alert, ConfirmationReason};
+
+/// Why the server stopped wanting an alert confirmed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Withdrawal {
+    Cancelled,
+    Expired,
+    /// Replaced by a later alert revising it
+    Superseded,
+}
+
+/// How an alert stopped awaiting confirmation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Confirmed by the user or automatically; a confirmation was sent
+    Confirmed(ConfirmationReason),
+    /// Withdrawn by the server; nothing was sent
+    Withdrawn(Withdrawal),
+}
+
+/// Something told about each alert the handler delivers and each one that is resolved.
+///
+/// Calls are made on the handler's path, so implementations must return
+/// quickly and handle their own failures; one sink failing never affects
+/// the toast, the sound, or the other sinks.
+pub trait AlertSink: Send + Sync {
+    /// An alert passed suppression and is being delivered on this machine
+    fn delivered(&self, alert: &Alert);
+
+    /// An alert awaiting confirmation was confirmed, timed out, or withdrawn
+    fn resolved(&self, alert_id: uuid::Uuid, resolution: Resolution);
+}
@@ -0,0 +1,455 @@
+//! Sound packs: a versioned zip of alert sounds with a `manifest.json` inside,
+//! installed into the [`SoundLibrary`] as one set
+
+use crate::error::{EmnsError, Result};
+use crate::messages::{AlertLevel, SoundPackOffer};
+use crate::sounds::{is_plain_name, remove_set, SoundFile, SoundLibrary};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+/// Name of the manifest inside a pack, kept beside the sounds once installed
+pub const PACK_MANIFEST: &str = "manifest.json";
+
+/// Largest pack downloaded, and the most its files may unpack to
+pub const MAX_PACK_BYTES: u64 = 50 * 1024 * 1024;
+
+/// Sound each alert level plays when the alert names none
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LevelSounds {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub critical: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emergency: Option<String>,
+}
+
+impl LevelSounds {
+    pub fn get(&self, level: &AlertLevel) -> Option<&str> {
+        match level {
+            AlertLevel::Info => self.info.as_deref(),
+            AlertLevel::Warning => self.warning.as_deref(),
+            AlertLevel::Critical => self.critical.as_deref(),
+            AlertLevel::Emergency => self.emergency.as_deref(),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &str> {
+        [&self.info, &self.warning, &self.critical, &self.emergency]
+            .into_iter()
+            .filter_map(|sound| sound.as_deref())
+    }
+}
+
+/// The `manifest.json` of a sound pack
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackManifest {
+    /// Pack version; only newer versions replace an installed pack
+    pub version: u32,
+    #[serde(default)]
+    pub levels: LevelSounds,
+    /// Every sound in the pack, with its checksum
+    pub files: Vec<SoundFile>,
+}
+
+impl PackManifest {
+    /// The manifest of the pack installed in `dir`, if a pack is
+    pub fn read(dir: &Path) -> Option<Self> {
+        let path: PathBuf = dir.join(PACK_MANIFEST);
+        let data: Vec<u8> = std::fs::read(&path).ok()?;
+        serde_json::from_slice(&data)
+            .map_err(|e| log::warn!("Ignoring unreadable {}: {}", path.display(), e))
+            .ok()
+    }
+
+    /// Check the level sounds are all files of the pack
+    fn check(&self) -> Result<()> {
+        if self.files.is_empty() {
+            return Err(EmnsError::audio(None, "Sound pack has no files"));
+        }
+        match self
+            .levels
+            .iter()
+            .find(|sound| !self.files.iter().any(|file| file.name == *sound))
+        {
+            Some(missing) => Err(EmnsError::audio(
+                None,
+                format!(
+                    "Sound pack maps a level to {:?}, which it does not contain",
+                    missing
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Install the sound pack in `archive` into `library` if it is newer than the
+/// pack in use; returns whether it was installed.
+///
+/// Every entry must be a plain file name listed in the manifest, so a pack
+/// cannot write outside its directory, and every sound must match its
+/// checksum and decode. A pack that fails is deleted and the sounds in use are
+/// left as they were.
+pub fn install_pack(library: &SoundLibrary, archive: &[u8]) -> Result<bool> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(archive))
+        .map_err(|e| EmnsError::audio(None, format!("Not a sound pack: {}", e)))?;
+    let manifest: PackManifest = {
+        let entry = zip.by_name(PACK_MANIFEST).map_err(|e| {
+            EmnsError::audio(None, format!("Sound pack has no {}: {}", PACK_MANIFEST, e))
+        })?;
+        let mut data: Vec<u8> = Vec::new();
+        entry
+            .take(MAX_PACK_BYTES)
+            .read_to_end(&mut data)
+            .map_err(|e| EmnsError::audio(None, e))?;
+        serde_json::from_slice(&data)
+            .map_err(|e| EmnsError::audio(None, format!("Bad {}: {}", PACK_MANIFEST, e)))?
+    };
+    if let Some(installed) = library.pack_version().filter(|v| manifest.version <= *v) {
+        log::info!(
+            "Sound pack {} is not newer than {}; not installing",
+            manifest.version,
+            installed
+        );
+        return Ok(false);
+    }
+    manifest.check()?;
+
+    let staging: PathBuf = library.stage()?;
+    if let Err(e) = extract(&mut zip, &manifest, &staging) {
+        log::error!("Keeping the current sounds; sound pack rejected: {}", e);
+        remove_set(&staging);
+        return Err(e);
+    }
+    library.install(&staging, &manifest.files)?;
+    log::info!("Installed sound pack {}", manifest.version);
+    Ok(true)
+}
+
+/// Unpack every entry of `zip` into `dir`, refusing any the manifest does not list
+fn extract(
+    zip: &mut zip::ZipArchive<Cursor<&[u8]>>,
+    manifest: &PackManifest,
+    dir: &Path,
+) -> Result<()> {
+    let mut remaining: u64 = MAX_PACK_BYTES;
+    for index in 0..zip.len() {
+        let mut entry = zip
+            .by_index(index)
+            .map_err(|e| EmnsError::audio(None, format!("Bad sound pack entry: {}", e)))?;
+        let name: String = entry.name().to_string();
+        // Checked on the raw name, before it is joined to anything
+        if entry.is_dir() || !is_plain_name(&name) {
+            return Err(EmnsError::audio(
+                None,
+                format!("Sound pack entry {:?} is not a plain file name", name),
+            ));
+        }
+        if name != PACK_MANIFEST && !manifest.files.iter().any(|file| file.name == name) {
+            return Err(EmnsError::audio(
+                None,
+                format!("Sound pack entry {:?} is not in its manifest", name),
+            ));
+        }
+
+        let path: PathBuf = dir.join(&name);
+        let mut file: std::fs::File = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| EmnsError::storage(Some(&path), e))?;
+        let written: u64 = std::io::copy(&mut (&mut entry).take(remaining + 1), &mut file)
+            .map_err(|e| EmnsError::audio(Some(&path), e))?;
+        remaining = remaining.checked_sub(written).ok_or_else(|| {
+            EmnsError::audio(
+                None,
+                format!("Sound pack unpacks to more than {} bytes", MAX_PACK_BYTES),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Hex SHA-256 of `data`
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Downloads and installs the sound packs the server offers
+pub struct SoundPacks {
+    library: SoundLibrary,
+    /// The latest pack the server offered, not yet looked at
+    offered: Mutex<Option<SoundPackOffer>>,
+    offer_arrived: Notify,
+    max_bytes: u64,
+    client: reqwest::Client,
+}
+
+impl SoundPacks {
+    pub fn new(library: SoundLibrary) -> Self {
+        Self {
+            library,
+            offered: Mutex::new(None),
+            offer_arrived: Notify::new(),
+            max_bytes: MAX_PACK_BYTES,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// The library packs are installed into
+    pub fn library(&self) -> &SoundLibrary {
+        &self.library
+    }
+
+    /// Look at a pack the server offered; the newest offer wins if several arrive at once
+    pub fn offer(&self, offer: SoundPackOffer) {
+        *self.offered.lock().unwrap() = Some(offer);
+        self.offer_arrived.notify_one();
+    }
+
+    /// Download `offer`, check it against its checksum, and install it if it
+    /// is newer than the pack in use; returns whether it was installed
+    pub async fn apply(&self, offer: &SoundPackOffer) -> Result<bool> {
+        if self
+            .library
+            .pack_version()
+            .is_some_and(|installed| offer.version <= installed)
+        {
+            log::debug!("Sound pack {} is not newer; not installing", offer.version);
+            return Ok(false);
+        }
+        let archive: Vec<u8> = self.download(&offer.url).await?;
+        let actual: String = sha256_hex(&archive);
+        if !actual.eq_ignore_ascii_case(offer.sha256.trim()) {
+            return Err(EmnsError::protocol(format!(
+                "sound pack checksum mismatch: expected {}, got {}",
+                offer.sha256, actual
+            )));
+        }
+
+        // Decoding every sound is slow enough to keep off the runtime
+        let library: SoundLibrary = self.library.clone();
+        tokio::task::spawn_blocking(move || install_pack(&library, &archive))
+            .await
+            .map_err(|e| EmnsError::audio(None, e))?
+    }
+
+    /// Read the response body, stopping as soon as it passes the size limit
+    async fn download(&self, url: &str) -> Result<Vec<u8>> {
+        if !url.starts_with("https://") {
+            return Err(EmnsError::protocol(format!(
+                "sound pack URL {} is not HTTPS",
+                url
+            )));
+        }
+        let mut response: reqwest::Response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| EmnsError::connection(url, e))?;
+        let mut body: Vec<u8> = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| EmnsError::connection(url, e))?
+        {
+            if (body.len() + chunk.len()) as u64 > self.max_bytes {
+                return Err(EmnsError::protocol(format!(
+                    "sound pack is larger than {} bytes",
+                    self.max_bytes
+                )));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+}
+
+/// Install sound packs as the server offers them, until `cancel` fires
+pub async fn run_sound_packs(packs: Arc<SoundPacks>, cancel: CancellationToken) {
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = packs.offer_arrived.notified() => {}
+        }
+        let Some(offer) = packs.offered.lock().unwrap().take() else {
+            continue;
+        };
+        if let Err(e) = packs.apply(&offer).await {
+            log::warn!("Sound pack {} not installed: {}", offer.version, e);
+        }
+    }
+    log::debug!("Sound pack installs stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    /// A tenth of a second of silence as 8 kHz mono 16-bit PCM
+    fn wav() -> Vec<u8> {
+        let samples: u32 = 800;
+        let data_len: u32 = samples * 2;
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&8000u32.to_le_bytes());
+        bytes.extend_from_slice(&16000u32.to_le_bytes());
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&16u16.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_len.to_le_bytes());
+        bytes.resize(bytes.len() + data_len as usize, 0);
+        bytes
+    }
+
+    /// A manifest for `version` listing `files` with their checksums
+    fn manifest(version: u32, files: &[(&str, &[u8])]) -> PackManifest {
+        PackManifest {
+            version,
+            levels: LevelSounds {
+                critical: Some("siren.wav".to_string()),
+                ..LevelSounds::default()
+            },
+            files: files
+                .iter()
+                .map(|(name, contents)| SoundFile {
+                    name: name.to_string(),
+                    sha256: sha256_hex(contents),
+                })
+                .collect(),
+        }
+    }
+
+    /// Zip `manifest` with `entries`
+    fn pack(manifest: &PackManifest, entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file(PACK_MANIFEST, SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(&serde_json::to_vec(manifest).unwrap())
+            .unwrap();
+        for (name, contents) in entries {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn temp_root() -> PathBuf {
+        let root: PathBuf =
+            std::env::temp_dir().join(format!("emns-sound-pack-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_good_pack_is_installed_and_maps_levels() {
+        let root: PathBuf = temp_root();
+        let library: SoundLibrary = SoundLibrary::open(&root);
+        let files: [(&str, &[u8]); 2] = [("siren.wav", &wav()), ("chime.wav", &wav())];
+        assert!(install_pack(&library, &pack(&manifest(3, &files), &files)).unwrap());
+
+        assert_eq!(library.pack_version(), Some(3));
+        assert_eq!(std::fs::read(library.path("siren.wav")).unwrap(), wav());
+        let mut alert = crate::test_support::alert(AlertLevel::Critical, false);
+        assert_eq!(library.sound_for(&alert), "siren.wav");
+        alert.level = AlertLevel::Warning;
+        assert_eq!(library.sound_for(&alert), alert.get_sound_file());
+        alert.sound_file = Some("chime.wav".to_string());
+        assert_eq!(library.sound_for(&alert), "chime.wav");
+
+        // Kept across restarts
+        assert_eq!(SoundLibrary::open(&root).pack_version(), Some(3));
+
+        // Neither the same version nor an older one replaces it
+        let installed: PathBuf = library.active_dir();
+        assert!(!install_pack(&library, &pack(&manifest(3, &files), &files)).unwrap());
+        assert!(!install_pack(&library, &pack(&manifest(2, &files), &files)).unwrap());
+        assert_eq!(library.active_dir(), installed);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_tampered_pack_leaves_sounds_in_place() {
+        let root: PathBuf = temp_root();
+        let library: SoundLibrary = SoundLibrary::open(&root);
+        let files: [(&str, &[u8]); 1] = [("siren.wav", &wav())];
+        assert!(install_pack(&library, &pack(&manifest(1, &files), &files)).unwrap());
+        let good: PathBuf = library.active_dir();
+
+        // The sound was changed after the manifest was written
+        let mut tampered: Vec<u8> = wav();
+        tampered[100] = 1;
+        let archive: Vec<u8> = pack(&manifest(2, &files), &[("siren.wav", &tampered)]);
+        assert!(matches!(
+            install_pack(&library, &archive),
+            Err(EmnsError::Audio { .. })
+        ));
+        assert_eq!(library.active_dir(), good);
+        assert_eq!(library.pack_version(), Some(1));
+
+        // A level mapped to a sound the pack lacks
+        let mut unmapped: PackManifest = manifest(2, &files);
+        unmapped.levels.emergency = Some("missing.wav".to_string());
+        assert!(install_pack(&library, &pack(&unmapped, &files)).is_err());
+        assert_eq!(library.active_dir(), good);
+
+        let leftovers: usize = std::fs::read_dir(&root).unwrap().count();
+        // The active generation and the `.active` file
+        assert_eq!(leftovers, 2);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_zip_slip_entry_is_refused() {
+        let root: PathBuf = temp_root();
+        let sounds: PathBuf = root.join("sounds");
+        std::fs::create_dir_all(&sounds).unwrap();
+        let library: SoundLibrary = SoundLibrary::open(&sounds);
+
+        for name in [
+            "../escaped.wav",
+            "sub/../../escaped.wav",
+            "/tmp/escaped.wav",
+        ] {
+            let files: [(&str, &[u8]); 1] = [(name, &wav())];
+            let archive: Vec<u8> = pack(&manifest(1, &[("siren.wav", &wav())]), &files);
+            let error: EmnsError = install_pack(&library, &archive).unwrap_err();
+            assert!(
+                error.to_string().contains("not a plain file name"),
+                "{}",
+                error
+            );
+        }
+        assert!(!root.join("escaped.wav").exists());
+        assert_eq!(library.pack_version(), None);
+        assert_eq!(std::fs::read_dir(&sounds).unwrap().count(), 0);
+
+        // Nor may a pack carry files its manifest does not list
+        let files: [(&str, &[u8]); 2] = [("siren.wav", &wav()), ("extra.wav", &wav())];
+        let archive: Vec<u8> = pack(&manifest(1, &files[..1]), &files);
+        assert!(install_pack(&library, &archive).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,392 @@
+//! The set of sound files alerts play from, replaced as a whole so playback
+//! never opens a half-written file
+
+use crate::error::{EmnsError, Result};
+use crate::messages::Alert;
+use crate::sound_pack::PackManifest;
+use crate::storage::write_private_file;
+use rodio::Decoder;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// File in the sounds directory naming the generation in use
+const ACTIVE_GENERATION_FILE: &str = ".active";
+
+/// Directory name prefix for sound sets being downloaded
+const STAGING_PREFIX: &str = ".staging-";
+
+/// Directory name prefix for validated sound sets
+const GENERATION_PREFIX: &str = ".generation-";
+
+/// A file a new sound set must contain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SoundFile {
+    pub name: String,
+    /// Hex SHA-256 of the file's contents
+    pub sha256: String,
+}
+
+#[derive(Debug)]
+struct Generations {
+    active: PathBuf,
+    /// Manifest of the active set when it came from a sound pack
+    pack: Option<Arc<PackManifest>>,
+    /// Set the active one replaced, kept to roll back to
+    previous: Option<PathBuf>,
+}
+
+/// Sounds directory whose contents can be swapped while alerts are playing.
+///
+/// New sets are downloaded into a staging directory from [`stage`](Self::stage)
+/// and only become active once [`install`](Self::install) has checked every
+/// file. Playback resolves a path once, when it opens the file, so sounds
+/// already playing finish from the set they started in.
+#[derive(Debug, Clone)]
+pub struct SoundLibrary {
+    root: PathBuf,
+    generations: Arc<RwLock<Generations>>,
+}
+
+impl SoundLibrary {
+    /// Play from `dir` as it is, ignoring any installed generation
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        let root: PathBuf = dir.into();
+        Self {
+            generations: Arc::new(RwLock::new(Generations {
+                pack: PackManifest::read(&root).map(Arc::new),
+                active: root.clone(),
+                previous: None,
+            })),
+            root,
+        }
+    }
+
+    /// Play from the generation last installed in `dir`, or from `dir` itself.
+    ///
+    /// Leftover staging directories and generations no longer in use are removed.
+    pub fn open(dir: impl Into<PathBuf>) -> Self {
+        let root: PathBuf = dir.into();
+        let active: PathBuf = std::fs::read_to_string(root.join(ACTIVE_GENERATION_FILE))
+            .ok()
+            .map(|name| root.join(name.trim()))
+            .filter(|path| is_generation(path) && path.is_dir())
+            .unwrap_or_else(|| root.clone());
+
+        if let Ok(entries) = std::fs::read_dir(&root) {
+            for path in entries.flatten().map(|entry| entry.path()) {
+                let stale: bool = (is_staging(&path) || is_generation(&path)) && path != active;
+                if stale && path.is_dir() {
+                    remove_set(&path);
+                }
+            }
+        }
+        if active != root {
+            log::info!("Playing sounds from {}", active.display());
+        }
+
+        Self {
+            root,
+            generations: Arc::new(RwLock::new(Generations {
+                pack: PackManifest::read(&active).map(Arc::new),
+                active,
+                previous: None,
+            })),
+        }
+    }
+
+    /// Where `filename` is in the active set
+    pub fn path(&self, filename: &str) -> PathBuf {
+        self.active_dir().join(filename)
+    }
+
+    /// Directory of the active set
+    pub fn active_dir(&self) -> PathBuf {
+        self.generations.read().unwrap().active.clone()
+    }
+
+    /// Version of the sound pack the active set came from, if it came from one
+    pub fn pack_version(&self) -> Option<u32> {
+        self.pack().map(|pack| pack.version)
+    }
+
+    /// Manifest of the sound pack the active set came from
+    pub fn pack(&self) -> Option<Arc<PackManifest>> {
+        self.generations.read().unwrap().pack.clone()
+    }
+
+    /// Sound `alert` plays: its own, else the active pack's sound for its level,
+    /// else the built-in default for the level
+    pub fn sound_for(&self, alert: &Alert) -> String {
+        match (&alert.sound_file, self.pack()) {
+            (Some(sound_file), _) => sound_file.clone(),
+            (None, Some(pack)) => pack
+                .levels
+                .get(&alert.level)
+                .map_or_else(|| alert.get_sound_file(), str::to_string),
+            (None, None) => alert.get_sound_file(),
+        }
+    }
+
+    /// Create an empty directory to download a new set into
+    pub fn stage(&self) -> Result<PathBuf> {
+        let staging: PathBuf =
+            self.root
+                .join(format!("{}{}", STAGING_PREFIX, uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&staging).map_err(|e| EmnsError::storage(Some(&staging), e))?;
+        Ok(staging)
+    }
+
+    /// Check every file in `manifest` is in `staging` and plays, then make it the active set.
+    ///
+    /// A set that fails a check is deleted and the active set is left as it was.
+    pub fn install(&self, staging: &Path, manifest: &[SoundFile]) -> Result<()> {
+        if let Err(e) = manifest.iter().try_for_each(|file| validate(staging, file)) {
+            log::error!("Keeping the current sounds; new set rejected: {}", e);
+            remove_set(staging);
+            return Err(e);
+        }
+
+        let generation: PathBuf =
+            self.root
+                .join(format!("{}{}", GENERATION_PREFIX, uuid::Uuid::new_v4()));
+        std::fs::rename(staging, &generation)
+            .map_err(|e| EmnsError::storage(Some(&generation), e))?;
+        self.activate(generation)?;
+        log::info!("Installed {} sound files", manifest.len());
+        Ok(())
+    }
+
+    /// Go back to the set the last install replaced; `false` if there is none
+    pub fn rollback(&self) -> Result<bool> {
+        let previous: Option<PathBuf> = self.generations.read().unwrap().previous.clone();
+        match previous {
+            Some(previous) => {
+                self.activate(previous)?;
+                log::warn!("Rolled sounds back to {}", self.active_dir().display());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Point playback at `dir`, keep the set it replaces, and delete the one before that
+    fn activate(&self, dir: PathBuf) -> Result<()> {
+        self.save_active(&dir)?;
+        let pack: Option<Arc<PackManifest>> = PackManifest::read(&dir).map(Arc::new);
+        let mut generations = self.generations.write().unwrap();
+        generations.pack = pack;
+        let replaced: PathBuf = std::mem::replace(&mut generations.active, dir);
+        let dropped: Option<PathBuf> = generations.previous.replace(replaced);
+        drop(generations);
+
+        // The root holds every generation, so it is never deleted
+        if let Some(dropped) = dropped.filter(|d| *d != self.root && *d != self.active_dir()) {
+            remove_set(&dropped);
+        }
+        Ok(())
+    }
+
+    fn save_active(&self, dir: &Path) -> Result<()> {
+        let path: PathBuf = self.root.join(ACTIVE_GENERATION_FILE);
+        let name: &str = dir
+            .strip_prefix(&self.root)
+            .ok()
+            .and_then(Path::to_str)
+            .unwrap_or_default();
+        let tmp: PathBuf = path.with_extension("tmp");
+        write_private_file(&tmp, name.as_bytes())
+            .and_then(|()| std::fs::rename(&tmp, &path))
+            .map_err(|e| EmnsError::storage(Some(&path), e))
+    }
+}
+
+/// Check `file` is a plain file name in `dir` with the expected contents and decodes as audio
+fn validate(dir: &Path, file: &SoundFile) -> Result<()> {
+    if !is_plain_name(&file.name) {
+        return Err(EmnsError::audio(
+            None,
+            format!("Sound file name {:?} is not a plain file name", file.name),
+        ));
+    }
+
+    let path: PathBuf = dir.join(&file.name);
+    let contents: Vec<u8> = std::fs::read(&path)
+        .map_err(|e| EmnsError::audio(Some(&path), format!("Missing sound file: {}", e)))?;
+    let actual: String = Sha256::digest(&contents)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if !actual.eq_ignore_ascii_case(file.sha256.trim()) {
+        return Err(EmnsError::audio(
+            Some(&path),
+            format!(
+                "Checksum mismatch: expected {}, got {}",
+                file.sha256, actual
+            ),
+        ));
+    }
+
+    let reader: BufReader<File> = File::open(&path)
+        .map(BufReader::new)
+        .map_err(|e| EmnsError::audio(Some(&path), e))?;
+    Decoder::new(reader)
+        .map_err(|e| EmnsError::audio(Some(&path), format!("Failed to decode: {}", e)))?;
+    Ok(())
+}
+
+/// A name that can only refer to a file directly inside the set's directory
+pub(crate) fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\', ':'])
+}
+
+fn is_staging(path: &Path) -> bool {
+    has_prefix(path, STAGING_PREFIX)
+}
+
+fn is_generation(path: &Path) -> bool {
+    has_prefix(path, GENERATION_PREFIX)
+}
+
+fn has_prefix(path: &Path, prefix: &str) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(prefix))
+}
+
+/// Deleting can fail while a sound from the set is still playing; the next start retries
+pub(crate) fn remove_set(dir: &Path) {
+    if let Err(e) = std::fs::remove_dir_all(dir) {
+        log::warn!("Could not remove sound set {}: {}", dir.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tenth of a second of silence as 8 kHz mono 16-bit PCM
+    fn wav() -> Vec<u8> {
+        let samples: u32 = 800;
+        let data_len: u32 = samples * 2;
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&8000u32.to_le_bytes());
+        bytes.extend_from_slice(&16000u32.to_le_bytes());
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&16u16.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_len.to_le_bytes());
+        bytes.resize(bytes.len() + data_len as usize, 0);
+        bytes
+    }
+
+    /// Stage `files` and return the manifest describing them
+    fn stage(library: &SoundLibrary, files: &[(&str, &[u8])]) -> (PathBuf, Vec<SoundFile>) {
+        let staging: PathBuf = library.stage().unwrap();
+        let manifest: Vec<SoundFile> = files
+            .iter()
+            .map(|(name, contents)| {
+                std::fs::write(staging.join(name), contents).unwrap();
+                SoundFile {
+                    name: name.to_string(),
+                    sha256: Sha256::digest(contents)
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect(),
+                }
+            })
+            .collect();
+        (staging, manifest)
+    }
+
+    fn temp_root() -> PathBuf {
+        let root: PathBuf =
+            std::env::temp_dir().join(format!("emns-sounds-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_installed_set_is_used_and_survives_restart() {
+        let root: PathBuf = temp_root();
+        let library: SoundLibrary = SoundLibrary::open(&root);
+        assert_eq!(library.path("alarm.wav"), root.join("alarm.wav"));
+
+        let (staging, manifest) = stage(&library, &[("alarm.wav", &wav())]);
+        library.install(&staging, &manifest).unwrap();
+        let installed: PathBuf = library.path("alarm.wav");
+        assert_ne!(installed, root.join("alarm.wav"));
+        assert_eq!(std::fs::read(&installed).unwrap(), wav());
+        assert!(!staging.exists());
+
+        // A leftover download is cleaned up on the next start
+        let abandoned: PathBuf = library.stage().unwrap();
+        let reopened: SoundLibrary = SoundLibrary::open(&root);
+        assert_eq!(reopened.path("alarm.wav"), installed);
+        assert!(!abandoned.exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_set_leaves_the_active_set_in_place() {
+        let root: PathBuf = temp_root();
+        let library: SoundLibrary = SoundLibrary::open(&root);
+        let (staging, manifest) = stage(&library, &[("alarm.wav", &wav())]);
+        library.install(&staging, &manifest).unwrap();
+        let good: PathBuf = library.active_dir();
+
+        // Right checksum, but not audio
+        let (staging, manifest) = stage(
+            &library,
+            &[("alarm.wav", &wav()), ("siren.wav", b"not a wav file")],
+        );
+        assert!(matches!(
+            library.install(&staging, &manifest),
+            Err(EmnsError::Audio { .. })
+        ));
+        assert!(!staging.exists());
+        assert_eq!(library.active_dir(), good);
+
+        // Audio, but not what the manifest promised
+        let (staging, mut manifest) = stage(&library, &[("alarm.wav", &wav())]);
+        manifest[0].sha256 = "00".repeat(32);
+        assert!(library.install(&staging, &manifest).is_err());
+        assert_eq!(library.active_dir(), good);
+        assert_eq!(SoundLibrary::open(&root).active_dir(), good);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_rollback_restores_the_replaced_set() {
+        let root: PathBuf = temp_root();
+        let library: SoundLibrary = SoundLibrary::open(&root);
+        assert!(!library.rollback().unwrap());
+
+        let (staging, manifest) = stage(&library, &[("alarm.wav", &wav())]);
+        library.install(&staging, &manifest).unwrap();
+        let first: PathBuf = library.active_dir();
+        // A sound still playing from the first set keeps its open file
+        let playing: File = File::open(library.path("alarm.wav")).unwrap();
+
+        let (staging, manifest) = stage(&library, &[("alarm.wav", &wav())]);
+        library.install(&staging, &manifest).unwrap();
+        assert_ne!(library.active_dir(), first);
+        assert!(first.exists());
+        assert!(library.rollback().unwrap());
+        assert_eq!(library.active_dir(), first);
+        assert!(Decoder::new(BufReader::new(playing)).is_ok());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,313 @@
+//! Waiting at startup for the network and audio to come up, so a slow boot
+//! is not taken for a missing server or sound device
+
+use crate::transport::ServerHost;
+use rodio::cpal::traits::HostTrait;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Default limit on the wait before starting without what is still missing
+pub const DEFAULT_STARTUP_WAIT: Duration = Duration::from_secs(90);
+
+/// Wait before the first re-probe; doubled after each one
+pub const FIRST_REPROBE: Duration = Duration::from_millis(500);
+
+/// Longest wait between re-probes
+pub const MAX_REPROBE: Duration = Duration::from_secs(15);
+
+/// Limit on one attempt to reach the server
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What one probe found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Readiness {
+    Ready,
+    /// Not up yet; probed again later
+    NotYet,
+    /// Known not to exist on this machine, e.g. no audio devices at all
+    Absent,
+}
+
+/// Something startup waits for
+pub trait ReadinessProbe: Send + Sync {
+    /// Shown in the status line, e.g. `network`
+    fn name(&self) -> String;
+
+    /// Check once. May block
+    fn probe(&self) -> Readiness;
+}
+
+/// Ready once a TCP connection to the server's host opens
+pub struct NetworkProbe {
+    target: ServerHost,
+}
+
+impl NetworkProbe {
+    /// Probe for the server at `url`; `None` when the URL names no host
+    pub fn for_url(url: &str) -> Option<Self> {
+        Some(Self {
+            target: ServerHost::from_url(url).ok()?,
+        })
+    }
+}
+
+impl ReadinessProbe for NetworkProbe {
+    fn name(&self) -> String {
+        format!("network ({})", self.target)
+    }
+
+    fn probe(&self) -> Readiness {
+        let addrs: Vec<SocketAddr> = match &self.target {
+            ServerHost::Address(addr) => vec![*addr],
+            ServerHost::Name { host, port } => match (host.as_str(), *port).to_socket_addrs() {
+                Ok(addrs) => addrs.collect(),
+                Err(e) => {
+                    log::debug!("Cannot resolve {}: {}", host, e);
+                    return Readiness::NotYet;
+                }
+            },
+        };
+        let reachable: bool = addrs
+            .iter()
+            .any(|addr| TcpStream::connect_timeout(addr, CONNECT_TIMEOUT).is_ok());
+        if reachable {
+            Readiness::Ready
+        } else {
+            Readiness::NotYet
+        }
+    }
+}
+
+/// Ready once the audio service lists an output device; absent when it
+/// answers with none
+pub struct AudioProbe;
+
+impl ReadinessProbe for AudioProbe {
+    fn name(&self) -> String {
+        "audio".to_string()
+    }
+
+    fn probe(&self) -> Readiness {
+        match rodio::cpal::default_host().output_devices() {
+            Ok(mut devices) => {
+                if devices.next().is_some() {
+                    Readiness::Ready
+                } else {
+                    Readiness::Absent
+                }
+            }
+            // The audio service is not answering yet
+            Err(e) => {
+                log::debug!("Cannot list audio devices: {}", e);
+                Readiness::NotYet
+            }
+        }
+    }
+}
+
+/// How the wait ended
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StartupReport {
+    pub ready: Vec<String>,
+    pub absent: Vec<String>,
+    /// Still not up when the wait ran out
+    pub missing: Vec<String>,
+    pub waited: Duration,
+}
+
+impl StartupReport {
+    /// Whether the agent starts without something it waited for
+    pub fn degraded(&self) -> bool {
+        !self.missing.is_empty()
+    }
+}
+
+/// Probe each of `probes` until it is ready or known absent, re-probing the
+/// rest with exponential backoff for up to `max_wait`.
+///
+/// Logs one line per round saying what is still awaited. Cancelling
+/// `cancel` ends the wait early, with the rest reported missing.
+pub async fn wait_for_dependencies(
+    probes: Vec<Arc<dyn ReadinessProbe>>,
+    max_wait: Duration,
+    cancel: &CancellationToken,
+) -> StartupReport {
+    let started: Instant = Instant::now();
+    let mut report: StartupReport = StartupReport::default();
+    let mut pending: Vec<Arc<dyn ReadinessProbe>> = probes;
+    let mut delay: Duration = FIRST_REPROBE;
+    loop {
+        let mut still: Vec<Arc<dyn ReadinessProbe>> = Vec::new();
+        for probe in pending {
+            let checked = tokio::task::spawn_blocking({
+                let probe: Arc<dyn ReadinessProbe> = probe.clone();
+                move || probe.probe()
+            });
+            // A probe that panics is tried again next round
+            match checked.await.unwrap_or(Readiness::NotYet) {
+                Readiness::Ready => report.ready.push(probe.name()),
+                Readiness::Absent => report.absent.push(probe.name()),
+                Readiness::NotYet => still.push(probe),
+            }
+        }
+        pending = still;
+        report.waited = started.elapsed();
+        if pending.is_empty() {
+            return report;
+        }
+
+        let names: Vec<String> = pending.iter().map(|probe| probe.name()).collect();
+        let left: Duration = max_wait.saturating_sub(report.waited);
+        if left.is_zero() {
+            log::warn!(
+                "Startup: still waiting for {} after {}s; starting without",
+                names.join(", "),
+                report.waited.as_secs()
+            );
+            report.missing = names;
+            return report;
+        }
+        let next: Duration = delay.min(left);
+        log::info!(
+            "Startup: waiting for {}; retrying in {:.1}s ({}s of {}s)",
+            names.join(", "),
+            next.as_secs_f32(),
+            report.waited.as_secs(),
+            max_wait.as_secs()
+        );
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                report.missing = names;
+                return report;
+            }
+            _ = tokio::time::sleep(next) => {}
+        }
+        delay = (delay * 2).min(MAX_REPROBE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Answers `NotYet` for its first `slow_for` probes, then `then`
+    struct MockProbe {
+        name: &'static str,
+        slow_for: usize,
+        then: Readiness,
+        probed_at: Mutex<Vec<Instant>>,
+    }
+
+    impl MockProbe {
+        fn new(name: &'static str, slow_for: usize, then: Readiness) -> Arc<Self> {
+            Arc::new(Self {
+                name,
+                slow_for,
+                then,
+                probed_at: Mutex::default(),
+            })
+        }
+
+        fn probed_at(&self) -> Vec<Instant> {
+            self.probed_at.lock().unwrap().clone()
+        }
+    }
+
+    impl ReadinessProbe for MockProbe {
+        fn name(&self) -> String {
+            self.name.to_string()
+        }
+
+        fn probe(&self) -> Readiness {
+            let mut probed_at = self.probed_at.lock().unwrap();
+            probed_at.push(Instant::now());
+            if probed_at.len() > self.slow_for {
+                self.then
+            } else {
+                Readiness::NotYet
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ready_at_once() {
+        let network = MockProbe::new("network", 0, Readiness::Ready);
+        let audio = MockProbe::new("audio", 0, Readiness::Absent);
+        let report: StartupReport = wait_for_dependencies(
+            vec![network.clone(), audio.clone()],
+            DEFAULT_STARTUP_WAIT,
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert_eq!(report.ready, ["network"]);
+        assert_eq!(report.absent, ["audio"]);
+        assert!(!report.degraded());
+        assert_eq!(report.waited, Duration::ZERO);
+        assert_eq!(network.probed_at().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_dependency_is_reprobed_with_backoff() {
+        let start: Instant = Instant::now();
+        let network = MockProbe::new("network", 0, Readiness::Ready);
+        let audio = MockProbe::new("audio", 4, Readiness::Ready);
+        let report: StartupReport = wait_for_dependencies(
+            vec![network.clone(), audio.clone()],
+            DEFAULT_STARTUP_WAIT,
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert_eq!(report.ready, ["network", "audio"]);
+        assert!(!report.degraded());
+        // Only the device still missing is probed again
+        assert_eq!(network.probed_at().len(), 1);
+        let gaps: Vec<Duration> = audio
+            .probed_at()
+            .windows(2)
+            .map(|pair| pair[1] - pair[0])
+            .collect();
+        assert_eq!(
+            gaps,
+            [500, 1000, 2000, 4000].map(Duration::from_millis).to_vec()
+        );
+        assert_eq!(report.waited, start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_never_ready_starts_degraded_at_the_cap() {
+        let start: Instant = Instant::now();
+        let audio = MockProbe::new("audio", usize::MAX, Readiness::Ready);
+        let report: StartupReport = wait_for_dependencies(
+            vec![audio.clone()],
+            Duration::from_secs(40),
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert!(report.degraded());
+        assert_eq!(report.missing, ["audio"]);
+        assert_eq!(start.elapsed(), Duration::from_secs(40));
+        // Backoff tops out at MAX_REPROBE, and the last wait is cut to the cap
+        let probed_at: Vec<Instant> = audio.probed_at();
+        let last_gap: Duration = probed_at[probed_at.len() - 2] - probed_at[probed_at.len() - 3];
+        assert_eq!(last_gap, MAX_REPROBE);
+        assert_eq!(*probed_at.last().unwrap() - start, Duration::from_secs(40));
+    }
+
+    #[test]
+    fn test_network_probe_targets_server_host() {
+        let probe: NetworkProbe = NetworkProbe::for_url("wss://emns.example.com/ws").unwrap();
+        assert_eq!(probe.name(), "network (emns.example.com:443)");
+        let probe: NetworkProbe = NetworkProbe::for_url("ws://10.0.0.5:8080/ws").unwrap();
+        assert_eq!(probe.name(), "network (10.0.0.5:8080)");
+        let probe: NetworkProbe = NetworkProbe::for_url("ws://[::1]:8080/ws").unwrap();
+        assert_eq!(probe.name(), "network ([::1]:8080)");
+        assert!(NetworkProbe::for_url("not a url").is_none());
+    }
+}
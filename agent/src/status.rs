@@ -0,0 +1,176 @@
+//! Status reports describing the agent's internal health
+
+use crate::handler::HandlerStats;
+use crate::maintenance::MaintenanceWindow;
+use crate::messages::{AgentStatus, HeartbeatStats, SystemHealth};
+use crate::outbound::{OutboundQueue, Priority};
+use crate::queue::AlertQueue;
+use crate::rate_limit::AlertRateLimiter;
+use crate::sounds::SoundLibrary;
+use crate::timing::DeliveryTimings;
+use crate::update::Updater;
+use crate::watchdog::PipelineWatchdog;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+
+/// Builds [`AgentStatus`] reports and heartbeat details from the agent's queues
+pub struct StatusCollector {
+    client_id: String,
+    alert_queue: Arc<AlertQueue>,
+    outbound: Arc<OutboundQueue>,
+    /// Latest host reading, refreshed by the health sampler
+    system: Mutex<SystemHealth>,
+    /// Activity of the handler processing alerts; absent in broker mode
+    handler: Option<Arc<HandlerStats>>,
+    rate_limiter: Option<Arc<AlertRateLimiter>>,
+    watchdog: Option<Arc<PipelineWatchdog>>,
+    maintenance: Option<Arc<MaintenanceWindow>>,
+    updater: Option<Arc<Updater>>,
+    sounds: Option<SoundLibrary>,
+    /// Stage times of recently handled alerts; not reported when unset
+    timings: Option<Arc<DeliveryTimings>>,
+    /// Server messages skipped as unreadable
+    unreadable_messages: AtomicU64,
+    started: Instant,
+}
+
+impl StatusCollector {
+    pub fn new(
+        client_id: impl Into<String>,
+        alert_queue: Arc<AlertQueue>,
+        outbound: Arc<OutboundQueue>,
+    ) -> Self {
+        Self {
+            client_id: client_id.into(),
+            alert_queue,
+            outbound,
+            system: Mutex::new(SystemHealth::default()),
+            handler: None,
+            rate_limiter: None,
+            watchdog: None,
+            maintenance: None,
+            updater: None,
+            sounds: None,
+            timings: None,
+            unreadable_messages: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    /// Report the last-alert time and pending confirmations from `stats` in heartbeats
+    pub fn with_handler_stats(mut self, stats: Arc<HandlerStats>) -> Self {
+        self.handler = Some(stats);
+        self
+    }
+
+    /// Report the alerts `limiter` has shed
+    pub fn with_rate_limiter(mut self, limiter: Arc<AlertRateLimiter>) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// Report whether `watchdog` has found the alert pipeline stalled
+    pub fn with_watchdog(mut self, watchdog: Arc<PipelineWatchdog>) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Report whether a server has announced a maintenance window that is still open
+    pub fn with_maintenance(mut self, maintenance: Arc<MaintenanceWindow>) -> Self {
+        self.maintenance = Some(maintenance);
+        self
+    }
+
+    /// Report `updater`'s current, staged and last-checked releases
+    pub fn with_updater(mut self, updater: Arc<Updater>) -> Self {
+        self.updater = Some(updater);
+        self
+    }
+
+    /// Report the version of the sound pack `sounds` plays from
+    pub fn with_sounds(mut self, sounds: SoundLibrary) -> Self {
+        self.sounds = Some(sounds);
+        self
+    }
+
+    /// Report how long recent alerts spent in each stage of delivery
+    pub fn with_delivery_timings(mut self, timings: Arc<DeliveryTimings>) -> Self {
+        self.timings = Some(timings);
+        self
+    }
+
+    /// Replace the host health included in later reports
+    pub fn set_system_health(&self, health: SystemHealth) {
+        *self.system.lock().unwrap() = health;
+    }
+
+    /// Count a server message that could not be read
+    pub fn unreadable_message(&self) {
+        self.unreadable_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Snapshot of the current queue depths and the last host health sample
+    pub fn collect(&self) -> AgentStatus {
+        AgentStatus {
+            client_id: self.client_id.clone(),
+            reported_at: chrono::Utc::now(),
+            alert_queue_depth: self.alert_queue.depth(),
+            alert_queue_capacity: self.alert_queue.capacity(),
+            alerts_shed: self.alert_queue.shed_count(),
+            alerts_rate_limited: self
+                .rate_limiter
+                .as_ref()
+                .map_or(0, |limiter| limiter.routine_shed()),
+            urgent_alerts_rate_limited: self
+                .rate_limiter
+                .as_ref()
+                .map_or(0, |limiter| limiter.urgent_shed()),
+            confirmation_queue_depth: self.outbound.depth(Priority::Confirmation),
+            confirmation_queue_capacity: self.outbound.capacity(),
+            outbound_queue_depth: self.outbound.len(),
+            outbound_queue_capacity: self.outbound.capacity(),
+            outbound_dropped: self.outbound.dropped_count(),
+            confirmations_dropped: self.outbound.dropped(Priority::Confirmation),
+            pipeline_stalled: self
+                .watchdog
+                .as_ref()
+                .is_some_and(|watchdog| watchdog.is_stalled()),
+            pipeline_stalls: self
+                .watchdog
+                .as_ref()
+                .map_or(0, |watchdog| watchdog.stalls()),
+            clock_jumps: self.handler.as_ref().map_or(0, |stats| stats.clock_jumps()),
+            unreadable_messages: self.unreadable_messages.load(Ordering::Relaxed),
+            in_maintenance_window: self
+                .maintenance
+                .as_ref()
+                .is_some_and(|maintenance| maintenance.is_active()),
+            system: self.system.lock().unwrap().clone(),
+            update: self.updater.as_ref().map(|updater| updater.status()),
+            sound_pack_version: self.sounds.as_ref().and_then(SoundLibrary::pack_version),
+            delivery_timing: self.timings.as_ref().and_then(|timings| timings.summary()),
+        }
+    }
+
+    /// Details for a heartbeat on a connection established at `connected_at`
+    pub fn heartbeat(&self, connected_at: chrono::DateTime<chrono::Utc>) -> HeartbeatStats {
+        HeartbeatStats {
+            uptime_secs: Some(self.started.elapsed().as_secs()),
+            last_alert_secs: self
+                .handler
+                .as_ref()
+                .and_then(|stats| stats.since_last_alert())
+                .map(|age| age.as_secs()),
+            pending_confirmations: self
+                .handler
+                .as_ref()
+                .map(|stats| stats.pending_confirmations()),
+            connected_at: Some(connected_at),
+            client_id: Some(self.client_id.clone()),
+            agent_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            seq: None,
+            clock_skew_ms: None,
+        }
+    }
+}
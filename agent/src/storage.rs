@@ -0,0 +1,513 @@
+use crate::error::{EmnsError, Result};
+use crate::sealed::AlertKey;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// File name used for the persisted client identity
+pub const CLIENT_ID_FILE: &str = "client_id";
+
+/// File name used for the key sealed alerts are opened with
+pub const ALERT_KEY_FILE: &str = "alert_key";
+
+/// Extension given to protected state files
+const PROTECTED_EXT: &str = "dat";
+
+/// Encrypts and decrypts state before it touches the disk
+pub trait Protector: Send + Sync {
+    fn protect(&self, plaintext: &[u8]) -> Result<Vec<u8>>;
+    fn unprotect(&self, blob: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// DPAPI key scope for protected state
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DpapiScope {
+    /// Any process on this machine can decrypt, so only the files' owner-only
+    /// permissions keep other local users out; for a data directory shared
+    /// between accounts
+    Machine,
+    /// Only the Windows account the agent runs as can decrypt; for a service,
+    /// that is its service account
+    User,
+}
+
+impl DpapiScope {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "machine" => Some(DpapiScope::Machine),
+            "user" => Some(DpapiScope::User),
+            _ => None,
+        }
+    }
+}
+
+/// Windows Data Protection API (`CryptProtectData`)
+#[cfg(windows)]
+pub struct DpapiProtector {
+    scope: DpapiScope,
+}
+
+#[cfg(windows)]
+impl DpapiProtector {
+    pub fn new(scope: DpapiScope) -> Self {
+        Self { scope }
+    }
+
+    fn flags(&self) -> u32 {
+        use windows::Win32::Security::Cryptography::{
+            CRYPTPROTECT_LOCAL_MACHINE, CRYPTPROTECT_UI_FORBIDDEN,
+        };
+        match self.scope {
+            DpapiScope::Machine => CRYPTPROTECT_UI_FORBIDDEN | CRYPTPROTECT_LOCAL_MACHINE,
+            DpapiScope::User => CRYPTPROTECT_UI_FORBIDDEN,
+        }
+    }
+
+    /// Copy a DPAPI output blob into a Vec and release it
+    unsafe fn take_blob(
+        blob: windows::Win32::Security::Cryptography::CRYPT_INTEGER_BLOB,
+    ) -> Vec<u8> {
+        use windows::Win32::Foundation::{LocalFree, HLOCAL};
+        let data: Vec<u8> = std::slice::from_raw_parts(blob.pbData, blob.cbData as usize).to_vec();
+        let _ = LocalFree(HLOCAL(blob.pbData as *mut core::ffi::c_void));
+        data
+    }
+}
+
+#[cfg(windows)]
+impl Protector for DpapiProtector {
+    fn protect(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        use windows::core::PCWSTR;
+        use windows::Win32::Security::Cryptography::{CryptProtectData, CRYPT_INTEGER_BLOB};
+
+        let input = CRYPT_INTEGER_BLOB {
+            cbData: plaintext.len() as u32,
+            pbData: plaintext.as_ptr() as *mut u8,
+        };
+        let mut output = CRYPT_INTEGER_BLOB::default();
+        unsafe {
+            CryptProtectData(
+                &input,
+                PCWSTR::null(),
+                None,
+                None,
+                None,
+                self.flags(),
+                &mut output,
+            )
+            .map_err(|e| EmnsError::storage(None, format!("CryptProtectData failed: {}", e)))?;
+            Ok(Self::take_blob(output))
+        }
+    }
+
+    fn unprotect(&self, blob: &[u8]) -> Result<Vec<u8>> {
+        use windows::Win32::Security::Cryptography::{CryptUnprotectData, CRYPT_INTEGER_BLOB};
+
+        let input = CRYPT_INTEGER_BLOB {
+            cbData: blob.len() as u32,
+            pbData: blob.as_ptr() as *mut u8,
+        };
+        let mut output = CRYPT_INTEGER_BLOB::default();
+        unsafe {
+            CryptUnprotectData(&input, None, None, None, None, self.flags(), &mut output).map_err(
+                |e| EmnsError::storage(None, format!("CryptUnprotectData failed: {}", e)),
+            )?;
+            Ok(Self::take_blob(output))
+        }
+    }
+}
+
+/// Pass-through protector used where DPAPI is unavailable; files rely on permissions instead
+#[cfg(not(windows))]
+pub struct PlainProtector;
+
+#[cfg(not(windows))]
+impl Protector for PlainProtector {
+    fn protect(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        Ok(plaintext.to_vec())
+    }
+
+    fn unprotect(&self, blob: &[u8]) -> Result<Vec<u8>> {
+        Ok(blob.to_vec())
+    }
+}
+
+/// Default protector for this platform
+#[allow(unused_variables)]
+pub fn platform_protector(scope: DpapiScope) -> Box<dyn Protector> {
+    #[cfg(windows)]
+    {
+        Box::new(DpapiProtector::new(scope))
+    }
+    #[cfg(not(windows))]
+    {
+        Box::new(PlainProtector)
+    }
+}
+
+/// Protection for JSON-lines state files such as the history, applied to
+/// each line on its own so the file can still be appended to.
+///
+/// A protected line is the base64 of the protected blob. Without a
+/// protector, as on non-Windows builds, lines are written as they are and the
+/// file relies on its owner-only permissions.
+#[derive(Clone, Default)]
+pub struct LineProtector {
+    protector: Option<Arc<dyn Protector>>,
+}
+
+impl std::fmt::Debug for LineProtector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LineProtector")
+            .field("protected", &self.protector.is_some())
+            .finish()
+    }
+}
+
+impl LineProtector {
+    pub fn new(protector: Arc<dyn Protector>) -> Self {
+        Self {
+            protector: Some(protector),
+        }
+    }
+
+    /// DPAPI with `scope` on Windows; plain lines elsewhere
+    #[allow(unused_variables)]
+    pub fn platform(scope: DpapiScope) -> Self {
+        #[cfg(windows)]
+        {
+            Self::new(Arc::new(DpapiProtector::new(scope)))
+        }
+        #[cfg(not(windows))]
+        {
+            Self::default()
+        }
+    }
+
+    /// The line to write for `line`
+    pub fn protect(&self, line: &str) -> Result<String> {
+        match &self.protector {
+            Some(protector) => Ok(BASE64.encode(protector.protect(line.as_bytes())?)),
+            None => Ok(line.to_string()),
+        }
+    }
+
+    /// The line [`protect`](Self::protect) was given. A plaintext JSON line,
+    /// as older agents wrote, is returned as it is.
+    pub fn unprotect(&self, line: &str) -> Result<String> {
+        let protector: &Arc<dyn Protector> = match &self.protector {
+            Some(protector) if !is_plain_line(line) => protector,
+            _ => return Ok(line.to_string()),
+        };
+        let blob: Vec<u8> = BASE64
+            .decode(line.trim())
+            .map_err(|e| EmnsError::storage(None, format!("not a protected line: {}", e)))?;
+        String::from_utf8(protector.unprotect(&blob)?)
+            .map_err(|e| EmnsError::storage(None, format!("protected line is not text: {}", e)))
+    }
+
+    /// Whether `line` is plaintext that should be rewritten protected
+    pub fn needs_migration(&self, line: &str) -> bool {
+        self.protector.is_some() && is_plain_line(line)
+    }
+}
+
+/// Protected lines are base64, which never holds a brace
+fn is_plain_line(line: &str) -> bool {
+    line.trim_start().starts_with('{')
+}
+
+/// Agent state files kept in the data directory, protected at rest
+pub struct StateStore {
+    dir: PathBuf,
+    protector: Box<dyn Protector>,
+}
+
+impl StateStore {
+    pub fn new(dir: PathBuf, protector: Box<dyn Protector>) -> Result<Self> {
+        if !dir.exists() {
+            create_private_dir(&dir).map_err(|e| {
+                EmnsError::storage(
+                    Some(&dir),
+                    format!("Failed to create data directory: {}", e),
+                )
+            })?;
+            log::info!("Created data directory: {}", dir.display());
+        }
+        Ok(Self { dir, protector })
+    }
+
+    fn protected_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", name, PROTECTED_EXT))
+    }
+
+    fn legacy_path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Load a state file.
+    ///
+    /// Plaintext files left by older agents are migrated to protected form on first read.
+    /// Blobs that can't be decrypted are discarded with a warning so the caller regenerates them.
+    pub fn load(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let protected: PathBuf = self.protected_path(name);
+        if protected.exists() {
+            let blob: Vec<u8> =
+                std::fs::read(&protected).map_err(|e| EmnsError::storage(Some(&protected), e))?;
+            return match self.protector.unprotect(&blob) {
+                Ok(data) => Ok(Some(data)),
+                Err(e) => {
+                    log::warn!(
+                        "Discarding unreadable state file {}: {}",
+                        protected.display(),
+                        e
+                    );
+                    let _ = std::fs::remove_file(&protected);
+                    Ok(None)
+                }
+            };
+        }
+
+        let legacy: PathBuf = self.legacy_path(name);
+        if legacy.exists() {
+            let data: Vec<u8> =
+                std::fs::read(&legacy).map_err(|e| EmnsError::storage(Some(&legacy), e))?;
+            self.save(name, &data)?;
+            std::fs::remove_file(&legacy).map_err(|e| EmnsError::storage(Some(&legacy), e))?;
+            log::info!(
+                "Migrated plaintext state file {} to protected storage",
+                name
+            );
+            return Ok(Some(data));
+        }
+
+        Ok(None)
+    }
+
+    /// Protect and atomically write a state file
+    pub fn save(&self, name: &str, data: &[u8]) -> Result<()> {
+        let path: PathBuf = self.protected_path(name);
+        let blob: Vec<u8> = self.protector.protect(data).map_err(|e| match e {
+            EmnsError::Storage { path: None, detail } => EmnsError::Storage {
+                path: Some(path.clone()),
+                detail,
+            },
+            other => other,
+        })?;
+        let tmp: PathBuf = path.with_extension("tmp");
+        write_private_file(&tmp, &blob).map_err(|e| EmnsError::storage(Some(&tmp), e))?;
+        std::fs::rename(&tmp, &path).map_err(|e| EmnsError::storage(Some(&path), e))?;
+        Ok(())
+    }
+}
+
+/// Load the persisted client id, generating and saving a new one if none is usable
+pub fn load_or_create_client_id(store: &StateStore) -> Result<String> {
+    if let Some(data) = store.load(CLIENT_ID_FILE)? {
+        match String::from_utf8(data) {
+            Ok(id) if !id.trim().is_empty() => return Ok(id.trim().to_string()),
+            _ => log::warn!("Stored client id is invalid, generating a new one"),
+        }
+    }
+
+    let id: String = uuid::Uuid::new_v4().to_string();
+    store.save(CLIENT_ID_FILE, id.as_bytes())?;
+    log::info!("Generated new client id {}", id);
+    Ok(id)
+}
+
+/// Load the key pair for sealed alerts, generating and saving a new one if none is usable
+pub fn load_or_create_alert_key(store: &StateStore) -> Result<AlertKey> {
+    if let Some(data) = store.load(ALERT_KEY_FILE)? {
+        match <[u8; 32]>::try_from(data.as_slice()) {
+            Ok(secret) => return Ok(AlertKey::from_secret_bytes(secret)),
+            Err(_) => log::warn!("Stored alert key is invalid, generating a new one"),
+        }
+    }
+
+    let key: AlertKey = AlertKey::generate();
+    store.save(ALERT_KEY_FILE, &key.secret_bytes())?;
+    log::info!("Generated new alert key {}", key.key_id());
+    Ok(key)
+}
+
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+}
+
+#[cfg(windows)]
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    restrict_to_owner(dir, PRIVATE_DIR_SDDL)
+}
+
+/// Create or truncate `path`, readable and writable by its owner only
+pub(crate) fn create_private_file(path: &Path) -> std::io::Result<File> {
+    let mut options: std::fs::OpenOptions = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    open_private(path, options)
+}
+
+/// Open `path` for appending, creating it readable and writable by its owner only
+pub(crate) fn open_private_append(path: &Path) -> std::io::Result<File> {
+    let mut options: std::fs::OpenOptions = std::fs::OpenOptions::new();
+    options.create(true).append(true);
+    open_private(path, options)
+}
+
+pub(crate) fn write_private_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    create_private_file(path)?.write_all(data)
+}
+
+#[cfg(unix)]
+fn open_private(path: &Path, mut options: std::fs::OpenOptions) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(0o600).open(path)
+}
+
+/// The DACL is set before anything is written, so no data is ever readable
+/// under the permissions the file inherited
+#[cfg(windows)]
+fn open_private(path: &Path, options: std::fs::OpenOptions) -> std::io::Result<File> {
+    let file: File = options.open(path)?;
+    restrict_to_owner(path, PRIVATE_FILE_SDDL)?;
+    Ok(file)
+}
+
+/// Full control for the owner and SYSTEM only, nothing inherited
+#[cfg(windows)]
+const PRIVATE_FILE_SDDL: &str = "D:P(A;;FA;;;OW)(A;;FA;;;SY)";
+
+/// As [`PRIVATE_FILE_SDDL`], passed on to whatever is created inside
+#[cfg(windows)]
+const PRIVATE_DIR_SDDL: &str = "D:P(A;OICI;FA;;;OW)(A;OICI;FA;;;SY)";
+
+/// Replace the DACL on `path` with `sddl`
+#[cfg(windows)]
+fn restrict_to_owner(path: &Path, sddl: &str) -> std::io::Result<()> {
+    use windows::core::HSTRING;
+    use windows::Win32::Foundation::{LocalFree, HLOCAL};
+    use windows::Win32::Security::Authorization::{
+        ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+    };
+    use windows::Win32::Security::{
+        SetFileSecurityW, DACL_SECURITY_INFORMATION, PROTECTED_DACL_SECURITY_INFORMATION,
+        PSECURITY_DESCRIPTOR,
+    };
+
+    unsafe {
+        let mut descriptor: PSECURITY_DESCRIPTOR = PSECURITY_DESCRIPTOR::default();
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            &HSTRING::from(sddl),
+            SDDL_REVISION_1,
+            &mut descriptor,
+            None,
+        )
+        .map_err(std::io::Error::other)?;
+        let result: std::io::Result<()> = match SetFileSecurityW(
+            &HSTRING::from(path.as_os_str()),
+            DACL_SECURITY_INFORMATION | PROTECTED_DACL_SECURITY_INFORMATION,
+            descriptor,
+        )
+        .as_bool()
+        {
+            true => Ok(()),
+            false => Err(std::io::Error::last_os_error()),
+        };
+        let _ = LocalFree(HLOCAL(descriptor.0));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::XorProtector;
+
+    fn temp_store(test: &str) -> (PathBuf, StateStore) {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("emns-storage-{}-{}", test, uuid::Uuid::new_v4()));
+        let store: StateStore = StateStore::new(dir.clone(), Box::new(XorProtector)).unwrap();
+        (dir, store)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (dir, store) = temp_store("round-trip");
+        store.save("token", b"secret").unwrap();
+
+        let on_disk: Vec<u8> = std::fs::read(dir.join("token.dat")).unwrap();
+        assert!(!on_disk.windows(6).any(|w| w == b"secret"));
+        assert_eq!(store.load("token").unwrap(), Some(b"secret".to_vec()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrates_plaintext_file() {
+        let (dir, store) = temp_store("migrate");
+        std::fs::write(dir.join(CLIENT_ID_FILE), "workstation-01\n").unwrap();
+
+        assert_eq!(load_or_create_client_id(&store).unwrap(), "workstation-01");
+        assert!(!dir.join(CLIENT_ID_FILE).exists());
+        assert!(dir.join("client_id.dat").exists());
+        assert_eq!(load_or_create_client_id(&store).unwrap(), "workstation-01");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_blob_regenerates() {
+        let (dir, store) = temp_store("corrupt");
+        std::fs::write(dir.join("client_id.dat"), b"garbage").unwrap();
+
+        let id: String = load_or_create_client_id(&store).unwrap();
+        assert!(uuid::Uuid::parse_str(&id).is_ok());
+        assert_eq!(load_or_create_client_id(&store).unwrap(), id);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_unwritable_dir_is_storage_error() {
+        let file: PathBuf =
+            std::env::temp_dir().join(format!("emns-file-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&file, b"not a directory").unwrap();
+
+        let store: StateStore = StateStore::new(file.clone(), Box::new(XorProtector)).unwrap();
+        match store.save("token", b"secret").unwrap_err() {
+            EmnsError::Storage { path, .. } => assert!(path.unwrap().starts_with(&file)),
+            other => panic!("expected storage error, got {:?}", other),
+        }
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_parse_scope() {
+        assert_eq!(DpapiScope::parse("Machine"), Some(DpapiScope::Machine));
+        assert_eq!(DpapiScope::parse("user"), Some(DpapiScope::User));
+        assert_eq!(DpapiScope::parse("nobody"), None);
+    }
+
+    #[test]
+    fn test_alert_key_persists_protected() {
+        let (dir, store) = temp_store("alert-key");
+        let key: AlertKey = load_or_create_alert_key(&store).unwrap();
+
+        let on_disk: Vec<u8> = std::fs::read(dir.join("alert_key.dat")).unwrap();
+        assert!(!on_disk.windows(32).any(|w| w == key.secret_bytes()));
+        let reloaded: AlertKey = load_or_create_alert_key(&store).unwrap();
+        assert_eq!(reloaded.public_key(), key.public_key());
+
+        // A truncated key is replaced rather than used
+        store.save(ALERT_KEY_FILE, b"short").unwrap();
+        let replaced: AlertKey = load_or_create_alert_key(&store).unwrap();
+        assert_ne!(replaced.public_key(), key.public_key());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,75 @@
+//! Alerts that revise an earlier one, e.g. a weather watch upgraded to a
+//! warning and later called off
+
+use crate::messages::{AlertLevel, Confirmation};
+use crate::queue::priority;
+
+/// What becomes of a superseded alert still awaiting confirmation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SupersedePolicy {
+    /// Its toast is taken down and confirming the update confirms it too.
+    /// An update needing no confirmation leaves it pending
+    #[default]
+    Transfer,
+    /// Its toast is taken down and it is never confirmed
+    Cancel,
+}
+
+impl SupersedePolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "transfer" => Some(Self::Transfer),
+            "cancel" => Some(Self::Cancel),
+            _ => None,
+        }
+    }
+}
+
+/// The update's message, opening with which alert it revises
+pub fn mention(message: &str, earlier_title: &str) -> String {
+    format!("Updates earlier alert \"{}\".\n{}", earlier_title, message)
+}
+
+/// An update at `level` is no more severe than the `earlier` alert it revises
+pub fn no_louder(level: &AlertLevel, earlier: &AlertLevel) -> bool {
+    priority(level) <= priority(earlier)
+}
+
+/// Confirmations for the `earlier` alerts an update took over, sent along
+/// with the update's own `confirmation`
+pub fn carried_over(confirmation: &Confirmation, earlier: &[uuid::Uuid]) -> Vec<Confirmation> {
+    earlier
+        .iter()
+        .map(|alert_id| Confirmation {
+            alert_id: *alert_id,
+            // The option was the update's, though what it says holds for both
+            response_id: None,
+            ..confirmation.clone()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!(
+            SupersedePolicy::parse(" Transfer"),
+            Some(SupersedePolicy::Transfer)
+        );
+        assert_eq!(
+            SupersedePolicy::parse("cancel"),
+            Some(SupersedePolicy::Cancel)
+        );
+        assert_eq!(SupersedePolicy::parse("merge"), None);
+    }
+
+    #[test]
+    fn test_only_a_more_severe_update_is_louder() {
+        assert!(no_louder(&AlertLevel::Warning, &AlertLevel::Warning));
+        assert!(no_louder(&AlertLevel::Info, &AlertLevel::Critical));
+        assert!(!no_louder(&AlertLevel::Emergency, &AlertLevel::Critical));
+    }
+}
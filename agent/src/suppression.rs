@@ -0,0 +1,210 @@
+//! Windows scheduled by the server in which matching alerts are recorded but not shown
+
+use crate::board::BoardChanges;
+use crate::error::{EmnsError, Result};
+use crate::messages::{Alert, AlertLevel, SuppressionWindow};
+use crate::storage::write_private_file;
+use chrono::{DateTime, Utc};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// File name of the persisted windows inside the data directory
+pub const SUPPRESSION_FILE: &str = "suppressions.json";
+
+/// Suppression windows received from the server, saved so they outlast a restart
+#[derive(Debug, Default)]
+pub struct SuppressionWindows {
+    windows: Mutex<Vec<SuppressionWindow>>,
+    file: Option<PathBuf>,
+    allow_emergency: bool,
+    /// Told about every window added or cancelled
+    changes: Option<BoardChanges>,
+}
+
+impl SuppressionWindows {
+    /// Windows kept in memory only
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the windows saved at `path` and save every change back to it
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path: &Path = path.as_ref();
+        let windows: Vec<SuppressionWindow> = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| {
+                EmnsError::storage(Some(path), format!("Unreadable suppression windows: {}", e))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(EmnsError::storage(Some(path), e)),
+        };
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).map_err(|e| EmnsError::storage(Some(parent), e))?;
+        }
+        Ok(Self {
+            windows: Mutex::new(windows),
+            file: Some(path.to_path_buf()),
+            allow_emergency: false,
+            changes: None,
+        })
+    }
+
+    /// Let windows that list Emergency silence Emergency alerts (default: never)
+    pub fn allow_emergency(mut self, allow: bool) -> Self {
+        self.allow_emergency = allow;
+        self
+    }
+
+    /// Bump `changes` whenever a window is added or cancelled
+    pub fn with_changes(mut self, changes: BoardChanges) -> Self {
+        self.changes = Some(changes);
+        self
+    }
+
+    /// Add a window, replacing one with the same id
+    pub fn insert(&self, window: SuppressionWindow) {
+        let mut windows = self.windows.lock().unwrap();
+        log::info!(
+            "Suppression window {} scheduled from {} to {}: {}",
+            window.id,
+            window.starts_at,
+            window.ends_at,
+            window.reason
+        );
+        windows.retain(|w| w.id != window.id);
+        windows.push(window);
+        let now: DateTime<Utc> = Utc::now();
+        windows.retain(|w| w.ends_at > now);
+        self.save(&windows);
+        self.changed();
+    }
+
+    /// End a window early; `false` if no window has that id
+    pub fn cancel(&self, id: Uuid) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+        let before: usize = windows.len();
+        windows.retain(|w| w.id != id);
+        if windows.len() == before {
+            return false;
+        }
+        log::info!("Suppression window {} cancelled", id);
+        self.save(&windows);
+        self.changed();
+        true
+    }
+
+    fn changed(&self) {
+        if let Some(changes) = &self.changes {
+            changes.bump();
+        }
+    }
+
+    /// The window silencing `alert` at `now`, if any
+    pub fn suppressing(&self, alert: &Alert, now: DateTime<Utc>) -> Option<SuppressionWindow> {
+        if alert.level == AlertLevel::Emergency && !self.allow_emergency {
+            return None;
+        }
+        self.windows
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|w| w.covers(alert, now))
+            .cloned()
+    }
+
+    /// Windows that have not ended, including ones yet to start
+    pub fn scheduled(&self) -> Vec<SuppressionWindow> {
+        let now: DateTime<Utc> = Utc::now();
+        self.windows
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|w| w.ends_at > now)
+            .cloned()
+            .collect()
+    }
+
+    /// Failing to save only loses the windows at the next restart, so it is logged
+    fn save(&self, windows: &[SuppressionWindow]) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let tmp: PathBuf = path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(windows)
+            .map_err(std::io::Error::other)
+            .and_then(|data| write_private_file(&tmp, &data))
+            .and_then(|()| std::fs::rename(&tmp, path));
+        if let Err(e) = result {
+            log::error!(
+                "Failed to save suppression windows to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::alert;
+    use chrono::Duration;
+
+    fn window(levels: Vec<AlertLevel>) -> SuppressionWindow {
+        let now: DateTime<Utc> = Utc::now();
+        SuppressionWindow {
+            id: Uuid::new_v4(),
+            starts_at: now - Duration::minutes(5),
+            ends_at: now + Duration::hours(1),
+            levels,
+            categories: Vec::new(),
+            reason: "Fire alarm testing".to_string(),
+            location: None,
+        }
+    }
+
+    #[test]
+    fn test_emergency_needs_window_and_config() {
+        let emergency: Alert = alert(AlertLevel::Emergency, true);
+        let listed: SuppressionWindow = window(vec![AlertLevel::Emergency]);
+
+        let windows: SuppressionWindows = SuppressionWindows::new();
+        windows.insert(window(Vec::new()));
+        windows.insert(listed.clone());
+        assert!(windows.suppressing(&emergency, Utc::now()).is_none());
+
+        let allowed: SuppressionWindows = SuppressionWindows::new().allow_emergency(true);
+        allowed.insert(window(Vec::new()));
+        assert!(allowed.suppressing(&emergency, Utc::now()).is_none());
+        allowed.insert(listed.clone());
+        assert_eq!(
+            allowed.suppressing(&emergency, Utc::now()).map(|w| w.id),
+            Some(listed.id)
+        );
+    }
+
+    #[test]
+    fn test_windows_persist_and_cancel() {
+        let path: PathBuf = std::env::temp_dir()
+            .join(format!("emns-suppression-{}", Uuid::new_v4()))
+            .join(SUPPRESSION_FILE);
+        let kept: SuppressionWindow = window(Vec::new());
+        let cancelled: SuppressionWindow = window(Vec::new());
+        let ended: SuppressionWindow = SuppressionWindow {
+            ends_at: Utc::now() - Duration::minutes(1),
+            ..window(Vec::new())
+        };
+
+        let windows: SuppressionWindows = SuppressionWindows::open(&path).unwrap();
+        windows.insert(ended);
+        windows.insert(cancelled.clone());
+        windows.insert(kept.clone());
+        assert!(windows.cancel(cancelled.id));
+        assert!(!windows.cancel(cancelled.id));
+
+        let reopened: SuppressionWindows = SuppressionWindows::open(&path).unwrap();
+        assert_eq!(reopened.scheduled(), vec![kept]);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+//! Which alert a fullscreen takeover window shows when several compete for it.
+//!
+//! [`TakeoverQueue`] only decides; the window code feeds it alert events and
+//! carries out the [`WindowCommand`]s it returns, so one window is ever open
+//! and it never flickers between alerts of equal priority.
+
+use crate::messages::Alert;
+use crate::queue::priority;
+
+/// What the window should do after an event
+#[derive(Debug, Clone)]
+pub enum WindowCommand {
+    /// Open the window on this alert
+    Show(Alert),
+    /// Keep the window open and switch it to this alert
+    Replace(Alert),
+    Close,
+}
+
+/// Why an alert left the takeover
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TakeoverEnd {
+    Confirmed,
+    /// The server withdrew the alert
+    Cancelled,
+    /// Timed out unanswered
+    Expired,
+}
+
+/// The alert on screen and those waiting for the window, highest priority first.
+///
+/// Alerts wait in order of level, then timestamp, then arrival. Only a strictly
+/// higher level displaces the alert on screen, which goes back into the queue
+/// in its usual place.
+#[derive(Debug, Default)]
+pub struct TakeoverQueue {
+    displayed: Option<Entry>,
+    waiting: Vec<Entry>,
+    arrivals: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    alert: Alert,
+    arrival: u64,
+}
+
+impl Entry {
+    /// Sorts first when it should be shown first
+    fn key(&self) -> (std::cmp::Reverse<u8>, chrono::DateTime<chrono::Utc>, u64) {
+        (
+            std::cmp::Reverse(priority(&self.alert.level)),
+            self.alert.timestamp,
+            self.arrival,
+        )
+    }
+}
+
+impl TakeoverQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An alert wants the window; alerts already displayed or waiting are ignored
+    pub fn arrive(&mut self, alert: Alert) -> Option<WindowCommand> {
+        if self.contains(alert.id) {
+            return None;
+        }
+        self.arrivals += 1;
+        let entry: Entry = Entry {
+            alert,
+            arrival: self.arrivals,
+        };
+
+        let Some(displayed) = &self.displayed else {
+            let command: WindowCommand = WindowCommand::Show(entry.alert.clone());
+            self.displayed = Some(entry);
+            return Some(command);
+        };
+        if priority(&entry.alert.level) <= priority(&displayed.alert.level) {
+            self.enqueue(entry);
+            return None;
+        }
+
+        log::info!(
+            "Alert {} takes over the window from lower-priority alert {}",
+            entry.alert.id,
+            displayed.alert.id
+        );
+        let command: WindowCommand = WindowCommand::Replace(entry.alert.clone());
+        if let Some(preempted) = self.displayed.replace(entry) {
+            self.enqueue(preempted);
+        }
+        Some(command)
+    }
+
+    /// An alert is done with; the window moves on if it was the one on screen
+    pub fn finish(&mut self, alert_id: uuid::Uuid, end: TakeoverEnd) -> Option<WindowCommand> {
+        if self.displayed.as_ref().map(|e| e.alert.id) != Some(alert_id) {
+            self.waiting.retain(|e| e.alert.id != alert_id);
+            return None;
+        }
+
+        log::debug!("Alert {} left the window ({:?})", alert_id, end);
+        if self.waiting.is_empty() {
+            self.displayed = None;
+            return Some(WindowCommand::Close);
+        }
+        let next: Entry = self.waiting.remove(0);
+        let command: WindowCommand = WindowCommand::Replace(next.alert.clone());
+        self.displayed = Some(next);
+        Some(command)
+    }
+
+    /// Empty the queue for shutdown: the command to close the window, if it is
+    /// open, and every alert that was displayed or waiting, in queue order
+    pub fn drain(&mut self) -> (Option<WindowCommand>, Vec<Alert>) {
+        let close: Option<WindowCommand> = self.displayed.is_some().then_some(WindowCommand::Close);
+        let alerts: Vec<Alert> = self
+            .displayed
+            .take()
+            .into_iter()
+            .chain(self.waiting.drain(..))
+            .map(|e| e.alert)
+            .collect();
+        (close, alerts)
+    }
+
+    /// The alert on screen
+    pub fn displayed(&self) -> Option<&Alert> {
+        self.displayed.as_ref().map(|e| &e.alert)
+    }
+
+    /// Alerts waiting for the window, next first
+    pub fn waiting(&self) -> Vec<&Alert> {
+        self.waiting.iter().map(|e| &e.alert).collect()
+    }
+
+    fn contains(&self, alert_id: uuid::Uuid) -> bool {
+        self.displayed
+            .iter()
+            .chain(&self.waiting)
+            .any(|e| e.alert.id == alert_id)
+    }
+
+    fn enqueue(&mut self, entry: Entry) {
+        let at: usize = self.waiting.partition_point(|e| e.key() <= entry.key());
+        self.waiting.insert(at, entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::AlertLevel;
+    use crate::test_support::alert;
+
+    /// Commands compared by alert id
+    #[derive(Debug, PartialEq)]
+    enum Cmd {
+        Show(uuid::Uuid),
+        Replace(uuid::Uuid),
+        Close,
+    }
+
+    fn cmd(command: Option<WindowCommand>) -> Option<Cmd> {
+        command.map(|command| match command {
+            WindowCommand::Show(alert) => Cmd::Show(alert.id),
+            WindowCommand::Replace(alert) => Cmd::Replace(alert.id),
+            WindowCommand::Close => Cmd::Close,
+        })
+    }
+
+    fn ids<'a>(alerts: impl IntoIterator<Item = &'a Alert>) -> Vec<uuid::Uuid> {
+        alerts.into_iter().map(|a| a.id).collect()
+    }
+
+    #[test]
+    fn test_one_window_and_equal_priority_waits_its_turn() {
+        let mut queue: TakeoverQueue = TakeoverQueue::new();
+        let first: Alert = alert(AlertLevel::Emergency, true);
+        let second: Alert = alert(AlertLevel::Emergency, true);
+
+        assert_eq!(cmd(queue.arrive(first.clone())), Some(Cmd::Show(first.id)));
+        assert_eq!(cmd(queue.arrive(second.clone())), None);
+        assert_eq!(cmd(queue.arrive(first.clone())), None);
+        assert_eq!(queue.displayed().map(|a| a.id), Some(first.id));
+        assert_eq!(ids(queue.waiting()), vec![second.id]);
+
+        assert_eq!(
+            cmd(queue.finish(first.id, TakeoverEnd::Confirmed)),
+            Some(Cmd::Replace(second.id))
+        );
+        assert_eq!(
+            cmd(queue.finish(second.id, TakeoverEnd::Expired)),
+            Some(Cmd::Close)
+        );
+        assert!(queue.displayed().is_none());
+        // Finishing an alert the queue no longer has changes nothing
+        assert_eq!(cmd(queue.finish(second.id, TakeoverEnd::Confirmed)), None);
+    }
+
+    #[test]
+    fn test_higher_priority_preempts_and_the_preempted_alert_is_requeued() {
+        let mut queue: TakeoverQueue = TakeoverQueue::new();
+        let critical: Alert = alert(AlertLevel::Critical, true);
+        let later_critical: Alert = alert(AlertLevel::Critical, true);
+        let emergency: Alert = alert(AlertLevel::Emergency, true);
+
+        queue.arrive(critical.clone());
+        queue.arrive(later_critical.clone());
+        assert_eq!(
+            cmd(queue.arrive(emergency.clone())),
+            Some(Cmd::Replace(emergency.id))
+        );
+        // The preempted alert is older, so it is next again
+        assert_eq!(ids(queue.waiting()), vec![critical.id, later_critical.id]);
+
+        assert_eq!(
+            cmd(queue.finish(emergency.id, TakeoverEnd::Confirmed)),
+            Some(Cmd::Replace(critical.id))
+        );
+    }
+
+    #[test]
+    fn test_queue_orders_by_level_then_timestamp() {
+        let mut queue: TakeoverQueue = TakeoverQueue::new();
+        let shown: Alert = alert(AlertLevel::Emergency, true);
+        let newer: Alert = alert(AlertLevel::Critical, true);
+        let mut older: Alert = alert(AlertLevel::Critical, true);
+        older.timestamp = newer.timestamp - chrono::Duration::minutes(5);
+        let emergency: Alert = alert(AlertLevel::Emergency, true);
+
+        queue.arrive(shown.clone());
+        queue.arrive(newer.clone());
+        queue.arrive(older.clone());
+        // Equal to the alert on screen, so it waits, but ahead of the Critical ones
+        assert_eq!(cmd(queue.arrive(emergency.clone())), None);
+        assert_eq!(ids(queue.waiting()), vec![emergency.id, older.id, newer.id]);
+
+        // A waiting alert that is cancelled leaves the queue without touching the window
+        assert_eq!(cmd(queue.finish(older.id, TakeoverEnd::Cancelled)), None);
+        assert_eq!(ids(queue.waiting()), vec![emergency.id, newer.id]);
+        assert_eq!(queue.displayed().map(|a| a.id), Some(shown.id));
+    }
+
+    #[test]
+    fn test_drain_closes_the_window_and_returns_everything() {
+        let mut queue: TakeoverQueue = TakeoverQueue::new();
+        let (close, drained) = queue.drain();
+        assert!(close.is_none() && drained.is_empty());
+
+        let shown: Alert = alert(AlertLevel::Emergency, true);
+        let waiting: Alert = alert(AlertLevel::Critical, true);
+        queue.arrive(shown.clone());
+        queue.arrive(waiting.clone());
+        let (close, drained) = queue.drain();
+        assert_eq!(cmd(close), Some(Cmd::Close));
+        assert_eq!(ids(&drained), vec![shown.id, waiting.id]);
+        assert!(queue.displayed().is_none());
+        assert!(queue.waiting().is_empty());
+
+        // Usable again after draining
+        assert_eq!(
+            cmd(queue.arrive(waiting.clone())),
+            Some(Cmd::Show(waiting.id))
+        );
+    }
+}
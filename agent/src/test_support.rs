@@ -0,0 +1,329 @@
+//! Mock backends shared by unit tests
+
+use crate::attention::{AttentionBackend, UserNotificationState};
+use crate::audio::{AudioBackend, PlaybackHandle};
+use crate::clock::Clock;
+use crate::countdown::Countdown;
+use crate::error::{EmnsError, Result};
+use crate::idle::IdleProbe;
+use crate::messages::{Alert, AlertLevel, Confirmation};
+use crate::notification::NotificationBackend;
+use crate::operator::{OperatorPrompt, OperatorQuestion};
+use crate::outbound::{OutboundMessage, OutboundQueue};
+use crate::power::PowerBackend;
+use crate::storage::Protector;
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+/// Reads back the confirmations queued for the server, skipping other messages
+pub struct Confirmations(Arc<OutboundQueue>);
+
+impl Confirmations {
+    pub fn new(outbound: &Arc<OutboundQueue>) -> Self {
+        Self(outbound.clone())
+    }
+
+    pub async fn recv(&self) -> Confirmation {
+        loop {
+            if let OutboundMessage::Confirmation(confirmation) = self.0.next().await {
+                return confirmation;
+            }
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<Confirmation> {
+        std::iter::from_fn(|| self.0.try_next()).find_map(|message| match message {
+            OutboundMessage::Confirmation(confirmation) => Some(confirmation),
+            _ => None,
+        })
+    }
+}
+
+/// A wall clock that runs with tokio's clock and can be jumped
+pub struct ManualClock {
+    start: chrono::DateTime<chrono::Utc>,
+    started: tokio::time::Instant,
+    offset: Mutex<chrono::TimeDelta>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            start: chrono::Utc::now(),
+            started: tokio::time::Instant::now(),
+            offset: Mutex::new(chrono::TimeDelta::zero()),
+        }
+    }
+
+    /// Move the wall clock by `by`, as an NTP correction would
+    pub fn jump(&self, by: chrono::TimeDelta) {
+        *self.offset.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        let elapsed: chrono::TimeDelta =
+            chrono::TimeDelta::from_std(self.started.elapsed()).unwrap();
+        self.start + elapsed + *self.offset.lock().unwrap()
+    }
+}
+
+/// Gives canned answers to the operator id prompt, then cancels it
+#[derive(Default)]
+pub struct MockOperatorPrompt {
+    answers: Mutex<std::collections::VecDeque<Option<String>>>,
+    questions: Mutex<Vec<OperatorQuestion>>,
+}
+
+impl MockOperatorPrompt {
+    /// `None` answers cancel the prompt
+    pub fn answering<'a>(answers: impl IntoIterator<Item = Option<&'a str>>) -> Self {
+        Self {
+            answers: Mutex::new(answers.into_iter().map(|a| a.map(String::from)).collect()),
+            questions: Mutex::default(),
+        }
+    }
+
+    pub fn questions(&self) -> Vec<OperatorQuestion> {
+        self.questions.lock().unwrap().clone()
+    }
+}
+
+impl OperatorPrompt for MockOperatorPrompt {
+    fn ask(&self, question: &OperatorQuestion) -> Option<String> {
+        self.questions.lock().unwrap().push(question.clone());
+        self.answers.lock().unwrap().pop_front().flatten()
+    }
+}
+
+/// Records every alert it is asked to show
+#[derive(Default)]
+pub struct MockNotifier {
+    shown: Mutex<Vec<Alert>>,
+    countdowns: Mutex<Vec<(uuid::Uuid, Countdown)>>,
+    dismissed: Mutex<Vec<uuid::Uuid>>,
+    removed: Mutex<Vec<uuid::Uuid>>,
+    acknowledged: Mutex<Vec<(uuid::Uuid, Vec<String>)>>,
+}
+
+impl MockNotifier {
+    pub fn shown(&self) -> Vec<Alert> {
+        self.shown.lock().unwrap().clone()
+    }
+
+    /// Every countdown update requested, including those for toasts already gone
+    pub fn countdowns(&self) -> Vec<(uuid::Uuid, Countdown)> {
+        self.countdowns.lock().unwrap().clone()
+    }
+
+    /// Toasts the handler asked to take down
+    pub fn removed(&self) -> Vec<uuid::Uuid> {
+        self.removed.lock().unwrap().clone()
+    }
+
+    /// Toasts marked as acknowledged by others, and by whom
+    pub fn acknowledged(&self) -> Vec<(uuid::Uuid, Vec<String>)> {
+        self.acknowledged.lock().unwrap().clone()
+    }
+
+    /// Simulate the toast disappearing without the handler being told
+    pub fn dismiss(&self, alert_id: uuid::Uuid) {
+        self.dismissed.lock().unwrap().push(alert_id);
+    }
+}
+
+impl NotificationBackend for MockNotifier {
+    fn show_notification(&self, alert: &Alert) -> Result<()> {
+        self.shown.lock().unwrap().push(alert.clone());
+        Ok(())
+    }
+
+    fn update_countdown(&self, alert_id: uuid::Uuid, countdown: &Countdown) -> Result<bool> {
+        self.countdowns.lock().unwrap().push((alert_id, *countdown));
+        Ok(!self.dismissed.lock().unwrap().contains(&alert_id))
+    }
+
+    fn remove_notification(&self, alert_id: uuid::Uuid) -> Result<()> {
+        self.removed.lock().unwrap().push(alert_id);
+        Ok(())
+    }
+
+    fn show_acknowledged(&self, alert_id: uuid::Uuid, acknowledged_by: &[String]) -> Result<bool> {
+        self.acknowledged
+            .lock()
+            .unwrap()
+            .push((alert_id, acknowledged_by.to_vec()));
+        Ok(!self.dismissed.lock().unwrap().contains(&alert_id))
+    }
+}
+
+/// Records every sound it is asked to play
+#[derive(Default)]
+pub struct MockAudio {
+    played: Mutex<Vec<String>>,
+    played_at: Mutex<Vec<(String, f32, CancellationToken)>>,
+}
+
+impl MockAudio {
+    pub fn played(&self) -> Vec<String> {
+        self.played.lock().unwrap().clone()
+    }
+
+    /// Sounds started with a volume override, and whether each has been stopped
+    pub fn played_at(&self) -> Vec<(String, f32, bool)> {
+        self.played_at
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(sound, volume, stop)| (sound.clone(), *volume, stop.is_cancelled()))
+            .collect()
+    }
+}
+
+impl AudioBackend for MockAudio {
+    fn play(&self, sound_file: &str) {
+        self.played.lock().unwrap().push(sound_file.to_string());
+    }
+
+    fn play_at(&self, sound_file: &str, volume: f32) -> PlaybackHandle {
+        let stop: CancellationToken = CancellationToken::new();
+        self.played_at
+            .lock()
+            .unwrap()
+            .push((sound_file.to_string(), volume, stop.clone()));
+        PlaybackHandle::new(stop)
+    }
+}
+
+/// A test alert with the given level
+pub fn alert(level: AlertLevel, requires_confirmation: bool) -> Alert {
+    Alert {
+        id: uuid::Uuid::new_v4(),
+        title: format!("{} test alert", level.as_str()),
+        message: "Test message".to_string(),
+        level,
+        requires_confirmation,
+        sound_file: None,
+        timestamp: chrono::Utc::now(),
+        ..Default::default()
+    }
+}
+
+/// Records keep-awake changes and display wakes
+#[derive(Default)]
+pub struct MockPower {
+    keep_awake: Mutex<Vec<bool>>,
+    wakes: Mutex<usize>,
+}
+
+impl MockPower {
+    pub fn keep_awake_calls(&self) -> Vec<bool> {
+        self.keep_awake.lock().unwrap().clone()
+    }
+
+    pub fn wakes(&self) -> usize {
+        *self.wakes.lock().unwrap()
+    }
+}
+
+impl PowerBackend for MockPower {
+    fn set_keep_awake(&self, keep_awake: bool) {
+        self.keep_awake.lock().unwrap().push(keep_awake);
+    }
+
+    fn wake_display(&self) {
+        *self.wakes.lock().unwrap() += 1;
+    }
+}
+
+/// Reports a settable notification state and records attention requests
+pub struct MockAttention {
+    state: Mutex<UserNotificationState>,
+    requested: Mutex<Vec<uuid::Uuid>>,
+    cleared: Mutex<usize>,
+}
+
+impl MockAttention {
+    pub fn set_state(&self, state: UserNotificationState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    pub fn requested(&self) -> Vec<uuid::Uuid> {
+        self.requested.lock().unwrap().clone()
+    }
+
+    pub fn cleared(&self) -> usize {
+        *self.cleared.lock().unwrap()
+    }
+}
+
+impl Default for MockAttention {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(UserNotificationState::AcceptsNotifications),
+            requested: Mutex::new(Vec::new()),
+            cleared: Mutex::new(0),
+        }
+    }
+}
+
+impl AttentionBackend for MockAttention {
+    fn notification_state(&self) -> UserNotificationState {
+        *self.state.lock().unwrap()
+    }
+
+    fn request_attention(&self, alert: &Alert) {
+        self.requested.lock().unwrap().push(alert.id);
+    }
+
+    fn clear_attention(&self) {
+        *self.cleared.lock().unwrap() += 1;
+    }
+}
+
+/// Reports idle time since the last simulated input, on tokio's clock
+pub struct MockIdle {
+    last_input: Mutex<tokio::time::Instant>,
+}
+
+impl MockIdle {
+    /// Simulate keyboard or mouse input now
+    pub fn input(&self) {
+        *self.last_input.lock().unwrap() = tokio::time::Instant::now();
+    }
+}
+
+impl Default for MockIdle {
+    fn default() -> Self {
+        Self {
+            last_input: Mutex::new(tokio::time::Instant::now()),
+        }
+    }
+}
+
+impl IdleProbe for MockIdle {
+    fn idle_time(&self) -> Option<std::time::Duration> {
+        Some(self.last_input.lock().unwrap().elapsed())
+    }
+}
+
+/// Reversible stand-in for DPAPI that fails on blobs it didn't produce
+pub struct XorProtector;
+
+const XOR_MAGIC: &[u8] = b"TEST";
+
+impl Protector for XorProtector {
+    fn protect(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut out: Vec<u8> = XOR_MAGIC.to_vec();
+        out.extend(plaintext.iter().map(|b| b ^ 0x5a));
+        Ok(out)
+    }
+
+    fn unprotect(&self, blob: &[u8]) -> Result<Vec<u8>> {
+        let body: &[u8] = blob
+            .strip_prefix(XOR_MAGIC)
+            .ok_or_else(|| EmnsError::storage(None, "not a protected blob"))?;
+        Ok(body.iter().map(|b| b ^ 0x5a).collect())
+    }
+}
@@ -0,0 +1,243 @@
+//! Where an alert's delivery time goes: stamps taken at each stage on its way
+//! to the user, and the recent distribution of time spent in each stage
+
+use crate::clock::{Clock, SystemClock};
+use crate::messages::{DeliveryTiming, StageTiming};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Alerts the reported distribution is drawn from
+pub const TIMING_SAMPLES: usize = 200;
+
+/// Wall-clock stamps taken as one alert moves through the agent.
+///
+/// Copied along with the alert, so tracing allocates nothing. A stamp is
+/// `None` for a stage the alert did not go through, e.g. `received` for a
+/// locally raised alert.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliveryTrace {
+    /// The frame carrying the alert was read off the socket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parsed: Option<chrono::DateTime<chrono::Utc>>,
+    /// Placed on the alert queue
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enqueued: Option<chrono::DateTime<chrono::Utc>>,
+    /// Taken off the alert queue by the handler
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dequeued: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub toast_shown: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sound_started: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// A stretch of an alert's delivery, between two stamps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// The sender's timestamp to `received`
+    Transit,
+    Parse,
+    Enqueue,
+    Queued,
+    Toast,
+    Sound,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 6] = [
+        Stage::Transit,
+        Stage::Parse,
+        Stage::Enqueue,
+        Stage::Queued,
+        Stage::Toast,
+        Stage::Sound,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Transit => "transit",
+            Stage::Parse => "parse",
+            Stage::Enqueue => "enqueue",
+            Stage::Queued => "queued",
+            Stage::Toast => "toast",
+            Stage::Sound => "sound",
+        }
+    }
+}
+
+impl DeliveryTrace {
+    /// Time the alert spent in `stage`, given when its sender stamped it.
+    ///
+    /// `None` when either end was not stamped, or the end comes before the
+    /// start, as clock skew can make transit appear.
+    pub fn stage(
+        &self,
+        stage: Stage,
+        sent_at: chrono::DateTime<chrono::Utc>,
+    ) -> Option<std::time::Duration> {
+        let (from, to) = match stage {
+            Stage::Transit => (Some(sent_at), self.received),
+            Stage::Parse => (self.received, self.parsed),
+            Stage::Enqueue => (self.parsed, self.enqueued),
+            Stage::Queued => (self.enqueued, self.dequeued),
+            Stage::Toast => (self.dequeued, self.toast_shown),
+            Stage::Sound => (self.dequeued, self.sound_started),
+        };
+        (to? - from?).to_std().ok()
+    }
+}
+
+/// Milliseconds one alert spent in each of [`Stage::ALL`]
+type Sample = [Option<u64>; Stage::ALL.len()];
+
+/// The clock every stage is stamped from, and the stage times of the latest alerts
+pub struct DeliveryTimings {
+    clock: Arc<dyn Clock>,
+    /// Oldest first; allocated once, at full size
+    samples: Mutex<VecDeque<Sample>>,
+}
+
+impl Default for DeliveryTimings {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock))
+    }
+}
+
+impl DeliveryTimings {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            samples: Mutex::new(VecDeque::with_capacity(TIMING_SAMPLES)),
+        }
+    }
+
+    /// Stamp for a stage reached now
+    pub fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        self.clock.now()
+    }
+
+    /// Add a handled alert's stage times, forgetting the oldest alert's when full
+    pub fn record(&self, sent_at: chrono::DateTime<chrono::Utc>, trace: &DeliveryTrace) {
+        let sample: Sample = Stage::ALL.map(|stage| {
+            trace
+                .stage(stage, sent_at)
+                .map(|time| time.as_millis() as u64)
+        });
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() >= TIMING_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// Median and 95th percentile of each stage; `None` before any alert was recorded
+    pub fn summary(&self) -> Option<DeliveryTiming> {
+        let samples = self.samples.lock().unwrap();
+        if samples.is_empty() {
+            return None;
+        }
+        let stage = |index: usize| {
+            let mut times: Vec<u64> = samples.iter().filter_map(|s| s[index]).collect();
+            times.sort_unstable();
+            (!times.is_empty()).then(|| StageTiming {
+                p50_ms: percentile(&times, 50),
+                p95_ms: percentile(&times, 95),
+            })
+        };
+        Some(DeliveryTiming {
+            samples: samples.len(),
+            transit: stage(0),
+            parse: stage(1),
+            enqueue: stage(2),
+            queued: stage(3),
+            toast: stage(4),
+            sound: stage(5),
+        })
+    }
+}
+
+/// Nearest-rank percentile of sorted, non-empty `times`
+fn percentile(times: &[u64], percent: usize) -> u64 {
+    let rank: usize = (times.len() * percent).div_ceil(100).max(1);
+    times[rank - 1]
+}
+
+/// One line per stage of `timing`, for printing
+pub fn report(timing: &DeliveryTiming) -> Vec<String> {
+    let stages: [(Stage, Option<StageTiming>); 6] = [
+        (Stage::Transit, timing.transit),
+        (Stage::Parse, timing.parse),
+        (Stage::Enqueue, timing.enqueue),
+        (Stage::Queued, timing.queued),
+        (Stage::Toast, timing.toast),
+        (Stage::Sound, timing.sound),
+    ];
+    stages
+        .iter()
+        .map(|(stage, times)| match times {
+            Some(times) => format!(
+                "{:<8} p50 {:>6} ms  p95 {:>6} ms",
+                stage.as_str(),
+                times.p50_ms,
+                times.p95_ms
+            ),
+            None => format!("{:<8} no samples", stage.as_str()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ManualClock;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_percentiles_over_recent_alerts() {
+        let clock: Arc<ManualClock> = Arc::new(ManualClock::new());
+        let timings: DeliveryTimings = DeliveryTimings::new(clock.clone());
+        assert_eq!(timings.summary(), None);
+
+        // Queue waits of 1..=100 ms; nothing else is stamped
+        for waited in 1..=100 {
+            let mut trace: DeliveryTrace = DeliveryTrace {
+                enqueued: Some(timings.now()),
+                ..DeliveryTrace::default()
+            };
+            clock.jump(chrono::TimeDelta::milliseconds(waited));
+            trace.dequeued = Some(timings.now());
+            timings.record(timings.now(), &trace);
+        }
+        let summary: DeliveryTiming = timings.summary().unwrap();
+        assert_eq!(summary.samples, 100);
+        assert_eq!(
+            summary.queued,
+            Some(StageTiming {
+                p50_ms: 50,
+                p95_ms: 95
+            })
+        );
+        assert_eq!(summary.transit, None);
+        assert_eq!(summary.toast, None);
+
+        // Only the latest alerts count
+        for _ in 0..TIMING_SAMPLES {
+            timings.record(timings.now(), &DeliveryTrace::default());
+        }
+        let summary: DeliveryTiming = timings.summary().unwrap();
+        assert_eq!(summary.samples, TIMING_SAMPLES);
+        assert_eq!(summary.queued, None);
+    }
+
+    #[test]
+    fn test_skewed_transit_is_left_out() {
+        let sent_at: chrono::DateTime<chrono::Utc> = chrono::Utc::now();
+        let trace: DeliveryTrace = DeliveryTrace {
+            received: Some(sent_at - chrono::TimeDelta::seconds(2)),
+            ..DeliveryTrace::default()
+        };
+        assert_eq!(trace.stage(Stage::Transit, sent_at), None);
+    }
+}
@@ -0,0 +1,213 @@
+//! How toasts are presented for each alert level, and per alert when the server says so
+
+use crate::messages::{Alert, AlertLevel, ToastOptions};
+
+/// Windows toast scenario, which decides how long the toast stays and how it sounds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToastScenario {
+    Default,
+    Alarm,
+    Reminder,
+    /// Stays on screen until acted on, styled as a call
+    IncomingCall,
+    Urgent,
+}
+
+impl ToastScenario {
+    /// Parse a scenario name, ignoring case
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "default" => Some(Self::Default),
+            "alarm" => Some(Self::Alarm),
+            "reminder" => Some(Self::Reminder),
+            "incomingcall" => Some(Self::IncomingCall),
+            "urgent" => Some(Self::Urgent),
+            _ => None,
+        }
+    }
+
+    /// Value of the toast's `scenario` attribute
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::Alarm => "alarm",
+            Self::Reminder => "reminder",
+            Self::IncomingCall => "incomingCall",
+            Self::Urgent => "urgent",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToastDuration {
+    Short,
+    Long,
+}
+
+impl ToastDuration {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "short" => Some(Self::Short),
+            "long" => Some(Self::Long),
+            _ => None,
+        }
+    }
+
+    /// Value of the toast's `duration` attribute
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Short => "short",
+            Self::Long => "long",
+        }
+    }
+}
+
+/// How one toast is presented
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ToastStyle {
+    pub scenario: ToastScenario,
+    pub duration: ToastDuration,
+    /// Deliver straight to Action Center without a popup
+    pub suppress_popup: bool,
+}
+
+impl ToastStyle {
+    pub const fn new(scenario: ToastScenario, duration: ToastDuration) -> Self {
+        Self {
+            scenario,
+            duration,
+            suppress_popup: false,
+        }
+    }
+
+    /// This style with the alert's overrides applied; invalid values are logged and ignored
+    fn with_options(mut self, alert_id: uuid::Uuid, options: &ToastOptions) -> Self {
+        if let Some(value) = &options.scenario {
+            match ToastScenario::parse(value) {
+                Some(scenario) => self.scenario = scenario,
+                None => log::warn!(
+                    "Alert {} asks for unknown toast scenario {:?}; using {}",
+                    alert_id,
+                    value,
+                    self.scenario.as_str()
+                ),
+            }
+        }
+        if let Some(value) = &options.duration {
+            match ToastDuration::parse(value) {
+                Some(duration) => self.duration = duration,
+                None => log::warn!(
+                    "Alert {} asks for unknown toast duration {:?}; using {}",
+                    alert_id,
+                    value,
+                    self.duration.as_str()
+                ),
+            }
+        }
+        if let Some(suppress_popup) = options.suppress_popup {
+            self.suppress_popup = suppress_popup;
+        }
+        self
+    }
+}
+
+/// Default toast style for each alert level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ToastStyles {
+    pub info: ToastStyle,
+    pub warning: ToastStyle,
+    pub critical: ToastStyle,
+    pub emergency: ToastStyle,
+}
+
+impl Default for ToastStyles {
+    fn default() -> Self {
+        Self {
+            info: ToastStyle::new(ToastScenario::Default, ToastDuration::Short),
+            warning: ToastStyle::new(ToastScenario::Reminder, ToastDuration::Long),
+            critical: ToastStyle::new(ToastScenario::Urgent, ToastDuration::Long),
+            emergency: ToastStyle::new(ToastScenario::Urgent, ToastDuration::Long),
+        }
+    }
+}
+
+impl ToastStyles {
+    pub fn for_level(&self, level: &AlertLevel) -> &ToastStyle {
+        match level {
+            AlertLevel::Info => &self.info,
+            AlertLevel::Warning => &self.warning,
+            AlertLevel::Critical => &self.critical,
+            AlertLevel::Emergency => &self.emergency,
+        }
+    }
+
+    pub fn for_level_mut(&mut self, level: &AlertLevel) -> &mut ToastStyle {
+        match level {
+            AlertLevel::Info => &mut self.info,
+            AlertLevel::Warning => &mut self.warning,
+            AlertLevel::Critical => &mut self.critical,
+            AlertLevel::Emergency => &mut self.emergency,
+        }
+    }
+
+    /// Style for `alert`: its level's default, with the alert's own toast options winning
+    pub fn resolve(&self, alert: &Alert) -> ToastStyle {
+        let style: ToastStyle = *self.for_level(&alert.level);
+        match &alert.toast {
+            Some(options) => style.with_options(alert.id, options),
+            None => style,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::alert;
+
+    #[test]
+    fn test_alert_options_override_level_defaults_field_by_field() {
+        let mut styles: ToastStyles = ToastStyles::default();
+        styles.warning.suppress_popup = true;
+
+        let mut warning: Alert = alert(AlertLevel::Warning, false);
+        assert_eq!(
+            styles.resolve(&warning),
+            ToastStyle {
+                suppress_popup: true,
+                ..ToastStyle::new(ToastScenario::Reminder, ToastDuration::Long)
+            }
+        );
+
+        warning.toast = Some(ToastOptions {
+            duration: Some("SHORT".to_string()),
+            suppress_popup: Some(false),
+            ..ToastOptions::default()
+        });
+        assert_eq!(
+            styles.resolve(&warning),
+            ToastStyle::new(ToastScenario::Reminder, ToastDuration::Short)
+        );
+    }
+
+    #[test]
+    fn test_unknown_values_fall_back_to_the_level_default() {
+        let mut emergency: Alert = alert(AlertLevel::Emergency, true);
+        emergency.toast = Some(ToastOptions {
+            scenario: Some("klaxon".to_string()),
+            duration: Some("forever".to_string()),
+            suppress_popup: Some(true),
+        });
+        assert_eq!(
+            ToastStyles::default().resolve(&emergency),
+            ToastStyle {
+                suppress_popup: true,
+                ..ToastStyle::new(ToastScenario::Urgent, ToastDuration::Long)
+            }
+        );
+        assert_eq!(
+            ToastScenario::parse("incomingcall"),
+            Some(ToastScenario::IncomingCall)
+        );
+    }
+}
@@ -0,0 +1,778 @@
+//! Connections to the notification server, abstracted so the client can be tested without sockets
+
+use crate::compression::{self, ReceiveLimits};
+use crate::error::{EmnsError, Result};
+use futures_util::future::BoxFuture;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use native_tls::{Certificate, TlsConnector};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, AUTHORIZATION};
+use tokio_tungstenite::tungstenite::http::Uri;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::Connector;
+
+pub use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+pub use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+pub use tokio_tungstenite::tungstenite::Message as Frame;
+
+/// Outgoing half of a connection
+pub type FrameSink = Pin<Box<dyn Sink<Frame, Error = EmnsError> + Send>>;
+
+/// Incoming half of a connection
+pub type FrameStream = Pin<Box<dyn Stream<Item = Result<Frame>> + Send>>;
+
+/// An open, bidirectional connection to the server
+pub struct Connection {
+    pub sink: FrameSink,
+    pub stream: FrameStream,
+}
+
+/// Opens connections to the server
+pub trait Transport: Send + Sync {
+    fn connect<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Connection>>;
+}
+
+/// How `wss://` servers' certificates are trusted
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// PEM bundle of root certificates trusted alongside the system store
+    pub ca_file: Option<PathBuf>,
+    /// Accept any certificate for any host; for lab testing only
+    pub insecure_skip_verify: bool,
+}
+
+impl TlsConfig {
+    /// Connector trusting the extra roots; `None` when the system defaults apply
+    pub fn connector(&self) -> Result<Option<TlsConnector>> {
+        if self.ca_file.is_none() && !self.insecure_skip_verify {
+            return Ok(None);
+        }
+        let mut builder = TlsConnector::builder();
+        if let Some(path) = &self.ca_file {
+            let pem: Vec<u8> = std::fs::read(path).map_err(|e| {
+                EmnsError::config("TLS_CA_FILE", format!("{}: {}", path.display(), e))
+            })?;
+            let roots: Vec<Certificate> = Certificate::stack_from_pem(&pem).map_err(|e| {
+                EmnsError::config("TLS_CA_FILE", format!("{}: {}", path.display(), e))
+            })?;
+            if roots.is_empty() {
+                return Err(EmnsError::config(
+                    "TLS_CA_FILE",
+                    format!("{} holds no PEM certificates", path.display()),
+                ));
+            }
+            for root in roots {
+                builder.add_root_certificate(root);
+            }
+        }
+        if self.insecure_skip_verify {
+            builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+        let connector: TlsConnector = builder
+            .build()
+            .map_err(|e| EmnsError::config("TLS_CA_FILE", e))?;
+        Ok(Some(connector))
+    }
+}
+
+/// Default limit on opening a TCP connection to one of the server's addresses
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default largest message accepted from the server
+pub const DEFAULT_MAX_RECEIVE_MESSAGE_BYTES: usize = 1 << 20;
+
+/// Default largest single frame accepted from the server
+pub const DEFAULT_MAX_RECEIVE_FRAME_BYTES: usize = 1 << 20;
+
+/// Where a server URL points: an address given literally, or a host name to look up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerHost {
+    Address(SocketAddr),
+    Name { host: String, port: u16 },
+}
+
+impl ServerHost {
+    /// Read the host and port of a `ws://` or `wss://` URL. IPv6 addresses
+    /// are bracketed, e.g. `ws://[::1]:8080/ws`
+    pub fn from_url(url: &str) -> Result<Self> {
+        let uri: Uri = url.parse().map_err(|e| EmnsError::connection(url, e))?;
+        let host: &str = uri
+            .host()
+            .ok_or_else(|| EmnsError::connection(url, "no host in URL"))?;
+        let port: u16 = match (uri.port_u16(), uri.scheme_str()) {
+            (Some(port), _) => port,
+            (None, Some("wss")) => 443,
+            (None, Some("ws")) => 80,
+            (None, _) => return Err(EmnsError::connection(url, "not a ws:// or wss:// URL")),
+        };
+        let bare: &str = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+        Ok(match bare.parse::<IpAddr>() {
+            Ok(ip) => ServerHost::Address(SocketAddr::new(ip, port)),
+            Err(_) => ServerHost::Name {
+                host: bare.to_string(),
+                port,
+            },
+        })
+    }
+
+    /// The name to check the server's certificate against: the host name, or
+    /// the address without the brackets URLs put around IPv6
+    pub fn domain(&self) -> String {
+        match self {
+            ServerHost::Address(addr) => addr.ip().to_string(),
+            ServerHost::Name { host, .. } => host.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for ServerHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerHost::Address(addr) => write!(f, "{}", addr),
+            ServerHost::Name { host, port } => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// Looks up the addresses behind a server's host name
+pub trait HostResolver: Send + Sync {
+    fn lookup<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, std::io::Result<Vec<SocketAddr>>>;
+}
+
+/// Asks the operating system on every call, so a DNS record moved to
+/// another server is followed on the next reconnect
+#[derive(Debug, Default)]
+pub struct SystemHostResolver;
+
+impl HostResolver for SystemHostResolver {
+    fn lookup<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, std::io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move { Ok(tokio::net::lookup_host((host, port)).await?.collect()) })
+    }
+}
+
+/// Open a connection to the first of `addrs`, in order, that answers within `timeout`
+async fn connect_first<S, F, Fut>(
+    url: &str,
+    addrs: &[SocketAddr],
+    timeout: Duration,
+    connect: F,
+) -> Result<(SocketAddr, S)>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = std::io::Result<S>>,
+{
+    let mut failures: Vec<String> = Vec::new();
+    for &addr in addrs {
+        let failure: String = match tokio::time::timeout(timeout, connect(addr)).await {
+            Ok(Ok(stream)) => return Ok((addr, stream)),
+            Ok(Err(e)) => format!("{}: {}", addr, e),
+            Err(_) => format!("{}: no answer within {:?}", addr, timeout),
+        };
+        log::debug!("Could not reach {} at {}", url, failure);
+        failures.push(failure);
+    }
+    if failures.is_empty() {
+        return Err(EmnsError::connection(url, "host name has no addresses"));
+    }
+    Err(EmnsError::connection(url, failures.join("; ")))
+}
+
+/// WebSocket transport backed by tokio-tungstenite
+pub struct TungsteniteTransport {
+    /// Used for `wss://` servers; the system trust store when unset
+    tls: Option<TlsConnector>,
+    /// `Authorization` header sent with every upgrade request; none when unset
+    auth: Option<HeaderValue>,
+    /// Looks up the server's host name afresh for every connection
+    resolver: Arc<dyn HostResolver>,
+    /// Limit on reaching each of the server's addresses before trying the next
+    connect_timeout: Duration,
+    /// Size limits on what the server sends; a connection exceeding them is dropped
+    websocket: WebSocketConfig,
+    /// Offer permessage-deflate on every connection; see [`crate::compression`]
+    compression: bool,
+}
+
+impl Default for TungsteniteTransport {
+    fn default() -> Self {
+        Self {
+            tls: None,
+            auth: None,
+            resolver: Arc::new(SystemHostResolver),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            websocket: WebSocketConfig::default(),
+            compression: false,
+        }
+        .with_receive_limits(
+            DEFAULT_MAX_RECEIVE_MESSAGE_BYTES,
+            DEFAULT_MAX_RECEIVE_FRAME_BYTES,
+        )
+    }
+}
+
+impl TungsteniteTransport {
+    /// Transport that trusts `wss://` servers as `tls` says
+    pub fn with_tls(tls: &TlsConfig) -> Result<Self> {
+        if tls.insecure_skip_verify {
+            log::warn!(
+                "TLS_INSECURE_SKIP_VERIFY is set: server certificates are NOT checked, and \
+                 anyone on the network can pose as the server. Use this for lab testing only"
+            );
+        }
+        Ok(Self {
+            tls: tls.connector()?,
+            ..Self::default()
+        })
+    }
+
+    /// Look up host names with `resolver` (default: [`SystemHostResolver`])
+    pub fn with_resolver(mut self, resolver: Arc<dyn HostResolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Try the next of the server's addresses once one has not answered
+    /// within `timeout` (default: [`DEFAULT_CONNECT_TIMEOUT`])
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Refuse messages from the server over `max_message` bytes, and frames
+    /// over `max_frame` (default: [`DEFAULT_MAX_RECEIVE_MESSAGE_BYTES`] and
+    /// [`DEFAULT_MAX_RECEIVE_FRAME_BYTES`]). Past a refused message the
+    /// connection cannot be read on, so it is dropped and made again.
+    pub fn with_receive_limits(mut self, max_message: usize, max_frame: usize) -> Self {
+        self.websocket.max_message_size = Some(max_message);
+        self.websocket.max_frame_size = Some(max_frame);
+        self
+    }
+
+    /// Offer the server permessage-deflate compression when `enabled`
+    /// (default: off); the server may decline it
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// Every address `url` points at, looked up now
+    async fn addresses(&self, url: &str) -> Result<Vec<SocketAddr>> {
+        match ServerHost::from_url(url)? {
+            ServerHost::Address(addr) => Ok(vec![addr]),
+            ServerHost::Name { host, port } => {
+                self.resolver.lookup(&host, port).await.map_err(|e| {
+                    EmnsError::connection(url, format!("cannot resolve {}: {}", host, e))
+                })
+            }
+        }
+    }
+
+    /// Send `token` as a bearer token when opening each connection
+    pub fn with_auth_token(mut self, token: Option<&AuthToken>) -> Self {
+        self.auth = token.map(|token| token.header.clone());
+        self
+    }
+
+    /// Finish connecting over `tcp` with permessage-deflate offered
+    async fn connect_compressed(&self, url: &str, tcp: TcpStream) -> Result<Connection> {
+        let limits: ReceiveLimits = ReceiveLimits {
+            max_message: self
+                .websocket
+                .max_message_size
+                .unwrap_or(DEFAULT_MAX_RECEIVE_MESSAGE_BYTES),
+            max_frame: self
+                .websocket
+                .max_frame_size
+                .unwrap_or(DEFAULT_MAX_RECEIVE_FRAME_BYTES),
+        };
+        let auth: Option<&[u8]> = self.auth.as_ref().map(HeaderValue::as_bytes);
+        let uri: Uri = url.parse().map_err(|e| EmnsError::connection(url, e))?;
+        if uri.scheme_str() != Some("wss") {
+            return compression::connect(url, tcp, auth, limits).await;
+        }
+        let tls: TlsConnector = match &self.tls {
+            Some(tls) => tls.clone(),
+            None => TlsConnector::new().map_err(|e| EmnsError::connection(url, e))?,
+        };
+        let domain: String = ServerHost::from_url(url)?.domain();
+        let tls_stream = tokio_native_tls::TlsConnector::from(tls)
+            .connect(&domain, tcp)
+            .await
+            .map_err(|e| EmnsError::connection(url, e))?;
+        compression::connect(url, tls_stream, auth, limits).await
+    }
+}
+
+/// Secret the server checks before taking a registration, sent as a bearer
+/// token on the upgrade request; kept out of debug output
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuthToken {
+    header: HeaderValue,
+}
+
+impl AuthToken {
+    pub fn new(token: &str) -> Result<Self> {
+        let mut header: HeaderValue = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|_| EmnsError::config("AUTH_TOKEN", "must be printable ASCII"))?;
+        header.set_sensitive(true);
+        Ok(Self { header })
+    }
+}
+
+impl std::fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AuthToken(<redacted>)")
+    }
+}
+
+impl Transport for TungsteniteTransport {
+    fn connect<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Connection>> {
+        Box::pin(async move {
+            let connector: Option<Connector> = self.tls.clone().map(Connector::NativeTls);
+            let mut request = url
+                .into_client_request()
+                .map_err(|e| EmnsError::connection(url, e))?;
+            if let Some(auth) = &self.auth {
+                request.headers_mut().insert(AUTHORIZATION, auth.clone());
+            }
+            let addrs: Vec<SocketAddr> = self.addresses(url).await?;
+            let (addr, tcp) =
+                connect_first(url, &addrs, self.connect_timeout, TcpStream::connect).await?;
+            log::info!("Reached {} at {}", url, addr);
+            if self.compression {
+                return self.connect_compressed(url, tcp).await;
+            }
+            let (ws_stream, _) = tokio_tungstenite::client_async_tls_with_config(
+                request,
+                tcp,
+                Some(self.websocket),
+                connector,
+            )
+            .await
+            .map_err(|e| EmnsError::connection(url, e))?;
+            let (sink, stream) = ws_stream.split();
+
+            let sink_url: String = url.to_string();
+            let stream_url: String = url.to_string();
+            Ok(Connection {
+                sink: Box::pin(sink.sink_map_err(move |e| EmnsError::connection(&sink_url, e))),
+                stream: Box::pin(stream.map(move |r| {
+                    r.map_err(|e| {
+                        if let WsError::Capacity(capacity) = &e {
+                            log::error!(
+                                "Server {} sent more than this agent accepts ({}); dropping the connection",
+                                stream_url,
+                                capacity
+                            );
+                        }
+                        EmnsError::connection(&stream_url, e)
+                    })
+                })),
+            })
+        })
+    }
+}
+
+/// In-memory transport with a scripted server side, for tests
+pub mod memory {
+    use super::*;
+    use crate::messages::{Envelope, Message};
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc;
+
+    /// Transport whose connections are accepted by a [`MemoryListener`]
+    pub struct MemoryTransport {
+        accept_tx: mpsc::UnboundedSender<MemoryPeer>,
+        refusals: Mutex<VecDeque<String>>,
+    }
+
+    /// Server side of a [`MemoryTransport`]
+    pub struct MemoryListener {
+        accept_rx: mpsc::UnboundedReceiver<MemoryPeer>,
+    }
+
+    /// Server end of one in-memory connection
+    pub struct MemoryPeer {
+        to_client: mpsc::UnboundedSender<Result<Frame>>,
+        from_client: mpsc::UnboundedReceiver<Frame>,
+        url: String,
+        /// Set once the client's writes are to hang
+        stalled: Arc<AtomicBool>,
+    }
+
+    impl MemoryTransport {
+        pub fn new() -> (Self, MemoryListener) {
+            let (accept_tx, accept_rx) = mpsc::unbounded_channel();
+            (
+                Self {
+                    accept_tx,
+                    refusals: Mutex::new(VecDeque::new()),
+                },
+                MemoryListener { accept_rx },
+            )
+        }
+
+        /// Make the next connection attempt fail with `detail`
+        pub fn refuse_next(&self, detail: impl Into<String>) {
+            self.refusals.lock().unwrap().push_back(detail.into());
+        }
+    }
+
+    impl Transport for MemoryTransport {
+        fn connect<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Connection>> {
+            Box::pin(async move {
+                if let Some(detail) = self.refusals.lock().unwrap().pop_front() {
+                    return Err(EmnsError::connection(url, detail));
+                }
+
+                let (to_client, client_rx) = mpsc::unbounded_channel::<Result<Frame>>();
+                let (client_tx, from_client) = mpsc::unbounded_channel::<Frame>();
+                let stalled: Arc<AtomicBool> = Arc::default();
+                self.accept_tx
+                    .send(MemoryPeer {
+                        to_client,
+                        from_client,
+                        url: url.to_string(),
+                        stalled: stalled.clone(),
+                    })
+                    .map_err(|_| EmnsError::connection(url, "listener dropped"))?;
+
+                let sink_url: String = url.to_string();
+                let sink = futures_util::sink::unfold(client_tx, move |tx, frame: Frame| {
+                    let url: String = sink_url.clone();
+                    let stalled: bool = stalled.load(Ordering::SeqCst);
+                    async move {
+                        if stalled {
+                            std::future::pending::<()>().await;
+                        }
+                        tx.send(frame)
+                            .map_err(|_| EmnsError::connection(&url, "peer closed"))?;
+                        Ok::<_, EmnsError>(tx)
+                    }
+                });
+                let stream = futures_util::stream::unfold(client_rx, |mut rx| async move {
+                    rx.recv().await.map(|frame| (frame, rx))
+                });
+
+                Ok(Connection {
+                    sink: Box::pin(sink),
+                    stream: Box::pin(stream),
+                })
+            })
+        }
+    }
+
+    impl MemoryListener {
+        /// Wait for the client's next connection
+        pub async fn accept(&mut self) -> Option<MemoryPeer> {
+            self.accept_rx.recv().await
+        }
+    }
+
+    impl MemoryPeer {
+        /// Send a protocol message to the client
+        pub fn send(&self, message: &Message) {
+            let json: String = serde_json::to_string(message).expect("message serializes");
+            self.send_frame(Frame::Text(json));
+        }
+
+        /// Accept the client's registration, as a server does before anything else
+        pub fn ack_registration(&self) {
+            self.send(&Message::RegisterAck {
+                server_name: None,
+                environment: None,
+                encoding: None,
+                server_version: None,
+                protocol_version: None,
+                server_time: None,
+                envelopes: false,
+                confirmation_timeouts: false,
+            });
+        }
+
+        /// Accept the client's registration and ask for messages in envelopes
+        pub fn ack_registration_with_envelopes(&self) {
+            self.send(&Message::RegisterAck {
+                server_name: None,
+                environment: None,
+                encoding: None,
+                server_version: None,
+                protocol_version: None,
+                server_time: None,
+                envelopes: true,
+                confirmation_timeouts: false,
+            });
+        }
+
+        /// Send a protocol message to the client in an envelope with `message_id`
+        pub fn send_envelope(&self, message_id: uuid::Uuid, message: &Message) {
+            let envelope: Envelope = Envelope {
+                message_id,
+                message: message.clone(),
+            };
+            let json: String = serde_json::to_string(&envelope).expect("envelope serializes");
+            self.send_frame(Frame::Text(json));
+        }
+
+        /// Send a protocol message to the client as MessagePack
+        pub fn send_msgpack(&self, message: &Message) {
+            let bytes: Vec<u8> = message.to_msgpack().expect("message encodes");
+            self.send_frame(Frame::Binary(bytes));
+        }
+
+        /// Send a raw frame to the client
+        pub fn send_frame(&self, frame: Frame) {
+            let _ = self.to_client.send(Ok(frame));
+        }
+
+        /// Fail the connection as if it dropped mid-frame
+        pub fn fail(&self, detail: &str) {
+            let _ = self
+                .to_client
+                .send(Err(EmnsError::connection(&self.url, detail)));
+        }
+
+        /// Make the client's sends fail from now on, as on a connection that
+        /// broke while writing, while its reads stay open
+        pub fn break_writes(&mut self) {
+            self.from_client.close();
+        }
+
+        /// Make the client's sends hang from now on, as when the TCP window
+        /// fills on a dead link, while its reads stay open
+        pub fn stall_writes(&self) {
+            self.stalled.store(true, Ordering::SeqCst);
+        }
+
+        /// Close the connection with a close code
+        pub fn close(&self, code: CloseCode, reason: &str) {
+            self.send_frame(Frame::Close(Some(CloseFrame {
+                code,
+                reason: reason.to_string().into(),
+            })));
+        }
+
+        /// URL the client connected to
+        pub fn url(&self) -> &str {
+            &self.url
+        }
+
+        /// Next frame from the client, or `None` once the client has hung up
+        pub async fn recv_frame(&mut self) -> Option<Frame> {
+            self.from_client.recv().await
+        }
+
+        /// Next protocol message from the client, in either encoding, skipping
+        /// other frames and answering pings as a real server's WebSocket stack would
+        pub async fn recv(&mut self) -> Option<Message> {
+            loop {
+                match self.recv_frame().await? {
+                    Frame::Text(text) => {
+                        return Some(serde_json::from_str(&text).expect("client sent valid JSON"))
+                    }
+                    Frame::Binary(bytes) => {
+                        return Some(
+                            Message::from_msgpack(&bytes).expect("client sent valid msgpack"),
+                        )
+                    }
+                    Frame::Ping(data) => self.send_frame(Frame::Pong(data)),
+                    _ => {}
+                }
+            }
+        }
+
+        /// Next envelope from the client, once it has been asked for them
+        pub async fn recv_envelope(&mut self) -> Option<Envelope> {
+            loop {
+                match self.recv_frame().await? {
+                    Frame::Text(text) => {
+                        return Some(serde_json::from_str(&text).expect("client sent an envelope"))
+                    }
+                    Frame::Ping(data) => self.send_frame(Frame::Pong(data)),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    /// Answers every lookup with the same addresses, counting the lookups
+    struct MockResolver {
+        addrs: Vec<SocketAddr>,
+        lookups: AtomicUsize,
+    }
+
+    impl HostResolver for MockResolver {
+        fn lookup<'a>(
+            &'a self,
+            _host: &'a str,
+            _port: u16,
+        ) -> BoxFuture<'a, std::io::Result<Vec<SocketAddr>>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok(self.addrs.clone()) })
+        }
+    }
+
+    #[test]
+    fn test_server_host_from_url() {
+        assert_eq!(
+            ServerHost::from_url("ws://[::1]:8080/ws").unwrap(),
+            ServerHost::Address("[::1]:8080".parse().unwrap())
+        );
+        assert_eq!(
+            ServerHost::from_url("ws://10.0.0.5/ws").unwrap(),
+            ServerHost::Address("10.0.0.5:80".parse().unwrap())
+        );
+        assert_eq!(
+            ServerHost::from_url("wss://emns.example.com/ws").unwrap(),
+            ServerHost::Name {
+                host: "emns.example.com".to_string(),
+                port: 443
+            }
+        );
+        assert!(ServerHost::from_url("not a url").is_err());
+        assert!(ServerHost::from_url("ftp://emns.example.com/ws").is_err());
+    }
+
+    #[test]
+    fn test_server_host_domain_has_no_brackets() {
+        let domain = |url: &str| ServerHost::from_url(url).unwrap().domain();
+        assert_eq!(domain("wss://[::1]:8443/ws"), "::1");
+        assert_eq!(domain("wss://[fd00::5]/ws"), "fd00::5");
+        assert_eq!(domain("wss://10.0.0.5:8443/ws"), "10.0.0.5");
+        assert_eq!(domain("wss://emns.example.com/ws"), "emns.example.com");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_address_that_does_not_answer_is_skipped() {
+        let silent: SocketAddr = "[fd00::5]:8080".parse().unwrap();
+        let answering: SocketAddr = "10.0.0.5:8080".parse().unwrap();
+        let (addr, stream) = connect_first(
+            "ws://emns.test:8080/ws",
+            &[silent, answering],
+            Duration::from_secs(5),
+            |addr| async move {
+                if addr == silent {
+                    std::future::pending::<()>().await;
+                }
+                Ok(addr.port())
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!((addr, stream), (answering, 8080));
+
+        let err: EmnsError = connect_first(
+            "ws://emns.test:8080/ws",
+            &[silent],
+            Duration::from_secs(5),
+            |_| std::future::pending::<std::io::Result<()>>(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("[fd00::5]:8080"));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_is_connection_error() {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: String = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            ws.send(Frame::Text("x".repeat(1024))).await.unwrap();
+            ws.send(Frame::Text("x".repeat(1025))).await.unwrap();
+        });
+
+        let mut connection: Connection = TungsteniteTransport::default()
+            .with_receive_limits(1024, 1024)
+            .connect(&url)
+            .await
+            .unwrap();
+        match connection.stream.next().await {
+            Some(Ok(Frame::Text(text))) => assert_eq!(text.len(), 1024),
+            other => panic!("expected the message at the limit, got {:?}", other),
+        }
+        match connection.stream.next().await {
+            Some(Err(EmnsError::Connection { detail, .. })) => {
+                assert!(detail.contains("1025"), "{}", detail)
+            }
+            other => panic!("expected a size error, got {:?}", other),
+        }
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_falls_back_to_next_address() {
+        // A port nothing listens on any more, then a real server
+        let refused: SocketAddr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listening: SocketAddr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            for _ in 0..2 {
+                let (tcp, _) = listener.accept().await.unwrap();
+                tokio_tungstenite::accept_async(tcp).await.unwrap();
+            }
+        });
+
+        let resolver: Arc<MockResolver> = Arc::new(MockResolver {
+            addrs: vec![refused, listening],
+            lookups: AtomicUsize::new(0),
+        });
+        let transport: TungsteniteTransport =
+            TungsteniteTransport::default().with_resolver(resolver.clone());
+        let url: String = format!("ws://emns.test:{}/ws", listening.port());
+        transport.connect(&url).await.unwrap();
+        // Looked up again for the next connection
+        transport.connect(&url).await.unwrap();
+        assert_eq!(resolver.lookups.load(Ordering::SeqCst), 2);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_server_is_connection_error() {
+        let err: EmnsError = TungsteniteTransport::default()
+            .connect("ws://127.0.0.1:9/ws")
+            .await
+            .err()
+            .unwrap();
+        match err {
+            EmnsError::Connection { url, .. } => assert_eq!(url, "ws://127.0.0.1:9/ws"),
+            other => panic!("expected connection error, got {:?}", other),
+        }
+    }
+}
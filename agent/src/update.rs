@@ -0,0 +1,558 @@
+//! Self-update: download a signed agent binary, stage it, and swap it in for
+//! the next start, keeping the old binary for rollback
+
+use crate::error::{EmnsError, Result};
+use crate::handler::AlertHandler;
+use crate::messages::{UpdateManifest, UpdateStatus};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::NaiveTime;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use std::cmp::Ordering;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+/// Default time between looks at the manifest URL
+pub const DEFAULT_UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Largest agent binary downloaded
+pub const MAX_UPDATE_BYTES: u64 = 100 * 1024 * 1024;
+
+/// Directory under the data dir that downloads are staged in
+pub const UPDATES_DIR: &str = "updates";
+
+/// Exit code asking the service wrapper to start the agent again, into the new binary
+pub const RESTART_EXIT_CODE: i32 = 75;
+
+/// How often a staged update waits for its restart window and for emergencies to clear
+const RESTART_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A release version: dotted numbers, compared numerically
+#[derive(Debug, Clone)]
+pub struct Version(Vec<u64>);
+
+impl Version {
+    /// Parse `1.4.2`, with or without a leading `v`
+    pub fn parse(value: &str) -> Result<Self> {
+        let digits: &str = value.trim().trim_start_matches('v');
+        let parts: Vec<u64> = digits
+            .split('.')
+            .map(|part| part.parse::<u64>())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| EmnsError::protocol(format!("{:?} is not a release version", value)))?;
+        Ok(Self(parts))
+    }
+}
+
+impl Ord for Version {
+    /// Missing trailing parts count as zero, so `1.4` and `1.4.0` are equal
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len: usize = self.0.len().max(other.0.len());
+        let part = |v: &Version, i: usize| v.0.get(i).copied().unwrap_or(0);
+        (0..len)
+            .map(|i| part(self, i).cmp(&part(other, i)))
+            .find(|order| order.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Local times of day the agent may restart into a staged update
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl RestartWindow {
+    /// Parse `HH:MM-HH:MM`; a window may run past midnight, e.g. `23:00-01:00`
+    pub fn parse(value: &str) -> Option<Self> {
+        let (start, end) = value.trim().split_once('-')?;
+        let time = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").ok();
+        let window: RestartWindow = Self {
+            start: time(start)?,
+            end: time(end)?,
+        };
+        (window.start != window.end).then_some(window)
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Self-update settings
+#[derive(Debug, Clone)]
+pub struct UpdateConfig {
+    /// Key release binaries are signed with
+    pub public_key: VerifyingKey,
+    /// HTTPS manifest polled every `check_interval`; only server offers are taken when `None`
+    pub manifest_url: Option<String>,
+    pub check_interval: Duration,
+    /// When the agent may exit to be restarted into a staged update; it waits for
+    /// the next start when `None`
+    pub restart_window: Option<RestartWindow>,
+    pub max_bytes: u64,
+}
+
+impl UpdateConfig {
+    pub fn new(public_key: VerifyingKey) -> Self {
+        Self {
+            public_key,
+            manifest_url: None,
+            check_interval: DEFAULT_UPDATE_CHECK_INTERVAL,
+            restart_window: None,
+            max_bytes: MAX_UPDATE_BYTES,
+        }
+    }
+}
+
+/// Parse a base64 Ed25519 public key
+pub fn parse_public_key(value: &str) -> Result<VerifyingKey> {
+    BASE64
+        .decode(value.trim())
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| EmnsError::config("UPDATE_PUBLIC_KEY", "not a base64 Ed25519 public key"))
+}
+
+/// Check that `signature`, base64, is `key`'s signature of `binary`
+pub fn verify(binary: &[u8], signature: &str, key: &VerifyingKey) -> Result<()> {
+    let signature: Signature = BASE64
+        .decode(signature.trim())
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(|| EmnsError::protocol("update signature is not base64 Ed25519"))?;
+    key.verify(binary, &signature)
+        .map_err(|_| EmnsError::protocol("update signature does not match the binary"))
+}
+
+/// Where the binary replaced by an update is kept, e.g. `emns-agent.exe.old`
+pub fn rollback_path(exe: &Path) -> PathBuf {
+    with_suffix(exe, ".old")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Put `staged` in place of `exe`, keeping the binary it replaces at
+/// [`rollback_path`]. Both renames work while `exe` is running; the new binary
+/// runs from the next start.
+pub fn swap(exe: &Path, staged: &Path) -> Result<()> {
+    let old: PathBuf = rollback_path(exe);
+    let storage = |e: std::io::Error| EmnsError::storage(Some(exe), e);
+    match std::fs::remove_file(&old) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(storage(e)),
+        _ => {}
+    }
+    std::fs::rename(exe, &old).map_err(storage)?;
+    if let Err(e) = std::fs::rename(staged, exe) {
+        // Leave the running binary where the service expects it
+        if let Err(restore) = std::fs::rename(&old, exe) {
+            log::error!("Could not put {} back: {}", exe.display(), restore);
+        }
+        return Err(storage(e));
+    }
+    Ok(())
+}
+
+/// Put the binary kept by the last [`swap`] back in place of `exe`; the
+/// rejected one is left beside it as `<exe>.rejected`
+pub fn rollback(exe: &Path) -> Result<()> {
+    let old: PathBuf = rollback_path(exe);
+    if !old.exists() {
+        return Err(EmnsError::storage(
+            Some(&old),
+            "no previous binary to roll back to",
+        ));
+    }
+    let rejected: PathBuf = with_suffix(exe, ".rejected");
+    let storage = |e: std::io::Error| EmnsError::storage(Some(exe), e);
+    match std::fs::remove_file(&rejected) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(storage(e)),
+        _ => {}
+    }
+    std::fs::rename(exe, &rejected).map_err(storage)?;
+    std::fs::rename(&old, exe).map_err(storage)
+}
+
+/// Finds, verifies and stages agent releases, and says when to restart into one
+pub struct Updater {
+    config: UpdateConfig,
+    /// The binary updates replace
+    exe: PathBuf,
+    staging_dir: PathBuf,
+    current: Version,
+    status: Mutex<UpdateStatus>,
+    /// The latest release the server offered, not yet looked at
+    offered: Mutex<Option<UpdateManifest>>,
+    offer_arrived: Notify,
+    restart: CancellationToken,
+    client: reqwest::Client,
+}
+
+impl Updater {
+    /// Updates for the running binary, staged under `data_dir`
+    pub fn new(config: UpdateConfig, data_dir: &Path, exe: impl Into<PathBuf>) -> Self {
+        Self {
+            config,
+            exe: exe.into(),
+            staging_dir: data_dir.join(UPDATES_DIR),
+            current: Version::parse(env!("CARGO_PKG_VERSION")).expect("crate version"),
+            status: Mutex::new(UpdateStatus {
+                current_version: env!("CARGO_PKG_VERSION").to_string(),
+                ..UpdateStatus::default()
+            }),
+            offered: Mutex::new(None),
+            offer_arrived: Notify::new(),
+            restart: CancellationToken::new(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Treat the running binary as `version` rather than this crate's
+    pub fn with_current_version(mut self, version: &str) -> Result<Self> {
+        self.current = Version::parse(version)?;
+        self.status.get_mut().unwrap().current_version = version.to_string();
+        Ok(self)
+    }
+
+    /// Current, staged, last check and last error, for status reports
+    pub fn status(&self) -> UpdateStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Cancelled when a staged update is due and the agent should exit with
+    /// [`RESTART_EXIT_CODE`]
+    pub fn restart_requested(&self) -> &CancellationToken {
+        &self.restart
+    }
+
+    /// Look at a release the server offered; the newest offer wins if several arrive at once
+    pub fn offer(&self, manifest: UpdateManifest) {
+        *self.offered.lock().unwrap() = Some(manifest);
+        self.offer_arrived.notify_one();
+    }
+
+    /// The version staged, or the running one when none is
+    fn installed(&self) -> Version {
+        self.status
+            .lock()
+            .unwrap()
+            .staged_version
+            .as_deref()
+            .and_then(|staged| Version::parse(staged).ok())
+            .unwrap_or_else(|| self.current.clone())
+    }
+
+    /// Download and install `manifest`'s release if it is newer than what is
+    /// running or staged; returns whether it was staged
+    pub async fn apply(&self, manifest: &UpdateManifest) -> Result<bool> {
+        if Version::parse(&manifest.version)? <= self.installed() {
+            log::debug!("Release {} is not newer; not updating", manifest.version);
+            return Ok(false);
+        }
+        let binary: Vec<u8> = self.download(&manifest.url).await?;
+        self.install(manifest, &binary)
+    }
+
+    /// Verify `binary` against `manifest`, stage it, and swap it in for the next start.
+    ///
+    /// Versions not newer than the one running or staged are refused, so a
+    /// replayed older manifest cannot roll the agent back.
+    pub fn install(&self, manifest: &UpdateManifest, binary: &[u8]) -> Result<bool> {
+        if Version::parse(&manifest.version)? <= self.installed() {
+            return Ok(false);
+        }
+        verify(binary, &manifest.signature, &self.config.public_key)?;
+
+        let file_name: String = match self.exe.extension() {
+            Some(extension) => format!(
+                "emns-agent-{}.{}",
+                manifest.version,
+                extension.to_string_lossy()
+            ),
+            None => format!("emns-agent-{}", manifest.version),
+        };
+        let staged: PathBuf = self.staging_dir.join(file_name);
+        let partial: PathBuf = with_suffix(&staged, ".partial");
+        std::fs::create_dir_all(&self.staging_dir)
+            .and_then(|()| std::fs::write(&partial, binary))
+            .and_then(|()| std::fs::rename(&partial, &staged))
+            .map_err(|e| EmnsError::storage(Some(&staged), e))?;
+        swap(&self.exe, &staged)?;
+
+        log::info!(
+            "Staged agent {}; it runs from the next start",
+            manifest.version
+        );
+        self.status.lock().unwrap().staged_version = Some(manifest.version.clone());
+        Ok(true)
+    }
+
+    /// Read the response body, stopping as soon as it passes the size limit
+    async fn download(&self, url: &str) -> Result<Vec<u8>> {
+        if !url.starts_with("https://") {
+            return Err(EmnsError::protocol(format!(
+                "update URL {} is not HTTPS",
+                url
+            )));
+        }
+        let mut response: reqwest::Response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| EmnsError::connection(url, e))?;
+        let mut body: Vec<u8> = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| EmnsError::connection(url, e))?
+        {
+            if (body.len() + chunk.len()) as u64 > self.config.max_bytes {
+                return Err(EmnsError::protocol(format!(
+                    "update binary is larger than {} bytes",
+                    self.config.max_bytes
+                )));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    async fn fetch_manifest(&self, url: &str) -> Result<UpdateManifest> {
+        self.client
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| EmnsError::connection(url, e))?
+            .json()
+            .await
+            .map_err(|e| EmnsError::protocol(format!("bad update manifest: {}", e)))
+    }
+
+    /// Apply `manifest`, or the one at the manifest URL, and record the outcome
+    async fn check(&self, manifest: Option<UpdateManifest>) {
+        let outcome: Result<bool> = async {
+            let manifest: UpdateManifest = match (manifest, &self.config.manifest_url) {
+                (Some(manifest), _) => manifest,
+                (None, Some(url)) => self.fetch_manifest(url).await?,
+                (None, None) => return Ok(false),
+            };
+            self.apply(&manifest).await
+        }
+        .await;
+        let mut status = self.status.lock().unwrap();
+        status.last_check_at = Some(chrono::Utc::now());
+        match outcome {
+            Ok(_) => status.last_error = None,
+            Err(e) => {
+                log::warn!("Self-update failed: {}", e);
+                status.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Whether the agent should now exit to run its staged update
+    fn restart_due(&self, now: NaiveTime, emergency_pending: bool) -> bool {
+        let staged: bool = self.status.lock().unwrap().staged_version.is_some();
+        staged
+            && !emergency_pending
+            && self
+                .config
+                .restart_window
+                .is_some_and(|window| window.contains(now))
+    }
+}
+
+/// Check the manifest URL every interval and take server offers as they come,
+/// until `cancel` fires.
+///
+/// With a restart window, a staged update is restarted into during the window
+/// once no Emergency alert is awaiting confirmation. Without `handler`, as in
+/// broker mode where the helpers hold the alerts, the agent never restarts itself.
+pub async fn run_updates(
+    updater: Arc<Updater>,
+    handler: Option<Arc<AlertHandler>>,
+    cancel: CancellationToken,
+) {
+    let mut next_check = tokio::time::Instant::now();
+    loop {
+        let offered: Option<UpdateManifest> = tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = updater.offer_arrived.notified() => updater.offered.lock().unwrap().take(),
+            _ = tokio::time::sleep_until(next_check) => {
+                next_check += updater.config.check_interval;
+                None
+            }
+            _ = tokio::time::sleep(RESTART_POLL_INTERVAL) => {
+                if let Some(handler) = &handler {
+                    let now: NaiveTime = chrono::Local::now().time();
+                    if updater.restart_due(now, handler.emergency_pending().await) {
+                        log::info!("Restarting into the staged agent update");
+                        updater.restart.cancel();
+                        break;
+                    }
+                }
+                continue;
+            }
+        };
+        updater.check(offered).await;
+    }
+    log::debug!("Self-update stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn manifest(version: &str, binary: &[u8]) -> UpdateManifest {
+        UpdateManifest {
+            version: version.to_string(),
+            url: format!("https://updates.test/emns-agent-{}.exe", version),
+            signature: BASE64.encode(signing_key().sign(binary).to_bytes()),
+        }
+    }
+
+    /// A data dir and a running "binary" in a fresh temporary directory
+    fn install_dir() -> (PathBuf, PathBuf) {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("emns-update-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let exe: PathBuf = dir.join("emns-agent.exe");
+        std::fs::write(&exe, b"agent 1.4.1").unwrap();
+        (dir, exe)
+    }
+
+    fn updater(dir: &Path, exe: &Path) -> Updater {
+        Updater::new(UpdateConfig::new(signing_key().verifying_key()), dir, exe)
+            .with_current_version("1.4.1")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_version_ordering() {
+        let v = |s: &str| Version::parse(s).unwrap();
+        assert!(v("1.4.2") > v("1.4.1"));
+        assert!(v("1.10.0") > v("1.9.9"));
+        assert!(v("2") > v("1.99.99"));
+        assert_eq!(v("v1.4"), v("1.4.0"));
+        assert!(Version::parse("1.4.2-beta").is_err());
+        assert!(Version::parse("").is_err());
+    }
+
+    #[test]
+    fn test_verify_rejects_tampered_binary() {
+        let key: VerifyingKey = signing_key().verifying_key();
+        let signed: UpdateManifest = manifest("1.4.2", b"agent 1.4.2");
+        assert!(verify(b"agent 1.4.2", &signed.signature, &key).is_ok());
+        assert!(verify(b"agent 1.4.2 with extras", &signed.signature, &key).is_err());
+        assert!(verify(b"agent 1.4.2", "not base64!", &key).is_err());
+        let other: VerifyingKey = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        assert!(verify(b"agent 1.4.2", &signed.signature, &other).is_err());
+    }
+
+    #[test]
+    fn test_install_swaps_and_keeps_old_binary() {
+        let (dir, exe) = install_dir();
+        let updater: Updater = updater(&dir, &exe);
+
+        // A bad signature leaves everything as it was
+        let mut forged: UpdateManifest = manifest("1.4.2", b"agent 1.4.2");
+        forged.signature = manifest("1.4.2", b"something else").signature;
+        assert!(updater.install(&forged, b"agent 1.4.2").is_err());
+        assert_eq!(std::fs::read(&exe).unwrap(), b"agent 1.4.1");
+        assert!(!rollback_path(&exe).exists());
+
+        let release: UpdateManifest = manifest("1.4.2", b"agent 1.4.2");
+        assert!(updater.install(&release, b"agent 1.4.2").unwrap());
+        assert_eq!(std::fs::read(&exe).unwrap(), b"agent 1.4.2");
+        assert_eq!(std::fs::read(rollback_path(&exe)).unwrap(), b"agent 1.4.1");
+        assert_eq!(updater.status().staged_version.as_deref(), Some("1.4.2"));
+
+        // Neither the running version nor an older one is installed again
+        assert!(!updater.install(&release, b"agent 1.4.2").unwrap());
+        let older: UpdateManifest = manifest("1.4.0", b"agent 1.4.0");
+        assert!(!updater.install(&older, b"agent 1.4.0").unwrap());
+        assert_eq!(std::fs::read(&exe).unwrap(), b"agent 1.4.2");
+
+        rollback(&exe).unwrap();
+        assert_eq!(std::fs::read(&exe).unwrap(), b"agent 1.4.1");
+        assert_eq!(
+            std::fs::read(dir.join("emns-agent.exe.rejected")).unwrap(),
+            b"agent 1.4.2"
+        );
+        assert!(rollback(&exe).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restart_only_in_window_without_emergency() {
+        let (dir, exe) = install_dir();
+        let mut config: UpdateConfig = UpdateConfig::new(signing_key().verifying_key());
+        config.restart_window = RestartWindow::parse("23:00-01:00");
+        let updater: Updater = Updater::new(config, &dir, &exe)
+            .with_current_version("1.4.1")
+            .unwrap();
+        let at = |t: &str| NaiveTime::parse_from_str(t, "%H:%M").unwrap();
+
+        // Nothing staged yet
+        assert!(!updater.restart_due(at("23:30"), false));
+        updater
+            .install(&manifest("1.4.2", b"agent 1.4.2"), b"agent 1.4.2")
+            .unwrap();
+        assert!(updater.restart_due(at("23:30"), false));
+        assert!(updater.restart_due(at("00:59"), false));
+        assert!(!updater.restart_due(at("01:00"), false));
+        assert!(!updater.restart_due(at("12:00"), false));
+        assert!(!updater.restart_due(at("23:30"), true));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restart_window_parse() {
+        assert_eq!(
+            RestartWindow::parse("02:00-04:30"),
+            Some(RestartWindow {
+                start: NaiveTime::from_hms_opt(2, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(4, 30, 0).unwrap(),
+            })
+        );
+        assert_eq!(RestartWindow::parse("02:00"), None);
+        assert_eq!(RestartWindow::parse("02:00-02:00"), None);
+        assert_eq!(RestartWindow::parse("25:00-02:00"), None);
+    }
+}
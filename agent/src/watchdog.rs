@@ -0,0 +1,252 @@
+//! Noticing when the alert pipeline stops making progress
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Default time without progress, while alerts wait, before the pipeline counts as stalled
+pub const DEFAULT_STALL_AFTER: Duration = Duration::from_secs(120);
+
+/// Event Log ID written when the pipeline stalls
+const STALL_EVENT_ID: u32 = 1001;
+
+/// When the alert pipeline counts as stalled and what is done about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogConfig {
+    pub stall_after: Duration,
+    /// Abandon the stalled alert loop and start a new one
+    pub restart: bool,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            stall_after: DEFAULT_STALL_AFTER,
+            restart: false,
+        }
+    }
+}
+
+impl WatchdogConfig {
+    /// How often the watchdog looks, and how often an idle loop reports in
+    pub fn check_interval(&self) -> Duration {
+        (self.stall_after / 4).max(Duration::from_millis(10))
+    }
+}
+
+/// A change in the pipeline's health found by [`PipelineWatchdog::check`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// No progress for this long while alerts waited
+    Stalled(Duration),
+    Recovered,
+}
+
+#[derive(Debug)]
+struct Progress {
+    last: Instant,
+    /// Sounds being started on the pipeline's path; their time is not held against it
+    playing: usize,
+    playing_since: Option<Instant>,
+    /// Playback time since `last`
+    excluded: Duration,
+}
+
+/// Progress of the alert processing loop, reported by the loop and judged by [`run`]
+#[derive(Debug)]
+pub struct PipelineWatchdog {
+    progress: Mutex<Progress>,
+    stalled: AtomicBool,
+    stalls: AtomicU64,
+}
+
+impl PipelineWatchdog {
+    pub fn new() -> Self {
+        Self {
+            progress: Mutex::new(Progress {
+                last: Instant::now(),
+                playing: 0,
+                playing_since: None,
+                excluded: Duration::ZERO,
+            }),
+            stalled: AtomicBool::new(false),
+            stalls: AtomicU64::new(0),
+        }
+    }
+
+    /// The loop finished an alert, or is idle and still able to take one
+    pub fn progress(&self) {
+        let now: Instant = Instant::now();
+        let mut progress = self.progress.lock().unwrap();
+        progress.last = now;
+        progress.excluded = Duration::ZERO;
+        if progress.playing > 0 {
+            progress.playing_since = Some(now);
+        }
+    }
+
+    /// Leave the time until the returned span is dropped out of the stall calculation
+    pub fn playback(&self) -> PlaybackSpan<'_> {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.playing == 0 {
+            progress.playing_since = Some(Instant::now());
+        }
+        progress.playing += 1;
+        PlaybackSpan(self)
+    }
+
+    fn end_playback(&self) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.playing -= 1;
+        if progress.playing == 0 {
+            if let Some(since) = progress.playing_since.take() {
+                progress.excluded += since.elapsed();
+            }
+        }
+    }
+
+    /// Time since the last progress, not counting playback
+    pub fn stalled_for(&self, now: Instant) -> Duration {
+        let progress = self.progress.lock().unwrap();
+        let playing: Duration = progress
+            .playing_since
+            .map_or(Duration::ZERO, |since| now.saturating_duration_since(since));
+        now.saturating_duration_since(progress.last)
+            .saturating_sub(progress.excluded + playing)
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        self.stalled.load(Ordering::Relaxed)
+    }
+
+    /// Times the pipeline has been found stalled since startup
+    pub fn stalls(&self) -> u64 {
+        self.stalls.load(Ordering::Relaxed)
+    }
+
+    /// Judge the pipeline with `queued` alerts waiting; returns a change in its health
+    pub fn check(&self, queued: usize, stall_after: Duration, now: Instant) -> Option<Transition> {
+        let idle_for: Duration = self.stalled_for(now);
+        let stalled: bool = queued > 0 && idle_for >= stall_after;
+        if stalled == self.stalled.swap(stalled, Ordering::Relaxed) {
+            return None;
+        }
+        if stalled {
+            self.stalls.fetch_add(1, Ordering::Relaxed);
+            Some(Transition::Stalled(idle_for))
+        } else {
+            Some(Transition::Recovered)
+        }
+    }
+}
+
+impl Default for PipelineWatchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Playback on the pipeline's path; see [`PipelineWatchdog::playback`]
+pub struct PlaybackSpan<'a>(&'a PipelineWatchdog);
+
+impl Drop for PlaybackSpan<'_> {
+    fn drop(&mut self) {
+        self.0.end_playback();
+    }
+}
+
+/// Check `watchdog` until `cancel` fires, calling `on_stall` each time the pipeline stalls.
+///
+/// `queued` reports how many alerts are waiting for the pipeline.
+pub async fn run(
+    watchdog: std::sync::Arc<PipelineWatchdog>,
+    config: WatchdogConfig,
+    queued: impl Fn() -> usize,
+    mut on_stall: impl FnMut(),
+    cancel: tokio_util::sync::CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = tokio::time::sleep(config.check_interval()) => {}
+        }
+        let waiting: usize = queued();
+        match watchdog.check(waiting, config.stall_after, Instant::now()) {
+            Some(Transition::Stalled(idle_for)) => {
+                let message: String = format!(
+                    "Alert pipeline has made no progress for {}s with {} alert(s) waiting{}",
+                    idle_for.as_secs(),
+                    waiting,
+                    if config.restart {
+                        "; restarting it"
+                    } else {
+                        ""
+                    }
+                );
+                log::error!("{}", message);
+                crate::eventlog::error(STALL_EVENT_ID, &message);
+                on_stall();
+            }
+            Some(Transition::Recovered) => log::info!("Alert pipeline is making progress again"),
+            None => {}
+        }
+    }
+    log::debug!("Pipeline watchdog stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STALL_AFTER: Duration = Duration::from_secs(60);
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stall_needs_waiting_alerts_and_clears_on_progress() {
+        let watchdog: PipelineWatchdog = PipelineWatchdog::new();
+        tokio::time::advance(Duration::from_secs(300)).await;
+        // A quiet pipeline with nothing to do is not stalled
+        assert_eq!(watchdog.check(0, STALL_AFTER, Instant::now()), None);
+
+        assert_eq!(
+            watchdog.check(3, STALL_AFTER, Instant::now()),
+            Some(Transition::Stalled(Duration::from_secs(300)))
+        );
+        assert!(watchdog.is_stalled());
+        assert_eq!(watchdog.check(3, STALL_AFTER, Instant::now()), None);
+
+        watchdog.progress();
+        assert_eq!(
+            watchdog.check(3, STALL_AFTER, Instant::now()),
+            Some(Transition::Recovered)
+        );
+        assert!(!watchdog.is_stalled());
+        assert_eq!(watchdog.stalls(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_playback_time_is_not_held_against_the_pipeline() {
+        let watchdog: PipelineWatchdog = PipelineWatchdog::new();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        {
+            let _playing: PlaybackSpan = watchdog.playback();
+            tokio::time::advance(Duration::from_secs(600)).await;
+            assert_eq!(
+                watchdog.stalled_for(Instant::now()),
+                Duration::from_secs(10)
+            );
+            assert_eq!(watchdog.check(5, STALL_AFTER, Instant::now()), None);
+        }
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert_eq!(
+            watchdog.stalled_for(Instant::now()),
+            Duration::from_secs(30)
+        );
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(matches!(
+            watchdog.check(5, STALL_AFTER, Instant::now()),
+            Some(Transition::Stalled(_))
+        ));
+    }
+}
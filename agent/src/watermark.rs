@@ -0,0 +1,220 @@
+//! The newest alert this machine has received from the server, sent at
+//! registration so the server can replay what was issued after it
+
+use crate::storage::write_private_file;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// File under the data directory the watermark is normally kept in
+pub const WATERMARK_FILE: &str = "last_seen_alert.json";
+
+/// Shed alerts held for a replay; past this the oldest is given up on
+const SHED_KEPT: usize = 100;
+
+/// How long a shed alert without an expiry holds the watermark back
+const SHED_HELD_FOR: TimeDelta = TimeDelta::hours(24);
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Record {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_seen_alert: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    shed: Vec<Shed>,
+}
+
+/// An alert received but shed from the queue before it was shown
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Shed {
+    alert_id: Uuid,
+    issued_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Shed {
+    /// Whether a server would still replay it at `now`
+    fn replayable(&self, now: DateTime<Utc>) -> bool {
+        let until: DateTime<Utc> = self.expires_at.unwrap_or(self.issued_at + SHED_HELD_FOR);
+        until > now
+    }
+}
+
+/// Timestamp of the newest alert received, kept in a file across restarts.
+///
+/// Alerts shed before they were shown hold it back to just before the
+/// oldest of them, so the server replays them at the next registration even
+/// after newer alerts have come in. A missing or unreadable file leaves it
+/// unset, and the server decides what to replay on its own.
+#[derive(Debug)]
+pub struct Watermark {
+    path: PathBuf,
+    record: Mutex<Record>,
+}
+
+impl Watermark {
+    /// Read the watermark the previous run left in `file`
+    pub fn load(file: impl AsRef<Path>) -> Self {
+        let path: PathBuf = file.as_ref().to_path_buf();
+        let record: Record = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice::<Record>(&data)
+                .map_err(|e| log::warn!("Ignoring unreadable {}: {}", path.display(), e))
+                .unwrap_or_default(),
+            Err(_) => Record::default(),
+        };
+        Self {
+            path,
+            record: Mutex::new(record),
+        }
+    }
+
+    /// Where the server should replay from: just before the oldest shed
+    /// alert it would still replay, otherwise the newest alert received
+    pub fn get(&self) -> Option<DateTime<Utc>> {
+        let now: DateTime<Utc> = Utc::now();
+        let record = self.record.lock().unwrap();
+        let oldest_shed: Option<DateTime<Utc>> = record
+            .shed
+            .iter()
+            .filter(|shed| shed.replayable(now))
+            .map(|shed| shed.issued_at - TimeDelta::milliseconds(1))
+            .min();
+        match (record.last_seen_alert, oldest_shed) {
+            (Some(latest), Some(shed)) => Some(latest.min(shed)),
+            (latest, shed) => latest.or(shed),
+        }
+    }
+
+    /// Note alert `alert_id`, issued at `at`, as queued, saving the watermark
+    /// if it moves forward or the alert had been shed.
+    ///
+    /// Failing to save only means the next run may be replayed alerts it has
+    /// seen, which the history ignores, so it is logged.
+    pub fn advance(&self, alert_id: Uuid, at: DateTime<Utc>) {
+        let mut record = self.record.lock().unwrap();
+        let shed_before: usize = record.shed.len();
+        record.shed.retain(|shed| shed.alert_id != alert_id);
+        let moved: bool = record.last_seen_alert.is_none_or(|latest| latest < at);
+        if moved {
+            record.last_seen_alert = Some(at);
+        }
+        if moved || record.shed.len() != shed_before {
+            self.save(&record);
+        }
+    }
+
+    /// Note that alert `alert_id`, issued at `at`, was shed before it was
+    /// shown, so the watermark stays before it until it is queued again
+    pub fn reopen(&self, alert_id: Uuid, at: DateTime<Utc>, expires_at: Option<DateTime<Utc>>) {
+        let now: DateTime<Utc> = Utc::now();
+        let mut record = self.record.lock().unwrap();
+        record
+            .shed
+            .retain(|shed| shed.alert_id != alert_id && shed.replayable(now));
+        record.shed.push(Shed {
+            alert_id,
+            issued_at: at,
+            expires_at,
+        });
+        if record.shed.len() > SHED_KEPT {
+            record.shed.sort_by_key(|shed| shed.issued_at);
+            let given_up: Shed = record.shed.remove(0);
+            log::warn!(
+                "More than {} shed alerts awaiting a replay; no longer asking for alert {}",
+                SHED_KEPT,
+                given_up.alert_id
+            );
+        }
+        self.save(&record);
+    }
+
+    fn save(&self, record: &Record) {
+        let tmp: PathBuf = self.path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(record)
+            .map_err(std::io::Error::other)
+            .and_then(|data| write_private_file(&tmp, &data))
+            .and_then(|()| std::fs::rename(&tmp, &self.path));
+        if let Err(e) = result {
+            log::error!(
+                "Failed to save the alert watermark to {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file() -> PathBuf {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("emns-watermark-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(WATERMARK_FILE)
+    }
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        use chrono::TimeZone;
+        Utc.with_ymd_and_hms(2024, 1, 15, hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_watermark_survives_restart_and_only_moves_forward() {
+        let file: PathBuf = temp_file();
+        let watermark: Watermark = Watermark::load(&file);
+        assert_eq!(watermark.get(), None);
+
+        watermark.advance(Uuid::new_v4(), at(10));
+        watermark.advance(Uuid::new_v4(), at(12));
+        // A replayed older alert does not move it back
+        watermark.advance(Uuid::new_v4(), at(11));
+        assert_eq!(watermark.get(), Some(at(12)));
+        assert_eq!(Watermark::load(&file).get(), Some(at(12)));
+    }
+
+    #[test]
+    fn test_corrupt_watermark_is_ignored_and_replaced() {
+        let file: PathBuf = temp_file();
+        std::fs::write(&file, b"{\"last_seen_alert\": 17").unwrap();
+        let watermark: Watermark = Watermark::load(&file);
+        assert_eq!(watermark.get(), None);
+
+        watermark.advance(Uuid::new_v4(), at(9));
+        assert_eq!(Watermark::load(&file).get(), Some(at(9)));
+    }
+
+    #[test]
+    fn test_shed_alert_holds_the_watermark_back_until_queued_again() {
+        let file: PathBuf = temp_file();
+        let watermark: Watermark = Watermark::load(&file);
+        let shed: Uuid = Uuid::new_v4();
+        let issued_at: DateTime<Utc> = Utc::now() - TimeDelta::minutes(5);
+        let just_before: DateTime<Utc> = issued_at - TimeDelta::milliseconds(1);
+
+        watermark.advance(shed, issued_at);
+        watermark.reopen(shed, issued_at, None);
+        // Newer alerts do not move it past the shed one, across a restart too
+        watermark.advance(Uuid::new_v4(), Utc::now());
+        assert_eq!(watermark.get(), Some(just_before));
+        assert_eq!(Watermark::load(&file).get(), Some(just_before));
+
+        let newest: DateTime<Utc> = Utc::now() + TimeDelta::seconds(1);
+        watermark.advance(Uuid::new_v4(), newest);
+        watermark.advance(shed, issued_at);
+        assert_eq!(watermark.get(), Some(newest));
+        assert_eq!(Watermark::load(&file).get(), Some(newest));
+    }
+
+    #[test]
+    fn test_expired_shed_alert_no_longer_holds_the_watermark_back() {
+        let file: PathBuf = temp_file();
+        let watermark: Watermark = Watermark::load(&file);
+        watermark.advance(Uuid::new_v4(), at(12));
+        watermark.reopen(Uuid::new_v4(), at(10), Some(at(11)));
+        assert_eq!(watermark.get(), Some(at(12)));
+    }
+}
@@ -0,0 +1,67 @@
+//! Exercises the library surface the way an integrator would
+
+mod common;
+
+use emns_agent::messages::{Alert, AlertLevel, Confirmation, ConfirmationMethod, Message};
+use emns_agent::sanitize::TextLimits;
+use emns_agent::{AlertHandler, OutboundMessage, OutboundQueue};
+use std::sync::Arc;
+
+fn alert(title: &str, requires_confirmation: bool) -> Alert {
+    Alert {
+        requires_confirmation,
+        sound_file: Some("missing.wav".to_string()),
+        ..common::alert(title, AlertLevel::Info)
+    }
+}
+
+#[tokio::test]
+async fn test_handler_records_sanitized_history() {
+    let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+    let handler: AlertHandler = AlertHandler::builder(outbound, "it-client")
+        .sounds_dir(std::env::temp_dir())
+        .text_limits(TextLimits {
+            max_title_chars: 10,
+            max_message_chars: 100,
+        })
+        .build();
+
+    let alert: Alert = alert(&"x".repeat(50), false);
+    let id: uuid::Uuid = alert.id;
+    handler.handle_alert(alert).await.unwrap();
+
+    let entry = handler
+        .history()
+        .get(id)
+        .expect("alert recorded in history");
+    assert_eq!(entry.original_title_len, 50);
+    assert_eq!(entry.title.chars().count(), 10);
+    assert_eq!(handler.pending_count().await, 0);
+}
+
+#[tokio::test]
+async fn test_confirm_sends_confirmation() {
+    let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+    let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "it-client")
+        .sounds_dir(std::env::temp_dir())
+        .build();
+
+    let alert: Alert = alert("Needs confirmation", true);
+    let id: uuid::Uuid = alert.id;
+    handler.handle_alert(alert).await.unwrap();
+    assert_eq!(handler.get_pending_alerts().await, vec![id]);
+
+    handler
+        .confirm_alert(id, ConfirmationMethod::ToastButton)
+        .await
+        .unwrap();
+    let OutboundMessage::Confirmation(confirmation) = outbound.try_next().unwrap() else {
+        panic!("expected a confirmation");
+    };
+    let confirmation: Confirmation = confirmation;
+    assert_eq!(confirmation.alert_id, id);
+    assert_eq!(confirmation.client_id, "it-client");
+
+    let json: String = serde_json::to_string(&Message::Confirmation { confirmation }).unwrap();
+    assert!(json.contains(r#""type":"confirmation""#));
+}
@@ -0,0 +1,151 @@
+//! The alert board lists pending and recent alerts and streams changes to kiosk pages
+
+mod common;
+
+use common::{SilentAudio, SilentNotifier};
+use emns_agent::board::AlertBoard;
+use emns_agent::http_api::HttpApiConfig;
+use emns_agent::messages::{Alert, AlertLevel, ConfirmationMethod};
+use emns_agent::transport::memory::{MemoryListener, MemoryTransport};
+use emns_agent::{Agent, Config};
+use std::sync::Arc;
+use std::time::Duration;
+
+const KIOSK_ORIGIN: &str = "http://kiosk.test";
+
+fn alert(level: AlertLevel, requires_confirmation: bool) -> Alert {
+    Alert {
+        requires_confirmation,
+        ..common::alert(&format!("{} alert", level.as_str()), level)
+    }
+}
+
+fn start_agent() -> (Agent, MemoryListener) {
+    let mut config: Config = Config::new("ws://server.test/ws", "it-client");
+    config.http_api = Some(HttpApiConfig {
+        board_origins: vec![KIOSK_ORIGIN.to_string()],
+        ..HttpApiConfig::new("127.0.0.1:0".parse().unwrap())
+    });
+    // Never accepted: the board works without a server
+    let (transport, listener) = MemoryTransport::new();
+    let mut agent: Agent = Agent::builder(config)
+        .notification_backend(Arc::new(SilentNotifier))
+        .audio_backend(Arc::new(SilentAudio))
+        .transport(Arc::new(transport))
+        .build();
+    agent.start().unwrap();
+    (agent, listener)
+}
+
+async fn handled(agent: &Agent, alert: &Alert) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while agent.handler().history().get(alert.id).is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("alert handled");
+}
+
+/// Reads `board` events off a server-sent event stream
+struct BoardEvents {
+    response: reqwest::Response,
+    buffer: String,
+}
+
+impl BoardEvents {
+    async fn next(&mut self) -> AlertBoard {
+        loop {
+            if let Some(end) = self.buffer.find("\n\n") {
+                let event: String = self.buffer.drain(..end + 2).collect();
+                if !event.lines().any(|line| line == "event: board") {
+                    continue;
+                }
+                let data: String = event
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("data: "))
+                    .collect();
+                return serde_json::from_str(&data).unwrap();
+            }
+            let chunk = tokio::time::timeout(Duration::from_secs(5), self.response.chunk())
+                .await
+                .expect("event sent")
+                .unwrap()
+                .expect("stream open");
+            self.buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_board_lists_pending_and_recent_alerts() {
+    let (agent, _listener) = start_agent();
+    let pending: Alert = alert(AlertLevel::Critical, true);
+    let info: Alert = alert(AlertLevel::Info, false);
+    agent.alert_queue().try_push(pending.clone()).unwrap();
+    agent.alert_queue().try_push(info.clone()).unwrap();
+    handled(&agent, &pending).await;
+    handled(&agent, &info).await;
+
+    let url: String = format!("http://{}/status/alerts", agent.http_addr().unwrap());
+    let http: reqwest::Client = reqwest::Client::new();
+    let response = http
+        .get(&url)
+        .header("origin", KIOSK_ORIGIN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        response.headers()["access-control-allow-origin"],
+        KIOSK_ORIGIN
+    );
+    let board: AlertBoard = response.json().await.unwrap();
+    assert_eq!(board.version, 1);
+    assert_eq!(board.pending.len(), 1);
+    assert_eq!(board.pending[0].alert_id, pending.id);
+    assert!(board.pending[0].auto_confirm_at.unwrap() > board.generated_at);
+    let recent: Vec<uuid::Uuid> = board.recent.iter().map(|r| r.alert_id).collect();
+    assert_eq!(recent, vec![info.id]);
+
+    // Other pages may not read it
+    let response = http
+        .get(&url)
+        .header("origin", "http://elsewhere.test")
+        .send()
+        .await
+        .unwrap();
+    assert!(response
+        .headers()
+        .get("access-control-allow-origin")
+        .is_none());
+
+    assert!(agent.shutdown(Duration::from_secs(5)).await);
+}
+
+#[tokio::test]
+async fn test_board_stream_follows_confirmations() {
+    let (agent, _listener) = start_agent();
+    let url: String = format!("http://{}/status/alerts/stream", agent.http_addr().unwrap());
+    let mut events: BoardEvents = BoardEvents {
+        response: reqwest::get(&url).await.unwrap(),
+        buffer: String::new(),
+    };
+    assert!(events.next().await.pending.is_empty());
+
+    let pending: Alert = alert(AlertLevel::Warning, true);
+    agent.alert_queue().try_push(pending.clone()).unwrap();
+    let board: AlertBoard = events.next().await;
+    assert_eq!(board.pending[0].alert_id, pending.id);
+
+    agent
+        .handler()
+        .confirm_alert(pending.id, ConfirmationMethod::ToastButton)
+        .await
+        .unwrap();
+    let board: AlertBoard = events.next().await;
+    assert!(board.pending.is_empty());
+    assert!(board.recent.is_empty());
+
+    // An open stream does not hold up shutdown
+    assert!(agent.shutdown(Duration::from_secs(5)).await);
+}
@@ -0,0 +1,143 @@
+//! Attachments download behind the toast and only verified ones can be opened
+
+mod common;
+
+use axum::routing::get;
+use common::{RecordingNotifier, SilentAudio};
+use emns_agent::attachments::{AttachmentConfig, AttachmentStore, DocumentLauncher};
+use emns_agent::messages::{Alert, AlertLevel, Attachment, AttachmentState, DeliveryStatus};
+use emns_agent::{AlertHandler, OutboundMessage, OutboundQueue};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+const DOCUMENT: &[u8] = b"%PDF-1.4 shelter in place";
+
+#[derive(Default)]
+struct RecordingLauncher {
+    opened: Mutex<Vec<PathBuf>>,
+}
+
+impl DocumentLauncher for RecordingLauncher {
+    fn open(&self, path: &Path) -> emns_agent::Result<()> {
+        self.opened.lock().unwrap().push(path.to_path_buf());
+        Ok(())
+    }
+}
+
+/// Serves `DOCUMENT`, but only once `release` has a permit
+async fn slow_fixture(release: Arc<Semaphore>) -> SocketAddr {
+    let app = axum::Router::new().route(
+        "/procedure.pdf",
+        get(move || async move {
+            let _permit = release.acquire().await.unwrap();
+            DOCUMENT
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    addr
+}
+
+fn alert_with(attachment: Attachment) -> Alert {
+    Alert {
+        message: "Follow the attached procedure".to_string(),
+        attachment: Some(attachment),
+        ..common::alert("Shelter in place", AlertLevel::Emergency)
+    }
+}
+
+/// The next delivery status other than a report of how an alert was delivered
+async fn next_delivery_status(outbound: &OutboundQueue) -> DeliveryStatus {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match outbound.next().await {
+                OutboundMessage::DeliveryStatus(status) if status.decision.is_none() => {
+                    return status
+                }
+                _ => {}
+            }
+        }
+    })
+    .await
+    .expect("delivery status reported")
+}
+
+#[tokio::test]
+async fn test_attachment_downloads_behind_the_toast() {
+    let release: Arc<Semaphore> = Arc::new(Semaphore::new(0));
+    let addr: SocketAddr = slow_fixture(release.clone()).await;
+    let data_dir: PathBuf =
+        std::env::temp_dir().join(format!("emns-attachments-{}", uuid::Uuid::new_v4()));
+
+    let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+    let notifier: Arc<RecordingNotifier> = Arc::new(RecordingNotifier::default());
+    let launcher: Arc<RecordingLauncher> = Arc::new(RecordingLauncher::default());
+    let handler: AlertHandler = AlertHandler::builder(outbound.clone(), "it-client")
+        .notification_backend(notifier.clone())
+        .audio_backend(Arc::new(SilentAudio))
+        .attachment_store(Arc::new(AttachmentStore::new(
+            &data_dir,
+            &AttachmentConfig::default(),
+        )))
+        .document_launcher(launcher.clone())
+        .build();
+
+    let good: Alert = alert_with(Attachment {
+        url: format!("http://{}/procedure.pdf", addr),
+        filename: "procedure.pdf".to_string(),
+        sha256: format!("{:x}", Sha256::digest(DOCUMENT)),
+        size: DOCUMENT.len() as u64,
+    });
+    let corrupt: Alert = alert_with(Attachment {
+        sha256: format!("{:x}", Sha256::digest(b"another file")),
+        ..good.attachment.clone().unwrap()
+    });
+
+    // The server is holding the download, yet both toasts are already up
+    handler.handle_alert(good.clone()).await.unwrap();
+    handler.handle_alert(corrupt.clone()).await.unwrap();
+    assert_eq!(notifier.shown.lock().unwrap().len(), 2);
+
+    // Opening before the download finishes is refused
+    handler.open_attachment(good.id).await.unwrap();
+    assert!(launcher.opened.lock().unwrap().is_empty());
+    assert_eq!(
+        notifier.shown.lock().unwrap()[2].title,
+        "Document unavailable"
+    );
+
+    release.add_permits(1);
+    let mut statuses: Vec<DeliveryStatus> = vec![
+        next_delivery_status(&outbound).await,
+        next_delivery_status(&outbound).await,
+    ];
+    statuses.sort_by_key(|s| s.alert_id != good.id);
+    assert_eq!(statuses[0].alert_id, good.id);
+    assert_eq!(statuses[0].attachment, Some(AttachmentState::Verified));
+    assert_eq!(statuses[1].alert_id, corrupt.id);
+    assert_eq!(statuses[1].attachment, Some(AttachmentState::Failed));
+    assert!(statuses[1]
+        .detail
+        .as_deref()
+        .is_some_and(|d| d.contains("checksum mismatch")));
+
+    handler.open_attachment(good.id).await.unwrap();
+    assert_eq!(
+        *launcher.opened.lock().unwrap(),
+        [data_dir
+            .join("attachments")
+            .join(good.id.to_string())
+            .join("procedure.pdf")]
+    );
+
+    handler.open_attachment(corrupt.id).await.unwrap();
+    assert_eq!(launcher.opened.lock().unwrap().len(), 1);
+    assert_eq!(notifier.shown.lock().unwrap().len(), 4);
+
+    std::fs::remove_dir_all(data_dir).unwrap();
+}
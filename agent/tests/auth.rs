@@ -0,0 +1,55 @@
+//! The auth token is offered to the server as a bearer token on the upgrade request
+
+use emns_agent::transport::{AuthToken, Transport, TungsteniteTransport};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio_tungstenite::tungstenite::handshake::server::{
+    Callback, ErrorResponse, Request, Response,
+};
+
+/// Reports the `Authorization` header of the upgrade request
+struct HeaderProbe(oneshot::Sender<Option<String>>);
+
+impl Callback for HeaderProbe {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        let header: Option<String> = request
+            .headers()
+            .get("authorization")
+            .map(|value| value.to_str().unwrap().to_string());
+        let _ = self.0.send(header);
+        Ok(response)
+    }
+}
+
+/// Accept one WebSocket connection, reporting the `Authorization` header it came with
+async fn start_server() -> (String, oneshot::Receiver<Option<String>>) {
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port: u16 = listener.local_addr().unwrap().port();
+    let (header_tx, header_rx) = oneshot::channel::<Option<String>>();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let _ws = tokio_tungstenite::accept_hdr_async(socket, HeaderProbe(header_tx))
+            .await
+            .unwrap();
+    });
+    (format!("ws://127.0.0.1:{}/ws", port), header_rx)
+}
+
+#[tokio::test]
+async fn test_token_is_sent_as_bearer() {
+    let (url, header) = start_server().await;
+    let token: AuthToken = AuthToken::new("s3cret").unwrap();
+    let transport: TungsteniteTransport =
+        TungsteniteTransport::default().with_auth_token(Some(&token));
+
+    transport.connect(&url).await.unwrap();
+    assert_eq!(header.await.unwrap().as_deref(), Some("Bearer s3cret"));
+}
+
+#[tokio::test]
+async fn test_no_token_sends_no_header() {
+    let (url, header) = start_server().await;
+
+    TungsteniteTransport::default().connect(&url).await.unwrap();
+    assert_eq!(header.await.unwrap(), None);
+}
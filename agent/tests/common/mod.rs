@@ -0,0 +1,127 @@
+//! Mock backends and fixtures shared by the integration tests
+
+// Each test binary uses only some of these
+#![allow(dead_code)]
+
+use emns_agent::messages::{Alert, AlertLevel, Message};
+use emns_agent::transport::memory::{MemoryListener, MemoryPeer};
+use emns_agent::{AudioBackend, NotificationBackend};
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Plays nothing
+pub struct SilentAudio;
+
+impl AudioBackend for SilentAudio {
+    fn play(&self, _sound_file: &str) {}
+}
+
+/// Shows nothing
+pub struct SilentNotifier;
+
+impl NotificationBackend for SilentNotifier {
+    fn show_notification(&self, _alert: &Alert) -> emns_agent::Result<()> {
+        Ok(())
+    }
+}
+
+/// Records the toasts shown, removed and marked acknowledged
+#[derive(Default)]
+pub struct RecordingNotifier {
+    pub shown: Mutex<Vec<Alert>>,
+    pub removed: Mutex<Vec<Uuid>>,
+    pub acknowledged: Mutex<Vec<(Uuid, Vec<String>)>>,
+}
+
+impl RecordingNotifier {
+    /// IDs of the alerts shown, in order
+    pub fn shown_ids(&self) -> Vec<Uuid> {
+        self.shown
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|alert| alert.id)
+            .collect()
+    }
+
+    /// Titles of the alerts shown, in order
+    pub fn shown_titles(&self) -> Vec<String> {
+        self.shown
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|alert| alert.title.clone())
+            .collect()
+    }
+
+    /// Wait until `count` toasts have been shown
+    pub async fn wait_for(&self, count: usize) {
+        wait_until(|| self.shown.lock().unwrap().len() >= count).await;
+    }
+}
+
+impl NotificationBackend for RecordingNotifier {
+    fn show_notification(&self, alert: &Alert) -> emns_agent::Result<()> {
+        self.shown.lock().unwrap().push(alert.clone());
+        Ok(())
+    }
+
+    fn show_acknowledged(
+        &self,
+        alert_id: Uuid,
+        acknowledged_by: &[String],
+    ) -> emns_agent::Result<bool> {
+        self.acknowledged
+            .lock()
+            .unwrap()
+            .push((alert_id, acknowledged_by.to_vec()));
+        Ok(true)
+    }
+
+    fn remove_notification(&self, alert_id: Uuid) -> emns_agent::Result<()> {
+        self.removed.lock().unwrap().push(alert_id);
+        Ok(())
+    }
+}
+
+/// A server alert shown without confirmation, with every optional field unset
+pub fn alert(title: &str, level: AlertLevel) -> Alert {
+    Alert {
+        id: Uuid::new_v4(),
+        title: title.to_string(),
+        message: "Integration test".to_string(),
+        level,
+        requires_confirmation: false,
+        sound_file: None,
+        timestamp: chrono::Utc::now(),
+        ..Default::default()
+    }
+}
+
+/// Accept the agent's next connection and acknowledge its registration;
+/// returns the connection with the registration it sent
+pub async fn accept(listener: &mut MemoryListener) -> (MemoryPeer, Message) {
+    let mut peer: MemoryPeer = tokio::time::timeout(Duration::from_secs(60), listener.accept())
+        .await
+        .expect("agent connected")
+        .unwrap();
+    match peer.recv().await {
+        Some(register @ Message::Register { .. }) => {
+            peer.ack_registration();
+            (peer, register)
+        }
+        other => panic!("expected register, got {:?}", other),
+    }
+}
+
+/// Wait until `check` passes
+pub async fn wait_until(mut check: impl FnMut() -> bool) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !check() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("condition reached");
+}
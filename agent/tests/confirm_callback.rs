@@ -0,0 +1,165 @@
+//! Confirm callbacks go only to allow-listed hosts and never hold up the confirmation
+
+mod common;
+
+use axum::http::StatusCode;
+use axum::routing::post;
+use axum::Json;
+use common::{SilentAudio, SilentNotifier};
+use emns_agent::callback::{CallbackBody, CallbackConfig, CallbackSender};
+use emns_agent::messages::{Alert, AlertLevel, CallbackState, ConfirmationMethod, DeliveryStatus};
+use emns_agent::{AlertHandler, OutboundMessage, OutboundQueue};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// Records every callback body; answers the first with 503 and the rest with 200,
+/// each only once `release` has a permit
+async fn capture_server(
+    captured: Arc<Mutex<Vec<CallbackBody>>>,
+    release: Arc<Semaphore>,
+) -> SocketAddr {
+    let app = axum::Router::new().route(
+        "/ack",
+        post(move |Json(body): Json<CallbackBody>| async move {
+            release.acquire().await.unwrap().forget();
+            let mut captured = captured.lock().unwrap();
+            captured.push(body);
+            if captured.len() == 1 {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::OK
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    addr
+}
+
+fn handler(outbound: Arc<OutboundQueue>) -> AlertHandler {
+    AlertHandler::builder(outbound, "it-client")
+        .notification_backend(Arc::new(SilentNotifier))
+        .audio_backend(Arc::new(SilentAudio))
+        .callback_sender(Arc::new(
+            // The capture server has no certificate
+            CallbackSender::new(&CallbackConfig {
+                allowed_domains: vec!["127.0.0.1".to_string()],
+                ..CallbackConfig::default()
+            })
+            .with_plain_http_loopback(),
+        ))
+        .build()
+}
+
+fn alert_with_callback(url: String) -> Alert {
+    Alert {
+        message: "Confirm you are at the assembly point".to_string(),
+        requires_confirmation: true,
+        confirm_callback_url: Some(url),
+        ..common::alert("Muster check", AlertLevel::Critical)
+    }
+}
+
+async fn next_message(outbound: &OutboundQueue, within: Duration) -> OutboundMessage {
+    tokio::time::timeout(within, outbound.next())
+        .await
+        .expect("message queued")
+}
+
+async fn callback_status(outbound: &OutboundQueue) -> DeliveryStatus {
+    match next_message(outbound, Duration::from_secs(10)).await {
+        OutboundMessage::DeliveryStatus(status) => status,
+        other => panic!("expected delivery status, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_callback_is_retried_without_delaying_the_confirmation() {
+    let captured: Arc<Mutex<Vec<CallbackBody>>> = Arc::default();
+    let release: Arc<Semaphore> = Arc::new(Semaphore::new(0));
+    let addr: SocketAddr = capture_server(captured.clone(), release.clone()).await;
+    let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+    let handler: AlertHandler = handler(outbound.clone());
+
+    let alert: Alert = alert_with_callback(format!("http://{}/ack", addr));
+    handler.handle_alert(alert.clone()).await.unwrap();
+    handler
+        .confirm_alert(alert.id, ConfirmationMethod::ToastButton)
+        .await
+        .unwrap();
+
+    // The callback is stuck at the server, yet the confirmation is already queued
+    match next_message(&outbound, Duration::from_secs(1)).await {
+        OutboundMessage::Confirmation(confirmation) => assert_eq!(confirmation.alert_id, alert.id),
+        other => panic!("expected confirmation, got {:?}", other),
+    }
+    // How the alert was delivered follows the confirmation, which jumps the queue
+    assert!(matches!(
+        next_message(&outbound, Duration::from_secs(1)).await,
+        OutboundMessage::DeliveryStatus(DeliveryStatus {
+            decision: Some(_),
+            locale: None,
+            ..
+        })
+    ));
+
+    release.add_permits(2);
+    let status: DeliveryStatus = callback_status(&outbound).await;
+    assert_eq!(status.alert_id, alert.id);
+    assert_eq!(status.callback, Some(CallbackState::Delivered));
+    assert_eq!(status.detail, None);
+
+    let captured: Vec<CallbackBody> = captured.lock().unwrap().clone();
+    assert_eq!(captured.len(), 2);
+    assert_eq!(captured[0], captured[1]);
+    assert_eq!(captured[0].alert_id, alert.id);
+    assert_eq!(captured[0].client_id, "it-client");
+    assert_eq!(
+        handler.history().get(alert.id).unwrap().callback,
+        Some(CallbackState::Delivered)
+    );
+}
+
+#[tokio::test]
+async fn test_callback_outside_allow_list_is_refused() {
+    let captured: Arc<Mutex<Vec<CallbackBody>>> = Arc::default();
+    let addr: SocketAddr = capture_server(captured.clone(), Arc::new(Semaphore::new(10))).await;
+    let outbound: Arc<OutboundQueue> = Arc::new(OutboundQueue::default());
+    let handler: AlertHandler = handler(outbound.clone());
+
+    // The same server, but by a name that is not allow-listed
+    let alert: Alert = alert_with_callback(format!("http://localhost:{}/ack", addr.port()));
+    handler.handle_alert(alert.clone()).await.unwrap();
+    handler
+        .confirm_alert(alert.id, ConfirmationMethod::ToastButton)
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        next_message(&outbound, Duration::from_secs(1)).await,
+        OutboundMessage::Confirmation(_)
+    ));
+    // How the alert was delivered follows the confirmation, which jumps the queue
+    assert!(matches!(
+        next_message(&outbound, Duration::from_secs(1)).await,
+        OutboundMessage::DeliveryStatus(DeliveryStatus {
+            decision: Some(_),
+            locale: None,
+            ..
+        })
+    ));
+    let status: DeliveryStatus = callback_status(&outbound).await;
+    assert_eq!(status.callback, Some(CallbackState::Failed));
+    assert!(status
+        .detail
+        .as_deref()
+        .is_some_and(|d| d.contains("CONFIRM_CALLBACK_DOMAINS")));
+    assert!(captured.lock().unwrap().is_empty());
+    assert_eq!(
+        handler.history().get(alert.id).unwrap().callback,
+        Some(CallbackState::Failed)
+    );
+}
@@ -0,0 +1,97 @@
+//! The agent connects to servers found in DNS and follows them when records change
+
+mod common;
+
+use common::{accept, SilentAudio, SilentNotifier};
+use emns_agent::discovery::{DnsResolver, ServerDiscovery, SrvRecord};
+use emns_agent::transport::memory::MemoryTransport;
+use emns_agent::transport::CloseCode;
+use emns_agent::{Agent, Config, EmnsError};
+use futures_util::future::BoxFuture;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Serves whatever SRV records the test last set; `None` makes lookups fail
+#[derive(Default)]
+struct MockResolver {
+    srv: Mutex<Option<Vec<SrvRecord>>>,
+    txt: Vec<String>,
+}
+
+impl MockResolver {
+    fn set(&self, records: Option<Vec<SrvRecord>>) {
+        *self.srv.lock().unwrap() = records;
+    }
+}
+
+impl DnsResolver for MockResolver {
+    fn srv<'a>(&'a self, name: &'a str) -> BoxFuture<'a, emns_agent::Result<Vec<SrvRecord>>> {
+        Box::pin(async move {
+            assert_eq!(name, "_emns._tcp.corp.example");
+            self.srv
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or_else(|| EmnsError::connection(name, "NXDOMAIN"))
+        })
+    }
+
+    fn txt<'a>(&'a self, _name: &'a str) -> BoxFuture<'a, emns_agent::Result<Vec<String>>> {
+        Box::pin(async move { Ok(self.txt.clone()) })
+    }
+}
+
+fn srv(priority: u16, target: &str) -> SrvRecord {
+    SrvRecord {
+        priority,
+        weight: 10,
+        port: 443,
+        target: target.to_string(),
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_discovered_servers_fail_over_and_follow_dns() {
+    let resolver: Arc<MockResolver> = Arc::new(MockResolver {
+        txt: vec!["path=/emns tls=1".to_string()],
+        ..MockResolver::default()
+    });
+    resolver.set(Some(vec![
+        srv(20, "backup.corp.example."),
+        srv(10, "primary.corp.example."),
+    ]));
+
+    let mut config: Config = Config::new("ws://unused.test/ws", "it-client");
+    config.server_discovery = ServerDiscovery::Dns {
+        domain: "corp.example".to_string(),
+        fallback_url: Some("ws://fallback.corp.example/ws".to_string()),
+    };
+    let (transport, mut listener) = MemoryTransport::new();
+    let transport: Arc<MemoryTransport> = Arc::new(transport);
+    let mut agent: Agent = Agent::builder(config)
+        .notification_backend(Arc::new(SilentNotifier))
+        .audio_backend(Arc::new(SilentAudio))
+        .transport(transport.clone())
+        .dns_resolver(resolver.clone())
+        .build();
+
+    // The primary is down, so the agent moves on to the backup
+    transport.refuse_next("primary down");
+    agent.start().unwrap();
+    let (peer, _) = accept(&mut listener).await;
+    assert_eq!(peer.url(), "wss://backup.corp.example:443/emns");
+
+    // A server move in DNS is picked up on the next reconnect
+    resolver.set(Some(vec![srv(10, "new.corp.example.")]));
+    peer.close(CloseCode::Away, "maintenance");
+    let (peer, _) = accept(&mut listener).await;
+    assert_eq!(peer.url(), "wss://new.corp.example:443/emns");
+
+    // Without DNS the configured URL is used
+    resolver.set(None);
+    peer.close(CloseCode::Away, "maintenance");
+    let (peer, _) = accept(&mut listener).await;
+    assert_eq!(peer.url(), "ws://fallback.corp.example/ws");
+
+    assert!(agent.shutdown(Duration::from_secs(5)).await);
+}
@@ -0,0 +1,644 @@
+//! Agents against the example test server
+
+mod common;
+#[allow(dead_code)]
+#[path = "../examples/test_server.rs"]
+mod test_server;
+
+use common::{SilentAudio, SilentNotifier};
+use emns_agent::backoff::BackoffConfig;
+use emns_agent::messages::{
+    Confirmation, ConfirmationReason, ConfirmationResponse, DeliveryOutcome, DeliveryStatus,
+    Message, OfflineRecord,
+};
+use emns_agent::multicast::SigningKey;
+use emns_agent::offline::{self, OfflineSpool};
+use emns_agent::storage::LineProtector;
+use emns_agent::{Agent, Config};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use test_server::{Options, ServerState};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// A test server on `addr`, with its REST API on a port of its own
+struct RunningServer {
+    api: SocketAddr,
+    shutdown: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+impl RunningServer {
+    async fn start(addr: SocketAddr, options: Options) -> Self {
+        let listener: TcpListener = TcpListener::bind(addr).await.unwrap();
+        let api_listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api: SocketAddr = api_listener.local_addr().unwrap();
+        let shutdown: CancellationToken = CancellationToken::new();
+        let task: JoinHandle<()> = tokio::spawn(test_server::serve(
+            listener,
+            api_listener,
+            ServerState::default(),
+            Arc::new(options),
+            shutdown.clone(),
+        ));
+        Self {
+            api,
+            shutdown,
+            task,
+        }
+    }
+
+    /// Stop as on Ctrl+C, once every agent has been told
+    async fn stop(self) {
+        self.shutdown.cancel();
+        self.task.await.unwrap();
+    }
+
+    async fn is_registered(&self, client_id: &str) -> bool {
+        reqwest::get(format!("http://{}/api/clients/{}", self.api, client_id))
+            .await
+            .is_ok_and(|response| response.status().is_success())
+    }
+
+    /// Wait up to `limit` for every one of `client_ids` to register
+    async fn wait_for_registered(&self, client_ids: &[&str], limit: Duration) {
+        tokio::time::timeout(limit, async {
+            for client_id in client_ids {
+                while !self.is_registered(client_id).await {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            }
+        })
+        .await
+        .expect("agents registered in time");
+    }
+
+    /// Ask for a preview on `client_id`, with `api_key` if given
+    async fn preview(&self, api_key: Option<&str>, client_id: &str) -> reqwest::Response {
+        self.preview_message(api_key, client_id, "Draft wording")
+            .await
+    }
+
+    /// Ask for a preview of `message` on `client_id`, with `api_key` if given
+    async fn preview_message(
+        &self,
+        api_key: Option<&str>,
+        client_id: &str,
+        message: &str,
+    ) -> reqwest::Response {
+        let mut request = reqwest::Client::new()
+            .post(format!("http://{}/api/alerts/preview", self.api))
+            .json(&serde_json::json!({
+                "client_id": client_id,
+                "title": "Shelter in place",
+                "message": message,
+                "level": "critical",
+            }));
+        if let Some(api_key) = api_key {
+            request = request.header("X-Api-Key", api_key);
+        }
+        request.send().await.unwrap()
+    }
+
+    /// Hand a signed offline bundle to the server, as `import-offline` does
+    async fn import(&self, bundle: Vec<u8>) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("http://{}/offline-imports", self.api))
+            .body(bundle)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    /// What the server shows of `client_id`'s connection
+    async fn client(&self, client_id: &str) -> serde_json::Value {
+        reqwest::get(format!("http://{}/api/clients/{}", self.api, client_id))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+
+    /// What the server has heard of `alert_id`'s delivery
+    async fn deliveries(&self, alert_id: Uuid) -> serde_json::Value {
+        reqwest::get(format!("http://{}/alerts/{}", self.api, alert_id))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+}
+
+/// A free local address for a server to listen on
+async fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// The API key `dispatch-key` owns `it-preview-1` and nothing else
+fn preview_options() -> Options {
+    Options {
+        api_keys: HashMap::from([("dispatch-key".to_string(), vec!["it-preview-1".to_string()])]),
+        ..Options::default()
+    }
+}
+
+/// Key the isolated site signs its bundles with
+const OFFLINE_KEY: &str = "site-offline-secret";
+
+/// A confirmation made at the isolated site
+fn offline_confirmation(alert_id: Uuid) -> Confirmation {
+    Confirmation {
+        alert_id,
+        client_id: "airgap-01".to_string(),
+        confirmed_at: chrono::Utc::now(),
+        hostname: "AIRGAP".to_string(),
+        username: "operator".to_string(),
+        operator_id: None,
+        reason: ConfirmationReason::User,
+        method: None,
+        user_idle_secs: None,
+        response_id: None,
+        response: ConfirmationResponse::Acknowledged,
+        received_via: Default::default(),
+        shown_at: None,
+        response_latency_ms: None,
+        is_preview: false,
+    }
+}
+
+/// The isolated site's report that `alert_id` was past its expiry on arrival
+fn offline_status(alert_id: Uuid) -> DeliveryStatus {
+    DeliveryStatus {
+        alert_id,
+        client_id: "airgap-01".to_string(),
+        reported_at: chrono::Utc::now(),
+        attachment: None,
+        sound: None,
+        outcome: Some(DeliveryOutcome::Expired),
+        annunciator: None,
+        callback: None,
+        detail: None,
+        decision: None,
+        locale: None,
+    }
+}
+
+/// Export what the agent in `data_dir` has spooled, as `export-offline` does
+fn export_bundle(data_dir: &Path, name: &str) -> Vec<u8> {
+    let out: PathBuf = data_dir.join(name);
+    offline::export(
+        data_dir,
+        "airgap-01",
+        "AIRGAP",
+        &SigningKey::new(OFFLINE_KEY),
+        &LineProtector::default(),
+        &out,
+    )
+    .unwrap();
+    std::fs::read(out).unwrap()
+}
+
+/// An agent retrying after a second, and after eight the time after that;
+/// it offers compression when `ws_compression` is set
+fn start_agent(addr: SocketAddr, client_id: &str, ws_compression: bool) -> Agent {
+    let mut config: Config = Config::new(format!("ws://{}/ws", addr), client_id);
+    config.ws_compression = ws_compression;
+    config.data_dir = std::env::temp_dir().join(format!("emns-example-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&config.data_dir).unwrap();
+    config
+        .settings
+        .set_reconnect_delay(Duration::from_secs(1))
+        .unwrap();
+    config.reconnect_backoff = BackoffConfig {
+        multiplier: 8.0,
+        ..BackoffConfig::default()
+    };
+    let mut agent: Agent = Agent::builder(config)
+        .notification_backend(Arc::new(SilentNotifier))
+        .audio_backend(Arc::new(SilentAudio))
+        .build();
+    agent.start().unwrap();
+    agent
+}
+
+#[tokio::test]
+async fn test_agents_reconnect_without_backoff_after_a_graceful_restart() {
+    let resume_after: Duration = Duration::from_secs(3);
+    let options = || Options {
+        resume_after,
+        ..Options::default()
+    };
+    let addr: SocketAddr = free_addr().await;
+    let server: RunningServer = RunningServer::start(addr, options()).await;
+    let agents: Vec<Agent> = ["it-client-1", "it-client-2"]
+        .into_iter()
+        .map(|client_id| start_agent(addr, client_id, false))
+        .collect();
+    server
+        .wait_for_registered(&["it-client-1", "it-client-2"], Duration::from_secs(10))
+        .await;
+
+    server.stop().await;
+    let stopped: Instant = Instant::now();
+    // Down for most of the window it announced
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let server: RunningServer = RunningServer::start(addr, options()).await;
+
+    // Unwarned, the agents would be refused after a second and then wait eight more
+    server
+        .wait_for_registered(&["it-client-1", "it-client-2"], Duration::from_secs(5))
+        .await;
+    assert!(
+        stopped.elapsed() < resume_after + Duration::from_secs(2),
+        "reconnected {:?} after the shutdown",
+        stopped.elapsed()
+    );
+
+    for agent in &agents {
+        std::fs::remove_dir_all(&agent.config().data_dir).unwrap();
+    }
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_preview_goes_only_to_a_client_the_api_key_owns() {
+    let addr: SocketAddr = free_addr().await;
+    let server: RunningServer = RunningServer::start(addr, preview_options()).await;
+    let agent: Agent = start_agent(addr, "it-preview-1", false);
+    server
+        .wait_for_registered(&["it-preview-1"], Duration::from_secs(10))
+        .await;
+
+    let status = |response: reqwest::Response| response.status().as_u16();
+    assert_eq!(status(server.preview(None, "it-preview-1").await), 401);
+    assert_eq!(
+        status(server.preview(Some("stolen-key"), "it-preview-1").await),
+        401
+    );
+    assert_eq!(
+        status(server.preview(Some("dispatch-key"), "it-preview-2").await),
+        403
+    );
+
+    // Reported inline with the delivery status the agent sends on taking it
+    let response: reqwest::Response = server.preview(Some("dispatch-key"), "it-preview-1").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["timed_out"], false);
+    assert_eq!(report["client_id"], "it-preview-1");
+    assert_eq!(report["status"]["client_id"], "it-preview-1");
+    assert_eq!(report["status"]["alert_id"], report["alert_id"]);
+
+    std::fs::remove_dir_all(&agent.config().data_dir).unwrap();
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_preview_report_times_out_without_a_delivery_status() {
+    let addr: SocketAddr = free_addr().await;
+    let options: Options = Options {
+        preview_timeout: Duration::from_secs(1),
+        ..preview_options()
+    };
+    let server: RunningServer = RunningServer::start(addr, options).await;
+
+    // Registers, then never reports on what it is sent
+    let (mut silent, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+        .await
+        .unwrap();
+    silent
+        .send(tungstenite::Message::Text(
+            serde_json::json!({"type": "register", "client_id": "it-preview-1", "hostname": "IT"})
+                .to_string(),
+        ))
+        .await
+        .unwrap();
+    server
+        .wait_for_registered(&["it-preview-1"], Duration::from_secs(10))
+        .await;
+
+    let asked: Instant = Instant::now();
+    let response: reqwest::Response = server.preview(Some("dispatch-key"), "it-preview-1").await;
+    assert!(asked.elapsed() >= Duration::from_secs(1));
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["timed_out"], true);
+    assert_eq!(report["status"], serde_json::Value::Null);
+
+    // What the client was sent, after its registration ack, was the preview alone
+    let mut received: Vec<Message> = Vec::new();
+    while let Ok(Some(Ok(tungstenite::Message::Text(text)))) =
+        tokio::time::timeout(Duration::from_millis(200), silent.next()).await
+    {
+        received.push(serde_json::from_str(&text).unwrap());
+    }
+    match received.as_slice() {
+        [Message::RegisterAck { .. }, Message::Alert { alert }] => {
+            assert!(alert.is_preview);
+            assert_eq!(serde_json::json!(alert.id), report["alert_id"]);
+        }
+        other => panic!(
+            "expected a registration ack and the preview, got {:?}",
+            other
+        ),
+    }
+
+    drop(silent);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_offline_bundle_round_trips_into_the_server_store() {
+    let server: RunningServer = RunningServer::start(
+        free_addr().await,
+        Options {
+            offline_key: Some(SigningKey::new(OFFLINE_KEY)),
+            ..Options::default()
+        },
+    )
+    .await;
+    let data_dir: PathBuf =
+        std::env::temp_dir().join(format!("emns-example-offline-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+    // Confirmed and reported while the site had no way to reach the server
+    let spool: OfflineSpool = OfflineSpool::open(&data_dir).unwrap();
+    spool
+        .append(OfflineRecord::Confirmation {
+            confirmation: offline_confirmation(first),
+        })
+        .unwrap();
+    spool
+        .append(OfflineRecord::DeliveryStatus {
+            status: offline_status(first),
+        })
+        .unwrap();
+    let first_bundle: Vec<u8> = export_bundle(&data_dir, "first.json");
+
+    let response: reqwest::Response = server.import(first_bundle.clone()).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let summary: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(summary["client_id"], "airgap-01");
+    assert_eq!(summary["confirmations"], 1);
+    assert_eq!(summary["statuses"], 1);
+    assert_eq!(summary["duplicates"], 0);
+
+    // Carried in again on another stick
+    assert_eq!(server.import(first_bundle).await.status().as_u16(), 422);
+
+    // A second confirmation of the first alert adds nothing to what is stored
+    spool
+        .append(OfflineRecord::Confirmation {
+            confirmation: offline_confirmation(first),
+        })
+        .unwrap();
+    spool
+        .append(OfflineRecord::Confirmation {
+            confirmation: offline_confirmation(second),
+        })
+        .unwrap();
+    let second_bundle: Vec<u8> = export_bundle(&data_dir, "second.json");
+    let tampered: String = String::from_utf8(second_bundle.clone())
+        .unwrap()
+        .replace("operator", "intruder");
+    assert_ne!(tampered.as_bytes(), second_bundle);
+    assert_eq!(
+        server.import(tampered.into_bytes()).await.status().as_u16(),
+        422
+    );
+
+    // Refusing the tampered copy does not use up its sequence
+    let response: reqwest::Response = server.import(second_bundle).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let summary: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(summary["confirmations"], 1);
+    assert_eq!(summary["duplicates"], 1);
+
+    let deliveries: serde_json::Value = server.deliveries(first).await;
+    let confirmations = deliveries["confirmations"].as_array().unwrap();
+    assert_eq!(confirmations.len(), 1);
+    assert_eq!(confirmations[0]["confirmation"]["client_id"], "airgap-01");
+    assert_eq!(confirmations[0]["offline_import"], true);
+    assert_eq!(deliveries["statuses"][0]["status"]["outcome"], "expired");
+    assert_eq!(deliveries["statuses"][0]["offline_import"], true);
+    assert_eq!(
+        server.deliveries(second).await["confirmations"][0]["offline_import"],
+        true
+    );
+
+    std::fs::remove_dir_all(data_dir).unwrap();
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_previews_arrive_with_and_without_compression() {
+    // Whether the server agrees to compression, and whether the agent offers it
+    for (server_compression, agent_compression) in
+        [(false, false), (true, true), (true, false), (false, true)]
+    {
+        let addr: SocketAddr = free_addr().await;
+        let options: Options = Options {
+            ws_compression: server_compression,
+            ..preview_options()
+        };
+        let server: RunningServer = RunningServer::start(addr, options).await;
+        let agent: Agent = start_agent(addr, "it-preview-1", agent_compression);
+        server
+            .wait_for_registered(&["it-preview-1"], Duration::from_secs(10))
+            .await;
+        assert_eq!(
+            server.client("it-preview-1").await["compressed"],
+            server_compression && agent_compression,
+            "server {}, agent {}",
+            server_compression,
+            agent_compression
+        );
+
+        // Long, like the messages compression is meant for
+        let message: String = "Evacuate by the north stairwell. ".repeat(500);
+        let response: reqwest::Response = server
+            .preview_message(Some("dispatch-key"), "it-preview-1", &message)
+            .await;
+        assert_eq!(response.status().as_u16(), 200);
+        let report: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(
+            report["timed_out"], false,
+            "server {}, agent {}",
+            server_compression, agent_compression
+        );
+        assert_eq!(report["status"]["alert_id"], report["alert_id"]);
+
+        std::fs::remove_dir_all(&agent.config().data_dir).unwrap();
+        server.stop().await;
+    }
+}
+
+#[tokio::test]
+async fn test_unconfirmed_alert_reaches_the_server_as_a_timeout() {
+    let addr: SocketAddr = free_addr().await;
+    let server: RunningServer = RunningServer::start(addr, preview_options()).await;
+    let agent: Agent = start_agent(addr, "it-preview-1", false);
+    server
+        .wait_for_registered(&["it-preview-1"], Duration::from_secs(10))
+        .await;
+
+    // Nobody is at the machine to confirm it within its second
+    let report: serde_json::Value = reqwest::Client::new()
+        .post(format!("http://{}/api/alerts/preview", server.api))
+        .header("X-Api-Key", "dispatch-key")
+        .json(&serde_json::json!({
+            "client_id": "it-preview-1",
+            "title": "Shelter in place",
+            "message": "Confirm you have seen this",
+            "level": "critical",
+            "requires_confirmation": true,
+            "confirm_timeout_secs": 1,
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let alert_id: Uuid = serde_json::from_value(report["alert_id"].clone()).unwrap();
+
+    let deliveries: serde_json::Value = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let deliveries: serde_json::Value = server.deliveries(alert_id).await;
+            if deliveries["timeouts"]
+                .as_array()
+                .is_some_and(|t| !t.is_empty())
+            {
+                return deliveries;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("the timeout reached the server");
+    assert_eq!(deliveries["timeouts"][0]["client_id"], "it-preview-1");
+    assert_eq!(deliveries["confirmations"], serde_json::json!([]));
+
+    std::fs::remove_dir_all(&agent.config().data_dir).unwrap();
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn test_delivery_report_counts_each_response_option() {
+    let addr: SocketAddr = free_addr().await;
+    let options: Options = Options {
+        preview_timeout: Duration::from_secs(1),
+        ..preview_options()
+    };
+    let server: RunningServer = RunningServer::start(addr, options).await;
+    let (mut agent, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+        .await
+        .unwrap();
+    agent
+        .send(tungstenite::Message::Text(
+            serde_json::json!({"type": "register", "client_id": "it-preview-1", "hostname": "IT"})
+                .to_string(),
+        ))
+        .await
+        .unwrap();
+    server
+        .wait_for_registered(&["it-preview-1"], Duration::from_secs(10))
+        .await;
+
+    let report: serde_json::Value = reqwest::Client::new()
+        .post(format!("http://{}/api/alerts/preview", server.api))
+        .header("X-Api-Key", "dispatch-key")
+        .json(&serde_json::json!({
+            "client_id": "it-preview-1",
+            "title": "Headcount",
+            "message": "Are you safe?",
+            "level": "emergency",
+            "requires_confirmation": true,
+            "response_options": [
+                {"id": "safe", "label": "I am safe"},
+                {"id": "need_help", "label": "Need assistance", "response": {"kind": "cannot_comply"}},
+                {"id": "elsewhere", "label": "Not on site", "response": {"kind": "not_applicable"}},
+            ],
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let alert_id: Uuid = serde_json::from_value(report["alert_id"].clone()).unwrap();
+
+    // Two safe, one in need of help, one who timed out, and nobody elsewhere
+    for (client_id, response_id) in [
+        ("ws-01", Some("safe")),
+        ("ws-02", Some("safe")),
+        ("ws-03", Some("need_help")),
+        ("ws-04", None),
+    ] {
+        let confirmation: Confirmation = Confirmation {
+            client_id: client_id.to_string(),
+            response_id: response_id.map(str::to_string),
+            reason: match response_id {
+                Some(_) => ConfirmationReason::User,
+                None => ConfirmationReason::TimedOut,
+            },
+            ..offline_confirmation(alert_id)
+        };
+        agent
+            .send(tungstenite::Message::Text(
+                serde_json::to_string(&Message::Confirmation { confirmation }).unwrap(),
+            ))
+            .await
+            .unwrap();
+    }
+
+    let deliveries: serde_json::Value = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let deliveries: serde_json::Value = server.deliveries(alert_id).await;
+            if deliveries["confirmations"].as_array().map(Vec::len) == Some(4) {
+                return deliveries;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("the confirmations reached the server");
+    let counts: Vec<(serde_json::Value, u64)> = deliveries["responses"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|count| {
+            (
+                count["response_id"].clone(),
+                count["count"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        counts,
+        vec![
+            (serde_json::json!("safe"), 2),
+            (serde_json::json!("need_help"), 1),
+            (serde_json::json!("elsewhere"), 0),
+            (serde_json::Value::Null, 1),
+        ]
+    );
+    assert_eq!(deliveries["responses"][3]["label"], "timed out / no option");
+
+    drop(agent);
+    server.stop().await;
+}
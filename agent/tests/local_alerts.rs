@@ -0,0 +1,180 @@
+//! Alerts posted to the local HTTP API go through the normal pipeline and upstream,
+//! and pending ones can be resolved through it
+
+mod common;
+
+use common::{RecordingNotifier, SilentAudio};
+use emns_agent::bulk::{BulkFilter, BulkSummary};
+use emns_agent::http_api::{HttpApiConfig, CALLER_HEADER, TOKEN_HEADER};
+use emns_agent::messages::{
+    Alert, AlertLevel, AlertOrigin, Confirmation, ConfirmationMethod, ConfirmationReason, Message,
+};
+use emns_agent::transport::memory::{MemoryPeer, MemoryTransport};
+use emns_agent::{Agent, Config};
+use std::sync::Arc;
+use std::time::Duration;
+
+const TOKEN: &str = "local-secret";
+
+fn door_alert() -> Alert {
+    Alert {
+        message: "Server room east door".to_string(),
+        ..common::alert("Door forced open", AlertLevel::Critical)
+    }
+}
+
+#[tokio::test]
+async fn test_local_alert_is_shown_and_forwarded() {
+    let mut config: Config = Config::new("ws://server.test/ws", "it-client");
+    config.http_api = Some(HttpApiConfig {
+        local_alert_token: Some(TOKEN.to_string()),
+        forward_local_alerts: true,
+        max_body_bytes: 4096,
+        ..HttpApiConfig::new("127.0.0.1:0".parse().unwrap())
+    });
+
+    let (transport, mut listener) = MemoryTransport::new();
+    let notifier: Arc<RecordingNotifier> = Arc::new(RecordingNotifier::default());
+    let mut agent: Agent = Agent::builder(config)
+        .notification_backend(notifier.clone())
+        .audio_backend(Arc::new(SilentAudio))
+        .transport(Arc::new(transport))
+        .build();
+    agent.start().unwrap();
+    let mut peer: MemoryPeer = listener.accept().await.unwrap();
+    peer.ack_registration();
+
+    let url: String = format!("http://{}/local/alerts", agent.http_addr().unwrap());
+    let http: reqwest::Client = reqwest::Client::new();
+    let alert: Alert = door_alert();
+
+    // Missing token and oversized bodies are rejected
+    let response = http.post(&url).json(&alert).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    let mut huge: Alert = door_alert();
+    huge.message = "x".repeat(8192);
+    let response = http
+        .post(&url)
+        .header(TOKEN_HEADER, TOKEN)
+        .json(&huge)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 413);
+
+    let response = http
+        .post(&url)
+        .header(TOKEN_HEADER, TOKEN)
+        .json(&alert)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 202);
+
+    // The server receives a copy marked as local
+    let forwarded: Alert = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(Message::LocalAlert { client_id, alert }) = peer.recv().await {
+                assert_eq!(client_id, "it-client");
+                return alert;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(forwarded.id, alert.id);
+    assert_eq!(forwarded.origin, AlertOrigin::Local);
+
+    // And the toast backend shows it
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while notifier.shown.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let shown: Vec<Alert> = notifier.shown.lock().unwrap().clone();
+    assert_eq!(shown.len(), 1);
+    assert_eq!(shown[0].id, alert.id);
+    assert_eq!(shown[0].origin, AlertOrigin::Local);
+
+    assert!(agent.shutdown(Duration::from_secs(5)).await);
+}
+
+#[tokio::test]
+async fn test_pending_alerts_dismissed_in_bulk() {
+    let mut config: Config = Config::new("ws://server.test/ws", "it-client");
+    config.http_api = Some(HttpApiConfig {
+        local_alert_token: Some(TOKEN.to_string()),
+        forward_local_alerts: false,
+        ..HttpApiConfig::new("127.0.0.1:0".parse().unwrap())
+    });
+    let (transport, mut listener) = MemoryTransport::new();
+    let notifier: Arc<RecordingNotifier> = Arc::new(RecordingNotifier::default());
+    let mut agent: Agent = Agent::builder(config)
+        .notification_backend(notifier.clone())
+        .audio_backend(Arc::new(SilentAudio))
+        .transport(Arc::new(transport))
+        .build();
+    agent.start().unwrap();
+    let mut peer: MemoryPeer = listener.accept().await.unwrap();
+    peer.ack_registration();
+
+    let door: Alert = Alert {
+        requires_confirmation: true,
+        ..door_alert()
+    };
+    let evacuate: Alert = Alert {
+        level: AlertLevel::Emergency,
+        requires_confirmation: true,
+        ..door_alert()
+    };
+    agent.alert_queue().try_push(door.clone()).unwrap();
+    agent.alert_queue().try_push(evacuate.clone()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while notifier.shown.lock().unwrap().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let url: String = format!("http://{}/pending/dismiss", agent.http_addr().unwrap());
+    let http: reqwest::Client = reqwest::Client::new();
+    let response = http
+        .post(&url)
+        .json(&BulkFilter::all())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    let summary: BulkSummary = http
+        .post(&url)
+        .header(TOKEN_HEADER, TOKEN)
+        .header(CALLER_HEADER, "cli")
+        .json(&BulkFilter::all())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(summary.resolved, vec![door.id]);
+    assert_eq!(summary.skipped, 1);
+
+    let confirmation: Confirmation = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(Message::Confirmation { confirmation }) = peer.recv().await {
+                return confirmation;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(confirmation.alert_id, door.id);
+    assert_eq!(confirmation.reason, ConfirmationReason::Dismissed);
+    assert_eq!(confirmation.method, Some(ConfirmationMethod::Cli));
+    assert!(agent.handler().is_pending(evacuate.id).await);
+
+    assert!(agent.shutdown(Duration::from_secs(5)).await);
+}
@@ -0,0 +1,104 @@
+//! The agent tells the server when it last heard of an alert, across
+//! restarts, so the server can replay the ones it missed
+
+mod common;
+
+use chrono::{DateTime, TimeZone, Utc};
+use common::{accept, wait_until, RecordingNotifier, SilentAudio};
+use emns_agent::messages::{Alert, AlertBatch, AlertLevel, Message};
+use emns_agent::transport::memory::{MemoryListener, MemoryTransport};
+use emns_agent::watermark::WATERMARK_FILE;
+use emns_agent::{Agent, Config};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn server_alert(timestamp: DateTime<Utc>, requires_confirmation: bool) -> Alert {
+    Alert {
+        message: format!(
+            "Building 3 network is down as of {}",
+            timestamp.format("%H:%M")
+        ),
+        requires_confirmation,
+        timestamp,
+        ..common::alert("Network outage", AlertLevel::Warning)
+    }
+}
+
+fn start(data_dir: &Path, notifier: Arc<RecordingNotifier>) -> (Agent, MemoryListener) {
+    let mut config: Config = Config::new("ws://server.test/ws", "it-client");
+    config.data_dir = data_dir.to_path_buf();
+    config.watermark_file = Some(data_dir.join(WATERMARK_FILE));
+    let (transport, listener) = MemoryTransport::new();
+    let mut agent: Agent = Agent::builder(config)
+        .notification_backend(notifier)
+        .audio_backend(Arc::new(SilentAudio))
+        .transport(Arc::new(transport))
+        .build();
+    agent.start().unwrap();
+    (agent, listener)
+}
+
+/// The `since` the agent registered with
+fn since(register: &Message) -> Option<DateTime<Utc>> {
+    match register {
+        Message::Register { since, .. } => *since,
+        other => panic!("expected register, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_registration_reports_the_newest_alert_across_restarts() {
+    let data_dir: PathBuf =
+        std::env::temp_dir().join(format!("emns-replay-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let issued: DateTime<Utc> = Utc::now() - chrono::TimeDelta::hours(1);
+    let issued: DateTime<Utc> = Utc.timestamp_opt(issued.timestamp(), 0).unwrap();
+
+    // Nothing received before the first run
+    let notifier: Arc<RecordingNotifier> = Arc::new(RecordingNotifier::default());
+    let (first, mut listener) = start(&data_dir, notifier.clone());
+    let (peer, register) = accept(&mut listener).await;
+    assert_eq!(since(&register), None);
+    let older: Alert = server_alert(issued - chrono::TimeDelta::minutes(5), false);
+    let newest: Alert = server_alert(issued, false);
+    for alert in [&newest, &older] {
+        peer.send(&Message::Alert {
+            alert: alert.clone(),
+        });
+    }
+    wait_until(|| notifier.shown.lock().unwrap().len() == 2).await;
+    assert!(first.shutdown(Duration::from_secs(5)).await);
+
+    // The next run asks for what came after the newest, whatever the order received
+    let notifier: Arc<RecordingNotifier> = Arc::new(RecordingNotifier::default());
+    let (second, mut listener) = start(&data_dir, notifier.clone());
+    let (peer, register) = accept(&mut listener).await;
+    assert_eq!(since(&register), Some(issued));
+
+    // The replay goes through the usual handling for missed alerts
+    let mut pending: Alert = server_alert(issued + chrono::TimeDelta::minutes(10), true);
+    pending.missed = true;
+    let mut digested: Alert = server_alert(issued + chrono::TimeDelta::minutes(20), false);
+    digested.missed = true;
+    peer.send(&Message::AlertBatch {
+        batch: AlertBatch {
+            alerts: vec![digested.clone(), pending.clone()],
+            unreadable: Vec::new(),
+        },
+    });
+    let handler = second.handler().clone();
+    wait_until(|| notifier.shown_ids().contains(&pending.id)).await;
+    assert!(handler.is_pending(pending.id).await);
+    assert!(!notifier.shown_ids().contains(&digested.id));
+    assert!(second.shutdown(Duration::from_secs(5)).await);
+
+    let (third, mut listener) = start(&data_dir, Arc::default());
+    assert_eq!(
+        since(&accept(&mut listener).await.1),
+        Some(digested.timestamp)
+    );
+    assert!(third.shutdown(Duration::from_secs(5)).await);
+
+    std::fs::remove_dir_all(data_dir).unwrap();
+}
@@ -0,0 +1,95 @@
+//! An alert arriving over both the server connection and multicast is shown once
+
+mod common;
+
+use common::{RecordingNotifier, SilentAudio};
+use emns_agent::messages::{Alert, AlertLevel, ConfirmationMethod, Message, ReceivedVia};
+use emns_agent::multicast::{MulticastConfig, MulticastSender, SigningKey};
+use emns_agent::transport::memory::{MemoryPeer, MemoryTransport};
+use emns_agent::{Agent, Config};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn evacuation_alert() -> Alert {
+    Alert {
+        message: "Leave by the nearest exit".to_string(),
+        requires_confirmation: true,
+        ..common::alert("Evacuate", AlertLevel::Emergency)
+    }
+}
+
+#[tokio::test]
+async fn test_alert_on_both_paths_shows_one_toast() {
+    let multicast: MulticastConfig = MulticastConfig {
+        group: Ipv4Addr::new(239, 255, 40, 17),
+        port: 0,
+        interface: Ipv4Addr::LOCALHOST,
+        key: SigningKey::new("site-secret"),
+    };
+    let mut config: Config = Config::new("ws://server.test/ws", "it-client");
+    config.multicast = Some(multicast.clone());
+
+    let (transport, mut listener) = MemoryTransport::new();
+    let notifier: Arc<RecordingNotifier> = Arc::new(RecordingNotifier::default());
+    let mut agent: Agent = Agent::builder(config)
+        .notification_backend(notifier.clone())
+        .audio_backend(Arc::new(SilentAudio))
+        .transport(Arc::new(transport))
+        .build();
+    agent.start().unwrap();
+    let mut peer: MemoryPeer = listener.accept().await.unwrap();
+    peer.ack_registration();
+
+    // Multicast gets there first, then the server delivers the same alert
+    let alert: Alert = evacuation_alert();
+    let sender: MulticastSender = MulticastSender::new(&MulticastConfig {
+        port: agent.multicast_addr().unwrap().port(),
+        ..multicast
+    })
+    .unwrap();
+    sender.send(&alert).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while notifier.shown.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("multicast alert shown");
+    peer.send(&Message::Alert {
+        alert: alert.clone(),
+    });
+
+    // Wait for the server's copy to be processed before checking
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while agent.status().alert_queue_depth > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let shown: Vec<Alert> = notifier.shown.lock().unwrap().clone();
+    assert_eq!(shown.len(), 1);
+    assert_eq!(shown[0].id, alert.id);
+
+    // The confirmation goes back over the server connection, flagged
+    agent
+        .handler()
+        .confirm_alert(alert.id, ConfirmationMethod::ToastButton)
+        .await
+        .unwrap();
+    let confirmation = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(Message::Confirmation { confirmation }) = peer.recv().await {
+                return confirmation;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(confirmation.alert_id, alert.id);
+    assert_eq!(confirmation.received_via, ReceivedVia::Multicast);
+
+    assert!(agent.shutdown(Duration::from_secs(5)).await);
+}
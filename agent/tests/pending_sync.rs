@@ -0,0 +1,90 @@
+//! An alert cancelled while the agent was disconnected is withdrawn when it reconnects
+
+mod common;
+
+use common::{wait_until, RecordingNotifier, SilentAudio};
+use emns_agent::messages::{Alert, AlertLevel, Message};
+use emns_agent::transport::memory::{MemoryListener, MemoryPeer, MemoryTransport};
+use emns_agent::{Agent, Config};
+use std::sync::Arc;
+use std::time::Duration;
+
+const URL: &str = "ws://server.test/ws";
+
+fn server_alert() -> Alert {
+    Alert {
+        message: "Move away from windows".to_string(),
+        requires_confirmation: true,
+        ..common::alert("Shelter in place", AlertLevel::Emergency)
+    }
+}
+
+/// Accept the next connection and return it with the pending alerts it reported
+async fn accept(listener: &mut MemoryListener) -> (MemoryPeer, Vec<uuid::Uuid>) {
+    let (mut peer, _) = common::accept(listener).await;
+    match peer.recv().await {
+        Some(Message::PendingSync { pending_alert_ids }) => (peer, pending_alert_ids),
+        other => panic!("expected pending sync, got {:?}", other),
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_alert_cancelled_while_disconnected_is_withdrawn() {
+    let (transport, mut listener) = MemoryTransport::new();
+    let notifier: Arc<RecordingNotifier> = Arc::new(RecordingNotifier::default());
+    let mut agent: Agent = Agent::builder(Config::new(URL, "it-client"))
+        .notification_backend(notifier.clone())
+        .audio_backend(Arc::new(SilentAudio))
+        .transport(Arc::new(transport))
+        .build();
+    agent.start().unwrap();
+
+    // Nothing is pending on the first connection
+    let (peer, pending) = accept(&mut listener).await;
+    assert!(pending.is_empty());
+    let cancelled: Alert = server_alert();
+    let kept: Alert = Alert {
+        message: "Stay in the interior corridor".to_string(),
+        ..server_alert()
+    };
+    peer.send(&Message::Alert {
+        alert: cancelled.clone(),
+    });
+    peer.send(&Message::Alert {
+        alert: kept.clone(),
+    });
+    wait_until(|| notifier.shown.lock().unwrap().len() == 2).await;
+
+    // The connection drops, and the server cancels one alert while the agent is away
+    peer.fail("connection reset");
+    let (mut peer, mut pending) = accept(&mut listener).await;
+    pending.sort();
+    let mut expected: Vec<uuid::Uuid> = vec![cancelled.id, kept.id];
+    expected.sort();
+    assert_eq!(pending, expected);
+    peer.send(&Message::PendingSyncResult {
+        still_active: vec![kept.id],
+        cancelled: vec![cancelled.id],
+        expired: vec![],
+    });
+
+    let handler = agent.handler().clone();
+    wait_until(|| notifier.removed.lock().unwrap().len() == 1).await;
+    assert_eq!(*notifier.removed.lock().unwrap(), vec![cancelled.id]);
+    assert!(!handler.is_pending(cancelled.id).await);
+    assert!(handler.is_pending(kept.id).await);
+
+    // Past the auto-confirm timeout only the alert still active is confirmed
+    let mut confirmed: Vec<uuid::Uuid> = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(900), async {
+        while let Some(message) = peer.recv().await {
+            if let Message::Confirmation { confirmation } = message {
+                confirmed.push(confirmation.alert_id);
+            }
+        }
+    })
+    .await;
+    assert_eq!(confirmed, vec![kept.id]);
+
+    assert!(agent.shutdown(Duration::from_secs(5)).await);
+}
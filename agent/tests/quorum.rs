@@ -0,0 +1,72 @@
+//! A team alert met by others' acknowledgments stays up, softened, for this machine's user
+
+mod common;
+
+use common::{wait_until, RecordingNotifier, SilentAudio};
+use emns_agent::messages::{Alert, AlertLevel, ConfirmationMethod, Message};
+use emns_agent::transport::memory::{MemoryListener, MemoryPeer, MemoryTransport};
+use emns_agent::{Agent, Config};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn team_alert() -> Alert {
+    Alert {
+        message: "Server room temperature above threshold".to_string(),
+        requires_confirmation: true,
+        quorum: Some(2),
+        ..common::alert("Two-person response", AlertLevel::Critical)
+    }
+}
+
+#[tokio::test]
+async fn test_quorum_met_leaves_alert_confirmable() {
+    let (transport, mut listener): (MemoryTransport, MemoryListener) = MemoryTransport::new();
+    let notifier: Arc<RecordingNotifier> = Arc::new(RecordingNotifier::default());
+    let mut agent: Agent = Agent::builder(Config::new("ws://server.test/ws", "it-client"))
+        .notification_backend(notifier.clone())
+        .audio_backend(Arc::new(SilentAudio))
+        .transport(Arc::new(transport))
+        .build();
+    agent.start().unwrap();
+    let mut peer: MemoryPeer = listener.accept().await.expect("agent connected");
+    peer.ack_registration();
+
+    let alert: Alert = team_alert();
+    peer.send(&Message::Alert {
+        alert: alert.clone(),
+    });
+    wait_until(|| notifier.shown_ids().contains(&alert.id)).await;
+
+    let confirmed_by: Vec<String> = vec!["J. Smith".to_string(), "B-10442".to_string()];
+    peer.send(&Message::QuorumMet {
+        alert_id: alert.id,
+        confirmed_by: confirmed_by.clone(),
+    });
+    wait_until(|| !notifier.acknowledged.lock().unwrap().is_empty()).await;
+    assert_eq!(
+        *notifier.acknowledged.lock().unwrap(),
+        vec![(alert.id, confirmed_by)]
+    );
+    assert!(agent.handler().is_pending(alert.id).await);
+
+    // The user here can still confirm it, and the server hears of it
+    agent
+        .handler()
+        .confirm_alert(alert.id, ConfirmationMethod::ToastButton)
+        .await
+        .unwrap();
+    let confirmation = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match peer.recv().await {
+                Some(Message::Confirmation { confirmation }) => return confirmation,
+                Some(_) => continue,
+                None => panic!("connection closed"),
+            }
+        }
+    })
+    .await
+    .expect("confirmation sent");
+    assert_eq!(confirmation.alert_id, alert.id);
+
+    assert!(agent.shutdown(Duration::from_secs(5)).await);
+}
@@ -0,0 +1,111 @@
+//! Reminders go to the user's webhook once per unconfirmed urgent alert, and never after it is confirmed
+
+mod common;
+
+use axum::http::StatusCode;
+use axum::routing::post;
+use axum::Json;
+use common::{SilentAudio, SilentNotifier};
+use emns_agent::messages::{Alert, AlertLevel, ConfirmationMethod};
+use emns_agent::reminder::{ReminderBody, ReminderConfig};
+use emns_agent::{AlertHandler, OutboundQueue};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const REMIND_AFTER: Duration = Duration::from_millis(300);
+
+/// Records every reminder body and answers 200
+async fn capture_server(captured: Arc<Mutex<Vec<ReminderBody>>>) -> SocketAddr {
+    let app = axum::Router::new().route(
+        "/remind",
+        post(move |Json(body): Json<ReminderBody>| async move {
+            captured.lock().unwrap().push(body);
+            StatusCode::OK
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    addr
+}
+
+fn handler(addr: SocketAddr, include_body: bool) -> AlertHandler {
+    AlertHandler::builder(Arc::new(OutboundQueue::default()), "it-client")
+        .notification_backend(Arc::new(SilentNotifier))
+        .audio_backend(Arc::new(SilentAudio))
+        .reminders(Some(ReminderConfig {
+            after: REMIND_AFTER,
+            include_body,
+            ..ReminderConfig::new(format!("http://{}/remind", addr))
+        }))
+        .build()
+}
+
+fn alert(title: &str, level: AlertLevel) -> Alert {
+    Alert {
+        message: "Gas leak reported on level 2; evacuate via the east stairs".to_string(),
+        requires_confirmation: true,
+        ..common::alert(title, level)
+    }
+}
+
+#[tokio::test]
+async fn test_one_reminder_per_unconfirmed_alert() {
+    let captured: Arc<Mutex<Vec<ReminderBody>>> = Arc::default();
+    let addr: SocketAddr = capture_server(captured.clone()).await;
+    let handler: AlertHandler = handler(addr, false);
+
+    let unanswered: Alert = alert(
+        &format!("Evacuate {}", "building ".repeat(20)),
+        AlertLevel::Critical,
+    );
+    let confirmed: Alert = alert("Shelter in place", AlertLevel::Critical);
+    let routine: Alert = alert("Patch window tonight", AlertLevel::Warning);
+    for alert in [&unanswered, &confirmed, &routine] {
+        handler.handle_alert(alert.clone()).await.unwrap();
+    }
+    handler
+        .confirm_alert(confirmed.id, ConfirmationMethod::ToastButton)
+        .await
+        .unwrap();
+
+    // Well past the reminder, so a second one would have had time to arrive
+    tokio::time::sleep(REMIND_AFTER * 5).await;
+    let captured: Vec<ReminderBody> = captured.lock().unwrap().clone();
+    assert_eq!(captured.len(), 1, "{:?}", captured);
+    let reminder: &ReminderBody = &captured[0];
+    assert!(reminder.title.starts_with("Evacuate building"));
+    assert_eq!(
+        reminder.title.chars().count(),
+        emns_agent::reminder::REMINDER_TITLE_CHARS
+    );
+    assert_eq!(reminder.level, AlertLevel::Critical);
+    assert!(reminder.deadline.unwrap() > chrono::Utc::now());
+    assert_eq!(reminder.message, None);
+}
+
+#[tokio::test]
+async fn test_reminder_carries_the_body_only_when_asked() {
+    let captured: Arc<Mutex<Vec<ReminderBody>>> = Arc::default();
+    let addr: SocketAddr = capture_server(captured.clone()).await;
+    let handler: AlertHandler = handler(addr, true);
+
+    let alert: Alert = alert("Evacuate", AlertLevel::Emergency);
+    handler.handle_alert(alert.clone()).await.unwrap();
+    tokio::time::sleep(REMIND_AFTER * 3).await;
+
+    {
+        let captured = captured.lock().unwrap();
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].message.as_deref(), Some(alert.message.as_str()));
+    }
+
+    // Confirming afterwards sends nothing more
+    handler
+        .confirm_alert(alert.id, ConfirmationMethod::ToastButton)
+        .await
+        .unwrap();
+    tokio::time::sleep(REMIND_AFTER).await;
+    assert_eq!(captured.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,84 @@
+//! The agent tells the server how its previous run ended when it registers
+
+mod common;
+
+use common::{accept, SilentAudio, SilentNotifier};
+use emns_agent::messages::{Message, ShutdownReason, ShutdownRecord};
+use emns_agent::shutdown::SHUTDOWN_FILE;
+use emns_agent::transport::memory::{MemoryListener, MemoryTransport};
+use emns_agent::update::UpdateConfig;
+use emns_agent::{Agent, Config};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn build_agent(data_dir: &Path, update: Option<UpdateConfig>) -> (Agent, MemoryListener) {
+    let mut config: Config = Config::new("ws://server.test/ws", "it-client");
+    config.data_dir = data_dir.to_path_buf();
+    config.shutdown_file = Some(data_dir.join(SHUTDOWN_FILE));
+    config.update = update;
+    let (transport, listener) = MemoryTransport::new();
+    let agent: Agent = Agent::builder(config)
+        .notification_backend(Arc::new(SilentNotifier))
+        .audio_backend(Arc::new(SilentAudio))
+        .transport(Arc::new(transport))
+        .build();
+    (agent, listener)
+}
+
+/// Start `agent` and return what its first registration says of the previous run
+async fn registered(agent: &mut Agent, listener: &mut MemoryListener) -> ShutdownRecord {
+    agent.start().unwrap();
+    match accept(listener).await.1 {
+        Message::Register {
+            previous_shutdown, ..
+        } => previous_shutdown.expect("previous shutdown reported"),
+        other => panic!("expected register, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_register_reports_previous_shutdown() {
+    let data_dir: PathBuf =
+        std::env::temp_dir().join(format!("emns-restart-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&data_dir).unwrap();
+
+    // Nothing recorded before the first run
+    let (mut first, mut listener) = build_agent(&data_dir, None);
+    assert_eq!(
+        registered(&mut first, &mut listener).await,
+        ShutdownRecord::default()
+    );
+
+    // A run that never shut down, as after a power loss, leaves nothing either
+    let (mut unclean, mut unclean_listener) = build_agent(&data_dir, None);
+    assert_eq!(
+        registered(&mut unclean, &mut unclean_listener).await.reason,
+        ShutdownReason::Unknown
+    );
+    assert!(unclean.shutdown(Duration::from_secs(5)).await);
+    assert!(first.shutdown(Duration::from_secs(5)).await);
+
+    let (mut second, mut listener) = build_agent(&data_dir, None);
+    let record: ShutdownRecord = registered(&mut second, &mut listener).await;
+    assert_eq!(record.reason, ShutdownReason::Clean);
+    assert_eq!(record.version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+    assert!(record.at.is_some());
+
+    // Restarting into a staged update
+    let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]).verifying_key();
+    assert!(second.shutdown(Duration::from_secs(5)).await);
+    let (mut updating, mut listener) = build_agent(&data_dir, Some(UpdateConfig::new(key)));
+    registered(&mut updating, &mut listener).await;
+    updating.updater().unwrap().restart_requested().cancel();
+    assert!(updating.shutdown(Duration::from_secs(5)).await);
+
+    let (mut updated, mut listener) = build_agent(&data_dir, None);
+    assert_eq!(
+        registered(&mut updated, &mut listener).await.reason,
+        ShutdownReason::Update
+    );
+    assert!(updated.shutdown(Duration::from_secs(5)).await);
+
+    std::fs::remove_dir_all(data_dir).unwrap();
+}
@@ -0,0 +1,108 @@
+//! Pruning an aged data directory keeps recent and pending alerts and nothing else
+
+use emns_agent::attachments::{AttachmentConfig, AttachmentStore, ATTACHMENTS_DIR};
+use emns_agent::history::{self, AlertHistory, HistoryEntry, HISTORY_FILE};
+use emns_agent::retention::{self, PruneReport, RetentionConfig};
+use emns_agent::storage::LineProtector;
+use serde_json::json;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+fn history_line(alert_id: Uuid, received_at: chrono::DateTime<chrono::Utc>) -> String {
+    json!({
+        "alert_id": alert_id,
+        "level": "info",
+        "title": "Fixture",
+        "received_at": received_at,
+        "original_title_len": 7,
+        "original_message_len": 0,
+    })
+    .to_string()
+}
+
+fn attachment(data_dir: &Path, alert_id: Uuid) -> PathBuf {
+    let dir: PathBuf = data_dir.join(ATTACHMENTS_DIR).join(alert_id.to_string());
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("procedure.pdf"), b"%PDF").unwrap();
+    dir
+}
+
+#[test]
+fn test_prune_keeps_recent_and_pending_alerts_only() {
+    let data_dir: PathBuf = std::env::temp_dir().join(format!("emns-retention-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(data_dir.join(ATTACHMENTS_DIR)).unwrap();
+    let now = chrono::Utc::now();
+    let days_ago = |days: i64| now - chrono::TimeDelta::days(days);
+
+    let aged: Uuid = Uuid::new_v4();
+    let aged_pending: Uuid = Uuid::new_v4();
+    let recent: Uuid = Uuid::new_v4();
+    let orphan: Uuid = Uuid::new_v4();
+    let lines: Vec<String> = vec![
+        history_line(aged, days_ago(120)),
+        history_line(aged_pending, days_ago(100)),
+        history_line(recent, days_ago(10)),
+        // The same alert again, as appended when it escalated
+        history_line(recent, days_ago(10)),
+    ];
+    std::fs::write(data_dir.join(HISTORY_FILE), lines.join("\n") + "\n").unwrap();
+    let aged_folder: PathBuf = attachment(&data_dir, aged);
+    let pending_folder: PathBuf = attachment(&data_dir, aged_pending);
+    let recent_folder: PathBuf = attachment(&data_dir, recent);
+    let orphan_folder: PathBuf = attachment(&data_dir, orphan);
+    let unrelated: PathBuf = data_dir.join(ATTACHMENTS_DIR).join("README.txt");
+    std::fs::write(&unrelated, b"left by an administrator").unwrap();
+
+    let history: AlertHistory = AlertHistory::open(data_dir.join(HISTORY_FILE)).unwrap();
+    let attachments: AttachmentStore =
+        AttachmentStore::new(&data_dir, &AttachmentConfig::default());
+    let report: PruneReport = retention::prune(
+        &history,
+        &attachments,
+        &RetentionConfig::default(),
+        &HashSet::from([aged_pending]),
+        now,
+    )
+    .unwrap();
+
+    assert_eq!(report.history_removed, [aged]);
+    assert_eq!(report.history_lines_compacted, 1);
+    assert_eq!(report.attachments_removed, 2);
+    assert!(history.get(aged).is_none());
+
+    let survivors: Vec<Uuid> =
+        history::read_entries(&data_dir.join(HISTORY_FILE), &LineProtector::default())
+            .unwrap()
+            .iter()
+            .map(|entry: &HistoryEntry| entry.alert_id)
+            .collect();
+    assert_eq!(survivors, [aged_pending, recent]);
+    assert!(!aged_folder.exists());
+    assert!(!orphan_folder.exists());
+    assert!(pending_folder.exists());
+    assert!(recent_folder.exists());
+    assert!(unrelated.exists());
+
+    // The history keeps appending to the rewritten file
+    let later: Uuid = Uuid::new_v4();
+    history.record(serde_json::from_str(&history_line(later, now)).unwrap());
+    let reopened: AlertHistory = AlertHistory::open(data_dir.join(HISTORY_FILE)).unwrap();
+    assert!(reopened.get(later).is_some());
+    assert!(reopened.get(recent).is_some());
+
+    // A second pass right away finds nothing to do
+    assert_eq!(
+        retention::prune(
+            &reopened,
+            &attachments,
+            &RetentionConfig::default(),
+            &HashSet::from([aged_pending]),
+            now,
+        )
+        .unwrap(),
+        PruneReport::default()
+    );
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
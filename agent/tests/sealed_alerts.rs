@@ -0,0 +1,79 @@
+//! Sealed alerts are readable on the machine they were sealed to and nowhere along the way
+
+mod common;
+
+use common::{RecordingNotifier, SilentAudio};
+use emns_agent::messages::{Alert, AlertErrorReason, AlertLevel, Message, SealedContent};
+use emns_agent::sealed::{self, AlertKey};
+use emns_agent::transport::memory::{MemoryPeer, MemoryTransport};
+use emns_agent::{Agent, Config};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What a submitter hands the server: placeholders around a body sealed to `encryption_key`
+fn sealed_alert(content: &SealedContent, encryption_key: &str) -> Alert {
+    let placeholder: Alert = common::alert("Sealed alert", AlertLevel::Critical);
+    Alert {
+        message: "Sealed alert".to_string(),
+        sealed: Some(sealed::seal(content, placeholder.id, encryption_key).unwrap()),
+        ..placeholder
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_sealed_alert_opens_only_with_the_registered_key() {
+    let mut config: Config = Config::new("ws://server.test/ws", "it-client");
+    config.alert_key = Some(AlertKey::generate());
+    let (transport, mut listener) = MemoryTransport::new();
+    let notifier: Arc<RecordingNotifier> = Arc::new(RecordingNotifier::default());
+    let mut agent: Agent = Agent::builder(config)
+        .notification_backend(notifier.clone())
+        .audio_backend(Arc::new(SilentAudio))
+        .transport(Arc::new(transport))
+        .build();
+    agent.start().unwrap();
+
+    let mut peer: MemoryPeer = listener.accept().await.expect("agent connected");
+    let encryption_key: String = match peer.recv().await {
+        Some(Message::Register { encryption_key, .. }) => encryption_key.expect("key offered"),
+        other => panic!("expected register, got {:?}", other),
+    };
+    peer.ack_registration();
+
+    let content: SealedContent = SealedContent {
+        title: "Protective detail".to_string(),
+        message: "Principal arriving at the north entrance at 14:00".to_string(),
+    };
+    let readable: Alert = sealed_alert(&content, &encryption_key);
+    assert!(!serde_json::to_string(&readable)
+        .unwrap()
+        .contains("north entrance"));
+    peer.send(&Message::Alert { alert: readable });
+    notifier.wait_for(1).await;
+    let shown: Alert = notifier.shown.lock().unwrap()[0].clone();
+    assert_eq!(shown.title, content.title);
+    assert_eq!(shown.message, content.message);
+
+    // Sealed to a key this agent does not hold: shown generically and reported
+    let unreadable: Alert = sealed_alert(&content, &AlertKey::generate().public_key());
+    let unreadable_id: uuid::Uuid = unreadable.id;
+    peer.send(&Message::Alert { alert: unreadable });
+    notifier.wait_for(2).await;
+    let shown: Alert = notifier.shown.lock().unwrap()[1].clone();
+    assert_eq!(shown.title, sealed::UNREADABLE_TITLE);
+    assert_eq!(shown.message, sealed::UNREADABLE_MESSAGE);
+    loop {
+        match peer.recv().await.expect("connection open") {
+            Message::AlertError {
+                reason, alert_id, ..
+            } => {
+                assert_eq!(reason, AlertErrorReason::Undecryptable);
+                assert_eq!(alert_id, Some(unreadable_id));
+                break;
+            }
+            _ => continue,
+        }
+    }
+
+    assert!(agent.shutdown(Duration::from_secs(5)).await);
+}
@@ -0,0 +1,90 @@
+//! Alerts keep arriving, once each, when the primary server dies and the standby takes over
+
+mod common;
+
+use common::{accept, RecordingNotifier, SilentAudio};
+use emns_agent::messages::{Alert, AlertLevel, ConfirmationMethod, Message};
+use emns_agent::transport::memory::{MemoryPeer, MemoryTransport};
+use emns_agent::{Agent, Config};
+use std::sync::Arc;
+use std::time::Duration;
+
+const PRIMARY: &str = "ws://primary.test/ws";
+const BACKUP: &str = "ws://backup.test/ws";
+
+fn server_alert(title: &str) -> Alert {
+    Alert {
+        message: "Follow the posted procedure".to_string(),
+        requires_confirmation: true,
+        ..common::alert(title, AlertLevel::Critical)
+    }
+}
+
+/// Next confirmation sent over `peer`, acknowledging any registration and
+/// skipping everything else
+async fn recv_confirmation(peer: &mut MemoryPeer) -> Option<uuid::Uuid> {
+    loop {
+        match peer.recv().await? {
+            Message::Confirmation { confirmation } => return Some(confirmation.alert_id),
+            Message::Register { .. } => peer.ack_registration(),
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_standby_takes_over_when_primary_dies() {
+    let mut config: Config = Config::new(PRIMARY, "it-client");
+    config.standby_server_url = Some(BACKUP.to_string());
+    let (transport, mut listener) = MemoryTransport::new();
+    let notifier: Arc<RecordingNotifier> = Arc::new(RecordingNotifier::default());
+    let mut agent: Agent = Agent::builder(config)
+        .notification_backend(notifier.clone())
+        .audio_backend(Arc::new(SilentAudio))
+        .transport(Arc::new(transport))
+        .build();
+    agent.start().unwrap();
+
+    // The primary is active and the backup registers as a standby
+    let (first, first_register) = accept(&mut listener).await;
+    let (second, _) = accept(&mut listener).await;
+    let (primary, mut backup) = if matches!(first_register, Message::Register { standby: true, .. })
+    {
+        (second, first)
+    } else {
+        (first, second)
+    };
+    assert_eq!(primary.url(), PRIMARY);
+    assert_eq!(backup.url(), BACKUP);
+
+    // Both servers send the same alert; it is shown once
+    let both: Alert = server_alert("Sent by both servers");
+    primary.send(&Message::Alert {
+        alert: both.clone(),
+    });
+    backup.send(&Message::Alert {
+        alert: both.clone(),
+    });
+    notifier.wait_for(1).await;
+
+    // The primary dies mid-stream and the backup carries on a second later
+    primary.fail("connection reset");
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let after: Alert = server_alert("Sent after failover");
+    backup.send(&Message::Alert {
+        alert: after.clone(),
+    });
+    notifier.wait_for(2).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(notifier.shown_ids(), vec![both.id, after.id]);
+
+    // Confirmations go to the promoted backup
+    agent
+        .handler()
+        .confirm_alert(after.id, ConfirmationMethod::ToastButton)
+        .await
+        .unwrap();
+    assert_eq!(recv_confirmation(&mut backup).await, Some(after.id));
+
+    assert!(agent.shutdown(Duration::from_secs(5)).await);
+}
@@ -0,0 +1,82 @@
+//! `wss://` servers with certificates from a private CA are trusted once its root is supplied
+
+use emns_agent::transport::{Frame, TlsConfig, Transport, TungsteniteTransport};
+use emns_agent::EmnsError;
+use futures_util::{SinkExt, StreamExt};
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/tls")
+        .join(name)
+}
+
+/// Serve WebSocket over TLS with a certificate issued by the test CA, greeting each client
+async fn start_server() -> String {
+    let identity = native_tls::Identity::from_pkcs8(
+        &std::fs::read(fixture("server.pem")).unwrap(),
+        &std::fs::read(fixture("server.key")).unwrap(),
+    )
+    .unwrap();
+    let acceptor =
+        tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port: u16 = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                // Clients that reject the certificate fail the handshake here
+                let Ok(tls) = acceptor.accept(socket).await else {
+                    return;
+                };
+                let mut ws = tokio_tungstenite::accept_async(tls).await.unwrap();
+                ws.send(Frame::Text("hello".to_string())).await.unwrap();
+            });
+        }
+    });
+    format!("wss://localhost:{}/ws", port)
+}
+
+async fn greeting(transport: &TungsteniteTransport, url: &str) -> Result<String, EmnsError> {
+    let mut connection = transport.connect(url).await?;
+    match connection.stream.next().await {
+        Some(Ok(Frame::Text(text))) => Ok(text),
+        other => panic!("expected greeting, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_private_ca_is_trusted_from_ca_file() {
+    let url: String = start_server().await;
+    let transport: TungsteniteTransport = TungsteniteTransport::with_tls(&TlsConfig {
+        ca_file: Some(fixture("ca.pem")),
+        insecure_skip_verify: false,
+    })
+    .unwrap();
+
+    assert_eq!(greeting(&transport, &url).await.unwrap(), "hello");
+}
+
+#[tokio::test]
+async fn test_private_ca_is_refused_by_default() {
+    let url: String = start_server().await;
+
+    match greeting(&TungsteniteTransport::default(), &url).await {
+        Err(EmnsError::Connection { url: failed, .. }) => assert_eq!(failed, url),
+        other => panic!("expected the handshake to fail, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_insecure_skip_verify_accepts_any_certificate() {
+    let url: String = start_server().await;
+    let transport: TungsteniteTransport = TungsteniteTransport::with_tls(&TlsConfig {
+        ca_file: None,
+        insecure_skip_verify: true,
+    })
+    .unwrap();
+
+    assert_eq!(greeting(&transport, &url).await.unwrap(), "hello");
+}
@@ -0,0 +1,99 @@
+//! A wedged alert pipeline is noticed, reported, and restarted when that is enabled
+
+mod common;
+
+use common::{wait_until, RecordingNotifier, SilentAudio};
+use emns_agent::messages::{Alert, AlertLevel};
+use emns_agent::sink::{AlertSink, Resolution};
+use emns_agent::watchdog::WatchdogConfig;
+use emns_agent::{Agent, AudioBackend, Config};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+/// Starting a sound takes a while, as a driver stuck in a long loop might
+struct SlowAudio(Duration);
+
+impl AudioBackend for SlowAudio {
+    fn play(&self, _sound_file: &str) {
+        std::thread::sleep(self.0);
+    }
+}
+
+/// Blocks the first delivery until released, as a deadlocked sink would
+struct WedgingSink {
+    wedged: AtomicBool,
+    release: Mutex<mpsc::Receiver<()>>,
+}
+
+impl AlertSink for WedgingSink {
+    fn delivered(&self, _alert: &Alert) {
+        if !self.wedged.swap(true, Ordering::SeqCst) {
+            let _ = self.release.lock().unwrap().recv();
+        }
+    }
+
+    fn resolved(&self, _alert_id: uuid::Uuid, _resolution: Resolution) {}
+}
+
+fn alert(title: &str) -> Alert {
+    common::alert(title, AlertLevel::Warning)
+}
+
+fn config(restart: bool) -> Config {
+    let mut config: Config = Config::new("ws://127.0.0.1:9/ws", "it-client");
+    config.burst = None;
+    config.watchdog = Some(WatchdogConfig {
+        stall_after: Duration::from_millis(200),
+        restart,
+    });
+    config
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_wedged_pipeline_is_reported_and_restarted() {
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    let notifier: Arc<RecordingNotifier> = Arc::new(RecordingNotifier::default());
+    let mut agent: Agent = Agent::builder(config(true))
+        .notification_backend(notifier.clone())
+        .audio_backend(Arc::new(SilentAudio))
+        .sink(Arc::new(WedgingSink {
+            wedged: AtomicBool::new(false),
+            release: Mutex::new(release_rx),
+        }))
+        .build();
+    agent.start().unwrap();
+
+    agent.alert_queue().try_push(alert("wedges")).unwrap();
+    agent.alert_queue().try_push(alert("waits")).unwrap();
+    wait_until(|| agent.status().pipeline_stalled).await;
+
+    // The new loop takes the waiting alert while the old one is still stuck
+    wait_until(|| notifier.shown_titles() == ["waits"]).await;
+    wait_until(|| !agent.status().pipeline_stalled).await;
+    assert_eq!(agent.status().pipeline_stalls, 1);
+
+    release_tx.send(()).unwrap();
+    assert!(agent.shutdown(Duration::from_secs(5)).await);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_slow_sound_start_is_not_a_stall() {
+    let notifier: Arc<RecordingNotifier> = Arc::new(RecordingNotifier::default());
+    let mut agent: Agent = Agent::builder(config(false))
+        .notification_backend(notifier.clone())
+        .audio_backend(Arc::new(SlowAudio(Duration::from_millis(400))))
+        .build();
+    agent.start().unwrap();
+
+    for n in 0..3 {
+        agent
+            .alert_queue()
+            .try_push(alert(&format!("alert {}", n)))
+            .unwrap();
+    }
+    wait_until(|| notifier.shown.lock().unwrap().len() == 3).await;
+    assert_eq!(agent.status().pipeline_stalls, 0);
+
+    assert!(agent.shutdown(Duration::from_secs(5)).await);
+}
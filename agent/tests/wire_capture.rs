@@ -0,0 +1,124 @@
+//! Frames exchanged with the server are captured exactly as the transport carried them
+
+mod common;
+
+use common::{SilentAudio, SilentNotifier};
+use emns_agent::capture::{CapturedFrame, Direction, FrameKind, WireCaptureConfig};
+use emns_agent::messages::{Alert, AlertLevel, Message};
+use emns_agent::transport::memory::{MemoryPeer, MemoryTransport};
+use emns_agent::transport::Frame;
+use emns_agent::{Agent, Config};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+const URL: &str = "ws://server.test/ws";
+
+fn server_alert() -> Alert {
+    Alert {
+        message: "Move away from windows".to_string(),
+        ..common::alert("Shelter in place", AlertLevel::Warning)
+    }
+}
+
+fn read_capture(path: &Path) -> Vec<CapturedFrame> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("capture line is a frame"))
+        .collect()
+}
+
+/// Run a scripted session against an agent capturing to `config`; returns the capture
+async fn scripted_session(config: WireCaptureConfig) -> Vec<CapturedFrame> {
+    let path: PathBuf = config.path.clone();
+    let (transport, mut listener) = MemoryTransport::new();
+    let mut agent_config: Config = Config::new(URL, "it-client");
+    agent_config.wire_capture = Some(config);
+    let mut agent: Agent = Agent::builder(agent_config)
+        .notification_backend(Arc::new(SilentNotifier))
+        .audio_backend(Arc::new(SilentAudio))
+        .transport(Arc::new(transport))
+        .build();
+    agent.start().unwrap();
+
+    let mut peer: MemoryPeer = listener.accept().await.expect("agent connected");
+    assert!(matches!(peer.recv().await, Some(Message::Register { .. })));
+    peer.ack_registration();
+    peer.send(&Message::Alert {
+        alert: server_alert(),
+    });
+    // Fails to parse, so the client drops the connection, but it is still captured
+    peer.send_frame(Frame::Text("{not json".to_string()));
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while read_capture(&path)
+            .iter()
+            .filter(|frame| frame.direction == Direction::Received)
+            .count()
+            < 3
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("garbage frame captured");
+
+    assert!(agent.shutdown(Duration::from_secs(5)).await);
+    read_capture(&path)
+}
+
+fn capture_path() -> PathBuf {
+    std::env::temp_dir()
+        .join(format!("emns-wire-{}", uuid::Uuid::new_v4()))
+        .join("wire.jsonl")
+}
+
+#[tokio::test]
+async fn test_session_is_captured_in_order() {
+    let path: PathBuf = capture_path();
+    let frames: Vec<CapturedFrame> = scripted_session(WireCaptureConfig::new(&path)).await;
+
+    let register: &CapturedFrame = &frames[0];
+    assert_eq!(register.direction, Direction::Sent);
+    assert_eq!(register.url, URL);
+    assert_eq!(register.message_type().as_deref(), Some("register"));
+
+    let received: Vec<&CapturedFrame> = frames
+        .iter()
+        .filter(|frame| frame.direction == Direction::Received)
+        .collect();
+    assert_eq!(received.len(), 3);
+    assert_eq!(received[0].message_type().as_deref(), Some("register_ack"));
+    assert_eq!(received[1].message_type().as_deref(), Some("alert"));
+    assert!(received[1]
+        .payload
+        .as_deref()
+        .unwrap()
+        .contains("Move away from windows"));
+    assert_eq!(received[2].kind, FrameKind::Text);
+    assert_eq!(received[2].size, "{not json".len());
+    assert!(frames.iter().all(|frame| !frame.redacted));
+
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn test_redacted_capture_hides_alert_text() {
+    let path: PathBuf = capture_path();
+    let config: WireCaptureConfig = WireCaptureConfig {
+        redact: true,
+        ..WireCaptureConfig::new(&path)
+    };
+    let frames: Vec<CapturedFrame> = scripted_session(config).await;
+
+    let alert: &CapturedFrame = frames
+        .iter()
+        .find(|frame| frame.message_type().as_deref() == Some("alert"))
+        .expect("alert captured");
+    let payload: &str = alert.payload.as_deref().unwrap();
+    assert!(!payload.contains("Shelter in place"));
+    assert!(!payload.contains("Move away from windows"));
+    assert!(frames.iter().all(|frame| frame.redacted));
+
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
@@ -0,0 +1,42 @@
+//! Many alerts in one message, such as the backlog a server replays when an
+//! agent reconnects
+
+use crate::Alert;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The alerts carried by a [`Message::AlertBatch`](crate::Message::AlertBatch).
+///
+/// An entry that cannot be read as an [`Alert`] is set aside in `unreadable`
+/// rather than costing the rest of the batch.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(from = "WireBatch")]
+pub struct AlertBatch {
+    pub alerts: Vec<Alert>,
+    /// Why each unreadable entry was left out; never sent
+    #[serde(skip)]
+    pub unreadable: Vec<String>,
+}
+
+/// The batch as received, before its entries are read
+#[derive(Deserialize)]
+struct WireBatch {
+    alerts: Vec<serde_json::Value>,
+}
+
+impl From<WireBatch> for AlertBatch {
+    fn from(wire: WireBatch) -> Self {
+        let mut batch: AlertBatch = AlertBatch::default();
+        for (index, entry) in wire.alerts.into_iter().enumerate() {
+            let id: Option<String> = entry.get("id").map(|id| id.to_string());
+            match serde_json::from_value::<Alert>(entry) {
+                Ok(alert) => batch.alerts.push(alert),
+                Err(e) => batch.unreadable.push(match id {
+                    Some(id) => format!("entry {} (id {}): {}", index, id, e),
+                    None => format!("entry {}: {}", index, e),
+                }),
+            }
+        }
+        batch
+    }
+}
@@ -0,0 +1,16 @@
+//! Write the protocol's JSON Schemas to disk.
+//!
+//! Usage: `emns-schema [OUTPUT_DIR]`, defaulting to `./schema`.
+
+use std::path::PathBuf;
+
+fn main() -> std::io::Result<()> {
+    let dir: PathBuf = std::env::args_os()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("schema"));
+    for path in emns_protocol::schema::write_schemas(&dir)? {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
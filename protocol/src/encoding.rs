@@ -0,0 +1,43 @@
+//! How messages are put in WebSocket frames: JSON text, or MessagePack for
+//! links where every byte counts
+
+use crate::Message;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// An encoding for [`Message`]s, offered by the agent and chosen by the server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// UTF-8 JSON in text frames; always understood
+    #[default]
+    Json,
+    /// MessagePack in binary frames, with the same field names and values as JSON
+    Msgpack,
+}
+
+impl Message {
+    /// Encode as MessagePack. Structs are maps keyed by field name and values
+    /// take their JSON form, so the message reads back the same as its JSON.
+    pub fn to_msgpack(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut serializer = rmp_serde::Serializer::new(&mut bytes)
+            .with_struct_map()
+            .with_human_readable();
+        self.serialize(&mut serializer)?;
+        Ok(bytes)
+    }
+
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Self, rmp_serde::decode::Error> {
+        let mut deserializer = rmp_serde::Deserializer::from_read_ref(bytes).with_human_readable();
+        Message::deserialize(&mut deserializer)
+    }
+}
+
+/// Read MessagePack as the JSON value it stands for, to look into a message
+/// that did not parse
+pub fn msgpack_value(bytes: &[u8]) -> Result<Value, rmp_serde::decode::Error> {
+    let mut deserializer = rmp_serde::Deserializer::from_read_ref(bytes).with_human_readable();
+    Value::deserialize(&mut deserializer)
+}
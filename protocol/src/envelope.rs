@@ -0,0 +1,73 @@
+//! Envelopes giving each message an id its receiver acknowledges, so a server
+//! learns whether an alert was queued for display, and if not, why not
+
+use crate::Message;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+/// A message with an id, sent both ways once the registration ack turns
+/// envelopes on. The receiver answers with [`Message::Ack`] or
+/// [`Message::Nack`] for the id, except for acks and nacks themselves.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Envelope {
+    /// Unique per message; a message resent after a reconnect keeps its id
+    pub message_id: Uuid,
+    pub message: Message,
+}
+
+impl Envelope {
+    /// Whether the receiver answers this envelope with an ack or nack
+    pub fn wants_ack(&self) -> bool {
+        !matches!(self.message, Message::Ack { .. } | Message::Nack { .. })
+    }
+
+    /// Encode as MessagePack, the same way as [`Message::to_msgpack`]
+    pub fn to_msgpack(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut serializer = rmp_serde::Serializer::new(&mut bytes)
+            .with_struct_map()
+            .with_human_readable();
+        self.serialize(&mut serializer)?;
+        Ok(bytes)
+    }
+
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Self, rmp_serde::decode::Error> {
+        let mut deserializer = rmp_serde::Deserializer::from_read_ref(bytes).with_human_readable();
+        Envelope::deserialize(&mut deserializer)
+    }
+
+    /// The id of an envelope that did not parse, as a JSON value, so it can
+    /// still be answered with a [`Message::Nack`]; `None` if it holds an ack
+    /// or nack, which go unanswered
+    pub fn message_id_of(envelope: &Value) -> Option<Uuid> {
+        let kind: Option<&str> = envelope
+            .get("message")
+            .and_then(|message| message.get("type"))
+            .and_then(Value::as_str);
+        if matches!(kind, Some("ack" | "nack")) {
+            return None;
+        }
+        envelope
+            .get("message_id")
+            .and_then(Value::as_str)
+            .and_then(|id| Uuid::parse_str(id).ok())
+    }
+}
+
+/// Why a [`Message::Nack`] was sent
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum NackReason {
+    /// The alert queue stayed full and the alert was shed; it was not shown
+    QueueFull,
+    /// The alert failed validation and was not shown; the detail names each
+    /// field and its problem
+    Invalid,
+    /// The alert, or the cancellation of one, was unsigned or its signature
+    /// did not match, and was ignored
+    BadSignature,
+    /// The message could not be read at all
+    Unreadable,
+}
@@ -0,0 +1,1586 @@
+//! Wire types exchanged between the EMNS server and its agents.
+//!
+//! Both sides depend on this crate so the wire format has a single definition,
+//! whether carried as JSON or as MessagePack.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+mod batch;
+mod encoding;
+mod envelope;
+mod location;
+pub mod schema;
+mod validate;
+
+pub use batch::AlertBatch;
+pub use encoding::{msgpack_value, Encoding};
+pub use envelope::{Envelope, NackReason};
+pub use location::{Location, LocationField};
+pub use validate::{
+    is_http_url, is_language_tag, FieldProblem, InvalidAlert, MAX_ALERT_MESSAGE_CHARS,
+    MAX_ALERT_PRIORITY, MAX_ALERT_REPEATS, MAX_ALERT_TIMESTAMP_AHEAD_SECS, MAX_ALERT_TITLE_CHARS,
+    MAX_ALERT_URL_CHARS, MAX_CONFIRM_TIMEOUT_SECS, MIN_REPEAT_INTERVAL_SECS,
+};
+
+/// Version of the wire protocol defined by this crate
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// First field of every [`Alert::signing_payload`], naming what is signed
+pub const SIGNATURE_CONTEXT: &str = "emns-alert-v2";
+
+/// First field of every [`Message::cancel_signing_payload`]
+pub const CANCEL_SIGNATURE_CONTEXT: &str = "emns-cancel-v1";
+
+/// Alert severity levels
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertLevel {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+    Emergency,
+}
+
+/// AlertLevel implementation to get as string for logging
+impl AlertLevel {
+    /// Display name of the level
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertLevel::Info => "Info",
+            AlertLevel::Warning => "Warning",
+            AlertLevel::Critical => "Critical",
+            AlertLevel::Emergency => "Emergency",
+        }
+    }
+}
+
+/// Where an alert was raised
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertOrigin {
+    /// Pushed by the EMNS server
+    #[default]
+    Server,
+    /// Raised by another application on the same machine
+    Local,
+}
+
+impl AlertOrigin {
+    fn is_server(&self) -> bool {
+        *self == AlertOrigin::Server
+    }
+}
+
+/// One answer a user can give to an alert, e.g. "Safe" or "Need assistance"
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct ResponseOption {
+    /// Returned in [`Confirmation::response_id`]
+    pub id: String,
+    /// Button text
+    pub label: String,
+    /// What choosing it says, returned in [`Confirmation::response`]
+    #[serde(default, skip_serializing_if = "ConfirmationResponse::is_acknowledged")]
+    pub response: ConfirmationResponse,
+}
+
+/// What a user's answer to an alert says, beyond that they saw it
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ConfirmationResponse {
+    /// Seen and being acted on; all a plain confirm or a timeout says
+    #[default]
+    Acknowledged,
+    /// Seen, but the user cannot do what it asks
+    CannotComply {
+        /// e.g. why, as set by the alert's sender
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        note: Option<String>,
+    },
+    /// The alert does not apply to the user, e.g. it reached the wrong recipient
+    NotApplicable,
+}
+
+impl ConfirmationResponse {
+    /// No more than a plain confirm
+    pub fn is_acknowledged(&self) -> bool {
+        *self == ConfirmationResponse::Acknowledged
+    }
+}
+
+/// A document linked from an alert, e.g. the evacuation procedure PDF
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct Attachment {
+    /// Where the agent downloads the file from
+    pub url: String,
+    /// Name the file is saved under
+    pub filename: String,
+    /// Hex SHA-256 of the file; the agent refuses to open anything else
+    pub sha256: String,
+    /// Size in bytes
+    pub size: u64,
+}
+
+/// An alert's title and message encrypted to one client's key, so the server
+/// routing it cannot read them.
+///
+/// The sender makes a one-time X25519 key pair and agrees a secret with the
+/// client's `encryption_key` from [`Message::Register`]. HKDF-SHA256 over that
+/// secret, salted with the one-time public key followed by the client's public
+/// key and with info `emns sealed alert v1`, gives a ChaCha20-Poly1305 key. The
+/// plaintext is a [`SealedContent`] as JSON and the alert's 16 id bytes are the
+/// associated data, so a body cannot be moved onto another alert.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct SealedBody {
+    /// First 16 hex digits of the SHA-256 of the client public key it was sealed to
+    pub key_id: String,
+    /// The sender's one-time X25519 public key, base64
+    pub ephemeral_key: String,
+    /// 12-byte nonce, base64
+    pub nonce: String,
+    /// Encrypted [`SealedContent`] followed by its tag, base64
+    pub ciphertext: String,
+}
+
+/// What a [`SealedBody`] decrypts to
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct SealedContent {
+    pub title: String,
+    pub message: String,
+}
+
+/// An alert's title and message in another language
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct Translation {
+    pub title: String,
+    pub message: String,
+}
+
+/// How an alert that waits for confirmation is repeated, in place of the
+/// agent's own setting; fields left out keep the agent's value
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct AlertEscalation {
+    /// Seconds between repeats of the toast and sound, at least
+    /// [`MIN_REPEAT_INTERVAL_SECS`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_interval_secs: Option<u32>,
+    /// Repeats before the alert is left to auto-confirm, at most
+    /// [`MAX_ALERT_REPEATS`]; `0` turns them off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_repeats: Option<u32>,
+}
+
+/// Alert message sent from server to client.
+///
+/// The default is an empty Info alert with a nil id and every optional field
+/// unset, for building alerts with struct update syntax.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Alert {
+    pub id: Uuid,
+    pub title: String,
+    pub message: String,
+    pub level: AlertLevel,
+    pub requires_confirmation: bool,
+    pub sound_file: Option<String>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Omitted on the wire for server alerts
+    #[serde(default, skip_serializing_if = "AlertOrigin::is_server")]
+    pub origin: AlertOrigin,
+    /// Agents outside this location ignore the alert; `None` targets everyone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    /// Answers offered instead of a plain confirm
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_options: Option<Vec<ResponseOption>>,
+    /// Document the agent downloads and offers to open
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<Attachment>,
+    /// Issued while this client was disconnected and replayed on reconnect
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub missed: bool,
+    /// Kind of event, e.g. `fire_alarm`, for matching [`SuppressionWindow`]s
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    /// How the toast is presented, overriding the agent's defaults for the level
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub toast: Option<ToastOptions>,
+    /// Sent only to the composing operator's own machine to try the alert out;
+    /// labelled, never escalated, and taken down after a minute
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub is_preview: bool,
+    /// The real title and message, readable only by this client; `title` and
+    /// `message` then hold placeholders shown if it cannot be decrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<SealedBody>,
+    /// HTTPS URL the client also POSTs to when its user confirms, for
+    /// integrations that want to hear from the endpoint directly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confirm_callback_url: Option<String>,
+    /// Sent to a team when any `quorum` of them acknowledging is enough; the
+    /// server then sends [`Message::QuorumMet`] to the rest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quorum: Option<u32>,
+    /// Machine roles allowed to display the alert, e.g. `workstation`; other
+    /// agents record it as hidden. `None` lets every role display it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<Vec<String>>,
+    /// When the alert stops mattering. One arriving later is recorded but
+    /// neither shown nor sounded, and one still awaiting confirmation then is
+    /// taken down unconfirmed; either way the agent sends
+    /// [`Message::AlertExpired`]. `None` never expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// An earlier alert this one revises, e.g. a warning replacing a watch.
+    /// The agent takes the earlier one down if it still awaits confirmation,
+    /// and sounds the update only if it is more severe. An id the agent never
+    /// received is ignored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supersedes: Option<Uuid>,
+    /// Groups the alert is meant for, matched against the groups an agent
+    /// registers with. With `target_hosts`, an agent matching either list
+    /// shows it; when both are empty every agent does
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub target_groups: Vec<String>,
+    /// Hostnames the alert is meant for; a trailing `*` matches any suffix,
+    /// e.g. `ops-ws-*`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub target_hosts: Vec<String>,
+    /// Base64 HMAC-SHA256 over [`Alert::signing_payload`] with the key shared
+    /// by the server and its agents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// Web page with more about the alert, opened by the toast's More Info button
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// PNG, JPEG or GIF shown in the toast, e.g. a radar snapshot. The toast
+    /// waits only briefly for it and is shown without it otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+    /// The title and message in other languages, keyed by BCP 47 tag, e.g.
+    /// `fr` or `fr-CA`. Agents show the one best matching their locale, and
+    /// `title` and `message` when none does
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub translations: HashMap<String, Translation>,
+    /// Dispatch priority from 0 to [`MAX_ALERT_PRIORITY`], higher first.
+    /// Agents with a backlog handle alerts by level, then by priority, with
+    /// alerts that have none after those of their level that do
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u8>,
+    /// Repeats of the toast and sound until the alert is confirmed. Only
+    /// alerts that require confirmation repeat, and auto-confirm waits for
+    /// the last repeat to go unanswered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalation: Option<AlertEscalation>,
+    /// Seconds the alert waits for confirmation before the agent gives up on
+    /// it, in place of the agent's own timeout; `0` waits until someone
+    /// confirms it. At most [`MAX_CONFIRM_TIMEOUT_SECS`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confirm_timeout_secs: Option<u32>,
+}
+
+/// Toast presentation for one alert; fields left out keep the agent's default for the level.
+///
+/// Values are strings so an agent that does not know a newer value falls back
+/// to its default instead of rejecting the alert.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct ToastOptions {
+    /// Windows toast scenario: `default`, `alarm`, `reminder`, `incomingCall` or `urgent`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scenario: Option<String>,
+    /// `short` or `long`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<String>,
+    /// Deliver straight to Action Center without a popup
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suppress_popup: Option<bool>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn is_zero<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// Why a confirmation was sent
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfirmationReason {
+    /// The user confirmed the alert
+    #[default]
+    User,
+    /// Nobody confirmed the alert before the auto-confirm timeout
+    TimedOut,
+    /// The timeout passed while nobody was using the machine
+    TimedOutIdle,
+    /// The user dismissed the alert, among others, without confirming it
+    Dismissed,
+}
+
+impl ConfirmationReason {
+    fn is_user(&self) -> bool {
+        *self == ConfirmationReason::User
+    }
+}
+
+/// How a confirmation was given
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfirmationMethod {
+    /// A button on the alert's toast
+    ToastButton,
+    /// A button in the alert's details window, opened from its toast
+    DetailsWindow,
+    /// Nobody responded before the auto-confirm timeout
+    AutoTimeout,
+    /// The agent's local HTTP API, e.g. a kiosk's "confirm all"
+    Api,
+    /// The agent's `confirm-all` or `dismiss-all` command
+    Cli,
+}
+
+/// Which channel delivered an alert to the agent
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceivedVia {
+    /// The server connection
+    #[default]
+    WebSocket,
+    /// The site's multicast fallback, as an [`AlertEnvelope`]
+    Multicast,
+}
+
+impl ReceivedVia {
+    fn is_websocket(&self) -> bool {
+        *self == ReceivedVia::WebSocket
+    }
+}
+
+/// Confirmation sent from client to server
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Confirmation {
+    pub alert_id: Uuid,
+    pub client_id: String,
+    pub confirmed_at: chrono::DateTime<chrono::Utc>,
+    pub hostname: String,
+    pub username: String,
+    /// Badge or operator id typed in when confirming on a shared console;
+    /// `None` for timeouts and agents that report the session's user only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator_id: Option<String>,
+    /// Omitted on the wire for confirmations by the user
+    #[serde(default, skip_serializing_if = "ConfirmationReason::is_user")]
+    pub reason: ConfirmationReason,
+    /// What the confirmation came through, telling a person's response from
+    /// an automatic one; `None` from agents older than the field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<ConfirmationMethod>,
+    /// Seconds since the last keyboard or mouse input, when known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_idle_secs: Option<u64>,
+    /// The [`ResponseOption::id`] the user chose; `None` for a plain confirm or a timeout
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_id: Option<String>,
+    /// What the chosen answer says; omitted on the wire when acknowledged,
+    /// and read as acknowledged from agents that do not send it
+    #[serde(default, skip_serializing_if = "ConfirmationResponse::is_acknowledged")]
+    pub response: ConfirmationResponse,
+    /// Omitted on the wire for alerts that arrived over the server connection
+    #[serde(default, skip_serializing_if = "ReceivedVia::is_websocket")]
+    pub received_via: ReceivedVia,
+    /// When the alert's toast appeared; `None` if it was never shown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shown_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Milliseconds from `shown_at` to the confirmation. For timeouts this is
+    /// the whole time the toast was up, and `reason` says nobody responded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_latency_ms: Option<u64>,
+    /// Answers a preview alert rather than a real one
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub is_preview: bool,
+}
+
+/// How quickly people responded to one alert, for a server's delivery report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    /// Confirmations by a user that carried a latency
+    pub responses: usize,
+    /// Confirmations sent because the alert timed out or was dismissed
+    pub timed_out: usize,
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+}
+
+impl LatencySummary {
+    /// Summarize an alert's confirmations; `None` if no user responded.
+    ///
+    /// Timeouts and dismissals are counted but left out of the percentiles,
+    /// which use the nearest-rank method.
+    pub fn from_confirmations<'a>(
+        confirmations: impl IntoIterator<Item = &'a Confirmation>,
+    ) -> Option<Self> {
+        let mut latencies: Vec<u64> = Vec::new();
+        let mut timed_out: usize = 0;
+        for confirmation in confirmations {
+            match (confirmation.reason, confirmation.response_latency_ms) {
+                (ConfirmationReason::User, Some(latency)) => latencies.push(latency),
+                (ConfirmationReason::User, None) => {}
+                _ => timed_out += 1,
+            }
+        }
+        if latencies.is_empty() {
+            return None;
+        }
+        latencies.sort_unstable();
+        let rank = |percentile: usize| {
+            let index: usize = (latencies.len() * percentile).div_ceil(100);
+            latencies[index.saturating_sub(1)]
+        };
+        Some(Self {
+            responses: latencies.len(),
+            timed_out,
+            p50_ms: rank(50),
+            p95_ms: rank(95),
+        })
+    }
+}
+
+/// Tracks a team alert's confirmations against its [`Alert::quorum`], for a server
+#[derive(Debug, Clone)]
+pub struct QuorumTally {
+    alert_id: Uuid,
+    quorum: u32,
+    confirmed_by: Vec<String>,
+    met_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl QuorumTally {
+    pub fn new(alert_id: Uuid, quorum: u32) -> Self {
+        Self {
+            alert_id,
+            quorum,
+            confirmed_by: Vec::new(),
+            met_at: None,
+        }
+    }
+
+    /// Count a confirmation; returns the [`Message::QuorumMet`] to send the
+    /// rest of the team when this is the one that meets the quorum.
+    ///
+    /// Only people confirming count: timeouts and dismissals do not, and a
+    /// person confirming on two machines counts once. People are told apart by
+    /// operator id when one was given and by username otherwise.
+    pub fn record(&mut self, confirmation: &Confirmation) -> Option<Message> {
+        if confirmation.alert_id != self.alert_id
+            || confirmation.reason != ConfirmationReason::User
+            || confirmation.is_preview
+        {
+            return None;
+        }
+        let person: &str = confirmation
+            .operator_id
+            .as_deref()
+            .unwrap_or(&confirmation.username);
+        if self.met_at.is_some() || self.confirmed_by.iter().any(|p| p == person) {
+            return None;
+        }
+        self.confirmed_by.push(person.to_string());
+        if self.confirmed_by.len() < self.quorum as usize {
+            return None;
+        }
+        self.met_at = Some(confirmation.confirmed_at);
+        Some(Message::QuorumMet {
+            alert_id: self.alert_id,
+            confirmed_by: self.confirmed_by.clone(),
+        })
+    }
+
+    /// When the quorum was met, by the confirmation that met it
+    pub fn met_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.met_at
+    }
+
+    /// People counted so far, in the order they confirmed
+    pub fn confirmed_by(&self) -> &[String] {
+        &self.confirmed_by
+    }
+}
+
+/// A signed alert, as broadcast over the multicast fallback channel.
+///
+/// `payload` is the [`Alert`] serialized as JSON and `signature` is the
+/// base64 HMAC-SHA256 of the payload bytes under the site's shared key.
+/// Agents drop envelopes whose signature does not verify.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct AlertEnvelope {
+    pub payload: String,
+    pub signature: String,
+}
+
+/// What an agent on an isolated network hands over on removable media.
+///
+/// Carried as the payload of a [`SignedBundle`]. The server imports bundles
+/// from each agent in `sequence` order and refuses one whose sequence it has
+/// already seen, so a copied bundle cannot be imported twice.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct OfflineBundle {
+    pub client_id: String,
+    pub hostname: String,
+    /// One more than the agent's previous export, starting at 1
+    pub sequence: u64,
+    pub exported_at: chrono::DateTime<chrono::Utc>,
+    /// When the previous export was made; absent for the first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    /// Confirmations and delivery reports the agent could not send
+    #[serde(default)]
+    pub records: Vec<OfflineRecord>,
+    /// Alerts received since `since`
+    #[serde(default)]
+    pub alerts: Vec<OfflineAlert>,
+}
+
+/// A message held for the server that went out in an [`OfflineBundle`] instead
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OfflineRecord {
+    /// A confirmation, dismissal, or timeout
+    Confirmation {
+        confirmation: Confirmation,
+    },
+    DeliveryStatus {
+        status: DeliveryStatus,
+    },
+}
+
+/// An alert the agent received, from its history
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct OfflineAlert {
+    pub alert_id: uuid::Uuid,
+    pub level: AlertLevel,
+    pub title: String,
+    pub sent_at: chrono::DateTime<chrono::Utc>,
+    pub received_at: chrono::DateTime<chrono::Utc>,
+    pub requires_confirmation: bool,
+    pub origin: AlertOrigin,
+}
+
+/// An [`OfflineBundle`] as written to removable media.
+///
+/// `payload` is the bundle serialized as JSON and `signature` is the base64
+/// HMAC-SHA256 of the payload bytes under the site's offline export key.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct SignedBundle {
+    pub payload: String,
+    pub signature: String,
+}
+
+/// Outcome of fetching an alert's attachment
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachmentState {
+    /// Downloaded and matched its checksum
+    Verified,
+    /// Could not be downloaded, was too large, or did not match its checksum
+    Failed,
+}
+
+/// What happened to an alert's sound, when it did not simply play
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SoundDelivery {
+    /// The client's [`SoundPolicy`] does not allow the sound
+    SuppressedByPolicy,
+}
+
+/// What happened to the alarm panel an alert should have lit
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AnnunciatorState {
+    /// The serial port could not be written after retrying
+    Failed,
+}
+
+/// Outcome of the POST to an alert's `confirm_callback_url`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CallbackState {
+    /// The URL answered with a success status
+    Delivered,
+    /// Refused by the client's allow-list, or still failing after one retry
+    Failed,
+}
+
+/// What happened to an alert the client did not show when it arrived
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryOutcome {
+    /// Dropped by the client's alert rate limit; recorded in its history only
+    RateLimited,
+    /// Silenced by a [`SuppressionWindow`]; recorded in its history only
+    SuppressedByWindow,
+    /// Arrived while the workstation was locked; sounded at once and shown when it was unlocked
+    ShownOnUnlock,
+    /// Not meant for machines in the client's role; recorded in its history only
+    HiddenByRole,
+    /// Past its `expires_at` when it arrived; recorded in its history only
+    Expired,
+    /// Dropped from the client's full alert queue for alerts ranking above it;
+    /// never shown or recorded
+    QueueFull,
+    /// Already received within the client's dedup window, by id or, under
+    /// another id named in `detail`, by content; not shown or sounded again
+    Duplicate,
+}
+
+/// Whether a client sounded and showed an alert, and which of its delivery
+/// rules changed that
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct DecisionSummary {
+    /// A sound was started
+    pub sound: bool,
+    /// Put on screen at once, as a toast or, without toasts, in the details window
+    pub toast: bool,
+    /// Rules that kept back the sound or toast or changed how the alert was
+    /// shown, in the order the client consulted them, e.g. `["quiet_hours"]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub decided_by: Vec<String>,
+}
+
+/// A scheduled window in which matching alerts are recorded but not shown or
+/// sounded, e.g. while facilities tests the fire alarms
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct SuppressionWindow {
+    /// Sending a window with the same id replaces it
+    pub id: Uuid,
+    pub starts_at: chrono::DateTime<chrono::Utc>,
+    pub ends_at: chrono::DateTime<chrono::Utc>,
+    /// Levels silenced; empty silences every level except Emergency
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub levels: Vec<AlertLevel>,
+    /// Alert categories silenced; empty silences any category
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<String>,
+    pub reason: String,
+    /// Agents outside this location ignore the window; `None` targets everyone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+}
+
+impl SuppressionWindow {
+    /// Whether `now` falls within the window
+    pub fn is_active(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.starts_at <= now && now < self.ends_at
+    }
+
+    /// Whether an agent at `agent` should apply this window
+    pub fn targets(&self, agent: Option<&Location>) -> bool {
+        match (&self.location, agent) {
+            (Some(target), Some(agent)) => target.matches(agent),
+            _ => true,
+        }
+    }
+
+    /// Whether the window silences `alert` at `now`.
+    ///
+    /// Emergency alerts are only covered when `levels` names Emergency.
+    pub fn covers(&self, alert: &Alert, now: chrono::DateTime<chrono::Utc>) -> bool {
+        let level: bool = if self.levels.is_empty() {
+            alert.level != AlertLevel::Emergency
+        } else {
+            self.levels.contains(&alert.level)
+        };
+        let category: bool = self.categories.is_empty()
+            || alert.category.as_deref().is_some_and(|category| {
+                self.categories
+                    .iter()
+                    .any(|c| c.eq_ignore_ascii_case(category))
+            });
+        self.is_active(now) && level && category
+    }
+}
+
+/// Why a client reported an [`Message::AlertError`]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertErrorReason {
+    /// Alerts are arriving faster than the client's rate limit and are being shed
+    Overloaded,
+    /// A sealed alert could not be decrypted with this client's key
+    Undecryptable,
+    /// An alert failed validation, e.g. an empty title or an unknown level,
+    /// and was not shown; the detail names each field and its problem
+    Invalid,
+    /// An alert, or a cancellation of one, was unsigned or its signature did
+    /// not match the key the client shares with the server, and was ignored
+    BadSignature,
+}
+
+/// Per-alert delivery report sent from client to server
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct DeliveryStatus {
+    pub alert_id: Uuid,
+    pub client_id: String,
+    pub reported_at: chrono::DateTime<chrono::Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<AttachmentState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sound: Option<SoundDelivery>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<DeliveryOutcome>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annunciator: Option<AnnunciatorState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback: Option<CallbackState>,
+    /// Why the attachment, annunciator or callback failed, or the suppression window's reason
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// How the client decided to deliver the alert; sent once, when it is first handled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decision: Option<DecisionSummary>,
+    /// The `translations` tag the alert was shown in, sent with the
+    /// decision; left out when it was shown in its own title and message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+}
+
+/// Limits on the sounds a client plays, whatever the alert asks for.
+///
+/// The server keeps a default policy plus overrides per group and per
+/// client; unset fields defer to the layer below, see [`SoundPolicy::merged`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct SoundPolicy {
+    /// Loudest playback allowed, from 0.0 to 1.0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_volume: Option<f32>,
+    /// Sound files alerts may play; any other sound is suppressed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_sounds: Option<Vec<String>>,
+    /// Show alerts without playing any sound
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visual_only: Option<bool>,
+}
+
+impl SoundPolicy {
+    /// Combine layers from least to most specific, e.g. `[default, group, client]`;
+    /// each field comes from the last layer that sets it
+    pub fn merged<'a>(layers: impl IntoIterator<Item = &'a SoundPolicy>) -> SoundPolicy {
+        layers
+            .into_iter()
+            .fold(SoundPolicy::default(), |merged, layer| SoundPolicy {
+                max_volume: layer.max_volume.or(merged.max_volume),
+                allowed_sounds: layer.allowed_sounds.clone().or(merged.allowed_sounds),
+                visual_only: layer.visual_only.or(merged.visual_only),
+            })
+    }
+
+    /// Whether `sound_file` may play; file names compare case-insensitively
+    pub fn permits(&self, sound_file: &str) -> bool {
+        if self.visual_only == Some(true) {
+            return false;
+        }
+        match &self.allowed_sounds {
+            Some(allowed) => allowed.iter().any(|a| a.eq_ignore_ascii_case(sound_file)),
+            None => true,
+        }
+    }
+
+    /// Cap `volume` at the policy's maximum
+    pub fn limit_volume(&self, volume: f32) -> f32 {
+        match self.max_volume {
+            Some(max) => volume.min(max),
+            None => volume,
+        }
+    }
+}
+
+/// Host health sampled for status reports; values that could not be read are omitted
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct SystemHealth {
+    /// Machine-wide CPU use since the previous sample
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_percent: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_available_bytes: Option<u64>,
+    /// Free space on the volume holding the agent's data directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_disk_free_bytes: Option<u64>,
+    /// Whether an audio output device is present
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_available: Option<bool>,
+    /// Whether the default output device is muted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_muted: Option<bool>,
+}
+
+impl SystemHealth {
+    pub fn is_empty(&self) -> bool {
+        *self == SystemHealth::default()
+    }
+}
+
+/// How an agent's run ended
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownReason {
+    /// Stopped when asked to, e.g. by the service manager
+    Clean,
+    /// Exited to be restarted into a staged update
+    Update,
+    /// Stopped on a fatal error, including failing to start
+    Crash,
+    /// A panic; a task that panicked without taking the agent down is reported
+    /// only if the agent then stopped without recording anything else
+    Panic,
+    /// Nothing was recorded, e.g. after a power loss or on the first start
+    #[default]
+    Unknown,
+}
+
+/// What an agent recorded about how its previous run ended, sent when it registers
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct ShutdownRecord {
+    pub reason: ShutdownReason,
+    /// Absent when the reason is unknown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Agent version that stopped; absent when the reason is unknown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// First 8 bytes of the SHA-256 of the panic message, in hex, so repeated
+    /// panics can be told apart without sending the message itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub panic_digest: Option<String>,
+}
+
+/// What the agent found it can do at its last self-check; alerts are
+/// presented with whatever is left when something is missing
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct Capabilities {
+    /// Toast notifications are enabled for the agent
+    pub toasts: bool,
+    /// An audio output device is present
+    pub audio: bool,
+    /// The data directory holding history and state can be written
+    pub data_dir_writable: bool,
+    /// Entries can be written to the Windows event log
+    pub event_log: bool,
+    /// Attachments can be downloaded into the data directory
+    pub attachment_cache: bool,
+}
+
+impl Capabilities {
+    /// Everything available, as assumed until a self-check says otherwise
+    pub fn all() -> Self {
+        Self {
+            toasts: true,
+            audio: true,
+            data_dir_writable: true,
+            event_log: true,
+            attachment_cache: true,
+        }
+    }
+}
+
+/// Liveness details an agent adds to its heartbeats.
+///
+/// Every field is optional, so a bare `{"type": "heartbeat"}` from an older
+/// agent or from the server still parses.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct HeartbeatStats {
+    /// Seconds since the agent started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uptime_secs: Option<u64>,
+    /// Seconds since the agent last handled an alert; omitted until it has handled one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_alert_secs: Option<u64>,
+    /// Alerts shown and still waiting for the user to confirm them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_confirmations: Option<usize>,
+    /// When the connection carrying this heartbeat was established
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connected_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// The sending agent, so a heartbeat can be read without its connection's registration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// Version of the agent sending it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_version: Option<String>,
+    /// Counts up from 1 on each connection; echoed in the server's [`Message::HeartbeatAck`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// Milliseconds the agent's clock is ahead of the server's, negative when
+    /// behind, as measured at registration; omitted until measured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_skew_ms: Option<i64>,
+}
+
+/// Periodic health report sent from client to server
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct AgentStatus {
+    pub client_id: String,
+    pub reported_at: chrono::DateTime<chrono::Utc>,
+    /// Alerts received but not yet handled
+    #[serde(default)]
+    pub alert_queue_depth: usize,
+    #[serde(default)]
+    pub alert_queue_capacity: usize,
+    /// Alerts dropped since startup because the alert queue stayed full
+    #[serde(default)]
+    pub alerts_shed: u64,
+    /// Info and Warning alerts shed since startup by the alert rate limit; omitted while zero
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub alerts_rate_limited: u64,
+    /// Critical and Emergency alerts shed since startup by their higher rate limit; omitted while zero
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub urgent_alerts_rate_limited: u64,
+    /// Confirmations waiting in the outbound queue
+    #[serde(default)]
+    pub confirmation_queue_depth: usize,
+    /// Confirmations the outbound queue can hold, which is its whole capacity
+    #[serde(default)]
+    pub confirmation_queue_capacity: usize,
+    /// Messages of every kind waiting to be sent to the server
+    #[serde(default)]
+    pub outbound_queue_depth: usize,
+    /// Omitted by agents that do not report it
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub outbound_queue_capacity: usize,
+    /// Messages dropped since startup because the outbound queue was full; omitted while zero
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub outbound_dropped: u64,
+    /// The confirmations among `outbound_dropped`; omitted while zero
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub confirmations_dropped: u64,
+    /// Alerts are waiting but the agent has stopped handling them; omitted while false
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub pipeline_stalled: bool,
+    /// Times the alert pipeline has stalled since startup; omitted while zero
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub pipeline_stalls: u64,
+    /// Times the agent's wall clock has jumped by more than 30 seconds since
+    /// startup, e.g. an NTP correction; omitted while zero
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub clock_jumps: u64,
+    /// Messages from the server skipped since startup because they could not
+    /// be read; omitted while zero
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub unreadable_messages: u64,
+    /// A server has announced it is down for maintenance and the window has not
+    /// elapsed; omitted while false
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub in_maintenance_window: bool,
+    /// Omitted when nothing could be sampled
+    #[serde(default, skip_serializing_if = "SystemHealth::is_empty")]
+    pub system: SystemHealth,
+    /// Self-update progress; omitted by agents with self-update disabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update: Option<UpdateStatus>,
+    /// Version of the sound pack in use; omitted while playing loose sound files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sound_pack_version: Option<u32>,
+    /// Where recent alerts spent their time on the way to the user; omitted
+    /// until an alert has been handled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivery_timing: Option<DeliveryTiming>,
+}
+
+/// Time recent alerts spent in each stage of delivery.
+///
+/// A stage is omitted when none of the alerts went through it, e.g. `toast`
+/// while every alert was suppressed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct DeliveryTiming {
+    /// Alerts the figures are drawn from
+    pub samples: usize,
+    /// From the sender's timestamp to the frame arriving on the socket: server
+    /// fan-out and network transit, skewed by any difference between the clocks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transit: Option<StageTiming>,
+    /// Parsing the frame
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parse: Option<StageTiming>,
+    /// From parsed to placed on the alert queue, including unsealing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enqueue: Option<StageTiming>,
+    /// Waiting on the alert queue for the handler
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queued: Option<StageTiming>,
+    /// From the handler taking the alert to its toast being shown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub toast: Option<StageTiming>,
+    /// From the handler taking the alert to its sound starting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sound: Option<StageTiming>,
+}
+
+/// Percentiles of the time spent in one delivery stage, in milliseconds
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct StageTiming {
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+}
+
+/// An agent release on offer, from the server or a static manifest file
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct UpdateManifest {
+    /// Release version, dotted numbers such as `1.4.2`
+    pub version: String,
+    /// HTTPS URL of the agent binary
+    pub url: String,
+    /// Ed25519 signature of the binary by the release signing key, base64
+    pub signature: String,
+}
+
+/// A sound pack on offer: a zip of alert sounds with a `manifest.json` inside
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct SoundPackOffer {
+    /// HTTPS URL of the pack
+    pub url: String,
+    /// Pack version; agents only install versions newer than the one in use
+    pub version: u32,
+    /// Hex SHA-256 of the zip file
+    pub sha256: String,
+}
+
+/// Where an agent is with self-updates
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct UpdateStatus {
+    /// Version running now
+    pub current_version: String,
+    /// Verified and swapped in, to run from the next start; omitted when none is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staged_version: Option<String>,
+    /// When the agent last looked for, or was offered, a release
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_check_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Why the last check or install failed; cleared by the next one that succeeds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Message types for WebSocket communication
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Alert {
+        alert: Alert,
+    },
+    /// Server to client: several alerts at once, e.g. the backlog replayed
+    /// after a reconnect. The client handles them oldest first.
+    AlertBatch {
+        #[serde(flatten)]
+        batch: AlertBatch,
+    },
+    Confirmation {
+        confirmation: Confirmation,
+    },
+    /// Client to server: nobody confirmed an alert before its timeout, and
+    /// the agent stopped waiting on it.
+    ///
+    /// Sent in place of a confirmation, to servers whose registration ack
+    /// asked for it.
+    ConfirmationTimeout {
+        alert_id: Uuid,
+        client_id: String,
+        /// By the server's clock, as for a confirmation
+        timed_out_at: chrono::DateTime<chrono::Utc>,
+        /// The timeout passed while nobody was using the machine
+        #[serde(default, skip_serializing_if = "is_false")]
+        idle: bool,
+    },
+    Heartbeat {
+        #[serde(flatten)]
+        stats: HeartbeatStats,
+    },
+    /// Server to client: a heartbeat arrived.
+    ///
+    /// Once a server has acknowledged one heartbeat on a connection, the
+    /// client reconnects if several in a row then go unacknowledged.
+    HeartbeatAck {
+        /// The `seq` of the heartbeat acknowledged
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    Register {
+        client_id: String,
+        hostname: String,
+        /// Where the agent is, for location-targeted routing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        location: Option<Location>,
+        /// A passive second connection kept for failover; sent again as
+        /// `false` on the same connection when the agent promotes it
+        #[serde(default, skip_serializing_if = "is_false")]
+        standby: bool,
+        /// X25519 public key, base64, that alerts for this client can be sealed to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encryption_key: Option<String>,
+        /// Result of the agent's latest self-check
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capabilities: Option<Capabilities>,
+        /// How the agent's previous run ended
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        previous_shutdown: Option<ShutdownRecord>,
+        /// Timestamp of the newest alert the agent has received, for the
+        /// server to replay those issued after it as `missed`; absent when it
+        /// has received none
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        since: Option<chrono::DateTime<chrono::Utc>>,
+        /// Version of the sound pack in use, if any
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sound_pack_version: Option<u32>,
+        /// What the machine is used for, e.g. `workstation` or `signage`, for
+        /// routing alerts with a `visibility` list
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        machine_role: Option<String>,
+        /// Groups the agent belongs to, e.g. `ops`, for alerts with `target_groups`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        groups: Vec<String>,
+        /// Alert categories the agent has settings for, so the server can
+        /// warn of alerts sent with a category no agent knows
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        categories: Vec<String>,
+        /// The server URL this connection was opened to, so a server reached as
+        /// a fallback can tell which endpoint the agent landed on
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        server_url: Option<String>,
+        /// Encodings the agent reads besides JSON, which it always reads;
+        /// the server may send any of them from then on
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        supported_encodings: Vec<Encoding>,
+        /// The agent's [`PROTOCOL_VERSION`]; left out by agents older than the field
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        protocol_version: Option<u32>,
+        /// The agent can wrap messages in [`Envelope`]s, should the server's
+        /// ack ask for them
+        #[serde(default, skip_serializing_if = "is_false")]
+        envelopes: bool,
+        /// The agent can report timeouts as [`Message::ConfirmationTimeout`],
+        /// should the server's ack ask for them
+        #[serde(default, skip_serializing_if = "is_false")]
+        confirmation_timeouts: bool,
+    },
+    /// Server to client: the registration was accepted. The client waits for
+    /// this before serving the connection, and drops a connection whose
+    /// server does not answer.
+    RegisterAck {
+        /// Shown on the client's toasts, e.g. "EMNS"
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        server_name: Option<String>,
+        /// Shown after the name, e.g. "production" or "test"
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        environment: Option<String>,
+        /// Encoding the agent is to send in on this connection, one it
+        /// offered; JSON when unset
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encoding: Option<Encoding>,
+        /// Server software and version, for the agent's log
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        server_version: Option<String>,
+        /// Protocol version the server speaks on this connection; an agent
+        /// speaking another one drops the connection
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        protocol_version: Option<u32>,
+        /// The server's clock as it sent the ack. The agent measures how far
+        /// its own clock is off and stamps confirmations by the server's time.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        server_time: Option<chrono::DateTime<chrono::Utc>>,
+        /// Every message after this ack goes in an [`Envelope`], both ways.
+        /// Only sent to agents that offered envelopes; the rest keep sending
+        /// bare messages
+        #[serde(default, skip_serializing_if = "is_false")]
+        envelopes: bool,
+        /// Alerts nobody confirmed in time are reported as
+        /// [`Message::ConfirmationTimeout`]. Only sent to agents that offered
+        /// them; the rest keep sending a [`Confirmation`] with a timed-out
+        /// `reason`
+        #[serde(default, skip_serializing_if = "is_false")]
+        confirmation_timeouts: bool,
+    },
+    /// Server to client: the registration was refused, e.g. for a missing or
+    /// wrong token. The client backs off for its longest reconnect delay, as
+    /// retrying sooner will not change the answer.
+    RegisterRejected {
+        reason: String,
+    },
+    /// Client to server: the agent is stopping and closing this connection,
+    /// having sent everything it had queued
+    Unregister {
+        client_id: String,
+        /// `clean` when stopped by the service manager, `update` when
+        /// restarting into a staged release
+        reason: ShutdownReason,
+    },
+    Status {
+        status: AgentStatus,
+    },
+    /// Copy of an alert raised locally on a client, for the server's visibility
+    LocalAlert {
+        client_id: String,
+        alert: Alert,
+    },
+    /// Progress of an alert's delivery beyond the toast, e.g. its attachment
+    DeliveryStatus {
+        status: DeliveryStatus,
+    },
+    /// Client to server: the client cannot handle alerts as sent
+    AlertError {
+        client_id: String,
+        reason: AlertErrorReason,
+        /// The alert concerned, when the error is about one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        alert_id: Option<Uuid>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+    /// Client to server: an alert reached its `expires_at` on this machine.
+    ///
+    /// Sent in place of a confirmation: no confirmation follows for the alert.
+    AlertExpired {
+        client_id: String,
+        alert_id: Uuid,
+        /// Expired while up awaiting confirmation; otherwise it had expired
+        /// before it arrived and was never shown
+        #[serde(default, skip_serializing_if = "is_false")]
+        unconfirmed: bool,
+    },
+    /// Client to server: a message from the server could not be read. The
+    /// client skips it and stays connected.
+    Error {
+        /// What could not be read, e.g. the start of the message
+        context: String,
+        /// Why it could not be read
+        detail: String,
+    },
+    /// Server to client: settings managed centrally; fields left out are unchanged
+    ConfigUpdate {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sound_policy: Option<SoundPolicy>,
+    },
+    /// Server to client: silence matching alerts for a while
+    Suppression {
+        #[serde(flatten)]
+        window: SuppressionWindow,
+    },
+    /// Server to client: end a suppression window early
+    CancelSuppression {
+        id: Uuid,
+    },
+    /// Server to client, just before a graceful shutdown: the server expects to be
+    /// back by `resume_expected_at`.
+    ///
+    /// Until then the client reconnects less often and does not treat the lost
+    /// connection as a fault.
+    ServerShutdown {
+        resume_expected_at: chrono::DateTime<chrono::Utc>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// Server to client: a newer agent release is available; agents with
+    /// self-update enabled download, verify and stage it
+    UpdateAvailable {
+        #[serde(flatten)]
+        manifest: UpdateManifest,
+    },
+    /// Server to client: switch to a newer sound pack; agents download and
+    /// check it, then play from it
+    SoundPack {
+        #[serde(flatten)]
+        pack: SoundPackOffer,
+    },
+    /// Server to client: enough of the team has acknowledged a quorum alert.
+    ///
+    /// The client stops escalating it and says who responded, but leaves it
+    /// up for its own user to confirm.
+    QuorumMet {
+        alert_id: Uuid,
+        /// Who met the quorum, by operator id or username
+        confirmed_by: Vec<String>,
+    },
+    /// Server to client: an alert was sent by mistake or is over.
+    ///
+    /// The client takes its toast down and stops waiting on it without
+    /// confirming it, then says in a short info toast that it was cancelled.
+    /// An alert the client is not waiting on is ignored.
+    CancelAlert {
+        alert_id: Uuid,
+        /// Shown in the info toast, e.g. "sent in error"
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+        /// Base64 HMAC-SHA256 over [`Message::cancel_signing_payload`] with
+        /// the key alerts are signed with; agents holding that key ignore
+        /// cancellations without it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
+    },
+    /// Client to server, right after registering: alerts still awaiting confirmation here
+    PendingSync {
+        pending_alert_ids: Vec<Uuid>,
+    },
+    /// Server to client: the reply to [`Message::PendingSync`], sorting every reported alert.
+    ///
+    /// The client stops waiting on cancelled and expired alerts without
+    /// confirming them. Alerts the server never sent are listed as still active.
+    PendingSyncResult {
+        #[serde(default)]
+        still_active: Vec<Uuid>,
+        #[serde(default)]
+        cancelled: Vec<Uuid>,
+        #[serde(default)]
+        expired: Vec<Uuid>,
+    },
+    /// Either way, once envelopes are on: the message in the [`Envelope`]
+    /// with this id was handled; for an alert, queued to be shown
+    Ack {
+        message_id: Uuid,
+    },
+    /// Either way, once envelopes are on: the message in the [`Envelope`]
+    /// with this id was not handled
+    Nack {
+        message_id: Uuid,
+        reason: NackReason,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+}
+
+impl Message {
+    /// A heartbeat without any [`HeartbeatStats`], as the server sends
+    pub fn heartbeat() -> Self {
+        Message::Heartbeat {
+            stats: HeartbeatStats::default(),
+        }
+    }
+
+    /// The bytes a [`Message::CancelAlert`]'s `signature` is computed over:
+    /// netstrings of `emns-cancel-v1` and the alert id in lowercase
+    /// hyphenated form, then `reason` and the reason as sent if there is one
+    pub fn cancel_signing_payload(alert_id: Uuid, reason: Option<&str>) -> Vec<u8> {
+        let mut fields: Vec<String> = vec![
+            CANCEL_SIGNATURE_CONTEXT.to_string(),
+            alert_id.hyphenated().to_string(),
+        ];
+        if let Some(reason) = reason {
+            fields.push("reason".to_string());
+            fields.push(reason.to_string());
+        }
+        netstrings(&fields)
+    }
+}
+
+/// Each field as `<byte length>:<bytes>,`, one after another
+fn netstrings(fields: &[String]) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::new();
+    for field in fields {
+        payload.extend_from_slice(format!("{}:", field.len()).as_bytes());
+        payload.extend_from_slice(field.as_bytes());
+        payload.push(b',');
+    }
+    payload
+}
+
+impl Alert {
+    /// The bytes an alert's `signature` is computed over.
+    ///
+    /// Netstrings (`<byte length>:<bytes>,`) of, in order: `emns-alert-v2`,
+    /// the id in lowercase hyphenated form, the title and the message as
+    /// sent, the level as on the wire, the timestamp in whole milliseconds
+    /// since the Unix epoch, and `requires_confirmation` as `true` or
+    /// `false`. Then a name and a value for each other field that is set, in
+    /// the order they are declared, so everything but the signature itself is
+    /// covered, the sealed body included. The server guide spells out how
+    /// each field is written.
+    pub fn signing_payload(&self) -> Vec<u8> {
+        let mut fields: Vec<String> = vec![
+            SIGNATURE_CONTEXT.to_string(),
+            self.id.hyphenated().to_string(),
+            self.title.clone(),
+            self.message.clone(),
+            self.level.as_str().to_ascii_lowercase(),
+            self.timestamp.timestamp_millis().to_string(),
+            self.requires_confirmation.to_string(),
+        ];
+        let mut field = |name: &str, value: String| {
+            fields.push(name.to_string());
+            fields.push(value);
+        };
+        // A list is its length, then each item under the same name
+        fn list(field: &mut impl FnMut(&str, String), name: &str, items: &[String]) {
+            field(name, items.len().to_string());
+            for item in items {
+                field(name, item.clone());
+            }
+        }
+
+        if let Some(sound_file) = &self.sound_file {
+            field("sound_file", sound_file.clone());
+        }
+        if !self.origin.is_server() {
+            field("origin", "local".to_string());
+        }
+        if let Some(location) = &self.location {
+            let parts = [
+                ("location.site", &location.site),
+                ("location.building", &location.building),
+                ("location.floor", &location.floor),
+                ("location.room", &location.room),
+            ];
+            for (name, part) in parts {
+                if !part.is_any() {
+                    list(&mut field, name, part.values());
+                }
+            }
+        }
+        if let Some(options) = &self.response_options {
+            field("response_options", options.len().to_string());
+            for option in options {
+                field("response_options.id", option.id.clone());
+                field("response_options.label", option.label.clone());
+                let (kind, note) = match &option.response {
+                    ConfirmationResponse::Acknowledged => ("acknowledged", None),
+                    ConfirmationResponse::CannotComply { note } => ("cannot_comply", note.as_ref()),
+                    ConfirmationResponse::NotApplicable => ("not_applicable", None),
+                };
+                field("response_options.response", kind.to_string());
+                if let Some(note) = note {
+                    field("response_options.note", note.clone());
+                }
+            }
+        }
+        if let Some(attachment) = &self.attachment {
+            field("attachment.url", attachment.url.clone());
+            field("attachment.filename", attachment.filename.clone());
+            field("attachment.sha256", attachment.sha256.clone());
+            field("attachment.size", attachment.size.to_string());
+        }
+        if self.missed {
+            field("missed", "true".to_string());
+        }
+        if let Some(category) = &self.category {
+            field("category", category.clone());
+        }
+        if let Some(toast) = &self.toast {
+            if let Some(scenario) = &toast.scenario {
+                field("toast.scenario", scenario.clone());
+            }
+            if let Some(duration) = &toast.duration {
+                field("toast.duration", duration.clone());
+            }
+            if let Some(suppress_popup) = toast.suppress_popup {
+                field("toast.suppress_popup", suppress_popup.to_string());
+            }
+        }
+        if self.is_preview {
+            field("is_preview", "true".to_string());
+        }
+        if let Some(sealed) = &self.sealed {
+            field("sealed.key_id", sealed.key_id.clone());
+            field("sealed.ephemeral_key", sealed.ephemeral_key.clone());
+            field("sealed.nonce", sealed.nonce.clone());
+            field("sealed.ciphertext", sealed.ciphertext.clone());
+        }
+        if let Some(url) = &self.confirm_callback_url {
+            field("confirm_callback_url", url.clone());
+        }
+        if let Some(quorum) = self.quorum {
+            field("quorum", quorum.to_string());
+        }
+        if let Some(visibility) = &self.visibility {
+            list(&mut field, "visibility", visibility);
+        }
+        if let Some(expires_at) = self.expires_at {
+            field("expires_at", expires_at.timestamp_millis().to_string());
+        }
+        if let Some(supersedes) = self.supersedes {
+            field("supersedes", supersedes.hyphenated().to_string());
+        }
+        if !self.target_groups.is_empty() {
+            list(&mut field, "target_groups", &self.target_groups);
+        }
+        if !self.target_hosts.is_empty() {
+            list(&mut field, "target_hosts", &self.target_hosts);
+        }
+        if let Some(url) = &self.url {
+            field("url", url.clone());
+        }
+        if let Some(image_url) = &self.image_url {
+            field("image_url", image_url.clone());
+        }
+        let mut translations: Vec<(&String, &Translation)> = self.translations.iter().collect();
+        translations.sort_by_key(|(tag, _)| *tag);
+        for (tag, translation) in translations {
+            field("translations", tag.clone());
+            field("translations.title", translation.title.clone());
+            field("translations.message", translation.message.clone());
+        }
+        if let Some(priority) = self.priority {
+            field("priority", priority.to_string());
+        }
+        if let Some(escalation) = &self.escalation {
+            if let Some(interval) = escalation.repeat_interval_secs {
+                field("escalation.repeat_interval_secs", interval.to_string());
+            }
+            if let Some(max_repeats) = escalation.max_repeats {
+                field("escalation.max_repeats", max_repeats.to_string());
+            }
+        }
+        if let Some(timeout) = self.confirm_timeout_secs {
+            field("confirm_timeout_secs", timeout.to_string());
+        }
+        netstrings(&fields)
+    }
+
+    /// Whether an agent at `agent` should show this alert
+    pub fn targets(&self, agent: Option<&Location>) -> bool {
+        match (&self.location, agent) {
+            (Some(target), Some(agent)) => target.matches(agent),
+            _ => true,
+        }
+    }
+
+    /// Whether this alert is meant for the agent on `hostname` in `groups`.
+    ///
+    /// Groups and hostnames match case-insensitively, and a host pattern
+    /// ending in `*` matches every hostname starting with the rest.
+    pub fn addressed_to(&self, hostname: &str, groups: &[String]) -> bool {
+        if self.target_groups.is_empty() && self.target_hosts.is_empty() {
+            return true;
+        }
+        let group: bool = self
+            .target_groups
+            .iter()
+            .any(|target| groups.iter().any(|g| g.eq_ignore_ascii_case(target)));
+        let hostname: String = hostname.to_ascii_lowercase();
+        let host: bool = self.target_hosts.iter().any(|pattern| {
+            let pattern: String = pattern.to_ascii_lowercase();
+            match pattern.strip_suffix('*') {
+                Some(prefix) => hostname.starts_with(prefix),
+                None => hostname == pattern,
+            }
+        });
+        group || host
+    }
+
+    /// Whether a machine in `role` may display this alert; roles match case-insensitively
+    pub fn visible_to(&self, role: &str) -> bool {
+        match &self.visibility {
+            Some(roles) => roles
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(role)),
+            None => true,
+        }
+    }
+
+    /// Get the sound file path, or default based on level
+    pub fn get_sound_file(&self) -> String {
+        self.sound_file.clone().unwrap_or_else(|| match self.level {
+            AlertLevel::Emergency | AlertLevel::Critical => "alarm_critical.wav".to_string(),
+            AlertLevel::Warning => "alarm_warning.wav".to_string(),
+            AlertLevel::Info => "notification.wav".to_string(),
+        })
+    }
+}
@@ -0,0 +1,130 @@
+//! Physical location of an agent, and the locations an alert is aimed at
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Site, building, floor, and room.
+///
+/// On a registration each field holds the agent's own value. On an alert each
+/// field lists the values it targets. A field left out matches anything.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct Location {
+    #[serde(default, skip_serializing_if = "LocationField::is_any")]
+    pub site: LocationField,
+    #[serde(default, skip_serializing_if = "LocationField::is_any")]
+    pub building: LocationField,
+    #[serde(default, skip_serializing_if = "LocationField::is_any")]
+    pub floor: LocationField,
+    #[serde(default, skip_serializing_if = "LocationField::is_any")]
+    pub room: LocationField,
+}
+
+impl Location {
+    /// Whether every field is left out
+    pub fn is_any(&self) -> bool {
+        self.fields().iter().all(|field| field.is_any())
+    }
+
+    /// Whether an agent at `agent` falls inside this target.
+    ///
+    /// A field missing on either side does not constrain the match, so an
+    /// agent that does not know its floor still receives floor-targeted alerts.
+    pub fn matches(&self, agent: &Location) -> bool {
+        self.fields()
+            .iter()
+            .zip(agent.fields())
+            .all(|(target, own)| target.matches(own))
+    }
+
+    fn fields(&self) -> [&LocationField; 4] {
+        [&self.site, &self.building, &self.floor, &self.room]
+    }
+}
+
+/// One value or a list of values; empty matches anything.
+///
+/// A single value is written as a plain string, several as an array.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(from = "OneOrMany", into = "OneOrMany")]
+pub struct LocationField(Vec<String>);
+
+impl LocationField {
+    pub fn new<I, S>(values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self(
+            values
+                .into_iter()
+                .map(Into::into)
+                .filter(|v: &String| !v.trim().is_empty())
+                .collect(),
+        )
+    }
+
+    pub fn is_any(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn values(&self) -> &[String] {
+        &self.0
+    }
+
+    /// Case-insensitive match of any of our values against any of `other`'s
+    fn matches(&self, other: &LocationField) -> bool {
+        if self.is_any() || other.is_any() {
+            return true;
+        }
+        self.0.iter().any(|ours| {
+            other
+                .0
+                .iter()
+                .any(|theirs| ours.trim().eq_ignore_ascii_case(theirs.trim()))
+        })
+    }
+}
+
+impl From<&str> for LocationField {
+    fn from(value: &str) -> Self {
+        Self::new([value])
+    }
+}
+
+/// Described by its wire form, since serde reads and writes it through [`OneOrMany`]
+impl JsonSchema for LocationField {
+    fn schema_name() -> String {
+        "LocationField".to_string()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        OneOrMany::json_schema(gen)
+    }
+}
+
+/// A single value as a plain string, or several as an array
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<OneOrMany> for LocationField {
+    fn from(value: OneOrMany) -> Self {
+        match value {
+            OneOrMany::One(value) => Self::new([value]),
+            OneOrMany::Many(values) => Self::new(values),
+        }
+    }
+}
+
+impl From<LocationField> for OneOrMany {
+    fn from(mut field: LocationField) -> Self {
+        if field.0.len() == 1 {
+            OneOrMany::One(field.0.remove(0))
+        } else {
+            OneOrMany::Many(field.0)
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! JSON Schema for the wire format, for producers and consumers not written in Rust.
+//!
+//! The schemas are derived from the same types serde uses, so they cannot
+//! drift from what the agent actually sends and accepts.
+
+use crate::{
+    AgentStatus, Alert, Confirmation, DeliveryStatus, Envelope, Message, OfflineBundle, SoundPolicy,
+};
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use std::path::{Path, PathBuf};
+
+/// Every published schema, named for its file
+pub fn schemas() -> Vec<(&'static str, RootSchema)> {
+    vec![
+        ("message", schema_for!(Message)),
+        ("envelope", schema_for!(Envelope)),
+        ("alert", schema_for!(Alert)),
+        ("confirmation", schema_for!(Confirmation)),
+        ("agent_status", schema_for!(AgentStatus)),
+        ("delivery_status", schema_for!(DeliveryStatus)),
+        ("sound_policy", schema_for!(SoundPolicy)),
+        ("offline_bundle", schema_for!(OfflineBundle)),
+    ]
+}
+
+/// Write each schema to `<dir>/<name>.schema.json`, returning the files written
+pub fn write_schemas(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)?;
+    schemas()
+        .into_iter()
+        .map(|(name, schema)| {
+            let path: PathBuf = dir.join(format!("{}.schema.json", name));
+            let json: String = serde_json::to_string_pretty(&schema)?;
+            std::fs::write(&path, json + "\n")?;
+            Ok(path)
+        })
+        .collect()
+}
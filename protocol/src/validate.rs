@@ -0,0 +1,408 @@
+//! Checks on an alert's fields beyond what parsing enforces, so that a server
+//! bug is reported as what is wrong with the alert rather than as a parse error
+
+use crate::encoding::msgpack_value;
+use crate::{Alert, AlertEscalation, Translation};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::fmt;
+use uuid::Uuid;
+
+/// Longest title an alert may carry, in characters
+pub const MAX_ALERT_TITLE_CHARS: usize = 1_000;
+/// Longest message an alert may carry, in characters
+pub const MAX_ALERT_MESSAGE_CHARS: usize = 50_000;
+/// How far past the receiver's clock an alert's timestamp may be, in seconds
+pub const MAX_ALERT_TIMESTAMP_AHEAD_SECS: i64 = 24 * 60 * 60;
+/// Longest `url` or `image_url` an alert may carry, in characters
+pub const MAX_ALERT_URL_CHARS: usize = 2_048;
+/// Highest `priority` an alert may carry
+pub const MAX_ALERT_PRIORITY: u8 = 100;
+/// Shortest `escalation.repeat_interval_secs` an alert may ask for
+pub const MIN_REPEAT_INTERVAL_SECS: u32 = 10;
+/// Most `escalation.max_repeats` an alert may ask for
+pub const MAX_ALERT_REPEATS: u32 = 60;
+/// Longest `confirm_timeout_secs` an alert may ask for
+pub const MAX_CONFIRM_TIMEOUT_SECS: u32 = 86_400;
+
+/// Levels an alert may have, as sent
+const LEVELS: [&str; 4] = ["info", "warning", "critical", "emergency"];
+
+/// One field of an alert and what is wrong with it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldProblem {
+    pub field: &'static str,
+    pub problem: String,
+}
+
+impl fmt::Display for FieldProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.problem)
+    }
+}
+
+/// An alert that failed validation, with every problem found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidAlert {
+    /// `None` when the alert's id itself could not be read
+    pub alert_id: Option<Uuid>,
+    pub problems: Vec<FieldProblem>,
+}
+
+impl fmt::Display for InvalidAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let problems: Vec<String> = self.problems.iter().map(|p| p.to_string()).collect();
+        write!(f, "{}", problems.join("; "))
+    }
+}
+
+impl std::error::Error for InvalidAlert {}
+
+impl InvalidAlert {
+    /// What is wrong with the alert in a JSON message that did not parse, or
+    /// `None` when the message is not an alert
+    pub fn from_json(text: &str, now: DateTime<Utc>) -> Option<Self> {
+        Self::from_message(&serde_json::from_str(text).ok()?, now)
+    }
+
+    /// What is wrong with the alert in a MessagePack message that did not
+    /// parse, or `None` when the message is not an alert
+    pub fn from_msgpack(bytes: &[u8], now: DateTime<Utc>) -> Option<Self> {
+        Self::from_message(&msgpack_value(bytes).ok()?, now)
+    }
+
+    /// What is wrong with the alert in `message`, a [`Message::Alert`](crate::Message::Alert)
+    /// as a JSON value, or `None` when it is some other message
+    pub fn from_message(message: &Value, now: DateTime<Utc>) -> Option<Self> {
+        if message.get("type").and_then(Value::as_str) != Some("alert") {
+            return None;
+        }
+        let Some(alert) = message.get("alert").filter(|alert| alert.is_object()) else {
+            return Some(Self {
+                alert_id: None,
+                problems: vec![problem("alert", "missing or not an object")],
+            });
+        };
+        let mut problems: Vec<FieldProblem> = Vec::new();
+        let alert_id: Option<Uuid> = alert
+            .get("id")
+            .and_then(Value::as_str)
+            .and_then(|id| Uuid::parse_str(id).ok());
+        if alert_id.is_none() {
+            problems.push(problem("id", "missing or not a UUID"));
+        }
+        for (field, max_chars) in [
+            ("title", MAX_ALERT_TITLE_CHARS),
+            ("message", MAX_ALERT_MESSAGE_CHARS),
+        ] {
+            match alert.get(field).map(Value::as_str) {
+                Some(Some(text)) => check_text(field, text, max_chars, &mut problems),
+                _ => problems.push(problem(field, "missing or not a string")),
+            }
+        }
+        match alert.get("level") {
+            Some(Value::String(level)) if LEVELS.contains(&level.as_str()) => {}
+            Some(Value::String(level)) => problems.push(problem(
+                "level",
+                format!(
+                    "unknown level \"{}\", expected info, warning, critical or emergency",
+                    level
+                ),
+            )),
+            _ => problems.push(problem("level", "missing or not a string")),
+        }
+        match alert.get("timestamp").map(Value::as_str) {
+            Some(Some(timestamp)) => match DateTime::parse_from_rfc3339(timestamp) {
+                Ok(timestamp) => check_timestamp(timestamp.with_timezone(&Utc), now, &mut problems),
+                Err(_) => problems.push(problem("timestamp", "not an RFC 3339 time")),
+            },
+            _ => problems.push(problem("timestamp", "missing or not a string")),
+        }
+        match alert.get("sound_file") {
+            None | Some(Value::Null) => {}
+            Some(Value::String(name)) => check_sound_file(name, &mut problems),
+            Some(_) => problems.push(problem("sound_file", "not a string")),
+        }
+        for field in ["url", "image_url"] {
+            match alert.get(field) {
+                None | Some(Value::Null) => {}
+                Some(Value::String(url)) => check_link(field, url, &mut problems),
+                Some(_) => problems.push(problem(field, "not a string")),
+            }
+        }
+        match alert.get("priority") {
+            None | Some(Value::Null) => {}
+            Some(priority) => match priority.as_u64() {
+                Some(priority) => check_priority(priority, &mut problems),
+                None => problems.push(problem("priority", "not a whole number")),
+            },
+        }
+        match alert.get("confirm_timeout_secs") {
+            None | Some(Value::Null) => {}
+            Some(secs) => match secs.as_u64() {
+                Some(secs) => check_confirm_timeout(secs, &mut problems),
+                None => problems.push(problem("confirm_timeout_secs", "not a whole number")),
+            },
+        }
+        match alert.get("escalation") {
+            None | Some(Value::Null) => {}
+            Some(escalation) => match serde_json::from_value::<AlertEscalation>(escalation.clone())
+            {
+                Ok(escalation) => check_escalation(&escalation, &mut problems),
+                Err(_) => problems.push(problem(
+                    "escalation",
+                    "repeat_interval_secs and max_repeats must be whole numbers",
+                )),
+            },
+        }
+        match alert.get("translations") {
+            None | Some(Value::Null) => {}
+            Some(Value::Object(translations)) => {
+                for (tag, translation) in translations {
+                    match serde_json::from_value::<Translation>(translation.clone()) {
+                        Ok(translation) => check_translation(tag, &translation, &mut problems),
+                        Err(_) => problems.push(problem(
+                            "translations",
+                            format!("{}: not a title and message", tag),
+                        )),
+                    }
+                }
+            }
+            Some(_) => problems.push(problem("translations", "not an object")),
+        }
+        if problems.is_empty() {
+            // Nothing in the checked fields; name whatever else serde tripped on
+            let detail: String = serde_json::from_value::<Alert>(alert.clone())
+                .err()
+                .map_or_else(|| "could not be read".to_string(), |e| e.to_string());
+            problems.push(problem("alert", detail));
+        }
+        Some(Self { alert_id, problems })
+    }
+}
+
+impl Alert {
+    /// Check the fields parsing leaves unchecked: a non-empty title and
+    /// message of reasonable length, a timestamp no more than
+    /// [`MAX_ALERT_TIMESTAMP_AHEAD_SECS`] past `now`, a sound file named
+    /// without any path, links that are plain http(s) URLs, a priority no
+    /// higher than [`MAX_ALERT_PRIORITY`], a confirm timeout no longer than
+    /// [`MAX_CONFIRM_TIMEOUT_SECS`], repeats within
+    /// [`MIN_REPEAT_INTERVAL_SECS`] and [`MAX_ALERT_REPEATS`], and
+    /// translations keyed by language tag whose text passes the same checks
+    pub fn validate(&self, now: DateTime<Utc>) -> Result<(), InvalidAlert> {
+        let mut problems: Vec<FieldProblem> = Vec::new();
+        check_text("title", &self.title, MAX_ALERT_TITLE_CHARS, &mut problems);
+        check_text(
+            "message",
+            &self.message,
+            MAX_ALERT_MESSAGE_CHARS,
+            &mut problems,
+        );
+        check_timestamp(self.timestamp, now, &mut problems);
+        if let Some(name) = &self.sound_file {
+            check_sound_file(name, &mut problems);
+        }
+        if let Some(url) = &self.url {
+            check_link("url", url, &mut problems);
+        }
+        if let Some(url) = &self.image_url {
+            check_link("image_url", url, &mut problems);
+        }
+        if let Some(priority) = self.priority {
+            check_priority(u64::from(priority), &mut problems);
+        }
+        if let Some(secs) = self.confirm_timeout_secs {
+            check_confirm_timeout(u64::from(secs), &mut problems);
+        }
+        if let Some(escalation) = &self.escalation {
+            check_escalation(escalation, &mut problems);
+        }
+        let mut tags: Vec<&String> = self.translations.keys().collect();
+        tags.sort();
+        for tag in tags {
+            check_translation(tag, &self.translations[tag], &mut problems);
+        }
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(InvalidAlert {
+            alert_id: Some(self.id),
+            problems,
+        })
+    }
+}
+
+fn problem(field: &'static str, problem: impl Into<String>) -> FieldProblem {
+    FieldProblem {
+        field,
+        problem: problem.into(),
+    }
+}
+
+fn check_text(field: &'static str, text: &str, max_chars: usize, problems: &mut Vec<FieldProblem>) {
+    let chars: usize = text.chars().count();
+    if text.trim().is_empty() {
+        problems.push(problem(field, "empty"));
+    } else if chars > max_chars {
+        problems.push(problem(
+            field,
+            format!("{} characters, more than {}", chars, max_chars),
+        ));
+    }
+}
+
+fn check_timestamp(timestamp: DateTime<Utc>, now: DateTime<Utc>, problems: &mut Vec<FieldProblem>) {
+    let ahead: i64 = (timestamp - now).num_seconds();
+    if ahead > MAX_ALERT_TIMESTAMP_AHEAD_SECS {
+        problems.push(problem(
+            "timestamp",
+            format!(
+                "{} is {} hours in the future",
+                timestamp.to_rfc3339(),
+                ahead / 3600
+            ),
+        ));
+    }
+}
+
+fn check_priority(priority: u64, problems: &mut Vec<FieldProblem>) {
+    if priority > u64::from(MAX_ALERT_PRIORITY) {
+        problems.push(problem(
+            "priority",
+            format!("{} is more than {}", priority, MAX_ALERT_PRIORITY),
+        ));
+    }
+}
+
+fn check_confirm_timeout(secs: u64, problems: &mut Vec<FieldProblem>) {
+    if secs > u64::from(MAX_CONFIRM_TIMEOUT_SECS) {
+        problems.push(problem(
+            "confirm_timeout_secs",
+            format!("{} is more than {}", secs, MAX_CONFIRM_TIMEOUT_SECS),
+        ));
+    }
+}
+
+fn check_escalation(escalation: &AlertEscalation, problems: &mut Vec<FieldProblem>) {
+    if let Some(secs) = escalation
+        .repeat_interval_secs
+        .filter(|secs| *secs < MIN_REPEAT_INTERVAL_SECS)
+    {
+        problems.push(problem(
+            "escalation",
+            format!(
+                "repeat_interval_secs {} is less than {}",
+                secs, MIN_REPEAT_INTERVAL_SECS
+            ),
+        ));
+    }
+    if let Some(repeats) = escalation
+        .max_repeats
+        .filter(|repeats| *repeats > MAX_ALERT_REPEATS)
+    {
+        problems.push(problem(
+            "escalation",
+            format!("max_repeats {} is more than {}", repeats, MAX_ALERT_REPEATS),
+        ));
+    }
+}
+
+/// A sound file is a bare file name in the agent's sounds directory
+fn check_sound_file(name: &str, problems: &mut Vec<FieldProblem>) {
+    let trimmed: &str = name.trim();
+    if trimmed.is_empty() {
+        problems.push(problem("sound_file", "empty"));
+    } else if trimmed == "." || trimmed == ".." {
+        problems.push(problem(
+            "sound_file",
+            format!("\"{}\" is not a file name", name),
+        ));
+    } else if name.contains(['/', '\\', ':']) || name.chars().any(char::is_control) {
+        problems.push(problem(
+            "sound_file",
+            format!("\"{}\" is not a bare file name", name.escape_debug()),
+        ));
+    }
+}
+
+/// Whether `url` is an absolute http(s) URL of reasonable length, without
+/// spaces or control characters.
+///
+/// Links are opened by the user's browser or downloaded, so only http(s) will
+/// do; anything else could launch whatever handles its scheme.
+pub fn is_http_url(url: &str) -> bool {
+    link_problem(url).is_none()
+}
+
+fn check_link(field: &'static str, url: &str, problems: &mut Vec<FieldProblem>) {
+    if let Some(detail) = link_problem(url) {
+        problems.push(problem(field, detail));
+    }
+}
+
+fn link_problem(url: &str) -> Option<String> {
+    let chars: usize = url.chars().count();
+    let host: Option<&str> = ["https://", "http://"].iter().find_map(|scheme| {
+        url.get(..scheme.len())
+            .filter(|start| start.eq_ignore_ascii_case(scheme))
+            .map(|_| &url[scheme.len()..])
+    });
+    if chars > MAX_ALERT_URL_CHARS {
+        Some(format!(
+            "{} characters, more than {}",
+            chars, MAX_ALERT_URL_CHARS
+        ))
+    } else if url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        Some(format!(
+            "\"{}\" contains spaces or control characters",
+            url.escape_debug()
+        ))
+    } else if !host.is_some_and(|host| !host.is_empty() && !host.starts_with(['/', '?', '#'])) {
+        Some(format!("\"{}\" is not an http(s) URL", url))
+    } else {
+        None
+    }
+}
+
+/// Whether `tag` is shaped like a BCP 47 language tag: a language of 2 to 8
+/// letters, then any subtags of 1 to 8 letters or digits, joined by `-`
+pub fn is_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let language: bool = subtags.next().is_some_and(|language| {
+        (2..=8).contains(&language.len()) && language.chars().all(|c| c.is_ascii_alphabetic())
+    });
+    language
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+/// A translation's problems are reported under `translations`, named by tag
+fn check_translation(tag: &str, translation: &Translation, problems: &mut Vec<FieldProblem>) {
+    if !is_language_tag(tag) {
+        problems.push(problem(
+            "translations",
+            format!("\"{}\" is not a language tag", tag.escape_debug()),
+        ));
+    }
+    let mut text: Vec<FieldProblem> = Vec::new();
+    check_text(
+        "title",
+        &translation.title,
+        MAX_ALERT_TITLE_CHARS,
+        &mut text,
+    );
+    check_text(
+        "message",
+        &translation.message,
+        MAX_ALERT_MESSAGE_CHARS,
+        &mut text,
+    );
+    problems.extend(text.into_iter().map(|p| {
+        problem(
+            "translations",
+            format!("{} {}: {}", tag, p.field, p.problem),
+        )
+    }));
+}
@@ -0,0 +1,132 @@
+//! Committed example payloads that every peer must keep understanding.
+//!
+//! Each file under `golden/v<N>/` is a message as protocol version N put it
+//! on the wire, with `v0` for peers from before the version was sent. All of
+//! them must parse and satisfy the generated schema; those for the current
+//! version must also serialize back unchanged, so renaming or dropping a field
+//! fails here until the goldens are updated on purpose. `wire_compat.rs`
+//! holds the older versions to the same.
+
+use emns_protocol::{schema, Message, PROTOCOL_VERSION};
+use jsonschema::JSONSchema;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+fn golden_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+/// `(version, file, payload)` for every golden
+fn goldens() -> Vec<(u32, PathBuf, Value)> {
+    let mut goldens: Vec<(u32, PathBuf, Value)> = Vec::new();
+    for version_dir in std::fs::read_dir(golden_root()).unwrap() {
+        let version_dir: PathBuf = version_dir.unwrap().path();
+        let version: u32 = version_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix('v'))
+            .and_then(|n| n.parse().ok())
+            .unwrap_or_else(|| panic!("{} is not a v<N> directory", version_dir.display()));
+        for file in std::fs::read_dir(&version_dir).unwrap() {
+            let file: PathBuf = file.unwrap().path();
+            let payload: Value = serde_json::from_str(&std::fs::read_to_string(&file).unwrap())
+                .unwrap_or_else(|e| panic!("{} is not JSON: {}", file.display(), e));
+            goldens.push((version, file, payload));
+        }
+    }
+    goldens.sort_by(|a, b| a.1.cmp(&b.1));
+    goldens
+}
+
+fn message_schema() -> Value {
+    let (_, schema) = schema::schemas()
+        .into_iter()
+        .find(|(name, _)| *name == "message")
+        .unwrap();
+    serde_json::to_value(schema).unwrap()
+}
+
+#[test]
+fn test_goldens_parse_and_round_trip() {
+    for (version, file, payload) in goldens() {
+        let message: Message = serde_json::from_value(payload.clone())
+            .unwrap_or_else(|e| panic!("{} no longer parses: {}", file.display(), e));
+        if version == PROTOCOL_VERSION {
+            assert_eq!(
+                serde_json::to_value(&message).unwrap(),
+                payload,
+                "{} serializes differently",
+                file.display()
+            );
+        }
+    }
+}
+
+#[test]
+fn test_goldens_round_trip_through_msgpack() {
+    for (_, file, payload) in goldens() {
+        let message: Message = serde_json::from_value(payload).unwrap();
+        let bytes: Vec<u8> = message.to_msgpack().unwrap();
+        let parsed: Message = Message::from_msgpack(&bytes).unwrap_or_else(|e| {
+            panic!("{} does not read back from msgpack: {}", file.display(), e)
+        });
+        assert_eq!(
+            serde_json::to_value(&parsed).unwrap(),
+            serde_json::to_value(&message).unwrap(),
+            "{} changes through msgpack",
+            file.display()
+        );
+    }
+}
+
+#[test]
+fn test_goldens_match_the_schema() {
+    let schema: Value = message_schema();
+    let compiled: JSONSchema = JSONSchema::compile(&schema).expect("schema compiles");
+    for (_, file, payload) in goldens() {
+        if let Err(errors) = compiled.validate(&payload) {
+            let errors: Vec<String> = errors.map(|e| e.to_string()).collect();
+            panic!("{} violates the schema: {:?}", file.display(), errors);
+        }
+    }
+}
+
+#[test]
+fn test_every_message_type_has_a_golden() {
+    // Variant tags as the schema lists them, so a new variant needs a golden
+    let schema: Value = message_schema();
+    let tags: BTreeSet<String> = schema["oneOf"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .flat_map(|variant| variant["properties"]["type"]["enum"].as_array().unwrap())
+        .map(|tag| tag.as_str().unwrap().to_string())
+        .collect();
+    assert!(tags.contains("alert") && tags.contains("config_update"));
+
+    let covered: BTreeSet<String> = goldens()
+        .into_iter()
+        .filter(|(version, _, _)| *version == PROTOCOL_VERSION)
+        .map(|(_, _, payload)| payload["type"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(covered, tags);
+}
+
+#[test]
+fn test_committed_schemas_are_current() {
+    let dir: PathBuf = Path::new(env!("CARGO_MANIFEST_DIR")).join("schema");
+    for (name, schema) in schema::schemas() {
+        let path: PathBuf = dir.join(format!("{}.schema.json", name));
+        let committed: Value = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_else(|| panic!("{} is missing", path.display()));
+        assert_eq!(
+            committed,
+            serde_json::to_value(schema).unwrap(),
+            "{} is out of date; run `cargo run -p emns-protocol --bin emns-schema -- protocol/schema`",
+            path.display()
+        );
+    }
+}
@@ -0,0 +1,64 @@
+//! Response latency percentiles for a server's delivery report
+
+use chrono::Utc;
+use emns_protocol::{
+    Confirmation, ConfirmationReason, ConfirmationResponse, LatencySummary, ReceivedVia,
+};
+use uuid::Uuid;
+
+fn confirmation(reason: ConfirmationReason, latency_ms: Option<u64>) -> Confirmation {
+    Confirmation {
+        alert_id: Uuid::nil(),
+        client_id: "workstation-01".to_string(),
+        confirmed_at: Utc::now(),
+        hostname: "WIN-DESKTOP".to_string(),
+        username: "jdoe".to_string(),
+        operator_id: None,
+        reason,
+        method: None,
+        user_idle_secs: None,
+        response_id: None,
+        response: ConfirmationResponse::Acknowledged,
+        received_via: ReceivedVia::WebSocket,
+        shown_at: latency_ms.map(|_| Utc::now()),
+        response_latency_ms: latency_ms,
+        is_preview: false,
+    }
+}
+
+#[test]
+fn test_percentiles_use_nearest_rank() {
+    // 1s, 2s, ... 20s
+    let confirmations: Vec<Confirmation> = (1..=20)
+        .map(|s| confirmation(ConfirmationReason::User, Some(s * 1000)))
+        .collect();
+    let summary: LatencySummary = LatencySummary::from_confirmations(&confirmations).unwrap();
+    assert_eq!(summary.responses, 20);
+    assert_eq!(summary.p50_ms, 10_000);
+    assert_eq!(summary.p95_ms, 19_000);
+
+    let one: LatencySummary =
+        LatencySummary::from_confirmations(&[confirmation(ConfirmationReason::User, Some(750))])
+            .unwrap();
+    assert_eq!((one.p50_ms, one.p95_ms), (750, 750));
+}
+
+#[test]
+fn test_timeouts_are_counted_but_not_ranked() {
+    let confirmations: Vec<Confirmation> = vec![
+        confirmation(ConfirmationReason::User, Some(3_000)),
+        confirmation(ConfirmationReason::TimedOut, Some(300_000)),
+        confirmation(ConfirmationReason::TimedOutIdle, None),
+        // From an agent too old to report latency
+        confirmation(ConfirmationReason::User, None),
+    ];
+    let summary: LatencySummary = LatencySummary::from_confirmations(&confirmations).unwrap();
+    assert_eq!(summary.responses, 1);
+    assert_eq!(summary.timed_out, 2);
+    assert_eq!(summary.p95_ms, 3_000);
+
+    assert_eq!(
+        LatencySummary::from_confirmations(&confirmations[1..]),
+        None
+    );
+}
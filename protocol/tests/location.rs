@@ -0,0 +1,84 @@
+//! Location targeting shared by the agent's filter and the server's routing
+
+use emns_protocol::{Location, LocationField};
+
+fn location(site: &[&str], building: &[&str], floor: &[&str], room: &[&str]) -> Location {
+    Location {
+        site: LocationField::new(site.iter().copied()),
+        building: LocationField::new(building.iter().copied()),
+        floor: LocationField::new(floor.iter().copied()),
+        room: LocationField::new(room.iter().copied()),
+    }
+}
+
+#[test]
+fn test_location_matching() {
+    let agent: Location = location(&["Main Campus"], &["C"], &["3"], &["301"]);
+    let cases: Vec<(&str, Location, bool)> = vec![
+        ("empty target", Location::default(), true),
+        ("same building", location(&[], &["C"], &[], &[]), true),
+        ("other building", location(&[], &["D"], &[], &[]), false),
+        (
+            "case-insensitive",
+            location(&["main campus"], &["c"], &[], &[]),
+            true,
+        ),
+        ("surrounding space", location(&[], &[" C "], &[], &[]), true),
+        (
+            "floor in list",
+            location(&[], &["C"], &["2", "3", "4"], &[]),
+            true,
+        ),
+        (
+            "floor not in list",
+            location(&[], &["C"], &["1", "2"], &[]),
+            false,
+        ),
+        (
+            "one field misses",
+            location(&["North"], &["C"], &["3"], &[]),
+            false,
+        ),
+        ("exact room", location(&[], &[], &[], &["301"]), true),
+        ("other room", location(&[], &[], &[], &["302"]), false),
+    ];
+
+    for (name, target, expected) in cases {
+        assert_eq!(target.matches(&agent), expected, "{}", name);
+    }
+}
+
+#[test]
+fn test_unknown_agent_fields_match_anything() {
+    let agent: Location = location(&[], &["C"], &[], &[]);
+    let cases: Vec<(&str, Location, bool)> = vec![
+        (
+            "floor unknown",
+            location(&[], &["C"], &["2", "3"], &[]),
+            true,
+        ),
+        (
+            "site unknown",
+            location(&["Main Campus"], &[], &[], &[]),
+            true,
+        ),
+        (
+            "known field misses",
+            location(&[], &["D"], &["2"], &[]),
+            false,
+        ),
+    ];
+
+    for (name, target, expected) in cases {
+        assert_eq!(target.matches(&agent), expected, "{}", name);
+    }
+    assert!(Location::default().is_any());
+    assert!(!agent.is_any());
+}
+
+#[test]
+fn test_blank_values_are_dropped() {
+    let field: LocationField = LocationField::new(["", "  ", "2"]);
+    assert_eq!(field.values(), ["2"]);
+    assert!(LocationField::from("").is_any());
+}
@@ -0,0 +1,100 @@
+//! Quorum evaluation for team alerts that need only some of the team to respond
+
+use chrono::Utc;
+use emns_protocol::{
+    Confirmation, ConfirmationReason, ConfirmationResponse, Message, QuorumTally, ReceivedVia,
+};
+use uuid::Uuid;
+
+fn confirmation(
+    alert_id: Uuid,
+    username: &str,
+    operator_id: Option<&str>,
+    reason: ConfirmationReason,
+) -> Confirmation {
+    Confirmation {
+        alert_id,
+        client_id: format!("{}-pc", username),
+        confirmed_at: Utc::now(),
+        hostname: "WIN-DESKTOP".to_string(),
+        username: username.to_string(),
+        operator_id: operator_id.map(str::to_string),
+        reason,
+        method: None,
+        user_idle_secs: None,
+        response_id: None,
+        response: ConfirmationResponse::Acknowledged,
+        received_via: ReceivedVia::WebSocket,
+        shown_at: None,
+        response_latency_ms: None,
+        is_preview: false,
+    }
+}
+
+#[test]
+fn test_quorum_met_once_by_distinct_people() {
+    let alert_id: Uuid = Uuid::new_v4();
+    let mut tally: QuorumTally = QuorumTally::new(alert_id, 2);
+    let user = ConfirmationReason::User;
+
+    assert!(tally
+        .record(&confirmation(alert_id, "jsmith", None, user))
+        .is_none());
+    // The same person on a second machine does not count twice
+    assert!(tally
+        .record(&confirmation(alert_id, "jsmith", None, user))
+        .is_none());
+    // Nor do timeouts, dismissals or other alerts
+    assert!(tally
+        .record(&confirmation(
+            alert_id,
+            "adoe",
+            None,
+            ConfirmationReason::TimedOut
+        ))
+        .is_none());
+    assert!(tally
+        .record(&confirmation(
+            alert_id,
+            "adoe",
+            None,
+            ConfirmationReason::Dismissed
+        ))
+        .is_none());
+    assert!(tally
+        .record(&confirmation(Uuid::new_v4(), "adoe", None, user))
+        .is_none());
+    assert_eq!(tally.met_at(), None);
+
+    let second: Confirmation = confirmation(alert_id, "kiosk", Some("B-10442"), user);
+    match tally.record(&second) {
+        Some(Message::QuorumMet {
+            alert_id: met,
+            confirmed_by,
+        }) => {
+            assert_eq!(met, alert_id);
+            assert_eq!(confirmed_by, vec!["jsmith", "B-10442"]);
+        }
+        other => panic!("expected QuorumMet, got {:?}", other),
+    }
+    assert_eq!(tally.met_at(), Some(second.confirmed_at));
+
+    // Later confirmations are still accepted but do not announce it again
+    assert!(tally
+        .record(&confirmation(alert_id, "adoe", None, user))
+        .is_none());
+    assert_eq!(tally.confirmed_by(), ["jsmith", "B-10442"]);
+}
+
+#[test]
+fn test_shared_console_operators_count_separately() {
+    let alert_id: Uuid = Uuid::new_v4();
+    let mut tally: QuorumTally = QuorumTally::new(alert_id, 2);
+    let user = ConfirmationReason::User;
+    assert!(tally
+        .record(&confirmation(alert_id, "console", Some("B-1"), user))
+        .is_none());
+    assert!(tally
+        .record(&confirmation(alert_id, "console", Some("B-2"), user))
+        .is_some());
+}
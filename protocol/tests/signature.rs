@@ -0,0 +1,44 @@
+//! The canonical form alert signatures are computed over, checked against the
+//! vectors committed for server implementers in other languages
+
+use emns_protocol::{Alert, Message};
+use serde_json::Value;
+use std::path::Path;
+
+#[test]
+fn test_signing_payload_matches_the_vectors() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/vectors/alert_signature.json");
+    let vectors: Value = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+    for vector in vectors["vectors"].as_array().unwrap() {
+        let alert: Alert = serde_json::from_value(vector["alert"].clone()).unwrap();
+        assert_eq!(
+            String::from_utf8(alert.signing_payload()).unwrap(),
+            vector["signing_payload"].as_str().unwrap(),
+            "{}",
+            vector["name"]
+        );
+        assert_eq!(
+            alert.signature.as_ref(),
+            vector["signature"].as_str().map(String::from).as_ref()
+        );
+    }
+}
+
+#[test]
+fn test_cancel_signing_payload_matches_the_vectors() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/vectors/alert_signature.json");
+    let vectors: Value = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+    for vector in vectors["cancel_vectors"].as_array().unwrap() {
+        let alert_id: uuid::Uuid = vector["alert_id"].as_str().unwrap().parse().unwrap();
+        assert_eq!(
+            String::from_utf8(Message::cancel_signing_payload(
+                alert_id,
+                vector["reason"].as_str()
+            ))
+            .unwrap(),
+            vector["signing_payload"].as_str().unwrap(),
+            "{}",
+            vector["name"]
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! Sound policy layering shared by the server's store and the agent's enforcement
+
+use emns_protocol::SoundPolicy;
+
+fn allow(sounds: &[&str]) -> Option<Vec<String>> {
+    Some(sounds.iter().map(|s| s.to_string()).collect())
+}
+
+#[test]
+fn test_client_overrides_group_overrides_default() {
+    let default: SoundPolicy = SoundPolicy {
+        max_volume: Some(1.0),
+        allowed_sounds: None,
+        visual_only: Some(false),
+    };
+    let group: SoundPolicy = SoundPolicy {
+        max_volume: Some(0.4),
+        allowed_sounds: allow(&["notification.wav", "chime.wav"]),
+        visual_only: None,
+    };
+    let client: SoundPolicy = SoundPolicy {
+        visual_only: Some(true),
+        ..SoundPolicy::default()
+    };
+
+    assert_eq!(SoundPolicy::merged([&default]), default);
+    assert_eq!(
+        SoundPolicy::merged([&default, &group]),
+        SoundPolicy {
+            max_volume: Some(0.4),
+            allowed_sounds: allow(&["notification.wav", "chime.wav"]),
+            visual_only: Some(false),
+        }
+    );
+    assert_eq!(
+        SoundPolicy::merged([&default, &group, &client]),
+        SoundPolicy {
+            max_volume: Some(0.4),
+            allowed_sounds: allow(&["notification.wav", "chime.wav"]),
+            visual_only: Some(true),
+        }
+    );
+
+    // A client can loosen what its group restricts
+    let loud_client: SoundPolicy = SoundPolicy {
+        max_volume: Some(0.9),
+        ..SoundPolicy::default()
+    };
+    assert_eq!(
+        SoundPolicy::merged([&default, &group, &loud_client]).max_volume,
+        Some(0.9)
+    );
+}
+
+#[test]
+fn test_policy_decides_sound_and_volume() {
+    let unrestricted: SoundPolicy = SoundPolicy::default();
+    assert!(unrestricted.permits("alarm_critical.wav"));
+    assert_eq!(unrestricted.limit_volume(0.8), 0.8);
+
+    let quiet_room: SoundPolicy = SoundPolicy {
+        max_volume: Some(0.3),
+        allowed_sounds: allow(&["Notification.wav"]),
+        visual_only: None,
+    };
+    assert!(quiet_room.permits("notification.wav"));
+    assert!(!quiet_room.permits("alarm_critical.wav"));
+    assert_eq!(quiet_room.limit_volume(0.8), 0.3);
+    assert_eq!(quiet_room.limit_volume(0.1), 0.1);
+
+    let visual_only: SoundPolicy = SoundPolicy {
+        visual_only: Some(true),
+        ..quiet_room
+    };
+    assert!(!visual_only.permits("notification.wav"));
+}
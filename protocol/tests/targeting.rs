@@ -0,0 +1,60 @@
+//! Group and host targeting, applied by the agent to each alert it receives
+
+use emns_protocol::Alert;
+use serde_json::json;
+
+fn alert(groups: &[&str], hosts: &[&str]) -> Alert {
+    serde_json::from_value(json!({
+        "id": "0c9e4d2a-7b1f-4e3a-8d6c-5a2b1f0e9d84",
+        "title": "Change window starting",
+        "message": "Production deploys are frozen",
+        "level": "info",
+        "requires_confirmation": false,
+        "sound_file": null,
+        "timestamp": "2024-01-15T22:00:00Z",
+        "target_groups": groups,
+        "target_hosts": hosts,
+    }))
+    .unwrap()
+}
+
+#[test]
+fn test_targeting() {
+    let groups: Vec<String> = vec!["ops".to_string(), "night-shift".to_string()];
+    let cases: Vec<(&str, Alert, bool)> = vec![
+        ("no targeting", alert(&[], &[]), true),
+        ("group match", alert(&["finance", "ops"], &[]), true),
+        ("group in other case", alert(&["Night-Shift"], &[]), true),
+        ("other group", alert(&["finance"], &[]), false),
+        ("exact host", alert(&[], &["ops-ws-07"]), true),
+        ("host glob", alert(&[], &["OPS-WS-*"]), true),
+        ("host glob misses", alert(&[], &["lab-*"]), false),
+        ("glob only at the end", alert(&[], &["ops-*-07"]), false),
+        ("host prefix without glob", alert(&[], &["ops-ws"]), false),
+        ("either list", alert(&["finance"], &["ops-ws-*"]), true),
+        ("neither list", alert(&["finance"], &["lab-*"]), false),
+    ];
+
+    for (name, alert, expected) in cases {
+        assert_eq!(
+            alert.addressed_to("OPS-WS-07", &groups),
+            expected,
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_agent_without_groups_matches_only_by_host() {
+    assert!(alert(&[], &[]).addressed_to("lab-01", &[]));
+    assert!(!alert(&["ops"], &[]).addressed_to("lab-01", &[]));
+    assert!(alert(&["ops"], &["lab-*"]).addressed_to("lab-01", &[]));
+}
+
+#[test]
+fn test_host_glob_matches_a_hostname_in_either_case() {
+    assert!(alert(&[], &["OPS-WS-*"]).addressed_to("ops-ws-07", &[]));
+    assert!(alert(&[], &["ops-ws-*"]).addressed_to("OPS-WS-07", &[]));
+    assert!(!alert(&[], &["OPS-WS-*"]).addressed_to("ops-lab-07", &[]));
+}
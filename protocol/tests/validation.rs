@@ -0,0 +1,288 @@
+//! Alert validation, so a server bug is reported field by field rather than
+//! as a bare parse error
+
+use chrono::{DateTime, TimeDelta, TimeZone, Utc};
+use emns_protocol::{Alert, InvalidAlert, Message};
+use serde_json::{json, Value};
+
+fn now() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, 1, 15, 10, 30, 0).unwrap()
+}
+
+fn message() -> Value {
+    json!({
+        "type": "alert",
+        "alert": {
+            "id": "123e4567-e89b-12d3-a456-426614174000",
+            "title": "System Alert",
+            "message": "Critical system event detected",
+            "level": "critical",
+            "requires_confirmation": true,
+            "sound_file": "alarm_critical.wav",
+            "timestamp": "2024-01-15T10:30:00Z"
+        }
+    })
+}
+
+fn alert(message: Value) -> Alert {
+    match serde_json::from_value(message).unwrap() {
+        Message::Alert { alert } => alert,
+        other => panic!("expected an alert, got {:?}", other),
+    }
+}
+
+fn problems(alert: &Alert) -> Vec<&'static str> {
+    match alert.validate(now()) {
+        Ok(()) => Vec::new(),
+        Err(invalid) => invalid.problems.iter().map(|p| p.field).collect(),
+    }
+}
+
+#[test]
+fn test_well_formed_alert_is_valid() {
+    assert!(alert(message()).validate(now()).is_ok());
+
+    // A clock a little behind the server's is no problem
+    let mut ahead: Alert = alert(message());
+    ahead.timestamp = now() + TimeDelta::hours(2);
+    assert!(ahead.validate(now()).is_ok());
+
+    let mut linked: Value = message();
+    linked["alert"]["url"] = json!("https://status.example.com/incidents/42?tab=map");
+    linked["alert"]["image_url"] = json!("HTTP://wx.example.com/radar.png");
+    assert!(alert(linked).validate(now()).is_ok());
+
+    for priority in [0, 100] {
+        let mut ranked: Value = message();
+        ranked["alert"]["priority"] = json!(priority);
+        assert!(alert(ranked).validate(now()).is_ok());
+    }
+
+    let mut repeated: Value = message();
+    repeated["alert"]["escalation"] = json!({"repeat_interval_secs": 10, "max_repeats": 60});
+    assert!(alert(repeated).validate(now()).is_ok());
+
+    // 0 waits until someone confirms
+    for secs in [0, 86_400] {
+        let mut timed: Value = message();
+        timed["alert"]["confirm_timeout_secs"] = json!(secs);
+        assert!(alert(timed).validate(now()).is_ok());
+    }
+}
+
+#[test]
+fn test_each_problem_is_named() {
+    let cases: Vec<(&str, &str, Value, Vec<&str>)> = vec![
+        ("empty title", "title", json!(""), vec!["title"]),
+        ("blank message", "message", json!("  \n"), vec!["message"]),
+        (
+            "overlong message",
+            "message",
+            json!("x".repeat(50_001)),
+            vec!["message"],
+        ),
+        (
+            "far future",
+            "timestamp",
+            json!("2024-01-17T10:30:00Z"),
+            vec!["timestamp"],
+        ),
+        (
+            "path in sound file",
+            "sound_file",
+            json!("../alarm.wav"),
+            vec!["sound_file"],
+        ),
+        (
+            "windows path in sound file",
+            "sound_file",
+            json!("C:\\Windows\\Media\\alarm.wav"),
+            vec!["sound_file"],
+        ),
+        (
+            "parent directory",
+            "sound_file",
+            json!(".."),
+            vec!["sound_file"],
+        ),
+        (
+            "link to another scheme",
+            "url",
+            json!("ms-settings:privacy"),
+            vec!["url"],
+        ),
+        (
+            "link without a host",
+            "url",
+            json!("https:///status"),
+            vec!["url"],
+        ),
+        (
+            "priority over 100",
+            "priority",
+            json!(101),
+            vec!["priority"],
+        ),
+        (
+            "repeats too often",
+            "escalation",
+            json!({"repeat_interval_secs": 5, "max_repeats": 3}),
+            vec!["escalation"],
+        ),
+        (
+            "too many repeats",
+            "escalation",
+            json!({"max_repeats": 61}),
+            vec!["escalation"],
+        ),
+        (
+            "confirm timeout over a day",
+            "confirm_timeout_secs",
+            json!(86_401),
+            vec!["confirm_timeout_secs"],
+        ),
+        (
+            "local image",
+            "image_url",
+            json!("file:///C:/radar.png"),
+            vec!["image_url"],
+        ),
+        (
+            "image link with spaces",
+            "image_url",
+            json!("https://wx.example.com/radar now.png"),
+            vec!["image_url"],
+        ),
+    ];
+
+    for (name, field, value, expected) in cases {
+        let mut sent: Value = message();
+        sent["alert"][field] = value;
+        assert_eq!(problems(&alert(sent)), expected, "{}", name);
+    }
+
+    let mut both: Value = message();
+    both["alert"]["title"] = json!("");
+    both["alert"]["sound_file"] = json!("sounds/alarm.wav");
+    let invalid: InvalidAlert = alert(both).validate(now()).unwrap_err();
+    assert_eq!(
+        invalid.alert_id.unwrap().to_string(),
+        "123e4567-e89b-12d3-a456-426614174000"
+    );
+    assert_eq!(
+        invalid.to_string(),
+        "title: empty; sound_file: \"sounds/alarm.wav\" is not a bare file name"
+    );
+}
+
+#[test]
+fn test_translations_are_checked_like_the_alert_text() {
+    let mut translated: Value = message();
+    translated["alert"]["translations"] = json!({
+        "fr-CA": {"title": "Alerte système", "message": "Événement critique"},
+        "zh-Hant": {"title": "系統警報", "message": "偵測到嚴重事件"}
+    });
+    assert!(alert(translated.clone()).validate(now()).is_ok());
+
+    translated["alert"]["translations"]["es"] = json!({"title": " ", "message": "Evento"});
+    translated["alert"]["translations"]["fr_CA"] = json!({"title": "Alerte", "message": "x"});
+    let invalid: InvalidAlert = alert(translated.clone()).validate(now()).unwrap_err();
+    assert_eq!(
+        invalid.to_string(),
+        "translations: es title: empty; translations: \"fr_CA\" is not a language tag"
+    );
+
+    // A translation that does not parse is named by its tag
+    translated["alert"]["translations"]["de"] = json!({"title": "Alarm"});
+    let invalid: InvalidAlert = InvalidAlert::from_json(&translated.to_string(), now()).unwrap();
+    assert!(invalid
+        .problems
+        .iter()
+        .any(|p| p.to_string() == "translations: de: not a title and message"));
+}
+
+#[test]
+fn test_unparseable_alert_is_diagnosed() {
+    let mut sent: Value = message();
+    sent["alert"]["title"] = json!("");
+    sent["alert"]["level"] = json!("sev1");
+    let text: String = sent.to_string();
+    assert!(serde_json::from_str::<Message>(&text).is_err());
+
+    let invalid: InvalidAlert = InvalidAlert::from_json(&text, now()).unwrap();
+    assert_eq!(
+        invalid.alert_id.unwrap().to_string(),
+        "123e4567-e89b-12d3-a456-426614174000"
+    );
+    assert_eq!(
+        invalid.to_string(),
+        "title: empty; level: unknown level \"sev1\", expected info, warning, critical or emergency"
+    );
+
+    // The same from MessagePack
+    let bytes: Vec<u8> = rmp_serde::to_vec_named(&sent).unwrap();
+    assert_eq!(InvalidAlert::from_msgpack(&bytes, now()), Some(invalid));
+}
+
+#[test]
+fn test_unparseable_alert_without_field_problems_names_the_parse_error() {
+    let mut sent: Value = message();
+    sent["alert"]["requires_confirmation"] = json!("yes");
+    let invalid: InvalidAlert = InvalidAlert::from_json(&sent.to_string(), now()).unwrap();
+    assert_eq!(invalid.problems.len(), 1);
+    assert_eq!(invalid.problems[0].field, "alert");
+    assert!(invalid.problems[0].problem.contains("invalid type"));
+
+    let mut linked: Value = message();
+    linked["alert"]["requires_confirmation"] = json!("yes");
+    linked["alert"]["url"] = json!("javascript:alert(1)");
+    linked["alert"]["image_url"] = json!(7);
+    let invalid: InvalidAlert = InvalidAlert::from_json(&linked.to_string(), now()).unwrap();
+    assert_eq!(
+        invalid.to_string(),
+        "url: \"javascript:alert(1)\" is not an http(s) URL; image_url: not a string"
+    );
+
+    // Priorities a u8 cannot hold are caught before parsing
+    for (priority, expected) in [
+        (json!(300), "priority: 300 is more than 100"),
+        (json!(-1), "priority: not a whole number"),
+        (json!(2.5), "priority: not a whole number"),
+    ] {
+        let mut ranked: Value = message();
+        ranked["alert"]["priority"] = priority;
+        let invalid: InvalidAlert = InvalidAlert::from_json(&ranked.to_string(), now()).unwrap();
+        assert_eq!(invalid.to_string(), expected);
+    }
+
+    let mut timed: Value = message();
+    timed["alert"]["confirm_timeout_secs"] = json!("never");
+    let invalid: InvalidAlert = InvalidAlert::from_json(&timed.to_string(), now()).unwrap();
+    assert_eq!(
+        invalid.to_string(),
+        "confirm_timeout_secs: not a whole number"
+    );
+
+    let mut repeated: Value = message();
+    repeated["alert"]["escalation"] = json!({"max_repeats": -1});
+    let invalid: InvalidAlert = InvalidAlert::from_json(&repeated.to_string(), now()).unwrap();
+    assert_eq!(
+        invalid.to_string(),
+        "escalation: repeat_interval_secs and max_repeats must be whole numbers"
+    );
+
+    let mut anonymous: Value = message();
+    anonymous["alert"]["id"] = json!(42);
+    let invalid: InvalidAlert = InvalidAlert::from_json(&anonymous.to_string(), now()).unwrap();
+    assert_eq!(invalid.alert_id, None);
+    assert_eq!(invalid.to_string(), "id: missing or not a UUID");
+}
+
+#[test]
+fn test_other_messages_are_not_diagnosed() {
+    assert_eq!(InvalidAlert::from_json("{not json", now()), None);
+    assert_eq!(
+        InvalidAlert::from_json(r#"{"type": "heartbeat", "seq": "x"}"#, now()),
+        None
+    );
+}
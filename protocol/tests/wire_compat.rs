@@ -0,0 +1,212 @@
+//! Compatibility with peers built from older versions of this crate.
+//!
+//! Servers and agents are upgraded independently, so every change to a wire
+//! type has to keep working against peers that predate it:
+//!
+//! - A new field is optional. It is an `Option`, a collection or a `bool`
+//!   marked `#[serde(default, skip_serializing_if = "...")]`, so older peers
+//!   that leave it out still parse and messages that do not use it are sent
+//!   exactly as before.
+//! - Fields are never renamed, removed or made required, and a field's type
+//!   only ever widens.
+//! - A new message type or enum value reaches an older peer as a message it
+//!   cannot read. It must be one that peer can afford to drop, or be sent
+//!   only to peers that announced support for it at registration.
+//! - Anything else is a breaking change, and bumps [`PROTOCOL_VERSION`].
+//!
+//! The goldens under `golden/v<N>/` are messages as peers on protocol version
+//! N sent them; `v0` holds those from before `protocol_version` existed.
+//! `golden.rs` checks that they all parse and that the current version's
+//! serialize back unchanged. Here, the older versions' goldens must serialize
+//! back unchanged too, until a breaking version says otherwise, and `compat/required_fields.json` pins which fields
+//! each message and type requires, so that a new required field fails the
+//! build.
+
+use emns_protocol::{schema, Message, PROTOCOL_VERSION};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+type RequiredFields = BTreeMap<String, Vec<String>>;
+
+fn tests_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests")
+}
+
+/// `(version, file, payload)` for every golden of a version before the current one
+fn historical_goldens() -> Vec<(u32, PathBuf, Value)> {
+    let mut goldens: Vec<(u32, PathBuf, Value)> = Vec::new();
+    for version in 0..PROTOCOL_VERSION {
+        let dir: PathBuf = tests_dir().join(format!("golden/v{}", version));
+        let Ok(files) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for file in files {
+            let file: PathBuf = file.unwrap().path();
+            let payload: Value = serde_json::from_str(&std::fs::read_to_string(&file).unwrap())
+                .unwrap_or_else(|e| panic!("{} is not JSON: {}", file.display(), e));
+            goldens.push((version, file, payload));
+        }
+    }
+    goldens.sort_by(|a, b| a.1.cmp(&b.1));
+    goldens
+}
+
+/// Fail unless `payload` parses and serializes back to exactly itself, naming
+/// the fields that appeared or went missing on the way
+fn assert_wire_unchanged(file: &Path, payload: &Value) {
+    let message: Message = serde_json::from_value(payload.clone())
+        .unwrap_or_else(|e| panic!("{} no longer parses: {}", file.display(), e));
+    let written: Value = serde_json::to_value(&message).unwrap();
+    if written == *payload {
+        return;
+    }
+    let mut added: Vec<String> = Vec::new();
+    let mut dropped: Vec<String> = Vec::new();
+    diff_fields("", payload, &written, &mut added, &mut dropped);
+    panic!(
+        "{} serializes differently; added {:?}, dropped or changed {:?}. \
+         Optional fields need skip_serializing_if so older peers see what they sent",
+        file.display(),
+        added,
+        dropped
+    );
+}
+
+/// Paths of fields only in `written` into `added`, and of fields missing from
+/// it or holding another value into `dropped`
+fn diff_fields(
+    path: &str,
+    sent: &Value,
+    written: &Value,
+    added: &mut Vec<String>,
+    dropped: &mut Vec<String>,
+) {
+    match (sent, written) {
+        (Value::Object(sent), Value::Object(written)) => {
+            for (key, value) in sent {
+                let field: String = format!("{}{}", path, key);
+                match written.get(key) {
+                    Some(other) => {
+                        diff_fields(&format!("{}.", field), value, other, added, dropped)
+                    }
+                    None => dropped.push(field),
+                }
+            }
+            added.extend(
+                written
+                    .keys()
+                    .filter(|key| !sent.contains_key(*key))
+                    .map(|key| format!("{}{}", path, key)),
+            );
+        }
+        _ if sent != written => dropped.push(path.trim_end_matches('.').to_string()),
+        _ => {}
+    }
+}
+
+/// Fields each message type and each type it refers to requires, from the schema
+fn required_fields() -> (RequiredFields, RequiredFields) {
+    let (_, schema) = schema::schemas()
+        .into_iter()
+        .find(|(name, _)| *name == "message")
+        .unwrap();
+    let schema: Value = serde_json::to_value(schema).unwrap();
+    let required = |object: &Value| -> Vec<String> {
+        let mut fields: Vec<String> = object["required"]
+            .as_array()
+            .map(|fields| fields.iter().map(|f| f.as_str().unwrap().to_string()))
+            .into_iter()
+            .flatten()
+            .filter(|field| field != "type")
+            .collect();
+        fields.sort();
+        fields
+    };
+    let messages: RequiredFields = schema["oneOf"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|variant| {
+            let tag: &str = variant["properties"]["type"]["enum"][0].as_str().unwrap();
+            (tag.to_string(), required(variant))
+        })
+        .collect();
+    let types: RequiredFields = schema["definitions"]
+        .as_object()
+        .unwrap()
+        .iter()
+        .filter(|(_, definition)| definition.get("properties").is_some())
+        .map(|(name, definition)| (name.clone(), required(definition)))
+        .collect();
+    (messages, types)
+}
+
+#[test]
+fn test_historical_goldens_serialize_back_unchanged() {
+    let goldens: Vec<(u32, PathBuf, Value)> = historical_goldens();
+    assert!(!goldens.is_empty());
+    for (_, file, payload) in goldens {
+        assert_wire_unchanged(&file, &payload);
+    }
+}
+
+#[test]
+fn test_messages_keep_their_original_shape() {
+    // What the first agents and servers sent, before any optional field existed
+    let mut tags: Vec<String> = historical_goldens()
+        .into_iter()
+        .filter(|(version, _, _)| *version == 0)
+        .map(|(_, _, payload)| payload["type"].as_str().unwrap().to_string())
+        .collect();
+    tags.sort();
+    assert_eq!(tags, ["alert", "confirmation", "heartbeat", "register"]);
+}
+
+#[test]
+fn test_required_fields_never_grow() {
+    let path: PathBuf = tests_dir().join("compat/required_fields.json");
+    let pinned: Map<String, Value> =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    let pinned =
+        |key: &str| -> RequiredFields { serde_json::from_value(pinned[key].clone()).unwrap() };
+    let (messages, types) = required_fields();
+    for (kind, current, pinned) in [
+        ("message", messages, pinned("messages")),
+        ("type", types, pinned("types")),
+    ] {
+        for (name, fields) in &current {
+            let known: &[String] = pinned.get(name).map_or(&[], Vec::as_slice);
+            let added: Vec<&String> = fields.iter().filter(|f| !known.contains(f)).collect();
+            // A new message or type may require what it likes; older peers never send it
+            if pinned.contains_key(name) {
+                assert!(
+                    added.is_empty(),
+                    "{} {} now requires {:?}, which older peers do not send; \
+                     make the field optional with #[serde(default)]",
+                    kind,
+                    name,
+                    added
+                );
+            }
+        }
+        assert_eq!(
+            current,
+            pinned,
+            "required {} fields changed compatibly; update {}",
+            kind,
+            path.display()
+        );
+    }
+}
+
+#[test]
+fn test_drift_is_reported_field_by_field() {
+    let sent: Value = serde_json::json!({"alert": {"id": 1, "title": "a"}, "seq": 2});
+    let written: Value = serde_json::json!({"alert": {"id": 1, "title": "b", "url": null}});
+    let mut added: Vec<String> = Vec::new();
+    let mut dropped: Vec<String> = Vec::new();
+    diff_fields("", &sent, &written, &mut added, &mut dropped);
+    assert_eq!(added, ["alert.url"]);
+    assert_eq!(dropped, ["alert.title", "seq"]);
+}
@@ -0,0 +1,1255 @@
+//! Pins the JSON wire format. A failure here means peers built from an older
+//! version of this crate would no longer understand the new output.
+
+use chrono::{DateTime, TimeZone, Utc};
+use emns_protocol::{
+    msgpack_value, AgentStatus, Alert, AlertBatch, AlertEnvelope, AlertErrorReason,
+    AlertEscalation, AlertLevel, AlertOrigin, Attachment, AttachmentState, Confirmation,
+    ConfirmationMethod, ConfirmationReason, ConfirmationResponse, DeliveryOutcome, DeliveryStatus,
+    Envelope, HeartbeatStats, Location, LocationField, Message, NackReason, ReceivedVia,
+    ResponseOption, ShutdownReason, SoundPackOffer, SoundPolicy, SuppressionWindow, SystemHealth,
+    Translation, UpdateManifest,
+};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+const ALERT_ID: &str = "123e4567-e89b-12d3-a456-426614174000";
+
+fn timestamp() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, 1, 15, 10, 30, 0).unwrap()
+}
+
+fn sample_alert() -> Alert {
+    Alert {
+        id: Uuid::parse_str(ALERT_ID).unwrap(),
+        title: "System Alert".to_string(),
+        message: "Critical system event detected".to_string(),
+        level: AlertLevel::Critical,
+        requires_confirmation: true,
+        sound_file: Some("alarm_critical.wav".to_string()),
+        timestamp: timestamp(),
+        ..Default::default()
+    }
+}
+
+const WINDOW_ID: &str = "6f1c2a4e-2b7d-4c1e-9a3f-0d5e8b7c6a51";
+const MESSAGE_ID: &str = "0b8e2f6a-5c3d-4e71-9f2a-8d4c6b1e3a70";
+
+fn sample_window() -> SuppressionWindow {
+    SuppressionWindow {
+        id: Uuid::parse_str(WINDOW_ID).unwrap(),
+        starts_at: timestamp(),
+        ends_at: Utc.with_ymd_and_hms(2024, 1, 15, 12, 0, 0).unwrap(),
+        levels: vec![AlertLevel::Warning, AlertLevel::Critical],
+        categories: vec!["fire_alarm".to_string()],
+        reason: "Fire alarm testing in building C".to_string(),
+        location: Some(Location {
+            building: "C".into(),
+            ..Location::default()
+        }),
+    }
+}
+
+fn sample_confirmation() -> Confirmation {
+    Confirmation {
+        alert_id: Uuid::parse_str(ALERT_ID).unwrap(),
+        client_id: "workstation-01".to_string(),
+        confirmed_at: timestamp(),
+        hostname: "WIN-DESKTOP".to_string(),
+        username: "jdoe".to_string(),
+        operator_id: None,
+        reason: ConfirmationReason::User,
+        method: None,
+        user_idle_secs: Some(4),
+        response_id: Some("safe".to_string()),
+        response: ConfirmationResponse::Acknowledged,
+        received_via: ReceivedVia::WebSocket,
+        shown_at: Some(Utc.with_ymd_and_hms(2024, 1, 15, 10, 29, 48).unwrap()),
+        response_latency_ms: Some(12_000),
+        is_preview: false,
+    }
+}
+
+/// One sample of every message variant with its expected JSON.
+///
+/// The exhaustive match makes adding a variant without a snapshot a compile error.
+fn snapshots() -> Vec<(Message, Value)> {
+    let samples: Vec<Message> = vec![
+        Message::Alert {
+            alert: sample_alert(),
+        },
+        Message::AlertBatch {
+            batch: AlertBatch {
+                alerts: vec![sample_alert()],
+                unreadable: Vec::new(),
+            },
+        },
+        Message::Confirmation {
+            confirmation: sample_confirmation(),
+        },
+        Message::ConfirmationTimeout {
+            alert_id: Uuid::parse_str(ALERT_ID).unwrap(),
+            client_id: "workstation-01".to_string(),
+            timed_out_at: timestamp(),
+            idle: false,
+        },
+        Message::heartbeat(),
+        Message::Heartbeat {
+            stats: HeartbeatStats {
+                uptime_secs: Some(86_400),
+                last_alert_secs: Some(125),
+                pending_confirmations: Some(2),
+                connected_at: Some(timestamp()),
+                client_id: Some("workstation-01".to_string()),
+                agent_version: Some("0.1.0".to_string()),
+                seq: Some(7),
+                clock_skew_ms: Some(-1_250),
+            },
+        },
+        Message::HeartbeatAck { seq: Some(7) },
+        Message::Register {
+            client_id: "workstation-01".to_string(),
+            hostname: "WIN-DESKTOP".to_string(),
+            location: Some(Location {
+                site: "Main Campus".into(),
+                building: "C".into(),
+                floor: "3".into(),
+                room: LocationField::default(),
+            }),
+            standby: false,
+            encryption_key: None,
+            capabilities: None,
+            previous_shutdown: None,
+            since: None,
+            sound_pack_version: None,
+            machine_role: None,
+            groups: Vec::new(),
+            categories: Vec::new(),
+            server_url: None,
+            supported_encodings: Vec::new(),
+            protocol_version: Some(1),
+            envelopes: false,
+            confirmation_timeouts: false,
+        },
+        Message::RegisterAck {
+            server_name: Some("EMNS".to_string()),
+            environment: Some("production".to_string()),
+            encoding: None,
+            server_version: Some("emns-server 2.3.0".to_string()),
+            protocol_version: Some(1),
+            server_time: Some(timestamp()),
+            envelopes: false,
+            confirmation_timeouts: false,
+        },
+        Message::RegisterRejected {
+            reason: "invalid token".to_string(),
+        },
+        Message::Unregister {
+            client_id: "workstation-01".to_string(),
+            reason: ShutdownReason::Clean,
+        },
+        Message::Status {
+            status: AgentStatus {
+                client_id: "workstation-01".to_string(),
+                reported_at: timestamp(),
+                alert_queue_depth: 3,
+                alert_queue_capacity: 100,
+                alerts_shed: 1,
+                alerts_rate_limited: 0,
+                urgent_alerts_rate_limited: 0,
+                confirmation_queue_depth: 0,
+                confirmation_queue_capacity: 100,
+                outbound_queue_depth: 2,
+                outbound_queue_capacity: 0,
+                outbound_dropped: 0,
+                confirmations_dropped: 0,
+                pipeline_stalled: false,
+                pipeline_stalls: 0,
+                clock_jumps: 0,
+                unreadable_messages: 0,
+                in_maintenance_window: false,
+                update: None,
+                sound_pack_version: None,
+                delivery_timing: None,
+                system: SystemHealth {
+                    cpu_percent: Some(12.5),
+                    memory_available_bytes: Some(4_294_967_296),
+                    data_disk_free_bytes: Some(53_687_091_200),
+                    audio_available: Some(true),
+                    system_muted: None,
+                },
+            },
+        },
+        Message::LocalAlert {
+            client_id: "workstation-01".to_string(),
+            alert: Alert {
+                origin: AlertOrigin::Local,
+                ..sample_alert()
+            },
+        },
+        Message::DeliveryStatus {
+            status: DeliveryStatus {
+                alert_id: Uuid::parse_str(ALERT_ID).unwrap(),
+                client_id: "workstation-01".to_string(),
+                reported_at: timestamp(),
+                attachment: Some(AttachmentState::Failed),
+                sound: None,
+                annunciator: None,
+                callback: None,
+                outcome: None,
+                detail: Some("checksum mismatch".to_string()),
+                decision: None,
+                locale: None,
+            },
+        },
+        Message::AlertExpired {
+            client_id: "workstation-01".to_string(),
+            alert_id: Uuid::parse_str(ALERT_ID).unwrap(),
+            unconfirmed: true,
+        },
+        Message::AlertError {
+            client_id: "workstation-01".to_string(),
+            reason: AlertErrorReason::Overloaded,
+            alert_id: None,
+            detail: Some("30 alerts shed by the rate limit".to_string()),
+        },
+        Message::Error {
+            context: "{\"type\":\"alert\"}".to_string(),
+            detail: "missing field `alert` at line 1 column 16".to_string(),
+        },
+        Message::ConfigUpdate {
+            sound_policy: Some(SoundPolicy {
+                max_volume: Some(0.5),
+                allowed_sounds: Some(vec!["notification.wav".to_string()]),
+                visual_only: Some(true),
+            }),
+        },
+        Message::Suppression {
+            window: sample_window(),
+        },
+        Message::CancelSuppression {
+            id: Uuid::parse_str(WINDOW_ID).unwrap(),
+        },
+        Message::PendingSync {
+            pending_alert_ids: vec![Uuid::parse_str(ALERT_ID).unwrap()],
+        },
+        Message::PendingSyncResult {
+            still_active: vec![],
+            cancelled: vec![Uuid::parse_str(ALERT_ID).unwrap()],
+            expired: vec![],
+        },
+        Message::ServerShutdown {
+            resume_expected_at: timestamp(),
+            reason: Some("Scheduled patching".to_string()),
+        },
+        Message::UpdateAvailable {
+            manifest: UpdateManifest {
+                version: "1.4.2".to_string(),
+                url: "https://updates.example.com/emns-agent-1.4.2.exe".to_string(),
+                signature: "c2lnbmF0dXJl".to_string(),
+            },
+        },
+        Message::SoundPack {
+            pack: SoundPackOffer {
+                url: "https://sounds.example.com/site-pack-4.zip".to_string(),
+                version: 4,
+                sha256: "ab".repeat(32),
+            },
+        },
+        Message::QuorumMet {
+            alert_id: Uuid::parse_str(ALERT_ID).unwrap(),
+            confirmed_by: vec!["jsmith".to_string(), "B-10442".to_string()],
+        },
+        Message::CancelAlert {
+            alert_id: Uuid::parse_str(ALERT_ID).unwrap(),
+            reason: Some("sent in error".to_string()),
+            signature: None,
+        },
+        Message::Ack {
+            message_id: Uuid::parse_str(MESSAGE_ID).unwrap(),
+        },
+        Message::Nack {
+            message_id: Uuid::parse_str(MESSAGE_ID).unwrap(),
+            reason: NackReason::QueueFull,
+            detail: Some("the alert queue is full".to_string()),
+        },
+    ];
+
+    samples
+        .into_iter()
+        .map(|message| {
+            let expected: Value = match &message {
+                Message::Alert { .. } => json!({
+                    "type": "alert",
+                    "alert": {
+                        "id": ALERT_ID,
+                        "title": "System Alert",
+                        "message": "Critical system event detected",
+                        "level": "critical",
+                        "requires_confirmation": true,
+                        "sound_file": "alarm_critical.wav",
+                        "timestamp": "2024-01-15T10:30:00Z"
+                    }
+                }),
+                Message::AlertBatch { .. } => json!({
+                    "type": "alert_batch",
+                    "alerts": [{
+                        "id": ALERT_ID,
+                        "title": "System Alert",
+                        "message": "Critical system event detected",
+                        "level": "critical",
+                        "requires_confirmation": true,
+                        "sound_file": "alarm_critical.wav",
+                        "timestamp": "2024-01-15T10:30:00Z"
+                    }]
+                }),
+                Message::Confirmation { .. } => json!({
+                    "type": "confirmation",
+                    "confirmation": {
+                        "alert_id": ALERT_ID,
+                        "client_id": "workstation-01",
+                        "confirmed_at": "2024-01-15T10:30:00Z",
+                        "hostname": "WIN-DESKTOP",
+                        "username": "jdoe",
+                        "user_idle_secs": 4,
+                        "response_id": "safe",
+                        "shown_at": "2024-01-15T10:29:48Z",
+                        "response_latency_ms": 12_000
+                    }
+                }),
+                Message::ConfirmationTimeout { .. } => json!({
+                    "type": "confirmation_timeout",
+                    "alert_id": ALERT_ID,
+                    "client_id": "workstation-01",
+                    "timed_out_at": "2024-01-15T10:30:00Z"
+                }),
+                Message::Heartbeat { stats } if *stats == HeartbeatStats::default() => {
+                    json!({ "type": "heartbeat" })
+                }
+                Message::Heartbeat { .. } => json!({
+                    "type": "heartbeat",
+                    "uptime_secs": 86_400,
+                    "last_alert_secs": 125,
+                    "pending_confirmations": 2,
+                    "connected_at": "2024-01-15T10:30:00Z",
+                    "client_id": "workstation-01",
+                    "agent_version": "0.1.0",
+                    "seq": 7,
+                    "clock_skew_ms": -1_250
+                }),
+                Message::HeartbeatAck { .. } => json!({
+                    "type": "heartbeat_ack",
+                    "seq": 7
+                }),
+                Message::Register { .. } => json!({
+                    "type": "register",
+                    "client_id": "workstation-01",
+                    "hostname": "WIN-DESKTOP",
+                    "location": {
+                        "site": "Main Campus",
+                        "building": "C",
+                        "floor": "3"
+                    },
+                    "protocol_version": 1
+                }),
+                Message::RegisterAck { .. } => json!({
+                    "type": "register_ack",
+                    "server_name": "EMNS",
+                    "environment": "production",
+                    "server_version": "emns-server 2.3.0",
+                    "protocol_version": 1,
+                    "server_time": "2024-01-15T10:30:00Z"
+                }),
+                Message::RegisterRejected { .. } => json!({
+                    "type": "register_rejected",
+                    "reason": "invalid token"
+                }),
+                Message::Unregister { .. } => json!({
+                    "type": "unregister",
+                    "client_id": "workstation-01",
+                    "reason": "clean"
+                }),
+                Message::Status { .. } => json!({
+                    "type": "status",
+                    "status": {
+                        "client_id": "workstation-01",
+                        "reported_at": "2024-01-15T10:30:00Z",
+                        "alert_queue_depth": 3,
+                        "alert_queue_capacity": 100,
+                        "alerts_shed": 1,
+                        "confirmation_queue_depth": 0,
+                        "confirmation_queue_capacity": 100,
+                        "outbound_queue_depth": 2,
+                        "system": {
+                            "cpu_percent": 12.5,
+                            "memory_available_bytes": 4_294_967_296u64,
+                            "data_disk_free_bytes": 53_687_091_200u64,
+                            "audio_available": true
+                        }
+                    }
+                }),
+                Message::LocalAlert { .. } => json!({
+                    "type": "local_alert",
+                    "client_id": "workstation-01",
+                    "alert": {
+                        "id": ALERT_ID,
+                        "title": "System Alert",
+                        "message": "Critical system event detected",
+                        "level": "critical",
+                        "requires_confirmation": true,
+                        "sound_file": "alarm_critical.wav",
+                        "timestamp": "2024-01-15T10:30:00Z",
+                        "origin": "local"
+                    }
+                }),
+                Message::DeliveryStatus { .. } => json!({
+                    "type": "delivery_status",
+                    "status": {
+                        "alert_id": ALERT_ID,
+                        "client_id": "workstation-01",
+                        "reported_at": "2024-01-15T10:30:00Z",
+                        "attachment": "failed",
+                        "detail": "checksum mismatch"
+                    }
+                }),
+                Message::AlertExpired { .. } => json!({
+                    "type": "alert_expired",
+                    "client_id": "workstation-01",
+                    "alert_id": ALERT_ID,
+                    "unconfirmed": true
+                }),
+                Message::AlertError { .. } => json!({
+                    "type": "alert_error",
+                    "client_id": "workstation-01",
+                    "reason": "overloaded",
+                    "detail": "30 alerts shed by the rate limit"
+                }),
+                Message::Error { .. } => json!({
+                    "type": "error",
+                    "context": "{\"type\":\"alert\"}",
+                    "detail": "missing field `alert` at line 1 column 16"
+                }),
+                Message::ConfigUpdate { .. } => json!({
+                    "type": "config_update",
+                    "sound_policy": {
+                        "max_volume": 0.5,
+                        "allowed_sounds": ["notification.wav"],
+                        "visual_only": true
+                    }
+                }),
+                Message::Suppression { .. } => json!({
+                    "type": "suppression",
+                    "id": WINDOW_ID,
+                    "starts_at": "2024-01-15T10:30:00Z",
+                    "ends_at": "2024-01-15T12:00:00Z",
+                    "levels": ["warning", "critical"],
+                    "categories": ["fire_alarm"],
+                    "reason": "Fire alarm testing in building C",
+                    "location": { "building": "C" }
+                }),
+                Message::CancelSuppression { .. } => json!({
+                    "type": "cancel_suppression",
+                    "id": WINDOW_ID
+                }),
+                Message::PendingSync { .. } => json!({
+                    "type": "pending_sync",
+                    "pending_alert_ids": [ALERT_ID]
+                }),
+                Message::PendingSyncResult { .. } => json!({
+                    "type": "pending_sync_result",
+                    "still_active": [],
+                    "cancelled": [ALERT_ID],
+                    "expired": []
+                }),
+                Message::ServerShutdown { .. } => json!({
+                    "type": "server_shutdown",
+                    "resume_expected_at": "2024-01-15T10:30:00Z",
+                    "reason": "Scheduled patching"
+                }),
+                Message::UpdateAvailable { .. } => json!({
+                    "type": "update_available",
+                    "version": "1.4.2",
+                    "url": "https://updates.example.com/emns-agent-1.4.2.exe",
+                    "signature": "c2lnbmF0dXJl"
+                }),
+                Message::SoundPack { .. } => json!({
+                    "type": "sound_pack",
+                    "url": "https://sounds.example.com/site-pack-4.zip",
+                    "version": 4,
+                    "sha256": "ab".repeat(32)
+                }),
+                Message::QuorumMet { .. } => json!({
+                    "type": "quorum_met",
+                    "alert_id": ALERT_ID,
+                    "confirmed_by": ["jsmith", "B-10442"]
+                }),
+                Message::CancelAlert { .. } => json!({
+                    "type": "cancel_alert",
+                    "alert_id": ALERT_ID,
+                    "reason": "sent in error"
+                }),
+                Message::Ack { .. } => json!({
+                    "type": "ack",
+                    "message_id": MESSAGE_ID
+                }),
+                Message::Nack { .. } => json!({
+                    "type": "nack",
+                    "message_id": MESSAGE_ID,
+                    "reason": "queue_full",
+                    "detail": "the alert queue is full"
+                }),
+            };
+            (message, expected)
+        })
+        .collect()
+}
+
+#[test]
+fn test_serialization_matches_snapshots() {
+    for (message, expected) in snapshots() {
+        let actual: Value = serde_json::to_value(&message).unwrap();
+        assert_eq!(actual, expected, "wire format changed for {:?}", message);
+    }
+}
+
+#[test]
+fn test_snapshots_round_trip() {
+    for (message, expected) in snapshots() {
+        let parsed: Message = serde_json::from_value(expected.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), expected);
+        assert_eq!(
+            serde_json::to_string(&parsed).unwrap(),
+            serde_json::to_string(&message).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_snapshots_round_trip_through_msgpack() {
+    // Every variant, so the binary path cannot drift from the JSON one
+    for (message, expected) in snapshots() {
+        let bytes: Vec<u8> = message.to_msgpack().unwrap();
+        let parsed: Message = Message::from_msgpack(&bytes)
+            .unwrap_or_else(|e| panic!("{:?} does not read back from msgpack: {}", message, e));
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_alert_batch_sets_aside_unreadable_entries() {
+    let payload: Value = json!({
+        "type": "alert_batch",
+        "alerts": [
+            serde_json::to_value(sample_alert()).unwrap(),
+            { "id": "6f1c2a4e-2b7d-4c1e-9a3f-0d5e8b7c6a51", "title": "No level" },
+            "not an alert"
+        ]
+    });
+    let batch: AlertBatch = match serde_json::from_value(payload.clone()).unwrap() {
+        Message::AlertBatch { batch } => batch,
+        other => panic!("expected alert_batch, got {:?}", other),
+    };
+    assert_eq!(batch.alerts.len(), 1);
+    assert_eq!(batch.alerts[0].id, Uuid::parse_str(ALERT_ID).unwrap());
+    assert_eq!(batch.unreadable.len(), 2);
+    assert!(batch.unreadable[0].starts_with("entry 1 (id \"6f1c2a4e"));
+    assert!(batch.unreadable[1].starts_with("entry 2: "));
+
+    // The same through msgpack
+    let bytes: Vec<u8> = rmp_serde::to_vec_named(&payload).unwrap();
+    match Message::from_msgpack(&bytes).unwrap() {
+        Message::AlertBatch { batch } => {
+            assert_eq!(batch.alerts.len(), 1);
+            assert_eq!(batch.unreadable.len(), 2);
+        }
+        other => panic!("expected alert_batch, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_all_levels_round_trip() {
+    for (level, wire) in [
+        (AlertLevel::Info, "info"),
+        (AlertLevel::Warning, "warning"),
+        (AlertLevel::Critical, "critical"),
+        (AlertLevel::Emergency, "emergency"),
+    ] {
+        assert_eq!(serde_json::to_value(&level).unwrap(), json!(wire));
+        assert_eq!(
+            serde_json::from_value::<AlertLevel>(json!(wire)).unwrap(),
+            level
+        );
+    }
+}
+
+#[test]
+fn test_default_sound_files() {
+    let mut alert: Alert = sample_alert();
+    alert.sound_file = None;
+    for (level, sound) in [
+        (AlertLevel::Info, "notification.wav"),
+        (AlertLevel::Warning, "alarm_warning.wav"),
+        (AlertLevel::Critical, "alarm_critical.wav"),
+        (AlertLevel::Emergency, "alarm_critical.wav"),
+    ] {
+        alert.level = level;
+        assert_eq!(alert.get_sound_file(), sound);
+    }
+}
+
+#[test]
+fn test_timeout_reasons_round_trip() {
+    for (reason, wire) in [
+        (ConfirmationReason::TimedOut, "timed_out"),
+        (ConfirmationReason::TimedOutIdle, "timed_out_idle"),
+    ] {
+        let confirmation: Confirmation = Confirmation {
+            reason,
+            user_idle_secs: Some(10_800),
+            ..sample_confirmation()
+        };
+        let value: Value = serde_json::to_value(&confirmation).unwrap();
+        assert_eq!(value["reason"], json!(wire));
+        assert_eq!(value["user_idle_secs"], json!(10_800));
+        let parsed: Confirmation = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.reason, reason);
+    }
+}
+
+#[test]
+fn test_confirmation_methods_round_trip() {
+    for (method, wire) in [
+        (ConfirmationMethod::ToastButton, "toast_button"),
+        (ConfirmationMethod::DetailsWindow, "details_window"),
+        (ConfirmationMethod::AutoTimeout, "auto_timeout"),
+        (ConfirmationMethod::Api, "api"),
+        (ConfirmationMethod::Cli, "cli"),
+    ] {
+        let confirmation: Confirmation = Confirmation {
+            method: Some(method),
+            ..sample_confirmation()
+        };
+        let value: Value = serde_json::to_value(&confirmation).unwrap();
+        assert_eq!(value["method"], json!(wire));
+        let parsed: Confirmation = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.method, Some(method));
+    }
+    // Left out when unknown, as agents from before the field sent it
+    let value: Value = serde_json::to_value(sample_confirmation()).unwrap();
+    assert!(value.get("method").is_none());
+}
+
+#[test]
+fn test_confirmation_without_reason_is_from_user() {
+    let parsed: Confirmation = serde_json::from_value(json!({
+        "alert_id": ALERT_ID,
+        "client_id": "workstation-01",
+        "confirmed_at": "2024-01-15T10:30:00Z",
+        "hostname": "WIN-DESKTOP",
+        "username": "jdoe"
+    }))
+    .unwrap();
+    assert_eq!(parsed.reason, ConfirmationReason::User);
+    assert_eq!(parsed.user_idle_secs, None);
+}
+
+#[test]
+fn test_register_without_location_still_parses() {
+    let parsed: Message = serde_json::from_value(json!({
+        "type": "register",
+        "client_id": "workstation-01",
+        "hostname": "WIN-DESKTOP"
+    }))
+    .unwrap();
+    match parsed {
+        Message::Register {
+            location,
+            standby,
+            machine_role,
+            protocol_version,
+            ..
+        } => {
+            assert_eq!(location, None);
+            assert!(!standby);
+            assert_eq!(machine_role, None);
+            // An agent from before versions were exchanged
+            assert_eq!(protocol_version, None);
+        }
+        other => panic!("expected register, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_standby_registration_is_flagged() {
+    let value: Value = serde_json::to_value(Message::Register {
+        client_id: "workstation-01".to_string(),
+        hostname: "WIN-DESKTOP".to_string(),
+        location: None,
+        standby: true,
+        encryption_key: None,
+        capabilities: None,
+        previous_shutdown: None,
+        since: None,
+        sound_pack_version: None,
+        machine_role: None,
+        groups: Vec::new(),
+        categories: Vec::new(),
+        server_url: None,
+        supported_encodings: Vec::new(),
+        protocol_version: None,
+        envelopes: false,
+        confirmation_timeouts: false,
+    })
+    .unwrap();
+    assert_eq!(
+        value,
+        json!({
+            "type": "register",
+            "client_id": "workstation-01",
+            "hostname": "WIN-DESKTOP",
+            "standby": true
+        })
+    );
+}
+
+#[test]
+fn test_message_envelopes_round_trip() {
+    let payload: Value = json!({
+        "message_id": MESSAGE_ID,
+        "message": {
+            "type": "alert",
+            "alert": {
+                "id": ALERT_ID,
+                "title": "System Alert",
+                "message": "Critical system event detected",
+                "level": "critical",
+                "requires_confirmation": true,
+                "sound_file": "alarm_critical.wav",
+                "timestamp": "2024-01-15T10:30:00Z"
+            }
+        }
+    });
+    let envelope: Envelope = serde_json::from_value(payload.clone()).unwrap();
+    assert_eq!(envelope.message_id.to_string(), MESSAGE_ID);
+    assert!(envelope.wants_ack());
+    assert_eq!(serde_json::to_value(&envelope).unwrap(), payload);
+    let bytes: Vec<u8> = envelope.to_msgpack().unwrap();
+    let parsed: Envelope = Envelope::from_msgpack(&bytes).unwrap();
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), payload);
+    assert_eq!(msgpack_value(&bytes).unwrap(), payload);
+
+    // Acks and nacks are not answered, even when they cannot be read
+    let ack: Envelope = Envelope {
+        message_id: Uuid::new_v4(),
+        message: Message::Ack {
+            message_id: envelope.message_id,
+        },
+    };
+    assert!(!ack.wants_ack());
+    let garbled_nack: Value = json!({
+        "message_id": MESSAGE_ID,
+        "message": { "type": "nack", "reason": "bored" }
+    });
+    assert_eq!(Envelope::message_id_of(&garbled_nack), None);
+    let garbled_alert: Value = json!({
+        "message_id": MESSAGE_ID,
+        "message": { "type": "alert", "alert": 17 }
+    });
+    assert_eq!(
+        Envelope::message_id_of(&garbled_alert).map(|id| id.to_string()),
+        Some(MESSAGE_ID.to_string())
+    );
+}
+
+#[test]
+fn test_alert_location_lists_round_trip() {
+    let value: Value = json!({
+        "building": "c",
+        "floor": ["2", "3", "4"]
+    });
+    let location: Location = serde_json::from_value(value.clone()).unwrap();
+    assert_eq!(location.floor.values(), ["2", "3", "4"]);
+    assert!(location.site.is_any());
+    assert_eq!(serde_json::to_value(&location).unwrap(), value);
+
+    let alert: Alert = serde_json::from_value(json!({
+        "id": ALERT_ID,
+        "title": "System Alert",
+        "message": "Critical system event detected",
+        "level": "critical",
+        "requires_confirmation": true,
+        "sound_file": null,
+        "timestamp": "2024-01-15T10:30:00Z",
+        "location": value
+    }))
+    .unwrap();
+    assert_eq!(alert.location, Some(location));
+}
+
+#[test]
+fn test_response_options_round_trip() {
+    let alert: Alert = Alert {
+        response_options: Some(vec![
+            ResponseOption {
+                id: "safe".to_string(),
+                label: "Safe".to_string(),
+                response: ConfirmationResponse::Acknowledged,
+            },
+            ResponseOption {
+                id: "need-assistance".to_string(),
+                label: "Need assistance".to_string(),
+                response: ConfirmationResponse::Acknowledged,
+            },
+        ]),
+        ..sample_alert()
+    };
+    let value: Value = serde_json::to_value(&alert).unwrap();
+    assert_eq!(
+        value["response_options"],
+        json!([
+            { "id": "safe", "label": "Safe" },
+            { "id": "need-assistance", "label": "Need assistance" }
+        ])
+    );
+    let parsed: Alert = serde_json::from_value(value).unwrap();
+    assert_eq!(parsed.response_options, alert.response_options);
+
+    // Older alerts have no options and confirmations no response
+    let plain: Value = serde_json::to_value(sample_alert()).unwrap();
+    assert!(plain.get("response_options").is_none());
+    let confirmation: Value = serde_json::to_value(Confirmation {
+        response_id: None,
+        ..sample_confirmation()
+    })
+    .unwrap();
+    assert!(confirmation.get("response_id").is_none());
+}
+
+#[test]
+fn test_structured_responses_round_trip() {
+    let option: ResponseOption = ResponseOption {
+        id: "off-site".to_string(),
+        label: "Cannot comply - off site".to_string(),
+        response: ConfirmationResponse::CannotComply {
+            note: Some("Off site".to_string()),
+        },
+    };
+    assert_eq!(
+        serde_json::to_value(&option).unwrap(),
+        json!({
+            "id": "off-site",
+            "label": "Cannot comply - off site",
+            "response": { "kind": "cannot_comply", "note": "Off site" }
+        })
+    );
+
+    let confirmation: Confirmation = Confirmation {
+        response_id: Some("wrong-recipient".to_string()),
+        response: ConfirmationResponse::NotApplicable,
+        ..sample_confirmation()
+    };
+    let value: Value = serde_json::to_value(&confirmation).unwrap();
+    assert_eq!(value["response"], json!({ "kind": "not_applicable" }));
+    let parsed: Confirmation = serde_json::from_value(value).unwrap();
+    assert_eq!(parsed.response, ConfirmationResponse::NotApplicable);
+
+    // Acknowledged stays off the wire, and is what a confirmation without it means
+    let mut plain: Value = serde_json::to_value(sample_confirmation()).unwrap();
+    assert!(plain.get("response").is_none());
+    plain["response_id"] = json!("safe");
+    let parsed: Confirmation = serde_json::from_value(plain).unwrap();
+    assert_eq!(parsed.response, ConfirmationResponse::Acknowledged);
+}
+
+#[test]
+fn test_status_without_system_health_omits_it() {
+    let value: Value = json!({
+        "client_id": "workstation-01",
+        "reported_at": "2024-01-15T10:30:00Z",
+        "alert_queue_depth": 0,
+        "alert_queue_capacity": 100,
+        "alerts_shed": 0,
+        "confirmation_queue_depth": 0,
+        "confirmation_queue_capacity": 100,
+        "outbound_queue_depth": 0
+    });
+    let status: AgentStatus = serde_json::from_value(value.clone()).unwrap();
+    assert!(status.system.is_empty());
+    assert_eq!(serde_json::to_value(&status).unwrap(), value);
+}
+
+#[test]
+fn test_multicast_confirmations_are_flagged() {
+    let confirmation: Confirmation = Confirmation {
+        received_via: ReceivedVia::Multicast,
+        ..sample_confirmation()
+    };
+    let value: Value = serde_json::to_value(&confirmation).unwrap();
+    assert_eq!(value["received_via"], "multicast");
+    let parsed: Confirmation = serde_json::from_value(value).unwrap();
+    assert_eq!(parsed.received_via, ReceivedVia::Multicast);
+
+    // WebSocket is the default and stays off the wire
+    let plain: Value = serde_json::to_value(sample_confirmation()).unwrap();
+    assert!(plain.get("received_via").is_none());
+}
+
+#[test]
+fn test_alert_envelope_shape() {
+    let envelope: AlertEnvelope = AlertEnvelope {
+        payload: serde_json::to_string(&sample_alert()).unwrap(),
+        signature: "c2lnbmF0dXJl".to_string(),
+    };
+    let value: Value = serde_json::to_value(&envelope).unwrap();
+    assert_eq!(
+        value,
+        json!({ "payload": envelope.payload, "signature": "c2lnbmF0dXJl" })
+    );
+    let parsed: AlertEnvelope = serde_json::from_value(value).unwrap();
+    assert_eq!(parsed, envelope);
+}
+
+#[test]
+fn test_attachment_round_trip() {
+    let alert: Alert = Alert {
+        attachment: Some(Attachment {
+            url: "https://emns.example/files/evacuation.pdf".to_string(),
+            filename: "evacuation.pdf".to_string(),
+            sha256: "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08".to_string(),
+            size: 482_113,
+        }),
+        ..sample_alert()
+    };
+    let value: Value = serde_json::to_value(&alert).unwrap();
+    assert_eq!(
+        value["attachment"],
+        json!({
+            "url": "https://emns.example/files/evacuation.pdf",
+            "filename": "evacuation.pdf",
+            "sha256": "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+            "size": 482_113
+        })
+    );
+    let parsed: Alert = serde_json::from_value(value).unwrap();
+    assert_eq!(parsed.attachment, alert.attachment);
+
+    let plain: Value = serde_json::to_value(sample_alert()).unwrap();
+    assert!(plain.get("attachment").is_none());
+}
+
+#[test]
+fn test_missed_flag_only_sent_when_set() {
+    let replayed: Alert = Alert {
+        missed: true,
+        ..sample_alert()
+    };
+    let value: Value = serde_json::to_value(&replayed).unwrap();
+    assert_eq!(value["missed"], json!(true));
+    assert!(serde_json::from_value::<Alert>(value).unwrap().missed);
+
+    let live: Value = serde_json::to_value(sample_alert()).unwrap();
+    assert!(live.get("missed").is_none());
+    assert!(!serde_json::from_value::<Alert>(live).unwrap().missed);
+}
+
+#[test]
+fn test_translations_only_sent_when_present() {
+    let mut translated: Alert = sample_alert();
+    translated.translations.insert(
+        "fr-CA".to_string(),
+        Translation {
+            title: "Alerte système".to_string(),
+            message: "Événement critique détecté".to_string(),
+        },
+    );
+    let value: Value = serde_json::to_value(&translated).unwrap();
+    assert_eq!(
+        value["translations"],
+        json!({"fr-CA": {"title": "Alerte système", "message": "Événement critique détecté"}})
+    );
+    assert_eq!(
+        serde_json::from_value::<Alert>(value).unwrap().translations,
+        translated.translations
+    );
+
+    let plain: Value = serde_json::to_value(sample_alert()).unwrap();
+    assert!(plain.get("translations").is_none());
+    assert!(serde_json::from_value::<Alert>(plain)
+        .unwrap()
+        .translations
+        .is_empty());
+}
+
+#[test]
+fn test_priority_only_sent_when_set() {
+    let ranked: Alert = Alert {
+        priority: Some(95),
+        ..sample_alert()
+    };
+    let value: Value = serde_json::to_value(&ranked).unwrap();
+    assert_eq!(value["priority"], json!(95));
+    assert_eq!(
+        serde_json::from_value::<Alert>(value).unwrap().priority,
+        Some(95)
+    );
+
+    let unranked: Value = serde_json::to_value(sample_alert()).unwrap();
+    assert!(unranked.get("priority").is_none());
+    assert_eq!(
+        serde_json::from_value::<Alert>(unranked).unwrap().priority,
+        None
+    );
+}
+
+#[test]
+fn test_confirmation_timeouts_are_negotiated() {
+    let offered: Value = serde_json::to_value(Message::Register {
+        client_id: "workstation-01".to_string(),
+        hostname: "WIN-DESKTOP".to_string(),
+        location: None,
+        standby: false,
+        encryption_key: None,
+        capabilities: None,
+        previous_shutdown: None,
+        since: None,
+        sound_pack_version: None,
+        machine_role: None,
+        groups: Vec::new(),
+        categories: Vec::new(),
+        server_url: None,
+        supported_encodings: Vec::new(),
+        protocol_version: None,
+        envelopes: false,
+        confirmation_timeouts: true,
+    })
+    .unwrap();
+    assert_eq!(offered["confirmation_timeouts"], json!(true));
+
+    // Servers that predate the flag never ask for the new message
+    let ack: Message =
+        serde_json::from_value(json!({"type": "register_ack", "server_name": "EMNS"})).unwrap();
+    assert!(matches!(
+        ack,
+        Message::RegisterAck {
+            confirmation_timeouts: false,
+            ..
+        }
+    ));
+
+    let idle: Value = serde_json::to_value(Message::ConfirmationTimeout {
+        alert_id: Uuid::parse_str(ALERT_ID).unwrap(),
+        client_id: "workstation-01".to_string(),
+        timed_out_at: timestamp(),
+        idle: true,
+    })
+    .unwrap();
+    assert_eq!(idle["type"], json!("confirmation_timeout"));
+    assert_eq!(idle["idle"], json!(true));
+}
+
+#[test]
+fn test_confirm_timeout_only_sent_when_set() {
+    let never: Alert = Alert {
+        confirm_timeout_secs: Some(0),
+        ..sample_alert()
+    };
+    let value: Value = serde_json::to_value(&never).unwrap();
+    assert_eq!(value["confirm_timeout_secs"], json!(0));
+    assert_eq!(
+        serde_json::from_value::<Alert>(value)
+            .unwrap()
+            .confirm_timeout_secs,
+        Some(0)
+    );
+
+    let plain: Value = serde_json::to_value(sample_alert()).unwrap();
+    assert!(plain.get("confirm_timeout_secs").is_none());
+}
+
+#[test]
+fn test_escalation_sends_only_what_it_overrides() {
+    let repeated: Alert = Alert {
+        escalation: Some(AlertEscalation {
+            repeat_interval_secs: None,
+            max_repeats: Some(5),
+        }),
+        ..sample_alert()
+    };
+    let value: Value = serde_json::to_value(&repeated).unwrap();
+    assert_eq!(value["escalation"], json!({"max_repeats": 5}));
+    assert_eq!(
+        serde_json::from_value::<Alert>(value).unwrap().escalation,
+        repeated.escalation
+    );
+
+    let plain: Value = serde_json::to_value(sample_alert()).unwrap();
+    assert!(plain.get("escalation").is_none());
+}
+
+#[test]
+fn test_visibility_limits_roles() {
+    let restricted: Alert = Alert {
+        visibility: Some(vec!["workstation".to_string(), "Kiosk".to_string()]),
+        ..sample_alert()
+    };
+    let value: Value = serde_json::to_value(&restricted).unwrap();
+    assert_eq!(value["visibility"], json!(["workstation", "Kiosk"]));
+    let parsed: Alert = serde_json::from_value(value).unwrap();
+    assert!(parsed.visible_to("workstation"));
+    assert!(parsed.visible_to("kiosk"));
+    assert!(!parsed.visible_to("signage"));
+
+    // Alerts from servers that know nothing of roles show everywhere
+    let open: Value = serde_json::to_value(sample_alert()).unwrap();
+    assert!(open.get("visibility").is_none());
+    assert!(serde_json::from_value::<Alert>(open)
+        .unwrap()
+        .visible_to("signage"));
+}
+
+#[test]
+fn test_heartbeat_fields_are_optional() {
+    // A bare heartbeat, as older agents and the server send, has no stats
+    match serde_json::from_value::<Message>(json!({ "type": "heartbeat" })).unwrap() {
+        Message::Heartbeat { stats } => assert_eq!(stats, HeartbeatStats::default()),
+        other => panic!("expected heartbeat, got {:?}", other),
+    }
+
+    // Fields added by newer agents are ignored, the rest still read
+    let value: Value = json!({
+        "type": "heartbeat",
+        "uptime_secs": 30,
+        "some_future_field": true
+    });
+    match serde_json::from_value::<Message>(value).unwrap() {
+        Message::Heartbeat { stats } => assert_eq!(
+            stats,
+            HeartbeatStats {
+                uptime_secs: Some(30),
+                ..HeartbeatStats::default()
+            }
+        ),
+        other => panic!("expected heartbeat, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_sound_only_delivery_status() {
+    let status: Value = serde_json::to_value(Message::DeliveryStatus {
+        status: DeliveryStatus {
+            alert_id: Uuid::parse_str(ALERT_ID).unwrap(),
+            client_id: "nicu-station-2".to_string(),
+            reported_at: timestamp(),
+            attachment: None,
+            sound: Some(emns_protocol::SoundDelivery::SuppressedByPolicy),
+            annunciator: None,
+            callback: None,
+            outcome: None,
+            detail: None,
+            decision: None,
+            locale: None,
+        },
+    })
+    .unwrap();
+    assert_eq!(
+        status,
+        json!({
+            "type": "delivery_status",
+            "status": {
+                "alert_id": ALERT_ID,
+                "client_id": "nicu-station-2",
+                "reported_at": "2024-01-15T10:30:00Z",
+                "sound": "suppressed_by_policy"
+            }
+        })
+    );
+}
+
+#[test]
+fn test_rate_limited_delivery_and_counts() {
+    let status: Value = serde_json::to_value(Message::DeliveryStatus {
+        status: DeliveryStatus {
+            alert_id: Uuid::parse_str(ALERT_ID).unwrap(),
+            client_id: "workstation-01".to_string(),
+            reported_at: timestamp(),
+            attachment: None,
+            sound: None,
+            annunciator: None,
+            callback: None,
+            outcome: Some(DeliveryOutcome::RateLimited),
+            detail: None,
+            decision: None,
+            locale: None,
+        },
+    })
+    .unwrap();
+    assert_eq!(status["status"]["outcome"], "rate_limited");
+
+    // Counts are only sent once something has been shed
+    let value: Value = json!({
+        "client_id": "workstation-01",
+        "reported_at": "2024-01-15T10:30:00Z",
+        "alert_queue_depth": 0,
+        "alert_queue_capacity": 100,
+        "alerts_shed": 0,
+        "alerts_rate_limited": 470,
+        "urgent_alerts_rate_limited": 2,
+        "confirmation_queue_depth": 0,
+        "confirmation_queue_capacity": 100,
+        "outbound_queue_depth": 0
+    });
+    let status: AgentStatus = serde_json::from_value(value.clone()).unwrap();
+    assert_eq!(
+        (
+            status.alerts_rate_limited,
+            status.urgent_alerts_rate_limited
+        ),
+        (470, 2)
+    );
+    assert_eq!(serde_json::to_value(&status).unwrap(), value);
+}
+
+#[test]
+fn test_suppression_window_coverage() {
+    let window: SuppressionWindow = sample_window();
+    let fire_drill = |level: AlertLevel| Alert {
+        level,
+        category: Some("FIRE_ALARM".to_string()),
+        ..sample_alert()
+    };
+    let during: DateTime<Utc> = Utc.with_ymd_and_hms(2024, 1, 15, 11, 0, 0).unwrap();
+
+    assert!(window.covers(&fire_drill(AlertLevel::Critical), during));
+    // Boundaries: open at the start, closed at the end
+    assert!(window.covers(&fire_drill(AlertLevel::Critical), window.starts_at));
+    assert!(!window.covers(&fire_drill(AlertLevel::Critical), window.ends_at));
+    // Levels and categories outside the window are not covered
+    assert!(!window.covers(&fire_drill(AlertLevel::Info), during));
+    assert!(!window.covers(&sample_alert(), during));
+
+    // Without levels, everything but Emergency is covered
+    let any_level: SuppressionWindow = SuppressionWindow {
+        levels: Vec::new(),
+        ..window
+    };
+    assert!(any_level.covers(&fire_drill(AlertLevel::Info), during));
+    assert!(!any_level.covers(&fire_drill(AlertLevel::Emergency), during));
+    let with_emergency: SuppressionWindow = SuppressionWindow {
+        levels: vec![AlertLevel::Emergency],
+        ..any_level.clone()
+    };
+    assert!(with_emergency.covers(&fire_drill(AlertLevel::Emergency), during));
+
+    // An alert without a category is covered only by windows without categories
+    let uncategorised: Alert = Alert {
+        category: None,
+        toast: None,
+        ..fire_drill(AlertLevel::Warning)
+    };
+    assert!(!any_level.covers(&uncategorised, during));
+    let everything: SuppressionWindow = SuppressionWindow {
+        categories: Vec::new(),
+        ..any_level
+    };
+    assert!(everything.covers(&uncategorised, during));
+}